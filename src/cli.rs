@@ -1,26 +1,63 @@
+#[cfg(feature = "redis")]
+use crate::intkv::backend::RedisIntKv;
+#[cfg(feature = "sled")]
+use crate::intkv::backend::SledIntKv;
+#[cfg(unix)]
+use crate::intkv::backend::{BlockDevIntKv, LogIntKv};
+#[cfg(feature = "sftp")]
+use crate::intkv::backend::{SftpIntKv, SshOptions};
+#[cfg(feature = "testing")]
+use crate::intkv::wrapper::{FaultIntKv, Faults};
 use crate::{
-    ftpfs::IntKvFtpFs,
+    ftpfs::{self, check_references, IntKvFtpFs},
     intkv::{
-        backend::FsIntKv,
-        wrapper::{BufferedIntKv, EncIntKv, PageIntKv},
-        IntKv,
+        backend::{
+            CappedMemIntKv, Durability, FileNaming, FsIntKv, HttpClient, HttpIntKv, LockMode,
+            MemIntKv, OpenReport, ReadStrategy,
+        },
+        wrapper::{
+            key_check, prometheus_text, replay, timings, unwrap_key, wrap_key, BufferedIntKv,
+            Checksum, ChecksumIntKv, Cipher, CompressIntKv, CowIntKv, EncIntKv, HeaderVersion,
+            MetricsIntKv, MigrateIntKv, MirrorIntKv, PageIntKv, ParityIntKv, QuotaIntKv,
+            RetryIntKv, ShardedIntKv, StripeIntKv, ThrottleIntKv, TieredIntKv, TraceIntKv,
+            VersionedIntKv, DEFAULT_STRIPE_WIDTH,
+        },
+        Bytes, CacheLimits, IntKv, Stats,
+    },
+    util::{
+        self,
+        progress::Progress,
+        size::{self, parse_size},
+        Encoding, Secret,
     },
 };
+use parking_lot::Mutex;
 use scrypt::Params as ScryptParams;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use structopt::StructOpt;
+
+mod upgrade;
+mod validate;
+
 #[derive(Debug, StructOpt)]
-#[structopt(name = "x79d8", about = "Serve encrypted files via local FTP.")]
 pub(crate) enum Opt {
     /// Initializes a directory to store encrypted data.
     Init {
-        /// Block size in KB. Blocks hide individual file size information.
+        /// Block size in KB, or a size like "64K" or "1M". Blocks hide
+        /// individual file size information.
         /// 0: Disable blocks (do not hide file size information).
-        #[structopt(short, long, default_value = "1024")]
-        block_size_kb: u16,
+        /// [default: 1024, or 64 with --sync-friendly]
+        #[structopt(short, long, parse(try_from_str = parse_kb))]
+        block_size_kb: Option<u16>,
 
         /// Disable encryption.
         #[structopt(long)]
@@ -30,6 +67,75 @@ pub(crate) enum Opt {
         #[structopt(long, default_value = "15")]
         scrypt_log_n: u8,
 
+        /// Pick the scrypt parameter N so deriving the key takes about this
+        /// long on this machine. Overrides --scrypt-log-n.
+        #[structopt(long)]
+        kdf_target_ms: Option<u64>,
+
+        /// Encryption mode: aes256cfb, or aes256gcm to detect modified
+        /// blocks.
+        #[structopt(long, default_value = "aes256cfb")]
+        cipher: Cipher,
+
+        /// How to store blocks: files, a file per block, or sled, an
+        /// embedded database with its own crash safety instead of the
+        /// write-ahead log of files. sled runs background threads, and its
+        /// log can take several times the space of the blocks until it is
+        /// compacted. Needs a build with the "sled" feature.
+        /// [default: files]
+        #[structopt(
+            long,
+            conflicts_with_all = &["block-device", "append-only-log", "stripes", "parity-dirs"]
+        )]
+        storage: Option<StorageEngine>,
+
+        /// Store blocks on this block device, ex. a raw partition, instead
+        /// of the directory, which keeps the config. Formats the device,
+        /// which must start with zeros. Needs blocks.
+        #[structopt(long)]
+        block_device: Option<PathBuf>,
+
+        /// Append blocks to log segments instead of writing a file per
+        /// block, for storage preferring sequential writes.
+        #[structopt(long, conflicts_with = "block-device")]
+        append_only_log: bool,
+
+        /// Stripe blocks across these directories, ideally on different
+        /// disks, for throughput. Repeat for each directory. Losing one
+        /// loses the vault.
+        #[structopt(
+            long = "stripe",
+            number_of_values = 1,
+            conflicts_with_all = &["block-device", "append-only-log"]
+        )]
+        stripes: Vec<PathBuf>,
+
+        /// Split blocks across these directories, ideally on different
+        /// disks, with their parity in the last one. Repeat for each
+        /// directory. Any one of them can be lost.
+        #[structopt(
+            long = "parity",
+            number_of_values = 1,
+            conflicts_with_all = &["block-device", "append-only-log", "stripes"]
+        )]
+        parity_dirs: Vec<PathBuf>,
+
+        /// Change as few files as possible on each flush, for directories
+        /// synced by tools like syncthing or rclone. Uses smaller blocks.
+        #[structopt(long)]
+        sync_friendly: bool,
+
+        /// Compress entries before encrypting them. Block sizes then depend
+        /// on the content, which can leak parts of files to someone who
+        /// also controls other parts of them.
+        #[structopt(long)]
+        compress: bool,
+
+        /// Add a checksum to each block to detect corruption, even without
+        /// encryption: blake2s, or xxhash for accidental corruption only.
+        #[structopt(long, conflicts_with = "block-device")]
+        checksum: Option<Checksum>,
+
         /// Path to the local directory.
         #[structopt(name = "DIR", default_value = ".")]
         dir: PathBuf,
@@ -41,6 +147,316 @@ pub(crate) enum Opt {
         #[structopt(short, long, default_value = "127.0.0.1:7968")]
         address: String,
 
+        /// Allow core dumps, which may contain the key. For debugging.
+        #[structopt(long)]
+        allow_core_dumps: bool,
+
+        /// Serve a new vault kept in memory instead of DIR. Everything is
+        /// discarded on exit.
+        #[structopt(long)]
+        ephemeral: bool,
+
+        /// With --ephemeral, bytes kept in memory, like 512M. Beyond it,
+        /// blocks spill encrypted to a temporary directory.
+        #[structopt(long, default_value = "512M", parse(try_from_str = parse_size))]
+        mem_limit: u64,
+
+        /// On SIGUSR2, flush and write a snapshot of the directory, like
+        /// "x79d8 snapshot", to a new subdirectory of this directory. Other
+        /// commands can then read the snapshot while serving continues.
+        #[structopt(long, conflicts_with = "ephemeral")]
+        snapshot_dir: Option<PathBuf>,
+
+        /// Record operations on files and folders to this file, for "x79d8
+        /// replay". Also set by X79D8_TRACE.
+        #[structopt(long)]
+        trace: Option<PathBuf>,
+
+        /// With --trace, include the content of files. Only for vaults
+        /// without anything private.
+        #[structopt(long)]
+        trace_full: bool,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Shows space usage of an encrypted directory.
+    Status {
+        /// Walk all pages for chain statistics. Slow.
+        #[structopt(long)]
+        deep: bool,
+
+        /// Print latencies of each layer of the storage stack while
+        /// reading pages.
+        #[structopt(long)]
+        timings: bool,
+
+        /// Read the directory even if its config has fields unknown to
+        /// this version, like those written by a newer version.
+        #[structopt(long)]
+        ignore_unknown_config: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Checks integrity of an encrypted directory.
+    Fsck {
+        /// Drop files stored in corrupted blocks.
+        #[structopt(long)]
+        repair: bool,
+
+        /// Only check the files of the directory, without reading them.
+        /// Does not need the password.
+        #[structopt(long, conflicts_with = "repair")]
+        quick: bool,
+
+        /// Read the directory even if its config has fields unknown to
+        /// this version, like those written by a newer version.
+        #[structopt(long, conflicts_with = "repair")]
+        ignore_unknown_config: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Copies an encrypted directory to a new directory using another
+    /// encryption mode. The password stays the same.
+    Migrate {
+        /// Encryption mode of the new directory.
+        #[structopt(long)]
+        cipher: Cipher,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR")]
+        dir: PathBuf,
+
+        /// Path to the new directory.
+        #[structopt(name = "DEST")]
+        dest: PathBuf,
+    },
+
+    /// Re-encrypts an encrypted directory with a new random key, protected
+    /// by a new password. Blocks are re-encrypted in place. Run it again to
+    /// resume if interrupted.
+    Rekey {
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Moves blocks of older directories into the data subdirectory and its
+    /// subdirectories. Renames blocks if any of the naming options are
+    /// given. Run it again to resume if interrupted.
+    MigrateLayout {
+        /// Prefix of block file names.
+        #[structopt(long)]
+        file_prefix: Option<String>,
+
+        /// Pad numbers in block file names with zeros to this width.
+        #[structopt(long)]
+        file_name_width: Option<usize>,
+
+        /// Use hex numbers in block file names.
+        #[structopt(long)]
+        file_name_hex: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Spreads blocks across shard directories, for example on different
+    /// disks, or rebalances them after shards are added or removed, or
+    /// moves them between files and a sled database. Blocks are moved
+    /// without decrypting them. Run it again to resume if interrupted.
+    MigrateStorage {
+        /// Directory of a shard, relative to the directory unless absolute.
+        /// Repeat for each shard, in a fixed order. "." alone stores blocks
+        /// in the directory again.
+        #[structopt(long = "shard", number_of_values = 1)]
+        shards: Vec<String>,
+
+        /// Move blocks to files, a file per block, or to sled, an embedded
+        /// database. See `init --storage`.
+        #[structopt(long, conflicts_with_all = &["shards", "online"])]
+        storage: Option<StorageEngine>,
+
+        /// Keep using the directory while blocks are copied to new shards,
+        /// writing changes to both. If it is being served, the server
+        /// copies them, asked through the control API. Resumes where it
+        /// stopped if interrupted.
+        #[structopt(long)]
+        online: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Checks an encrypted directory for IVs used more than once.
+    AuditIv {
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Restores blocks replaced by the last write, if the directory fails
+    /// verification. Requires `keep_previous_generation` in the config.
+    Rollback {
+        /// Roll back even if the directory passes verification.
+        #[structopt(long)]
+        force: bool,
+
+        /// Restore blocks as of this many flushes with changes before the
+        /// last one, from older values kept by `versions_kept`, instead of
+        /// the previous generation.
+        #[structopt(long)]
+        flushes: Option<u64>,
+
+        /// Instead of rolling back, drop older values of removed blocks
+        /// kept by `versions_kept`.
+        #[structopt(long, conflicts_with_all = &["flushes", "force"])]
+        prune_versions: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Creates a point-in-time copy of an encrypted directory. Blocks are
+    /// hard-linked where possible, so the copy takes little space and
+    /// stays unchanged as the directory changes.
+    Snapshot {
+        /// Check integrity of the copy afterwards, like fsck.
+        #[structopt(long)]
+        verify: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR")]
+        dir: PathBuf,
+
+        /// Path to the new directory.
+        #[structopt(name = "SNAPDIR")]
+        dest: PathBuf,
+    },
+
+    /// Takes a copy-on-write snapshot kept inside an encrypted directory,
+    /// or lists, checks or deletes them. Blocks are copied as they change
+    /// after a snapshot. Requires `cow_snapshots` in the config.
+    CowSnapshot {
+        /// List snapshots instead.
+        #[structopt(long)]
+        list: bool,
+
+        /// Check integrity of the snapshot with this id instead, like fsck.
+        #[structopt(long)]
+        check: Option<u64>,
+
+        /// Delete the snapshot with this id instead, freeing its blocks.
+        #[structopt(long)]
+        delete: Option<u64>,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Changes a password of an encrypted directory without re-encrypting
+    /// blocks.
+    Passwd {
+        /// Add another password (ex. a recovery key) instead of replacing
+        /// the given one.
+        #[structopt(long)]
+        add: bool,
+
+        /// Path to the local directory.
+        #[structopt(name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Measures throughput on this machine. Nothing is written to disk.
+    Bench {
+        /// Block size in KB, or a size like "1M".
+        #[structopt(short, long, default_value = "1024", parse(try_from_str = parse_kb))]
+        block_size_kb: u16,
+
+        /// Number of blocks to encrypt and decrypt.
+        #[structopt(long, default_value = "64")]
+        blocks: usize,
+
+        /// Directory to measure file I/O in. Defaults to the temporary
+        /// directory.
+        #[structopt(long)]
+        dir: Option<PathBuf>,
+
+        /// Directories to measure striping across, ideally on different
+        /// disks. Repeat for each directory. Defaults to 4 directories in
+        /// the directory above.
+        #[structopt(long = "stripe-dir", number_of_values = 1)]
+        stripe_dirs: Vec<PathBuf>,
+    },
+
+    /// Replays a trace written by "serve --trace" on blocks in memory, and
+    /// checks the blocks after each flush.
+    Replay {
+        /// Path to the trace.
+        #[structopt(name = "TRACE")]
+        trace: PathBuf,
+    },
+
+    /// Upgrades a directory written by an older version to the current
+    /// format. Takes a snapshot to DIR.pre-upgrade-N first, where N is the
+    /// format version before.
+    Upgrade {
+        /// Do not take the snapshot.
+        #[structopt(long)]
+        no_snapshot: bool,
+
+        /// Seconds to wait for other x79d8 processes using the directory.
+        #[structopt(long, default_value = "0")]
+        wait_lock: u64,
+
         /// Path to the local directory.
         #[structopt(name = "DIR", default_value = ".")]
         dir: PathBuf,
@@ -49,10 +465,63 @@ pub(crate) enum Opt {
 
 static CONFIG_FILE: &str = "x79d8cfg.json";
 
+/// Files of a snapshot, written by `x79d8 snapshot`.
+static SNAPSHOT_FILE: &str = "x79d8snapshot.json";
+
+/// Subdirectory of blocks in layout version 2.
+static DATA_DIR: &str = "data";
+
+/// Entries changed while the mirror was offline, one index per line.
+static MIRROR_RESYNC_FILE: &str = "x79d8resync.txt";
+
+/// Entries of the local tier waiting for upload.
+static UPLOAD_JOURNAL_FILE: &str = "x79d8upload.txt";
+
+/// Subdirectory of the sled database, if blocks are stored there.
+static SLED_DIR: &str = "sled";
+
+/// Marks a shard directory: "<vault_id> <position> <count>".
+static SHARD_FILE: &str = "x79d8shard.txt";
+
+/// Progress of `migrate-storage --online`, see `MigrateIntKv`.
+static MIGRATE_CURSOR_FILE: &str = "x79d8migrate.txt";
+
+/// Layout of new directories. Version 1 stores blocks in the directory
+/// itself, and version 2 in `DATA_DIR`.
+const LAYOUT_VERSION: u8 = 2;
+
+const fn default_layout_version() -> u8 {
+    1
+}
+
 const fn default_cache_size_limit() -> usize {
     1 << 28
 }
 
+const fn default_flush_delay_secs() -> u64 {
+    ftpfs::WRITE_DELAY_SECS
+}
+
+const fn default_cache_entries_limit() -> usize {
+    1 << 20
+}
+
+const fn default_pinned_size_limit() -> usize {
+    1 << 24
+}
+
+const fn default_fill_factor() -> f64 {
+    1.0
+}
+
+const fn default_sequential_allocation() -> bool {
+    false
+}
+
+/// Block size of `init --sync-friendly`. A small edit rewrites whole
+/// blocks, so smaller blocks mean less to upload.
+const SYNC_FRIENDLY_BLOCK_SIZE_KB: u16 = 64;
+
 const fn default_scrypt_log_n() -> u8 {
     15
 }
@@ -69,10 +538,93 @@ const fn default_block_size_kb() -> u16 {
     1024
 }
 
-#[derive(Debug, StructOpt, Serialize, Deserialize)]
+const fn default_preallocate() -> bool {
+    true
+}
+
+const fn default_reserved_space_mb() -> u64 {
+    64
+}
+
+const fn default_low_space_warning_mb() -> u64 {
+    1024
+}
+
+const fn default_open_files() -> usize {
+    128
+}
+
+const fn default_io_retry_attempts() -> u32 {
+    3
+}
+
+const fn default_io_retry_delay_ms() -> u64 {
+    10
+}
+
+const fn default_remote_timeout_secs() -> u64 {
+    30
+}
+
+const fn default_remote_retry_attempts() -> u32 {
+    3
+}
+
+const fn default_remote_retry_delay_ms() -> u64 {
+    500
+}
+
+const fn default_local_cache_size_mb() -> u64 {
+    1024
+}
+
+const fn default_log_segment_size_mb() -> u64 {
+    64
+}
+
+const fn default_log_compaction_ratio() -> f64 {
+    0.5
+}
+
+const fn default_compression_level() -> u8 {
+    CompressIntKv::DEFAULT_LEVEL
+}
+
+const fn default_stripe_width() -> usize {
+    DEFAULT_STRIPE_WIDTH
+}
+
+const fn default_remote_connections() -> usize {
+    4
+}
+
+/// How blocks in the directory are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StorageEngine {
+    /// A file per block, see `FsIntKv`.
+    #[default]
+    Files,
+    /// A sled database, see `SledIntKv`.
+    Sled,
+}
+
+impl FromStr for StorageEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "files" => Ok(StorageEngine::Files),
+            "sled" => Ok(StorageEngine::Sled),
+            _ => Err(format!("unknown storage {} (expected files or sled)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt, Serialize, Deserialize)]
 struct Config {
     pub salt_hex: String,
-    #[serde(default = "default_block_size_kb")]
+    #[serde(default = "default_block_size_kb", with = "size::kib")]
     pub block_size_kb: u16,
     #[serde(default = "default_scrypt_log_n")]
     pub scrypt_log_n: u8,
@@ -80,8 +632,379 @@ struct Config {
     pub scrypt_r: u32,
     #[serde(default = "default_scrypt_p")]
     pub scrypt_p: u32,
-    #[serde(default = "default_cache_size_limit")]
+    #[serde(default = "default_cache_size_limit", with = "size::bytes")]
     pub cache_size_limit: usize,
+    /// Maximum number of cached entries, including known-missing ones.
+    #[serde(default = "default_cache_entries_limit")]
+    pub cache_entries_limit: usize,
+    /// Size of hot entries (ex. directories) protected from cache eviction.
+    #[serde(default = "default_pinned_size_limit", with = "size::bytes")]
+    pub pinned_size_limit: usize,
+    /// Flush buffered changes in background every N seconds (0: disabled).
+    #[serde(default)]
+    pub background_flush_secs: u64,
+    /// Flush buffered changes in background once they exceed the size.
+    #[serde(default, with = "size::bytes")]
+    pub background_flush_max_dirty_bytes: usize,
+    /// Flush changes this many seconds after the last one.
+    #[serde(default = "default_flush_delay_secs")]
+    pub flush_delay_secs: u64,
+    /// Check integrity of pages after each flush. Slow.
+    #[serde(default)]
+    #[structopt(long)]
+    pub paranoid_checks: bool,
+    /// Fill blocks up to this ratio so files can grow in place (0.5 to 1).
+    #[serde(default = "default_fill_factor")]
+    pub fill_factor: f64,
+    /// Allocate blocks sequentially instead of randomly. Random blocks hide
+    /// the write order. Set by `init --sync-friendly`.
+    #[serde(default = "default_sequential_allocation")]
+    #[structopt(long)]
+    pub sequential_allocation: bool,
+    /// Threads to encrypt and decrypt blocks in batches (0: auto, 1:
+    /// single-threaded).
+    #[serde(default)]
+    pub flush_threads: usize,
+    /// Encryption mode. Older directories use AES256-CFB.
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// Salt of the new password while re-encrypting. Empty otherwise.
+    #[serde(default)]
+    #[structopt(long)]
+    pub rekey_salt_hex: String,
+    /// Blocks leave room for MACs, and entries without one are rejected.
+    /// Older directories add MACs as entries are rewritten.
+    #[serde(default)]
+    #[structopt(long)]
+    pub mac_trailer: bool,
+    /// The scrypt parameters above are used to derive the key. Older
+    /// directories used the recommended parameters regardless.
+    #[serde(default)]
+    #[structopt(long)]
+    pub kdf_params_honored: bool,
+    /// The master key wrapped by each password. Empty if the key is derived
+    /// from the password directly.
+    #[serde(default)]
+    #[structopt(long)]
+    pub wrapped_keys_hex: Vec<String>,
+    /// The new master key wrapped by the new password while re-encrypting.
+    #[serde(default)]
+    #[structopt(long)]
+    pub rekey_wrapped_key_hex: String,
+    /// Check value of a key derived from the password directly. Recorded on
+    /// the first successful open.
+    #[serde(default)]
+    #[structopt(long)]
+    pub key_check_hex: String,
+    /// Time deriving the key took at init, in milliseconds. 0: unknown.
+    #[serde(default)]
+    pub kdf_measured_ms: u64,
+    /// Layout of block headers. Older directories use version 0.
+    #[serde(default)]
+    pub entry_header_version: u8,
+    /// How to read blocks: mmap, buffered (for network filesystems), or auto.
+    #[serde(default)]
+    #[structopt(long)]
+    pub read_strategy: ReadStrategy,
+    /// Tries of file operations failing with transient errors, such as
+    /// stale handles of network filesystems.
+    #[serde(default = "default_io_retry_attempts")]
+    pub io_retry_attempts: u32,
+    /// Delay before retrying a file operation, doubled for each retry.
+    #[serde(default = "default_io_retry_delay_ms")]
+    pub io_retry_delay_ms: u64,
+    /// Allocate disk space of blocks before writing them, to fail early if
+    /// the disk is full and to reduce fragmentation.
+    #[serde(default = "default_preallocate")]
+    #[structopt(long)]
+    pub preallocate: bool,
+    /// Which fsyncs to issue: full, data (skip directories, so a crash might
+    /// lose recent writes), or none (for storage that survives crashes by
+    /// other means, ex. a battery-backed cache).
+    #[serde(default)]
+    #[structopt(long)]
+    pub durability: Durability,
+    /// Keep blocks replaced by the last write for `x79d8 rollback`. Changed
+    /// blocks take twice the space.
+    #[serde(default)]
+    #[structopt(long)]
+    pub keep_previous_generation: bool,
+    /// Pack blocks smaller than this many bytes into segment files, so a
+    /// write fsyncs a few files instead of one per block (0: disabled).
+    /// Useful when blocks are disabled.
+    #[serde(default, with = "size::bytes")]
+    pub segment_threshold_bytes: usize,
+    /// Blocks to keep open for reading and writing, to save round trips on
+    /// high-latency filesystems (0: disabled). Ignored on Windows.
+    #[serde(default = "default_open_files")]
+    pub open_files: usize,
+    /// Read and write blocks bypassing the OS page cache, so streaming
+    /// large files does not evict more useful data. Blocks are cached by
+    /// x79d8 regardless.
+    #[serde(default)]
+    #[structopt(long)]
+    pub direct_io: bool,
+    /// Write even if another program or machine wrote the directory since
+    /// it was opened. By default, such writes are refused to avoid mixing
+    /// stale state with the other changes, and a restart picks up the
+    /// changes. Only set this for shared setups that keep writers from
+    /// overlapping by other means. Overlapping writes corrupt the data.
+    #[serde(default)]
+    #[structopt(long)]
+    pub allow_external_changes: bool,
+    /// Refuse uploads unless this much disk space stays free after writing
+    /// pending changes, so uploads already accepted can be written.
+    #[serde(default = "default_reserved_space_mb", with = "size::mib")]
+    pub reserved_space_mb: u64,
+    /// Warn when free disk space drops below this.
+    #[serde(default = "default_low_space_warning_mb", with = "size::mib")]
+    pub low_space_warning_mb: u64,
+    /// Names of block files. Older directories use bare decimal numbers.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub file_naming: FileNaming,
+    /// Names of block files while `migrate-layout` renames them. None
+    /// otherwise.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub next_file_naming: Option<FileNaming>,
+    /// Where blocks are stored, see `LAYOUT_VERSION`. Older directories use
+    /// version 1.
+    #[serde(default = "default_layout_version")]
+    #[structopt(skip)]
+    pub layout_version: u8,
+    /// Layout being moved to by `migrate-layout`. None otherwise.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub next_layout_version: Option<u8>,
+    /// How blocks in the directory are stored, set by `init --storage`:
+    /// "files", or "sled" for a sled database in `SLED_DIR`.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub storage: StorageEngine,
+    /// Store blocks on a WebDAV server instead of the local directory, ex.
+    /// "https://example.com/remote.php/dav/files/me/x79d8". The config
+    /// stays local. Empty: local.
+    #[serde(default)]
+    #[structopt(long)]
+    pub remote_url: String,
+    /// User name and password for the server.
+    #[serde(default)]
+    #[structopt(long)]
+    pub remote_user: String,
+    #[serde(default)]
+    #[structopt(long)]
+    pub remote_password: String,
+    /// Token for the server, used instead of the user name and password.
+    #[serde(default)]
+    #[structopt(long)]
+    pub remote_bearer_token: String,
+    /// PEM file of CA certificates to trust for https, instead of those of
+    /// the system.
+    #[serde(default)]
+    #[structopt(long)]
+    pub remote_ca_file: String,
+    /// Seconds to wait for the server before failing a request.
+    #[serde(default = "default_remote_timeout_secs")]
+    pub remote_timeout_secs: u64,
+    /// Tries of requests failing with connection errors, timeouts or server
+    /// errors. Also applies to operations on SFTP and Redis.
+    #[serde(default = "default_remote_retry_attempts")]
+    pub remote_retry_attempts: u32,
+    /// Delay before retrying a request, doubled for each retry.
+    #[serde(default = "default_remote_retry_delay_ms")]
+    pub remote_retry_delay_ms: u64,
+    /// Requests to the server in flight.
+    #[serde(default = "default_remote_connections")]
+    pub remote_connections: usize,
+    /// Store blocks on an SFTP server reached by `ssh`, which reads its
+    /// config and agent as usual. Empty: not SFTP.
+    #[serde(default)]
+    #[structopt(long)]
+    pub sftp_host: String,
+    /// 0: the default of `ssh`.
+    #[serde(default)]
+    #[structopt(long, default_value = "0")]
+    pub sftp_port: u16,
+    /// Empty: the default of `ssh`.
+    #[serde(default)]
+    #[structopt(long)]
+    pub sftp_user: String,
+    /// Private key to log in with, instead of the agent and the default
+    /// keys.
+    #[serde(default)]
+    #[structopt(long)]
+    pub sftp_key_file: String,
+    /// Directory of blocks on the server, relative to the home directory
+    /// unless absolute.
+    #[serde(default)]
+    #[structopt(long)]
+    pub sftp_path: String,
+    /// Store blocks in Redis or a compatible server, ex.
+    /// "redis://:password@host:6379/0", as keys prefixed by the vault id.
+    /// Empty: not Redis.
+    #[serde(default)]
+    #[structopt(long)]
+    pub redis_url: String,
+    /// Expire the blocks if the vault is not written for this long. 0: no
+    /// expiry.
+    #[serde(default)]
+    pub redis_ttl_secs: u64,
+    /// Also store blocks in this directory, for example on an external
+    /// drive, so either copy can restore the other. Relative to the
+    /// directory unless absolute. Run `fsck --repair` after setting it to
+    /// copy existing blocks. Empty: no mirror.
+    #[serde(default)]
+    #[structopt(long)]
+    pub mirror_dir: String,
+    /// Keep working while the mirror is unavailable, recording changed
+    /// blocks to copy once it is back. Otherwise errors of the mirror fail
+    /// operations.
+    #[serde(default)]
+    #[structopt(long)]
+    pub mirror_allow_degraded: bool,
+    /// Keep recently used blocks of a remote directory here, and blocks
+    /// not yet uploaded. Relative to the directory unless absolute.
+    /// Empty: read and write the server directly.
+    #[serde(default)]
+    #[structopt(long)]
+    pub local_cache_dir: String,
+    /// Size of blocks kept in `local_cache_dir`, beyond those waiting for
+    /// upload.
+    #[serde(default = "default_local_cache_size_mb", with = "size::mib")]
+    #[structopt(long, default_value = "1024")]
+    pub local_cache_size_mb: u64,
+    /// Upload changes as they are written, instead of on flush.
+    #[serde(default)]
+    #[structopt(long)]
+    pub local_cache_write_through: bool,
+    /// Random identifier, recorded in shards so shards of different
+    /// directories are not mixed. Older directories get one from
+    /// `migrate-storage`.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub vault_id: String,
+    /// Store blocks across these directories instead of the directory,
+    /// entry N in shard N modulo the count. Relative to the directory
+    /// unless absolute. Changed by `migrate-storage`. Empty: not sharded.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub shards: Vec<String>,
+    /// Stripe blocks across these directories, set by `init --stripe`.
+    /// Relative to the directory unless absolute. Empty: not striped.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub stripes: Vec<String>,
+    /// Split blocks across these directories, the last holding parity, set
+    /// by `init --parity`. Relative to the directory unless absolute.
+    /// Empty: no parity.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub parity_dirs: Vec<String>,
+    /// Consecutive blocks in each stripe directory.
+    #[serde(default = "default_stripe_width")]
+    pub stripe_width: usize,
+    /// Shards being moved to by `migrate-storage`. None otherwise.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub next_shards: Option<Vec<String>>,
+    /// `next_shards` are moved to by `migrate-storage --online`, while
+    /// blocks are read from and written to both.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub online_move: bool,
+    /// Store blocks in fixed slots of this block device instead of the
+    /// directory, set by `init --block-device`. Empty: not a device.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub block_device: String,
+    /// Append blocks to log segments in the data subdirectory instead of
+    /// writing a file per block, set by `init --append-only-log`.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub append_only_log: bool,
+    /// Start another log segment once one reaches this size.
+    #[serde(default = "default_log_segment_size_mb", with = "size::mib")]
+    pub log_segment_size_mb: u64,
+    /// Rewrite log segments whose live blocks take less than this ratio of
+    /// them, a few on each flush.
+    #[serde(default = "default_log_compaction_ratio")]
+    pub log_compaction_ratio: f64,
+    /// Drop writes that do not change an entry, so they do not show up as
+    /// changed files to sync tools. Set by `init --sync-friendly`, which
+    /// also picks smaller blocks and sequential allocation, and keeps small
+    /// entries in their own files, and no previous generation.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub sync_friendly: bool,
+    /// Compress entries before encrypting them, set by `init --compress`.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub compression: bool,
+    /// From 1 (fastest) to 9 (smallest).
+    #[serde(default = "default_compression_level")]
+    pub compression_level: u8,
+    /// Add a checksum to each block, set by `init --checksum`. Blocks
+    /// written before it was set are read unchecked until rewritten. Not
+    /// for block devices, as blocks grow by a few bytes.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub checksum: Option<Checksum>,
+    /// Refuse writes that would store more blocks than this (0: no limit).
+    #[serde(default)]
+    pub quota_max_blocks: u64,
+    /// Refuse writes that would store more than this many MB of blocks,
+    /// before encryption (0: no limit).
+    #[serde(default, with = "size::mib")]
+    pub quota_max_mb: u64,
+    /// Keep this many older values of each block, for `x79d8 rollback
+    /// --flushes` (0: disabled). Changed blocks take up to as many times
+    /// the space. Cannot change once older values are kept.
+    #[serde(default)]
+    pub versions_kept: usize,
+    /// Keep snapshots taken by `x79d8 cow-snapshot`, copying blocks as they
+    /// change. Delete the snapshots before turning it off.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub cow_snapshots: bool,
+    /// Limit reads of blocks to this many KB per second (0: no limit).
+    /// Changed while serving by `POST /throttle?read_kbps=N` of the
+    /// control API.
+    #[serde(default, with = "size::kib")]
+    pub throttle_read_kbps: u64,
+    /// Limit writes of blocks to this many KB per second (0: no limit).
+    #[serde(default, with = "size::kib")]
+    pub throttle_write_kbps: u64,
+    /// KB read or written at once before the limits apply (0: a second of
+    /// the limit).
+    #[serde(default, with = "size::kib")]
+    pub throttle_burst_kb: u64,
+    /// Address of the control API of `serve`, like "127.0.0.1:7969", for
+    /// `migrate-storage --online`. Anyone able to connect can use it.
+    /// Empty: disabled.
+    #[serde(default)]
+    #[structopt(long)]
+    pub control_address: String,
+    /// Record latencies of each layer of the storage stack, for `GET
+    /// /metrics` of the control API. For debugging.
+    #[serde(default)]
+    #[structopt(long)]
+    pub layer_metrics: bool,
+    /// Integer encoding of blocks of metadata and folders. Older vaults use
+    /// "fixint". New vaults use "varint", which is smaller.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub metadata_encoding: Encoding,
+    /// Format of the directory, see `upgrade`. Older directories use
+    /// version 1.
+    #[serde(default = "upgrade::default_format_version")]
+    #[structopt(skip)]
+    pub format_version: u32,
+    /// Changes of the format the directory uses, like "varint-metadata".
+    /// Binaries not knowing one of them refuse the directory.
+    #[serde(default)]
+    #[structopt(skip)]
+    pub features: Vec<String>,
 }
 
 impl Opt {
@@ -92,14 +1015,211 @@ impl Opt {
                 block_size_kb,
                 no_encrypt,
                 scrypt_log_n,
+                kdf_target_ms,
+                cipher,
+                storage: engine,
+                block_device,
+                append_only_log,
+                stripes,
+                parity_dirs,
+                sync_friendly,
+                compress,
+                checksum,
+                dir,
+            } => {
+                let cipher = if *no_encrypt { None } else { Some(*cipher) };
+                let storage = match (block_device, append_only_log) {
+                    (Some(device), _) => Storage::BlockDevice(device),
+                    (None, true) => Storage::Log,
+                    (None, false) if !stripes.is_empty() => Storage::Stripes(stripes),
+                    (None, false) if !parity_dirs.is_empty() => Storage::Parity(parity_dirs),
+                    (None, false) if *engine == Some(StorageEngine::Sled) => Storage::Sled,
+                    (None, false) => Storage::Files,
+                };
+                init_cmd(
+                    dir,
+                    *block_size_kb,
+                    cipher,
+                    *scrypt_log_n,
+                    *kdf_target_ms,
+                    storage,
+                    InitOptions {
+                        sync_friendly: *sync_friendly,
+                        compress: *compress,
+                        checksum: *checksum,
+                    },
+                )
+            }
+            Opt::Serve {
+                address,
+                allow_core_dumps,
+                ephemeral,
+                mem_limit,
+                snapshot_dir,
+                trace,
+                trace_full,
+                dir,
+            } => {
+                let ephemeral = ephemeral.then_some(*mem_limit);
+                let snapshot_dir = snapshot_dir.as_deref();
+                let trace = trace
+                    .clone()
+                    .or_else(|| std::env::var_os("X79D8_TRACE").map(PathBuf::from));
+                let trace = trace.as_deref().map(|path| (path, *trace_full));
+                serve_cmd(
+                    dir,
+                    address,
+                    *allow_core_dumps,
+                    ephemeral,
+                    snapshot_dir,
+                    trace,
+                )
+                .await
+            }
+            Opt::Status {
+                deep,
+                timings,
+                ignore_unknown_config,
+                wait_lock,
+                dir,
+            } => {
+                let lock = Lock::read_only(*wait_lock);
+                status_cmd(dir, *deep, *timings, *ignore_unknown_config, lock)
+            }
+            Opt::Fsck {
+                repair,
+                quick,
+                ignore_unknown_config,
+                wait_lock,
+                dir,
+            } => {
+                let lock = match repair {
+                    true => Lock::exclusive(*wait_lock),
+                    false => Lock::read_only(*wait_lock),
+                };
+                let ignore_unknown = *ignore_unknown_config;
+                match quick {
+                    true => quick_fsck_cmd(dir, ignore_unknown, lock),
+                    false => fsck_cmd(dir, *repair, ignore_unknown, lock),
+                }
+            }
+            Opt::Migrate { cipher, dir, dest } => migrate_cmd(dir, dest, *cipher),
+            Opt::Rekey { wait_lock, dir } => rekey_cmd(dir, Lock::exclusive(*wait_lock)),
+            Opt::AuditIv { wait_lock, dir } => audit_iv_cmd(dir, Lock::read_only(*wait_lock)),
+            Opt::MigrateLayout {
+                file_prefix,
+                file_name_width,
+                file_name_hex,
+                wait_lock,
+                dir,
+            } => {
+                let naming = match (file_prefix, file_name_width, file_name_hex) {
+                    (None, None, false) => None,
+                    _ => Some(FileNaming {
+                        prefix: file_prefix.clone().unwrap_or_default(),
+                        width: file_name_width.unwrap_or(0),
+                        hex: *file_name_hex,
+                    }),
+                };
+                migrate_layout_cmd(dir, naming, Lock::exclusive(*wait_lock))
+            }
+            Opt::MigrateStorage {
+                shards,
+                storage,
+                online,
+                wait_lock,
+                dir,
+            } => {
+                let lock = Lock::exclusive(*wait_lock);
+                match storage {
+                    Some(storage) => migrate_storage_engine_cmd(dir, *storage, lock),
+                    None => migrate_storage_cmd(dir, shards, *online, lock),
+                }
+            }
+            Opt::Rollback {
+                force,
+                flushes,
+                prune_versions,
+                wait_lock,
+                dir,
+            } => {
+                let lock = Lock::exclusive(*wait_lock);
+                match (flushes, prune_versions) {
+                    (_, true) => prune_versions_cmd(dir, lock),
+                    (Some(flushes), false) => rollback_versions_cmd(dir, *flushes, lock),
+                    (None, false) => rollback_cmd(dir, *force, lock),
+                }
+            }
+            Opt::Snapshot {
+                verify,
+                wait_lock,
+                dir,
+                dest,
+            } => snapshot_cmd(dir, dest, *verify, Lock::exclusive(*wait_lock)),
+            Opt::CowSnapshot {
+                list,
+                check,
+                delete,
+                wait_lock,
+                dir,
+            } => {
+                let lock = match *list || check.is_some() {
+                    true => Lock::read_only(*wait_lock),
+                    false => Lock::exclusive(*wait_lock),
+                };
+                cow_snapshot_cmd(dir, *list, *check, *delete, lock)
+            }
+            Opt::Passwd { add, dir } => passwd_cmd(dir, *add),
+            Opt::Bench {
+                block_size_kb,
+                blocks,
+                dir,
+                stripe_dirs,
+            } => bench_cmd(*block_size_kb, *blocks, dir.as_deref(), stripe_dirs),
+            Opt::Replay { trace } => replay_cmd(trace),
+            Opt::Upgrade {
+                no_snapshot,
+                wait_lock,
                 dir,
-            } => init_cmd(&dir, *block_size_kb, !no_encrypt, *scrypt_log_n),
-            Opt::Serve { address, dir } => serve_cmd(&dir, address).await,
+            } => upgrade_cmd(dir, !*no_snapshot, Lock::exclusive(*wait_lock)),
         }
     }
 }
 
-fn init_cmd(dir: &Path, block_size_kb: u16, encrypted: bool, scrypt_log_n: u8) -> io::Result<()> {
+/// Where `init` stores blocks.
+enum Storage<'a> {
+    /// A file per block in the directory.
+    Files,
+    BlockDevice(&'a Path),
+    /// Log segments in the directory.
+    Log,
+    /// A sled database in the directory.
+    Sled,
+    /// Files striped across directories.
+    Stripes(&'a [PathBuf]),
+    /// Files split across directories, the last holding parity.
+    Parity(&'a [PathBuf]),
+}
+
+/// Optional features `init` records in the config.
+#[derive(Clone, Copy, Default)]
+struct InitOptions {
+    sync_friendly: bool,
+    compress: bool,
+    checksum: Option<Checksum>,
+}
+
+/// Initialize a directory. `cipher` is None if encryption is disabled.
+/// `block_size_kb` is None to use the default of the profile.
+fn init_cmd(
+    dir: &Path,
+    block_size_kb: Option<u16>,
+    cipher: Option<Cipher>,
+    mut scrypt_log_n: u8,
+    kdf_target_ms: Option<u64>,
+    storage: Storage,
+    options: InitOptions,
+) -> io::Result<()> {
     let dir = fs::canonicalize(dir)?;
     let config_path = dir.join(CONFIG_FILE);
     if config_path.exists() {
@@ -108,36 +1228,246 @@ fn init_cmd(dir: &Path, block_size_kb: u16, encrypted: bool, scrypt_log_n: u8) -
             format!("{} was already initialized", dir.display()),
         ));
     }
-    let config = {
-        let salt_hex = if encrypted {
+    let block_size_kb = block_size_kb.unwrap_or(match options.sync_friendly {
+        true => SYNC_FRIENDLY_BLOCK_SIZE_KB,
+        false => default_block_size_kb(),
+    });
+    let page_size = page_size(block_size_kb, cipher, true, HeaderVersion::LATEST);
+    if page_size > 0 && page_size < PageIntKv::min_page_size(Encoding::Varint) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("block size {} KB is too small", block_size_kb),
+        ));
+    }
+    if let Storage::Parity(dirs) = storage {
+        if page_size == 0 || dirs.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "parity needs blocks and at least 2 directories",
+            ));
+        }
+    }
+    let mut kdf_measured_ms = 0;
+    if let (Some(target_ms), Some(_)) = (kdf_target_ms, cipher) {
+        let max_memory = util::physical_memory().map_or(KDF_MAX_MEMORY, |m| m / 2);
+        let (log_n, elapsed) = calibrate_scrypt_log_n(
+            Duration::from_millis(target_ms),
+            default_scrypt_r(),
+            default_scrypt_p(),
+            max_memory.min(KDF_MAX_MEMORY),
+        )?;
+        eprintln!(
+            "Selected scrypt log_n {} ({} MB), deriving the key took {} ms",
+            log_n,
+            scrypt_memory(log_n, default_scrypt_r()) >> 20,
+            elapsed.as_millis()
+        );
+        scrypt_log_n = log_n;
+        kdf_measured_ms = elapsed.as_millis() as u64;
+    }
+    let mut config = {
+        let salt_hex = if cipher.is_some() {
             let salt: [u8; 32] = rand::random();
-            hex::encode(&salt)
+            hex::encode(salt)
         } else {
             String::new()
         };
         Config {
             salt_hex,
-            scrypt_log_n: scrypt_log_n,
+            scrypt_log_n,
             scrypt_r: default_scrypt_r(),
             scrypt_p: default_scrypt_p(),
             block_size_kb,
             cache_size_limit: default_cache_size_limit(),
+            cache_entries_limit: default_cache_entries_limit(),
+            pinned_size_limit: default_pinned_size_limit(),
+            background_flush_secs: 0,
+            background_flush_max_dirty_bytes: 0,
+            flush_delay_secs: default_flush_delay_secs(),
+            paranoid_checks: false,
+            fill_factor: default_fill_factor(),
+            sequential_allocation: options.sync_friendly || default_sequential_allocation(),
+            flush_threads: 0,
+            cipher: cipher.unwrap_or_default(),
+            rekey_salt_hex: String::new(),
+            mac_trailer: true,
+            kdf_params_honored: true,
+            wrapped_keys_hex: Vec::new(),
+            rekey_wrapped_key_hex: String::new(),
+            key_check_hex: String::new(),
+            kdf_measured_ms,
+            entry_header_version: HeaderVersion::LATEST.into(),
+            read_strategy: ReadStrategy::default(),
+            io_retry_attempts: default_io_retry_attempts(),
+            io_retry_delay_ms: default_io_retry_delay_ms(),
+            preallocate: default_preallocate(),
+            durability: Durability::default(),
+            keep_previous_generation: false,
+            segment_threshold_bytes: 0,
+            open_files: default_open_files(),
+            direct_io: false,
+            allow_external_changes: false,
+            reserved_space_mb: default_reserved_space_mb(),
+            low_space_warning_mb: default_low_space_warning_mb(),
+            file_naming: FileNaming::default(),
+            next_file_naming: None,
+            layout_version: LAYOUT_VERSION,
+            next_layout_version: None,
+            storage: match storage {
+                Storage::Sled => StorageEngine::Sled,
+                _ => StorageEngine::Files,
+            },
+            remote_url: String::new(),
+            remote_user: String::new(),
+            remote_password: String::new(),
+            remote_bearer_token: String::new(),
+            remote_ca_file: String::new(),
+            remote_timeout_secs: default_remote_timeout_secs(),
+            remote_retry_attempts: default_remote_retry_attempts(),
+            remote_retry_delay_ms: default_remote_retry_delay_ms(),
+            remote_connections: default_remote_connections(),
+            sftp_host: String::new(),
+            sftp_port: 0,
+            sftp_user: String::new(),
+            sftp_key_file: String::new(),
+            sftp_path: String::new(),
+            redis_url: String::new(),
+            redis_ttl_secs: 0,
+            mirror_dir: String::new(),
+            mirror_allow_degraded: false,
+            local_cache_dir: String::new(),
+            local_cache_size_mb: default_local_cache_size_mb(),
+            local_cache_write_through: false,
+            vault_id: new_vault_id(),
+            shards: Vec::new(),
+            next_shards: None,
+            online_move: false,
+            stripes: Vec::new(),
+            parity_dirs: Vec::new(),
+            stripe_width: default_stripe_width(),
+            block_device: String::new(),
+            append_only_log: matches!(storage, Storage::Log),
+            log_segment_size_mb: default_log_segment_size_mb(),
+            log_compaction_ratio: default_log_compaction_ratio(),
+            sync_friendly: options.sync_friendly,
+            compression: options.compress,
+            compression_level: default_compression_level(),
+            checksum: options.checksum,
+            quota_max_blocks: 0,
+            quota_max_mb: 0,
+            versions_kept: 0,
+            cow_snapshots: false,
+            throttle_read_kbps: 0,
+            throttle_write_kbps: 0,
+            throttle_burst_kb: 0,
+            control_address: String::new(),
+            layer_metrics: false,
+            metadata_encoding: Encoding::Varint,
+            format_version: upgrade::FORMAT_VERSION,
+            features: Vec::new(),
         }
     };
-    fs::write(
-        config_path,
-        serde_json::to_string_pretty(&config).unwrap().as_bytes(),
-    )?;
+    config.features = upgrade::required_features(&config);
+    if cipher.is_some() {
+        let pass = read_new_password("Password: ")?;
+        let kek = password_derive(&pass, &config.salt_hex, &scrypt_params(&config)?);
+        let key = Secret::new(rand::random::<[u8; 32]>());
+        config.wrapped_keys_hex = vec![hex::encode(wrap_key(&kek, &key))];
+    }
+    match storage {
+        Storage::BlockDevice(device) => {
+            let device = fs::canonicalize(device)?;
+            format_block_device(&device, &config)?;
+            config.block_device = device.display().to_string();
+        }
+        Storage::Files | Storage::Log => fs::create_dir(data_dir(&dir, &config)?)?,
+        Storage::Sled => sled_kv_from_dir(&dir, Lock::exclusive(0))?.flush()?,
+        Storage::Stripes(stripes) => {
+            fs::create_dir(data_dir(&dir, &config)?)?;
+            for stripe in stripes {
+                fs::create_dir_all(data_dir(&dir.join(stripe), &config)?)?;
+                config.stripes.push(stripe.display().to_string());
+            }
+        }
+        Storage::Parity(dirs) => {
+            fs::create_dir(data_dir(&dir, &config)?)?;
+            for member in dirs {
+                fs::create_dir_all(data_dir(&dir.join(member), &config)?)?;
+                config.parity_dirs.push(member.display().to_string());
+            }
+        }
+    }
+    save_config(&dir, &config)?;
 
     eprintln!("Initialized {}", dir.display());
     Ok(())
 }
 
-async fn serve_cmd(dir: &Path, address: &str) -> io::Result<()> {
-    let dir = fs::canonicalize(dir)?;
-    let kv = kv_from_dir(&dir)?;
-    let fs = IntKvFtpFs::new(kv);
-    tokio::task::spawn(flush_on_ctrl_c(fs.clone()));
+/// `ephemeral`: serve a vault in memory with this many bytes before
+/// spilling, instead of `dir`. `snapshot_dir`: where to write snapshots
+/// on SIGUSR2.
+async fn serve_cmd(
+    dir: &Path,
+    address: &str,
+    allow_core_dumps: bool,
+    ephemeral: Option<u64>,
+    snapshot_dir: Option<&Path>,
+    trace: Option<(&Path, bool)>,
+) -> io::Result<()> {
+    if !allow_core_dumps {
+        if let Err(e) = util::harden::disable_core_dumps() {
+            eprintln!("Warning: cannot disable core dumps: {}", e);
+        }
+    }
+    let (fs, name) = match ephemeral {
+        Some(mem_limit) => {
+            let kv = CappedMemIntKv::new(mem_limit)?;
+            tokio::task::spawn(discard_on_ctrl_c(kv.spill_dir()));
+            let page_size = page_size(default_block_size_kb(), None, false, HeaderVersion::LATEST);
+            let kv = PageIntKv::new_with_encoding(page_size, Box::new(kv), Encoding::Varint)?;
+            let kv = traced(Box::new(kv), trace, page_size)?;
+            let fs = IntKvFtpFs::new(kv).with_tree_encoding(Encoding::Varint);
+            (fs, "an ephemeral vault".to_string())
+        }
+        None => {
+            let dir = fs::canonicalize(dir)?;
+            let config = load_config(&dir)?;
+            let key = read_key(&config)?;
+            let kv = kv_from_dir_config(&dir, &config, key.as_deref(), Lock::exclusive(0))?;
+            let page_size = page_size(
+                config.block_size_kb,
+                key.as_ref().map(|_| config.cipher),
+                config.mac_trailer,
+                header_version(&config)?,
+            );
+            let fs = IntKvFtpFs::new(traced(kv, trace, page_size)?)
+                .with_tree_encoding(config.metadata_encoding)
+                .with_flush_delay(flush_delay(&config));
+            tokio::task::spawn(flush_on_ctrl_c(fs.clone()));
+            let served = Served {
+                fs: fs.clone(),
+                dir: dir.clone(),
+                key: Arc::new(key),
+                tracing: trace.is_some(),
+                moving: Default::default(),
+                active: Arc::new(Mutex::new(config.clone())),
+            };
+            if config.online_move {
+                served.resume_move();
+            }
+            reload_on_signal(served.clone())?;
+            if !config.control_address.is_empty() {
+                serve_control(&config.control_address, served).await?;
+            }
+            if let Some(snapshot_dir) = snapshot_dir {
+                fs::create_dir_all(snapshot_dir)?;
+                let snapshot_dir = fs::canonicalize(snapshot_dir)?;
+                let snapshots = snapshot_on_signal(fs.clone(), dir.clone(), config, snapshot_dir);
+                tokio::task::spawn(snapshots?);
+            }
+            (fs, dir.display().to_string())
+        }
+    };
 
     let logger = slog::Logger::root(slog::Drain::ignore_res(slog_stdlog::StdLog), slog::o!());
     let server = libunftp::Server::new(Box::new(move || fs.clone()))
@@ -145,18 +1475,374 @@ async fn serve_cmd(dir: &Path, address: &str) -> io::Result<()> {
         .passive_ports(50000..65535)
         .logger(logger);
 
-    eprintln!("Serving {} at ftp://{}", dir.display(), address);
-    server
-        .listen(address)
-        .await
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    eprintln!("Serving {} at ftp://{}", name, address);
+    server.listen(address).await.map_err(io::Error::other)?;
 
     Ok(())
 }
 
-async fn flush_on_ctrl_c(mut fs: IntKvFtpFs) {
-    while let Ok(_) = tokio::signal::ctrl_c().await {
-        eprintln!("Writing changes on Ctrl+C...");
+/// Delay of `IntKvFtpFs` flushing after changes. None if it flushes by
+/// itself.
+fn flush_delay(config: &Config) -> Option<Duration> {
+    match config.background_flush_secs > 0 && config.block_size_kb == 0 {
+        // BufferedIntKv is the top layer and flushes by itself.
+        // With blocks, PageIntKv still needs the timer to flush its pages.
+        true => None,
+        false => Some(Duration::from_secs(config.flush_delay_secs)),
+    }
+}
+
+/// Fields of the config `Served::reload` applies while serving. Others
+/// need a restart.
+const RELOADABLE_FIELDS: &[&str] = &[
+    "cache_size_limit",
+    "cache_entries_limit",
+    "pinned_size_limit",
+    "flush_delay_secs",
+    "quota_max_blocks",
+    "quota_max_mb",
+    "throttle_read_kbps",
+    "throttle_write_kbps",
+    "throttle_burst_kb",
+];
+
+/// A directory being served, changed by the control API.
+#[derive(Clone)]
+struct Served {
+    fs: IntKvFtpFs,
+    dir: PathBuf,
+    key: Arc<Option<Key>>,
+    tracing: bool,
+    /// Whether blocks of `migrate-storage --online` are being copied.
+    moving: Arc<AtomicBool>,
+    /// Config the storage was last opened with.
+    active: Arc<Mutex<Config>>,
+}
+
+impl Served {
+    /// Open the shards recorded by `migrate-storage --online` since serving
+    /// started, then copy blocks in background.
+    fn start_move(&self) -> io::Result<String> {
+        let config = load_config(&self.dir)?;
+        if !config.online_move {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no blocks are being moved online",
+            ));
+        }
+        if self.moving.swap(true, Ordering::AcqRel) {
+            return Ok("Blocks are being moved already".to_string());
+        }
+        if let Err(e) = self.reopen(&config) {
+            self.moving.store(false, Ordering::Release);
+            return Err(e);
+        }
+        self.spawn_copy();
+        Ok("Moving blocks".to_string())
+    }
+
+    /// Copy blocks of a move the storage was opened with.
+    fn resume_move(&self) {
+        if !self.moving.swap(true, Ordering::AcqRel) {
+            eprintln!("Resuming the previous move");
+            self.spawn_copy();
+        }
+    }
+
+    /// Change the bandwidth limits to those in `query`, like
+    /// "read_kbps=1024&write_kbps=0", in KB or sizes like "1M". Omitted
+    /// ones stay as they are.
+    fn set_throttle(&self, query: &str) -> io::Result<String> {
+        let mut config = load_config(&self.dir)?;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = size::parse_size_in(value, size::KIB)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            match name {
+                "read_kbps" => config.throttle_read_kbps = value,
+                "write_kbps" => config.throttle_write_kbps = value,
+                "burst_kb" => config.throttle_burst_kb = value,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown limit {}", name),
+                    ))
+                }
+            }
+        }
+        self.reopen(&config)?;
+        save_config(&self.dir, &config)?;
+        Ok(format!(
+            "Reads are limited to {} KB/s, writes to {} KB/s (0: no limit)",
+            config.throttle_read_kbps, config.throttle_write_kbps
+        ))
+    }
+
+    /// Apply changes of the config file to `RELOADABLE_FIELDS`. Other
+    /// changes are skipped. An invalid config changes nothing. Log a line
+    /// per change, and return them.
+    fn reload(&self) -> io::Result<String> {
+        match self.apply_config() {
+            Ok(lines) => {
+                for (level, line) in &lines {
+                    log::log!(*level, "{}", line);
+                }
+                let lines: Vec<String> = lines.into_iter().map(|(_, line)| line).collect();
+                Ok(lines.join("\n"))
+            }
+            Err(e) => {
+                log::error!("Cannot reload {}: {}", CONFIG_FILE, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_config(&self) -> io::Result<Vec<(log::Level, String)>> {
+        let config = load_config(&self.dir)?;
+        let active = self.active.lock().clone();
+        let (old, new) = match (
+            serde_json::to_value(&active)?,
+            serde_json::to_value(&config)?,
+        ) {
+            (serde_json::Value::Object(old), serde_json::Value::Object(new)) => (old, new),
+            _ => unreachable!("configs are objects"),
+        };
+        let mut applied = old.clone();
+        let mut lines = Vec::new();
+        for (name, value) in new
+            .iter()
+            .filter(|(name, value)| old.get(*name) != Some(value))
+        {
+            match RELOADABLE_FIELDS.contains(&name.as_str()) {
+                true => {
+                    let line = format!("Changed {} from {} to {}", name, old[name], value);
+                    lines.push((log::Level::Info, line));
+                    applied.insert(name.clone(), value.clone());
+                }
+                false => lines.push((
+                    log::Level::Warn,
+                    format!(
+                        "Skipped {}: cannot change while serving, restart to apply",
+                        name
+                    ),
+                )),
+            }
+        }
+        let applied: Config = serde_json::from_value(applied.into())?;
+        let changed = |names: &[&str]| names.iter().any(|&name| old[name] != new[name]);
+        let cache_fields = [
+            "cache_size_limit",
+            "cache_entries_limit",
+            "pinned_size_limit",
+        ];
+        let layer_fields: Vec<&str> = RELOADABLE_FIELDS
+            .iter()
+            .copied()
+            .filter(|name| *name != "flush_delay_secs" && !cache_fields.contains(name))
+            .collect();
+        if changed(&layer_fields) {
+            self.reopen(&applied)?;
+        } else if changed(&cache_fields) {
+            // Keep what is cached.
+            self.fs.set_cache_limits(cache_limits(&applied));
+        }
+        self.fs.set_flush_delay(flush_delay(&applied));
+        *self.active.lock() = applied;
+        if lines.is_empty() {
+            lines.push((log::Level::Info, format!("No changes in {}", CONFIG_FILE)));
+        }
+        Ok(lines)
+    }
+
+    fn reopen(&self, config: &Config) -> io::Result<()> {
+        if self.tracing {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "storage cannot change while tracing",
+            ));
+        }
+        let key = self.key.as_deref();
+        self.fs
+            .replace_kv(|| kv_from_dir_config(&self.dir, config, key, Lock::exclusive(0)))?;
+        *self.active.lock() = config.clone();
+        Ok(())
+    }
+
+    fn spawn_copy(&self) {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match this.copy() {
+                Ok(removed) => eprintln!(
+                    "Moved blocks to the new shards. Removed {} blocks from the old shards",
+                    removed
+                ),
+                Err(e) => eprintln!("Cannot move blocks: {}", e),
+            }
+            this.moving.store(false, Ordering::Release);
+        });
+    }
+
+    /// Copy blocks, then use the new shards alone. Return the number of
+    /// blocks removed from the old shards.
+    fn copy(&self) -> io::Result<usize> {
+        loop {
+            let stats = self.fs.compact_step(ONLINE_MOVE_BATCH)?;
+            match stats.get("migrate.remaining") {
+                Some(0) => break,
+                Some(_) => {}
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "storage is not being moved",
+                    ))
+                }
+            }
+        }
+        let mut config = load_config(&self.dir)?;
+        let mut old_dirs = Vec::new();
+        let key = self.key.as_deref();
+        self.fs.replace_kv(|| {
+            old_dirs = end_online_move(&self.dir, &mut config)?;
+            kv_from_dir_config(&self.dir, &config, key, Lock::exclusive(0))
+        })?;
+        clear_old_shards(&config, &old_dirs, Lock::exclusive(0))
+    }
+}
+
+/// Answer the control API at `address` in background. `POST
+/// /migrate-storage` starts copying blocks recorded by `migrate-storage
+/// --online`. `POST /throttle?read_kbps=N&write_kbps=N&burst_kb=N` changes
+/// bandwidth limits. `POST /reload` applies changes of the config, like
+/// SIGHUP. `GET /metrics` returns stats for Prometheus.
+async fn serve_control(address: &str, served: Served) -> io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    eprintln!("Control API at http://{}", address);
+    tokio::task::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::task::spawn(answer_control(stream, served.clone()));
+                }
+                Err(e) => log::warn!("Cannot accept a control connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn answer_control(mut stream: tokio::net::TcpStream, served: Served) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut words = head.split_whitespace();
+    let result = match (words.next(), words.next()) {
+        (Some("POST"), Some("/migrate-storage")) => {
+            match tokio::task::spawn_blocking(move || served.start_move()).await {
+                Ok(result) => result,
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+        (Some("POST"), Some(path)) if path.split('?').next() == Some("/throttle") => {
+            let query = path.split_once('?').map_or("", |(_, q)| q).to_string();
+            match tokio::task::spawn_blocking(move || served.set_throttle(&query)).await {
+                Ok(result) => result,
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+        (Some("POST"), Some("/reload")) => {
+            match tokio::task::spawn_blocking(move || served.reload()).await {
+                Ok(result) => result,
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+        (Some("GET"), Some("/metrics")) => {
+            let fs = served.fs;
+            match tokio::task::spawn_blocking(move || prometheus_text(&fs.stats())).await {
+                Ok(text) => Ok(text.trim_end().to_string()),
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+        _ => Err(io::Error::new(io::ErrorKind::NotFound, "unknown request")),
+    };
+    let (status, body) = match result {
+        Ok(body) => ("200 OK", body),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => ("404 Not Found", e.to_string()),
+        Err(e) => ("409 Conflict", e.to_string()),
+    };
+    let reply = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        body.len() + 1,
+        body
+    );
+    if let Err(e) = stream.write_all(reply.as_bytes()).await {
+        log::warn!("Cannot answer a control request: {}", e);
+    }
+}
+
+/// Send a request to the control API of `serve` at `address`. Return the
+/// body of the reply.
+fn control_request(address: &str, method: &str, path: &str) -> io::Result<String> {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(address).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot reach the control API at {}: {}", address, e),
+        )
+    })?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        method, path, address
+    )?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let (head, body) = reply.split_once("\r\n\r\n").unwrap_or((&reply, ""));
+    match head.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(body.to_string()),
+        Some(_) => Err(io::Error::other(format!(
+            "the server refused: {}",
+            body.trim_end()
+        ))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid reply from the control API",
+        )),
+    }
+}
+
+/// Add `TraceIntKv` on top if `trace` is set to a path, and whether to
+/// include data.
+fn traced(
+    kv: Box<dyn IntKv>,
+    trace: Option<(&Path, bool)>,
+    page_size: u64,
+) -> io::Result<Box<dyn IntKv>> {
+    match trace {
+        Some((path, full)) => {
+            eprintln!("Tracing operations to {}", path.display());
+            Ok(Box::new(TraceIntKv::create(kv, path, page_size, full)?))
+        }
+        None => Ok(kv),
+    }
+}
+
+fn replay_cmd(trace: &Path) -> io::Result<()> {
+    let count = replay(trace)?;
+    println!("Replayed {} operations without problems", count);
+    Ok(())
+}
+
+async fn flush_on_ctrl_c(mut fs: IntKvFtpFs) {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        eprintln!("Writing changes on Ctrl+C...");
         match fs.flush() {
             Ok(_) => {
                 eprintln!("Done. Exiting.");
@@ -167,54 +1853,3039 @@ async fn flush_on_ctrl_c(mut fs: IntKvFtpFs) {
     }
 }
 
-/// Construct the `IntKv` backend.
-fn kv_from_dir(dir: &Path) -> io::Result<Box<dyn IntKv>> {
-    let config_path = dir.join(CONFIG_FILE);
-    if !config_path.exists() {
+/// Reload the config of `served` on each SIGHUP, in background.
+#[cfg(unix)]
+fn reload_on_signal(served: Served) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signals = signal(SignalKind::hangup())?;
+    tokio::task::spawn(async move {
+        while signals.recv().await.is_some() {
+            let served = served.clone();
+            // `reload` logs the changes, or why it failed.
+            if let Err(e) = tokio::task::spawn_blocking(move || served.reload()).await {
+                log::error!("Cannot reload {}: {}", CONFIG_FILE, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_signal(_served: Served) -> io::Result<()> {
+    Ok(())
+}
+
+/// Return a task writing a snapshot of `dir` served by `fs` to a new
+/// subdirectory of `snapshot_dir` on each SIGUSR2.
+#[cfg(unix)]
+fn snapshot_on_signal(
+    fs: IntKvFtpFs,
+    dir: PathBuf,
+    config: Config,
+    snapshot_dir: PathBuf,
+) -> io::Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signals = signal(SignalKind::user_defined2())?;
+    Ok(async move {
+        while signals.recv().await.is_some() {
+            let (fs, dir, config) = (fs.clone(), dir.clone(), config.clone());
+            let dest = new_snapshot_path(&snapshot_dir);
+            let task = tokio::task::spawn_blocking(move || {
+                write_snapshot(&dir, &config, &dest, |data| fs.snapshot(data))
+            });
+            match task.await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("Cannot create a snapshot: {}", e),
+                Err(e) => eprintln!("Cannot create a snapshot: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn snapshot_on_signal(
+    _fs: IntKvFtpFs,
+    _dir: PathBuf,
+    _config: Config,
+    _snapshot_dir: PathBuf,
+) -> io::Result<std::future::Ready<()>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--snapshot-dir needs SIGUSR2, which this platform does not have",
+    ))
+}
+
+/// A path in `snapshot_dir` named by the time, not used yet.
+fn new_snapshot_path(snapshot_dir: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut path = snapshot_dir.join(secs.to_string());
+    for i in 1.. {
+        if !path.exists() {
+            break;
+        }
+        path = snapshot_dir.join(format!("{}-{}", secs, i));
+    }
+    path
+}
+
+/// `process::exit` skips destructors, so remove the spilled blocks here.
+async fn discard_on_ctrl_c(spill_dir: PathBuf) {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        eprintln!("Discarding the ephemeral vault on Ctrl+C.");
+        if let Err(e) = fs::remove_dir_all(&spill_dir) {
+            eprintln!("Cannot remove {}: {}", spill_dir.display(), e);
+        }
+        std::process::exit(0);
+    }
+}
+
+fn status_cmd(
+    dir: &Path,
+    deep: bool,
+    timings: bool,
+    ignore_unknown: bool,
+    lock: Lock,
+) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut config = load_config_with(&dir, ignore_unknown)?;
+    config.layer_metrics |= timings;
+    let (kv, page_size) =
+        buffered_kv_from_dir_config(&dir, &config, read_key(&config)?.as_deref(), lock)?;
+    if let Some(remote) = remote_location(&config) {
+        println!("Blocks are stored at {}", remote);
+    } else {
+        let kv = fs_kv_from_dir_config(&dir, &config, lock)?;
+        let (count, size) = kv.previous_generation_usage()?;
+        if count > 0 {
+            println!("Previous generation: {} blocks, {} bytes", count, size);
+        }
+        let space = util::disk_space(&dir)?;
+        println!(
+            "Disk space: {} bytes available of {}",
+            space.available, space.total
+        );
+    }
+    if page_size == 0 {
+        println!("Blocks are disabled");
+        if timings {
+            print_timings(&kv.stats());
+        }
+        return Ok(());
+    }
+    let kv = PageIntKv::new_with_encoding(page_size, kv, config.metadata_encoding)?;
+    let stats = kv.frag_stats(deep)?;
+    println!(
+        "Pages: {} data, {} meta, {} extent",
+        stats.data_pages, stats.meta_pages, stats.extent_pages
+    );
+    println!("Physical size: {} bytes", stats.physical_bytes);
+    println!("Used size: {} bytes", stats.used_bytes);
+    for (i, count) in stats.fill_histogram.iter().enumerate() {
+        println!("Pages {:>3}%-{:>3}% full: {}", i * 10, i * 10 + 10, count);
+    }
+    if let Some(n) = stats.longest_chain {
+        println!("Longest chain: {} pages", n);
+    }
+    if let Some(n) = stats.multi_page_entries {
+        println!("Entries using multiple pages: {}", n);
+    }
+    let pages = pages_changed_per_edit(stats.meta_pages);
+    println!(
+        "Changed on disk per flush: about {} bytes in {} blocks, editing a small file",
+        pages * config.block_size_kb as u64 * 1024,
+        pages
+    );
+    if timings {
+        print_timings(&kv.stats());
+    }
+    Ok(())
+}
+
+/// Print latencies recorded with `layer_metrics`, like "EncIntKv read
+/// p95 = 16 ms". Layers include the time of layers below.
+fn print_timings(stats: &Stats) {
+    for t in timings(stats) {
+        println!(
+            "{} {} p50 = {}, p95 = {}, p99 = {} ({} calls)",
+            t.label,
+            t.op,
+            format_micros(t.percentiles[0]),
+            format_micros(t.percentiles[1]),
+            format_micros(t.percentiles[2]),
+            t.count
+        );
+    }
+}
+
+fn format_micros(micros: u64) -> String {
+    match micros {
+        0..=999 => format!("{} us", micros),
+        1_000..=9_999_999 => format!("{} ms", micros / 1_000),
+        _ => format!("{} s", micros / 1_000_000),
+    }
+}
+
+/// Blocks a flush rewrites after editing a small file: the data pages of
+/// the file and of its folder, the meta pages covering both, and meta page
+/// 0 linking to them.
+fn pages_changed_per_edit(meta_pages: usize) -> u64 {
+    2 + meta_pages.clamp(1, 3) as u64
+}
+
+fn fsck_cmd(dir: &Path, repair: bool, ignore_unknown: bool, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config_with(&dir, ignore_unknown)?;
+    if !config.mirror_dir.is_empty() {
+        fsck_mirror(&dir, &config, repair, lock)?;
+    }
+    if !config.parity_dirs.is_empty() {
+        fsck_parity(&dir, &config, repair, lock)?;
+    }
+    let (kv, page_size) =
+        buffered_kv_from_dir_config(&dir, &config, read_key(&config)?.as_deref(), lock)?;
+    if page_size == 0 {
+        println!("Blocks are disabled");
+        if config.checksum.is_some() {
+            fsck_entries(&kv)?;
+        }
+        return Ok(());
+    }
+    let mut kv = PageIntKv::new_with_encoding(page_size, kv, config.metadata_encoding)?
+        .with_paranoid_checks(false);
+    let mut progress = Progress::new("fsck", "blocks", None);
+    let report =
+        kv.verify_full_with_progress(|done, total| progress.set(done as u64, total as u64));
+    progress.finish();
+    for problem in &report.problems {
+        println!("Problem: {}", problem);
+    }
+    let corrupted = kv.corrupted_pages();
+    for &page_index in &corrupted {
+        let entries = kv.salvage(page_index, repair)?;
+        println!(
+            "Block {} is corrupted. Affected entries: {:?}",
+            page_index, entries
+        );
+    }
+    if repair && !corrupted.is_empty() {
+        kv.flush()?;
+        println!("Dropped affected entries");
+        kv.verify()?;
+    }
+    let stats = kv.stats();
+    let unauthenticated = stats.get("enc.unauthenticated_entries").copied();
+    if let Some(n) = unauthenticated.filter(|&n| n > 0) {
+        println!("Blocks without a MAC: {}", n);
+    }
+    let direct_key = stats.get("enc.direct_key_entries").copied();
+    if let Some(n) = direct_key.filter(|&n| n > 0) {
+        println!("Blocks using the master key directly: {}", n);
+    }
+    let unchecked = stats.get("checksum.unchecked_entries").copied();
+    if let Some(n) = unchecked.filter(|&n| n > 0) {
+        println!("Blocks without a checksum: {}", n);
+    }
+    let unstamped = kv.unstamped_pages();
+    if unstamped > 0 {
+        println!("Blocks without a generation: {}", unstamped);
+    }
+    let kv = compressed_kv(&config, Box::new(kv));
+    let (orphaned, missing) = check_references(&kv)?;
+    if !orphaned.is_empty() {
+        println!("Entries not in any folder: {:?}", orphaned);
+    }
+    if !missing.is_empty() {
+        println!("Missing entries referred by folders: {:?}", missing);
+    }
+    if report.is_ok() && orphaned.is_empty() && missing.is_empty() {
+        println!("No problems found");
+    }
+    Ok(())
+}
+
+/// Read all entries to verify their checksums, without blocks.
+fn fsck_entries(kv: &dyn IntKv) -> io::Result<()> {
+    let keys = kv.keys()?;
+    let mut progress = Progress::new("fsck", "entries", Some(keys.len() as u64));
+    let mut problems = Vec::new();
+    for index in keys {
+        match kv.read(index) {
+            Ok(data) => progress.add(1, data.len() as u64),
+            Err(e) => {
+                problems.push(e);
+                progress.add(1, 0);
+            }
+        }
+    }
+    progress.finish();
+    for e in &problems {
+        println!("Problem: {}", e);
+    }
+    if let Some(n) = kv
+        .stats()
+        .get("checksum.unchecked_entries")
+        .filter(|&&n| n > 0)
+    {
+        println!("Entries without a checksum: {}", n);
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+    }
+    Ok(())
+}
+
+/// Compare the blocks of the directory and the mirror. Copy blocks missing
+/// on either side if `repair`.
+fn fsck_mirror(dir: &Path, config: &Config, repair: bool, lock: Lock) -> io::Result<()> {
+    let primary = primary_backend_from_dir_config(dir, config, lock)?;
+    let mut kv = mirror_kv_from_dir_config(dir, config, lock, primary)?;
+    if kv.is_degraded() {
+        println!(
+            "Mirror is unavailable. Blocks to copy later: {}",
+            kv.resync_len()
+        );
+        return Ok(());
+    }
+    let (to_mirror, from_mirror) = kv.differences()?;
+    if !to_mirror.is_empty() {
+        println!("Blocks to copy to the mirror: {}", to_mirror.len());
+    }
+    if !from_mirror.is_empty() {
+        println!("Blocks only in the mirror: {}", from_mirror.len());
+    }
+    if repair && !(to_mirror.is_empty() && from_mirror.is_empty()) {
+        let count = kv.repair()?;
+        println!(
+            "Copied {} blocks between the directory and the mirror",
+            count
+        );
+    }
+    Ok(())
+}
+
+/// Check that the parity matches the blocks. Rebuild empty directories,
+/// ex. replaced disks, and fix the parity if `repair`. Like RAID scrubs,
+/// parity is rewritten from the blocks, as a corrupted block cannot be
+/// told from corrupted parity.
+fn fsck_parity(dir: &Path, config: &Config, repair: bool, lock: Lock) -> io::Result<()> {
+    let mut kv = parity_kv_from_dir_config(dir, config, lock)?;
+    if let Some(&member) = kv.offline().first() {
+        println!(
+            "Parity directory {} is unavailable",
+            config.parity_dirs[member]
+        );
+        return Ok(());
+    }
+    for member in kv.empty_members()? {
+        println!("Parity directory {} is empty", config.parity_dirs[member]);
+        if repair {
+            let count = kv.repair(member)?;
+            println!("Rebuilt {} blocks", count);
+        } else {
+            return Ok(());
+        }
+    }
+    let mismatches = kv.mismatches()?;
+    if !mismatches.is_empty() {
+        println!("Blocks not matching their parity: {:?}", mismatches);
+        if repair {
+            let count = kv.resync(&mismatches)?;
+            println!("Updated the parity of {} blocks", count);
+        }
+    }
+    Ok(())
+}
+
+fn quick_fsck_cmd(dir: &Path, ignore_unknown: bool, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config_with(&dir, ignore_unknown)?;
+    let report = fs_kv_from_dir_config(&dir, &config, lock)?.open_report();
+    for (_, line) in describe_open_report(&report) {
+        println!("{}", line);
+    }
+    if !report.has_problems() {
+        println!("No problems found");
+    }
+    Ok(())
+}
+
+fn migrate_cmd(dir: &Path, dest: &Path, cipher: Cipher) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    if config.salt_hex.is_empty() {
         return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("{} was not initialized (try \"x79d8 init\")", dir.display()),
+            io::ErrorKind::InvalidInput,
+            format!("{} is not encrypted", dir.display()),
+        ));
+    }
+    fs::create_dir_all(dest)?;
+    let dest = fs::canonicalize(dest)?;
+    let config_path = dest.join(CONFIG_FILE);
+    if config_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} was already initialized", dest.display()),
+        ));
+    }
+    let page_size = page_size(
+        config.block_size_kb,
+        Some(cipher),
+        true,
+        HeaderVersion::LATEST,
+    );
+    if page_size > 0 && page_size < PageIntKv::min_page_size(Encoding::Varint) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("block size {} KB is too small", config.block_size_kb),
         ));
     }
 
-    let config: Config = {
-        let config_str = fs::read_to_string(config_path)?;
-        serde_json::from_str(&config_str)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    let key = read_key(&config)?;
+    let src = kv_from_dir_config(&dir, &config, key.as_deref(), Lock::read_only(0))?;
+    let config = Config {
+        cipher,
+        mac_trailer: true,
+        entry_header_version: HeaderVersion::LATEST.into(),
+        layout_version: LAYOUT_VERSION,
+        vault_id: new_vault_id(),
+        shards: Vec::new(),
+        next_shards: None,
+        online_move: false,
+        stripes: Vec::new(),
+        parity_dirs: Vec::new(),
+        block_device: String::new(),
+        metadata_encoding: Encoding::Varint,
+        format_version: upgrade::FORMAT_VERSION,
+        ..config
     };
+    let config = Config {
+        features: upgrade::required_features(&config),
+        ..config
+    };
+    match fs::create_dir(data_dir(&dest, &config)?) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let mut dst = kv_from_dir_config(&dest, &config, key.as_deref(), Lock::exclusive(0))?;
+    let keys = src.keys()?;
+    let mut progress = Progress::new("migrate", "entries", Some(keys.len() as u64));
+    let mut pending_bytes = 0;
+    for &index in &keys {
+        let data = src.read(index)?;
+        pending_bytes += data.len();
+        progress.add(1, data.len() as u64);
+        dst.write(index, data)?;
+        if pending_bytes >= MIGRATE_FLUSH_BYTES {
+            dst.flush()?;
+            pending_bytes = 0;
+        }
+    }
+    dst.flush()?;
+    progress.finish();
 
-    kv_from_dir_config(dir, &config)
+    // Write the config last. An incomplete copy is not usable.
+    save_config(&dest, &config)?;
+    eprintln!("Copied {} entries to {}", keys.len(), dest.display());
+    Ok(())
 }
 
-/// Construct the `IntKv` backend.
-fn kv_from_dir_config(dir: &Path, config: &Config) -> io::Result<Box<dyn IntKv>> {
-    let mut kv: Box<dyn IntKv> = { Box::new(FsIntKv::new(&dir)?) };
-    let mut page_overhead = 0;
-    if config.salt_hex.is_empty() {
-        log::info!("Encryption is disabled");
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotManifest {
+    source: PathBuf,
+    files: Vec<SnapshotFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotFile {
+    path: PathBuf,
+    /// Hard-linked to the file of the source. Copied otherwise.
+    linked: bool,
+}
+
+fn snapshot_cmd(dir: &Path, dest: &Path, verify: bool, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    let mut kv = fs_kv_from_dir_config(&dir, &config, lock)?;
+    let dest = write_snapshot(&dir, &config, dest, |data| kv.snapshot(data))?;
+    if verify {
+        fsck_cmd(&dest, false, false, Lock::read_only(0))?;
+    }
+    Ok(())
+}
+
+fn upgrade_cmd(dir: &Path, snapshot: bool, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut config = load_config(&dir)?;
+    if config.format_version >= upgrade::FORMAT_VERSION {
+        println!(
+            "{} is already at format version {}",
+            dir.display(),
+            config.format_version
+        );
+        return Ok(());
+    }
+    // Keep other processes out while migrating.
+    let mut kv = match remote_location(&config) {
+        None => Some(fs_kv_from_dir_config(&dir, &config, lock)?),
+        Some(_) => None,
+    };
+    if snapshot {
+        let dest = PathBuf::from(format!(
+            "{}.pre-upgrade-{}",
+            dir.display(),
+            config.format_version
+        ));
+        match kv.as_mut() {
+            // Left by an interrupted upgrade.
+            _ if dest.exists() => eprintln!("Keeping the snapshot {}", dest.display()),
+            Some(kv) => drop(write_snapshot(&dir, &config, &dest, |data| {
+                kv.snapshot(data)
+            })?),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "blocks on a server cannot be snapshotted (try --no-snapshot)",
+                ))
+            }
+        }
+    }
+    let applied = upgrade::upgrade(&dir, &mut config, upgrade::MIGRATIONS, |i, n, m| {
+        eprintln!(
+            "[{}/{}] Upgrading to format version {}: {}",
+            i + 1,
+            n,
+            m.version,
+            m.description
+        )
+    })?;
+    println!(
+        "Upgraded {} to format version {} ({} migrations)",
+        dir.display(),
+        config.format_version,
+        applied
+    );
+    Ok(())
+}
+
+fn cow_snapshot_cmd(
+    dir: &Path,
+    list: bool,
+    check: Option<u64>,
+    delete: Option<u64>,
+    lock: Lock,
+) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    if !config.cow_snapshots {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cow_snapshots is not set in the config",
+        ));
+    }
+    let below = Config {
+        cow_snapshots: false,
+        ..config.clone()
+    };
+    let key = read_key(&config)?;
+    let (kv, page_size) = buffered_kv_from_dir_config(&dir, &below, key.as_deref(), lock)?;
+    let mut kv = cow_kv(kv, page_size)?;
+    if list {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        for s in kv.snapshots() {
+            println!(
+                "Snapshot {}: taken {} minutes ago, {} blocks copied ({} bytes)",
+                s.id,
+                now.saturating_sub(s.taken) / 60,
+                s.copies,
+                s.copy_bytes
+            );
+        }
+    } else if let Some(id) = check {
+        check_cow_snapshot(&config, Box::new(kv.view(id)?), page_size)?;
+    } else if let Some(id) = delete {
+        let freed = kv.delete_snapshot(id)?;
+        println!("Deleted snapshot {}. Freed {} blocks", id, freed);
     } else {
-        let prompt = "Password: ";
-        let pass = rpassword::read_password_from_tty(Some(prompt)).unwrap();
-        let key = password_derive(&pass, &config);
-        // Use password encryption.
-        kv = Box::new(EncIntKv::from_key_kv(key, kv));
-        // Bytes per page is used by encryption header (IV count).
-        page_overhead = EncIntKv::iv_header_size() as u64;
+        let id = kv.take_snapshot()?;
+        println!("Took snapshot {}", id);
     }
+    Ok(())
+}
 
-    kv = Box::new(BufferedIntKv::new(kv).with_cache_size_limit(config.cache_size_limit));
-    if config.block_size_kb > 0 {
-        let block_size = (config.block_size_kb as u64) * 1024;
-        kv = Box::new(PageIntKv::new(block_size - page_overhead, kv)?);
+/// Check pages and folders of a snapshot, without changing it.
+fn check_cow_snapshot(config: &Config, view: Box<dyn IntKv>, page_size: u64) -> io::Result<()> {
+    let kv = match page_size {
+        0 => view,
+        _ => {
+            let kv = PageIntKv::new_with_encoding(page_size, view, config.metadata_encoding)?
+                .with_paranoid_checks(false);
+            let report = kv.verify_full();
+            for problem in &report.problems {
+                println!("Problem: {}", problem);
+            }
+            if !report.is_ok() {
+                return Ok(());
+            }
+            Box::new(kv)
+        }
+    };
+    let (orphaned, missing) = check_references(&compressed_kv(config, kv))?;
+    if !orphaned.is_empty() {
+        println!("Entries not in any folder: {:?}", orphaned);
     }
-    Ok(kv)
+    if !missing.is_empty() {
+        println!("Missing entries referred by folders: {:?}", missing);
+    }
+    if orphaned.is_empty() && missing.is_empty() {
+        println!("No problems found");
+    }
+    Ok(())
 }
 
-/// Derive key from password.
-fn password_derive(password: &str, config: &Config) -> [u8; 32] {
-    let params = ScryptParams::recommended();
-    let salt = hex::decode(&config.salt_hex).unwrap();
-    let mut output = [0u8; 32];
-    scrypt::scrypt(password.as_bytes(), &salt, &params, &mut output).unwrap();
-    output
+/// Create the directory `dest` as a copy of `dir` with `config`. `copy`
+/// copies the blocks to the given data directory, like `IntKv::snapshot`.
+/// Return `dest` canonicalized.
+fn write_snapshot(
+    dir: &Path,
+    config: &Config,
+    dest: &Path,
+    copy: impl FnOnce(&Path) -> io::Result<Vec<(PathBuf, bool)>>,
+) -> io::Result<PathBuf> {
+    if dest.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        ));
+    }
+    let data = data_dir(dest, config)?;
+    if data != dest {
+        fs::create_dir(dest)?;
+    }
+    let files = copy(&data)?;
+    let prefix = data.strip_prefix(dest).unwrap().to_path_buf();
+    let dest = fs::canonicalize(dest)?;
+    let linked = files.iter().filter(|(_, linked)| *linked).count();
+    let manifest = SnapshotManifest {
+        source: dir.to_path_buf(),
+        files: files
+            .into_iter()
+            .map(|(path, linked)| SnapshotFile {
+                path: prefix.join(path),
+                linked,
+            })
+            .collect(),
+    };
+    fs::write(
+        dest.join(SNAPSHOT_FILE),
+        serde_json::to_string_pretty(&manifest).unwrap().as_bytes(),
+    )?;
+    // Write the config last. An incomplete copy is not usable.
+    save_config(&dest, config)?;
+    eprintln!(
+        "Created snapshot {} of {} ({} files linked, {} copied)",
+        dest.display(),
+        dir.display(),
+        linked,
+        manifest.files.len() - linked
+    );
+    Ok(dest)
+}
+
+fn rekey_cmd(dir: &Path, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    if config.salt_hex.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not encrypted", dir.display()),
+        ));
+    }
+    let pass = read_password("Old password: ");
+    let (old_key, _) = unlock(&config, &pass)?;
+    let pass = if config.rekey_salt_hex.is_empty() {
+        read_new_password("New password: ")?
+    } else {
+        eprintln!("Resuming the previous password change");
+        read_password("New password: ")
+    };
+    rekey_dir(&dir, config, &old_key, &pass, lock)
+}
+
+/// Re-encrypt entries of `dir` with a new key wrapped by `pass`. The config
+/// switches to it once every entry is re-encrypted.
+fn rekey_dir(
+    dir: &Path,
+    mut config: Config,
+    old_key: &Key,
+    pass: &[u8],
+    lock: Lock,
+) -> io::Result<()> {
+    // The new key is always wrapped using the configured scrypt parameters.
+    let params = scrypt_params(&Config {
+        kdf_params_honored: true,
+        ..config.clone()
+    })?;
+    let key = if config.rekey_salt_hex.is_empty() {
+        let salt: [u8; 32] = rand::random();
+        config.rekey_salt_hex = hex::encode(salt);
+        let kek = password_derive(pass, &config.rekey_salt_hex, &params);
+        let key = Secret::new(rand::random::<[u8; 32]>());
+        config.rekey_wrapped_key_hex = hex::encode(wrap_key(&kek, &key));
+        save_config(dir, &config)?;
+        key
+    } else {
+        let kek = password_derive(pass, &config.rekey_salt_hex, &params);
+        match unwrap_hex(&kek, &config.rekey_wrapped_key_hex)? {
+            Some(key) => key,
+            None => return Err(wrong_password()),
+        }
+    };
+
+    // `unlock` verified the old key against the wrapped keys or the check
+    // value, if any. Otherwise, blocks of metadata only parse with the right
+    // key.
+    let mut checked = !config.wrapped_keys_hex.is_empty() || !config.key_check_hex.is_empty();
+    if !checked && config.block_size_kb > 0 {
+        let kv = kv_from_dir_config(dir, &config, Some(old_key), lock)?;
+        checked = !kv.keys()?.is_empty();
+    }
+    let mut enc = raw_kv_from_dir_config(dir, &config, &key, lock)?
+        .with_old_key(**old_key)
+        .with_old_key_checked(checked);
+    let mut progress = Progress::new("rekey", "entries", None);
+    enc.rekey_all(|done, total| progress.set(done as u64, total as u64))?;
+    progress.finish();
+    drop(enc);
+
+    config.salt_hex = std::mem::take(&mut config.rekey_salt_hex);
+    config.wrapped_keys_hex = vec![std::mem::take(&mut config.rekey_wrapped_key_hex)];
+    config.kdf_params_honored = true;
+    save_config(dir, &config)?;
+    eprintln!("Changed password of {}", dir.display());
+    Ok(())
+}
+
+fn passwd_cmd(dir: &Path, add: bool) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut config = load_config(&dir)?;
+    if config.salt_hex.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not encrypted", dir.display()),
+        ));
+    }
+    check_not_rekeying(&config)?;
+    if config.wrapped_keys_hex.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} derives its key from the password (try \"x79d8 rekey\")",
+                dir.display()
+            ),
+        ));
+    }
+    let pass = read_password("Password: ");
+    let (key, wrapped_index) = unlock(&config, &pass)?;
+    let pass = read_new_password("New password: ")?;
+    let kek = password_derive(&pass, &config.salt_hex, &scrypt_params(&config)?);
+    let wrapped = hex::encode(wrap_key(&kek, &key));
+    match wrapped_index {
+        Some(i) if !add => config.wrapped_keys_hex[i] = wrapped,
+        _ => config.wrapped_keys_hex.push(wrapped),
+    }
+    save_config(&dir, &config)?;
+    if add {
+        eprintln!("Added a password to {}", dir.display());
+    } else {
+        eprintln!("Changed password of {}", dir.display());
+    }
+    Ok(())
+}
+
+/// Open the directory up to `VersionedIntKv`, to reach older values of
+/// blocks. Return it with the page size.
+fn versioned_kv_from_dir(dir: &Path, lock: Lock) -> io::Result<(VersionedIntKv, u64)> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    if config.versions_kept == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "older values of blocks are not kept (set versions_kept in the config)",
+        ));
+    }
+    let key = read_key(&config)?;
+    let unversioned = Config {
+        versions_kept: 0,
+        ..config.clone()
+    };
+    let (kv, page_size) = buffered_kv_from_dir_config(&dir, &unversioned, key.as_deref(), lock)?;
+    Ok((versioned_kv(&config, kv, page_size)?, page_size))
+}
+
+/// Restore blocks as of `flushes` flushes with changes before the last
+/// one, from older values kept by `VersionedIntKv`.
+fn rollback_versions_cmd(dir: &Path, flushes: u64, lock: Lock) -> io::Result<()> {
+    let (mut kv, page_size) = versioned_kv_from_dir(dir, lock)?;
+    let encoding = load_config(&fs::canonicalize(dir)?)?.metadata_encoding;
+    if flushes > kv.generation() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("only {} flushes with changes are recorded", kv.generation()),
+        ));
+    }
+    let mut indexes: BTreeSet<usize> = kv.keys()?.into_iter().collect();
+    indexes.extend(kv.versioned_keys());
+    // Read all older values first, so nothing changes if one is missing.
+    let mut changes = Vec::new();
+    for index in indexes {
+        match kv.read_version(index, flushes) {
+            Ok(data) => {
+                if kv.read(index).ok().as_ref() != Some(&data) {
+                    changes.push((index, Some(data)));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if kv.has(index)? {
+                    changes.push((index, None));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let restored = changes.len();
+    for (index, data) in changes {
+        match data {
+            Some(data) => kv.write(index, data)?,
+            None => kv.remove(index)?,
+        }
+    }
+    kv.flush()?;
+    println!("Restored {} blocks as of {} flushes ago", restored, flushes);
+    if page_size > 0
+        && !PageIntKv::new_with_encoding(page_size, Box::new(kv), encoding)?
+            .verify_full()
+            .is_ok()
+    {
+        println!("Problems remain. Run fsck for details");
+    }
+    Ok(())
+}
+
+/// Drop older values of removed blocks kept by `VersionedIntKv`.
+fn prune_versions_cmd(dir: &Path, lock: Lock) -> io::Result<()> {
+    let (mut kv, _) = versioned_kv_from_dir(dir, lock)?;
+    let count = kv.prune()?;
+    kv.flush()?;
+    println!("Dropped {} older values of removed blocks", count);
+    Ok(())
+}
+
+fn rollback_cmd(dir: &Path, force: bool, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    let key = read_key(&config)?;
+    let verify = || -> io::Result<bool> {
+        let (kv, page_size) = buffered_kv_from_dir_config(&dir, &config, key.as_deref(), lock)?;
+        if page_size == 0 {
+            return Ok(true);
+        }
+        Ok(
+            match PageIntKv::new_with_encoding(page_size, kv, config.metadata_encoding) {
+                Ok(kv) => kv.verify_full().is_ok(),
+                Err(_) => false,
+            },
+        )
+    };
+    if !force && verify()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no problems found (use --force to roll back anyway)",
+        ));
+    }
+    let restored = fs_kv_from_dir_config(&dir, &config, lock)?.rollback()?;
+    println!("Restored {} blocks", restored);
+    if !verify()? {
+        println!("Problems remain. Run fsck for details");
+    }
+    Ok(())
+}
+
+fn migrate_layout_cmd(dir: &Path, naming: Option<FileNaming>, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut config = load_config(&dir)?;
+    let naming = match (config.next_file_naming.clone(), naming) {
+        (Some(next), Some(naming)) if next != naming => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "renaming is incomplete (run migrate-layout without naming options)",
+            ));
+        }
+        (Some(next), _) => {
+            eprintln!("Resuming the previous renaming");
+            Some(next)
+        }
+        (None, naming) => naming.filter(|n| n != &config.file_naming),
+    };
+    let open = |config: &Config| {
+        let data = data_dir(&dir, config)?;
+        lock.open(&dir, &data, &config.file_naming, file_size(config))
+    };
+    let mut kv = open(&config)?;
+    let moved = kv.migrate_layout()?;
+    println!("Moved {} blocks to subdirectories", moved);
+    if config.layout_version < LAYOUT_VERSION {
+        if config.next_layout_version.is_some() {
+            eprintln!("Resuming the previous move");
+        }
+        // Other commands refuse the directory until the move completes.
+        config.next_layout_version = Some(LAYOUT_VERSION);
+        save_config(&dir, &config)?;
+        let moved = kv.move_into(&dir.join(DATA_DIR))?;
+        config.layout_version = LAYOUT_VERSION;
+        config.next_layout_version = None;
+        save_config(&dir, &config)?;
+        println!("Moved {} entries to {}", moved, DATA_DIR);
+        kv = open(&config)?;
+    }
+    if let Some(naming) = naming {
+        naming.validate()?;
+        // Files using either naming are found if interrupted.
+        config.next_file_naming = Some(naming.clone());
+        save_config(&dir, &config)?;
+        let mut progress = Progress::new("migrate-layout", "blocks", None);
+        let renamed = kv.migrate_naming(naming.clone(), |done, total| {
+            progress.set(done as u64, total as u64)
+        })?;
+        progress.finish();
+        config.file_naming = naming;
+        config.next_file_naming = None;
+        save_config(&dir, &config)?;
+        println!("Renamed {} blocks", renamed);
+    }
+    Ok(())
+}
+
+fn migrate_storage_cmd(dir: &Path, shards: &[String], online: bool, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut config = load_config(&dir)?;
+    if config.next_shards.is_some() && config.online_move != online {
+        let message = match online {
+            true => "moving blocks is incomplete (run migrate-storage without --online)",
+            false => "moving blocks is incomplete (run migrate-storage --online)",
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    if !config.remote_url.is_empty() || !config.sftp_host.is_empty() || !config.redis_url.is_empty()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "blocks on a server cannot be sharded",
+        ));
+    }
+    if !config.block_device.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "blocks on a block device cannot be sharded",
+        ));
+    }
+    if config.append_only_log {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "blocks in log segments cannot be sharded",
+        ));
+    }
+    if config.storage != StorageEngine::Files {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "blocks in a sled database cannot be sharded (try \"migrate-storage --storage files\")",
+        ));
+    }
+    let target = match (config.next_shards.clone(), shards) {
+        (Some(next), shards) if !shards.is_empty() && next != shards => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "moving blocks is incomplete (run migrate-storage with the same shards)",
+            ));
+        }
+        (Some(next), _) => {
+            eprintln!("Resuming the previous move");
+            next
+        }
+        (None, []) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no shards given",
+            ))
+        }
+        (None, shards) => shards.to_vec(),
+    };
+    // "." alone is the directory itself, not sharded.
+    let target = match target.as_slice() {
+        [only] if only == "." => Vec::new(),
+        _ => target,
+    };
+    if config.vault_id.is_empty() {
+        config.vault_id = new_vault_id();
+        save_config(&dir, &config)?;
+    }
+
+    // Directories of shards in order, and each distinct directory once.
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let mut positions = |shards: &[String]| -> io::Result<Vec<usize>> {
+        let shards = match shards {
+            [] => vec![".".to_string()],
+            shards => shards.to_vec(),
+        };
+        let mut positions = Vec::new();
+        for shard in shards {
+            let path = fs::canonicalize(dir.join(&shard))
+                .map_err(|e| io::Error::new(e.kind(), format!("shard {}: {}", shard, e)))?;
+            let position = match dirs.iter().position(|d| d == &path) {
+                Some(position) => position,
+                None => {
+                    dirs.push(path);
+                    dirs.len() - 1
+                }
+            };
+            if positions.contains(&position) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("shard {} is given twice", shard),
+                ));
+            }
+            positions.push(position);
+        }
+        Ok(positions)
+    };
+    let old = positions(&config.shards)?;
+    let new = positions(&target)?;
+    if old == new && config.next_shards.is_none() {
+        println!("Blocks are already stored there");
+        return Ok(());
+    }
+    if online {
+        if new.iter().any(|i| old.contains(i)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "moving blocks online needs shards not used now",
+            ));
+        }
+        let new_dirs: Vec<PathBuf> = new.iter().map(|&i| dirs[i].clone()).collect();
+        return migrate_storage_online(&dir, config, target, &new_dirs, lock);
+    }
+
+    let local = local_config(&config);
+    let mut kvs = Vec::new();
+    for (i, path) in dirs.iter().enumerate() {
+        // Not the root, in case it is where a drive is mounted.
+        fs::create_dir_all(data_dir(path, &local)?)?;
+        let kv = fs_kv_from_dir_config(path, &local, lock)?;
+        let marker = fs::read_to_string(path.join(SHARD_FILE)).ok();
+        let vault_id = marker.as_deref().and_then(|m| m.split_whitespace().next());
+        let known = match vault_id {
+            Some(id) => id == config.vault_id,
+            // Blocks of the directory itself, or an empty new shard.
+            None => (config.shards.is_empty() && old == [i]) || kv.keys()?.is_empty(),
+        };
+        if !known {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has blocks of another directory", path.display()),
+            ));
+        }
+        kvs.push(kv);
+    }
+    if config.next_shards.is_none() {
+        // Other commands refuse the directory until the move completes.
+        config.next_shards = Some(target.clone());
+        save_config(&dir, &config)?;
+    }
+
+    // Drop copies left by an interrupted move. The old layout is intact.
+    remove_unowned(&mut kvs, &old)?;
+    let mut keys = Vec::new();
+    for kv in &kvs {
+        keys.push(kv.keys()?);
+    }
+    let total = keys.iter().map(|k| k.len() as u64).sum();
+    let mut progress = Progress::new("migrate-storage", "blocks", Some(total));
+    let mut moved = 0;
+    let mut pending_bytes = 0;
+    for (from, keys) in keys.into_iter().enumerate() {
+        for index in keys {
+            let to = new[ShardedIntKv::shard_of(index, new.len())];
+            if to == from {
+                progress.add(1, 0);
+                continue;
+            }
+            let data = kvs[from].read(index)?;
+            pending_bytes += data.len();
+            progress.add(1, data.len() as u64);
+            kvs[to].write(index, data)?;
+            moved += 1;
+            if pending_bytes >= MIGRATE_FLUSH_BYTES {
+                kvs[to].flush()?;
+                pending_bytes = 0;
+            }
+        }
+    }
+    for kv in &mut kvs {
+        kv.flush()?;
+    }
+    progress.finish();
+    if !target.is_empty() {
+        for (position, &i) in new.iter().enumerate() {
+            let marker = shard_marker(&config.vault_id, position, new.len());
+            fs::write(dirs[i].join(SHARD_FILE), marker)?;
+        }
+    }
+    config.shards = target;
+    config.next_shards = None;
+    save_config(&dir, &config)?;
+    println!("Moved {} blocks", moved);
+
+    let removed = remove_unowned(&mut kvs, &new)?;
+    for (i, path) in dirs.iter().enumerate() {
+        if config.shards.is_empty() || !new.contains(&i) {
+            match fs::remove_file(path.join(SHARD_FILE)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    println!("Removed {} moved blocks from their old shards", removed);
+    Ok(())
+}
+
+/// Move blocks between files and a sled database. The config switches to
+/// `storage` once all blocks are copied, then they are removed from the
+/// old storage. Running it again removes copies an interrupted run left.
+fn migrate_storage_engine_cmd(dir: &Path, storage: StorageEngine, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut config = load_config(&dir)?;
+    if !config.stripes.is_empty() || !config.parity_dirs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "striped blocks cannot be moved to another storage",
+        ));
+    }
+    let files_config = Config {
+        storage: StorageEngine::Files,
+        ..config.clone()
+    };
+    let open = |storage| -> io::Result<Box<dyn IntKv>> {
+        match storage {
+            StorageEngine::Files => {
+                fs::create_dir_all(data_dir(&dir, &files_config)?)?;
+                Ok(Box::new(fs_kv_from_dir_config(&dir, &files_config, lock)?))
+            }
+            StorageEngine::Sled => sled_kv_from_dir(&dir, lock),
+        }
+    };
+    let (other, other_path) = match storage {
+        StorageEngine::Files => (StorageEngine::Sled, dir.join(SLED_DIR)),
+        StorageEngine::Sled => (StorageEngine::Files, data_dir(&dir, &files_config)?),
+    };
+    if config.storage == storage && !other_path.exists() {
+        println!("Blocks are already stored there");
+        return Ok(());
+    }
+
+    let mut old = open(other)?;
+    let moving = config.storage == other;
+    if moving {
+        let mut new = open(storage)?;
+        let keys = old.keys()?;
+        // Drop copies left by an interrupted move, which may be outdated.
+        let known: BTreeSet<usize> = keys.iter().copied().collect();
+        for index in new.keys()? {
+            if !known.contains(&index) {
+                new.remove(index)?;
+            }
+        }
+        let mut progress = Progress::new("migrate-storage", "blocks", Some(keys.len() as u64));
+        let mut pending_bytes = 0;
+        for &index in &keys {
+            let data = old.read(index)?;
+            pending_bytes += data.len();
+            progress.add(1, data.len() as u64);
+            new.write(index, data)?;
+            if pending_bytes >= MIGRATE_FLUSH_BYTES {
+                new.flush()?;
+                pending_bytes = 0;
+            }
+        }
+        new.flush()?;
+        progress.finish();
+        config.storage = storage;
+        save_config(&dir, &config)?;
+        println!("Moved {} blocks", keys.len());
+    }
+
+    let keys = old.keys()?;
+    for &index in &keys {
+        old.remove(index)?;
+    }
+    old.flush()?;
+    drop(old);
+    if other == StorageEngine::Sled {
+        fs::remove_dir_all(&other_path)?;
+    }
+    match (moving, keys.len()) {
+        (false, 0) => println!("Blocks are already stored there"),
+        (_, n) => println!("Removed {} blocks from the old storage", n),
+    }
+    Ok(())
+}
+
+/// Start moving blocks to `target` in `new_dirs` with `MigrateIntKv`, or
+/// resume. Copy them here, or ask the server serving the directory to.
+fn migrate_storage_online(
+    dir: &Path,
+    mut config: Config,
+    target: Vec<String>,
+    new_dirs: &[PathBuf],
+    lock: Lock,
+) -> io::Result<()> {
+    if config.next_shards.is_none() {
+        let local = local_config(&config);
+        for path in new_dirs {
+            fs::create_dir_all(data_dir(path, &local)?)?;
+            if !fs_kv_from_dir_config(path, &local, lock)?
+                .keys()?
+                .is_empty()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has blocks already", path.display()),
+                ));
+            }
+        }
+        if !target.is_empty() {
+            for (position, path) in new_dirs.iter().enumerate() {
+                let marker = shard_marker(&config.vault_id, position, new_dirs.len());
+                fs::write(path.join(SHARD_FILE), marker)?;
+            }
+        }
+        config.next_shards = Some(target);
+        config.online_move = true;
+        save_config(dir, &config)?;
+    } else {
+        eprintln!("Resuming the previous move");
+    }
+
+    let mut kv = match migrate_kv_from_dir_config(dir, &config, lock) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            if config.control_address.is_empty() {
+                println!("Blocks will be moved when the directory is served again");
+                return Ok(());
+            }
+            let reply = control_request(&config.control_address, "POST", "/migrate-storage")?;
+            println!("{}", reply.trim_end());
+            return Ok(());
+        }
+        result => result?,
+    };
+    let total = kv.remaining()? as u64;
+    let mut progress = Progress::new("migrate-storage", "blocks", Some(total));
+    while !kv.is_drained() {
+        kv.drain(ONLINE_MOVE_BATCH)?;
+        progress.set(total - kv.remaining()? as u64, total);
+    }
+    progress.finish();
+    println!("Copied {} blocks", kv.stats()["migrate.copied"]);
+    drop(kv);
+    let old_dirs = end_online_move(dir, &mut config)?;
+    let removed = clear_old_shards(&config, &old_dirs, lock)?;
+    println!("Removed {} moved blocks from their old shards", removed);
+    Ok(())
+}
+
+/// Blocks copied by each step of `migrate-storage --online`.
+const ONLINE_MOVE_BATCH: usize = 64;
+
+/// Use the new shards of a drained online move alone. Return the old
+/// shard directories.
+fn end_online_move(dir: &Path, config: &mut Config) -> io::Result<Vec<PathBuf>> {
+    let old_dirs: Vec<PathBuf> = match config.shards.as_slice() {
+        [] => vec![dir.to_path_buf()],
+        shards => shards.iter().map(|shard| dir.join(shard)).collect(),
+    };
+    config.shards = config.next_shards.take().unwrap_or_default();
+    config.online_move = false;
+    save_config(dir, config)?;
+    match fs::remove_file(dir.join(MIGRATE_CURSOR_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    Ok(old_dirs)
+}
+
+/// Remove the blocks and markers of shards left by an online move. Return
+/// the number of blocks removed.
+fn clear_old_shards(config: &Config, old_dirs: &[PathBuf], lock: Lock) -> io::Result<usize> {
+    let local = local_config(config);
+    let mut removed = 0;
+    for path in old_dirs {
+        let mut kv = fs_kv_from_dir_config(path, &local, lock)?;
+        for index in kv.keys()? {
+            kv.remove(index)?;
+            removed += 1;
+        }
+        kv.flush()?;
+        match fs::remove_file(path.join(SHARD_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove blocks from `kvs` that are not theirs by `positions`, the index
+/// in `kvs` of each shard. Return the number removed.
+fn remove_unowned(kvs: &mut [FsIntKv], positions: &[usize]) -> io::Result<usize> {
+    let mut removed = 0;
+    for (i, kv) in kvs.iter_mut().enumerate() {
+        for index in kv.keys()? {
+            if positions[ShardedIntKv::shard_of(index, positions.len())] != i {
+                kv.remove(index)?;
+                removed += 1;
+            }
+        }
+        kv.flush()?;
+    }
+    Ok(removed)
+}
+
+fn shard_marker(vault_id: &str, position: usize, count: usize) -> String {
+    format!("{} {} {}\n", vault_id, position, count)
+}
+
+fn new_vault_id() -> String {
+    let id: [u8; 16] = rand::random();
+    hex::encode(id)
+}
+
+fn audit_iv_cmd(dir: &Path, lock: Lock) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let config = load_config(&dir)?;
+    let key = match read_key(&config)? {
+        Some(key) => key,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not encrypted", dir.display()),
+            ))
+        }
+    };
+    let audit = raw_kv_from_dir_config(&dir, &config, &key, lock)?.audit_iv()?;
+    println!("Scanned {} blocks", audit.entries);
+    println!("IV collisions: {}", audit.iv_collisions.len());
+    for (a, b) in &audit.iv_collisions {
+        println!("  Blocks {} and {}", a, b);
+    }
+    println!("Blocks sharing a count: {}", audit.shared_counts);
+    println!(
+        "Blocks with a count from the weak rng: {}",
+        audit.weak_counts
+    );
+    if audit.affected() {
+        println!("Affected: IVs might have been reused. Run \"x79d8 rekey\" to re-encrypt all blocks with a new key.");
+    } else {
+        println!("Not affected");
+    }
+    Ok(())
+}
+
+fn bench_cmd(
+    block_size_kb: u16,
+    blocks: usize,
+    dir: Option<&Path>,
+    stripe_dirs: &[PathBuf],
+) -> io::Result<()> {
+    let block_size = block_size_kb as usize * 1024;
+    let total_mb = (block_size * blocks) as f64 / (1 << 20) as f64;
+    let items: Vec<(usize, Bytes)> = (0..blocks)
+        .map(|i| (i, vec![i as u8; block_size].into()))
+        .collect();
+    let indexes: Vec<usize> = (0..blocks).collect();
+    let mut thread_counts = vec![1, util::default_threads()];
+    thread_counts.dedup();
+    for &cipher in &[Cipher::Aes256Cfb, Cipher::Aes256Gcm] {
+        for &threads in &thread_counts {
+            let mut kv = EncIntKv::from_key_kv(rand::random(), Box::new(MemIntKv::new()))
+                .with_cipher(cipher)
+                .with_header_version(HeaderVersion::LATEST)
+                .with_threads(threads);
+            let start = Instant::now();
+            let (_, result) = kv.write_batch(items.clone());
+            result?;
+            let encrypt = start.elapsed();
+            let start = Instant::now();
+            for result in kv.read_batch(&indexes) {
+                result?;
+            }
+            let decrypt = start.elapsed();
+            println!(
+                "{:?}, threads {}: encrypt {:.1} MB/s, decrypt {:.1} MB/s",
+                cipher,
+                threads,
+                total_mb / encrypt.as_secs_f64(),
+                total_mb / decrypt.as_secs_f64(),
+            );
+        }
+    }
+
+    // Text-like entries: random words from a small vocabulary.
+    let text_items: Vec<(usize, Bytes)> = {
+        use rand::{seq::SliceRandom, SeedableRng};
+        let mut rng = rand_chacha::ChaChaRng::seed_from_u64(0);
+        let words: Vec<String> = (0..500).map(|i| format!("w{}", i * 7919 % 1000)).collect();
+        let mut text = Vec::with_capacity(block_size * blocks);
+        while text.len() < block_size * blocks {
+            text.extend_from_slice(words.choose(&mut rng).unwrap().as_bytes());
+            text.push(if text.len() % 71 < 6 { b'\n' } else { b' ' });
+        }
+        let text = Bytes::from(text);
+        (0..blocks)
+            .map(|i| (i, text.slice(i * block_size..(i + 1) * block_size)))
+            .collect()
+    };
+    for &level in &[1, CompressIntKv::DEFAULT_LEVEL, 9] {
+        let mut kv = CompressIntKv::new(Box::new(MemIntKv::new())).with_level(level);
+        let start = Instant::now();
+        let (_, result) = kv.write_batch(text_items.clone());
+        result?;
+        let compress = start.elapsed();
+        let start = Instant::now();
+        for result in kv.read_batch(&indexes) {
+            result?;
+        }
+        let decompress = start.elapsed();
+        let stats = kv.stats();
+        println!(
+            "Compression level {}, text: {:.1}% of the size, compress {:.1} MB/s, decompress {:.1} MB/s",
+            level,
+            stats["compress.stored_bytes"] as f64 * 100.0 / stats["compress.raw_bytes"] as f64,
+            total_mb / compress.as_secs_f64(),
+            total_mb / decompress.as_secs_f64(),
+        );
+    }
+
+    let parent = dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+    for &direct_io in &[false, true] {
+        let dir = tempfile::tempdir_in(&parent)?;
+        let mut kv = Lock::exclusive(0)
+            .open(dir.path(), dir.path(), &FileNaming::default(), None)?
+            .with_direct_io(direct_io);
+        let start = Instant::now();
+        for (index, data) in &items {
+            kv.write(*index, data.clone())?;
+        }
+        kv.flush()?;
+        let write = start.elapsed();
+        let start = Instant::now();
+        for &index in &indexes {
+            kv.read(index)?;
+        }
+        let read = start.elapsed();
+        println!(
+            "Files, direct I/O {}: write {:.1} MB/s, read {:.1} MB/s",
+            direct_io,
+            total_mb / write.as_secs_f64(),
+            total_mb / read.as_secs_f64(),
+        );
+    }
+    for &durability in &[Durability::Full, Durability::Data, Durability::None] {
+        let dir = tempfile::tempdir_in(&parent)?;
+        let mut kv = Lock::exclusive(0)
+            .open(dir.path(), dir.path(), &FileNaming::default(), None)?
+            .with_durability(durability);
+        for (index, data) in &items {
+            kv.write(*index, data.clone())?;
+        }
+        let start = Instant::now();
+        kv.flush()?;
+        println!(
+            "Files, durability {}: flush {} ms",
+            durability,
+            start.elapsed().as_millis()
+        );
+    }
+
+    let stripe_parents = match stripe_dirs {
+        [] => vec![parent; 4],
+        dirs => dirs.to_vec(),
+    };
+    let mut stripe_counts = vec![1, stripe_parents.len()];
+    stripe_counts.dedup();
+    for &count in &stripe_counts {
+        let dirs = stripe_parents[..count]
+            .iter()
+            .map(tempfile::tempdir_in)
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut members: Vec<Box<dyn IntKv>> = Vec::new();
+        for dir in &dirs {
+            let kv =
+                Lock::exclusive(0).open(dir.path(), dir.path(), &FileNaming::default(), None)?;
+            members.push(Box::new(kv));
+        }
+        let mut kv = StripeIntKv::open(members, "bench", DEFAULT_STRIPE_WIDTH, 0)?;
+        let start = Instant::now();
+        let (_, result) = kv.write_batch(items.clone());
+        result?;
+        kv.flush()?;
+        let write = start.elapsed();
+        let start = Instant::now();
+        for result in kv.read_batch(&indexes) {
+            result?;
+        }
+        let read = start.elapsed();
+        println!(
+            "Stripes {}: write {:.1} MB/s, read {:.1} MB/s",
+            count,
+            total_mb / write.as_secs_f64(),
+            total_mb / read.as_secs_f64(),
+        );
+    }
+    Ok(())
+}
+
+/// Flush the new directory after copying this many bytes in `migrate_cmd`.
+const MIGRATE_FLUSH_BYTES: usize = 1 << 26;
+
+/// Read the config of an initialized directory.
+fn load_config(dir: &Path) -> io::Result<Config> {
+    load_config_with(dir, false)
+}
+
+/// Read and check the config of a directory. With `ignore_unknown`, fields
+/// unknown to this binary are warned about instead of refused.
+fn load_config_with(dir: &Path, ignore_unknown: bool) -> io::Result<Config> {
+    let config_path = dir.join(CONFIG_FILE);
+    if !config_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} was not initialized (try \"x79d8 init\")", dir.display()),
+        ));
+    }
+
+    let config_str = fs::read_to_string(config_path)?;
+    let parse_error = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let config: Config = serde_json::from_str(&config_str).map_err(parse_error)?;
+    upgrade::check_supported(dir, &config)?;
+
+    let json = serde_json::from_str(&config_str).map_err(parse_error)?;
+    let mut problems = validate::unknown_fields(&json, &config);
+    if ignore_unknown {
+        for problem in problems.drain(..) {
+            eprintln!("Warning: ignored {} in {}", problem.path, CONFIG_FILE);
+        }
+    }
+    problems.extend(config.validate());
+    if !problems.is_empty() {
+        return Err(validate::error(dir, &problems));
+    }
+
+    Ok(config)
+}
+
+/// Write the config of a directory.
+fn save_config(dir: &Path, config: &Config) -> io::Result<()> {
+    fs::write(
+        dir.join(CONFIG_FILE),
+        serde_json::to_string_pretty(config).unwrap().as_bytes(),
+    )
+}
+
+/// Ask for the password and derive the key. Return None if encryption is
+/// disabled.
+fn read_key(config: &Config) -> io::Result<Option<Key>> {
+    if config.salt_hex.is_empty() {
+        log::info!("Encryption is disabled");
+        return Ok(None);
+    }
+    check_not_rekeying(config)?;
+    let params = scrypt_params(config)?;
+    let ignored = (config.scrypt_log_n, config.scrypt_r, config.scrypt_p)
+        != (params.log_n(), params.r(), params.p());
+    if ignored {
+        eprintln!(
+            "Note: scrypt parameters in {} are not used. Run \"x79d8 rekey\" to apply them.",
+            CONFIG_FILE
+        );
+    }
+    let prompt = "Password: ";
+    let pass = read_password(prompt);
+    let start = Instant::now();
+    let (key, _) = unlock(config, &pass)?;
+    let elapsed = start.elapsed();
+    if config.kdf_measured_ms > 0
+        && elapsed > Duration::from_millis(config.kdf_measured_ms * KDF_SLOW_FACTOR)
+    {
+        eprintln!(
+            "Note: deriving the key took {} ms, {} ms at init. Consider \"x79d8 rekey\" with a smaller scrypt_log_n.",
+            elapsed.as_millis(),
+            config.kdf_measured_ms
+        );
+    }
+    Ok(Some(key))
+}
+
+/// Ask for a password. It is wiped on drop.
+fn read_password(prompt: &str) -> Secret<Vec<u8>> {
+    let pass = rpassword::read_password_from_tty(Some(prompt)).unwrap();
+    Secret::new(pass.into_bytes())
+}
+
+/// Ask for a new password twice.
+fn read_new_password(prompt: &str) -> io::Result<Secret<Vec<u8>>> {
+    let pass = read_password(prompt);
+    let confirm = read_password("Confirm password: ");
+    if *pass != *confirm {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passwords do not match",
+        ));
+    }
+    Ok(pass)
+}
+
+/// Get the master key from a password. Also return the index of the
+/// wrapped key it unlocks, or None if the key is derived directly.
+fn unlock(config: &Config, password: &[u8]) -> io::Result<(Key, Option<usize>)> {
+    let kek = password_derive(password, &config.salt_hex, &scrypt_params(config)?);
+    if config.wrapped_keys_hex.is_empty() {
+        if !config.key_check_hex.is_empty() && hex::encode(key_check(&kek)) != config.key_check_hex
+        {
+            return Err(wrong_password());
+        }
+        return Ok((kek, None));
+    }
+    for (i, wrapped_hex) in config.wrapped_keys_hex.iter().enumerate() {
+        if let Some(key) = unwrap_hex(&kek, wrapped_hex)? {
+            return Ok((key, Some(i)));
+        }
+    }
+    Err(wrong_password())
+}
+
+/// Unwrap a hex-encoded wrapped key. Return None if `kek` does not match.
+fn unwrap_hex(kek: &[u8; 32], wrapped_hex: &str) -> io::Result<Option<Key>> {
+    let wrapped =
+        hex::decode(wrapped_hex).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(unwrap_key(kek, &wrapped))
+}
+
+fn wrong_password() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "incorrect password")
+}
+
+/// Process exit code for an error. Wrong passwords and corrupted data are
+/// told apart from other errors.
+pub fn exit_code(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => 3,
+        io::ErrorKind::InvalidData => 2,
+        _ => 1,
+    }
+}
+
+/// Refuse to use a directory while its password change is incomplete.
+fn check_not_rekeying(config: &Config) -> io::Result<()> {
+    if !config.rekey_salt_hex.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "password change is incomplete (try \"x79d8 rekey\")",
+        ));
+    }
+    Ok(())
+}
+
+/// How to lock a directory against other processes.
+#[derive(Clone, Copy)]
+struct Lock {
+    mode: LockMode,
+    wait: Duration,
+}
+
+impl Lock {
+    /// For commands that change the directory.
+    fn exclusive(wait_secs: u64) -> Self {
+        Self {
+            mode: LockMode::Exclusive,
+            wait: Duration::from_secs(wait_secs),
+        }
+    }
+
+    /// For commands that only read the directory. They can run while the
+    /// directory is being served.
+    fn read_only(wait_secs: u64) -> Self {
+        Self {
+            mode: LockMode::Shared,
+            wait: Duration::from_secs(wait_secs),
+        }
+    }
+
+    /// Open `FsIntKv` of `dir` storing blocks in `data`, see `data_dir`.
+    /// `file_size` is the expected size of block files.
+    fn open(
+        self,
+        dir: &Path,
+        data: &Path,
+        naming: &FileNaming,
+        file_size: Option<u64>,
+    ) -> io::Result<FsIntKv> {
+        let kv =
+            FsIntKv::new_with_lock(data, dir, self.mode, self.wait, naming.clone(), file_size)?;
+        if self.mode == LockMode::Exclusive {
+            for (level, line) in describe_open_report(&kv.open_report()) {
+                log::log!(level, "{}: {}", data.display(), line);
+            }
+        }
+        if kv.has_wal() {
+            eprintln!(
+                "Warning: {} has uncommitted changes. They are not shown.",
+                dir.display()
+            );
+        }
+        Ok(kv)
+    }
+}
+
+/// Lines describing `report`, with the log level they deserve.
+fn describe_open_report(report: &OpenReport) -> Vec<(log::Level, String)> {
+    /// Paths to list of each problem.
+    const MAX_LISTED: usize = 10;
+    let mut lines = vec![(
+        log::Level::Info,
+        format!(
+            "{} blocks, {} bytes in total",
+            report.entry_files, report.total_bytes
+        ),
+    )];
+    if let Some(entries) = report.wal_entries {
+        let state = if report.wal_replayed {
+            "replayed"
+        } else {
+            "not replayed"
+        };
+        lines.push((
+            log::Level::Info,
+            format!(
+                "WAL of an interrupted write: {} entries, {}",
+                entries, state
+            ),
+        ));
+    }
+    if report.torn_wal {
+        lines.push((
+            log::Level::Warn,
+            "WAL of an interrupted write is torn".to_string(),
+        ));
+    }
+    if report.pending_files > 0 {
+        lines.push((
+            log::Level::Info,
+            format!("Uncommitted files: {}", report.pending_files),
+        ));
+    }
+    for (path, len) in report.size_mismatches.iter().take(MAX_LISTED) {
+        lines.push((
+            log::Level::Warn,
+            format!(
+                "Block {} has an unexpected size: {} bytes",
+                path.display(),
+                len
+            ),
+        ));
+    }
+    for path in report.foreign_files.iter().take(MAX_LISTED) {
+        lines.push((
+            log::Level::Warn,
+            format!("Unexpected file: {}", path.display()),
+        ));
+    }
+    let unlisted = report.size_mismatches.len().saturating_sub(MAX_LISTED)
+        + report.foreign_files.len().saturating_sub(MAX_LISTED);
+    if unlisted > 0 {
+        lines.push((
+            log::Level::Warn,
+            format!("{} more problems are not listed", unlisted),
+        ));
+    }
+    lines
+}
+
+/// Construct the `IntKv` backend. From the top: `CompressIntKv`,
+/// `PageIntKv`, `QuotaIntKv`, `BufferedIntKv`, `EncIntKv`, then the
+/// storage of `backend_from_dir_config`. `ParityIntKv` is at the bottom,
+/// splitting entries of the block size into fixed-size shares, so it
+/// refuses to open if blocks are disabled.
+fn kv_from_dir_config(
+    dir: &Path,
+    config: &Config,
+    key: Option<&[u8; 32]>,
+    lock: Lock,
+) -> io::Result<Box<dyn IntKv>> {
+    let (mut kv, page_size) = buffered_kv_from_dir_config(dir, config, key, lock)?;
+    let unchecked = key_unchecked(config, key);
+    if page_size > 0 {
+        let encoding = config.metadata_encoding;
+        let page_kv =
+            PageIntKv::new_with_encoding(page_size, kv, encoding).map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData if unchecked => io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} (incorrect password?)", e),
+                ),
+                _ => e,
+            })?;
+        if unchecked && !page_kv.keys()?.is_empty() {
+            // Metadata decrypted fine.
+            record_key_check(dir, config, key.unwrap(), lock)?;
+        }
+        let mut page_kv = page_kv
+            .with_fill_factor(config.fill_factor)
+            .with_sequential_allocation(config.sequential_allocation)
+            .with_skip_identical_writes(config.sync_friendly)
+            .with_threads(flush_threads(config));
+        if config.paranoid_checks {
+            page_kv = page_kv.with_paranoid_checks(true);
+        }
+        kv = measured(config, "PageIntKv", Box::new(page_kv));
+    }
+    Ok(compressed_kv(config, kv))
+}
+
+/// Add `CompressIntKv` on top if compression is enabled.
+fn compressed_kv(config: &Config, kv: Box<dyn IntKv>) -> Box<dyn IntKv> {
+    match config.compression {
+        true => {
+            let kv = CompressIntKv::new(kv).with_level(config.compression_level);
+            measured(config, "CompressIntKv", Box::new(kv))
+        }
+        false => kv,
+    }
+}
+
+/// Add `MetricsIntKv` labeled `label` on top if `layer_metrics` is set.
+/// Called on each layer, so the latencies of each show up.
+fn measured(config: &Config, label: &str, kv: Box<dyn IntKv>) -> Box<dyn IntKv> {
+    match config.layer_metrics {
+        true => Box::new(MetricsIntKv::new(kv, label)),
+        false => kv,
+    }
+}
+
+/// Whether `key` is derived from the password without a check value to
+/// tell wrong passwords.
+fn key_unchecked(config: &Config, key: Option<&[u8; 32]>) -> bool {
+    key.is_some() && config.wrapped_keys_hex.is_empty() && config.key_check_hex.is_empty()
+}
+
+/// Remember the check value of `key`, so `unlock` tells wrong passwords
+/// next time. Only with exclusive locks, so commands reading a served
+/// directory do not write its config.
+fn record_key_check(dir: &Path, config: &Config, key: &[u8; 32], lock: Lock) -> io::Result<()> {
+    if lock.mode != LockMode::Exclusive {
+        return Ok(());
+    }
+    let config = Config {
+        key_check_hex: hex::encode(key_check(key)),
+        ..config.clone()
+    };
+    save_config(dir, &config)
+}
+
+/// Construct `EncIntKv` directly on top of `FsIntKv`, for maintenance that
+/// works on raw entries.
+fn raw_kv_from_dir_config(
+    dir: &Path,
+    config: &Config,
+    key: &[u8; 32],
+    lock: Lock,
+) -> io::Result<EncIntKv> {
+    let kv = backend_from_dir_config(dir, config, lock)?;
+    let enc = EncIntKv::from_key_kv(*key, kv)
+        .with_cipher(config.cipher)
+        .with_header_version(header_version(config)?)
+        .with_required_mac(config.mac_trailer)
+        .with_threads(flush_threads(config));
+    Ok(enc)
+}
+
+/// Construct the `IntKv` backend below `PageIntKv`. Return it with the page
+/// size for `PageIntKv` (0: blocks are disabled).
+fn buffered_kv_from_dir_config(
+    dir: &Path,
+    config: &Config,
+    key: Option<&[u8; 32]>,
+    lock: Lock,
+) -> io::Result<(Box<dyn IntKv>, u64)> {
+    let mut kv = backend_from_dir_config(dir, config, lock)?;
+    if let Some(key) = key {
+        // Use password encryption.
+        let enc = EncIntKv::from_key_kv(*key, kv)
+            .with_cipher(config.cipher)
+            .with_header_version(header_version(config)?)
+            .with_required_mac(config.mac_trailer)
+            .with_threads(flush_threads(config));
+        if key_unchecked(config, Some(key)) && config.block_size_kb == 0 {
+            // Without blocks of metadata, entries tell wrong passwords.
+            match enc.check_key()? {
+                Some(true) => record_key_check(dir, config, key, lock)?,
+                Some(false) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "entries do not authenticate (incorrect password?)",
+                    ))
+                }
+                None => {}
+            }
+        }
+        let enc = Box::new(enc);
+        if let Err(e) = enc.lock_key_memory() {
+            eprintln!("Warning: cannot lock key memory: {}", e);
+        }
+        kv = measured(config, "EncIntKv", enc);
+    }
+
+    let mut buffered = BufferedIntKv::new(kv)
+        .with_cache_size_limit(config.cache_size_limit)
+        .with_cache_entries_limit(config.cache_entries_limit)
+        .with_pinned_size_limit(config.pinned_size_limit);
+    if config.background_flush_secs > 0 {
+        let interval = Duration::from_secs(config.background_flush_secs);
+        buffered =
+            buffered.with_background_flush(interval, config.background_flush_max_dirty_bytes);
+    }
+    kv = measured(config, "BufferedIntKv", Box::new(buffered));
+    let cipher = key.map(|_| config.cipher);
+    let page_size = page_size(
+        config.block_size_kb,
+        cipher,
+        config.mac_trailer,
+        header_version(config)?,
+    );
+    if config.quota_max_blocks > 0 || config.quota_max_mb > 0 {
+        let quota = QuotaIntKv::open(kv)?
+            .with_limits(config.quota_max_blocks, config.quota_max_mb << 20)
+            .with_record_size(page_size as usize);
+        let stats = quota.stats();
+        for problem in validate::check_quota(config, stats["quota.entries"], stats["quota.bytes"]) {
+            eprintln!("Warning: {}", problem);
+        }
+        kv = measured(config, "QuotaIntKv", Box::new(quota));
+    }
+    if config.versions_kept > 0 {
+        let versioned = versioned_kv(config, kv, page_size)?;
+        kv = measured(config, "VersionedIntKv", Box::new(versioned));
+    }
+    if config.cow_snapshots {
+        kv = measured(config, "CowIntKv", Box::new(cow_kv(kv, page_size)?));
+    }
+    Ok((kv, page_size))
+}
+
+/// Limits of the cache of `BufferedIntKv`.
+fn cache_limits(config: &Config) -> CacheLimits {
+    CacheLimits {
+        size: config.cache_size_limit,
+        entries: config.cache_entries_limit,
+        pinned_size: config.pinned_size_limit,
+    }
+}
+
+/// Keep copy-on-write snapshots, below `PageIntKv`.
+fn cow_kv(kv: Box<dyn IntKv>, page_size: u64) -> io::Result<CowIntKv> {
+    let kv = CowIntKv::open(kv)?;
+    Ok(kv.with_record_size(page_size as usize))
+}
+
+/// Keep older values of blocks, below `PageIntKv`.
+fn versioned_kv(config: &Config, kv: Box<dyn IntKv>, page_size: u64) -> io::Result<VersionedIntKv> {
+    let kv = VersionedIntKv::open(kv, config.versions_kept)?;
+    Ok(kv.with_record_size(page_size as usize))
+}
+
+/// Open the `IntKv` storing blocks, mirrored to `mirror_dir` if set,
+/// checksummed if `checksum` is set, and throttled if limits are set.
+fn backend_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    let mut kv = primary_backend_from_dir_config(dir, config, lock)?;
+    if !config.mirror_dir.is_empty() {
+        let mirror = mirror_kv_from_dir_config(dir, config, lock, kv)?;
+        kv = measured(config, "MirrorIntKv", Box::new(mirror));
+    }
+    if let Some(checksum) = config.checksum {
+        if !config.block_device.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checksums do not fit in block device slots",
+            ));
+        }
+        let checksummed = ChecksumIntKv::new(kv, checksum);
+        kv = measured(config, "ChecksumIntKv", Box::new(checksummed));
+    }
+    if config.throttle_read_kbps > 0 || config.throttle_write_kbps > 0 {
+        let throttle = ThrottleIntKv::new(kv).with_limits(
+            config.throttle_read_kbps << 10,
+            config.throttle_write_kbps << 10,
+            config.throttle_burst_kb << 10,
+        );
+        kv = measured(config, "ThrottleIntKv", Box::new(throttle));
+    }
+    #[cfg(feature = "testing")]
+    if let Ok(spec) = std::env::var("X79D8_FAULTS") {
+        let faults =
+            Faults::parse(&spec).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        eprintln!("Warning: injecting faults: {}", spec);
+        kv = Box::new(FaultIntKv::with_faults(kv, faults));
+    }
+    Ok(kv)
+}
+
+/// Mirror `primary` to `mirror_dir`. Blocks recorded while the mirror was
+/// offline are copied when the directory is opened for writing.
+fn mirror_kv_from_dir_config(
+    dir: &Path,
+    config: &Config,
+    lock: Lock,
+    primary: Box<dyn IntKv>,
+) -> io::Result<MirrorIntKv> {
+    let mirror_dir = dir.join(&config.mirror_dir);
+    // Not the root, in case it is where a drive is mounted.
+    if lock.mode == LockMode::Exclusive && mirror_dir.is_dir() {
+        fs::create_dir_all(data_dir(&mirror_dir, config)?)?;
+    }
+    let secondary = fs_kv_from_dir_config(&mirror_dir, &local_config(config), lock);
+    if !config.mirror_allow_degraded {
+        return Ok(MirrorIntKv::new(primary, Box::new(secondary?)));
+    }
+    let resync_path = dir.join(MIRROR_RESYNC_FILE);
+    let mut kv = match secondary {
+        Ok(secondary) => {
+            MirrorIntKv::new(primary, Box::new(secondary)).with_degraded(&resync_path)?
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: mirror {} is unavailable ({}). Changes are recorded to copy later.",
+                mirror_dir.display(),
+                e
+            );
+            MirrorIntKv::offline(primary, &resync_path)?
+        }
+    };
+    if lock.mode == LockMode::Exclusive && !kv.is_degraded() && kv.resync_len() > 0 {
+        let count = kv.repair()?;
+        eprintln!(
+            "Copied {} blocks changed while the mirror was offline",
+            count
+        );
+    }
+    Ok(kv)
+}
+
+/// Open the `IntKv` storing blocks: `ShardedIntKv` if `shards` is set,
+/// `BlockDevIntKv` if `block_device` is set, `LogIntKv` if
+/// `append_only_log` is set, `SftpIntKv` if `sftp_host` is set,
+/// `RedisIntKv` if `redis_url` is set, `HttpIntKv` if `remote_url` is set,
+/// behind `TieredIntKv` if `local_cache_dir` is set. `SledIntKv` if
+/// `storage` is sled, `FsIntKv` otherwise.
+fn primary_backend_from_dir_config(
+    dir: &Path,
+    config: &Config,
+    lock: Lock,
+) -> io::Result<Box<dyn IntKv>> {
+    let remote = if config.online_move {
+        let kv = migrate_kv_from_dir_config(dir, config, lock)?;
+        return Ok(measured(config, "MigrateIntKv", Box::new(kv)));
+    } else if !config.shards.is_empty() {
+        let kv = sharded_kv_from_dir_config(dir, config, lock)?;
+        return Ok(measured(config, "ShardedIntKv", Box::new(kv)));
+    } else if !config.stripes.is_empty() {
+        let kv = striped_kv_from_dir_config(dir, config, lock)?;
+        return Ok(measured(config, "StripeIntKv", Box::new(kv)));
+    } else if !config.parity_dirs.is_empty() {
+        let kv = parity_kv_from_dir_config(dir, config, lock)?;
+        return Ok(measured(config, "ParityIntKv", Box::new(kv)));
+    } else if !config.block_device.is_empty() {
+        let kv = block_device_kv_from_config(config, lock)?;
+        return Ok(measured(config, "BlockDevIntKv", kv));
+    } else if config.append_only_log {
+        let kv = log_kv_from_dir_config(dir, config, lock)?;
+        return Ok(measured(config, "LogIntKv", kv));
+    } else if !config.sftp_host.is_empty() {
+        let kv = retried(config, sftp_kv_from_config(config)?);
+        measured(config, "SftpIntKv", kv)
+    } else if !config.redis_url.is_empty() {
+        let kv = retried(config, redis_kv_from_config(config, lock)?);
+        measured(config, "RedisIntKv", kv)
+    } else if !config.remote_url.is_empty() {
+        measured(config, "HttpIntKv", http_kv_from_dir_config(dir, config)?)
+    } else if config.storage == StorageEngine::Sled {
+        let kv = sled_kv_from_dir(dir, lock)?;
+        return Ok(measured(config, "SledIntKv", kv));
+    } else {
+        let kv = fs_kv_from_dir_config(dir, config, lock)?;
+        return Ok(measured(config, "FsIntKv", Box::new(kv)));
+    };
+    if config.local_cache_dir.is_empty() {
+        return Ok(remote);
+    }
+    let cache_dir = dir.join(&config.local_cache_dir);
+    if lock.mode == LockMode::Exclusive {
+        fs::create_dir_all(data_dir(&cache_dir, config)?)?;
+    } else if !cache_dir.exists() {
+        return Ok(remote);
+    }
+    let local = fs_kv_from_dir_config(&cache_dir, &local_config(config), lock)?;
+    let local = measured(config, "FsIntKv", Box::new(local));
+    let journal_path = cache_dir.join(UPLOAD_JOURNAL_FILE);
+    let kv = TieredIntKv::new(local, remote, &journal_path)?
+        .with_cache_size_limit(config.local_cache_size_mb << 20)
+        .with_write_through(config.local_cache_write_through);
+    Ok(measured(config, "TieredIntKv", Box::new(kv)))
+}
+
+/// `config` storing blocks in a local directory, for the mirror and the
+/// local tier of a remote directory.
+fn local_config(config: &Config) -> Config {
+    Config {
+        remote_url: String::new(),
+        sftp_host: String::new(),
+        redis_url: String::new(),
+        mirror_dir: String::new(),
+        local_cache_dir: String::new(),
+        shards: Vec::new(),
+        next_shards: None,
+        online_move: false,
+        stripes: Vec::new(),
+        parity_dirs: Vec::new(),
+        block_device: String::new(),
+        append_only_log: false,
+        storage: StorageEngine::Files,
+        ..config.clone()
+    }
+}
+
+#[cfg(unix)]
+fn log_kv_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    let kv = LogIntKv::open(&data_dir(dir, config)?, lock.mode, lock.wait)?
+        .with_segment_size(config.log_segment_size_mb << 20)
+        .with_compaction_ratio(config.log_compaction_ratio);
+    Ok(Box::new(kv))
+}
+
+#[cfg(not(unix))]
+fn log_kv_from_dir_config(
+    _dir: &Path,
+    _config: &Config,
+    _lock: Lock,
+) -> io::Result<Box<dyn IntKv>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "log segments are not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn block_device_kv_from_config(config: &Config, lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    let (slot_size, vault_id) = block_device_params(config)?;
+    let path = Path::new(&config.block_device);
+    Ok(Box::new(BlockDevIntKv::open(
+        path, slot_size, vault_id, lock.mode,
+    )?))
+}
+
+#[cfg(unix)]
+fn format_block_device(path: &Path, config: &Config) -> io::Result<()> {
+    let (slot_size, vault_id) = block_device_params(config)?;
+    BlockDevIntKv::format(path, slot_size, vault_id)
+}
+
+/// Slot size and vault id of the block device. Slots hold one block each,
+/// so blocks must be enabled.
+#[cfg(unix)]
+fn block_device_params(config: &Config) -> io::Result<(u64, [u8; 16])> {
+    let slot_size = file_size(config).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a block device needs blocks (block_size_kb > 0)",
+        )
+    })?;
+    let mut vault_id = [0; 16];
+    hex::decode_to_slice(&config.vault_id, &mut vault_id)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("vault_id: {}", e)))?;
+    Ok((slot_size, vault_id))
+}
+
+#[cfg(not(unix))]
+fn block_device_kv_from_config(_config: &Config, _lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    Err(block_devices_unsupported())
+}
+
+#[cfg(not(unix))]
+fn format_block_device(_path: &Path, _config: &Config) -> io::Result<()> {
+    Err(block_devices_unsupported())
+}
+
+#[cfg(not(unix))]
+fn block_devices_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "block devices are not supported on this platform",
+    )
+}
+
+/// Open the shards, checking that they are the shards of `dir` in order.
+fn sharded_kv_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<ShardedIntKv> {
+    if config.next_shards.is_some() {
+        return Err(incomplete_shard_move());
+    }
+    if !config.remote_url.is_empty()
+        || !config.sftp_host.is_empty()
+        || !config.redis_url.is_empty()
+        || !config.block_device.is_empty()
+        || config.append_only_log
+        || config.storage != StorageEngine::Files
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "shards cannot be combined with a server, a block device, log segments or sled",
+        ));
+    }
+    let local = local_config(config);
+    let count = config.shards.len();
+    let mut shards: Vec<Box<dyn IntKv>> = Vec::new();
+    for (position, shard) in config.shards.iter().enumerate() {
+        let shard_dir = dir.join(shard);
+        let marker = fs::read_to_string(shard_dir.join(SHARD_FILE)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot read the marker of shard {}: {}", shard, e),
+            )
+        })?;
+        if marker.trim_end() != shard_marker(&config.vault_id, position, count).trim_end() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is not shard {} of {} of {}",
+                    shard_dir.display(),
+                    position + 1,
+                    count,
+                    dir.display()
+                ),
+            ));
+        }
+        shards.push(Box::new(fs_kv_from_dir_config(&shard_dir, &local, lock)?));
+    }
+    Ok(ShardedIntKv::new(shards))
+}
+
+/// Read and write both the shards and the next shards of an online move.
+fn migrate_kv_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<MigrateIntKv> {
+    let old = Config {
+        next_shards: None,
+        online_move: false,
+        ..config.clone()
+    };
+    let new = Config {
+        shards: config.next_shards.clone().unwrap_or_default(),
+        ..old.clone()
+    };
+    let old = primary_backend_from_dir_config(dir, &old, lock)?;
+    let new = primary_backend_from_dir_config(dir, &new, lock)?;
+    MigrateIntKv::open(old, new, &dir.join(MIGRATE_CURSOR_FILE))
+}
+
+/// Open the members of the stripe. They check their markers.
+fn striped_kv_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<StripeIntKv> {
+    if !config.shards.is_empty() || remote_location(config).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stripes cannot be combined with shards or a server",
+        ));
+    }
+    let local = local_config(config);
+    let mut members: Vec<Box<dyn IntKv>> = Vec::new();
+    for stripe in &config.stripes {
+        members.push(Box::new(fs_kv_from_dir_config(
+            &dir.join(stripe),
+            &local,
+            lock,
+        )?));
+    }
+    let marker_size = file_size(config).unwrap_or(0) as usize;
+    StripeIntKv::open(members, &config.vault_id, config.stripe_width, marker_size)
+}
+
+/// Open the members of the parity set, the last holding parity. A member
+/// failing to open is left offline. Entries are blocks with a checksum if
+/// enabled, so they fit in fixed-size shares.
+fn parity_kv_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<ParityIntKv> {
+    let block_size = match file_size(config) {
+        Some(size) if config.shards.is_empty() && remote_location(config).is_none() => size,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "parity needs blocks, and cannot be combined with shards or a server",
+            ))
+        }
+    };
+    let entry_size = block_size as usize + config.checksum.map_or(0, Checksum::overhead);
+    // Shares are smaller than blocks. ParityIntKv checks their size.
+    let member_config = Config {
+        block_size_kb: 0,
+        ..local_config(config)
+    };
+    let mut members = Vec::new();
+    for member_dir in &config.parity_dirs {
+        let member_dir = dir.join(member_dir);
+        // Not the root, in case it is where a replaced drive is mounted.
+        if lock.mode == LockMode::Exclusive && member_dir.is_dir() {
+            fs::create_dir_all(data_dir(&member_dir, config)?)?;
+        }
+        match fs_kv_from_dir_config(&member_dir, &member_config, lock) {
+            Ok(kv) => members.push(Some(Box::new(kv) as Box<dyn IntKv>)),
+            Err(e) => {
+                eprintln!(
+                    "Warning: {} is unavailable ({}). Losing another directory loses the vault.",
+                    member_dir.display(),
+                    e
+                );
+                members.push(None);
+            }
+        }
+    }
+    let parity = members.pop().unwrap();
+    let mut kv = ParityIntKv::open(members, parity, entry_size)?;
+    if lock.mode == LockMode::Exclusive && !kv.is_degraded() && kv.stale_len() > 0 {
+        let count = kv.recover()?;
+        eprintln!(
+            "Updated the parity of {} blocks changed before a crash",
+            count
+        );
+    }
+    Ok(kv)
+}
+
+/// Retry operations of a network backend that fail with connection errors.
+/// `HttpClient` retries requests itself.
+fn retried(config: &Config, kv: Box<dyn IntKv>) -> Box<dyn IntKv> {
+    let delay = Duration::from_millis(config.remote_retry_delay_ms);
+    Box::new(RetryIntKv::new(kv).with_retry(config.remote_retry_attempts, delay))
+}
+
+fn incomplete_shard_move() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "moving blocks between shards is incomplete (try \"x79d8 migrate-storage\")",
+    )
+}
+
+fn http_kv_from_dir_config(dir: &Path, config: &Config) -> io::Result<Box<dyn IntKv>> {
+    let mut client = HttpClient::new(&config.remote_url)?
+        .with_timeout(Duration::from_secs(config.remote_timeout_secs))
+        .with_retry(
+            config.remote_retry_attempts,
+            Duration::from_millis(config.remote_retry_delay_ms),
+        )
+        .with_connections(config.remote_connections);
+    if !config.remote_bearer_token.is_empty() {
+        client = client.with_bearer_token(&config.remote_bearer_token);
+    } else if !config.remote_user.is_empty() {
+        client = client.with_basic_auth(&config.remote_user, &config.remote_password);
+    }
+    if !config.remote_ca_file.is_empty() {
+        client = client.with_ca_file(dir.join(&config.remote_ca_file));
+    }
+    Ok(Box::new(HttpIntKv::new(client)?))
+}
+
+/// Open `FsIntKv` with the read options of `config`.
+fn fs_kv_from_dir_config(dir: &Path, config: &Config, lock: Lock) -> io::Result<FsIntKv> {
+    if let Some(remote) = remote_location(config) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} stores blocks at {}, not supported by this command",
+                dir.display(),
+                remote
+            ),
+        ));
+    }
+    if config.next_file_naming.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "renaming blocks is incomplete (try \"x79d8 migrate-layout\")",
+        ));
+    }
+    if config.next_layout_version.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "moving blocks is incomplete (try \"x79d8 migrate-layout\")",
+        ));
+    }
+    if config.next_shards.is_some() {
+        return Err(incomplete_shard_move());
+    }
+    let data = data_dir(dir, config)?;
+    Ok(lock
+        .open(dir, &data, &config.file_naming, file_size(config))?
+        .with_read_strategy(config.read_strategy)
+        .with_retry(
+            config.io_retry_attempts,
+            Duration::from_millis(config.io_retry_delay_ms),
+        )
+        .with_preallocation(config.preallocate)
+        .with_durability(config.durability)
+        .with_previous_generation(config.keep_previous_generation)
+        .with_segments(config.segment_threshold_bytes)
+        .with_open_files(config.open_files)
+        .with_direct_io(config.direct_io)
+        .with_external_change_check(!config.allow_external_changes)
+        .with_reserved_space(
+            config.reserved_space_mb << 20,
+            config.low_space_warning_mb << 20,
+        ))
+}
+
+/// Size of block files. Blocks, with their header and trailer, take
+/// exactly the block size. None if blocks are disabled.
+fn file_size(config: &Config) -> Option<u64> {
+    match config.block_size_kb {
+        0 => None,
+        kb => Some(kb as u64 * 1024),
+    }
+}
+
+#[cfg(feature = "sftp")]
+fn sftp_kv_from_config(config: &Config) -> io::Result<Box<dyn IntKv>> {
+    let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+    let ssh = SshOptions {
+        host: config.sftp_host.clone(),
+        port: (config.sftp_port != 0).then_some(config.sftp_port),
+        user: non_empty(&config.sftp_user),
+        key_file: non_empty(&config.sftp_key_file),
+    };
+    let path = non_empty(&config.sftp_path).unwrap_or_else(|| ".".to_string());
+    Ok(Box::new(SftpIntKv::new(ssh, &path)?))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn sftp_kv_from_config(config: &Config) -> io::Result<Box<dyn IntKv>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "blocks are stored on {} by SFTP, which this build does not support",
+            config.sftp_host
+        ),
+    ))
+}
+
+#[cfg(feature = "redis")]
+fn redis_kv_from_config(config: &Config, lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    let ttl = (config.redis_ttl_secs > 0).then(|| Duration::from_secs(config.redis_ttl_secs));
+    let kv = RedisIntKv::open(
+        &config.redis_url,
+        &config.vault_id,
+        ttl,
+        lock.mode,
+        lock.wait,
+    )?;
+    Ok(Box::new(kv))
+}
+
+#[cfg(not(feature = "redis"))]
+fn redis_kv_from_config(_config: &Config, _lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "blocks are stored in Redis, which this build does not support",
+    ))
+}
+
+/// Open the sled database of `dir`, creating it if missing.
+#[cfg(feature = "sled")]
+fn sled_kv_from_dir(dir: &Path, lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    let path = dir.join(SLED_DIR);
+    Ok(Box::new(SledIntKv::open(&path, lock.mode, lock.wait)?))
+}
+
+#[cfg(not(feature = "sled"))]
+fn sled_kv_from_dir(dir: &Path, _lock: Lock) -> io::Result<Box<dyn IntKv>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "blocks of {} are stored in a sled database, which this build does not support",
+            dir.display()
+        ),
+    ))
+}
+
+/// Where blocks are stored if not in files of the directory, for messages.
+fn remote_location(config: &Config) -> Option<String> {
+    if !config.sftp_host.is_empty() {
+        let user = match config.sftp_user.as_str() {
+            "" => String::new(),
+            user => format!("{}@", user),
+        };
+        Some(format!(
+            "sftp://{}{}/{}",
+            user, config.sftp_host, config.sftp_path
+        ))
+    } else if !config.redis_url.is_empty() {
+        // Without the password.
+        match config.redis_url.rsplit_once('@') {
+            Some((_, host)) => Some(format!("redis://{}", host)),
+            None => Some(config.redis_url.clone()),
+        }
+    } else if !config.remote_url.is_empty() {
+        Some(config.remote_url.clone())
+    } else if !config.shards.is_empty() {
+        Some(format!("shards {}", config.shards.join(", ")))
+    } else if !config.block_device.is_empty() {
+        Some(format!("block device {}", config.block_device))
+    } else if config.append_only_log {
+        Some(format!("log segments in {}", DATA_DIR))
+    } else if config.storage == StorageEngine::Sled {
+        Some(format!("sled database {}", SLED_DIR))
+    } else {
+        None
+    }
+}
+
+/// Directory of the files of `FsIntKv`, by the layout version.
+fn data_dir(dir: &Path, config: &Config) -> io::Result<PathBuf> {
+    match config.layout_version {
+        1 => Ok(dir.to_path_buf()),
+        2 => Ok(dir.join(DATA_DIR)),
+        v => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("layout version {} is not supported", v),
+        )),
+    }
+}
+
+fn header_version(config: &Config) -> io::Result<HeaderVersion> {
+    HeaderVersion::try_from(config.entry_header_version)
+}
+
+/// Threads to encode blocks on flush.
+fn flush_threads(config: &Config) -> usize {
+    match config.flush_threads {
+        0 => util::default_threads(),
+        n => n,
+    }
+}
+
+/// Parse a block size in KB, or with a suffix like "64K".
+fn parse_kb(s: &str) -> Result<u16, String> {
+    size::parse_size_as(s, size::KIB)
+}
+
+/// Page size for `PageIntKv` (0: blocks are disabled). `cipher` is None if
+/// encryption is disabled. `mac_trailer` is false for older directories
+/// whose blocks do not leave room for MACs.
+fn page_size(
+    block_size_kb: u16,
+    cipher: Option<Cipher>,
+    mac_trailer: bool,
+    version: HeaderVersion,
+) -> u64 {
+    // Bytes per page is used by encryption header (version, IV count, and
+    // tag), and the MAC trailer.
+    let page_overhead = match cipher {
+        Some(cipher) if mac_trailer => (version.header_size(cipher) + cipher.trailer_size()) as u64,
+        Some(cipher) => version.header_size(cipher) as u64,
+        None => 0,
+    };
+    match block_size_kb {
+        0 => 0,
+        kb => (kb as u64) * 1024 - page_overhead,
+    }
+}
+
+/// Scrypt parameters to derive the key with. Configs without
+/// `kdf_params_honored` use the recommended parameters.
+fn scrypt_params(config: &Config) -> io::Result<ScryptParams> {
+    if !config.kdf_params_honored {
+        return Ok(ScryptParams::recommended());
+    }
+    ScryptParams::new(config.scrypt_log_n, config.scrypt_r, config.scrypt_p).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid scrypt parameters (log_n {}, r {}, p {})",
+                config.scrypt_log_n, config.scrypt_r, config.scrypt_p
+            ),
+        )
+    })
+}
+
+/// Upper bound of memory scrypt calibration uses.
+const KDF_MAX_MEMORY: u64 = 1 << 30;
+
+/// Warn if deriving the key is this many times slower than at init.
+const KDF_SLOW_FACTOR: u64 = 4;
+
+/// Memory scrypt uses in bytes.
+fn scrypt_memory(log_n: u8, r: u32) -> u64 {
+    128 * r as u64 * (1u64 << log_n)
+}
+
+/// Double the scrypt parameter N until deriving a key takes at least
+/// `target`, or until the next N would use more than `max_memory`. Return
+/// log 2 of N and the time the last derivation took.
+fn calibrate_scrypt_log_n(
+    target: Duration,
+    r: u32,
+    p: u32,
+    max_memory: u64,
+) -> io::Result<(u8, Duration)> {
+    let salt_hex = hex::encode(rand::random::<[u8; 32]>());
+    let mut log_n = 10;
+    loop {
+        let params = ScryptParams::new(log_n, r, p).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid scrypt parameters")
+        })?;
+        let start = Instant::now();
+        password_derive(b"calibrate", &salt_hex, &params);
+        let elapsed = start.elapsed();
+        if elapsed >= target || log_n >= 30 || scrypt_memory(log_n + 1, r) > max_memory {
+            return Ok((log_n, elapsed));
+        }
+        log_n += 1;
+    }
+}
+
+/// A master key or key-encryption key. Wiped on drop.
+type Key = Secret<[u8; 32]>;
+
+/// Derive key from password.
+fn password_derive(password: &[u8], salt_hex: &str, params: &ScryptParams) -> Key {
+    let salt = hex::decode(salt_hex).unwrap();
+    let mut output = Secret::new([0u8; 32]);
+    scrypt::scrypt(password, &salt, params, &mut *output).unwrap();
+    output
+}
+
+#[test]
+fn test_password_derive_params() {
+    let derive = |params: &str| {
+        let json = format!(r#"{{"salt_hex": "0102", {}}}"#, params);
+        let config: Config = serde_json::from_str(&json).unwrap();
+        let params = scrypt_params(&config).unwrap();
+        (
+            params.log_n(),
+            *password_derive(b"pass", &config.salt_hex, &params),
+        )
+    };
+
+    // Older configs use the recommended parameters.
+    assert_eq!(derive(r#""scrypt_log_n": 4"#).0, 15);
+
+    let keys: Vec<[u8; 32]> = [(4, 8, 1), (5, 8, 1), (4, 4, 1), (4, 8, 2)]
+        .iter()
+        .map(|(n, r, p)| {
+            let params = format!(
+                r#""scrypt_log_n": {}, "scrypt_r": {}, "scrypt_p": {}, "kdf_params_honored": true"#,
+                n, r, p
+            );
+            derive(&params).1
+        })
+        .collect();
+    for (i, a) in keys.iter().enumerate() {
+        for b in &keys[i + 1..] {
+            assert_ne!(a, b);
+        }
+    }
+    let params = r#""scrypt_log_n": 4, "kdf_params_honored": true"#;
+    assert_eq!(derive(params).1, keys[0]);
+}
+
+#[test]
+fn test_calibrate_scrypt_log_n() {
+    let r = default_scrypt_r();
+    let (log_n, _) = calibrate_scrypt_log_n(Duration::from_secs(0), r, 1, 1 << 30).unwrap();
+    assert_eq!(log_n, 10);
+
+    // Bounded by memory.
+    let (log_n, _) =
+        calibrate_scrypt_log_n(Duration::from_secs(3600), r, 1, scrypt_memory(12, r)).unwrap();
+    assert_eq!(log_n, 12);
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn test_sled_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    init_cmd(
+        dir,
+        Some(4),
+        None,
+        10,
+        None,
+        Storage::Sled,
+        InitOptions::default(),
+    )
+    .unwrap();
+    let config = load_config(dir).unwrap();
+    assert_eq!(config.storage, StorageEngine::Sled);
+    assert!(!data_dir(dir, &config).unwrap().exists());
+
+    let open = || kv_from_dir_config(dir, &config, None, Lock::exclusive(0)).unwrap();
+    let mut kv = open();
+    kv.write(3, b"hello"[..].into()).unwrap();
+    kv.flush().unwrap();
+    drop(kv);
+    assert_eq!(open().read(3).unwrap(), &b"hello"[..]);
+
+    // Commands reading block files refuse it.
+    let e = fs_kv_from_dir_config(dir, &config, Lock::read_only(0)).unwrap_err();
+    assert!(e.to_string().contains("sled database"), "{}", e);
+
+    // Move the blocks to files and back.
+    let lock = Lock::exclusive(0);
+    migrate_storage_engine_cmd(dir, StorageEngine::Files, lock).unwrap();
+    let config = load_config(dir).unwrap();
+    assert_eq!(config.storage, StorageEngine::Files);
+    assert!(!dir.join(SLED_DIR).exists());
+    let kv = kv_from_dir_config(dir, &config, None, Lock::exclusive(0)).unwrap();
+    assert_eq!(kv.read(3).unwrap(), &b"hello"[..]);
+    drop(kv);
+    migrate_storage_engine_cmd(dir, StorageEngine::Sled, lock).unwrap();
+    let config = load_config(dir).unwrap();
+    assert_eq!(config.storage, StorageEngine::Sled);
+    let fs = fs_kv_from_dir_config(dir, &local_config(&config), Lock::read_only(0)).unwrap();
+    assert!(fs.keys().unwrap().is_empty());
+    drop(fs);
+    let open = || kv_from_dir_config(dir, &config, None, Lock::exclusive(0)).unwrap();
+    assert_eq!(open().read(3).unwrap(), &b"hello"[..]);
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("0"), Ok(0));
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("512M"), Ok(512 << 20));
+    assert_eq!(parse_size("2g"), Ok(2 << 30));
+    assert!(parse_size("1X").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("99999999T").is_err());
+}
+
+#[test]
+fn test_sync_friendly_churn() {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(path) = stack.pop() {
+            for entry in fs::read_dir(path).unwrap() {
+                let path = entry.unwrap().path();
+                match path.is_dir() {
+                    true => stack.push(path),
+                    false => drop(files.insert(path.clone(), fs::read(&path).unwrap())),
+                }
+            }
+        }
+        files
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let options = InitOptions {
+        sync_friendly: true,
+        ..Default::default()
+    };
+    init_cmd(dir, None, None, 10, None, Storage::Files, options).unwrap();
+    let config = load_config(dir).unwrap();
+    assert_eq!(config.block_size_kb, SYNC_FRIENDLY_BLOCK_SIZE_KB);
+    assert!(config.sequential_allocation);
+    assert!(!default_sequential_allocation());
+
+    // Entry 0 stands for a folder listing the files, and the others for
+    // the files.
+    let file = |i: usize, edited: bool| -> Bytes {
+        let mut data = vec![i as u8; 1000 + i * 37 % 20000];
+        data[0] = edited as u8;
+        data.into()
+    };
+    let folder = |edited: bool| -> Bytes { vec![edited as u8; 3000].into() };
+    let mut kv = kv_from_dir_config(dir, &config, None, Lock::exclusive(0)).unwrap();
+    kv.write(0, folder(false)).unwrap();
+    for i in 1..300 {
+        kv.write(i, file(i, false)).unwrap();
+    }
+    kv.flush().unwrap();
+    let before = snapshot(dir);
+
+    // Edit one file. Rewriting other files with the same content changes
+    // nothing.
+    kv.write(100, file(100, true)).unwrap();
+    kv.write(0, folder(true)).unwrap();
+    for i in 200..210 {
+        kv.write(i, file(i, false)).unwrap();
+    }
+    kv.flush().unwrap();
+    let after = snapshot(dir);
+    let changed: Vec<_> = before
+        .keys()
+        .chain(after.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|path| before.get(*path) != after.get(*path))
+        .collect();
+    assert!(changed.len() <= 8, "{} files changed", changed.len());
+    let changed_bytes: usize = changed
+        .iter()
+        .filter_map(|p| after.get(*p))
+        .map(|d| d.len())
+        .sum();
+    assert!(
+        changed_bytes <= 512 << 10,
+        "{} bytes changed",
+        changed_bytes
+    );
+}
+
+#[test]
+fn test_snapshot_while_writing() {
+    use parking_lot::RwLock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir().unwrap();
+    let vault = dir.path().join("vault");
+    fs::create_dir(&vault).unwrap();
+    let options = InitOptions::default();
+    init_cmd(&vault, Some(4), None, 10, None, Storage::Files, options).unwrap();
+    let config = load_config(&vault).unwrap();
+    let kv = kv_from_dir_config(&vault, &config, None, Lock::exclusive(0)).unwrap();
+    let kv = Arc::new(RwLock::new(kv));
+
+    // Keep rewriting entries of various sizes, flushing now and then, as a
+    // server would.
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (kv, stop) = (kv.clone(), stop.clone());
+        std::thread::spawn(move || {
+            let mut round = 0u32;
+            while !stop.load(Ordering::Acquire) {
+                round += 1;
+                for i in 0..40 {
+                    let data = vec![round as u8; (i * 997 + round as usize * 31) % 20000];
+                    kv.write().write(i, data.into()).unwrap();
+                    if i % 13 == 0 {
+                        kv.write().flush().unwrap();
+                    }
+                }
+            }
+            round
+        })
+    };
+
+    let mut expected = Vec::new();
+    for i in 0..3 {
+        std::thread::sleep(Duration::from_millis(50));
+        let dest = dir.path().join(format!("snap{}", i));
+        let mut kv = kv.write();
+        let entries: Vec<(usize, Bytes)> = kv
+            .keys()
+            .unwrap()
+            .into_iter()
+            .map(|k| (k, kv.read(k).unwrap()))
+            .collect();
+        write_snapshot(&vault, &config, &dest, |data| kv.snapshot(data)).unwrap();
+        expected.push((dest, entries));
+    }
+    stop.store(true, Ordering::Release);
+    assert!(writer.join().unwrap() > 1);
+
+    for (dest, entries) in expected {
+        let config = load_config(&dest).unwrap();
+        let kv = kv_from_dir_config(&dest, &config, None, Lock::read_only(0)).unwrap();
+        let keys: Vec<usize> = entries.iter().map(|(k, _)| *k).collect();
+        assert_eq!(kv.keys().unwrap(), keys);
+        for (k, data) in entries {
+            assert_eq!(kv.read(k).unwrap(), data);
+        }
+    }
+}
+
+#[test]
+fn test_reload_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let options = InitOptions::default();
+    init_cmd(dir, Some(4), None, 10, None, Storage::Files, options).unwrap();
+    let config = load_config(dir).unwrap();
+    let kv = kv_from_dir_config(dir, &config, None, Lock::exclusive(0)).unwrap();
+    let served = Served {
+        fs: IntKvFtpFs::new(kv),
+        dir: dir.to_path_buf(),
+        key: Arc::new(None),
+        tracing: false,
+        moving: Default::default(),
+        active: Arc::new(Mutex::new(config.clone())),
+    };
+    assert_eq!(served.reload().unwrap(), "No changes in x79d8cfg.json");
+
+    let mut changed = config.clone();
+    changed.cache_size_limit = 1 << 20;
+    changed.throttle_write_kbps = 1024;
+    changed.block_size_kb = 8;
+    save_config(dir, &changed).unwrap();
+    let lines = served.reload().unwrap();
+    let lines: Vec<&str> = lines.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "Skipped block_size_kb: cannot change while serving, restart to apply",
+            "Changed cache_size_limit from 268435456 to 1048576",
+            "Changed throttle_write_kbps from 0 to 1024",
+        ]
+    );
+    let stats = served.fs.stats();
+    assert_eq!(stats["throttle.write_limit"], 1 << 20);
+    let active = served.active.lock().clone();
+    assert_eq!(
+        (active.cache_size_limit, active.block_size_kb),
+        (1 << 20, 4)
+    );
+
+    // Cache limits change without reopening, which would drop the cache.
+    let kv = BufferedIntKv::new(Box::new(MemIntKv::new()));
+    let cached = Served {
+        fs: IntKvFtpFs::new(Box::new(kv)),
+        active: Arc::new(Mutex::new(changed.clone())),
+        ..served.clone()
+    };
+    changed.cache_entries_limit = 1000;
+    save_config(dir, &changed).unwrap();
+    cached.reload().unwrap();
+    assert_eq!(cached.active.lock().cache_entries_limit, 1000);
+    assert!(!cached.fs.stats().contains_key("fs.read_count"));
+
+    // Invalid configs change nothing.
+    changed.quota_max_mb = 1;
+    changed.compression_level = 12;
+    save_config(dir, &changed).unwrap();
+    assert!(served.reload().is_err());
+    assert_eq!(served.active.lock().quota_max_mb, 0);
+    assert!(!served.fs.stats().contains_key("quota.bytes"));
+}
+
+#[test]
+fn test_rekey_legacy() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let options = InitOptions::default();
+    init_cmd(dir, Some(4), None, 10, None, Storage::Files, options).unwrap();
+
+    // A directory in the baseline format: the key is derived from the
+    // password, and entries have neither key ids nor MACs.
+    let mut config = load_config(dir).unwrap();
+    config.salt_hex = hex::encode([1; 32]);
+    config.cipher = Cipher::Aes256Cfb;
+    config.entry_header_version = 0;
+    config.mac_trailer = false;
+    config.kdf_params_honored = true;
+    save_config(dir, &config).unwrap();
+    let (old_key, _) = unlock(&config, b"old").unwrap();
+    let lock = Lock::exclusive(0);
+    let mut kv = kv_from_dir_config(dir, &config, Some(&old_key), lock).unwrap();
+    let values: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 3000]).collect();
+    for (i, value) in values.iter().enumerate() {
+        kv.write(i, value.clone().into()).unwrap();
+    }
+    kv.flush().unwrap();
+    drop(kv);
+    let mut raw = raw_kv_from_dir_config(dir, &config, &old_key, lock).unwrap();
+    for index in raw.keys().unwrap() {
+        let data = raw.read(index).unwrap();
+        raw.write_legacy(index, &data).unwrap();
+    }
+    raw.flush().unwrap();
+    drop(raw);
+    let read_all = |config: &Config, key: &Key| {
+        let kv = kv_from_dir_config(dir, config, Some(key), lock).unwrap();
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(kv.read(i).unwrap(), &value[..]);
+        }
+    };
+    read_all(&config, &old_key);
+
+    // A wrong old password is refused before the config changes.
+    let (wrong_key, _) = unlock(&config, b"wrong").unwrap();
+    let err = rekey_dir(dir, config.clone(), &wrong_key, b"new", lock).unwrap_err();
+    assert!(err.to_string().contains("incorrect password"), "{}", err);
+    let resumed = load_config(dir).unwrap();
+    assert_eq!(resumed.salt_hex, config.salt_hex);
+    assert!(resumed.wrapped_keys_hex.is_empty());
+
+    // Resumed with the right one. Entries are read with the old key, and
+    // re-encrypted.
+    rekey_dir(dir, resumed, &old_key, b"new", lock).unwrap();
+    let config = load_config(dir).unwrap();
+    assert!(config.rekey_salt_hex.is_empty());
+    assert!(unlock(&config, b"old").is_err());
+    let (key, _) = unlock(&config, b"new").unwrap();
+    read_all(&config, &key);
+    let raw = raw_kv_from_dir_config(dir, &config, &key, lock).unwrap();
+    let raw = raw.with_required_mac(true);
+    for index in raw.keys().unwrap() {
+        raw.read(index).unwrap();
+    }
+}
+
+#[test]
+fn test_key_check_recorded() {
+    for &block_size_kb in &[0, 4] {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let options = InitOptions::default();
+        init_cmd(
+            dir,
+            Some(block_size_kb),
+            None,
+            10,
+            None,
+            Storage::Files,
+            options,
+        )
+        .unwrap();
+        // A key derived from the password, without a check value.
+        let mut config = load_config(dir).unwrap();
+        config.salt_hex = hex::encode([1; 32]);
+        config.kdf_params_honored = true;
+        save_config(dir, &config).unwrap();
+        let (key, _) = unlock(&config, b"right").unwrap();
+        let (wrong_key, _) = unlock(&config, b"wrong").unwrap();
+        let open = |key: &Key, lock| kv_from_dir_config(dir, &config, Some(key), lock);
+        let mut kv = open(&key, Lock::exclusive(0)).unwrap();
+        kv.write(1, b"a"[..].into()).unwrap();
+        kv.flush().unwrap();
+        drop(kv);
+        assert!(load_config(dir).unwrap().key_check_hex.is_empty());
+
+        // Wrong passwords fail when opening. Readers do not write the config.
+        let err = open(&wrong_key, Lock::exclusive(0)).unwrap_err();
+        assert!(err.to_string().contains("incorrect password"), "{}", err);
+        drop(open(&key, Lock::read_only(0)).unwrap());
+        assert!(load_config(dir).unwrap().key_check_hex.is_empty());
+
+        // Recorded, then told at unlock.
+        drop(open(&key, Lock::exclusive(0)).unwrap());
+        let config = load_config(dir).unwrap();
+        assert!(!config.key_check_hex.is_empty());
+        let err = unlock(&config, b"wrong").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
 }
@@ -0,0 +1,317 @@
+//! Format versions of directories, and migrations between them.
+//!
+//! The config records the format version a directory was written with,
+//! and features changing the format, like `varint-metadata`. Directories
+//! with a newer version or unknown features are refused, so an older
+//! binary cannot scramble them. `x79d8 upgrade` applies migrations in
+//! order to bring older directories to `FORMAT_VERSION`.
+
+use super::{save_config, Config};
+use crate::intkv::wrapper::{Cipher, HeaderVersion};
+use crate::util::Encoding;
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+
+/// Format version of directories written by this binary.
+pub(super) const FORMAT_VERSION: u32 = 3;
+
+/// Blocks of metadata and folders use `Encoding::Varint`.
+const VARINT_METADATA: &str = "varint-metadata";
+
+/// Encrypted entries start with the version and `Format` bytes of
+/// `HeaderVersion::V1`.
+const ENTRY_HEADER_V1: &str = "entry-header-v1";
+
+/// GCM tags of encrypted entries also cover their index.
+const GCM_INDEX_AAD: &str = "gcm-index-aad";
+
+/// Entries start with a byte telling how they are compressed.
+const COMPRESSED_ENTRIES: &str = "compressed-entries";
+
+/// Features understood by this binary.
+const FEATURES: &[&str] = &[
+    VARINT_METADATA,
+    ENTRY_HEADER_V1,
+    GCM_INDEX_AAD,
+    COMPRESSED_ENTRIES,
+];
+
+/// A step from the previous format version to `version`.
+pub(super) struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&Path, &mut Config) -> io::Result<()>,
+}
+
+/// Migrations of this binary, by version.
+pub(super) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "record features in the config",
+        apply: record_features,
+    },
+    Migration {
+        version: 3,
+        description: "record entry header, GCM and compression features",
+        apply: record_features,
+    },
+];
+
+pub(super) const fn default_format_version() -> u32 {
+    1
+}
+
+/// Features the config needs a binary to understand.
+pub(super) fn required_features(config: &Config) -> Vec<String> {
+    let mut features = Vec::new();
+    if config.metadata_encoding == Encoding::Varint {
+        features.push(VARINT_METADATA.to_string());
+    }
+    if !config.salt_hex.is_empty() {
+        if HeaderVersion::try_from(config.entry_header_version).ok() == Some(HeaderVersion::V1) {
+            features.push(ENTRY_HEADER_V1.to_string());
+        }
+        if config.cipher == Cipher::Aes256Gcm {
+            features.push(GCM_INDEX_AAD.to_string());
+        }
+    }
+    if config.compression {
+        features.push(COMPRESSED_ENTRIES.to_string());
+    }
+    features
+}
+
+fn record_features(_dir: &Path, config: &mut Config) -> io::Result<()> {
+    for feature in required_features(config) {
+        if !config.features.contains(&feature) {
+            config.features.push(feature);
+        }
+    }
+    Ok(())
+}
+
+/// Refuse directories this binary does not understand.
+pub(super) fn check_supported(dir: &Path, config: &Config) -> io::Result<()> {
+    check_version(dir, config, FORMAT_VERSION)
+}
+
+fn check_version(dir: &Path, config: &Config, latest: u32) -> io::Result<()> {
+    let unsupported = |what: String| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} uses {}, which needs a version of x79d8 newer than {}",
+                dir.display(),
+                what,
+                env!("CARGO_PKG_VERSION")
+            ),
+        )
+    };
+    if config.format_version > latest {
+        return Err(unsupported(format!(
+            "format version {}",
+            config.format_version
+        )));
+    }
+    let unknown: Vec<&str> = config
+        .features
+        .iter()
+        .map(|f| f.as_str())
+        .filter(|f| !FEATURES.contains(f))
+        .collect();
+    match unknown.len() {
+        0 => Ok(()),
+        1 => Err(unsupported(format!("the feature {}", unknown[0]))),
+        _ => Err(unsupported(format!("the features {}", unknown.join(", ")))),
+    }
+}
+
+/// Apply `migrations` newer than the format version of the directory in
+/// order. The config is saved after each, so an interrupted upgrade resumes
+/// from there. `progress` is called with the position and the number of
+/// migrations before each. Return the number of migrations applied.
+pub(super) fn upgrade(
+    dir: &Path,
+    config: &mut Config,
+    migrations: &[Migration],
+    mut progress: impl FnMut(usize, usize, &Migration),
+) -> io::Result<usize> {
+    let latest = migrations
+        .last()
+        .map_or(default_format_version(), |m| m.version);
+    check_version(dir, config, latest)?;
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|m| m.version > config.format_version)
+        .collect();
+    for (i, migration) in pending.iter().enumerate() {
+        progress(i, pending.len(), migration);
+        (migration.apply)(dir, config)?;
+        config.format_version = migration.version;
+        save_config(dir, config)?;
+    }
+    Ok(pending.len())
+}
+
+#[cfg(test)]
+fn test_dir() -> tempfile::TempDir {
+    use super::{init_cmd, InitOptions, Storage};
+    let dir = tempfile::tempdir().unwrap();
+    let options = InitOptions::default();
+    init_cmd(dir.path(), Some(4), None, 10, None, Storage::Files, options).unwrap();
+    dir
+}
+
+#[test]
+fn test_upgrade_refuse_newer() {
+    use super::load_config;
+    let dir = test_dir();
+    let dir = dir.path();
+    let config = load_config(dir).unwrap();
+    assert_eq!(config.format_version, FORMAT_VERSION);
+    assert_eq!(config.features, [VARINT_METADATA]);
+
+    let newer = Config {
+        format_version: FORMAT_VERSION + 1,
+        ..config.clone()
+    };
+    save_config(dir, &newer).unwrap();
+    let err = load_config(dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    let message = err.to_string();
+    assert!(message.contains("format version 4"), "{}", message);
+    assert!(message.contains(env!("CARGO_PKG_VERSION")), "{}", message);
+
+    let mut unknown = config;
+    unknown.features.push("sharded-trees".to_string());
+    save_config(dir, &unknown).unwrap();
+    let message = load_config(dir).unwrap_err().to_string();
+    assert!(
+        message.contains("the feature sharded-trees,"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn test_upgrade_sequential() {
+    use super::load_config;
+    let dir = test_dir();
+    let dir = dir.path();
+    let mut config = load_config(dir).unwrap();
+    config.format_version = 1;
+    config.features.clear();
+
+    // Record the order in an unrelated field.
+    fn step(config: &mut Config, n: u64) -> io::Result<()> {
+        config.quota_max_mb = config.quota_max_mb * 10 + n;
+        Ok(())
+    }
+    let mut migrations = vec![
+        Migration {
+            version: 2,
+            description: "two",
+            apply: |_, c| step(c, 2),
+        },
+        Migration {
+            version: 3,
+            description: "three",
+            apply: |_, _| Err(io::Error::other("interrupted")),
+        },
+        Migration {
+            version: 4,
+            description: "four",
+            apply: |_, c| step(c, 4),
+        },
+    ];
+
+    // Interrupted. Completed migrations are saved.
+    let mut seen = Vec::new();
+    let progress = |i, n, m: &Migration| seen.push((i, n, m.version));
+    assert!(upgrade(dir, &mut config, &migrations, progress).is_err());
+    assert_eq!(seen, [(0, 3, 2), (1, 3, 3)]);
+    let mut config = load_config(dir).unwrap();
+    assert_eq!((config.format_version, config.quota_max_mb), (2, 2));
+
+    // Resumed from the saved version.
+    migrations[1].apply = |_, c| step(c, 3);
+    let mut seen = Vec::new();
+    let progress = |i, n, m: &Migration| seen.push((i, n, m.version));
+    assert_eq!(upgrade(dir, &mut config, &migrations, progress).unwrap(), 2);
+    assert_eq!(seen, [(0, 2, 3), (1, 2, 4)]);
+    let config = load_config(dir);
+    // Newer than this binary after the test migrations.
+    assert!(config.is_err());
+}
+
+#[test]
+fn test_upgrade_rerun() {
+    use super::load_config;
+    let dir = test_dir();
+    let dir = dir.path();
+    let mut config = load_config(dir).unwrap();
+    config.format_version = 1;
+    config.features.clear();
+    save_config(dir, &config).unwrap();
+
+    let mut config = load_config(dir).unwrap();
+    assert_eq!(
+        upgrade(dir, &mut config, MIGRATIONS, |_, _, _| {}).unwrap(),
+        2
+    );
+    assert_eq!(config.features, [VARINT_METADATA]);
+    let saved = serde_json::to_string(&load_config(dir).unwrap()).unwrap();
+
+    // Nothing to do again.
+    let mut config = load_config(dir).unwrap();
+    assert_eq!(
+        upgrade(dir, &mut config, MIGRATIONS, |_, _, _| {}).unwrap(),
+        0
+    );
+    assert_eq!(serde_json::to_string(&config).unwrap(), saved);
+    config.format_version = 1;
+    assert_eq!(
+        upgrade(dir, &mut config, MIGRATIONS, |_, _, _| {}).unwrap(),
+        2
+    );
+    assert_eq!(serde_json::to_string(&config).unwrap(), saved);
+}
+
+#[test]
+fn test_upgrade_entry_features() {
+    use super::load_config;
+    let dir = test_dir();
+    let dir = dir.path();
+    let mut config = load_config(dir).unwrap();
+    config.salt_hex = "00".into();
+    config.cipher = Cipher::Aes256Gcm;
+    config.compression = true;
+    assert_eq!(
+        required_features(&config),
+        [
+            VARINT_METADATA,
+            ENTRY_HEADER_V1,
+            GCM_INDEX_AAD,
+            COMPRESSED_ENTRIES
+        ]
+    );
+    config.entry_header_version = HeaderVersion::V0.into();
+    config.cipher = Cipher::Aes256Cfb;
+    assert_eq!(
+        required_features(&config),
+        [VARINT_METADATA, COMPRESSED_ENTRIES]
+    );
+
+    // Recorded by upgrading directories of the previous version.
+    config.format_version = 2;
+    config.features = vec![VARINT_METADATA.to_string()];
+    save_config(dir, &config).unwrap();
+    let mut config = load_config(dir).unwrap();
+    assert_eq!(
+        upgrade(dir, &mut config, MIGRATIONS, |_, _, _| {}).unwrap(),
+        1
+    );
+    let config = load_config(dir).unwrap();
+    assert_eq!(config.features, [VARINT_METADATA, COMPRESSED_ENTRIES]);
+}
@@ -0,0 +1,335 @@
+//! Checks of configs, run when they are loaded, so a typo or a value out
+//! of range is reported with the field to fix instead of failing later
+//! with an unrelated error.
+
+use super::{header_version, page_size, scrypt_memory, Config, CONFIG_FILE};
+use crate::intkv::wrapper::PageIntKv;
+use scrypt::Params as ScryptParams;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Memory deriving the key may use. Beyond it, the parameters are more
+/// likely a typo than intended.
+const SCRYPT_MAX_MEMORY: u64 = 16 << 30;
+
+/// A problem of a field of the config.
+#[derive(Debug)]
+pub(super) struct Problem {
+    /// JSON path of the field, like "$.block_size_kb".
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(field: &str, message: String) -> Self {
+        Self {
+            path: format!("$.{}", field),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl Config {
+    /// Problems of values, alone or combined with other values.
+    pub(super) fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let encrypted = !self.salt_hex.is_empty();
+        // Older configs derive the key with the recommended parameters, so
+        // theirs are unused.
+        if encrypted && self.kdf_params_honored {
+            problems.extend(self.validate_scrypt());
+        }
+        match header_version(self) {
+            Err(e) => problems.push(Problem::new("entry_header_version", e.to_string())),
+            Ok(version) if self.block_size_kb > 0 => {
+                let cipher = encrypted.then_some(self.cipher);
+                let page_size = page_size(self.block_size_kb, cipher, self.mac_trailer, version);
+                let overhead = self.block_size_kb as u64 * 1024 - page_size;
+                let min = PageIntKv::min_page_size(self.metadata_encoding);
+                if page_size < min {
+                    problems.push(Problem::new(
+                        "block_size_kb",
+                        format!(
+                            "{} KB leaves {} bytes per block after {} bytes of encryption overhead, fewer than the {} needed; use at least {} KB, or 0 to disable blocks",
+                            self.block_size_kb,
+                            page_size,
+                            overhead,
+                            min,
+                            (min + overhead).div_ceil(1024)
+                        ),
+                    ));
+                }
+            }
+            Ok(_) => {}
+        }
+        let needing_blocks = [
+            ("block_device", !self.block_device.is_empty()),
+            ("parity_dirs", !self.parity_dirs.is_empty()),
+        ];
+        for &(field, set) in needing_blocks.iter() {
+            if set && self.block_size_kb == 0 {
+                problems.push(Problem::new(
+                    "block_size_kb",
+                    format!("0 disables blocks, which {} needs", field),
+                ));
+            }
+        }
+        if !(1..=9).contains(&self.compression_level) {
+            problems.push(Problem::new(
+                "compression_level",
+                format!("{} is not from 1 to 9", self.compression_level),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.log_compaction_ratio) {
+            problems.push(Problem::new(
+                "log_compaction_ratio",
+                format!("{} is not from 0 to 1", self.log_compaction_ratio),
+            ));
+        }
+        problems
+    }
+
+    fn validate_scrypt(&self) -> Option<Problem> {
+        let (log_n, r, p) = (self.scrypt_log_n, self.scrypt_r, self.scrypt_p);
+        if r == 0 || p == 0 {
+            let field = if r == 0 { "scrypt_r" } else { "scrypt_p" };
+            return Some(Problem::new(
+                field,
+                "0 is not a valid scrypt parameter, use at least 1".into(),
+            ));
+        }
+        // Up to the largest N fitting in memory.
+        let max_log_n = (0..64u8)
+            .take_while(|&n| scrypt_memory(n, r) <= SCRYPT_MAX_MEMORY)
+            .last()
+            .unwrap_or(0);
+        if log_n > max_log_n {
+            return Some(Problem::new(
+                "scrypt_log_n",
+                format!(
+                    "{} needs more than {} GB of memory to derive the key with scrypt_r {}; use at most {}",
+                    log_n,
+                    SCRYPT_MAX_MEMORY >> 30,
+                    r,
+                    max_log_n
+                ),
+            ));
+        }
+        match ScryptParams::new(log_n, r, p) {
+            Ok(_) => None,
+            Err(_) => Some(Problem::new(
+                "scrypt_log_n",
+                format!(
+                    "invalid scrypt parameters (log_n {}, r {}, p {})",
+                    log_n, r, p
+                ),
+            )),
+        }
+    }
+}
+
+/// Fields of `json` that `config`, parsed from it, does not have.
+pub(super) fn unknown_fields(json: &serde_json::Value, config: &Config) -> Vec<Problem> {
+    let known = serde_json::to_value(config).unwrap();
+    let (json, known) = match (json.as_object(), known.as_object()) {
+        (Some(json), Some(known)) => (json, known),
+        _ => return Vec::new(),
+    };
+    json.keys()
+        .filter(|name| !known.contains_key(name.as_str()))
+        .map(|name| {
+            let message = match closest(name, known.keys().map(|k| k.as_str())) {
+                Some(known) => format!("unknown field, did you mean \"{}\"?", known),
+                None => "unknown field; if a newer x79d8 wrote it, use --ignore-unknown-config to read the directory".to_string(),
+            };
+            Problem::new(name, message)
+        })
+        .collect()
+}
+
+/// Problems of usage exceeding quotas, which refuse writes adding blocks.
+pub(super) fn check_quota(config: &Config, blocks: u64, bytes: u64) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if config.quota_max_blocks > 0 && blocks > config.quota_max_blocks {
+        problems.push(Problem::new(
+            "quota_max_blocks",
+            format!(
+                "{} is fewer than the {} blocks stored, so writes adding blocks are refused",
+                config.quota_max_blocks, blocks
+            ),
+        ));
+    }
+    let mb = bytes.div_ceil(1 << 20);
+    if config.quota_max_mb > 0 && bytes > config.quota_max_mb << 20 {
+        problems.push(Problem::new(
+            "quota_max_mb",
+            format!(
+                "{} is less than the {} MB stored, so writes adding blocks are refused",
+                config.quota_max_mb, mb
+            ),
+        ));
+    }
+    problems
+}
+
+/// An error listing `problems`.
+pub(super) fn error(dir: &Path, problems: &[Problem]) -> io::Error {
+    let mut message = format!(
+        "{} has {} problem{}:",
+        dir.join(CONFIG_FILE).display(),
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" }
+    );
+    for problem in problems {
+        message += &format!("\n  {}", problem);
+    }
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The name in `names` closest to `name`, if only a typo away.
+fn closest<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    names
+        .map(|n| (edit_distance(name, n), n))
+        .filter(|&(d, _)| d <= 2)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, n)| n)
+}
+
+/// Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substituted = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("", ""), 0);
+    assert_eq!(edit_distance("abc", ""), 3);
+    assert_eq!(edit_distance("cache_size_limt", "cache_size_limit"), 1);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    let names = ["cache_size_limit", "cache_entries_limit"];
+    assert_eq!(
+        closest("cache_sise_limit", names.iter().copied()),
+        Some("cache_size_limit")
+    );
+    assert_eq!(closest("shards_v2", names.iter().copied()), None);
+}
+
+#[test]
+fn test_validate_config() {
+    use super::{init_cmd, load_config, save_config, InitOptions, Storage};
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    init_cmd(
+        dir,
+        Some(4),
+        None,
+        10,
+        None,
+        Storage::Files,
+        InitOptions::default(),
+    )
+    .unwrap();
+    let config = load_config(dir).unwrap();
+    assert!(config.validate().is_empty());
+
+    // Every problem is reported, with its field.
+    let path = dir.join(CONFIG_FILE);
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    json["cache_size_limt"] = 1.into();
+    json["shards_v2"] = 1.into();
+    json["compression_level"] = 12.into();
+    std::fs::write(&path, json.to_string()).unwrap();
+    let err = load_config(dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let message = err.to_string();
+    assert!(message.contains("has 3 problems"), "{}", message);
+    assert!(
+        message.contains("$.cache_size_limt: unknown field, did you mean \"cache_size_limit\"?"),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("$.shards_v2: unknown field;"),
+        "{}",
+        message
+    );
+    assert!(message.contains("$.compression_level: 12"), "{}", message);
+
+    // Unknown fields can be ignored. Other problems cannot.
+    assert!(super::load_config_with(dir, true).is_err());
+    let mut config = config;
+    save_config(dir, &config).unwrap();
+    json = serde_json::to_value(&config).unwrap();
+    json["shards_v2"] = 1.into();
+    std::fs::write(&path, json.to_string()).unwrap();
+    assert!(load_config(dir).is_err());
+    assert!(super::load_config_with(dir, true).is_ok());
+
+    // Combined values.
+    config.salt_hex = "00".into();
+    config.scrypt_log_n = 40;
+    config.block_size_kb = 0;
+    config.parity_dirs = vec!["a".into(), "b".into()];
+    let problems = config.validate();
+    let paths: Vec<&str> = problems.iter().map(|p| p.path.as_str()).collect();
+    assert_eq!(paths, ["$.scrypt_log_n", "$.block_size_kb"]);
+    assert!(
+        problems[0].message.ends_with("use at most 24"),
+        "{}",
+        problems[0]
+    );
+    assert!(
+        problems[1].message.contains("parity_dirs"),
+        "{}",
+        problems[1]
+    );
+    config.scrypt_log_n = 15;
+    config.block_size_kb = 4;
+    assert!(config.validate().is_empty());
+
+    // Scrypt parameters are not checked where they are unused.
+    config.scrypt_r = 0;
+    assert_eq!(config.validate().len(), 1);
+    config.kdf_params_honored = false;
+    assert!(config.validate().is_empty());
+}
+
+#[test]
+fn test_check_quota() {
+    use super::load_config;
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let options = super::InitOptions::default();
+    super::init_cmd(dir, Some(4), None, 10, None, super::Storage::Files, options).unwrap();
+    let mut config = load_config(dir).unwrap();
+    assert!(check_quota(&config, 100, 1 << 30).is_empty());
+    config.quota_max_blocks = 10;
+    config.quota_max_mb = 1;
+    assert!(check_quota(&config, 10, 1 << 20).is_empty());
+    let problems = check_quota(&config, 11, (3 << 20) + 1);
+    assert_eq!(problems.len(), 2);
+    assert!(
+        problems[1].to_string().contains("the 4 MB stored"),
+        "{}",
+        problems[1]
+    );
+}
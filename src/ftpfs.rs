@@ -1,6 +1,10 @@
 use crate::intkv::Bytes;
+use crate::intkv::CacheLimits;
+use crate::intkv::Hint;
 use crate::intkv::IntKv;
+use crate::intkv::Stats;
 use crate::util;
+use crate::util::Encoding;
 use libunftp::storage;
 use libunftp::storage::Error;
 use libunftp::storage::ErrorKind;
@@ -8,16 +12,16 @@ use libunftp::storage::Fileinfo;
 use libunftp::storage::Metadata;
 use libunftp::storage::Result;
 use libunftp::storage::StorageBackend;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::io;
 use std::sync::{atomic::AtomicU64, atomic::Ordering, Arc};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     ffi::OsStr,
-    path::{Component, Path},
+    path::{Component, Path, PathBuf},
 };
 use tokio::io::AsyncReadExt;
 use tokio::time::Duration;
@@ -31,24 +35,67 @@ macro_rules! denied {
     };
 }
 
-const WRITE_DELAY_SECS: u64 = 5;
+pub(crate) const WRITE_DELAY_SECS: u64 = 5;
+
+/// Number of pages to compact before flushing.
+const COMPACT_PAGES_PER_FLUSH: usize = 16;
+
+/// Number of recently used trees to pin, in addition to the root tree.
+const PINNED_TREES: usize = 64;
 
 /// Expose `IntKv` as a libunftp filesystem.
 #[derive(Debug, Clone)]
 pub struct IntKvFtpFs {
     kv: Arc<RwLock<Box<dyn IntKv>>>,
     flush_timer_id: Arc<AtomicU64>,
+    flush_delay: Arc<Mutex<Option<Duration>>>,
+    recent_trees: Arc<Mutex<VecDeque<u64>>>,
+    tree_encoding: Encoding,
 }
 
 impl IntKvFtpFs {
     pub fn new(kv: Box<dyn IntKv>) -> Self {
+        kv.pin(ROOT_ID as _);
         Self {
             kv: Arc::new(RwLock::new(kv)),
             flush_timer_id: Default::default(),
+            flush_delay: Arc::new(Mutex::new(Some(Duration::from_secs(WRITE_DELAY_SECS)))),
+            recent_trees: Default::default(),
+            tree_encoding: Encoding::Fixint,
         }
     }
 
+    /// Set the encoding of folders written from now on. Folders written
+    /// with either encoding can be read.
+    pub fn with_tree_encoding(mut self, encoding: Encoding) -> Self {
+        self.tree_encoding = encoding;
+        self
+    }
+
+    /// Set the delay of flushing after changes.
+    /// `None` disables the flush timer, useful if the `IntKv` flushes
+    /// by itself.
+    pub fn with_flush_delay(self, delay: Option<Duration>) -> Self {
+        self.set_flush_delay(delay);
+        self
+    }
+
+    /// Change the delay of flushing for this and its clones. Flushes
+    /// already scheduled keep their delay.
+    pub(crate) fn set_flush_delay(&self, delay: Option<Duration>) {
+        *self.flush_delay.lock() = delay;
+    }
+
+    /// Change the limits of caches of the `IntKv`, keeping what is cached.
+    pub(crate) fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.read().set_cache_limits(limits);
+    }
+
     fn schedule_flush(&self) {
+        let delay = match *self.flush_delay.lock() {
+            Some(delay) => delay,
+            None => return,
+        };
         let kv = self.kv.clone();
         let timer_id1 = self.flush_timer_id.clone();
         let timer_id2 = self
@@ -56,7 +103,7 @@ impl IntKvFtpFs {
             .fetch_add(1, Ordering::AcqRel)
             .wrapping_add(1);
         tokio::task::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(WRITE_DELAY_SECS)).await;
+            tokio::time::sleep(delay).await;
             if timer_id1.load(Ordering::Acquire) == timer_id2 {
                 maybe_flush(&kv)
             }
@@ -66,14 +113,92 @@ impl IntKvFtpFs {
     pub(crate) fn flush(&mut self) -> io::Result<()> {
         self.kv.write().flush()
     }
+
+    /// Flush, then copy the storage to the new directory `dest` by
+    /// `IntKv::snapshot`. Changes wait until the copy is done.
+    pub(crate) fn snapshot(&self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        let mut kv = self.kv.write();
+        kv.flush()?;
+        kv.snapshot(dest)
+    }
+
+    /// Flush, then replace the `IntKv` by the one `open` returns, ex. after
+    /// storage changed. Keep the current one if `open` fails. Changes wait
+    /// until it is done.
+    pub(crate) fn replace_kv(
+        &self,
+        open: impl FnOnce() -> io::Result<Box<dyn IntKv>>,
+    ) -> io::Result<()> {
+        let mut kv = self.kv.write();
+        kv.flush()?;
+        let new = open()?;
+        new.pin(ROOT_ID as _);
+        for &index in self.recent_trees.lock().iter() {
+            new.pin(index as _);
+        }
+        *kv = new;
+        Ok(())
+    }
+
+    /// Stats of the `IntKv`.
+    pub(crate) fn stats(&self) -> Stats {
+        self.kv.read().stats()
+    }
+
+    /// Run `IntKv::compact_step`, then return the stats.
+    pub(crate) fn compact_step(&self, max_pages: usize) -> io::Result<Stats> {
+        let mut kv = self.kv.write();
+        kv.compact_step(max_pages)?;
+        Ok(kv.stats())
+    }
+
+    /// Pin a recently used tree so it stays cached.
+    fn touch_tree(&self, kv: &dyn IntKv, index: u64) {
+        if index == ROOT_ID {
+            return;
+        }
+        let mut recent = self.recent_trees.lock();
+        match recent.iter().position(|&i| i == index) {
+            Some(pos) => {
+                recent.remove(pos);
+            }
+            None => {
+                kv.pin(index as _);
+                if recent.len() >= PINNED_TREES {
+                    if let Some(old) = recent.pop_front() {
+                        kv.unpin(old as _);
+                    }
+                }
+            }
+        }
+        recent.push_back(index);
+    }
+
+    /// Unpin a removed tree.
+    fn forget_tree(&self, kv: &dyn IntKv, index: u64) {
+        let mut recent = self.recent_trees.lock();
+        if let Some(pos) = recent.iter().position(|&i| i == index) {
+            recent.remove(pos);
+            kv.unpin(index as _);
+        }
+    }
 }
 
 fn maybe_flush(kv: &Arc<RwLock<Box<dyn IntKv>>>) {
     log::info!("Writing changes to disk");
-    match kv.write().flush() {
-        Err(e) => log::error!("Cannot flush: {:?}", e),
-        Ok(_) => {}
+    let mut kv = kv.write();
+    if let Err(e) = kv.compact_step(COMPACT_PAGES_PER_FLUSH) {
+        crate::log_kv!(log::Level::Warn, "Cannot compact"; error = format!("{:?}", e));
+    }
+    let start = Instant::now();
+    match kv.flush() {
+        Ok(()) => {
+            let secs = start.elapsed().as_secs_f64();
+            crate::log_kv!(log::Level::Debug, "Wrote changes to disk"; secs = secs);
+        }
+        Err(e) => crate::log_kv!(log::Level::Error, "Cannot flush"; error = format!("{:?}", e)),
     }
+    log::debug!("Stats: {:?}", kv.stats());
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -102,16 +227,26 @@ impl Tree {
 
 const ROOT_ID: u64 = 0;
 
+/// First byte of trees with the varint encoding. Trees with the fixint
+/// encoding start with the high byte of the number of items, 0.
+const VARINT_TREE_MARKER: u8 = 1;
+
 trait IntKvFsExt: IntKv {
     fn read_tree_by_id(&self, index: u64) -> Result<Tree> {
-        log::debug!("read_tree_by_id {} {:p}", index, self);
+        crate::log_kv!(log::Level::Debug, "read_tree_by_id"; index = index);
         // PERF: Caching?
         let kv = self;
         if index == ROOT_ID && !kv.has(index as _)? {
             return Ok(Tree::default());
         }
         let bytes = kv.read(index as _)?;
-        let mut tree: Tree = util::bincode_deserialize(&bytes).map_err(|_| local_error())?;
+        let parsed = match bytes.split_first() {
+            Some((&VARINT_TREE_MARKER, rest)) => {
+                util::bincode_deserialize_with(Encoding::Varint, rest)
+            }
+            _ => util::bincode_deserialize(&bytes),
+        };
+        let mut tree: Tree = parsed.map_err(|_| local_error())?;
         tree.index = index;
         Ok(tree)
     }
@@ -121,7 +256,7 @@ trait IntKvFsExt: IntKv {
         loop {
             let i: u32 = rand::random();
             if !self.has(i as _)? {
-                log::debug!("find_free_index => {}", i);
+                crate::log_kv!(log::Level::Debug, "find_free_index"; index = i);
                 return Ok(i as _);
             }
         }
@@ -129,23 +264,32 @@ trait IntKvFsExt: IntKv {
 
     fn create_blob(&mut self, data: Bytes) -> Result<usize> {
         let index = self.find_free_index()?;
-        self.write(index, data)?;
+        self.write_with_hint(index, data, Hint::Cold)?;
         Ok(index)
     }
 
-    fn create_tree(&mut self) -> Result<Tree> {
+    fn create_tree(&mut self, encoding: Encoding) -> Result<Tree> {
         let kv = self;
-        let mut tree = Tree::default();
-        tree.index = kv.find_free_index()? as _;
-        kv.write_tree(&tree)?;
+        let tree = Tree {
+            index: kv.find_free_index()? as _,
+            ..Default::default()
+        };
+        kv.write_tree(&tree, encoding)?;
         Ok(tree)
     }
 
-    fn write_tree(&mut self, tree: &Tree) -> Result<()> {
+    fn write_tree(&mut self, tree: &Tree, encoding: Encoding) -> Result<()> {
         log::debug!("write_tree {:#?}", tree);
         let index = tree.index;
-        let bytes = util::bincode_serialize_pad(&tree, 0);
-        self.write(index as _, bytes.into())?;
+        let bytes = match encoding {
+            Encoding::Fixint => util::bincode_serialize_pad(&tree, 0),
+            Encoding::Varint => {
+                let mut bytes = vec![VARINT_TREE_MARKER];
+                bytes.extend(util::bincode_serialize_pad_with(encoding, &tree, 0));
+                bytes
+            }
+        };
+        self.write_with_hint(index as _, bytes.into(), Hint::Hot)?;
         debug_assert_eq!(
             self.read_tree_by_id(index as _)?.items.len(),
             tree.items.len()
@@ -166,11 +310,11 @@ trait IntKvFsExt: IntKv {
     }
 
     fn write_blob(&mut self, index: u64, data: Bytes) -> Result<()> {
-        Ok(self.write(index as _, data)?)
+        Ok(self.write_with_hint(index as _, data, Hint::Cold)?)
     }
 
     fn remove_blob(&mut self, index: u64) -> Result<()> {
-        log::debug!("Remove blob {}", index);
+        crate::log_kv!(log::Level::Debug, "Remove blob"; index = index);
         Ok(self.remove(index as _)?)
     }
 
@@ -179,7 +323,8 @@ trait IntKvFsExt: IntKv {
     }
 
     fn read_tree_by_path(&self, path: &Path) -> Result<Tree> {
-        log::debug!("read_tree_by_path {}", path.display());
+        let display = path.display().to_string();
+        crate::log_kv!(log::Level::Debug, "read_tree_by_path"; path = display);
         let mut tree = self.root_tree()?;
         for name in path.components() {
             match name {
@@ -233,7 +378,40 @@ trait IntKvFsExt: IntKv {
 
 impl<T: IntKv> IntKvFsExt for T {}
 
+/// Cross-check entries referenced by folders with entries in `kv`.
+/// Return (entries not referenced by any folder, referenced but missing
+/// entries).
+pub(crate) fn check_references<K: IntKv>(kv: &K) -> io::Result<(Vec<usize>, Vec<usize>)> {
+    let mut referenced = BTreeSet::new();
+    let mut to_visit = vec![ROOT_ID];
+    let mut missing = Vec::new();
+    let keys: BTreeSet<usize> = kv.keys()?.into_iter().collect();
+    while let Some(index) = to_visit.pop() {
+        if !keys.contains(&(index as usize)) {
+            if index != ROOT_ID {
+                missing.push(index as usize);
+            }
+            continue;
+        }
+        referenced.insert(index as usize);
+        let tree = kv.read_tree_by_id(index).map_err(io::Error::other)?;
+        for (id, meta) in tree.items.values() {
+            if meta.is_dir() {
+                to_visit.push(*id);
+            } else if keys.contains(&(*id as usize)) {
+                referenced.insert(*id as usize);
+            } else {
+                missing.push(*id as usize);
+            }
+        }
+    }
+    let orphaned = keys.difference(&referenced).cloned().collect();
+    missing.sort_unstable();
+    Ok((orphaned, missing))
+}
+
 #[async_trait::async_trait]
+#[allow(clippy::multiple_bound_locations)]
 impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
     /// The concrete type of the _metadata_ used by this storage backend.
     type Metadata = Meta;
@@ -269,6 +447,7 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
         let kv = self.kv.read();
         let path = path.as_ref();
         let tree = kv.read_tree_by_path(path)?;
+        self.touch_tree(&*kv, tree.index);
         let files = tree
             .items
             .iter()
@@ -333,6 +512,8 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
         let written = (buf.len() as u64) - start_pos;
         let data: Bytes = buf.into();
         let mut kv = self.kv.write();
+        // Refuse while changes accepted earlier can still be flushed.
+        kv.check_space(data.len() as u64).map_err(storage_error)?;
         let (mut tree, name) = kv.read_tree_name_from_path(path)?;
         let (index, meta) = if let Some((index, mut meta)) = tree.items.get(name).cloned() {
             if !meta.is_file() {
@@ -349,7 +530,8 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
             (index, meta)
         };
         tree.items.insert(name.to_string(), (index as _, meta));
-        kv.write_tree(&tree)?;
+        kv.write_tree(&tree, self.tree_encoding)?;
+        self.touch_tree(&*kv, tree.index);
         self.schedule_flush();
         Ok(written)
     }
@@ -366,7 +548,7 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
             denied!("del: {} is not a file", path.display());
         }
         tree.items.remove(name);
-        kv.write_tree(&tree)?;
+        kv.write_tree(&tree, self.tree_encoding)?;
         kv.remove_blob(id)?;
         self.schedule_flush();
         Ok(())
@@ -380,10 +562,10 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
         if tree.has(name) {
             denied!("mkd: {} exists", path.display());
         }
-        let new_tree = kv.create_tree()?;
+        let new_tree = kv.create_tree(self.tree_encoding)?;
         let meta = Meta::new_folder();
         tree.items.insert(name.to_string(), (new_tree.index, meta));
-        kv.write_tree(&tree)?;
+        kv.write_tree(&tree, self.tree_encoding)?;
         self.schedule_flush();
         Ok(())
     }
@@ -403,15 +585,15 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
         if to_tree.has(to_name) {
             denied!("rename: destination {} exists", to.display());
         }
-        let from_item = from_tree.find(&from_name)?;
+        let from_item = from_tree.find(from_name)?;
         to_tree.items.insert(to_name.to_string(), from_item.clone());
         if to_tree.index == from_tree.index {
             to_tree.items.remove(from_name);
-            kv.write_tree(&to_tree)?;
+            kv.write_tree(&to_tree, self.tree_encoding)?;
         } else {
-            kv.write_tree(&to_tree)?;
+            kv.write_tree(&to_tree, self.tree_encoding)?;
             from_tree.items.remove(from_name);
-            kv.write_tree(&from_tree)?;
+            kv.write_tree(&from_tree, self.tree_encoding)?;
         }
         self.schedule_flush();
         Ok(())
@@ -431,8 +613,10 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
         if !kv.read_tree_by_id(*index)?.items.is_empty() {
             denied!("rmd: {} is not empty", path.display());
         }
+        let index = *index;
         tree.items.remove(name);
-        kv.write_tree(&tree)?;
+        kv.write_tree(&tree, self.tree_encoding)?;
+        self.forget_tree(&*kv, index);
         self.schedule_flush();
         Ok(())
     }
@@ -441,7 +625,8 @@ impl<U: Send + Sync + Debug> StorageBackend<U> for IntKvFtpFs {
     async fn cwd<P: AsRef<Path> + Send + Debug>(&self, _user: &Option<U>, path: P) -> Result<()> {
         let path = path.as_ref();
         let kv = self.kv.read();
-        kv.read_tree_by_path(path)?;
+        let tree = kv.read_tree_by_path(path)?;
+        self.touch_tree(&*kv, tree.index);
         Ok(())
     }
 }
@@ -505,6 +690,17 @@ fn local_error() -> Error {
     ErrorKind::LocalError.into()
 }
 
+/// Report running out of space or quota as 452, so clients know the
+/// upload was not stored.
+fn storage_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => {
+            Error::new(ErrorKind::InsufficientStorageSpaceError, e)
+        }
+        _ => e.into(),
+    }
+}
+
 fn to_str(path: &OsStr) -> Result<&str> {
     match path.to_str() {
         Some(s) => Ok(s),
@@ -0,0 +1,616 @@
+//! Entries in fixed-size slots of a block device, ex. a raw partition, or a
+//! file standing in for one. No filesystem is involved.
+//!
+//! The device starts with two superblocks, then two metadata regions, then
+//! the slots. Metadata maps indexes to slots, followed by a bitmap of the
+//! allocated slots. Changed entries go to free slots, so the committed ones
+//! stay intact. `flush` syncs the slots, writes the metadata to the region
+//! not in use and syncs, then writes a superblock of the next generation
+//! pointing to it and syncs. On open, the valid superblock of the highest
+//! generation wins, so an interrupted flush leaves the previous state.
+
+use super::super::{Bytes, IntKv, Stats};
+use super::LockMode;
+use blake2::{Blake2s, Digest};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"x79d8dev";
+const VERSION: u32 = 1;
+/// Bytes of a superblock, also the alignment of regions.
+const SUPER_SIZE: u64 = 4096;
+/// Bytes of a metadata entry: index, slot, length.
+const ENTRY_SIZE: u64 = 20;
+/// Bytes that must be zero before formatting, so nothing is overwritten
+/// by mistake.
+const CHECKED_SIZE: usize = 1 << 16;
+
+pub struct BlockDevIntKv {
+    file: File,
+    path: PathBuf,
+    layout: Layout,
+    vault_id: [u8; 16],
+    generation: u64,
+    /// Entries as of the last flush.
+    committed: BTreeMap<usize, Slot>,
+    /// Entries including pending changes.
+    entries: BTreeMap<usize, Slot>,
+    /// Slots used by neither `committed` nor `entries`.
+    free: BTreeSet<u64>,
+    /// Slots of `committed` replaced since, free after the next flush.
+    released: Vec<u64>,
+    read_only: bool,
+    #[cfg(test)]
+    crash: Option<Crash>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Slot {
+    slot: u64,
+    len: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Layout {
+    slot_size: u64,
+    slot_count: u64,
+}
+
+/// A flush write to cut short in tests.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Crash {
+    Metadata,
+    Superblock,
+}
+
+impl Layout {
+    /// The most slots fitting in `device_size` bytes.
+    fn fit(device_size: u64, slot_size: u64) -> io::Result<Self> {
+        // Each slot takes an entry and a bit in both metadata regions.
+        let per_slot = slot_size * 8 + ENTRY_SIZE * 16 + 2;
+        let fixed = SUPER_SIZE * 2;
+        let mut layout = Self {
+            slot_size,
+            slot_count: device_size.saturating_sub(fixed) * 8 / per_slot,
+        };
+        while layout.slot_count > 0 && layout.device_size() > device_size {
+            layout.slot_count -= 1;
+        }
+        if layout.slot_count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes fit no slot of {} bytes", device_size, slot_size),
+            ));
+        }
+        Ok(layout)
+    }
+
+    fn meta_size(&self) -> u64 {
+        let len = 8 + self.slot_count * ENTRY_SIZE + self.slot_count.div_ceil(8);
+        len.div_ceil(SUPER_SIZE) * SUPER_SIZE
+    }
+
+    fn meta_offset(&self, region: u64) -> u64 {
+        SUPER_SIZE * 2 + self.meta_size() * region
+    }
+
+    fn slot_offset(&self, slot: u64) -> u64 {
+        self.meta_offset(2) + slot * self.slot_size
+    }
+
+    fn device_size(&self) -> u64 {
+        self.slot_offset(self.slot_count)
+    }
+}
+
+impl BlockDevIntKv {
+    /// Format `path` with slots of `slot_size` bytes, the size of entries
+    /// of the page layer. Refuse unless the start of `path` is zeros.
+    pub fn format(path: &Path, slot_size: u64, vault_id: [u8; 16]) -> io::Result<()> {
+        if slot_size == 0 || !slot_size.is_multiple_of(512) || slot_size > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "slots of {} bytes are not supported (a block device needs blocks)",
+                    slot_size
+                ),
+            ));
+        }
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let device_size = file.seek(SeekFrom::End(0))?;
+        let layout = Layout::fit(device_size, slot_size)?;
+        let mut head = vec![0; CHECKED_SIZE.min(device_size as usize)];
+        file.read_exact_at(&mut head, 0)?;
+        if head.starts_with(MAGIC) || head[SUPER_SIZE as usize..].starts_with(MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already formatted", path.display()),
+            ));
+        }
+        if head.iter().any(|&b| b != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is not empty (clear it first, ex. with \"wipefs -a\")",
+                    path.display()
+                ),
+            ));
+        }
+        let mut kv = Self::with_parts(file, path, layout, vault_id, false);
+        kv.commit()
+    }
+
+    /// Open a formatted `path`. Fail unless it was formatted with
+    /// `slot_size` and `vault_id`. `LockMode::Shared` opens read-only.
+    pub fn open(
+        path: &Path,
+        slot_size: u64,
+        vault_id: [u8; 16],
+        mode: LockMode,
+    ) -> io::Result<Self> {
+        let read_only = mode == LockMode::Shared;
+        let mut file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let locked = match mode {
+            LockMode::Shared => fs2::FileExt::try_lock_shared(&file),
+            LockMode::Exclusive => fs2::FileExt::try_lock_exclusive(&file),
+        };
+        locked.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{} is used by another process", path.display()),
+            )
+        })?;
+        let device_size = file.seek(SeekFrom::End(0))?;
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut best = None;
+        for index in 0..2 {
+            let mut data = vec![0; SUPER_SIZE as usize];
+            if file.read_exact_at(&mut data, index * SUPER_SIZE).is_err() {
+                continue;
+            }
+            if let Some(sb) = Superblock::decode(&data) {
+                if best
+                    .as_ref()
+                    .is_none_or(|b: &Superblock| b.generation < sb.generation)
+                {
+                    best = Some(sb);
+                }
+            }
+        }
+        let sb = best.ok_or_else(|| invalid(format!("{} is not formatted", path.display())))?;
+        if sb.vault_id != vault_id {
+            return Err(invalid(format!(
+                "{} belongs to another directory",
+                path.display()
+            )));
+        }
+        if sb.layout.slot_size != slot_size {
+            return Err(invalid(format!(
+                "{} has slots of {} bytes, not {}",
+                path.display(),
+                sb.layout.slot_size,
+                slot_size
+            )));
+        }
+        if device_size < sb.layout.device_size() {
+            return Err(invalid(format!(
+                "{} is smaller than formatted ({} of {} bytes)",
+                path.display(),
+                device_size,
+                sb.layout.device_size()
+            )));
+        }
+
+        let mut kv = Self::with_parts(file, path, sb.layout, vault_id, read_only);
+        let mut meta = vec![0; sb.meta_len as usize];
+        let region = sb.generation % 2;
+        kv.file
+            .read_exact_at(&mut meta, sb.layout.meta_offset(region))?;
+        if Blake2s::digest(&meta).as_slice() != sb.meta_hash {
+            // Written before the superblock, so only damage explains this.
+            return Err(invalid(format!(
+                "metadata of {} is corrupted",
+                path.display()
+            )));
+        }
+        kv.entries = kv
+            .decode_meta(&meta)
+            .ok_or_else(|| invalid(format!("metadata of {} is invalid", path.display())))?;
+        kv.committed = kv.entries.clone();
+        for entry in kv.entries.values() {
+            kv.free.remove(&entry.slot);
+        }
+        kv.generation = sb.generation;
+        Ok(kv)
+    }
+
+    fn with_parts(
+        file: File,
+        path: &Path,
+        layout: Layout,
+        vault_id: [u8; 16],
+        read_only: bool,
+    ) -> Self {
+        Self {
+            file,
+            path: path.to_path_buf(),
+            layout,
+            vault_id,
+            generation: 0,
+            committed: BTreeMap::new(),
+            entries: BTreeMap::new(),
+            free: (0..layout.slot_count).collect(),
+            released: Vec::new(),
+            read_only,
+            #[cfg(test)]
+            crash: None,
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        match self.read_only {
+            true => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is opened read-only", self.path.display()),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Stop using the slot of `index`, if any.
+    fn release(&mut self, index: usize) -> Option<Slot> {
+        let entry = self.entries.remove(&index)?;
+        match self.committed.get(&index) {
+            // Still needed if the next flush is interrupted.
+            Some(committed) if committed.slot == entry.slot => self.released.push(entry.slot),
+            _ => {
+                self.free.insert(entry.slot);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Write the metadata and a superblock of the next generation.
+    fn commit(&mut self) -> io::Result<()> {
+        let generation = self.generation + 1;
+        let meta = self.encode_meta();
+        let region = generation % 2;
+        #[cfg(test)]
+        self.crash_at(Crash::Metadata, &meta, self.layout.meta_offset(region))?;
+        self.write_flushed(&meta, self.layout.meta_offset(region))?;
+        self.file.sync_data()?;
+        let sb = Superblock {
+            layout: self.layout,
+            vault_id: self.vault_id,
+            generation,
+            meta_len: meta.len() as u64,
+            meta_hash: Blake2s::digest(&meta).into(),
+        };
+        let sb = sb.encode();
+        let offset = (generation % 2) * SUPER_SIZE;
+        #[cfg(test)]
+        self.crash_at(Crash::Superblock, &sb, offset)?;
+        self.write_flushed(&sb, offset)?;
+        self.file.sync_data()?;
+        self.generation = generation;
+        self.committed = self.entries.clone();
+        self.free.extend(self.released.drain(..));
+        Ok(())
+    }
+
+    fn write_flushed(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(data, offset)
+    }
+
+    /// Write half of `data` and fail, if asked to crash at `step`.
+    #[cfg(test)]
+    fn crash_at(&self, step: Crash, data: &[u8], offset: u64) -> io::Result<()> {
+        if self.crash != Some(step) {
+            return Ok(());
+        }
+        let torn: Vec<u8> = data.iter().map(|b| !b).collect();
+        self.file.write_all_at(&torn[..data.len() / 2], offset)?;
+        Err(io::Error::other("simulated crash"))
+    }
+
+    fn encode_meta(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + self.entries.len() * ENTRY_SIZE as usize);
+        data.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        let mut bitmap = vec![0u8; self.layout.slot_count.div_ceil(8) as usize];
+        for (&index, entry) in &self.entries {
+            data.extend_from_slice(&(index as u64).to_le_bytes());
+            data.extend_from_slice(&entry.slot.to_le_bytes());
+            data.extend_from_slice(&entry.len.to_le_bytes());
+            bitmap[(entry.slot / 8) as usize] |= 1 << (entry.slot % 8);
+        }
+        data.extend_from_slice(&bitmap);
+        data
+    }
+
+    /// Decode metadata. None if it is inconsistent.
+    fn decode_meta(&self, mut data: &[u8]) -> Option<BTreeMap<usize, Slot>> {
+        let count = u64::from_le_bytes(take(&mut data)?);
+        let mut entries = BTreeMap::new();
+        let mut bitmap = vec![0u8; self.layout.slot_count.div_ceil(8) as usize];
+        for _ in 0..count {
+            let index = u64::from_le_bytes(take(&mut data)?) as usize;
+            let slot = u64::from_le_bytes(take(&mut data)?);
+            let len = u32::from_le_bytes(take(&mut data)?);
+            if slot >= self.layout.slot_count || len as u64 > self.layout.slot_size {
+                return None;
+            }
+            bitmap[(slot / 8) as usize] |= 1 << (slot % 8);
+            entries.insert(index, Slot { slot, len });
+        }
+        // The bitmap must agree with the slots of the entries.
+        (data == bitmap.as_slice()).then_some(entries)
+    }
+}
+
+/// Take the next `N` bytes.
+fn take<const N: usize>(data: &mut &[u8]) -> Option<[u8; N]> {
+    if data.len() < N {
+        return None;
+    }
+    let (head, rest) = data.split_at(N);
+    *data = rest;
+    head.try_into().ok()
+}
+
+struct Superblock {
+    layout: Layout,
+    vault_id: [u8; 16],
+    generation: u64,
+    meta_len: u64,
+    meta_hash: [u8; 32],
+}
+
+impl Superblock {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&self.vault_id);
+        data.extend_from_slice(&self.layout.slot_size.to_le_bytes());
+        data.extend_from_slice(&self.layout.slot_count.to_le_bytes());
+        data.extend_from_slice(&self.generation.to_le_bytes());
+        data.extend_from_slice(&self.meta_len.to_le_bytes());
+        data.extend_from_slice(&self.meta_hash);
+        let hash = Blake2s::digest(&data);
+        data.extend_from_slice(&hash);
+        data.resize(SUPER_SIZE as usize, 0);
+        data
+    }
+
+    /// Decode a superblock. None if it is missing, torn, or of another
+    /// version.
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut rest = data;
+        let magic: [u8; 8] = take(&mut rest)?;
+        let version = u32::from_le_bytes(take(&mut rest)?);
+        if &magic != MAGIC || version != VERSION {
+            return None;
+        }
+        let vault_id = take(&mut rest)?;
+        let slot_size = u64::from_le_bytes(take(&mut rest)?);
+        let slot_count = u64::from_le_bytes(take(&mut rest)?);
+        let generation = u64::from_le_bytes(take(&mut rest)?);
+        let meta_len = u64::from_le_bytes(take(&mut rest)?);
+        let meta_hash = take(&mut rest)?;
+        let hashed = data.len() - rest.len();
+        let hash: [u8; 32] = take(&mut rest)?;
+        if Blake2s::digest(&data[..hashed]).as_slice() != hash {
+            return None;
+        }
+        let layout = Layout {
+            slot_size,
+            slot_count,
+        };
+        (meta_len <= layout.meta_size()).then_some(Self {
+            layout,
+            vault_id,
+            generation,
+            meta_len,
+            meta_hash,
+        })
+    }
+}
+
+impl fmt::Debug for BlockDevIntKv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockDevIntKv")
+            .field("path", &self.path)
+            .field("layout", &self.layout)
+            .field("generation", &self.generation)
+            .field("entries", &self.entries.len())
+            .field("free", &self.free.len())
+            .finish()
+    }
+}
+
+impl IntKv for BlockDevIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let entry = match self.entries.get(&index) {
+            Some(entry) => entry,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let mut data = vec![0; entry.len as usize];
+        self.file
+            .read_exact_at(&mut data, self.layout.slot_offset(entry.slot))?;
+        Ok(data.into())
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.check_writable()?;
+        if data.len() as u64 > self.layout.slot_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "entry of {} bytes does not fit in a slot of {}",
+                    data.len(),
+                    self.layout.slot_size
+                ),
+            ));
+        }
+        let slot = match self.free.pop_first() {
+            Some(slot) => slot,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!("no free slot in {}", self.path.display()),
+                ))
+            }
+        };
+        if let Err(e) = self.file.write_all_at(&data, self.layout.slot_offset(slot)) {
+            self.free.insert(slot);
+            return Err(e);
+        }
+        self.release(index);
+        let len = data.len() as u32;
+        self.entries.insert(index, Slot { slot, len });
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.check_writable()?;
+        match self.release(index) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        Ok(self.entries.contains_key(&index))
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.entries == self.committed {
+            return Ok(());
+        }
+        self.check_writable()?;
+        // Slots must be durable before the metadata refers to them.
+        self.file.sync_data()?;
+        self.commit()
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        stats.insert("blockdev.entries".into(), self.entries.len() as u64);
+        stats.insert("blockdev.free_slots".into(), self.free.len() as u64);
+        stats.insert("blockdev.generation".into(), self.generation);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        let needed = extra.div_ceil(self.layout.slot_size);
+        match needed > self.free.len() as u64 {
+            true => Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("not enough free slots in {}", self.path.display()),
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_device(slots: u64, slot_size: u64) -> tempfile::NamedTempFile {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let layout = Layout {
+        slot_size,
+        slot_count: slots,
+    };
+    file.as_file().set_len(layout.device_size()).unwrap();
+    file
+}
+
+#[test]
+fn test_block_dev_int_kv() {
+    let slot_size = 128 << 10;
+    let device = test_device(512, slot_size);
+    let id = [1; 16];
+    BlockDevIntKv::format(device.path(), slot_size, id).unwrap();
+    let open = || BlockDevIntKv::open(device.path(), slot_size, id, LockMode::Exclusive).unwrap();
+    let kv = super::super::test_int_kv(
+        |kv| {
+            drop(kv);
+            open()
+        },
+        30,
+    );
+    drop(kv);
+    let kv = open();
+    let free = kv.stats()["blockdev.free_slots"] as usize;
+    assert_eq!(free, 512 - kv.keys().unwrap().len());
+    drop(kv);
+
+    let e = BlockDevIntKv::format(device.path(), slot_size, id).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    let e = BlockDevIntKv::open(device.path(), slot_size, [2; 16], LockMode::Shared).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let e = BlockDevIntKv::open(device.path(), slot_size / 2, id, LockMode::Shared).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let mut kv = BlockDevIntKv::open(device.path(), slot_size, id, LockMode::Shared).unwrap();
+    assert_eq!(
+        kv.write(1, vec![1].into()).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    drop(kv);
+
+    // A device truncated after formatting.
+    let len = device.as_file().metadata().unwrap().len();
+    device.as_file().set_len(len - 1).unwrap();
+    let e = BlockDevIntKv::open(device.path(), slot_size, id, LockMode::Exclusive).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_block_dev_int_kv_torn_flush() {
+    let device = test_device(8, 512);
+    let id = [3; 16];
+    BlockDevIntKv::format(device.path(), 512, id).unwrap();
+    let open = || BlockDevIntKv::open(device.path(), 512, id, LockMode::Exclusive).unwrap();
+    let mut kv = open();
+    for i in 0..4 {
+        kv.write(i, vec![i as u8; 100].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    drop(kv);
+
+    for crash in [Crash::Metadata, Crash::Superblock] {
+        let mut kv = open();
+        kv.crash = Some(crash);
+        // Replaced entries take other slots, so the committed ones survive.
+        for i in 0..3 {
+            kv.write(i, vec![9; 200].into()).unwrap();
+        }
+        kv.remove(3).unwrap();
+        kv.flush().unwrap_err();
+        drop(kv);
+        let kv = open();
+        assert_eq!(kv.keys().unwrap(), vec![0, 1, 2, 3]);
+        for i in 0..4 {
+            assert_eq!(kv.read(i).unwrap().as_ref(), &[i as u8; 100][..]);
+        }
+    }
+
+    // Full.
+    let mut kv = open();
+    for i in 4..8 {
+        kv.write(i, vec![0; 512].into()).unwrap();
+    }
+    let e = kv.write(8, vec![0].into()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+    let e = kv.write(0, vec![0; 513].into()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
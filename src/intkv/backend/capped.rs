@@ -0,0 +1,228 @@
+//! Entries in memory up to a byte budget. Beyond it, the least recently
+//! used entries move to a temporary directory, encrypted with a random key
+//! that only lives in memory. The directory is removed on drop.
+
+use super::super::wrapper::EncIntKv;
+use super::super::{Bytes, IntKv, Stats};
+use super::{Durability, FileNaming, FsIntKv, LockMode};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct CappedMemIntKv {
+    state: Mutex<State>,
+    /// Bytes of entries kept in memory.
+    limit: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Entries in memory, with their last use.
+    mem: HashMap<usize, (Bytes, u64)>,
+    /// Entries in memory by last use.
+    order: BTreeMap<u64, usize>,
+    tick: u64,
+    size: u64,
+    /// Entries in `spill`.
+    spilled: BTreeSet<usize>,
+    spill: EncIntKv,
+    spills: u64,
+    loads: u64,
+    /// Dropped after `spill`.
+    dir: tempfile::TempDir,
+}
+
+impl CappedMemIntKv {
+    /// Keep up to `limit` bytes in memory.
+    pub fn new(limit: u64) -> io::Result<Self> {
+        let dir = tempfile::Builder::new().prefix("x79d8-").tempdir()?;
+        // Nothing survives a crash anyway.
+        let path = dir.path();
+        let naming = FileNaming::default();
+        let fs = FsIntKv::new_with_lock(
+            path,
+            path,
+            LockMode::Exclusive,
+            Duration::from_secs(0),
+            naming,
+            None,
+        )?
+        .with_durability(Durability::None);
+        let spill = EncIntKv::from_key_kv(rand::random(), Box::new(fs));
+        let state = State {
+            mem: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            spilled: BTreeSet::new(),
+            spill,
+            spills: 0,
+            loads: 0,
+            dir,
+        };
+        Ok(Self {
+            state: Mutex::new(state),
+            limit,
+        })
+    }
+
+    /// The temporary directory, to remove it if the process exits without
+    /// dropping this.
+    pub fn spill_dir(&self) -> PathBuf {
+        self.state.lock().dir.path().to_path_buf()
+    }
+}
+
+impl State {
+    fn insert(&mut self, index: usize, data: Bytes, limit: u64) -> io::Result<()> {
+        self.forget(index);
+        self.tick += 1;
+        self.size += data.len() as u64;
+        self.mem.insert(index, (data, self.tick));
+        self.order.insert(self.tick, index);
+        // Move the least recently used entries out.
+        while self.size > limit {
+            let (_, index) = match self.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let (data, _) = self.mem.remove(&index).unwrap();
+            self.size -= data.len() as u64;
+            self.spill.write(index, data)?;
+            self.spilled.insert(index);
+            self.spills += 1;
+        }
+        Ok(())
+    }
+
+    /// Remove an entry from memory. Return whether it was there.
+    fn forget(&mut self, index: usize) -> bool {
+        match self.mem.remove(&index) {
+            Some((data, tick)) => {
+                self.order.remove(&tick);
+                self.size -= data.len() as u64;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove an entry from the temporary directory. Return whether it
+    /// was there.
+    fn unspill(&mut self, index: usize) -> io::Result<bool> {
+        if !self.spilled.remove(&index) {
+            return Ok(false);
+        }
+        self.spill.remove(index)?;
+        Ok(true)
+    }
+}
+
+impl IntKv for CappedMemIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if let Some((data, tick)) = state.mem.get_mut(&index) {
+            state.order.remove(tick);
+            state.tick += 1;
+            *tick = state.tick;
+            state.order.insert(state.tick, index);
+            return Ok(data.clone());
+        }
+        if !state.spilled.contains(&index) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        // Bring it back, as it is used again.
+        let data = state.spill.read(index)?;
+        state.unspill(index)?;
+        state.loads += 1;
+        state.insert(index, data.clone(), self.limit)?;
+        Ok(data)
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let state = self.state.get_mut();
+        state.unspill(index)?;
+        state.insert(index, data, self.limit)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let state = self.state.get_mut();
+        if state.forget(index) || state.unspill(index)? {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        let state = self.state.lock();
+        Ok(state.mem.contains_key(&index) || state.spilled.contains(&index))
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let state = self.state.lock();
+        let mut keys: BTreeSet<usize> = state.mem.keys().cloned().collect();
+        keys.extend(state.spilled.iter().cloned());
+        Ok(keys.into_iter().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Nothing is meant to be persisted.
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        let state = self.state.lock();
+        let mut stats = Stats::new();
+        stats.insert("capped.mem_bytes".into(), state.size);
+        stats.insert("capped.mem_entries".into(), state.mem.len() as u64);
+        stats.insert("capped.spilled_entries".into(), state.spilled.len() as u64);
+        stats.insert("capped.spills".into(), state.spills);
+        stats.insert("capped.loads".into(), state.loads);
+        stats
+    }
+}
+
+#[test]
+fn test_capped_mem_int_kv() {
+    let kv = super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| CappedMemIntKv::new(5000).unwrap()),
+        30,
+    );
+    let stats = kv.stats();
+    assert!(stats["capped.spills"] > 0);
+    assert!(stats["capped.loads"] > 0);
+    assert!(stats["capped.mem_bytes"] <= 5000);
+}
+
+#[test]
+fn test_capped_mem_int_kv_spill_dir() {
+    let mut kv = CappedMemIntKv::new(10).unwrap();
+    let secret = b"secret data that should not be on disk in plain text";
+    kv.write(1, secret.to_vec().into()).unwrap();
+    kv.write(2, b"x".to_vec().into()).unwrap();
+    assert_eq!(kv.stats()["capped.spilled_entries"], 1);
+    let dir = kv.spill_dir();
+    let mut found = Vec::new();
+    let mut stack = vec![dir.clone()];
+    while let Some(path) = stack.pop() {
+        for entry in std::fs::read_dir(path).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => stack.push(path),
+                false => found.push(std::fs::read(&path).unwrap()),
+            }
+        }
+    }
+    assert!(found.iter().any(|data| data.len() >= secret.len()));
+    assert!(!found
+        .iter()
+        .any(|data| data.windows(secret.len()).any(|w| w == secret)));
+    assert_eq!(kv.read(1).unwrap().as_ref(), secret);
+    drop(kv);
+    assert!(!dir.exists());
+}
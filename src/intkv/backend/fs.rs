@@ -1,32 +1,435 @@
-use super::super::{Bytes, IntKv};
+use super::super::{Bytes, IntKv, Stats};
+use crate::util::{self, rename::rename_keeping, DiskSpace};
+use blake2::{Blake2s, Digest};
+use fs2::FileExt;
 use memmap::MmapOptions;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 
+mod direct;
+mod handles;
+mod metrics;
+mod report;
+mod segment;
+
+use handles::Handles;
+use metrics::{Metrics, Op};
+pub use report::OpenReport;
+use segment::{Location, Segments, MANIFEST_NAME, SEGMENTS_DIR};
+
 /// `IntKv` based on filesystem.
 ///
+/// Entries are stored as `blocks/ab/cd/<index>`, where `ab` and `cd` are the lowest
+/// two bytes of the index in hex. Older directories store entries directly
+/// in the top directory. They are still readable, and are moved when
+/// rewritten or by `migrate_layout()`.
+///
 /// Changes will be write to disk but will not be visible to new `FsIntKv`
 /// instances until `flush()`.
 ///
 /// `flush()` ensures changes are atomic by using WAL:
-/// 1. fsync files to write using "pending" names (suffix "p").
-/// 2. Write WAL about what files to replace or delete.
-/// 3. Rename the pending files. Remove deleted files.
-/// 4. Remove WAL.
+/// 1. fsync files to write using "pending" names (suffix "p"), and their
+///    directories.
+/// 2. Write WAL about what files to replace or delete. fsync the directory.
+/// 3. Rename the pending files. Remove deleted files. fsync directories.
+/// 4. Remove WAL. fsync the directory.
 ///
 /// If the program was killed during `flush()`, the next `FsIntKv` will
-/// try to redo WAL to complete partially modified state.
+/// try to redo WAL to complete partially modified state. If it was killed
+/// before `flush()`, the next `FsIntKv` removes the pending files. A marker
+/// file tells whether pending files might exist, to avoid scanning.
+///
+/// With `with_segments`, small entries are appended to segment files
+/// instead, and their locations are recorded in a manifest when WAL is
+/// applied. Step 1 fsyncs the segments instead of the files.
+///
+/// The directory is locked against other processes for the lifetime of the
+/// instance. The OS releases the lock if the process dies.
 #[derive(Debug)]
 pub struct FsIntKv {
     dir: PathBuf,
     overlay: HashMap<usize, State>,
+
+    /// Whether the marker of pending files was written.
+    marked: bool,
+
+    /// Lock of the directory. None if a read-only instance could not get it.
+    _lock: Option<Arc<DirLock>>,
+
+    /// Opened by `open_read_only`. Changes are refused.
+    read_only: bool,
+
+    /// A WAL was left uncommitted. Only set for read-only instances.
+    has_wal: bool,
+
+    /// How to read files.
+    read_strategy: ReadStrategy,
+
+    /// Whether the directory is on a local filesystem. Used by
+    /// `ReadStrategy::Auto`.
+    local: bool,
+
+    /// Expected size of entry files. Files shorter than this are reported
+    /// as truncated.
+    min_file_size: u64,
+
+    /// What opening found.
+    report: OpenReport,
+
+    /// Tries of file operations failing with transient errors.
+    retry_attempts: u32,
+
+    /// Delay before the first retry. Doubled for each retry.
+    retry_delay: Duration,
+
+    /// Allocate files before writing them.
+    preallocate: bool,
+
+    /// Bytes written since the last flush that the filesystem might not have
+    /// allocated yet.
+    unallocated_bytes: u64,
+
+    /// Keep replaced files of the last flush for `rollback()`.
+    keep_old: bool,
+
+    /// Names of entry files.
+    naming: FileNaming,
+
+    /// WAL of `overlay` was written, but applying it failed. Pending files
+    /// might have been renamed.
+    committing: bool,
+
+    /// Entries shorter than this are packed into segments. 0: disabled.
+    segment_threshold: usize,
+
+    /// Segment files and the manifest.
+    segments: Mutex<Segments>,
+
+    /// Space to keep free when checking new writes by `check_space`.
+    reserved_space: u64,
+
+    /// Warn if less space than this is available.
+    low_space_warning: u64,
+
+    /// Space of the filesystem, and when it was measured.
+    space: Mutex<Option<(Instant, DiskSpace)>>,
+
+    /// The generation token last read or written. None if there was none.
+    generation: Option<String>,
+
+    /// Refuse to flush if the generation token was changed by others.
+    check_generation: bool,
+
+    /// Counts and latencies of file operations.
+    metrics: Metrics,
+
+    /// Which fsyncs to issue.
+    durability: Durability,
+
+    /// Read and write files bypassing the OS page cache. Cleared if the
+    /// filesystem refuses.
+    direct_io: AtomicBool,
+
+    /// Open files of entries. Not used by read-only instances, as a writer
+    /// might rename the files.
+    handles: Mutex<Handles>,
+
+    /// Errors to fail the next file operations with.
+    #[cfg(test)]
+    faults: Mutex<Vec<Option<io::Error>>>,
+}
+
+/// How `FsIntKv` names files of entries. The default is the bare decimal
+/// index.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileNaming {
+    /// Prepended to names.
+    pub prefix: String,
+
+    /// Pad numbers with zeros to this width.
+    pub width: usize,
+
+    /// Use lowercase hex numbers.
+    pub hex: bool,
+}
+
+impl FileNaming {
+    /// Name of the file of `index`.
+    pub fn name(&self, index: usize) -> String {
+        match self.hex {
+            true => format!("{}{:0w$x}", self.prefix, index, w = self.width),
+            false => format!("{}{:0w$}", self.prefix, index, w = self.width),
+        }
+    }
+
+    /// Index of a file name. None if the name is not from `name`.
+    pub fn parse(&self, name: &str) -> Option<usize> {
+        let digits = name.strip_prefix(self.prefix.as_str())?;
+        let radix = if self.hex { 16 } else { 10 };
+        if !digits
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (self.hex && b.is_ascii_lowercase()))
+        {
+            return None;
+        }
+        let index = usize::from_str_radix(digits, radix).ok()?;
+        // Reject other paddings.
+        (self.name(index) == name).then_some(index)
+    }
+
+    /// Check the prefix is usable in file names.
+    pub fn validate(&self) -> io::Result<()> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+        if !self.prefix.chars().all(valid) || self.prefix.starts_with('.') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported file name prefix {:?}", self.prefix),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How `FsIntKv` reads files.
+///
+/// A mapped file that gets truncated, or whose network filesystem goes away,
+/// kills the process with SIGBUS on access. Prefer `Buffered` on network
+/// filesystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadStrategy {
+    /// Map files into memory.
+    #[default]
+    Mmap,
+
+    /// Copy files into memory.
+    Buffered,
+
+    /// Map large files on local filesystems. Copy otherwise.
+    Auto,
+}
+
+impl FromStr for ReadStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mmap" => Ok(ReadStrategy::Mmap),
+            "buffered" => Ok(ReadStrategy::Buffered),
+            "auto" => Ok(ReadStrategy::Auto),
+            _ => Err(format!(
+                "unknown read strategy {} (expected mmap, buffered or auto)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReadStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ReadStrategy::Mmap => "mmap",
+            ReadStrategy::Buffered => "buffered",
+            ReadStrategy::Auto => "auto",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Which fsyncs `FsIntKv` issues. Weaker settings trade durability for
+/// flush speed. Files are renamed in the same order regardless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Fsync files and the directories they are in.
+    #[default]
+    Full,
+
+    /// Fsync files, but not directories. A crash might lose renames and
+    /// new files, but not the content of files it kept.
+    Data,
+
+    /// No fsyncs. For storage that survives crashes by other means, such as
+    /// a battery-backed cache. A crash might lose or tear any change.
+    None,
+}
+
+impl Durability {
+    fn sync_all(self, file: &fs::File) -> io::Result<()> {
+        match self {
+            Durability::None => Ok(()),
+            _ => file.sync_all(),
+        }
+    }
+
+    fn sync_data(self, file: &fs::File) -> io::Result<()> {
+        match self {
+            Durability::None => Ok(()),
+            _ => file.sync_data(),
+        }
+    }
+
+    fn sync_dir(self, path: &Path) -> io::Result<()> {
+        match self {
+            Durability::Full => sync_dir(path),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Durability::Full),
+            "data" => Ok(Durability::Data),
+            "none" => Ok(Durability::None),
+            _ => Err(format!(
+                "unknown durability {} (expected full, data or none)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Durability::Full => "full",
+            Durability::Data => "data",
+            Durability::None => "none",
+        };
+        f.write_str(name)
+    }
+}
+
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Log where the time went if a flush takes longer than this.
+const SLOW_FLUSH: Duration = Duration::from_secs(1);
+
+/// How long `FsIntKv::check_space` reuses a measurement of free space.
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default limit of `FsIntKv::with_open_files`.
+const DEFAULT_OPEN_FILES: usize = 128;
+
+/// `ReadStrategy::Auto` maps files of at least this size.
+const AUTO_MMAP_MIN_SIZE: u64 = 64 << 10;
+
+/// How `FsIntKv` locks its directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Read-only. Coexist with other readers, and with a writer if needed.
+    Shared,
+
+    /// Exclude all other processes.
+    Exclusive,
+}
+
+/// An advisory lock of a directory. Shared by instances in the same process.
+#[derive(Debug)]
+pub(super) struct DirLock {
+    file: fs::File,
+    mode: LockMode,
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// Locks held by this process, by canonical directory path.
+static DIR_LOCKS: Lazy<Mutex<HashMap<PathBuf, Weak<DirLock>>>> = Lazy::new(Default::default);
+
+impl DirLock {
+    /// Lock `dir`, retrying for up to `wait`.
+    pub(super) fn acquire(dir: &Path, mode: LockMode, wait: Duration) -> io::Result<Arc<Self>> {
+        let mut locks = DIR_LOCKS.lock();
+        let canonical = fs::canonicalize(dir)?;
+        if let Some(lock) = locks.get(&canonical).and_then(Weak::upgrade) {
+            if lock.mode == LockMode::Shared && mode == LockMode::Exclusive {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{} is opened read-only", dir.display()),
+                ));
+            }
+            return Ok(lock);
+        }
+        // Readers do not create or write the lock file.
+        let writer = mode == LockMode::Exclusive;
+        let path = dir.join(LOCK_NAME);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(writer)
+            .create(writer)
+            .truncate(false)
+            .open(&path)?;
+        let deadline = Instant::now() + wait;
+        loop {
+            let result = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() => {
+                    return Err(e)
+                }
+                Err(_) if Instant::now() < deadline => std::thread::sleep(LOCK_RETRY_INTERVAL),
+                Err(_) => {
+                    let pid = fs::read_to_string(&path).unwrap_or_default();
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("{} is in use by process {}", dir.display(), pid.trim()),
+                    ));
+                }
+            }
+        }
+        if writer {
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+        }
+        let lock = Arc::new(Self { file, mode });
+        locks.insert(canonical, Arc::downgrade(&lock));
+        Ok(lock)
+    }
 }
 
+const LOCK_NAME: &str = "lock";
+
+/// WAL of the last flush, if the previous generation is kept.
+const OLD_WAL_NAME: &str = "wal.old";
+
+/// What an interrupted `rollback()` was doing.
+const ROLLBACK_NAME: &str = "rollback";
+
+/// Random token replaced by each flush, to detect other writers.
+const GENERATION_NAME: &str = "generation";
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 enum State {
     /// Modified. Stored using suffix "p".
@@ -34,175 +437,1643 @@ enum State {
 
     /// Removed.
     Removed,
+
+    /// Modified. Stored in a segment.
+    Packed(Location),
 }
 
 impl FsIntKv {
+    /// Open with an exclusive lock. Fail if another process uses the
+    /// directory.
+    #[cfg(test)]
     pub fn new(path: &Path) -> io::Result<Self> {
-        let kv = Self {
+        Self::new_with_lock(
+            path,
+            path,
+            LockMode::Exclusive,
+            Duration::from_secs(0),
+            FileNaming::default(),
+            None,
+        )
+    }
+
+    /// Open with the given lock of `lock_dir`, usually `path` itself,
+    /// waiting up to `wait` for other processes. `LockMode::Shared` opens
+    /// read-only, see `open_read_only`. Files are named by `naming`. `file_size` is the size of all entry files if
+    /// known, ex. the block size. Files of other sizes are reported by
+    /// `open_report`, and shorter ones fail to read.
+    pub fn new_with_lock(
+        path: &Path,
+        lock_dir: &Path,
+        mode: LockMode,
+        wait: Duration,
+        naming: FileNaming,
+        file_size: Option<u64>,
+    ) -> io::Result<Self> {
+        if mode == LockMode::Shared {
+            return Self::open_read_only_with(path, lock_dir, wait, naming, file_size);
+        }
+        let mut kv = Self {
             dir: path.to_path_buf(),
             overlay: Default::default(),
-        };
+            marked: false,
+            _lock: Some(DirLock::acquire(lock_dir, mode, wait)?),
+            read_only: false,
+            has_wal: false,
+            read_strategy: ReadStrategy::default(),
+            local: true,
+            min_file_size: file_size.unwrap_or(0),
+            report: Default::default(),
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            preallocate: false,
+            unallocated_bytes: 0,
+            keep_old: false,
+            committing: false,
+            naming,
+            segment_threshold: 0,
+            segments: Mutex::new(Segments::load(path)?),
+            reserved_space: 0,
+            low_space_warning: 0,
+            space: Default::default(),
+            generation: None,
+            check_generation: true,
+            metrics: Default::default(),
+            durability: Durability::Full,
+            direct_io: AtomicBool::new(false),
+            handles: Default::default(),
+            #[cfg(test)]
+            faults: Default::default(),
+        }
+        .with_open_files(DEFAULT_OPEN_FILES);
+        let mut report = OpenReport::default();
 
         // Redo WAL on previous crash.
         if kv.wal_path().exists() {
             log::info!("Re-committing WAL");
-            kv.wal_checkpoint()?;
+            match report::count_wal(&kv.wal_path())? {
+                Some(entries) => report.wal_entries = Some(entries),
+                None => report.torn_wal = true,
+            }
+            report.wal_replayed = true;
+            let mut steps = usize::MAX;
+            kv.wal_checkpoint(&mut steps)?;
+        }
+
+        // Complete an interrupted rollback.
+        kv.finish_rollback()?;
+
+        // Remove uncommitted files of a previous crash.
+        if kv.marker_path().exists() {
+            let removed = kv.remove_pending_files()?;
+            if removed > 0 {
+                crate::log_kv!(log::Level::Warn, "Removed uncommitted files"; files = removed);
+            }
+            report.pending_files = removed;
+            fs::remove_file(kv.marker_path())?;
+            kv.durability.sync_dir(&kv.dir)?;
+        }
+
+        kv.generation = read_generation(&kv.dir)?;
+
+        // Remove segments without committed entries, left by compaction or
+        // uncommitted writes.
+        let removed = kv.segments.lock().remove_dead()?;
+        if removed > 0 {
+            crate::log_kv!(log::Level::Info, "Removed unused segments"; segments = removed);
         }
 
+        kv.scan_report(&mut report)?;
+        kv.report = report;
         Ok(kv)
     }
 
-    fn get_path_for_index(&self, index: usize) -> PathBuf {
-        let in_wal = match self.overlay.get(&index) {
-            Some(State::Modified) => true,
-            _ => false,
-        };
-        self.get_path_for_index_wal(index, in_wal)
+    /// Open without changing anything on disk. `write`, `remove` and `flush`
+    /// fail with `PermissionDenied`.
+    ///
+    /// Take a shared lock, waiting up to `wait` for a writer. If the writer
+    /// still holds the lock, open without it. WAL is not replayed, check
+    /// `has_wal` to tell if the view is stale.
+    #[cfg(test)]
+    pub fn open_read_only(path: &Path, wait: Duration) -> io::Result<Self> {
+        Self::open_read_only_with(path, path, wait, FileNaming::default(), None)
     }
 
-    fn get_path_for_index_wal(&self, index: usize, in_wal: bool) -> PathBuf {
-        let name = match in_wal {
-            true => format!("{}p", index),
-            false => index.to_string(),
+    fn open_read_only_with(
+        path: &Path,
+        lock_dir: &Path,
+        wait: Duration,
+        naming: FileNaming,
+        file_size: Option<u64>,
+    ) -> io::Result<Self> {
+        if !path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        let lock = match DirLock::acquire(lock_dir, LockMode::Shared, wait) {
+            Ok(lock) => Some(lock),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                log::warn!("{}. Reading without a lock.", e);
+                None
+            }
+            // No writer has used the directory, or it is not writable.
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
         };
-        self.dir.join(name)
+        let mut kv = Self {
+            dir: path.to_path_buf(),
+            overlay: Default::default(),
+            marked: false,
+            read_only: true,
+            has_wal: path.join(WAL_NAME).exists(),
+            read_strategy: ReadStrategy::default(),
+            local: true,
+            min_file_size: file_size.unwrap_or(0),
+            report: Default::default(),
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            preallocate: false,
+            unallocated_bytes: 0,
+            keep_old: false,
+            committing: false,
+            naming,
+            segment_threshold: 0,
+            segments: Mutex::new(Segments::load(path)?),
+            reserved_space: 0,
+            low_space_warning: 0,
+            space: Default::default(),
+            generation: None,
+            check_generation: true,
+            metrics: Default::default(),
+            durability: Durability::Full,
+            direct_io: AtomicBool::new(false),
+            handles: Default::default(),
+            _lock: lock,
+            #[cfg(test)]
+            faults: Default::default(),
+        };
+        let mut report = OpenReport::default();
+        match report::count_wal(&kv.wal_path()) {
+            Ok(Some(entries)) => report.wal_entries = Some(entries),
+            Ok(None) => report.torn_wal = true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        kv.scan_report(&mut report)?;
+        kv.report = report;
+        Ok(kv)
     }
-}
 
-impl IntKv for FsIntKv {
-    fn read(&self, index: usize) -> io::Result<Bytes> {
-        match self.overlay.get(&index) {
-            Some(State::Removed) => return Err(io::ErrorKind::NotFound.into()),
-            _ => {}
+    /// What was found when opening. Read-only instances leave WAL and
+    /// pending files alone, which a writer might be using.
+    pub fn open_report(&self) -> OpenReport {
+        self.report.clone()
+    }
+
+    /// Set which fsyncs to issue.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self.segments.lock().durability = durability;
+        self
+    }
+
+    /// Set how to read files.
+    pub fn with_read_strategy(mut self, strategy: ReadStrategy) -> Self {
+        if strategy == ReadStrategy::Auto {
+            self.local = !is_network_fs(&self.dir);
         }
-        let path = self.get_path_for_index(index);
-        let file = fs::OpenOptions::new().read(true).open(path)?;
-        let bytes: Bytes = if file.metadata()?.len() == 0 {
-            Bytes::new()
+        self.read_strategy = strategy;
+        self
+    }
+
+    /// Try file operations up to `attempts` times on transient errors, such
+    /// as `ESTALE` of network filesystems. Wait `delay` before the first
+    /// retry, doubling for each retry.
+    pub fn with_retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.retry_attempts = attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Allocate disk space of files before writing them. Running out of
+    /// space then fails the write instead of the flush, and files are less
+    /// fragmented. Page files are written in one go, so their size is known.
+    pub fn with_preallocation(mut self, enabled: bool) -> Self {
+        self.preallocate = enabled;
+        self
+    }
+
+    /// Keep files replaced or removed by the last flush as `<index>.old`, so
+    /// `rollback()` can restore them. Changed files take twice the space
+    /// until the next flush.
+    pub fn with_previous_generation(mut self, enabled: bool) -> Self {
+        self.keep_old = enabled;
+        self
+    }
+
+    /// Pack entries shorter than `threshold` bytes into segment files, so
+    /// flushing many small entries fsyncs a few files. 0 disables packing.
+    /// Packed entries stay readable regardless.
+    pub fn with_segments(mut self, threshold: usize) -> Self {
+        self.segment_threshold = threshold;
+        self
+    }
+
+    /// Keep up to `limit` files open for reading and fsyncing. 0 disables
+    /// the cache. Ignored by read-only instances, and on Windows, where open
+    /// files cannot be renamed.
+    pub fn with_open_files(self, limit: usize) -> Self {
+        let limit = if cfg!(windows) || self.read_only {
+            0
         } else {
-            // Use mmap to read files.
-            unsafe { MmapOptions::new().map(&file) }?.into()
+            limit
         };
-        // fs::read(self.get_path_for_index(index)).map(|b| b.into())
-        Ok(bytes)
+        self.handles.lock().set_limit(limit);
+        self
     }
 
-    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
-        self.overlay.insert(index, State::Modified);
-        let path = self.get_path_for_index(index);
-        fs::write(path, &data)
+    /// Make `check_space` fail unless `bytes` stay free after the pending
+    /// changes, so accepted changes can still be flushed. Warn if less than
+    /// `warning` bytes are available.
+    pub fn with_reserved_space(mut self, bytes: u64, warning: u64) -> Self {
+        self.reserved_space = bytes;
+        self.low_space_warning = warning;
+        self
     }
 
-    fn remove(&mut self, index: usize) -> io::Result<()> {
-        match self.overlay.get(&index).cloned() {
-            Some(State::Removed) => {
-                return Err(io::ErrorKind::NotFound.into());
-            }
-            Some(State::Modified) => {
-                let path = self.get_path_for_index(index);
-                fs::remove_file(&path)?;
-                self.overlay.insert(index, State::Removed);
-            }
-            None => {
-                if !self.has(index)? {
-                    return Err(io::ErrorKind::NotFound.into());
-                }
-                self.overlay.insert(index, State::Removed);
+    /// Space of the filesystem. Measured again after
+    /// `SPACE_CHECK_INTERVAL`, or if `fresh`.
+    fn disk_space(&self, fresh: bool) -> io::Result<DiskSpace> {
+        let mut cached = self.space.lock();
+        if let Some((time, space)) = *cached {
+            if !fresh && time.elapsed() < SPACE_CHECK_INTERVAL {
+                return Ok(space);
             }
         }
-        Ok(())
+        let space = util::disk_space(&self.dir)?;
+        let was_low = cached.is_some_and(|(_, s)| s.available < self.low_space_warning);
+        if space.available < self.low_space_warning && !was_low {
+            crate::log_kv!(
+                log::Level::Warn, "Low disk space";
+                available_mb = space.available >> 20,
+                path = self.dir.display().to_string(),
+            );
+        }
+        *cached = Some((Instant::now(), space));
+        Ok(space)
     }
 
-    fn has(&self, index: usize) -> io::Result<bool> {
-        match self.overlay.get(&index).cloned() {
-            Some(State::Removed) => Ok(false),
-            Some(State::Modified) => Ok(true),
-            None => {
-                let path = self.get_path_for_index(index);
-                Ok(path.exists())
-            }
-        }
+    /// Refuse to flush if another program or machine flushed the directory
+    /// since this instance last did, as in-memory state of upper layers
+    /// would overwrite its changes. Enabled by default.
+    pub fn with_external_change_check(mut self, enabled: bool) -> Self {
+        self.check_generation = enabled;
+        self
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.flush_wal()
+    /// Read and write files bypassing the OS page cache, so large files do
+    /// not evict more useful data. Files are not mapped. Falls back to the
+    /// page cache with a warning if the filesystem refuses.
+    pub fn with_direct_io(self, enabled: bool) -> Self {
+        self.direct_io.store(enabled, Ordering::Release);
+        self
     }
-}
 
-impl FsIntKv {
-    fn flush_wal(&mut self) -> io::Result<()> {
-        if self.overlay.is_empty() {
-            return Ok(());
+    /// Restore files changed by the last flush. Return the number of
+    /// restored files. Entries added by the last flush are removed.
+    pub fn rollback(&mut self) -> io::Result<usize> {
+        self.check_writable()?;
+        if !self.overlay.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot roll back with uncommitted changes",
+            ));
+        }
+        if self.segments.lock().in_use() {
+            // Replaced entries in segments are not kept.
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot roll back entries packed in segments",
+            ));
         }
+        let previous = self.read_old_wal()?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no previous generation", self.dir.display()),
+            )
+        })?;
 
-        // Step 1: Fsync pending files.
-        for (&index, &state) in self.overlay.iter() {
+        // Decide what to do before changing anything, so an interrupted
+        // rollback can be completed.
+        let plan: HashMap<usize, State> = previous
+            .keys()
+            .map(
+                |&index| match self.get_old_path_for_index(index).is_file() {
+                    true => (index, State::Modified),
+                    false => (index, State::Removed),
+                },
+            )
+            .collect();
+        let mut file = NamedTempFile::new_in(self.dir.join(""))?;
+        file.write_all(&encode_wal(&plan))?;
+        self.durability.sync_data(file.as_file())?;
+        file.persist(self.dir.join(ROLLBACK_NAME))?;
+        fs::remove_file(self.dir.join(OLD_WAL_NAME))?;
+        self.durability.sync_dir(&self.dir)?;
+
+        self.finish_rollback()?;
+        Ok(plan
+            .values()
+            .filter(|&&s| matches!(s, State::Modified))
+            .count())
+    }
+
+    /// Apply the plan written by `rollback()`, if any.
+    fn finish_rollback(&self) -> io::Result<()> {
+        let path = self.dir.join(ROLLBACK_NAME);
+        let data = match fs::read(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        let plan = decode_wal(&data).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupted", path.display()),
+            )
+        })?;
+        self.handles.lock().clear();
+        for (&index, &state) in plan.iter() {
+            let dest_path = self.get_path_for_index_wal(index, false);
             match state {
                 State::Modified => {
-                    let path = self.get_path_for_index(index);
-                    let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
-                    file.sync_all()?;
+                    let old_path = self.get_old_path_for_index(index);
+                    ignore_not_found(fs::rename(old_path, dest_path))?;
                 }
-                State::Removed => {}
+                State::Removed => ignore_not_found(fs::remove_file(dest_path))?,
+                State::Packed(_) => {}
             }
         }
+        self.sync_dirs(plan.keys().copied())?;
+        fs::remove_file(&path)?;
+        self.durability.sync_dir(&self.dir)?;
+        Ok(())
+    }
 
-        // Step 2: Write WAL.
-        log::info!("Writing WAL of {} entries", self.overlay.len());
-        let wal_bytes = bincode::serialize(&self.overlay).unwrap();
-        let mut wal_file = NamedTempFile::new_in(self.dir.join(""))?;
-        wal_file.write_all(&wal_bytes)?;
-        wal_file.as_file().sync_data()?;
-        wal_file.persist_noclobber(self.wal_path())?;
+    /// Number and total size of the files kept by `with_previous_generation`.
+    pub fn previous_generation_usage(&self) -> io::Result<(usize, u64)> {
+        let mut count = 0;
+        let mut size = 0;
+        for &index in self.read_old_wal()?.unwrap_or_default().keys() {
+            match fs::metadata(self.get_old_path_for_index(index)) {
+                Ok(metadata) => {
+                    count += 1;
+                    size += metadata.len();
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((count, size))
+    }
 
-        // Step 3: Apply WAL. Clear internal state.
-        log::info!("Committing WAL");
-        self.wal_checkpoint()?;
-        self.overlay = Default::default();
+    /// Copy committed files to the new directory `dest`, hard-linking them
+    /// where possible. Flush pending changes first. Return the paths of the
+    /// files relative to `dest`, and whether they were linked.
+    ///
+    /// A flush replaces committed files by renaming new files over them,
+    /// and never writes them in place. So linked files keep their content
+    /// while this directory changes. The last segment is appended to, and
+    /// is copied instead.
+    pub fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.check_writable()?;
+        self.flush()?;
+        let mut files = Vec::new();
+        scan_dir(&self.dir, 0, &mut |path, name| {
+            if self.naming.parse(name).is_some() {
+                files.push((path.to_path_buf(), true));
+            }
+            Ok(())
+        })?;
+        {
+            let segments = self.segments.lock();
+            let last = segments.ids().last();
+            for id in segments.ids() {
+                files.push((segment::segment_path(&self.dir, id), Some(id) != last));
+            }
+        }
+        let manifest_path = self.dir.join(MANIFEST_NAME);
+        if manifest_path.exists() {
+            files.push((manifest_path, false));
+        }
+
+        fs::create_dir(dest)?;
+        let mut dirs = BTreeSet::new();
+        dirs.insert(dest.to_path_buf());
+        let mut result = Vec::with_capacity(files.len());
+        for (path, link) in files {
+            let relative = path.strip_prefix(&self.dir).unwrap().to_path_buf();
+            let dest_path = dest.join(&relative);
+            let parent = dest_path.parent().unwrap();
+            if !dirs.contains(parent) {
+                fs::create_dir_all(parent)?;
+                dirs.extend(
+                    parent
+                        .ancestors()
+                        .take_while(|p| p.starts_with(dest))
+                        .map(Path::to_path_buf),
+                );
+            }
+            // Some filesystems do not support hard links.
+            let linked = link && fs::hard_link(&path, &dest_path).is_ok();
+            if !linked {
+                fs::copy(&path, &dest_path)?;
+                self.durability.sync_all(&fs::File::open(&dest_path)?)?;
+            }
+            result.push((relative, linked));
+        }
+        for dir in dirs {
+            self.durability.sync_dir(&dir)?;
+        }
+        Ok(result)
+    }
+
+    /// WAL of the last flush, kept by `with_previous_generation`.
+    fn read_old_wal(&self) -> io::Result<Option<HashMap<usize, State>>> {
+        let path = self.dir.join(OLD_WAL_NAME);
+        match fs::read(&path) {
+            Ok(data) => Ok(decode_wal(&data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_old_path_for_index(&self, index: usize) -> PathBuf {
+        self.shard_dir(index)
+            .join(format!("{}.old", self.naming.name(index)))
+    }
 
+    /// Move the file of `index`, if any, to the old name.
+    fn keep_old_file(&self, index: usize) -> io::Result<()> {
+        let paths = [
+            self.get_path_for_index_wal(index, false),
+            self.get_flat_path_for_index_wal(index, false),
+        ];
+        for path in &paths {
+            match self.keep_old_path(index, path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => return result,
+            }
+        }
         Ok(())
     }
 
-    fn wal_path(&self) -> PathBuf {
-        const WAL_NAME: &str = "wal";
-        self.dir.join(WAL_NAME)
+    /// Move `path`, a file of `index`, to the old name.
+    fn keep_old_path(&self, index: usize, path: &Path) -> io::Result<()> {
+        let old_path = self.get_old_path_for_index(index);
+        // The shard directory is missing if `path` uses the flat layout.
+        let result = match fs::rename(path, &old_path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && fs::metadata(path).is_ok() => {
+                self.create_shard_dir(index)?;
+                fs::rename(path, &old_path)
+            }
+            result => result,
+        };
+        result?;
+        self.handles.lock().rename(path, &old_path);
+        Ok(())
     }
 
-    /// Persist WAL to disk.
-    fn wal_checkpoint(&self) -> io::Result<()> {
-        let wal_path = self.wal_path();
-        let wal_data = ignore_not_found(fs::read(self.wal_path()))?;
-        if wal_data.is_empty() {
-            return Ok(());
+    /// Rename the pending file `from` of `index` to the committed file `to`,
+    /// keeping the replaced file if `keep_old` is set. Fails with `NotFound`
+    /// if `from` is missing.
+    fn commit_file(&self, index: usize, from: &Path, to: &Path) -> io::Result<()> {
+        if self.keep_old {
+            let old_path = self.get_old_path_for_index(index);
+            if old_path.parent() != to.parent() {
+                // Flat layout.
+                self.create_shard_dir(index)?;
+            }
+            rename_keeping(from, to, &old_path)?;
+        } else {
+            fs::rename(from, to)?;
         }
-        let overlay: HashMap<usize, State> = bincode::deserialize(&wal_data)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.handles.lock().rename(from, to);
+        Ok(())
+    }
 
-        // Apply WAL: Rename or remove files.
-        for (&index, &state) in overlay.iter() {
-            match state {
-                State::Modified => {
-                    log::info!("Committing {}", index);
-                    let wal_path = self.get_path_for_index_wal(index, true);
-                    if wal_path.exists() {
-                        let dest_path = self.get_path_for_index_wal(index, false);
-                        fs::rename(wal_path, dest_path)?;
-                    }
+    /// Write a file. Return whether its space was allocated upfront.
+    /// The file is kept open for the fsync of `flush()`.
+    fn write_file(&self, path: &Path, data: &[u8]) -> io::Result<bool> {
+        let allocated = self
+            .metrics
+            .time(Op::Write, || self.write_file_untimed(path, data))?;
+        self.metrics.add_written(data.len() as u64);
+        Ok(allocated)
+    }
+
+    fn write_file_untimed(&self, path: &Path, data: &[u8]) -> io::Result<bool> {
+        let options = || {
+            let mut options = fs::OpenOptions::new();
+            options.read(true).write(true).create(true).truncate(true);
+            options
+        };
+        let (mut file, direct) = self.open_direct(options, path, !data.is_empty())?;
+        let allocated = match self.preallocate && !data.is_empty() {
+            false => false,
+            true => match allocate(&file, data.len() as u64) {
+                Ok(()) => true,
+                Err(e) if e.kind() == io::ErrorKind::StorageFull => return Err(e),
+                Err(e) => {
+                    log::debug!("Cannot preallocate {}: {}", path.display(), e);
+                    false
                 }
-                State::Removed => {
-                    log::info!("Removing {}", index);
-                    let dest_path = self.get_path_for_index_wal(index, false);
-                    ignore_not_found(fs::remove_file(&dest_path))?;
+            },
+        };
+        match direct {
+            true => match direct::write(&file, data) {
+                Err(e) if self.refuse_direct_io(&e) => {
+                    file = options().open(path)?;
+                    file.write_all(data)?;
                 }
+                result => result?,
+            },
+            false => file.write_all(data)?,
+        }
+        self.handles.lock().insert(path, Arc::new(file));
+        Ok(allocated)
+    }
+
+    /// Open a file, bypassing the page cache if `with_direct_io` and
+    /// `wanted`. Return whether it does.
+    fn open_direct(
+        &self,
+        options: impl Fn() -> fs::OpenOptions,
+        path: &Path,
+        wanted: bool,
+    ) -> io::Result<(fs::File, bool)> {
+        if wanted && self.direct_io.load(Ordering::Acquire) {
+            match direct::open(&mut options(), path) {
+                Err(e) if self.refuse_direct_io(&e) => {}
+                result => return Ok((result?, true)),
             }
         }
+        Ok((options().open(path)?, false))
+    }
 
-        ignore_not_found(fs::remove_file(wal_path))?;
-        Ok(())
+    /// Whether `e` tells direct I/O is not supported. If so, stop using it.
+    fn refuse_direct_io(&self, e: &io::Error) -> bool {
+        let refused = e.kind() == io::ErrorKind::InvalidInput;
+        if refused && self.direct_io.swap(false, Ordering::AcqRel) {
+            log::warn!(
+                "Direct I/O is not supported in {} ({}). Using the page cache.",
+                self.dir.display(),
+                e
+            );
+        }
+        refused
     }
-}
 
-fn ignore_not_found<T: Default>(result: io::Result<T>) -> io::Result<T> {
+    /// Open a file, or reuse the file kept open by `with_open_files`.
+    fn open_file(
+        &self,
+        path: &Path,
+        open: impl FnOnce() -> io::Result<fs::File>,
+    ) -> io::Result<Arc<fs::File>> {
+        if let Some(file) = self.handles.lock().get(path) {
+            return Ok(file);
+        }
+        let file = Arc::new(open()?);
+        self.handles.lock().insert(path, file.clone());
+        Ok(file)
+    }
+
+    /// Remove a file, dropping it from the open files.
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.handles.lock().remove(path);
+        self.metrics.time(Op::Remove, || fs::remove_file(path))
+    }
+
+    /// Run a file operation on `path`. Retry on transient errors.
+    fn retry<T>(&self, path: &Path, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 1;
+        let mut delay = self.retry_delay;
+        loop {
+            match self.inject_fault().and_then(|()| op()) {
+                Err(e) if is_transient(&e) && attempt < self.retry_attempts => {
+                    crate::log_kv!(
+                        log::Level::Warn, "Retrying";
+                        path = path.display().to_string(),
+                        error = e.to_string(),
+                        attempt = attempt,
+                        attempts = self.retry_attempts,
+                    );
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn inject_fault(&self) -> io::Result<()> {
+        match self.faults.lock().pop() {
+            Some(Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(test))]
+    fn inject_fault(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Bytes> {
+        let data = self
+            .metrics
+            .time(Op::Read, || self.read_file_untimed(path))?;
+        self.metrics.add_read(data.len() as u64);
+        Ok(data)
+    }
+
+    fn read_file_untimed(&self, path: &Path) -> io::Result<Bytes> {
+        let options = || {
+            let mut options = fs::OpenOptions::new();
+            options.read(true);
+            options
+        };
+        let file = self.open_file(path, || Ok(self.open_direct(options, path, true)?.0))?;
+        let len = file.metadata()?.len();
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        if len < self.min_file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is truncated: {} bytes, expected at least {}",
+                    path.display(),
+                    len,
+                    self.min_file_size
+                ),
+            ));
+        }
+        if self.direct_io.load(Ordering::Acquire) {
+            match direct::read(&file, len) {
+                Err(e) if self.refuse_direct_io(&e) => {
+                    // The file might have been opened for direct I/O.
+                    self.handles.lock().remove(path);
+                    return Ok(read_from_start(&options().open(path)?, len)?.into());
+                }
+                result => return Ok(result?.into()),
+            }
+        }
+        let mmap = match self.read_strategy {
+            ReadStrategy::Mmap => true,
+            ReadStrategy::Buffered => false,
+            ReadStrategy::Auto => self.local && len >= AUTO_MMAP_MIN_SIZE,
+        };
+        let bytes: Bytes = if mmap {
+            unsafe { MmapOptions::new().map(&file) }?.into()
+        } else {
+            read_from_start(&file, len)?.into()
+        };
+        Ok(bytes)
+    }
+
+    /// Whether a read-only instance found a WAL not yet committed by a
+    /// writer. If so, recent changes are not visible.
+    pub fn has_wal(&self) -> bool {
+        self.has_wal
+    }
+
+    fn marker_path(&self) -> PathBuf {
+        self.dir.join(MARKER_NAME)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} was opened read-only", self.dir.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Remove pending files not covered by WAL. Return the number of removed
+    /// files.
+    fn remove_pending_files(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        scan_dir(&self.dir, 0, &mut |path, name| {
+            let is_pending = name
+                .strip_suffix('p')
+                .is_some_and(|s| self.naming.parse(s).is_some());
+            if is_pending {
+                self.remove_file(path)?;
+                removed += 1;
+            }
+            Ok(())
+        })?;
+        Ok(removed)
+    }
+
+    fn get_path_for_index(&self, index: usize) -> PathBuf {
+        let in_wal = matches!(self.overlay.get(&index), Some(State::Modified));
+        if in_wal {
+            return self.get_path_for_index_wal(index, true);
+        }
+        let path = self.get_path_for_index_wal(index, false);
+        if !path.exists() {
+            let flat_path = self.get_flat_path_for_index_wal(index, false);
+            if flat_path.is_file() {
+                return flat_path;
+            }
+        }
+        path
+    }
+
+    fn get_path_for_index_wal(&self, index: usize, in_wal: bool) -> PathBuf {
+        self.shard_dir(index).join(self.file_name(index, in_wal))
+    }
+
+    /// Path in the flat layout of older directories.
+    fn get_flat_path_for_index_wal(&self, index: usize, in_wal: bool) -> PathBuf {
+        self.dir.join(self.file_name(index, in_wal))
+    }
+
+    fn shard_dir(&self, index: usize) -> PathBuf {
+        self.dir
+            .join(BLOCKS_DIR)
+            .join(format!("{:02x}", index & 0xff))
+            .join(format!("{:02x}", (index >> 8) & 0xff))
+    }
+
+    /// Create `shard_dir(index)`. Unlike `create_dir_all`, fail if the top
+    /// directory is missing.
+    fn create_shard_dir(&self, index: usize) -> io::Result<()> {
+        let dir = self.shard_dir(index);
+        let parent = dir.parent().unwrap();
+        for path in [parent.parent().unwrap(), parent, &dir] {
+            match fs::create_dir(path) {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Move entries in the flat layout to subdirectories. Flush pending
+    /// changes first. Return the number of moved entries.
+    pub fn migrate_layout(&mut self) -> io::Result<usize> {
+        self.flush()?;
+        // Files are renamed below, and by `migrate_naming`.
+        self.handles.lock().clear();
+        // Older versions did not write the marker.
+        self.remove_pending_files()?;
+        let mut moved = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name();
+            let index = match name.to_str().and_then(|s| self.naming.parse(s)) {
+                Some(index) => index,
+                None => continue,
+            };
+            let dest_path = self.get_path_for_index_wal(index, false);
+            if dest_path.exists() {
+                // Superseded by a rewrite.
+                fs::remove_file(entry.path())?;
+            } else {
+                self.create_shard_dir(index)?;
+                fs::rename(entry.path(), dest_path)?;
+                moved.push(index);
+            }
+        }
+        self.sync_dirs(moved.iter().copied())?;
+        Ok(moved.len())
+    }
+
+    /// Move the files of this directory into `dest`, creating it if
+    /// missing. Flush pending changes and migrate the flat layout first.
+    /// Return the number of moved entries. The lock stays.
+    ///
+    /// Each top-level entry is moved by one rename, so an interrupted move
+    /// can be run again to move the rest. Until then, neither directory
+    /// should be opened otherwise.
+    pub fn move_into(mut self, dest: &Path) -> io::Result<usize> {
+        self.migrate_layout()?;
+        self.segments.lock().close();
+        match fs::create_dir(dest) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        let mut moved = 0;
+        // Segments go before the manifest. Opening segments without their
+        // manifest would remove them as dead.
+        for name in [
+            BLOCKS_DIR,
+            SEGMENTS_DIR,
+            MANIFEST_NAME,
+            GENERATION_NAME,
+            WAL_NAME,
+            OLD_WAL_NAME,
+            ROLLBACK_NAME,
+            MARKER_NAME,
+        ] {
+            match fs::rename(self.dir.join(name), dest.join(name)) {
+                Ok(()) => moved += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.durability.sync_dir(dest)?;
+        self.durability.sync_dir(&self.dir)?;
+        Ok(moved)
+    }
+
+    /// Rename files to use `naming`. Flush pending changes and drop the
+    /// previous generation first. `progress` is called with the number of
+    /// files checked and the total. Return the number of renamed files.
+    ///
+    /// Files already using `naming` are left alone, so an interrupted
+    /// migration can be run again.
+    pub fn migrate_naming(
+        &mut self,
+        naming: FileNaming,
+        mut progress: impl FnMut(usize, usize),
+    ) -> io::Result<usize> {
+        naming.validate()?;
+        self.migrate_layout()?;
+        let (count, _) = self.previous_generation_usage()?;
+        if let Some(previous) = self.read_old_wal()? {
+            for &index in previous.keys() {
+                ignore_not_found(fs::remove_file(self.get_old_path_for_index(index)))?;
+            }
+            fs::remove_file(self.dir.join(OLD_WAL_NAME))?;
+            crate::log_kv!(log::Level::Info, "Dropped files of the previous generation"; files = count);
+        }
+        let mut files = Vec::new();
+        scan_dir(&self.dir, 0, &mut |path, name| {
+            if let Some(index) = self.naming.parse(name) {
+                files.push((path.to_path_buf(), index));
+            }
+            Ok(())
+        })?;
+        let old_naming = std::mem::replace(&mut self.naming, naming);
+        let mut renamed = Vec::new();
+        let total = files.len();
+        for (done, (path, index)) in files.into_iter().enumerate() {
+            progress(done, total);
+            let dest_path = self.get_path_for_index_wal(index, false);
+            if dest_path == path {
+                continue;
+            }
+            if dest_path.exists() {
+                self.naming = old_naming;
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "cannot rename {} to {}: the name is in use",
+                        path.display(),
+                        dest_path.display()
+                    ),
+                ));
+            }
+            fs::rename(&path, dest_path)?;
+            renamed.push(index);
+        }
+        progress(total, total);
+        self.sync_dirs(renamed.iter().copied())?;
+        Ok(renamed.len())
+    }
+
+    fn file_name(&self, index: usize, in_wal: bool) -> String {
+        match in_wal {
+            true => format!("{}p", self.naming.name(index)),
+            false => self.naming.name(index),
+        }
+    }
+}
+
+const WAL_NAME: &str = "wal";
+
+/// Exists if pending files might exist.
+const MARKER_NAME: &str = "pending";
+
+/// Top directory of the layout with subdirectories.
+const BLOCKS_DIR: &str = "blocks";
+
+/// Whether a directory name is a level of `FsIntKv::shard_dir` below
+/// `BLOCKS_DIR`.
+fn is_shard_name(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Call `f` with the path and the name of files in `dir` and the
+/// subdirectories of the layout. `depth` is the number of directory levels
+/// below the top.
+fn scan_dir(
+    dir: &Path,
+    depth: usize,
+    f: &mut dyn FnMut(&Path, &str) -> io::Result<()>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if entry.file_type()?.is_dir() {
+            let descend = match depth {
+                0 => name == BLOCKS_DIR,
+                1 | 2 => is_shard_name(name),
+                _ => false,
+            };
+            if descend {
+                scan_dir(&entry.path(), depth + 1, f)?;
+            }
+        } else {
+            f(&entry.path(), name)?;
+        }
+    }
+    Ok(())
+}
+
+impl IntKv for FsIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let location = match self.overlay.get(&index) {
+            Some(State::Removed) => return Err(io::ErrorKind::NotFound.into()),
+            Some(&State::Packed(location)) => Some(location),
+            Some(State::Modified) => None,
+            None => self.segments.lock().get(index),
+        };
+        if let Some(location) = location {
+            let path = self.dir.join(SEGMENTS_DIR);
+            let data = self.retry(&path, || {
+                self.metrics
+                    .time(Op::Read, || segment::read(&self.dir, location))
+            })?;
+            self.metrics.add_read(data.len() as u64);
+            return Ok(data.into());
+        }
+        let mut path = self.get_path_for_index(index);
+        if self.committing && !path.exists() {
+            // Renamed by the failed commit.
+            path = self.get_path_for_index_wal(index, false);
+        }
+        self.retry(&path, || self.read_file(&path))
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.check_writable()?;
+        self.finish_commit()?;
+        if data.len() < self.segment_threshold {
+            return self.write_packed(index, &data);
+        }
+        if !self.marked {
+            let marker_path = self.marker_path();
+            self.retry(&marker_path, || fs::write(&marker_path, b""))?;
+            self.durability.sync_dir(&self.dir)?;
+            self.marked = true;
+        }
+        let previous = self.overlay.insert(index, State::Modified);
+        let path = self.get_path_for_index(index);
+        let result = self.retry(&path, || match self.write_file(&path, &data) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.create_shard_dir(index)?;
+                self.write_file(&path, &data)
+            }
+            result => result,
+        });
+        let allocated = match result {
+            Ok(allocated) => allocated,
+            Err(e) => {
+                // Do not commit a partial file. If it replaced an earlier
+                // write, flush fails until the entry is written again.
+                let _ = self.remove_file(&path);
+                match previous {
+                    None => self.overlay.remove(&index),
+                    Some(state @ State::Removed) | Some(state @ State::Packed(_)) => {
+                        self.overlay.insert(index, state)
+                    }
+                    Some(State::Modified) => None,
+                };
+                return Err(e);
+            }
+        };
+        if !allocated {
+            self.unallocated_bytes += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.check_writable()?;
+        self.finish_commit()?;
+        match self.overlay.get(&index).cloned() {
+            Some(State::Removed) => {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            Some(State::Modified) => {
+                let path = self.get_path_for_index(index);
+                self.retry(&path, || self.remove_file(&path))?;
+                self.overlay.insert(index, State::Removed);
+            }
+            Some(State::Packed(_)) => {
+                self.overlay.insert(index, State::Removed);
+            }
+            None => {
+                if !self.has(index)? {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
+                self.overlay.insert(index, State::Removed);
+            }
+        }
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        match self.overlay.get(&index).cloned() {
+            Some(State::Removed) => Ok(false),
+            Some(State::Modified) | Some(State::Packed(_)) => Ok(true),
+            None => {
+                if self.segments.lock().get(index).is_some() {
+                    return Ok(true);
+                }
+                let path = self.get_path_for_index_wal(index, false);
+                Ok(path.exists() || self.get_flat_path_for_index_wal(index, false).is_file())
+            }
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys = BTreeSet::new();
+        scan_dir(&self.dir, 0, &mut |_, name| {
+            // Skip pending files, WAL, and other files.
+            if let Some(index) = self.naming.parse(name) {
+                keys.insert(index);
+            }
+            Ok(())
+        })?;
+        keys.extend(self.segments.lock().keys());
+        for (&index, &state) in self.overlay.iter() {
+            match state {
+                State::Modified | State::Packed(_) => keys.insert(index),
+                State::Removed => keys.remove(&index),
+            };
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_writable()?;
+        self.flush_wal()
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        self.metrics.stats(&mut stats);
+        if let Ok(space) = self.disk_space(false) {
+            stats.insert("fs.available_bytes".into(), space.available);
+            stats.insert("fs.total_bytes".into(), space.total);
+        }
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let required = extra + self.unallocated_bytes + self.reserved_space;
+        check_free_space(&self.dir, required, self.disk_space(false)?.available)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.compact_segments(max_pages)?;
+        Ok(())
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        // Inherent methods come first.
+        FsIntKv::snapshot(self, dest)
+    }
+}
+
+impl FsIntKv {
+    fn flush_wal(&mut self) -> io::Result<()> {
+        self.flush_wal_steps(usize::MAX)
+    }
+
+    /// Retry applying WAL written by a failed `flush()`. The overlay must
+    /// not change before that.
+    fn finish_commit(&mut self) -> io::Result<()> {
+        match self.committing {
+            true => self.flush_wal(),
+            false => Ok(()),
+        }
+    }
+
+    /// Append an entry to a segment.
+    fn write_packed(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        let segments_dir = self.dir.join(SEGMENTS_DIR);
+        let location = self.retry(&segments_dir, || {
+            self.metrics
+                .time(Op::Write, || self.segments.lock().append(data))
+        })?;
+        self.metrics.add_written(data.len() as u64);
+        if let Some(State::Modified) = self.overlay.get(&index) {
+            // Replace the pending file of an earlier write.
+            let path = self.get_path_for_index(index);
+            self.retry(&path, || self.remove_file(&path))?;
+        }
+        self.overlay.insert(index, State::Packed(location));
+        self.unallocated_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// Rewrite the live entries of up to `max` mostly-dead segments, then
+    /// delete them. Flush pending changes first. Return the number of
+    /// deleted segments.
+    fn compact_segments(&mut self, max: usize) -> io::Result<usize> {
+        let candidates = self.segments.lock().compaction_candidates(max);
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+        self.flush()?;
+        let segments_dir = self.dir.join(SEGMENTS_DIR);
+        for id in candidates {
+            let entries = self.segments.lock().entries_in(id);
+            for (index, location) in entries {
+                let data = self.retry(&segments_dir, || segment::read(&self.dir, location))?;
+                let location = self.retry(&segments_dir, || self.segments.lock().append(&data))?;
+                self.overlay.insert(index, State::Packed(location));
+                self.unallocated_bytes += data.len() as u64;
+            }
+        }
+        self.flush()?;
+        let removed = self.retry(&segments_dir, || self.segments.lock().remove_dead())?;
+        crate::log_kv!(log::Level::Info, "Compacted segments"; segments = removed);
+        Ok(removed)
+    }
+
+    /// Flush, but stop after `steps` steps as if power was cut. Fsyncing
+    /// pending files, writing WAL, applying each entry, and removing WAL are
+    /// steps.
+    fn flush_wal_steps(&mut self, steps: usize) -> io::Result<()> {
+        if self.overlay.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let before = self.metrics.totals();
+        let entries = self.overlay.len();
+        let result = self.commit_wal_steps(steps);
+        let elapsed = start.elapsed();
+        self.metrics.record(Op::Commit, elapsed);
+        if elapsed >= SLOW_FLUSH {
+            crate::log_kv!(
+                log::Level::Info, "Flushed entries";
+                entries = entries,
+                secs = elapsed.as_secs_f64(),
+                summary = metrics::summary(&before, &self.metrics.totals()),
+            );
+        }
+        result
+    }
+
+    /// `flush_wal_steps` without the metrics.
+    fn commit_wal_steps(&mut self, mut steps: usize) -> io::Result<()> {
+        if !self.committing {
+            self.write_wal_steps(&mut steps)?;
+            if steps == 0 {
+                return Ok(());
+            }
+        }
+
+        // Step 3: Apply WAL. Clear internal state.
+        log::info!("Committing WAL");
+        self.apply_wal(&self.overlay, &mut steps)?;
+        if steps > 0 {
+            self.overlay = Default::default();
+            self.committing = false;
+            self.unallocated_bytes = 0;
+            if self.marked {
+                fs::remove_file(self.marker_path())?;
+                self.marked = false;
+            }
+            self.bump_generation()?;
+        }
+
+        Ok(())
+    }
+
+    /// Fail if the generation token is not the one last read or written.
+    fn check_generation(&self) -> io::Result<()> {
+        if !self.check_generation {
+            return Ok(());
+        }
+        let current = read_generation(&self.dir)?;
+        if current != self.generation {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} was modified externally since it was opened (generation {} became {})",
+                    self.dir.display(),
+                    self.generation.as_deref().unwrap_or("none"),
+                    current.as_deref().unwrap_or("none"),
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replace the generation token after a flush.
+    fn bump_generation(&mut self) -> io::Result<()> {
+        let token = format!("{:016x}", rand::random::<u64>());
+        let path = self.dir.join(GENERATION_NAME);
+        self.retry(&path, || {
+            let mut file = NamedTempFile::new_in(self.dir.join(""))?;
+            file.write_all(token.as_bytes())?;
+            self.durability.sync_data(file.as_file())?;
+            file.persist(&path)?;
+            Ok(())
+        })?;
+        self.generation = Some(token);
+        Ok(())
+    }
+
+    /// Steps 1 and 2 of `flush_wal_steps`. Failures leave the overlay and
+    /// the committed files untouched, so `flush()` can be retried.
+    fn write_wal_steps(&mut self, steps: &mut usize) -> io::Result<()> {
+        self.check_generation()?;
+
+        // Fail before WAL is written if files might not fit.
+        let wal_bytes = encode_wal(&self.overlay);
+        let required = self.unallocated_bytes + wal_bytes.len() as u64;
+        check_free_space(&self.dir, required, self.disk_space(true)?.available)?;
+
+        // Step 1: Fsync pending files.
+        if !next_step(steps) {
+            return Ok(());
+        }
+        let no_sync = self.durability == Durability::None;
+        for (&index, &state) in self.overlay.iter() {
+            match state {
+                State::Modified if !no_sync => {
+                    let path = self.get_path_for_index(index);
+                    self.retry(&path, || {
+                        let file = self.open_file(&path, || {
+                            fs::OpenOptions::new().read(true).write(true).open(&path)
+                        })?;
+                        self.metrics.time(Op::Fsync, || file.sync_all())
+                    })?;
+                }
+                _ => {}
+            }
+        }
+        let segments_dir = self.dir.join(SEGMENTS_DIR);
+        self.retry(&segments_dir, || match no_sync {
+            // Only forgets what to sync.
+            true => self.segments.lock().sync(),
+            false => self.metrics.time(Op::Fsync, || self.segments.lock().sync()),
+        })?;
+        self.sync_dirs(self.overlay.keys().copied())?;
+
+        // Step 2: Write WAL.
+        if !next_step(steps) {
+            return Ok(());
+        }
+        crate::log_kv!(log::Level::Info, "Writing WAL"; entries = self.overlay.len());
+        let wal_path = self.wal_path();
+        self.retry(&wal_path, || {
+            let mut wal_file = NamedTempFile::new_in(self.dir.join(""))?;
+            wal_file.write_all(&wal_bytes)?;
+            if self.durability != Durability::None {
+                self.metrics
+                    .time(Op::Fsync, || wal_file.as_file().sync_data())?;
+            }
+            wal_file.persist_noclobber(&wal_path)?;
+            Ok(())
+        })?;
+        self.committing = true;
+        self.durability.sync_dir(&self.dir)?;
+        Ok(())
+    }
+
+    fn wal_path(&self) -> PathBuf {
+        self.dir.join(WAL_NAME)
+    }
+
+    /// Redo WAL left by a previous crash. Stop after `steps` steps, see
+    /// `flush_wal_steps`.
+    ///
+    /// WAL with a bad checksum is treated as never committed and removed.
+    /// `flush()` only renames WAL into place after its content was synced,
+    /// and only applies it after that, so a torn WAL means no pending file
+    /// was renamed. The pending files are removed as uncommitted files.
+    fn wal_checkpoint(&self, steps: &mut usize) -> io::Result<()> {
+        let wal_path = self.wal_path();
+        let wal_data = match self.retry(&wal_path, || fs::read(&wal_path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        let overlay = match decode_wal(&wal_data) {
+            Some(overlay) => overlay,
+            None => {
+                log::warn!("Discarding torn WAL ({} bytes)", wal_data.len());
+                fs::remove_file(&wal_path)?;
+                self.durability.sync_dir(&self.dir)?;
+                return Ok(());
+            }
+        };
+        self.apply_wal(&overlay, steps)
+    }
+
+    /// Rename or remove files, then remove WAL.
+    ///
+    /// A missing pending file means it was renamed by an earlier, interrupted
+    /// run. Applying WAL again is harmless.
+    fn apply_wal(&self, overlay: &HashMap<usize, State>, steps: &mut usize) -> io::Result<()> {
+        let wal_path = self.wal_path();
+        for (&index, &state) in overlay.iter() {
+            if !next_step(steps) {
+                return Ok(());
+            }
+            let dest_path = self.get_path_for_index_wal(index, false);
+            // Applying an entry again is harmless. Retry it as a whole.
+            self.retry(&dest_path, || self.apply_wal_entry(index, state))?;
+        }
+
+        self.sync_dirs(overlay.keys().copied())?;
+        // Packed entries are committed by the manifest.
+        let manifest_path = self.dir.join(MANIFEST_NAME);
+        self.retry(&manifest_path, || self.segments.lock().save_manifest())?;
+
+        if !next_step(steps) {
+            return Ok(());
+        }
+        // Replace the WAL of the generation before, then drop its old files.
+        let previous = self.read_old_wal()?.unwrap_or_default();
+        let old_wal_path = self.dir.join(OLD_WAL_NAME);
+        self.retry(&wal_path, || {
+            if self.keep_old {
+                ignore_not_found(fs::rename(&wal_path, &old_wal_path))
+            } else {
+                ignore_not_found(fs::remove_file(&wal_path))?;
+                ignore_not_found(fs::remove_file(&old_wal_path))
+            }
+        })?;
+        self.durability.sync_dir(&self.dir)?;
+        for &index in previous.keys() {
+            if !(self.keep_old && overlay.contains_key(&index)) {
+                ignore_not_found(self.remove_file(&self.get_old_path_for_index(index)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_wal_entry(&self, index: usize, state: State) -> io::Result<()> {
+        let dest_path = self.get_path_for_index_wal(index, false);
+        let flat_dest_path = self.get_flat_path_for_index_wal(index, false);
+        match state {
+            State::Modified => {
+                crate::log_kv!(log::Level::Info, "Committing"; index = index);
+                // A missing pending file was committed by an earlier run.
+                let wal_path = self.get_path_for_index_wal(index, true);
+                ignore_not_found(self.commit_file(index, &wal_path, &dest_path))?;
+                // WAL written by older versions uses the flat layout.
+                let flat_wal_path = self.get_flat_path_for_index_wal(index, true);
+                match self.commit_file(index, &flat_wal_path, &flat_dest_path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        // Drop the file superseded by `dest_path`, if any.
+                        if dest_path.is_file() {
+                            ignore_not_found(match self.keep_old {
+                                true => self.keep_old_path(index, &flat_dest_path),
+                                false => self.remove_file(&flat_dest_path),
+                            })?;
+                        }
+                    }
+                    result => result?,
+                }
+            }
+            State::Removed | State::Packed(_) => {
+                match state {
+                    State::Packed(location) => crate::log_kv!(
+                        log::Level::Info, "Committing to segment";
+                        index = index,
+                        segment = location.segment,
+                    ),
+                    _ => crate::log_kv!(log::Level::Info, "Removing"; index = index),
+                }
+                if self.keep_old {
+                    self.keep_old_file(index)?;
+                }
+                ignore_not_found(self.remove_file(&dest_path))?;
+                ignore_not_found(self.remove_file(&flat_dest_path))?;
+            }
+        }
+        let location = match state {
+            State::Packed(location) => Some(location),
+            _ => None,
+        };
+        self.segments.lock().set(index, location);
+        Ok(())
+    }
+
+    /// Fsync the top directory, and directories of the entries with their
+    /// parents, so renames and new directories are durable.
+    fn sync_dirs(&self, indexes: impl Iterator<Item = usize>) -> io::Result<()> {
+        if self.durability != Durability::Full {
+            return Ok(());
+        }
+        let mut dirs = BTreeSet::new();
+        dirs.insert(self.dir.clone());
+        for index in indexes {
+            let dir = self.shard_dir(index);
+            dirs.extend(dir.ancestors().take(3).map(Path::to_path_buf));
+        }
+        for dir in dirs {
+            ignore_not_found(self.metrics.time(Op::Fsync, || sync_dir(&dir)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the generation token of `dir`.
+fn read_generation(dir: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(dir.join(GENERATION_NAME)) {
+        Ok(token) => Ok(Some(token.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the first `len` bytes of `file`. Does not move the position shared
+/// by users of a kept-open file.
+fn read_from_start(file: &fs::File, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    #[cfg(unix)]
+    std::os::unix::fs::FileExt::read_exact_at(file, &mut buf, 0)?;
+    #[cfg(not(unix))]
+    {
+        // Files are not kept open.
+        let mut file = file;
+        io::Seek::seek(&mut file, io::SeekFrom::Start(0))?;
+        io::Read::read_exact(&mut file, &mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Fail with `StorageFull` if `required` bytes are more than `available`.
+fn check_free_space(dir: &Path, required: u64, available: u64) -> io::Result<()> {
+    if required > available {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "not enough space to write {}: {} bytes needed, {} bytes available",
+                dir.display(),
+                required,
+                available
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Allocate `len` bytes for `file`.
+#[cfg(target_os = "linux")]
+fn allocate(file: &fs::File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // posix_fallocate returns the error instead of setting errno.
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &fs::File, len: u64) -> io::Result<()> {
+    FileExt::allocate(file, len)
+}
+
+/// Whether an error might go away if the operation is retried.
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        #[cfg(unix)]
+        _ if e.raw_os_error() == Some(libc::ESTALE) => true,
+        _ => false,
+    }
+}
+
+/// Whether `path` is on a network or FUSE filesystem. Unknown filesystems
+/// are treated as such.
+#[cfg(target_os = "linux")]
+fn is_network_fs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    const NETWORK_FS_TYPES: &[i64] = &[
+        0x6969,               // NFS
+        0x517b,               // SMB
+        0xff53_4d42_u32 as _, // CIFS
+        0xfe53_4d42_u32 as _, // SMB2
+        0x6573_5546,          // FUSE
+        0x0102_1997,          // 9P
+        0x00c3_6400,          // Ceph
+    ];
+    let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return true,
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return true;
+    }
+    NETWORK_FS_TYPES.contains(&(stat.f_type as i64))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_network_fs(_path: &Path) -> bool {
+    true
+}
+
+/// Header of WAL with a checksum. Older WAL is plain bincode.
+const WAL_MAGIC: &[u8] = b"x79d8wal";
+
+const WAL_CHECKSUM_SIZE: usize = 16;
+
+/// Serialize WAL: the magic, the length of the overlay, the overlay, and a
+/// checksum of the length and the overlay.
+fn encode_wal(overlay: &HashMap<usize, State>) -> Vec<u8> {
+    let payload = bincode::serialize(overlay).unwrap();
+    let mut data = WAL_MAGIC.to_vec();
+    data.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    data.extend_from_slice(&payload);
+    let checksum = wal_checksum(&data[WAL_MAGIC.len()..]);
+    data.extend_from_slice(&checksum);
+    data
+}
+
+/// Parse WAL. Return None if it is torn or corrupted.
+fn decode_wal(data: &[u8]) -> Option<HashMap<usize, State>> {
+    let rest = match data.strip_prefix(WAL_MAGIC) {
+        Some(rest) => rest,
+        None => return bincode::deserialize(data).ok(),
+    };
+    let body_len = rest.len().checked_sub(WAL_CHECKSUM_SIZE)?;
+    let (body, checksum) = rest.split_at(body_len);
+    if wal_checksum(body)[..] != checksum[..] {
+        return None;
+    }
+    let (len, payload) = body.split_at(body.len().min(8));
+    let len = u64::from_be_bytes(len.try_into().ok()?);
+    if len != payload.len() as u64 {
+        return None;
+    }
+    bincode::deserialize(payload).ok()
+}
+
+fn wal_checksum(data: &[u8]) -> [u8; WAL_CHECKSUM_SIZE] {
+    let hash = Blake2s::digest(data);
+    hash[..WAL_CHECKSUM_SIZE].try_into().unwrap()
+}
+
+/// Consume a step of `FsIntKv::flush_wal_steps`. Return false if there are
+/// no steps left.
+fn next_step(steps: &mut usize) -> bool {
+    match steps.checked_sub(1) {
+        Some(rest) => {
+            *steps = rest;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Fsync a directory so changes to its entries are durable. No-op on
+/// platforms that cannot open directories as files (Windows).
+pub(super) fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+fn ignore_not_found<T: Default>(result: io::Result<T>) -> io::Result<T> {
     match result {
         Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
         _ => result,
@@ -213,5 +2084,909 @@ fn ignore_not_found<T: Default>(result: io::Result<T>) -> io::Result<T> {
 fn test_fsint_kv() {
     let dir = tempfile::tempdir().unwrap();
     let path = dir.path();
-    super::super::test_int_kv(|_| FsIntKv::new(&path).unwrap(), 10);
+    super::super::test_int_kv(|_| FsIntKv::new(path).unwrap(), 10);
+}
+
+#[test]
+fn test_fsint_kv_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    // Entries 0 and 1 are packed. The others are files.
+    super::super::test_int_kv(|_| FsIntKv::new(path).unwrap().with_segments(600), 10);
+    assert!(path.join("segments").is_dir());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap().with_segments(100);
+    kv.segments.lock().max_size = 100;
+    for i in 0..50 {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    let count_segments = || fs::read_dir(path.join("segments")).unwrap().count();
+    assert_eq!(count_segments(), 5);
+    assert!(!path.join("blocks").exists());
+
+    // Live entries of mostly-dead segments are moved.
+    for i in (0..50).filter(|i| i % 10 != 0) {
+        kv.remove(i).unwrap();
+    }
+    kv.write(0, vec![100; 10].into()).unwrap();
+    kv.flush().unwrap();
+    kv.compact_step(usize::MAX).unwrap();
+    assert!(count_segments() < 5);
+    drop(kv);
+
+    let kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.keys().unwrap(), vec![0, 10, 20, 30, 40]);
+    assert_eq!(kv.read(0).unwrap(), vec![100; 10]);
+    assert_eq!(kv.read(30).unwrap(), vec![30; 10]);
+    drop(kv);
+
+    // Segments of uncommitted writes are removed on open.
+    let mut kv = FsIntKv::new(path).unwrap().with_segments(100);
+    kv.segments.lock().max_size = 100;
+    let before = count_segments();
+    for i in 100..120 {
+        kv.write(i, vec![1; 10].into()).unwrap();
+    }
+    drop(kv);
+    assert!(count_segments() > before);
+    let kv = FsIntKv::new(path).unwrap();
+    assert!(count_segments() <= before);
+    assert_eq!(kv.keys().unwrap(), vec![0, 10, 20, 30, 40]);
+}
+
+#[test]
+fn test_fsint_kv_flat_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    for index in [1, 2, 3] {
+        fs::write(path.join(index.to_string()), vec![index as u8]).unwrap();
+    }
+
+    // Entries in the flat layout are readable.
+    let mut kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.keys().unwrap(), vec![1, 2, 3]);
+    assert_eq!(kv.read(2).unwrap(), vec![2]);
+    assert!(kv.has(3).unwrap());
+
+    // Rewriting or removing replaces them.
+    kv.write(1, vec![10].into()).unwrap();
+    kv.remove(2).unwrap();
+    kv.write(258, vec![11].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(!path.join("1").exists());
+    assert!(!path.join("2").exists());
+    assert!(path.join("blocks/02/01/258").exists());
+    let kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.keys().unwrap(), vec![1, 3, 258]);
+    assert_eq!(kv.read(1).unwrap(), vec![10]);
+    assert!(!kv.has(2).unwrap());
+
+    // WAL written by older versions is replayed.
+    fs::write(path.join("4p"), vec![4]).unwrap();
+    let mut overlay = HashMap::new();
+    overlay.insert(4usize, State::Modified);
+    fs::write(path.join("wal"), bincode::serialize(&overlay).unwrap()).unwrap();
+    let mut kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.read(4).unwrap(), vec![4]);
+
+    assert_eq!(kv.migrate_layout().unwrap(), 2);
+    assert!(!path.join("3").exists());
+    let kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.keys().unwrap(), vec![1, 3, 4, 258]);
+    assert_eq!(kv.read(3).unwrap(), vec![3]);
+}
+
+#[test]
+fn test_fsint_kv_power_cut() {
+    // Steps: fsync files, write WAL, apply 3 entries, remove WAL.
+    // The threshold of 4 packs all entries but 300 into segments.
+    for &threshold in &[0, 4] {
+        for steps in 0..7 {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path();
+            let open = || FsIntKv::new(path).unwrap().with_segments(threshold);
+            let mut kv = open();
+            for index in [1, 2, 3] {
+                kv.write(index, vec![index as u8].into()).unwrap();
+            }
+            kv.flush().unwrap();
+
+            kv.write(1, vec![10].into()).unwrap();
+            kv.remove(2).unwrap();
+            kv.write(300, vec![30; 8].into()).unwrap();
+            kv.flush_wal_steps(steps).unwrap();
+            drop(kv);
+
+            let kv = open();
+            assert!(!path.join("wal").exists());
+            if steps < 2 {
+                // WAL was not written. Changes are lost.
+                assert_eq!(kv.keys().unwrap(), vec![1, 2, 3], "steps {}", steps);
+                assert_eq!(kv.read(1).unwrap(), vec![1]);
+            } else {
+                assert_eq!(kv.keys().unwrap(), vec![1, 3, 300], "steps {}", steps);
+                assert_eq!(kv.read(1).unwrap(), vec![10]);
+                assert_eq!(kv.read(300).unwrap(), vec![30; 8]);
+            }
+            assert_eq!(kv.read(3).unwrap(), vec![3]);
+        }
+    }
+}
+
+#[test]
+fn test_fsint_kv_checkpoint_interrupted() {
+    // The WAL is replayed again and again, stopped at each step, or failing
+    // at each file operation.
+    for stop in 0..6 {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let mut kv = FsIntKv::new(path).unwrap();
+        for index in [1, 2, 3] {
+            kv.write(index, vec![index as u8].into()).unwrap();
+        }
+        kv.flush().unwrap();
+        kv.write(1, vec![10].into()).unwrap();
+        kv.remove(2).unwrap();
+        kv.write(300, vec![30; 8].into()).unwrap();
+        kv.flush_wal_steps(2).unwrap();
+
+        let mut steps = stop;
+        kv.wal_checkpoint(&mut steps).unwrap();
+        let fail = io::Error::from(io::ErrorKind::PermissionDenied);
+        *kv.faults.lock() = std::iter::once(Some(fail))
+            .chain((0..stop).map(|_| None))
+            .collect();
+        let mut steps = usize::MAX;
+        let _ = kv.wal_checkpoint(&mut steps);
+        drop(kv);
+
+        let kv = FsIntKv::new(path).unwrap();
+        assert!(!path.join("wal").exists());
+        assert_eq!(kv.keys().unwrap(), vec![1, 3, 300], "stop {}", stop);
+        assert_eq!(kv.read(1).unwrap(), vec![10]);
+        assert_eq!(kv.read(300).unwrap(), vec![30; 8]);
+    }
+}
+
+#[test]
+fn test_fsint_kv_pending_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap();
+    kv.write(1, vec![1].into()).unwrap();
+    kv.write(2, vec![2].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(!path.join("pending").exists());
+
+    // Killed before flush.
+    kv.write(1, vec![10].into()).unwrap();
+    kv.write(300, vec![30].into()).unwrap();
+    drop(kv);
+    assert!(path.join("blocks/2c/01/300p").exists());
+
+    let kv = FsIntKv::new(path).unwrap();
+    assert!(!path.join("blocks/2c/01/300p").exists());
+    assert!(!path.join("blocks/01/00/1p").exists());
+    assert!(!path.join("pending").exists());
+    assert_eq!(kv.keys().unwrap(), vec![1, 2]);
+    assert_eq!(kv.read(1).unwrap(), vec![1]);
+}
+
+#[test]
+fn test_fsint_kv_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    // Instances in this process share the lock.
+    let kv1 = FsIntKv::new(path).unwrap();
+    let kv2 = FsIntKv::new(path).unwrap();
+    drop((kv1, kv2));
+
+    // Simulate another process.
+    let other = fs::File::open(path.join("lock")).unwrap();
+    FileExt::try_lock_exclusive(&other).unwrap();
+    fs::write(path.join("lock"), "12345").unwrap();
+    let err = FsIntKv::new(path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(err.to_string().contains("process 12345"), "{}", err);
+    let wait = Duration::from_millis(300);
+
+    // Readers coexist with each other. Writers wait for them.
+    FileExt::unlock(&other).unwrap();
+    FileExt::try_lock_shared(&other).unwrap();
+    let naming = FileNaming::default();
+    let mut kv =
+        FsIntKv::new_with_lock(path, path, LockMode::Shared, wait, naming.clone(), None).unwrap();
+    assert_eq!(
+        kv.write(1, vec![1].into()).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    drop(kv);
+    assert!(FsIntKv::new(path).is_err());
+
+    // Waiting.
+    let unlock = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        FileExt::unlock(&other).unwrap();
+    });
+    let wait = Duration::from_secs(10);
+    FsIntKv::new_with_lock(path, path, LockMode::Exclusive, wait, naming, None).unwrap();
+    unlock.join().unwrap();
+}
+
+#[test]
+fn test_fsint_kv_torn_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap();
+    kv.write(1, vec![1].into()).unwrap();
+    kv.flush().unwrap();
+    drop(kv);
+
+    let mut overlay = HashMap::new();
+    overlay.insert(1usize, State::Modified);
+    overlay.insert(2usize, State::Modified);
+    let wal = encode_wal(&overlay);
+    assert_eq!(decode_wal(&wal).unwrap().len(), 2);
+    for len in 0..wal.len() {
+        assert!(decode_wal(&wal[..len]).is_none(), "len {}", len);
+    }
+
+    // Torn WAL was never committed. Pending files are dropped.
+    fs::create_dir_all(path.join("blocks/02/00")).unwrap();
+    let write_pending = || {
+        fs::write(path.join("blocks/01/00/1p"), vec![10]).unwrap();
+        fs::write(path.join("blocks/02/00/2p"), vec![20]).unwrap();
+        fs::write(path.join("pending"), b"").unwrap();
+    };
+    write_pending();
+    fs::write(path.join("wal"), &wal[..wal.len() - 1]).unwrap();
+    let kv = FsIntKv::new(path).unwrap();
+    assert!(!path.join("wal").exists());
+    assert_eq!(kv.keys().unwrap(), vec![1]);
+    assert_eq!(kv.read(1).unwrap(), vec![1]);
+    drop(kv);
+
+    // Valid WAL, but one pending file was already renamed.
+    write_pending();
+    fs::rename(path.join("blocks/02/00/2p"), path.join("blocks/02/00/2")).unwrap();
+    fs::write(path.join("wal"), &wal).unwrap();
+    let kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.keys().unwrap(), vec![1, 2]);
+    assert_eq!(kv.read(1).unwrap(), vec![10]);
+    assert_eq!(kv.read(2).unwrap(), vec![20]);
+}
+
+#[test]
+fn test_fsint_kv_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let wait = Duration::from_secs(0);
+    let denied =
+        |r: io::Result<()>| assert_eq!(r.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+    // No lock file is created.
+    let mut kv = FsIntKv::open_read_only(path, wait).unwrap();
+    assert!(kv.keys().unwrap().is_empty());
+    denied(kv.write(1, vec![1].into()));
+    denied(kv.remove(1));
+    denied(kv.flush());
+    assert!(!kv.has_wal());
+    drop(kv);
+    assert_eq!(fs::read_dir(path).unwrap().count(), 0);
+
+    let mut writer = FsIntKv::new(path).unwrap();
+    writer.write(1, vec![1].into()).unwrap();
+    writer.flush().unwrap();
+
+    // Reading while writing.
+    let reader = FsIntKv::open_read_only(path, wait).unwrap();
+    writer.write(2, vec![2].into()).unwrap();
+    assert_eq!(reader.keys().unwrap(), vec![1]);
+    writer.write(1, vec![3].into()).unwrap();
+    assert_eq!(reader.read(1).unwrap(), vec![1]);
+    writer.flush_wal_steps(2).unwrap();
+
+    // WAL is not replayed.
+    drop(reader);
+    let reader = FsIntKv::open_read_only(path, wait).unwrap();
+    assert!(reader.has_wal());
+    assert_eq!(reader.read(1).unwrap(), vec![1]);
+    assert!(path.join("wal").exists());
+    drop(writer);
+    let writer = FsIntKv::new(path).unwrap();
+    assert_eq!(reader.read(1).unwrap(), vec![3]);
+    assert_eq!(reader.keys().unwrap(), vec![1, 2]);
+    drop((reader, writer));
+
+    // Another process writing.
+    let other = fs::File::open(path.join("lock")).unwrap();
+    FileExt::try_lock_exclusive(&other).unwrap();
+    let reader = FsIntKv::open_read_only(path, wait).unwrap();
+    assert_eq!(reader.read(2).unwrap(), vec![2]);
+}
+
+#[test]
+fn test_fsint_kv_read_strategy() {
+    for &strategy in &[
+        ReadStrategy::Mmap,
+        ReadStrategy::Buffered,
+        ReadStrategy::Auto,
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let open = || FsIntKv::new(path).unwrap().with_read_strategy(strategy);
+        super::super::test_int_kv(|_| open(), 10);
+
+        let mut kv = open();
+        let large = vec![7u8; AUTO_MMAP_MIN_SIZE as usize + 1];
+        kv.write(1, large.clone().into()).unwrap();
+        kv.write(2, vec![1, 2].into()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.read(1).unwrap(), large);
+        assert_eq!(ReadStrategy::from_str(&strategy.to_string()), Ok(strategy));
+
+        drop(kv);
+        let wait = Duration::from_secs(0);
+        let naming = FileNaming::default();
+        let kv = FsIntKv::new_with_lock(path, path, LockMode::Exclusive, wait, naming, Some(3))
+            .unwrap()
+            .with_read_strategy(strategy);
+        let err = kv.read(2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("truncated"), "{}", err);
+        assert_eq!(kv.read(1).unwrap(), large);
+    }
+}
+
+#[test]
+fn test_fsint_kv_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let delay = Duration::from_millis(1);
+    let mut kv = FsIntKv::new(path).unwrap().with_retry(3, delay);
+    let fail = |kv: &FsIntKv, errors: Vec<io::Error>| {
+        *kv.faults.lock() = errors.into_iter().map(Some).collect();
+    };
+    let interrupted = || io::Error::from(io::ErrorKind::Interrupted);
+
+    // Transient errors are retried.
+    fail(&kv, vec![interrupted(), interrupted()]);
+    kv.write(1, vec![1].into()).unwrap();
+    kv.write(2, vec![2].into()).unwrap();
+    fail(&kv, vec![interrupted(), interrupted()]);
+    kv.flush().unwrap();
+    fail(&kv, vec![interrupted()]);
+    assert_eq!(kv.read(1).unwrap(), vec![1]);
+    assert!(kv.faults.lock().is_empty());
+
+    // Up to `attempts` times.
+    fail(&kv, vec![interrupted(), interrupted(), interrupted()]);
+    assert_eq!(kv.read(1).unwrap_err().kind(), io::ErrorKind::Interrupted);
+
+    // Permanent errors are not retried.
+    fail(
+        &kv,
+        vec![interrupted(), io::ErrorKind::PermissionDenied.into()],
+    );
+    assert_eq!(
+        kv.read(1).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(kv.faults.lock().len(), 1);
+    #[cfg(unix)]
+    assert!(is_transient(&io::Error::from_raw_os_error(libc::ESTALE)));
+    assert!(!is_transient(&io::ErrorKind::NotFound.into()));
+}
+
+#[test]
+fn test_fsint_kv_preallocation() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let open = || FsIntKv::new(path).unwrap().with_preallocation(true);
+    super::super::test_int_kv(|_| open(), 10);
+
+    let mut kv = open();
+    kv.write(1, vec![1; 5000].into()).unwrap();
+    kv.write(2, Vec::new().into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(kv.read(1).unwrap(), vec![1; 5000]);
+    assert!(kv.read(2).unwrap().is_empty());
+
+    check_free_space(path, 10, 10).unwrap();
+    let err = check_free_space(path, 11, 10).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    assert!(err.to_string().contains("11 bytes needed"), "{}", err);
+}
+
+#[test]
+fn test_fsint_kv_rollback() {
+    let dir = tempfile::tempdir().unwrap();
+    super::super::test_int_kv(
+        |_| {
+            FsIntKv::new(dir.path())
+                .unwrap()
+                .with_previous_generation(true)
+        },
+        10,
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap().with_previous_generation(true);
+    assert_eq!(kv.rollback().unwrap_err().kind(), io::ErrorKind::NotFound);
+    kv.write(1, vec![1].into()).unwrap();
+    kv.write(2, vec![2].into()).unwrap();
+    kv.flush().unwrap();
+    kv.write(1, vec![10].into()).unwrap();
+    kv.write(3, vec![3].into()).unwrap();
+    kv.flush().unwrap();
+    kv.write(1, vec![100].into()).unwrap();
+    kv.remove(2).unwrap();
+    kv.write(4, vec![4].into()).unwrap();
+    kv.flush().unwrap();
+    // One generation is kept.
+    assert_eq!(kv.previous_generation_usage().unwrap(), (2, 2));
+    assert!(!path.join("blocks/03/00/3.old").exists());
+
+    // Plans of interrupted rollbacks are applied on open. Corrupted ones fail.
+    fs::write(path.join("rollback"), b"x").unwrap();
+    assert_eq!(
+        FsIntKv::new(path).unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+    fs::remove_file(path.join("rollback")).unwrap();
+
+    assert_eq!(kv.rollback().unwrap(), 2);
+    assert_eq!(kv.keys().unwrap(), vec![1, 2, 3]);
+    assert_eq!(kv.read(1).unwrap(), vec![10]);
+    assert_eq!(kv.read(2).unwrap(), vec![2]);
+    assert_eq!(kv.previous_generation_usage().unwrap(), (0, 0));
+    assert_eq!(kv.rollback().unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    // Old files are dropped once disabled.
+    kv.write(1, vec![1].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(path.join("blocks/01/00/1.old").exists());
+    let mut kv = FsIntKv::new(path).unwrap();
+    kv.write(5, vec![5].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(!path.join("blocks/01/00/1.old").exists());
+    assert!(!path.join("wal.old").exists());
+}
+
+#[test]
+fn test_fsint_kv_disk_full() {
+    let full = || Some(io::Error::from(io::ErrorKind::StorageFull));
+    let old = vec![(1, vec![1]), (2, vec![2])];
+    let new = vec![(1, vec![10]), (3, vec![3])];
+    let state = |kv: &FsIntKv| -> Vec<(usize, Vec<u8>)> {
+        let keys = kv.keys().unwrap();
+        keys.into_iter()
+            .map(|i| (i, kv.read(i).unwrap().to_vec()))
+            .collect()
+    };
+    for &(retry, threshold) in &[(false, 0), (true, 0), (false, 4), (true, 4)] {
+        for n in 0.. {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path();
+            let open = || FsIntKv::new(path).unwrap().with_segments(threshold);
+            let mut kv = open();
+            for (index, data) in &old {
+                kv.write(*index, data.clone().into()).unwrap();
+            }
+            kv.flush().unwrap();
+
+            // Fail the n-th file operation.
+            *kv.faults.lock() = std::iter::once(full())
+                .chain((0..n).map(|_| None))
+                .collect();
+            let result = (|| -> io::Result<()> {
+                kv.write(1, vec![10].into())?;
+                kv.remove(2)?;
+                kv.write(3, vec![3].into())?;
+                kv.flush()
+            })();
+            if result.is_ok() {
+                assert!(n > 5);
+                break;
+            }
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::StorageFull);
+            kv.faults.lock().clear();
+
+            if retry {
+                // Retry after freeing space, in the same process.
+                kv.write(1, vec![10].into()).unwrap();
+                let _ = kv.remove(2);
+                kv.write(3, vec![3].into()).unwrap();
+                kv.flush().unwrap();
+                assert_eq!(state(&kv), new);
+            }
+
+            // Without retrying, the changes are either all or not committed.
+            drop(kv);
+            let kv = open();
+            assert!(!path.join("wal").exists());
+            assert!(!path.join("pending").exists());
+            let current = state(&kv);
+            match retry {
+                true => assert_eq!(current, new),
+                false => assert!(current == old || current == new, "{}: {:?}", n, current),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fsint_kv_naming() {
+    let naming = FileNaming {
+        prefix: "x79d8-".to_string(),
+        width: 8,
+        hex: true,
+    };
+    assert_eq!(naming.name(0x1a), "x79d8-0000001a");
+    assert_eq!(naming.parse("x79d8-0000001a"), Some(0x1a));
+    for name in &[
+        "x79d8-1a",
+        "x79d8-0000001A",
+        "0000001a",
+        "x79d8-0000001ap",
+        "x79d8-",
+    ] {
+        assert_eq!(naming.parse(name), None, "{}", name);
+    }
+    assert_eq!(FileNaming::default().parse("012"), None);
+    assert!(FileNaming {
+        prefix: "../".to_string(),
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let wait = Duration::from_secs(0);
+    let open = |naming: &FileNaming| {
+        FsIntKv::new_with_lock(path, path, LockMode::Exclusive, wait, naming.clone(), None).unwrap()
+    };
+    super::super::test_int_kv(|_| open(&naming), 10);
+
+    // Files of other tools are ignored.
+    fs::write(path.join("blocks/01/00/1"), b"x").unwrap();
+    let mut kv = open(&naming);
+    kv.write(0x10001, vec![1].into()).unwrap();
+    let keys = kv.keys().unwrap();
+    assert!(path.join("blocks/01/00/x79d8-00010001p").exists());
+    kv.flush().unwrap();
+    assert!(path.join("blocks/01/00/x79d8-00010001").exists());
+    drop(kv);
+
+    // Pending files are removed using the naming.
+    let mut kv = open(&naming);
+    kv.write(2, vec![2].into()).unwrap();
+    std::mem::forget(kv);
+    let kv = open(&naming);
+    assert!(!path.join("blocks/02/00/x79d8-00000002p").exists());
+    assert!(path.join("blocks/01/00/1").exists());
+
+    // Migrate back to the default.
+    fs::remove_file(path.join("blocks/01/00/1")).unwrap();
+    let mut kv = kv;
+    let mut last = None;
+    let renamed = kv
+        .migrate_naming(FileNaming::default(), |done, total| {
+            last = Some((done, total))
+        })
+        .unwrap();
+    assert_eq!(renamed, keys.len());
+    assert_eq!(last, Some((keys.len(), keys.len())));
+    assert_eq!(kv.keys().unwrap(), keys);
+    assert_eq!(kv.read(0x10001).unwrap(), vec![1]);
+    assert!(path.join("blocks/01/00/65537").exists());
+}
+
+#[test]
+fn test_fsint_kv_open_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    // Few open files evict often. Renamed and removed files must not be
+    // read through stale handles.
+    super::super::test_int_kv(|_| FsIntKv::new(path).unwrap().with_open_files(3), 10);
+
+    let cached = cfg!(unix);
+    for &limit in &[0, 4] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let mut kv = FsIntKv::new(path).unwrap().with_open_files(limit);
+        for index in [1, 2] {
+            kv.write(index, vec![index as u8].into()).unwrap();
+        }
+        kv.flush().unwrap();
+
+        // Files written and renamed by flush stay open.
+        kv.handles.lock().opened = 0;
+        for _ in 0..5 {
+            assert_eq!(kv.read(1).unwrap(), vec![1]);
+            assert_eq!(kv.read(2).unwrap(), vec![2]);
+        }
+        let opened = kv.handles.lock().opened;
+        match limit > 0 && cached {
+            true => assert_eq!(opened, 0),
+            false => assert_eq!(opened, 10),
+        }
+
+        // Writing and fsyncing a file opens it once.
+        kv.handles.lock().opened = 0;
+        kv.write(1, vec![10].into()).unwrap();
+        kv.remove(2).unwrap();
+        kv.flush().unwrap();
+        let opened = kv.handles.lock().opened;
+        match limit > 0 && cached {
+            true => assert_eq!(opened, 1),
+            false => assert_eq!(opened, 2),
+        }
+        assert_eq!(kv.read(1).unwrap(), vec![10]);
+        assert_eq!(kv.read(2).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
+
+#[test]
+fn test_fsint_kv_direct_io() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    super::super::test_int_kv(|_| FsIntKv::new(path).unwrap().with_direct_io(true), 10);
+
+    // Lengths are not aligned. The padding is dropped.
+    let mut kv = FsIntKv::new(path).unwrap().with_direct_io(true);
+    kv.write(1, vec![1; 5000].into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(
+        fs::metadata(path.join("blocks/01/00/1")).unwrap().len(),
+        5000
+    );
+    drop(kv);
+    let kv = FsIntKv::new(path).unwrap().with_direct_io(true);
+    assert_eq!(kv.read(1).unwrap(), vec![1; 5000]);
+}
+
+#[test]
+fn test_fsint_kv_external_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap();
+    kv.write(1, vec![1].into()).unwrap();
+    kv.flush().unwrap();
+    kv.write(1, vec![2].into()).unwrap();
+    kv.flush().unwrap();
+
+    // Another machine flushed.
+    fs::write(path.join("generation"), "other").unwrap();
+    kv.write(1, vec![3].into()).unwrap();
+    let e = kv.flush().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("modified externally"), "{}", e);
+    drop(kv);
+
+    // Reopening picks up the change.
+    let mut kv = FsIntKv::new(path).unwrap();
+    assert_eq!(kv.read(1).unwrap(), vec![2]);
+    kv.write(1, vec![3].into()).unwrap();
+    kv.flush().unwrap();
+
+    // The check can be disabled for shared directories.
+    fs::remove_file(path.join("generation")).unwrap();
+    let mut kv = kv.with_external_change_check(false);
+    kv.write(1, vec![4].into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(kv.read(1).unwrap(), vec![4]);
+}
+
+#[test]
+fn test_fsint_kv_check_space() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap();
+    kv.check_space(0).unwrap();
+    let available = kv.stats()["fs.available_bytes"];
+    assert!(available > 0);
+    assert!(kv.stats()["fs.total_bytes"] >= available);
+    let full = |r: io::Result<()>| assert_eq!(r.unwrap_err().kind(), io::ErrorKind::StorageFull);
+    full(kv.check_space(u64::MAX / 2));
+
+    // Pending changes and the reserve count.
+    kv = kv.with_reserved_space(available / 2, 0);
+    kv.check_space(0).unwrap();
+    full(kv.check_space(available));
+    kv.write(1, vec![1; 1000].into()).unwrap();
+    kv.unallocated_bytes += available;
+    full(kv.check_space(0));
+    kv.flush().unwrap_err();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_fsint_kv_metrics() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = FsIntKv::new(path).unwrap();
+    kv.write(1, vec![1; 10].into()).unwrap();
+    kv.write(2, vec![2; 20].into()).unwrap();
+    kv.flush().unwrap();
+    kv.read(1).unwrap();
+    kv.remove(2).unwrap();
+    kv.flush().unwrap();
+
+    let stats = kv.stats();
+    // Failed attempts, such as writing before the shard directory exists,
+    // are counted too.
+    assert!(stats["fs.write_count"] >= 2);
+    assert_eq!(stats["fs.bytes_written"], 30);
+    assert_eq!(stats["fs.read_count"], 1);
+    assert_eq!(stats["fs.bytes_read"], 10);
+    assert_eq!(stats["fs.commit_count"], 2);
+    assert!(stats["fs.fsync_count"] >= 2);
+    let fsync_buckets: u64 = ["1ms", "10ms", "100ms", "1s", "slower"]
+        .iter()
+        .map(|b| stats[&format!("fs.fsync_latency_{}", b)])
+        .sum();
+    assert_eq!(fsync_buckets, stats["fs.fsync_count"]);
+}
+
+#[test]
+fn test_fsint_kv_snapshot() {
+    for threshold in [0, 4] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let snap_dir = tempfile::tempdir().unwrap();
+        let snap_path = snap_dir.path().join("snap");
+        let mut kv = FsIntKv::new(path).unwrap().with_segments(threshold);
+        kv.write(1, b"ab".to_vec().into()).unwrap();
+        kv.write(2, b"cd".to_vec().into()).unwrap();
+        kv.write(3, vec![3; 10].into()).unwrap();
+        kv.flush().unwrap();
+        kv.write(5, b"ef".to_vec().into()).unwrap();
+
+        let files = kv.snapshot(&snap_path).unwrap();
+        assert!(files.iter().any(|&(_, linked)| linked));
+        assert!(kv.snapshot(&snap_path).is_err());
+
+        // Changes to the original do not affect the snapshot.
+        kv.write(1, b"gh".to_vec().into()).unwrap();
+        kv.write(3, vec![4; 10].into()).unwrap();
+        kv.remove(2).unwrap();
+        kv.write(4, b"ij".to_vec().into()).unwrap();
+        kv.flush().unwrap();
+        kv.compact_step(usize::MAX).unwrap();
+        assert_eq!(kv.read(1).unwrap().as_ref(), b"gh");
+
+        let snap = FsIntKv::new(&snap_path).unwrap();
+        assert_eq!(snap.read(1).unwrap().as_ref(), b"ab");
+        assert_eq!(snap.read(2).unwrap().as_ref(), b"cd");
+        assert_eq!(snap.read(3).unwrap().as_ref(), &[3; 10]);
+        assert_eq!(snap.read(5).unwrap().as_ref(), b"ef");
+        assert!(!snap.has(4).unwrap());
+    }
+}
+
+#[test]
+fn test_fsint_kv_move_into() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let data = path.join("data");
+    let wait = Duration::from_secs(0);
+    let mut kv = FsIntKv::new(path).unwrap().with_segments(4);
+    kv.write(1, b"ab".to_vec().into()).unwrap();
+    kv.write(2, vec![2; 10].into()).unwrap();
+    kv.flush().unwrap();
+    kv.write(3, b"cd".to_vec().into()).unwrap();
+    // Flat layout of older directories.
+    fs::write(path.join("4"), b"ef").unwrap();
+    assert!(kv.move_into(&data).unwrap() >= 3);
+    let mut names: Vec<_> = fs::read_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["data", LOCK_NAME]);
+
+    // Resume after an interruption.
+    fs::rename(data.join(MANIFEST_NAME), path.join(MANIFEST_NAME)).unwrap();
+    assert_eq!(FsIntKv::new(path).unwrap().move_into(&data).unwrap(), 1);
+
+    let open = || {
+        FsIntKv::new_with_lock(
+            &data,
+            path,
+            LockMode::Exclusive,
+            wait,
+            FileNaming::default(),
+            None,
+        )
+    };
+    let kv = open().unwrap();
+    assert!(!kv.open_report().has_problems());
+    assert_eq!(kv.read(1).unwrap().as_ref(), b"ab");
+    assert_eq!(kv.read(2).unwrap().as_ref(), &[2; 10]);
+    assert_eq!(kv.read(3).unwrap().as_ref(), b"cd");
+    assert_eq!(kv.read(4).unwrap().as_ref(), b"ef");
+    assert!(!data.join(LOCK_NAME).exists());
+}
+
+#[test]
+fn test_fsint_kv_open_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let wait = Duration::from_secs(0);
+    let open =
+        |mode| FsIntKv::new_with_lock(path, path, mode, wait, FileNaming::default(), Some(4));
+    let mut kv = open(LockMode::Exclusive).unwrap();
+    assert_eq!(kv.open_report().entry_files, 0);
+    assert!(!kv.open_report().has_problems());
+    kv.write(1, vec![1; 4].into()).unwrap();
+    kv.write(2, vec![2; 3].into()).unwrap();
+    kv.flush().unwrap();
+
+    // Interrupt a flush after writing WAL. Leave a file not in WAL.
+    kv.write(3, vec![3; 4].into()).unwrap();
+    kv.write(4, vec![4; 4].into()).unwrap();
+    kv.flush_wal_steps(2).unwrap();
+    drop(kv);
+    fs::create_dir_all(path.join("blocks/05/00")).unwrap();
+    fs::write(path.join("blocks/05/00/5p"), b"").unwrap();
+    fs::write(path.join("pending"), b"").unwrap();
+    fs::write(path.join("x79d8cfg.json"), b"{}").unwrap();
+    fs::write(path.join("stray"), b"").unwrap();
+
+    let report = open(LockMode::Shared).unwrap().open_report();
+    assert_eq!(report.wal_entries, Some(2));
+    assert!(!report.wal_replayed);
+    assert_eq!(report.pending_files, 3);
+    assert_eq!(report.entry_files, 2);
+
+    let report = open(LockMode::Exclusive).unwrap().open_report();
+    assert_eq!(report.wal_entries, Some(2));
+    assert!(report.wal_replayed);
+    assert_eq!(report.pending_files, 1);
+    assert_eq!(report.entry_files, 4);
+    let mismatch = FsIntKv::new(path).unwrap().get_path_for_index(2);
+    assert_eq!(report.size_mismatches, vec![(mismatch, 3)]);
+    assert_eq!(report.foreign_files, vec![path.join("stray")]);
+    assert!(report.has_problems());
+    assert!(report.total_bytes >= 15);
+}
+
+#[test]
+fn test_fsint_kv_durability() {
+    for durability in [Durability::Full, Durability::Data, Durability::None] {
+        assert_eq!(
+            Durability::from_str(&durability.to_string()),
+            Ok(durability)
+        );
+        for threshold in [0, 4] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path();
+            let open = || {
+                FsIntKv::new(path)
+                    .unwrap()
+                    .with_segments(threshold)
+                    .with_durability(durability)
+            };
+            let mut kv = open();
+            kv.write(1, vec![1].into()).unwrap();
+            kv.write(2, vec![2; 10].into()).unwrap();
+            kv.flush().unwrap();
+            kv.remove(1).unwrap();
+            kv.flush().unwrap();
+            if durability == Durability::None {
+                let fsyncs = kv.stats().get("fs.fsync_count").copied();
+                assert_eq!(fsyncs.unwrap_or(0), 0);
+            }
+            drop(kv);
+            let kv = open();
+            assert_eq!(kv.keys().unwrap(), vec![2]);
+            assert_eq!(kv.read(2).unwrap(), vec![2; 10]);
+        }
+    }
 }
@@ -0,0 +1,132 @@
+//! Direct I/O of entry files, bypassing the OS page cache.
+//!
+//! Linux uses `O_DIRECT`, which requires aligned buffers, offsets and
+//! lengths. Files are read and written through an aligned buffer rounded up
+//! to `ALIGN`, and truncated to their real length after writing. macOS uses
+//! `F_NOCACHE`, and Windows `FILE_FLAG_NO_BUFFERING`. Other platforms use
+//! the page cache.
+
+use std::alloc::{self, Layout};
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// Alignment of buffers, offsets and lengths. Covers the logical block
+/// size of common disks.
+const ALIGN: usize = 4096;
+
+/// Zeroed buffer aligned to `ALIGN`, with a length rounded up to it.
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let size = len.max(1).next_multiple_of(ALIGN);
+        let layout = Layout::from_size_align(size, ALIGN).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Open a file bypassing the page cache. Fails with `InvalidInput` if the
+/// filesystem does not support it.
+pub fn open(options: &mut fs::OpenOptions, path: &std::path::Path) -> io::Result<fs::File> {
+    #[cfg(target_os = "linux")]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(options, libc::O_DIRECT);
+    #[cfg(windows)]
+    std::os::windows::fs::OpenOptionsExt::custom_flags(options, FILE_FLAG_NO_BUFFERING);
+    let file = options.open(path)?;
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+#[cfg(windows)]
+const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+
+/// Read `len` bytes from the start of `file`.
+pub fn read(file: &fs::File, len: u64) -> io::Result<Vec<u8>> {
+    let len = len as usize;
+    let mut buf = AlignedBuf::new(len);
+    let mut done = 0;
+    while done < len {
+        // Offsets stay aligned, as only the last read can be short.
+        match read_at(file, &mut buf[done..], done as u64) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(buf[..len].to_vec())
+}
+
+/// Replace the content of `file`, which was truncated, with `data`.
+pub fn write(file: &fs::File, data: &[u8]) -> io::Result<()> {
+    let mut buf = AlignedBuf::new(data.len());
+    buf[..data.len()].copy_from_slice(data);
+    let mut done = 0;
+    while done < buf.len() {
+        match write_at(file, &buf[done..], done as u64) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    // Drop the padding.
+    if buf.len() != data.len() {
+        file.set_len(data.len() as u64)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &fs::File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &fs::File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
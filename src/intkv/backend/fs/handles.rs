@@ -0,0 +1,86 @@
+//! Open files of `FsIntKv`, kept to save open and close round trips on
+//! high-latency filesystems.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Least recently used open files, keyed by path.
+#[derive(Debug, Default)]
+pub struct Handles {
+    /// Maximum number of open files. 0: disabled.
+    limit: usize,
+
+    files: HashMap<PathBuf, (Arc<fs::File>, u64)>,
+
+    /// Bumped by each use, to find the least recently used file.
+    tick: u64,
+
+    /// Number of files passed to `insert`.
+    #[cfg(test)]
+    pub opened: usize,
+}
+
+impl Handles {
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.files.len() > limit {
+            self.evict();
+        }
+    }
+
+    pub fn get(&mut self, path: &Path) -> Option<Arc<fs::File>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.files.get_mut(path).map(|(file, used)| {
+            *used = tick;
+            file.clone()
+        })
+    }
+
+    /// Keep `file`, opened from `path`, evicting the least recently used
+    /// file if needed.
+    pub fn insert(&mut self, path: &Path, file: Arc<fs::File>) {
+        #[cfg(test)]
+        {
+            self.opened += 1;
+        }
+        if self.limit == 0 {
+            return;
+        }
+        if !self.files.contains_key(path) && self.files.len() >= self.limit {
+            self.evict();
+        }
+        self.tick += 1;
+        self.files.insert(path.to_path_buf(), (file, self.tick));
+    }
+
+    /// Forget the file of `path`, which was removed or replaced.
+    pub fn remove(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Move the file of `from`, which was renamed to `to`.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        self.files.remove(to);
+        if let Some(entry) = self.files.remove(from) {
+            self.files.insert(to.to_path_buf(), entry);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .files
+            .iter()
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(path, _)| path.clone());
+        if let Some(path) = oldest {
+            self.files.remove(&path);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Counts and latencies of file operations of `FsIntKv`. Without the
+//! `metrics` feature, nothing is recorded.
+
+use super::super::super::Stats;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Remove,
+    /// Fsync of a file or a directory.
+    Fsync,
+    /// Writing and applying WAL, as a whole.
+    Commit,
+}
+
+impl Op {
+    const ALL: [Op; 5] = [Op::Read, Op::Write, Op::Remove, Op::Fsync, Op::Commit];
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Remove => "remove",
+            Op::Fsync => "fsync",
+            Op::Commit => "commit",
+        }
+    }
+}
+
+/// Upper bounds of latency buckets in microseconds, and their names. The
+/// last bucket has no bound.
+#[cfg(feature = "metrics")]
+const BUCKETS: [(u64, &str); 5] = [
+    (1_000, "1ms"),
+    (10_000, "10ms"),
+    (100_000, "100ms"),
+    (1_000_000, "1s"),
+    (u64::MAX, "slower"),
+];
+
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct OpMetrics {
+    count: AtomicU64,
+    micros: AtomicU64,
+    buckets: [AtomicU64; BUCKETS.len()],
+}
+
+/// Totals of an operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Total {
+    pub count: u64,
+    pub micros: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    #[cfg(feature = "metrics")]
+    ops: [OpMetrics; Op::ALL.len()],
+    #[cfg(feature = "metrics")]
+    bytes_read: AtomicU64,
+    #[cfg(feature = "metrics")]
+    bytes_written: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    /// Run `f` as `op`.
+    pub fn time<T>(&self, op: Op, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(op, start.elapsed());
+        result
+    }
+
+    pub fn record(&self, op: Op, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let metrics = &self.ops[op as usize];
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+        metrics.micros.fetch_add(micros, Ordering::Relaxed);
+        let bucket = BUCKETS.iter().position(|&(bound, _)| micros < bound);
+        metrics.buckets[bucket.unwrap_or(BUCKETS.len() - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn total(&self, op: Op) -> Total {
+        let metrics = &self.ops[op as usize];
+        Total {
+            count: metrics.count.load(Ordering::Relaxed),
+            micros: metrics.micros.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn stats(&self, stats: &mut Stats) {
+        for op in Op::ALL {
+            let metrics = &self.ops[op as usize];
+            let total = self.total(op);
+            stats.insert(format!("fs.{}_count", op.name()), total.count);
+            stats.insert(format!("fs.{}_micros", op.name()), total.micros);
+            for (i, (_, name)) in BUCKETS.iter().enumerate() {
+                let count = metrics.buckets[i].load(Ordering::Relaxed);
+                stats.insert(format!("fs.{}_latency_{}", op.name(), name), count);
+            }
+        }
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        stats.insert("fs.bytes_read".into(), bytes_read);
+        let bytes_written = self.bytes_written.load(Ordering::Relaxed);
+        stats.insert("fs.bytes_written".into(), bytes_written);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+impl Metrics {
+    #[inline]
+    pub fn time<T>(&self, _op: Op, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    #[inline]
+    pub fn record(&self, _op: Op, _elapsed: Duration) {}
+
+    #[inline]
+    pub fn add_read(&self, _bytes: u64) {}
+
+    #[inline]
+    pub fn add_written(&self, _bytes: u64) {}
+
+    pub fn total(&self, _op: Op) -> Total {
+        Total::default()
+    }
+
+    pub fn stats(&self, _stats: &mut Stats) {}
+}
+
+/// Describe what the operations took between two sets of totals, such as
+/// "3 fsync 1.2s, 2 write 0.1s".
+pub fn summary(before: &[Total], after: &[Total]) -> String {
+    let parts: Vec<String> = Op::ALL
+        .iter()
+        .zip(before.iter().zip(after))
+        .filter(|(_, (b, a))| a.count > b.count)
+        .map(|(op, (b, a))| {
+            format!(
+                "{} {} {:.1}s",
+                a.count - b.count,
+                op.name(),
+                (a.micros - b.micros) as f64 / 1e6
+            )
+        })
+        .collect();
+    parts.join(", ")
+}
+
+impl Metrics {
+    /// Totals of all operations, for `summary`.
+    pub fn totals(&self) -> Vec<Total> {
+        Op::ALL.iter().map(|&op| self.total(op)).collect()
+    }
+}
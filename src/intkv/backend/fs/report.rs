@@ -0,0 +1,172 @@
+//! What `FsIntKv` found when opening a directory.
+
+use super::{
+    decode_wal, is_shard_name, FsIntKv, BLOCKS_DIR, GENERATION_NAME, LOCK_NAME, MANIFEST_NAME,
+    MARKER_NAME, OLD_WAL_NAME, ROLLBACK_NAME, SEGMENTS_DIR, WAL_NAME,
+};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files found by `FsIntKv` when opening a directory. Names starting with
+/// "x79d8" are left to the caller and not reported as foreign.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Entries of WAL left by an interrupted flush. None if there was none.
+    pub wal_entries: Option<usize>,
+
+    /// WAL left by an interrupted flush was torn, and discarded.
+    pub torn_wal: bool,
+
+    /// WAL was replayed. Read-only instances leave it alone.
+    pub wal_replayed: bool,
+
+    /// Uncommitted files of an interrupted write. Removed unless read-only.
+    pub pending_files: usize,
+
+    /// Committed entry files.
+    pub entry_files: usize,
+
+    /// Non-empty entry files of another size than expected.
+    pub size_mismatches: Vec<(PathBuf, u64)>,
+
+    /// Files and directories that do not belong to the directory.
+    pub foreign_files: Vec<PathBuf>,
+
+    /// Size of all files.
+    pub total_bytes: u64,
+}
+
+impl OpenReport {
+    /// Whether anything is worth a warning.
+    pub fn has_problems(&self) -> bool {
+        self.torn_wal || !self.size_mismatches.is_empty() || !self.foreign_files.is_empty()
+    }
+}
+
+/// Number of entries of the WAL at `path`, None if it is torn. Fails with
+/// `NotFound` if there is no WAL.
+pub fn count_wal(path: &Path) -> io::Result<Option<usize>> {
+    Ok(decode_wal(&fs::read(path)?).map(|overlay| overlay.len()))
+}
+
+impl FsIntKv {
+    /// Count files of the directory into `report`.
+    pub(super) fn scan_report(&self, report: &mut OpenReport) -> io::Result<()> {
+        for entry in list_dir(&self.dir)? {
+            report.total_bytes += entry.len;
+            let known = match (entry.is_dir, entry.name.as_deref()) {
+                (true, Some(BLOCKS_DIR)) => {
+                    self.scan_blocks(&entry.path, 1, report)?;
+                    true
+                }
+                (true, Some(SEGMENTS_DIR)) => {
+                    for segment in list_dir(&entry.path)? {
+                        report.total_bytes += segment.len;
+                        let name = segment.name.as_deref();
+                        let is_segment = name.is_some_and(|n| n.parse::<u64>().is_ok());
+                        if segment.is_dir || !is_segment {
+                            report.foreign_files.push(segment.path);
+                        }
+                    }
+                    true
+                }
+                (true, _) | (false, None) => false,
+                (false, Some(name)) => {
+                    [
+                        LOCK_NAME,
+                        WAL_NAME,
+                        OLD_WAL_NAME,
+                        ROLLBACK_NAME,
+                        GENERATION_NAME,
+                        MARKER_NAME,
+                        MANIFEST_NAME,
+                    ]
+                    .contains(&name)
+                        || name.starts_with("x79d8")
+                        // Flat layout of older directories.
+                        || self.count_entry_file(&entry.path, name, entry.len, report)
+                }
+            };
+            if !known {
+                report.foreign_files.push(entry.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Count files below `BLOCKS_DIR`. `depth` is the level of `dir`.
+    fn scan_blocks(&self, dir: &Path, depth: usize, report: &mut OpenReport) -> io::Result<()> {
+        for entry in list_dir(dir)? {
+            report.total_bytes += entry.len;
+            let known = match (entry.is_dir, entry.name.as_deref()) {
+                (true, Some(name)) if depth < 3 && is_shard_name(name) => {
+                    self.scan_blocks(&entry.path, depth + 1, report)?;
+                    true
+                }
+                (false, Some(name)) if depth == 3 => {
+                    self.count_entry_file(&entry.path, name, entry.len, report)
+                }
+                _ => false,
+            };
+            if !known {
+                report.foreign_files.push(entry.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Count an entry, pending or old file. Return false if `name` is none
+    /// of them.
+    fn count_entry_file(&self, path: &Path, name: &str, len: u64, report: &mut OpenReport) -> bool {
+        let parse = |suffix: &str| {
+            name.strip_suffix(suffix)
+                .and_then(|s| self.naming.parse(s))
+                .is_some()
+        };
+        if parse("") {
+            report.entry_files += 1;
+            if self.min_file_size > 0 && len > 0 && len != self.min_file_size {
+                report.size_mismatches.push((path.to_path_buf(), len));
+            }
+            true
+        } else if parse("p") {
+            report.pending_files += 1;
+            true
+        } else {
+            // Kept by `with_previous_generation`.
+            parse(".old")
+        }
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    /// None if not UTF-8.
+    name: Option<String>,
+    is_dir: bool,
+    /// 0 for directories.
+    len: u64,
+}
+
+/// Files in `dir`. Files removed meanwhile, ex. by a writer, are skipped.
+fn list_dir(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let name = entry.file_name().into_string().ok();
+        let len = if metadata.is_dir() { 0 } else { metadata.len() };
+        result.push(Entry {
+            path: entry.path(),
+            name,
+            is_dir: metadata.is_dir(),
+            len,
+        });
+    }
+    Ok(result)
+}
@@ -0,0 +1,316 @@
+//! Append-only segment files packing small entries of `FsIntKv`.
+//!
+//! Entries are appended to `segments/<id>`. Committed locations are
+//! recorded in the manifest, which is replaced atomically when a flush
+//! applies its WAL. Replaced and removed entries leave dead bytes that
+//! compaction reclaims by rewriting the live entries of mostly-dead
+//! segments.
+
+use super::{decode_wal, encode_wal, Durability, State};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+pub const SEGMENTS_DIR: &str = "segments";
+
+pub const MANIFEST_NAME: &str = "manifest";
+
+/// Default `Segments::max_size`.
+const SEGMENT_SIZE: u64 = 8 << 20;
+
+/// Compact segments with less than this fraction of live bytes.
+const MIN_LIVE_RATIO: f64 = 0.5;
+
+/// Where a packed entry is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    pub segment: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Segment files of a directory, and the committed locations of entries.
+#[derive(Debug)]
+pub struct Segments {
+    root: PathBuf,
+
+    /// Start a new segment once the active one reaches this size.
+    pub max_size: u64,
+
+    /// Which fsyncs to issue.
+    pub durability: Durability,
+
+    /// Committed locations of packed entries.
+    manifest: HashMap<usize, Location>,
+
+    /// The manifest changed since it was saved.
+    manifest_changed: bool,
+
+    /// Sizes of segment files.
+    sizes: BTreeMap<u64, u64>,
+
+    /// The segment to append to.
+    active: Option<(u64, fs::File)>,
+
+    /// Segments appended to since the last `sync`.
+    unsynced: BTreeSet<u64>,
+
+    /// A segment file was created since the last `sync`.
+    created: bool,
+}
+
+impl Segments {
+    /// Read the manifest and the sizes of segment files under `root`.
+    pub fn load(root: &Path) -> io::Result<Self> {
+        let manifest_path = root.join(MANIFEST_NAME);
+        let manifest = match fs::read(&manifest_path) {
+            Ok(data) => decode_wal(&data)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is corrupted", manifest_path.display()),
+                    )
+                })?
+                .into_iter()
+                .filter_map(|(index, state)| match state {
+                    State::Packed(location) => Some((index, location)),
+                    _ => None,
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut sizes = BTreeMap::new();
+        match fs::read_dir(root.join(SEGMENTS_DIR)) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    if let Some(id) = entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                        sizes.insert(id, entry.metadata()?.len());
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            max_size: SEGMENT_SIZE,
+            durability: Durability::Full,
+            manifest,
+            manifest_changed: false,
+            sizes,
+            active: None,
+            unsynced: BTreeSet::new(),
+            created: false,
+        })
+    }
+
+    /// Whether segments or a manifest exist.
+    pub fn in_use(&self) -> bool {
+        !self.manifest.is_empty() || !self.sizes.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Location> {
+        self.manifest.get(&index).copied()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = usize> + '_ {
+        self.manifest.keys().copied()
+    }
+
+    /// Ids of segment files, in order. Only the last one is appended to.
+    pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sizes.keys().copied()
+    }
+
+    /// Record a committed location. `None` drops the entry.
+    pub fn set(&mut self, index: usize, location: Option<Location>) {
+        let changed = match location {
+            Some(location) => self.manifest.insert(index, location) != Some(location),
+            None => self.manifest.remove(&index).is_some(),
+        };
+        self.manifest_changed |= changed;
+    }
+
+    /// Append `data` to the active segment.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<Location> {
+        let result = self.append_inner(data);
+        if result.is_err() {
+            // The file length tells where to append next.
+            self.active = None;
+        }
+        result
+    }
+
+    fn append_inner(&mut self, data: &[u8]) -> io::Result<Location> {
+        if self.active.is_none() {
+            let id = match self.sizes.iter().next_back() {
+                Some((&id, &size)) if size < self.max_size => id,
+                Some((&id, _)) => id + 1,
+                None => 0,
+            };
+            let path = segment_path(&self.root, id);
+            if !self.sizes.contains_key(&id) {
+                match fs::create_dir(path.parent().unwrap()) {
+                    Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                    _ => {}
+                }
+                self.created = true;
+            }
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            self.sizes.insert(id, file.metadata()?.len());
+            self.active = Some((id, file));
+        }
+        let (id, file) = self.active.as_mut().unwrap();
+        let id = *id;
+        let size = self.sizes.get_mut(&id).unwrap();
+        let location = Location {
+            segment: id,
+            offset: *size,
+            len: data.len() as u64,
+        };
+        self.unsynced.insert(id);
+        file.write_all(data)?;
+        *size += location.len;
+        if *size >= self.max_size {
+            self.active = None;
+        }
+        Ok(location)
+    }
+
+    /// Close the active segment, ex. before moving the directory.
+    pub fn close(&mut self) {
+        self.active = None;
+    }
+
+    /// Fsync segments appended to since the last call.
+    pub fn sync(&mut self) -> io::Result<()> {
+        for &id in &self.unsynced {
+            match &self.active {
+                Some((active, file)) if *active == id => self.durability.sync_data(file)?,
+                _ if self.durability == Durability::None => {}
+                _ => fs::OpenOptions::new()
+                    .append(true)
+                    .open(segment_path(&self.root, id))?
+                    .sync_data()?,
+            }
+        }
+        if self.created {
+            self.durability.sync_dir(&self.root.join(SEGMENTS_DIR))?;
+            self.durability.sync_dir(&self.root)?;
+        }
+        self.unsynced.clear();
+        self.created = false;
+        Ok(())
+    }
+
+    /// Replace the manifest on disk if it changed.
+    pub fn save_manifest(&mut self) -> io::Result<()> {
+        if !self.manifest_changed {
+            return Ok(());
+        }
+        let manifest: HashMap<usize, State> = self
+            .manifest
+            .iter()
+            .map(|(&index, &location)| (index, State::Packed(location)))
+            .collect();
+        let mut file = NamedTempFile::new_in(self.root.join(""))?;
+        file.write_all(&encode_wal(&manifest))?;
+        self.durability.sync_data(file.as_file())?;
+        file.persist(self.root.join(MANIFEST_NAME))?;
+        self.durability.sync_dir(&self.root)?;
+        self.manifest_changed = false;
+        Ok(())
+    }
+
+    /// Live bytes of each segment.
+    fn live_bytes(&self) -> BTreeMap<u64, u64> {
+        let mut live: BTreeMap<u64, u64> = self.sizes.keys().map(|&id| (id, 0)).collect();
+        for location in self.manifest.values() {
+            *live.entry(location.segment).or_default() += location.len;
+        }
+        live
+    }
+
+    /// Up to `max` segments worth compacting, the emptiest first. The last
+    /// segment, which might still be appended to, is skipped.
+    pub fn compaction_candidates(&self, max: usize) -> Vec<u64> {
+        let last = self.sizes.keys().next_back().copied();
+        let mut candidates: Vec<(f64, u64)> = self
+            .live_bytes()
+            .into_iter()
+            .filter(|&(id, _)| Some(id) != last)
+            .map(|(id, live)| {
+                let size = self.sizes.get(&id).copied().unwrap_or(0).max(1);
+                (live as f64 / size as f64, id)
+            })
+            .filter(|&(ratio, _)| ratio < MIN_LIVE_RATIO)
+            .collect();
+        candidates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        candidates.into_iter().take(max).map(|(_, id)| id).collect()
+    }
+
+    /// Committed entries stored in `segment`.
+    pub fn entries_in(&self, segment: u64) -> Vec<(usize, Location)> {
+        let mut entries: Vec<_> = self
+            .manifest
+            .iter()
+            .filter(|(_, location)| location.segment == segment)
+            .map(|(&index, &location)| (index, location))
+            .collect();
+        entries.sort_by_key(|&(_, location)| location.offset);
+        entries
+    }
+
+    /// Delete segments without committed entries, other than the active
+    /// one. Return the number of deleted segments.
+    pub fn remove_dead(&mut self) -> io::Result<usize> {
+        let active = self.active.as_ref().map(|(id, _)| *id);
+        let dead: Vec<u64> = self
+            .live_bytes()
+            .into_iter()
+            .filter(|&(id, live)| live == 0 && Some(id) != active)
+            .map(|(id, _)| id)
+            .collect();
+        for &id in &dead {
+            match fs::remove_file(segment_path(&self.root, id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            self.sizes.remove(&id);
+            self.unsynced.remove(&id);
+        }
+        if !dead.is_empty() {
+            self.durability.sync_dir(&self.root.join(SEGMENTS_DIR))?;
+        }
+        Ok(dead.len())
+    }
+}
+
+pub fn segment_path(root: &Path, id: u64) -> PathBuf {
+    root.join(SEGMENTS_DIR).join(id.to_string())
+}
+
+/// Read a packed entry of the directory `root`.
+pub fn read(root: &Path, location: Location) -> io::Result<Vec<u8>> {
+    let path = segment_path(root, location.segment);
+    let mut file = fs::File::open(&path)?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut buf = vec![0; location.len as usize];
+    file.read_exact(&mut buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is truncated", path.display()),
+        ),
+        _ => e,
+    })?;
+    Ok(buf)
+}
@@ -0,0 +1,511 @@
+//! Entries stored on a WebDAV server, ex. Nextcloud or `rclone serve
+//! webdav`. Entry `index` is stored at `<base>/<index>`.
+//!
+//! Writes and removes are sent in background by a few connections, and
+//! `flush` waits for them. ETags of entries are tracked, so changes by
+//! other clients fail requests with `If-Match` instead of being
+//! overwritten.
+
+mod client;
+#[cfg(test)]
+mod stub;
+
+use super::super::{Bytes, IntKv, Stats};
+pub use client::Client as HttpClient;
+use client::Response;
+use parking_lot::{Condvar, Mutex};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+/// Body of `PROPFIND` listing entries with their ETags.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:getetag/></d:prop></d:propfind>"#;
+
+#[derive(Debug)]
+pub struct HttpIntKv {
+    shared: Arc<Shared>,
+    /// Indexes with changes to send. None once dropped.
+    sender: Option<mpsc::Sender<usize>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    client: HttpClient,
+    state: Mutex<State>,
+    /// Notified when `State::queued` becomes empty.
+    idle: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Entries on the server, with their ETags if known.
+    entries: HashMap<usize, Option<String>>,
+    /// Changes not yet confirmed by the server, with the version of each
+    /// change. None removes the entry.
+    pending: HashMap<usize, (u64, Option<Bytes>)>,
+    version: u64,
+    /// Indexes queued or being sent.
+    queued: HashSet<usize>,
+    /// Last entry written since the last flush, for the final probe.
+    last_written: Option<usize>,
+    /// First failure since the last flush. Failed changes stay pending.
+    error: Option<io::Error>,
+}
+
+impl HttpIntKv {
+    /// Open the collection at the base URL of `client`, creating it if
+    /// missing, and list its entries.
+    pub fn new(client: HttpClient) -> io::Result<Self> {
+        let entries = match list(&client) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let response = client.request("MKCOL", "/", &[], &[])?;
+                check_status(&client, "MKCOL", "/", &response)?;
+                HashMap::new()
+            }
+            result => result?,
+        };
+        let connections = client.connections();
+        let shared = Arc::new(Shared {
+            client,
+            state: Mutex::new(State {
+                entries,
+                ..Default::default()
+            }),
+            idle: Condvar::new(),
+        });
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..connections)
+            .map(|_| {
+                let shared = shared.clone();
+                let receiver = receiver.clone();
+                std::thread::spawn(move || loop {
+                    let index = match receiver.lock().recv() {
+                        Ok(index) => index,
+                        Err(_) => break,
+                    };
+                    shared.send(index);
+                })
+            })
+            .collect();
+        Ok(Self {
+            shared,
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    /// Record a change of `index` and queue it.
+    fn change(&self, index: usize, data: Option<Bytes>) {
+        let mut state = self.shared.state.lock();
+        state.version += 1;
+        let version = state.version;
+        if data.is_some() {
+            state.last_written = Some(index);
+        }
+        state.pending.insert(index, (version, data));
+        if state.queued.insert(index) {
+            let _ = self.sender.as_ref().unwrap().send(index);
+        }
+    }
+
+    /// Check that the last entry written still has the ETag its write
+    /// returned, to catch servers or proxies acknowledging writes they did
+    /// not store.
+    fn probe(&self, index: usize) -> io::Result<()> {
+        let expected = match self.shared.state.lock().entries.get(&index) {
+            Some(Some(etag)) => etag.clone(),
+            // Changed since, or the server does not return ETags.
+            _ => return Ok(()),
+        };
+        let client = &self.shared.client;
+        let path = format!("/{}", index);
+        let response = client.request("HEAD", &path, &[], &[])?;
+        if response.status == 404 {
+            return Err(modified_error(client, &path));
+        }
+        check_status(client, "HEAD", &path, &response)?;
+        match response.header("etag") {
+            Some(etag) if etag != expected => Err(modified_error(client, &path)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    /// Send the pending change of `index`, and newer changes made
+    /// meanwhile.
+    fn send(&self, index: usize) {
+        loop {
+            let (version, data, etag) = {
+                let state = self.state.lock();
+                match state.pending.get(&index) {
+                    Some((version, data)) => {
+                        let etag = state.entries.get(&index).cloned();
+                        (*version, data.clone(), etag)
+                    }
+                    None => break,
+                }
+            };
+            let result = match &data {
+                Some(data) => self.put(index, data, etag.as_ref()),
+                None => self.delete(index, etag.as_ref()),
+            };
+            let mut state = self.state.lock();
+            match result {
+                Ok(new_etag) => {
+                    match data {
+                        Some(_) => state.entries.insert(index, new_etag),
+                        None => state.entries.remove(&index),
+                    };
+                    if state.pending.get(&index).map(|p| p.0) != Some(version) {
+                        // Changed while sending.
+                        continue;
+                    }
+                    state.pending.remove(&index);
+                }
+                Err(e) => {
+                    log::warn!("{}", e);
+                    state.error.get_or_insert(e);
+                }
+            }
+            break;
+        }
+        let mut state = self.state.lock();
+        state.queued.remove(&index);
+        if state.queued.is_empty() {
+            self.idle.notify_all();
+        }
+    }
+
+    /// Upload an entry. `etag` is of the entry on the server, None if it
+    /// is missing, and Some(None) if it exists with an unknown ETag. Return
+    /// the new ETag if the server tells.
+    fn put(
+        &self,
+        index: usize,
+        data: &[u8],
+        etag: Option<&Option<String>>,
+    ) -> io::Result<Option<String>> {
+        let path = format!("/{}", index);
+        let condition = match etag {
+            None => Some(("If-None-Match", "*")),
+            Some(Some(etag)) => Some(("If-Match", etag.as_str())),
+            Some(None) => None,
+        };
+        let headers: Vec<_> = condition.into_iter().collect();
+        let response = self.client.request("PUT", &path, &headers, data)?;
+        check_status(&self.client, "PUT", &path, &response)?;
+        Ok(response.header("etag").map(str::to_string))
+    }
+
+    fn delete(&self, index: usize, etag: Option<&Option<String>>) -> io::Result<Option<String>> {
+        let path = format!("/{}", index);
+        let headers: Vec<_> = match etag {
+            Some(Some(etag)) => vec![("If-Match", etag.as_str())],
+            _ => Vec::new(),
+        };
+        let response = self.client.request("DELETE", &path, &headers, &[])?;
+        match response.status {
+            // Removed by a retried request whose response was lost.
+            404 => Ok(None),
+            _ => check_status(&self.client, "DELETE", &path, &response).map(|()| None),
+        }
+    }
+}
+
+impl Drop for HttpIntKv {
+    fn drop(&mut self) {
+        // Workers send the queued changes, then exit.
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl IntKv for HttpIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        {
+            let state = self.shared.state.lock();
+            if let Some((_, data)) = state.pending.get(&index) {
+                return data.clone().ok_or_else(|| io::ErrorKind::NotFound.into());
+            }
+            if !state.entries.contains_key(&index) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        let client = &self.shared.client;
+        let path = format!("/{}", index);
+        let response = client.request("GET", &path, &[], &[])?;
+        check_status(client, "GET", &path, &response)?;
+        let mut state = self.shared.state.lock();
+        if !state.pending.contains_key(&index) {
+            let etag = response.header("etag").map(str::to_string);
+            state.entries.insert(index, etag);
+        }
+        Ok(response.body.into())
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.change(index, Some(data));
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if !self.has(index)? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.change(index, None);
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        let state = self.shared.state.lock();
+        Ok(match state.pending.get(&index) {
+            Some((_, data)) => data.is_some(),
+            None => state.entries.contains_key(&index),
+        })
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let state = self.shared.state.lock();
+        let mut keys: BTreeSet<usize> = state.entries.keys().copied().collect();
+        for (&index, (_, data)) in &state.pending {
+            match data {
+                Some(_) => keys.insert(index),
+                None => keys.remove(&index),
+            };
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let last_written = {
+            let mut state = self.shared.state.lock();
+            // Retry changes that failed before.
+            let failed: Vec<usize> = state
+                .pending
+                .keys()
+                .filter(|i| !state.queued.contains(i))
+                .copied()
+                .collect();
+            for index in failed {
+                state.queued.insert(index);
+                let _ = self.sender.as_ref().unwrap().send(index);
+            }
+            while !state.queued.is_empty() {
+                self.shared.idle.wait(&mut state);
+            }
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+            state.last_written.take()
+        };
+        match last_written {
+            Some(index) => self.probe(index),
+            None => Ok(()),
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        let (requests, retries) = self.shared.client.counts();
+        let state = self.shared.state.lock();
+        let mut stats = Stats::new();
+        stats.insert("http.requests".into(), requests);
+        stats.insert("http.retries".into(), retries);
+        stats.insert("http.entries".into(), state.entries.len() as u64);
+        stats.insert("http.pending".into(), state.pending.len() as u64);
+        stats
+    }
+}
+
+/// List entries of the collection with their ETags.
+fn list(client: &HttpClient) -> io::Result<HashMap<usize, Option<String>>> {
+    let headers = [
+        ("Depth", "1"),
+        ("Content-Type", "application/xml; charset=utf-8"),
+    ];
+    let response = client.request("PROPFIND", "/", &headers, PROPFIND_BODY.as_bytes())?;
+    check_status(client, "PROPFIND", "/", &response)?;
+    let body = String::from_utf8_lossy(&response.body);
+    let mut entries = HashMap::new();
+    for (href, etag) in parse_multistatus(&body) {
+        // The collection itself is listed too.
+        let name = href
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or_default();
+        if let Ok(index) = name.parse::<usize>() {
+            if !href.ends_with('/') {
+                entries.insert(index, etag);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// `href` and `getetag` of each `response` of a WebDAV multistatus body.
+/// Namespace prefixes are ignored.
+fn parse_multistatus(body: &str) -> Vec<(String, Option<String>)> {
+    let mut result = Vec::new();
+    let mut href = None;
+    let mut etag = None;
+    let mut rest = body;
+    let mut text_start = 0;
+    let mut offset = 0;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &rest[start + 1..end];
+        let text = &body[text_start..offset + start];
+        if let Some(name) = tag.strip_prefix('/') {
+            let local = name.trim().rsplit(':').next().unwrap_or_default();
+            match local {
+                "href" => href = Some(unescape(text.trim())),
+                "getetag" => etag = Some(unescape(text.trim())),
+                "response" => {
+                    if let Some(href) = href.take() {
+                        result.push((href, etag.take()));
+                    }
+                    etag = None;
+                }
+                _ => {}
+            }
+        }
+        offset += end + 1;
+        text_start = offset;
+        rest = &rest[end + 1..];
+    }
+    result
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Turn a failed response into an error of a kind telling why.
+fn check_status(
+    client: &HttpClient,
+    method: &str,
+    path: &str,
+    response: &Response,
+) -> io::Result<()> {
+    if response.is_success() {
+        return Ok(());
+    }
+    let kind = match response.status {
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        404 | 410 => io::ErrorKind::NotFound,
+        412 => return Err(modified_error(client, path)),
+        507 => io::ErrorKind::StorageFull,
+        400..=499 => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(
+        kind,
+        format!(
+            "{} {} failed with status {}",
+            method,
+            client.url(path),
+            response.status
+        ),
+    ))
+}
+
+fn modified_error(client: &HttpClient, path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} was modified externally since it was read",
+            client.url(path)
+        ),
+    )
+}
+
+#[cfg(test)]
+fn test_client(stub: &stub::Stub) -> HttpClient {
+    HttpClient::new(&stub.url("/dav/x79d8"))
+        .unwrap()
+        .with_retry(3, std::time::Duration::from_millis(1))
+}
+
+#[test]
+fn test_http_int_kv() {
+    let stub = stub::Stub::start();
+    let reload = |kv: Option<HttpIntKv>| {
+        drop(kv);
+        HttpIntKv::new(test_client(&stub)).unwrap()
+    };
+    let kv = super::super::test_int_kv(reload, 30);
+    assert!(kv.stats()["http.requests"] > 0);
+    assert_eq!(stub.get("/dav/x79d8/5"), None);
+}
+
+#[test]
+fn test_http_int_kv_errors() {
+    let stub = stub::Stub::start();
+    stub.require_auth("Bearer secret");
+    let kind = |client: HttpClient| HttpIntKv::new(client).unwrap_err().kind();
+    assert_eq!(kind(test_client(&stub)), io::ErrorKind::PermissionDenied);
+    let client = || test_client(&stub).with_bearer_token("secret");
+    let mut kv = HttpIntKv::new(client()).unwrap();
+    assert_eq!(kv.read(1).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    // Retried.
+    stub.fail(503, 2);
+    kv.write(1, b"a".to_vec().into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(stub.get("/dav/x79d8/1").unwrap(), b"a");
+    assert_eq!(kv.stats()["http.retries"], 2);
+
+    // Failed changes stay pending, and are sent by the next flush.
+    stub.fail(500, 3);
+    kv.write(2, b"b".to_vec().into()).unwrap();
+    let e = kv.flush().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert!(e.to_string().contains("status 500"), "{}", e);
+    assert_eq!(kv.read(2).unwrap().as_ref(), b"b");
+    kv.flush().unwrap();
+    assert_eq!(stub.get("/dav/x79d8/2").unwrap(), b"b");
+}
+
+#[test]
+fn test_http_int_kv_external_change() {
+    let stub = stub::Stub::start();
+    let mut kv1 = HttpIntKv::new(test_client(&stub)).unwrap();
+    kv1.write(1, b"a".to_vec().into()).unwrap();
+    kv1.flush().unwrap();
+    let mut kv2 = HttpIntKv::new(test_client(&stub)).unwrap();
+    assert_eq!(kv2.read(1).unwrap().as_ref(), b"a");
+
+    kv1.write(1, b"b".to_vec().into()).unwrap();
+    kv1.write(2, b"c".to_vec().into()).unwrap();
+    kv1.flush().unwrap();
+    for index in [1, 2] {
+        kv2.write(index, b"d".to_vec().into()).unwrap();
+        assert_eq!(kv2.flush().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        kv2.remove(index).unwrap();
+    }
+    assert_eq!(stub.get("/dav/x79d8/1").unwrap(), b"b");
+    assert_eq!(stub.get("/dav/x79d8/2").unwrap(), b"c");
+    stub.put("/dav/x79d8/1", b"e");
+    kv1.remove(1).unwrap();
+    assert_eq!(kv1.flush().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(stub.get("/dav/x79d8/1").unwrap(), b"e");
+
+    // Acknowledged, but not stored.
+    stub.lose_writes();
+    kv1.write(3, b"f".to_vec().into()).unwrap();
+    assert_eq!(kv1.flush().unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,526 @@
+//! A small blocking HTTP/1.1 client keeping connections alive, for
+//! `HttpIntKv`. Supports `http://` and `https://` URLs, bodies of known
+//! length, and chunked responses.
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// CA bundles tried for `https://` unless `with_ca_file` is used.
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Connections kept open unless `with_connections` is used.
+const DEFAULT_CONNECTIONS: usize = 4;
+
+/// Longest status line or header accepted.
+const MAX_LINE: usize = 64 << 10;
+
+/// Client of a base URL. Requests are sent to paths below it.
+pub struct Client {
+    tls: bool,
+    /// Without brackets of IPv6 addresses.
+    host: String,
+    port: u16,
+    /// Value of the `Host` header.
+    authority: String,
+    /// Path of the base URL, without the trailing slash.
+    base_path: String,
+    /// Value of the `Authorization` header.
+    auth: Option<String>,
+    timeout: Duration,
+    retry_attempts: u32,
+    retry_delay: Duration,
+    connections: usize,
+    ca_file: Option<PathBuf>,
+    tls_config: OnceCell<Arc<rustls::ClientConfig>>,
+    idle: Mutex<Vec<Conn>>,
+    requests: AtomicU64,
+    retries: AtomicU64,
+}
+
+/// Response with its body.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Value of a header by its case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the status is 2xx.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+impl Client {
+    /// Client of `url`, such as "https://example.com/dav/x79d8". Nothing is
+    /// sent until the first request.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid = |message: &str| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", url, message))
+        };
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid("only http:// and https:// are supported"));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if authority.contains('@') {
+            return Err(invalid("put credentials in the config instead of the URL"));
+        }
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .ok_or_else(|| invalid("invalid host"))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("invalid port"))?,
+            None if tls => 443,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            authority: authority.to_string(),
+            base_path: path.trim_end_matches('/').to_string(),
+            auth: None,
+            timeout: DEFAULT_TIMEOUT,
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            connections: DEFAULT_CONNECTIONS,
+            ca_file: None,
+            tls_config: OnceCell::new(),
+            idle: Default::default(),
+            requests: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+        })
+    }
+
+    /// Authenticate by user name and password.
+    pub fn with_basic_auth(mut self, user: &str, password: &str) -> Self {
+        let credentials = base64::encode(format!("{}:{}", user, password));
+        self.auth = Some(format!("Basic {}", credentials));
+        self
+    }
+
+    /// Authenticate by a token, ex. an app password of Nextcloud.
+    pub fn with_bearer_token(mut self, token: &str) -> Self {
+        self.auth = Some(format!("Bearer {}", token));
+        self
+    }
+
+    /// Give up on connecting, sending or receiving after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Try requests up to `attempts` times on connection errors, timeouts
+    /// and 5xx responses. Wait `delay` before the first retry, doubling for
+    /// each retry.
+    pub fn with_retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.retry_attempts = attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Number of connections to keep open, and of requests in flight.
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Trust the CA certificates in the PEM file `path` for `https://`,
+    /// instead of those of the system.
+    pub fn with_ca_file(mut self, path: PathBuf) -> Self {
+        self.ca_file = Some(path);
+        self
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Requests sent, and of them retries.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+        )
+    }
+
+    /// URL of `path` below the base URL, for messages.
+    pub fn url(&self, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}{}{}", scheme, self.authority, self.base_path, path)
+    }
+
+    /// Send a request to `path` below the base URL, such as "/1", retrying
+    /// as configured. Responses other than 5xx are returned as they are.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let mut attempt = 1;
+        let mut delay = self.retry_delay;
+        loop {
+            let result = self.send(method, path, headers, body);
+            let error = match result {
+                Ok(response) if response.status >= 500 || response.status == 429 => {
+                    if attempt >= self.retry_attempts {
+                        return Ok(response);
+                    }
+                    format!("status {}", response.status)
+                }
+                Err(e) if is_transient(&e) && attempt < self.retry_attempts => e.to_string(),
+                result => return result,
+            };
+            log::warn!(
+                "Retrying {} {} after {} (attempt {} of {})",
+                method,
+                self.url(path),
+                error,
+                attempt,
+                self.retry_attempts
+            );
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+        }
+    }
+
+    /// Send a request once. An idle connection closed by the server is
+    /// replaced without counting as an attempt.
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut head = format!(
+            "{} {}{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+            method,
+            self.base_path,
+            path,
+            self.authority,
+            body.len()
+        );
+        if let Some(auth) = &self.auth {
+            head += &format!("Authorization: {}\r\n", auth);
+        }
+        for (name, value) in headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        head += "\r\n";
+        let reused = self.idle.lock().pop();
+        if let Some(mut conn) = reused {
+            match conn.exchange(method, head.as_bytes(), body) {
+                Ok(response) => return self.finish(conn, response),
+                Err(e) if is_transient(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut conn = self.connect()?;
+        let response = conn.exchange(method, head.as_bytes(), body)?;
+        self.finish(conn, response)
+    }
+
+    /// Keep `conn` for later requests unless it is to be closed.
+    fn finish(&self, conn: Conn, response: (Response, bool)) -> io::Result<Response> {
+        let (response, keep_alive) = response;
+        if keep_alive {
+            let mut idle = self.idle.lock();
+            if idle.len() < self.connections {
+                idle.push(conn);
+            }
+        }
+        Ok(response)
+    }
+
+    fn connect(&self) -> io::Result<Conn> {
+        let addrs = (self.host.as_str(), self.port).to_socket_addrs()?;
+        let mut last_error = None;
+        let mut tcp = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let tcp = match tcp {
+            Some(tcp) => tcp,
+            None => {
+                return Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} has no address", self.host),
+                    )
+                }))
+            }
+        };
+        tcp.set_read_timeout(Some(self.timeout))?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+        tcp.set_nodelay(true)?;
+        let stream = if self.tls {
+            let config = self.tls_config.get_or_try_init(|| self.load_tls_config())?;
+            let name = webpki::DNSNameRef::try_from_ascii_str(&self.host).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a valid host name for TLS", self.host),
+                )
+            })?;
+            let session = rustls::ClientSession::new(config, name);
+            Stream::Tls(Box::new(rustls::StreamOwned::new(session, tcp)))
+        } else {
+            Stream::Plain(tcp)
+        };
+        Ok(Conn {
+            reader: BufReader::new(stream),
+        })
+    }
+
+    fn load_tls_config(&self) -> io::Result<Arc<rustls::ClientConfig>> {
+        let paths: Vec<PathBuf> = match &self.ca_file {
+            Some(path) => vec![path.clone()],
+            None => SYSTEM_CA_FILES.iter().map(PathBuf::from).collect(),
+        };
+        let mut config = rustls::ClientConfig::new();
+        for path in &paths {
+            let file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && self.ca_file.is_none() => continue,
+                Err(e) => return Err(e),
+            };
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("cannot parse {}", path.display()),
+                )
+            };
+            let (added, _) = config
+                .root_store
+                .add_pem_file(&mut BufReader::new(file))
+                .map_err(|()| invalid())?;
+            if added == 0 {
+                return Err(invalid());
+            }
+            break;
+        }
+        if config.root_store.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no CA certificates found (set a CA file)",
+            ));
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Credentials are left out.
+        f.debug_struct("Client")
+            .field("url", &self.url(""))
+            .field("connections", &self.connections)
+            .finish()
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientSession, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+struct Conn {
+    reader: BufReader<Stream>,
+}
+
+impl Conn {
+    /// Write a request and read its response. Return the response and
+    /// whether the connection can be reused.
+    fn exchange(&mut self, method: &str, head: &[u8], body: &[u8]) -> io::Result<(Response, bool)> {
+        let stream = self.reader.get_mut();
+        stream.write_all(head)?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let status_line = self.read_line()?;
+        if status_line.is_empty() {
+            // Closed before responding. Usually an idle connection.
+            return Err(io::ErrorKind::ConnectionAborted.into());
+        }
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        let status = match (version.starts_with("HTTP/1."), parts.next().map(str::parse)) {
+            (true, Some(Ok(status))) => status,
+            _ => return Err(invalid_response(&status_line)),
+        };
+        let mut keep_alive = version != "HTTP/1.0";
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_response(&line))?;
+            let (name, value) = (name.trim().to_string(), value.trim().to_string());
+            if name.eq_ignore_ascii_case("connection") {
+                keep_alive = !value.eq_ignore_ascii_case("close");
+            }
+            headers.push((name, value));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        // Responses to HEAD, 1xx, 204 and 304 have no body.
+        if method == "HEAD" || status < 200 || status == 204 || status == 304 {
+            return Ok((response, keep_alive));
+        }
+        let chunked = response
+            .header("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        if chunked {
+            response.body = self.read_chunked()?;
+        } else if let Some(len) = response.header("content-length") {
+            let len: usize = len.parse().map_err(|_| invalid_response(len))?;
+            response.body = vec![0; len];
+            self.reader.read_exact(&mut response.body)?;
+        } else {
+            self.reader.read_to_end(&mut response.body)?;
+            keep_alive = false;
+        }
+        Ok((response, keep_alive))
+    }
+
+    fn read_chunked(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| invalid_response(&line))?;
+            if size == 0 {
+                // Trailers.
+                while !self.read_line()?.is_empty() {}
+                return Ok(body);
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            self.reader.read_exact(&mut body[start..])?;
+            self.read_line()?;
+        }
+    }
+
+    /// Read a line without its line ending. Empty at EOF.
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        (&mut self.reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)?;
+        if line.len() >= MAX_LINE {
+            return Err(invalid_response("line too long"));
+        }
+        let line = String::from_utf8(line).map_err(|_| invalid_response("non-UTF-8 header"))?;
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+    }
+}
+
+fn invalid_response(detail: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid HTTP response: {}", detail),
+    )
+}
+
+/// Whether a request failing with `e` might succeed if sent again.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+    )
+}
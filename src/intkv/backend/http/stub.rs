@@ -0,0 +1,235 @@
+//! In-process WebDAV server for tests, keeping files in memory. Supports
+//! what `HttpIntKv` uses.
+
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+pub struct Stub {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Files by path, with their ETags.
+    files: BTreeMap<String, (Vec<u8>, String)>,
+    collections: BTreeSet<String>,
+    next_etag: u64,
+    /// Required `Authorization` header.
+    auth: Option<String>,
+    /// Status to answer the next requests with, and how many.
+    failures: Option<(u16, usize)>,
+    /// Acknowledge uploads without storing them.
+    lose_writes: bool,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Stub {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state: Arc<Mutex<State>> = Default::default();
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = server_state.clone();
+                if let Ok(stream) = stream {
+                    std::thread::spawn(move || {
+                        let _ = serve(stream, &state);
+                    });
+                }
+            }
+        });
+        Self { port, state }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub fn require_auth(&self, value: &str) {
+        self.state.lock().auth = Some(value.to_string());
+    }
+
+    /// Answer the next `count` requests with `status`.
+    pub fn fail(&self, status: u16, count: usize) {
+        self.state.lock().failures = Some((status, count));
+    }
+
+    /// Change a file as another client would.
+    pub fn put(&self, path: &str, data: &[u8]) {
+        let mut state = self.state.lock();
+        let etag = state.new_etag();
+        state.files.insert(path.to_string(), (data.to_vec(), etag));
+    }
+
+    pub fn lose_writes(&self) {
+        self.state.lock().lose_writes = true;
+    }
+
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().files.get(path).map(|f| f.0.clone())
+    }
+}
+
+impl State {
+    fn new_etag(&mut self) -> String {
+        self.next_etag += 1;
+        format!("\"{}\"", self.next_etag)
+    }
+
+    fn handle(&mut self, request: &Request) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        if let Some(auth) = &self.auth {
+            if request.header("authorization") != Some(auth.as_str()) {
+                return (401, Vec::new(), Vec::new());
+            }
+        }
+        if let Some((status, count)) = self.failures {
+            self.failures = (count > 1).then(|| (status, count - 1));
+            return (status, Vec::new(), Vec::new());
+        }
+        let path = request.path.clone();
+        let file = self.files.get(&path);
+        let etag_header = |etag: &str| vec![("ETag".to_string(), etag.to_string())];
+        let precondition_failed =
+            match (request.header("if-match"), request.header("if-none-match")) {
+                (Some(tag), _) => file.map(|f| f.1.as_str()) != Some(tag),
+                (_, Some("*")) => file.is_some(),
+                _ => false,
+            };
+        match request.method.as_str() {
+            "GET" | "HEAD" => match file {
+                Some((data, etag)) => (200, etag_header(etag), data.clone()),
+                None => (404, Vec::new(), Vec::new()),
+            },
+            "PUT" => {
+                let parent = path.rsplit_once('/').map_or("", |p| p.0);
+                if !self.collections.contains(parent) {
+                    return (409, Vec::new(), Vec::new());
+                }
+                if precondition_failed {
+                    return (412, Vec::new(), Vec::new());
+                }
+                let status = if file.is_some() { 204 } else { 201 };
+                let etag = self.new_etag();
+                if !self.lose_writes {
+                    self.files
+                        .insert(path, (request.body.clone(), etag.clone()));
+                }
+                (status, etag_header(&etag), Vec::new())
+            }
+            "DELETE" => {
+                if file.is_none() {
+                    return (404, Vec::new(), Vec::new());
+                }
+                if precondition_failed {
+                    return (412, Vec::new(), Vec::new());
+                }
+                self.files.remove(&path);
+                (204, Vec::new(), Vec::new())
+            }
+            "MKCOL" => {
+                let path = path.trim_end_matches('/').to_string();
+                match self.collections.insert(path) {
+                    true => (201, Vec::new(), Vec::new()),
+                    false => (405, Vec::new(), Vec::new()),
+                }
+            }
+            "PROPFIND" => {
+                let path = path.trim_end_matches('/');
+                if !self.collections.contains(path) {
+                    return (404, Vec::new(), Vec::new());
+                }
+                let mut body =
+                    String::from(r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">"#);
+                body += &format!("<D:response><D:href>{}/</D:href></D:response>", path);
+                let prefix = format!("{}/", path);
+                for (name, (_, etag)) in self.files.range(prefix.clone()..) {
+                    if !name.starts_with(&prefix) {
+                        break;
+                    }
+                    body += &format!(
+                        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+                         <D:getetag>{}</D:getetag></D:prop></D:propstat></D:response>",
+                        name,
+                        etag.replace('"', "&quot;")
+                    );
+                }
+                body += "</D:multistatus>";
+                (207, Vec::new(), body.into_bytes())
+            }
+            _ => (405, Vec::new(), Vec::new()),
+        }
+    }
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        let mut request = Request {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        };
+        let len: usize = request
+            .header("content-length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        request.body = vec![0; len];
+        reader.read_exact(&mut request.body)?;
+
+        let (status, headers, body) = state.lock().handle(&request);
+        let mut head = format!(
+            "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\n",
+            status,
+            body.len()
+        );
+        for (name, value) in headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        head += "\r\n";
+        writer.write_all(head.as_bytes())?;
+        if request.method != "HEAD" {
+            writer.write_all(&body)?;
+        }
+    }
+}
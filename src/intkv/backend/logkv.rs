@@ -0,0 +1,619 @@
+//! Entries appended as records to segment files, for storage preferring
+//! sequential writes.
+//!
+//! A record is the index, the data length, whether it is a removal, a
+//! checksum, then the data. The latest record of an index wins. `flush`
+//! syncs the segments appended to, then replaces the index snapshot,
+//! which maps indexes to records and tells where the segments end. Opening
+//! loads the snapshot, then replays records appended after it, dropping a
+//! torn record at the end. Compaction copies live records of mostly dead
+//! segments to the end, and deletes the segments after the next flush.
+
+use super::super::{Bytes, IntKv, Stats};
+use super::fs::{sync_dir, DirLock};
+use super::LockMode;
+use crate::util;
+use blake2::{Blake2s, Digest};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bytes of a record header: index, length, removal, checksum.
+const HEADER_SIZE: u64 = 21;
+const SNAPSHOT_NAME: &str = "index";
+const SNAPSHOT_VERSION: u32 = 1;
+
+pub struct LogIntKv {
+    dir: PathBuf,
+    _lock: Arc<DirLock>,
+    read_only: bool,
+    segments: BTreeMap<u64, Segment>,
+    /// Latest record of each existing entry.
+    index: BTreeMap<usize, Location>,
+    /// Segment of the latest removal record of each removed entry, kept
+    /// while older segments might have records of the entry.
+    removed: HashMap<usize, u64>,
+    /// Compacted segments to delete after the next flush.
+    obsolete: Vec<u64>,
+    /// Segments appended to since the last flush.
+    unsynced: BTreeSet<u64>,
+    /// Segments were created since the last flush.
+    created: bool,
+    dirty: bool,
+    segment_size: u64,
+    compaction_ratio: f64,
+    /// Segments compacted.
+    compacted: u64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    file: File,
+    len: u64,
+    /// Bytes of records that are the latest of their entries.
+    live: u64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+impl Location {
+    fn record_size(&self) -> u64 {
+        HEADER_SIZE + self.len as u64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    /// How long each segment was.
+    segments: Vec<(u64, u64)>,
+    index: Vec<(usize, Location)>,
+    removed: Vec<(usize, u64)>,
+}
+
+impl LogIntKv {
+    /// Open the log in `dir`, creating it if empty. `LockMode::Shared`
+    /// opens read-only, waiting up to `wait` for a writer.
+    pub fn open(dir: &Path, mode: LockMode, wait: Duration) -> io::Result<Self> {
+        let lock = DirLock::acquire(dir, mode, wait)?;
+        let read_only = mode == LockMode::Shared;
+        let mut kv = Self {
+            dir: dir.to_path_buf(),
+            _lock: lock,
+            read_only,
+            segments: BTreeMap::new(),
+            index: BTreeMap::new(),
+            removed: HashMap::new(),
+            obsolete: Vec::new(),
+            unsynced: BTreeSet::new(),
+            created: false,
+            dirty: false,
+            segment_size: 64 << 20,
+            compaction_ratio: 0.5,
+            compacted: 0,
+        };
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let id = match name.to_str().and_then(parse_segment_name) {
+                Some(id) => id,
+                None => continue,
+            };
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .open(kv.segment_path(id))?;
+            let len = file.metadata()?.len();
+            kv.segments.insert(id, Segment { file, len, live: 0 });
+        }
+
+        // Replay what the snapshot does not cover.
+        let mut replay_from: BTreeMap<u64, u64> = kv.segments.keys().map(|&id| (id, 0)).collect();
+        match kv.load_snapshot()? {
+            Some(snapshot) => {
+                for (id, len) in snapshot.segments {
+                    if let Some(from) = replay_from.get_mut(&id) {
+                        *from = len;
+                    }
+                }
+                for (index, location) in snapshot.index {
+                    match kv.segments.get_mut(&location.segment) {
+                        Some(segment) => segment.live += location.record_size(),
+                        None => return Err(kv.corrupted(&format!("entry {} is lost", index))),
+                    }
+                    kv.index.insert(index, location);
+                }
+                kv.removed.extend(snapshot.removed);
+            }
+            None if !kv.segments.is_empty() => {
+                log::warn!("{}: replaying all segments", kv.dir.display());
+            }
+            None => {}
+        }
+        let last = kv.segments.keys().next_back().cloned();
+        for (id, from) in replay_from {
+            if from > kv.segments[&id].len {
+                return Err(kv.corrupted(&format!("segment {} is truncated", id)));
+            }
+            let end = kv.replay(id, from)?;
+            if end < kv.segments[&id].len {
+                if Some(id) != last {
+                    return Err(kv.corrupted(&format!("segment {} is corrupted", id)));
+                }
+                log::warn!("{}: dropping a torn record at the end", kv.dir.display());
+                kv.dirty = true;
+                if !read_only {
+                    let segment = kv.segments.get_mut(&id).unwrap();
+                    segment.file.set_len(end)?;
+                    segment.len = end;
+                }
+            }
+        }
+        Ok(kv)
+    }
+
+    /// Start another segment once the current one reaches `bytes`.
+    pub fn with_segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes.max(1);
+        self
+    }
+
+    /// Compact segments whose live records take less than `ratio` of them.
+    pub fn with_compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:08}.log", id))
+    }
+
+    fn corrupted(&self, message: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", self.dir.display(), message),
+        )
+    }
+
+    fn load_snapshot(&self) -> io::Result<Option<Snapshot>> {
+        let data = match fs::read(self.dir.join(SNAPSHOT_NAME)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let valid = data.len() >= 32 && {
+            let (body, hash) = data.split_at(data.len() - 32);
+            Blake2s::digest(body).as_slice() == hash
+        };
+        let snapshot = match valid {
+            true => util::bincode_deserialize::<Snapshot>(&data[..data.len() - 32]).ok(),
+            false => None,
+        };
+        match snapshot {
+            Some(snapshot) if snapshot.version == SNAPSHOT_VERSION => Ok(Some(snapshot)),
+            _ => {
+                log::warn!("{}: the index snapshot is corrupted", self.dir.display());
+                Ok(None)
+            }
+        }
+    }
+
+    fn save_snapshot(&self) -> io::Result<()> {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            segments: self.segments.iter().map(|(&id, s)| (id, s.len)).collect(),
+            index: self.index.iter().map(|(&i, &l)| (i, l)).collect(),
+            removed: self.removed.iter().map(|(&i, &s)| (i, s)).collect(),
+        };
+        let mut data = util::bincode_serialize_pad(&snapshot, 0);
+        let hash = Blake2s::digest(&data);
+        data.extend_from_slice(&hash);
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&data)?;
+        file.as_file().sync_all()?;
+        file.persist(self.dir.join(SNAPSHOT_NAME))?;
+        Ok(())
+    }
+
+    /// Apply records of segment `id` from `offset`. Return where the valid
+    /// records end.
+    fn replay(&mut self, id: u64, mut offset: u64) -> io::Result<u64> {
+        let len = self.segments[&id].len;
+        let mut data = vec![0; len.saturating_sub(offset) as usize];
+        self.segments[&id].file.read_exact_at(&mut data, offset)?;
+        let mut rest = &data[..];
+        while let Some((index, removal, record)) = decode_record(rest) {
+            let location = Location {
+                segment: id,
+                offset,
+                len: (record.len() - HEADER_SIZE as usize) as u32,
+            };
+            match removal {
+                true => self.apply_removal(index, id),
+                false => self.apply_write(index, location),
+            }
+            offset += record.len() as u64;
+            rest = &rest[record.len()..];
+        }
+        Ok(offset)
+    }
+
+    fn forget(&mut self, index: usize) -> Option<Location> {
+        let old = self.index.remove(&index)?;
+        if let Some(segment) = self.segments.get_mut(&old.segment) {
+            segment.live -= old.record_size();
+        }
+        Some(old)
+    }
+
+    fn apply_write(&mut self, index: usize, location: Location) {
+        self.forget(index);
+        self.removed.remove(&index);
+        self.segments.get_mut(&location.segment).unwrap().live += location.record_size();
+        self.index.insert(index, location);
+    }
+
+    fn apply_removal(&mut self, index: usize, segment: u64) {
+        self.forget(index);
+        self.removed.insert(index, segment);
+    }
+
+    /// Append a record. Return where it is.
+    fn append(&mut self, index: usize, data: Option<&[u8]>) -> io::Result<Location> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is opened read-only", self.dir.display()),
+            ));
+        }
+        let record = encode_record(index, data);
+        let active = self.segments.iter().next_back().map(|(&id, s)| (id, s.len));
+        let id = match active {
+            Some((id, len)) if len == 0 || len + record.len() as u64 <= self.segment_size => id,
+            _ => {
+                let id = active.map_or(1, |(id, _)| id + 1);
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(self.segment_path(id))?;
+                self.segments.insert(
+                    id,
+                    Segment {
+                        file,
+                        len: 0,
+                        live: 0,
+                    },
+                );
+                self.created = true;
+                id
+            }
+        };
+        let segment = self.segments.get_mut(&id).unwrap();
+        let offset = segment.len;
+        segment.file.write_all_at(&record, offset)?;
+        segment.len += record.len() as u64;
+        self.unsynced.insert(id);
+        self.dirty = true;
+        Ok(Location {
+            segment: id,
+            offset,
+            len: data.map_or(0, |d| d.len()) as u32,
+        })
+    }
+
+    /// Copy the live records of segment `id` to the end.
+    fn compact_segment(&mut self, id: u64) -> io::Result<usize> {
+        let live: Vec<usize> = self
+            .index
+            .iter()
+            .filter(|(_, l)| l.segment == id)
+            .map(|(&i, _)| i)
+            .collect();
+        for &index in &live {
+            let data = self.read(index)?;
+            let location = self.append(index, Some(&data))?;
+            self.apply_write(index, location);
+        }
+        // Older segments might have records of removed entries.
+        let oldest = self.segments.keys().next() == Some(&id);
+        let removed: Vec<usize> = self
+            .removed
+            .iter()
+            .filter(|(_, &s)| s == id)
+            .map(|(&i, _)| i)
+            .collect();
+        for index in removed {
+            if oldest {
+                self.removed.remove(&index);
+            } else {
+                let location = self.append(index, None)?;
+                self.apply_removal(index, location.segment);
+            }
+        }
+        self.segments.remove(&id);
+        self.obsolete.push(id);
+        self.dirty = true;
+        self.compacted += 1;
+        Ok(live.len())
+    }
+}
+
+fn parse_segment_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".log")?.parse().ok()
+}
+
+/// A record of writing `data`, or removing if None.
+fn encode_record(index: usize, data: Option<&[u8]>) -> Vec<u8> {
+    let body = data.unwrap_or_default();
+    let mut record = Vec::with_capacity(HEADER_SIZE as usize + body.len());
+    record.extend_from_slice(&(index as u64).to_le_bytes());
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.push(data.is_none() as u8);
+    let checksum = record_checksum(&record, body);
+    record.extend_from_slice(&checksum);
+    record.extend_from_slice(body);
+    record
+}
+
+/// Decode the record at the start of `data`: the index, whether it is a
+/// removal, and the whole record. None if it is torn or corrupted.
+fn decode_record(data: &[u8]) -> Option<(usize, bool, &[u8])> {
+    if data.len() < HEADER_SIZE as usize {
+        return None;
+    }
+    let index = u64::from_le_bytes(data[..8].try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+    let removal = match data[12] {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    let end = HEADER_SIZE as usize + len;
+    if data.len() < end {
+        return None;
+    }
+    let body = &data[HEADER_SIZE as usize..end];
+    if record_checksum(&data[..13], body) != data[13..21] {
+        return None;
+    }
+    Some((index, removal, &data[..end]))
+}
+
+fn record_checksum(header: &[u8], body: &[u8]) -> [u8; 8] {
+    let mut hasher = Blake2s::new();
+    hasher.update(header);
+    hasher.update(body);
+    hasher.finalize()[..8].try_into().unwrap()
+}
+
+impl fmt::Debug for LogIntKv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogIntKv")
+            .field("dir", &self.dir)
+            .field("segments", &self.segments.len())
+            .field("entries", &self.index.len())
+            .field("dirty", &self.dirty)
+            .finish()
+    }
+}
+
+impl IntKv for LogIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let location = match self.index.get(&index) {
+            Some(location) => location,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let mut record = vec![0; location.record_size() as usize];
+        let segment = &self.segments[&location.segment];
+        segment.file.read_exact_at(&mut record, location.offset)?;
+        match decode_record(&record) {
+            Some((i, false, _)) if i == index => Ok(record.split_off(HEADER_SIZE as usize).into()),
+            _ => Err(self.corrupted(&format!("record of entry {} is corrupted", index))),
+        }
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let location = self.append(index, Some(&data))?;
+        self.apply_write(index, location);
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if !self.index.contains_key(&index) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let location = self.append(index, None)?;
+        self.apply_removal(index, location.segment);
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        Ok(self.index.contains_key(&index))
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        Ok(self.index.keys().cloned().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty || self.read_only {
+            return Ok(());
+        }
+        for id in &self.unsynced {
+            if let Some(segment) = self.segments.get(id) {
+                segment.file.sync_data()?;
+            }
+        }
+        if self.created {
+            sync_dir(&self.dir)?;
+        }
+        self.save_snapshot()?;
+        for id in std::mem::take(&mut self.obsolete) {
+            match fs::remove_file(self.segment_path(id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        sync_dir(&self.dir)?;
+        self.unsynced.clear();
+        self.created = false;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        stats.insert("log.segments".into(), self.segments.len() as u64);
+        let total = self.segments.values().map(|s| s.len).sum();
+        let live = self.segments.values().map(|s| s.live).sum();
+        stats.insert("log.total_bytes".into(), total);
+        stats.insert("log.live_bytes".into(), live);
+        stats.insert("log.compacted".into(), self.compacted);
+        stats
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let active = self.segments.keys().next_back().cloned();
+        let sparse: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(&id, s)| {
+                Some(id) != active && (s.live as f64) < (s.len as f64) * self.compaction_ratio
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        let mut moved = 0;
+        for id in sparse {
+            if moved >= max_pages {
+                break;
+            }
+            moved += self.compact_segment(id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_kv(dir: &Path) -> LogIntKv {
+    LogIntKv::open(dir, LockMode::Exclusive, Duration::from_secs(0))
+        .unwrap()
+        .with_segment_size(1 << 16)
+}
+
+#[test]
+fn test_log_int_kv() {
+    let dir = tempfile::tempdir().unwrap();
+    super::super::test_int_kv(
+        |kv: Option<LogIntKv>| {
+            if let Some(mut kv) = kv {
+                kv.compact_step(usize::MAX).unwrap();
+                kv.flush().unwrap();
+            }
+            test_kv(dir.path())
+        },
+        30,
+    );
+}
+
+#[test]
+fn test_log_int_kv_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut kv = test_kv(dir.path());
+    for i in 0..10 {
+        kv.write(i, vec![i as u8; 1000].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    // Not flushed: replayed from the segments.
+    kv.remove(3).unwrap();
+    for i in 10..100 {
+        kv.write(i, vec![i as u8; 1000].into()).unwrap();
+    }
+    drop(kv);
+    let expected: Vec<usize> = (0..100).filter(|&i| i != 3).collect();
+    let kv = test_kv(dir.path());
+    assert_eq!(kv.keys().unwrap(), expected);
+    assert!(kv.stats()["log.segments"] > 1);
+    drop(kv);
+
+    // A torn record at the end is dropped.
+    let last = fs::read_dir(dir.path())
+        .unwrap()
+        .filter_map(|e| e.unwrap().file_name().into_string().ok())
+        .filter_map(|n| parse_segment_name(&n))
+        .max()
+        .unwrap();
+    let path = dir.path().join(format!("{:08}.log", last));
+    let len = fs::metadata(&path).unwrap().len();
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len - 10).unwrap();
+    let mut kv = test_kv(dir.path());
+    assert_eq!(kv.keys().unwrap(), expected[..expected.len() - 1]);
+    kv.write(200, vec![1].into()).unwrap();
+    kv.flush().unwrap();
+    drop(kv);
+
+    // Without the snapshot, all segments are replayed.
+    fs::write(dir.path().join(SNAPSHOT_NAME), b"x").unwrap();
+    let kv = test_kv(dir.path());
+    assert_eq!(kv.keys().unwrap().len(), expected.len());
+    assert_eq!(kv.read(200).unwrap().as_ref(), &[1]);
+    assert_eq!(kv.read(50).unwrap().as_ref(), &[50; 1000][..]);
+}
+
+#[test]
+fn test_log_int_kv_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut kv = test_kv(dir.path());
+    for i in 0..200 {
+        kv.write(i, vec![i as u8; 1000].into()).unwrap();
+    }
+    // Most entries of the first segments are replaced or removed.
+    for i in 0..150 {
+        match i % 3 {
+            0 => kv.remove(i).unwrap(),
+            _ => kv.write(i, vec![!(i as u8); 500].into()).unwrap(),
+        }
+    }
+    kv.flush().unwrap();
+    let before = kv.stats();
+    kv.compact_step(usize::MAX).unwrap();
+    kv.flush().unwrap();
+    let after = kv.stats();
+    assert!(after["log.compacted"] > 0);
+    assert!(after["log.total_bytes"] < before["log.total_bytes"]);
+    assert_eq!(after["log.live_bytes"], before["log.live_bytes"]);
+    let files = fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(files, after["log.segments"] as usize + 2);
+
+    let check = |kv: &LogIntKv| {
+        for i in 0..200 {
+            let expected = match i {
+                i if i < 150 && i % 3 == 0 => None,
+                i if i < 150 => Some(vec![!(i as u8); 500]),
+                i => Some(vec![i as u8; 1000]),
+            };
+            assert_eq!(kv.read(i).ok().map(|d| d.to_vec()), expected);
+        }
+    };
+    check(&kv);
+    drop(kv);
+    check(&test_kv(dir.path()));
+    // Removals survive replaying everything.
+    fs::remove_file(dir.path().join(SNAPSHOT_NAME)).unwrap();
+    check(&test_kv(dir.path()));
+}
@@ -27,6 +27,10 @@ impl IntKv for MemIntKv {
         Ok(self.contains_key(&index))
     }
 
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        Ok(BTreeMap::keys(self).cloned().collect())
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
@@ -1,5 +1,29 @@
+#[cfg(unix)]
+mod blockdev;
+mod capped;
 mod fs;
+mod http;
+#[cfg(unix)]
+mod logkv;
 mod mem;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sftp")]
+mod sftp;
+#[cfg(feature = "sled")]
+mod sledkv;
 
-pub use fs::FsIntKv;
+#[cfg(unix)]
+pub use blockdev::BlockDevIntKv;
+pub use capped::CappedMemIntKv;
+pub use fs::{Durability, FileNaming, FsIntKv, LockMode, OpenReport, ReadStrategy};
+pub use http::{HttpClient, HttpIntKv};
+#[cfg(unix)]
+pub use logkv::LogIntKv;
 pub use mem::MemIntKv;
+#[cfg(feature = "redis")]
+pub use redis::RedisIntKv;
+#[cfg(feature = "sftp")]
+pub use sftp::{SftpIntKv, SshOptions};
+#[cfg(feature = "sled")]
+pub use sledkv::SledIntKv;
@@ -0,0 +1,502 @@
+//! Entries stored in Redis or a compatible server such as KeyDB, as keys
+//! `x79d8:<vault_id>:<index>`. Meant for scratch vaults shared by a team.
+//!
+//! Writes reach the server right away. `flush` only waits for the server,
+//! and refreshes the expiry of all keys if a TTL is set, so the vault
+//! expires as a whole once unused. Whether keys survive a restart of the
+//! server is up to its persistence settings.
+//!
+//! A writer holds the key `x79d8:<vault_id>:lock`, refreshed by a
+//! heartbeat thread, so other x79d8 instances do not write the same vault.
+//! Readers do not take it.
+
+mod resp;
+#[cfg(test)]
+mod stub;
+
+use super::super::{Bytes, IntKv, Stats};
+use super::LockMode;
+use parking_lot::Mutex;
+use resp::{Connection, Endpoint, Value};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Expiry of the lock key. The heartbeat refreshes it three times as often.
+const LOCK_TTL: Duration = Duration::from_secs(30);
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Commands sent before reading their replies.
+const PIPELINE_DEPTH: usize = 64;
+
+/// Keys asked for per `SCAN`.
+const SCAN_COUNT: &str = "1000";
+
+pub struct RedisIntKv {
+    endpoint: Endpoint,
+    /// `x79d8:<vault_id>:`.
+    prefix: String,
+    ttl: Option<Duration>,
+    /// None until connected, or after the connection broke.
+    conn: Mutex<Option<Connection>>,
+    heartbeat: Option<Heartbeat>,
+    commands: AtomicU64,
+    round_trips: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+/// The lock key, kept alive by a thread.
+struct Heartbeat {
+    key: String,
+    token: String,
+    /// Set once the lock key is gone or taken by others.
+    lost: Arc<AtomicBool>,
+    /// Dropped to stop the thread.
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+type Command<'a> = Vec<&'a [u8]>;
+
+impl RedisIntKv {
+    /// Open the vault `vault_id` on the server at `url`. With
+    /// `LockMode::Exclusive`, wait up to `wait` for the lock key. With a
+    /// `ttl`, keys expire if the vault is not flushed for that long.
+    pub fn open(
+        url: &str,
+        vault_id: &str,
+        ttl: Option<Duration>,
+        mode: LockMode,
+        wait: Duration,
+    ) -> io::Result<Self> {
+        let endpoint = Endpoint::parse(url)?;
+        let conn = endpoint.connect(TIMEOUT).map_err(|e| {
+            io::Error::new(e.kind(), format!("cannot connect to {}: {}", endpoint, e))
+        })?;
+        let prefix = format!("x79d8:{}:", vault_id);
+        let mut kv = Self {
+            endpoint,
+            prefix,
+            ttl,
+            conn: Mutex::new(Some(conn)),
+            heartbeat: None,
+            commands: AtomicU64::new(0),
+            round_trips: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+        };
+        if mode == LockMode::Exclusive {
+            kv.heartbeat = Some(kv.acquire_lock(wait)?);
+        }
+        Ok(kv)
+    }
+
+    fn acquire_lock(&self, wait: Duration) -> io::Result<Heartbeat> {
+        let key = format!("{}lock", self.prefix);
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        let ttl_ms = LOCK_TTL.as_millis().to_string();
+        let deadline = Instant::now() + wait;
+        loop {
+            let set: Command = vec![
+                b"SET",
+                key.as_bytes(),
+                token.as_bytes(),
+                b"NX",
+                b"PX",
+                ttl_ms.as_bytes(),
+            ];
+            match self.query(set)? {
+                Value::Nil if Instant::now() < deadline => std::thread::sleep(LOCK_RETRY_INTERVAL),
+                Value::Nil => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("{} on {} is in use", self.prefix, self.endpoint),
+                    ))
+                }
+                _ => break,
+            }
+        }
+        let lost = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = {
+            let endpoint = self.endpoint.clone();
+            let (key, token, lost) = (key.clone(), token.clone(), lost.clone());
+            std::thread::spawn(move || {
+                let mut conn = None;
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(LOCK_TTL / 3)
+                {
+                    match refresh_lock(&endpoint, &mut conn, &key, &token, &ttl_ms) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            log::error!("Lost the lock of {} on {}", key, endpoint);
+                            lost.store(true, Ordering::Release);
+                            return;
+                        }
+                        Err(e) => {
+                            log::warn!("Cannot refresh the lock on {}: {}", endpoint, e);
+                            conn = None;
+                        }
+                    }
+                }
+            })
+        };
+        Ok(Heartbeat {
+            key,
+            token,
+            lost,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Fail if the lock is lost, so a vault taken over by others is not
+    /// overwritten.
+    fn check_lock(&self) -> io::Result<()> {
+        match &self.heartbeat {
+            Some(h) if h.lost.load(Ordering::Acquire) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("lost the lock of {} on {}", self.prefix, self.endpoint),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn key(&self, index: usize) -> String {
+        format!("{}{}", self.prefix, index)
+    }
+
+    /// Send commands, pipelined. Return their replies. If the connection
+    /// breaks, connect again and retry once.
+    fn run(&self, commands: &[Command]) -> io::Result<Vec<io::Result<Value>>> {
+        let mut conn = self.conn.lock();
+        let mut retried = false;
+        loop {
+            if conn.is_none() {
+                *conn = Some(self.endpoint.connect(TIMEOUT)?);
+            }
+            match self.run_on(conn.as_mut().unwrap(), commands) {
+                Err(e) if resp::is_broken(&e) && !retried => {
+                    log::warn!(
+                        "Connection to {} broke ({}). Reconnecting.",
+                        self.endpoint,
+                        e
+                    );
+                    *conn = None;
+                    self.reconnects.fetch_add(1, Ordering::Relaxed);
+                    retried = true;
+                }
+                Err(e) => {
+                    *conn = None;
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn run_on(
+        &self,
+        conn: &mut Connection,
+        commands: &[Command],
+    ) -> io::Result<Vec<io::Result<Value>>> {
+        let mut replies = Vec::with_capacity(commands.len());
+        for chunk in commands.chunks(PIPELINE_DEPTH) {
+            for command in chunk {
+                conn.send(command)?;
+            }
+            replies.extend(conn.receive_all()?);
+            self.commands
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            self.round_trips.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(replies)
+    }
+
+    fn query(&self, command: Command) -> io::Result<Value> {
+        self.run(&[command])?.pop().unwrap()
+    }
+
+    fn set_command<'a>(&self, key: &'a str, data: &'a [u8], ttl_ms: &'a str) -> Command<'a> {
+        match self.ttl {
+            Some(_) => vec![b"SET", key.as_bytes(), data, b"PX", ttl_ms.as_bytes()],
+            None => vec![b"SET", key.as_bytes(), data],
+        }
+    }
+
+    fn ttl_ms(&self) -> String {
+        self.ttl.map_or(0, |t| t.as_millis().max(1)).to_string()
+    }
+}
+
+/// Extend the lock key if it still has `token`. Return false if not.
+fn refresh_lock(
+    endpoint: &Endpoint,
+    conn: &mut Option<Connection>,
+    key: &str,
+    token: &str,
+    ttl_ms: &str,
+) -> io::Result<bool> {
+    if conn.is_none() {
+        *conn = Some(endpoint.connect(TIMEOUT)?);
+    }
+    let conn = conn.as_mut().unwrap();
+    if conn.query(&[b"GET", key.as_bytes()])?.into_data()? != Some(token.as_bytes().to_vec()) {
+        return Ok(false);
+    }
+    let extended = conn.query(&[b"PEXPIRE", key.as_bytes(), ttl_ms.as_bytes()])?;
+    Ok(extended.into_int()? == 1)
+}
+
+impl fmt::Debug for RedisIntKv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisIntKv")
+            .field("endpoint", &self.endpoint)
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .field("locked", &self.heartbeat.is_some())
+            .finish()
+    }
+}
+
+impl IntKv for RedisIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.read_batch(&[index]).pop().unwrap()
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let keys: Vec<String> = indexes.iter().map(|&i| self.key(i)).collect();
+        let gets: Vec<Command> = keys
+            .iter()
+            .map(|k| vec![&b"GET"[..], k.as_bytes()])
+            .collect();
+        let replies = match self.run(&gets) {
+            Ok(replies) => replies,
+            Err(e) => {
+                return (0..indexes.len())
+                    .map(|_| Err(io::Error::new(e.kind(), e.to_string())))
+                    .collect()
+            }
+        };
+        replies
+            .into_iter()
+            .map(|reply| match reply?.into_data()? {
+                Some(data) => Ok(data.into()),
+                None => Err(io::ErrorKind::NotFound.into()),
+            })
+            .collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let (_, result) = self.write_batch(vec![(index, data)]);
+        result
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        if let Err(e) = self.check_lock() {
+            return (0, Err(e));
+        }
+        let keys: Vec<String> = items.iter().map(|(i, _)| self.key(*i)).collect();
+        let ttl_ms = self.ttl_ms();
+        let sets: Vec<Command> = keys
+            .iter()
+            .zip(&items)
+            .map(|(key, (_, data))| self.set_command(key, data, &ttl_ms))
+            .collect();
+        let replies = match self.run(&sets) {
+            Ok(replies) => replies,
+            Err(e) => return (0, Err(e)),
+        };
+        let mut written = 0;
+        for reply in replies {
+            if let Err(e) = reply {
+                return (written, Err(e));
+            }
+            written += 1;
+        }
+        (written, Ok(()))
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.check_lock()?;
+        let key = self.key(index);
+        match self.query(vec![b"DEL", key.as_bytes()])?.into_int()? {
+            0 => Err(io::ErrorKind::NotFound.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        let key = self.key(index);
+        Ok(self.query(vec![b"EXISTS", key.as_bytes()])?.into_int()? > 0)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let pattern = format!("{}*", self.prefix);
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+        loop {
+            let scan: Command = vec![
+                b"SCAN",
+                &cursor,
+                b"MATCH",
+                pattern.as_bytes(),
+                b"COUNT",
+                SCAN_COUNT.as_bytes(),
+            ];
+            let mut reply = self.query(scan)?.into_array()?.into_iter();
+            let (next, names) = match (reply.next(), reply.next()) {
+                (Some(next), Some(names)) => (next.into_data()?, names.into_array()?),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad SCAN reply")),
+            };
+            for name in names {
+                let name = name.into_data()?.unwrap_or_default();
+                let index = std::str::from_utf8(&name[self.prefix.len().min(name.len())..])
+                    .ok()
+                    .and_then(|s| s.parse::<usize>().ok());
+                // Skip the lock key.
+                keys.extend(index);
+            }
+            cursor = next.unwrap_or_default();
+            if cursor == b"0" {
+                break;
+            }
+        }
+        // SCAN can return a key more than once.
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_lock()?;
+        if self.ttl.is_none() {
+            self.query(vec![b"PING"])?;
+            return Ok(());
+        }
+        let keys: Vec<String> = self.keys()?.into_iter().map(|i| self.key(i)).collect();
+        let ttl_ms = self.ttl_ms();
+        let expires: Vec<Command> = keys
+            .iter()
+            .map(|key| vec![&b"PEXPIRE"[..], key.as_bytes(), ttl_ms.as_bytes()])
+            .collect();
+        for reply in self.run(&expires)? {
+            reply?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        stats.insert("redis.commands".into(), load(&self.commands));
+        stats.insert("redis.round_trips".into(), load(&self.round_trips));
+        stats.insert("redis.reconnects".into(), load(&self.reconnects));
+        stats
+    }
+}
+
+impl Drop for RedisIntKv {
+    fn drop(&mut self) {
+        let mut heartbeat = match self.heartbeat.take() {
+            Some(heartbeat) => heartbeat,
+            None => return,
+        };
+        drop(heartbeat.stop.take());
+        if let Some(thread) = heartbeat.thread.take() {
+            let _ = thread.join();
+        }
+        if heartbeat.lost.load(Ordering::Acquire) {
+            return;
+        }
+        // Release the lock unless it expired and was taken by others.
+        let key = heartbeat.key.as_bytes();
+        match self.query(vec![b"GET", key]).and_then(|v| v.into_data()) {
+            Ok(Some(token)) if token == heartbeat.token.as_bytes() => {
+                let _ = self.query(vec![b"DEL", key]);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_kv(stub: &stub::Stub, ttl: Option<Duration>) -> io::Result<RedisIntKv> {
+    let wait = Duration::from_secs(0);
+    RedisIntKv::open(&stub.url, "v1", ttl, LockMode::Exclusive, wait)
+}
+
+#[test]
+fn test_redis_int_kv() {
+    let stub = stub::Stub::start(Some("secret"));
+    let reload = |kv: Option<RedisIntKv>| {
+        drop(kv);
+        test_kv(&stub, None).unwrap()
+    };
+    let mut kv = super::super::test_int_kv(reload, 30);
+
+    // Batches are pipelined.
+    let round_trips = kv.stats()["redis.round_trips"];
+    let items: Vec<(usize, Bytes)> = (0..10).map(|i| (i, vec![i as u8].into())).collect();
+    assert_eq!(kv.write_batch(items).0, 10);
+    let reads = kv.read_batch(&[3, 30, 9]);
+    assert_eq!(reads[0].as_ref().unwrap().as_ref(), &[3]);
+    assert_eq!(
+        reads[1].as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(reads[2].as_ref().unwrap().as_ref(), &[9]);
+    assert_eq!(kv.stats()["redis.round_trips"], round_trips + 2);
+    let e = RedisIntKv::open(
+        &stub.url.replace("secret", "x"),
+        "v1",
+        None,
+        LockMode::Shared,
+        Duration::from_secs(0),
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("WRONGPASS"), "{}", e);
+}
+
+#[test]
+fn test_redis_int_kv_lock_and_ttl() {
+    let stub = stub::Stub::start(None);
+    let ttl = Some(Duration::from_secs(3600));
+    let mut kv = test_kv(&stub, ttl).unwrap();
+    kv.write(1, vec![1].into()).unwrap();
+    assert!(stub.ttl_ms("x79d8:v1:1").unwrap() > 3_000_000);
+    kv.flush().unwrap();
+
+    // Another writer waits for the lock. Readers do not.
+    let e = test_kv(&stub, ttl).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    let wait = Duration::from_secs(0);
+    let reader = RedisIntKv::open(&stub.url, "v1", ttl, LockMode::Shared, wait).unwrap();
+    assert_eq!(reader.keys().unwrap(), vec![1]);
+    let other = RedisIntKv::open(&stub.url, "v2", ttl, LockMode::Exclusive, wait).unwrap();
+    assert!(other.keys().unwrap().is_empty());
+
+    // The lock is released on drop.
+    drop(kv);
+    assert_eq!(stub.get("x79d8:v1:lock"), None);
+    let mut kv = test_kv(&stub, ttl).unwrap();
+
+    // Taken over by others. Writes fail once the heartbeat notices.
+    stub.set("x79d8:v1:lock", "someone else");
+    let h = kv.heartbeat.as_ref().unwrap();
+    let refreshed = refresh_lock(&kv.endpoint, &mut None, &h.key, &h.token, "1000");
+    assert!(!refreshed.unwrap());
+    // As the heartbeat thread would.
+    h.lost.store(true, Ordering::Release);
+    assert_eq!(
+        kv.write(2, vec![2].into()).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    drop(kv);
+    assert_eq!(stub.get("x79d8:v1:lock").as_deref(), Some("someone else"));
+    assert!(stub.connections() >= 4);
+}
@@ -0,0 +1,353 @@
+//! A small blocking Redis client speaking RESP2, for `RedisIntKv`.
+//! Commands can be queued and their replies read later, to pipeline them.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Longest bulk string accepted.
+const MAX_BULK: usize = 512 << 20;
+
+/// Deepest nesting of arrays accepted.
+const MAX_DEPTH: usize = 8;
+
+/// A reply, or a command sent by a client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Status(String),
+    Error(String),
+    Int(i64),
+    Data(Vec<u8>),
+    Array(Vec<Value>),
+}
+
+/// Where a server is and how to log in, from a
+/// `redis://[[user]:password@]host[:port][/db]` URL.
+#[derive(Clone, Default, PartialEq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub db: u32,
+}
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// Commands queued without their replies read.
+    queued: usize,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |message: &str| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", url, message))
+        };
+        let rest = match url.strip_prefix("redis://") {
+            Some(rest) => rest,
+            None if url.starts_with("rediss://") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{}: TLS is not supported", url),
+                ))
+            }
+            None => return Err(invalid("not a redis:// URL")),
+        };
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, 0),
+            Some((authority, db)) => (authority, db.parse().map_err(|_| invalid("bad db"))?),
+            None => (rest, 0),
+        };
+        let (user, password, address) = match authority.rsplit_once('@') {
+            Some((login, address)) => match login.split_once(':') {
+                Some((user, password)) => (
+                    (!user.is_empty()).then(|| user.to_string()),
+                    Some(password.to_string()),
+                    address,
+                ),
+                None => (Some(login.to_string()), None, address),
+            },
+            None => (None, None, authority),
+        };
+        let (host, port) = match address.strip_prefix('[') {
+            Some(v6) => v6.split_once(']').ok_or_else(|| invalid("bad host"))?,
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, port),
+                None => (address, ""),
+            },
+        };
+        let port = match port.strip_prefix(':').unwrap_or(port) {
+            "" => 6379,
+            port => port.parse().map_err(|_| invalid("bad port"))?,
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            user,
+            password,
+            db,
+        })
+    }
+
+    /// Connect and log in.
+    pub fn connect(&self, timeout: Duration) -> io::Result<Connection> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            queued: 0,
+        };
+        if let Some(password) = &self.password {
+            match &self.user {
+                Some(user) => conn.query(&[b"AUTH", user.as_bytes(), password.as_bytes()])?,
+                None => conn.query(&[b"AUTH", password.as_bytes()])?,
+            };
+        }
+        if self.db != 0 {
+            conn.query(&[b"SELECT", self.db.to_string().as_bytes()])?;
+        }
+        Ok(conn)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    /// The URL without the password.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redis://")?;
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port)?,
+            false => write!(f, "{}:{}", self.host, self.port)?,
+        }
+        if self.db != 0 {
+            write!(f, "/{}", self.db)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Connection {
+    /// Queue a command. Its reply is read by `receive`.
+    pub fn send(&mut self, args: &[&[u8]]) -> io::Result<()> {
+        write_command(&mut self.writer, args)?;
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Read the reply of the oldest queued command. Error replies become
+    /// errors. The connection stays usable after them.
+    pub fn receive(&mut self) -> io::Result<Value> {
+        if self.queued == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no command to receive a reply of",
+            ));
+        }
+        self.writer.flush()?;
+        let value = read_value(&mut self.reader)?;
+        self.queued -= 1;
+        match value {
+            Value::Error(message) => Err(io::Error::other(format!("redis: {}", message))),
+            value => Ok(value),
+        }
+    }
+
+    /// Send a command and read its reply.
+    pub fn query(&mut self, args: &[&[u8]]) -> io::Result<Value> {
+        self.send(args)?;
+        self.receive()
+    }
+
+    /// Read the replies of all queued commands, in order. Stop at a broken
+    /// connection, but not at error replies.
+    pub fn receive_all(&mut self) -> io::Result<Vec<io::Result<Value>>> {
+        let mut replies = Vec::with_capacity(self.queued);
+        while self.queued > 0 {
+            match self.receive() {
+                Err(e) if is_broken(&e) => return Err(e),
+                reply => replies.push(reply),
+            }
+        }
+        Ok(replies)
+    }
+}
+
+impl Value {
+    pub fn into_data(self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Value::Nil => Ok(None),
+            Value::Data(data) => Ok(Some(data)),
+            Value::Status(s) => Ok(Some(s.into_bytes())),
+            v => Err(unexpected(&v)),
+        }
+    }
+
+    pub fn into_int(self) -> io::Result<i64> {
+        match self {
+            Value::Int(n) => Ok(n),
+            v => Err(unexpected(&v)),
+        }
+    }
+
+    pub fn into_array(self) -> io::Result<Vec<Value>> {
+        match self {
+            Value::Array(values) => Ok(values),
+            v => Err(unexpected(&v)),
+        }
+    }
+}
+
+fn unexpected(value: &Value) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply from redis: {:?}", value),
+    )
+}
+
+/// Whether `e` leaves the connection unusable, as opposed to an error
+/// reply.
+pub fn is_broken(e: &io::Error) -> bool {
+    e.kind() != io::ErrorKind::Other
+}
+
+/// Write a command as an array of bulk strings.
+pub fn write_command(w: &mut impl Write, args: &[&[u8]]) -> io::Result<()> {
+    write!(w, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(w, "${}\r\n", arg.len())?;
+        w.write_all(arg)?;
+        w.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+/// Write a reply, for the test server.
+#[cfg(test)]
+pub fn write_value(w: &mut impl Write, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Nil => w.write_all(b"$-1\r\n"),
+        Value::Status(s) => write!(w, "+{}\r\n", s),
+        Value::Error(s) => write!(w, "-{}\r\n", s),
+        Value::Int(n) => write!(w, ":{}\r\n", n),
+        Value::Data(data) => {
+            write!(w, "${}\r\n", data.len())?;
+            w.write_all(data)?;
+            w.write_all(b"\r\n")
+        }
+        Value::Array(values) => {
+            write!(w, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(w, value)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn read_value(r: &mut impl BufRead) -> io::Result<Value> {
+    read_value_at(r, 0)
+}
+
+fn read_value_at(r: &mut impl BufRead, depth: usize) -> io::Result<Value> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut line = Vec::new();
+    r.take(64 << 10).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid("bad redis reply line"));
+    }
+    let text = String::from_utf8_lossy(&line[1..line.len() - 2]).into_owned();
+    let number = || -> io::Result<i64> { text.parse().map_err(|_| invalid("bad redis number")) };
+    match line[0] {
+        b'+' => Ok(Value::Status(text)),
+        b'-' => Ok(Value::Error(text)),
+        b':' => Ok(Value::Int(number()?)),
+        b'$' => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Value::Nil);
+            }
+            let len = len as usize;
+            if len > MAX_BULK {
+                return Err(invalid("redis bulk string is too long"));
+            }
+            let mut data = vec![0; len + 2];
+            r.read_exact(&mut data)?;
+            if !data.ends_with(b"\r\n") {
+                return Err(invalid("bad redis bulk string"));
+            }
+            data.truncate(len);
+            Ok(Value::Data(data))
+        }
+        b'*' => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Value::Nil);
+            }
+            if depth >= MAX_DEPTH {
+                return Err(invalid("redis reply is nested too deeply"));
+            }
+            let values = (0..len)
+                .map(|_| read_value_at(r, depth + 1))
+                .collect::<io::Result<_>>()?;
+            Ok(Value::Array(values))
+        }
+        _ => Err(invalid("bad redis reply type")),
+    }
+}
+
+#[test]
+fn test_endpoint_parse() {
+    let e = Endpoint::parse("redis://localhost").unwrap();
+    assert_eq!((e.host.as_str(), e.port, e.db), ("localhost", 6379, 0));
+    assert_eq!(e.password, None);
+    let e = Endpoint::parse("redis://:pw@10.0.0.1:7000/3").unwrap();
+    assert_eq!((e.host.as_str(), e.port, e.db), ("10.0.0.1", 7000, 3));
+    assert_eq!((e.user, e.password.as_deref()), (None, Some("pw")));
+    let e = Endpoint::parse("redis://me:p@ss@[::1]:7000/").unwrap();
+    assert_eq!(e.to_string(), "redis://me@[::1]:7000");
+    assert_eq!(e.password.as_deref(), Some("p@ss"));
+    assert!(Endpoint::parse("http://localhost").is_err());
+    assert!(Endpoint::parse("redis://host:x").is_err());
+    let e = Endpoint::parse("rediss://host").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn test_value_round_trip() {
+    let value = Value::Array(vec![
+        Value::Nil,
+        Value::Status("OK".into()),
+        Value::Error("ERR x".into()),
+        Value::Int(-3),
+        Value::Data(b"a\r\nb".to_vec()),
+        Value::Array(vec![]),
+    ]);
+    let mut buf = Vec::new();
+    write_value(&mut buf, &value).unwrap();
+    assert_eq!(read_value(&mut &buf[..]).unwrap(), value);
+    assert!(read_value(&mut &buf[..buf.len() - 1]).is_err());
+}
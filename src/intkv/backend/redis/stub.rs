@@ -0,0 +1,208 @@
+//! In-process Redis server for tests, keeping keys in memory. Supports
+//! what `RedisIntKv` uses.
+
+use super::resp::{read_value, write_value, Value};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct Stub {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Values with their expiry.
+    keys: HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)>,
+    password: Option<String>,
+    connections: usize,
+}
+
+impl Stub {
+    /// Listen on a local port. Clients must log in with `password` if set.
+    pub fn start(password: Option<&str>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State {
+            password: password.map(|p| p.to_string()),
+            ..Default::default()
+        }));
+        let url = match password {
+            Some(password) => format!("redis://:{}@127.0.0.1:{}", password, port),
+            None => format!("redis://127.0.0.1:{}", port),
+        };
+        let stub = Self { url, state };
+        let server = stub.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stub = server.clone();
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                stub.state.lock().connections += 1;
+                std::thread::spawn(move || {
+                    let _ = stub.serve(stream);
+                });
+            }
+        });
+        stub
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.lock().connections
+    }
+
+    /// Milliseconds until `key` expires. None if it has no expiry or does
+    /// not exist.
+    pub fn ttl_ms(&self, key: &str) -> Option<u64> {
+        let state = self.state.lock();
+        let (_, expiry) = state.keys.get(key.as_bytes())?;
+        expiry.map(|t| t.saturating_duration_since(Instant::now()).as_millis() as u64)
+    }
+
+    /// Set a key directly, ex. to play another instance.
+    pub fn set(&self, key: &str, value: &str) {
+        let mut state = self.state.lock();
+        state
+            .keys
+            .insert(key.into(), (value.as_bytes().to_vec(), None));
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock();
+        state.expire();
+        let (value, _) = state.keys.get(key.as_bytes())?;
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut authed = self.state.lock().password.is_none();
+        loop {
+            let args: Vec<Vec<u8>> = match read_value(&mut reader)? {
+                Value::Array(values) => values
+                    .into_iter()
+                    .map(|v| v.into_data().unwrap().unwrap())
+                    .collect(),
+                _ => return Ok(()),
+            };
+            let reply = match args[0].to_ascii_uppercase().as_slice() {
+                b"AUTH" => {
+                    let password = String::from_utf8_lossy(args.last().unwrap());
+                    authed = self.state.lock().password.as_deref() == Some(&*password);
+                    match authed {
+                        true => Value::Status("OK".into()),
+                        false => Value::Error("WRONGPASS invalid password".into()),
+                    }
+                }
+                _ if !authed => Value::Error("NOAUTH Authentication required.".into()),
+                _ => self.state.lock().run(&args),
+            };
+            write_value(&mut writer, &reply)?;
+            writer.flush()?;
+        }
+    }
+}
+
+impl State {
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.keys
+            .retain(|_, (_, expiry)| expiry.is_none_or(|t| t > now));
+    }
+
+    fn run(&mut self, args: &[Vec<u8>]) -> Value {
+        self.expire();
+        let number = |arg: &[u8]| -> u64 { String::from_utf8_lossy(arg).parse().unwrap() };
+        match args[0].to_ascii_uppercase().as_slice() {
+            b"PING" => Value::Status("PONG".into()),
+            b"SELECT" => Value::Status("OK".into()),
+            b"GET" => match self.keys.get(&args[1]) {
+                Some((value, _)) => Value::Data(value.clone()),
+                None => Value::Nil,
+            },
+            b"SET" => {
+                let mut expiry = None;
+                let mut condition = None;
+                let mut i = 3;
+                while i < args.len() {
+                    match args[i].to_ascii_uppercase().as_slice() {
+                        b"PX" => {
+                            expiry = Some(Duration::from_millis(number(&args[i + 1])));
+                            i += 1;
+                        }
+                        b"NX" => condition = Some(false),
+                        b"XX" => condition = Some(true),
+                        _ => return Value::Error("ERR syntax error".into()),
+                    }
+                    i += 1;
+                }
+                if condition.is_some_and(|exists| exists != self.keys.contains_key(&args[1])) {
+                    return Value::Nil;
+                }
+                let expiry = expiry.map(|d| Instant::now() + d);
+                self.keys.insert(args[1].clone(), (args[2].clone(), expiry));
+                Value::Status("OK".into())
+            }
+            b"DEL" => {
+                let removed = args[1..]
+                    .iter()
+                    .filter(|key| self.keys.remove(*key).is_some())
+                    .count();
+                Value::Int(removed as i64)
+            }
+            b"EXISTS" => {
+                let found = args[1..]
+                    .iter()
+                    .filter(|key| self.keys.contains_key(*key))
+                    .count();
+                Value::Int(found as i64)
+            }
+            b"PEXPIRE" => match self.keys.get_mut(&args[1]) {
+                Some((_, expiry)) => {
+                    *expiry = Some(Instant::now() + Duration::from_millis(number(&args[2])));
+                    Value::Int(1)
+                }
+                None => Value::Int(0),
+            },
+            b"SCAN" => {
+                // Return everything matching at once, in a few pages.
+                let prefix = match args.get(3) {
+                    Some(pattern) => pattern.strip_suffix(b"*").unwrap().to_vec(),
+                    None => Vec::new(),
+                };
+                let mut keys: Vec<Vec<u8>> = self
+                    .keys
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                keys.sort();
+                let cursor = number(&args[1]) as usize;
+                let page: Vec<Value> = keys
+                    .iter()
+                    .skip(cursor)
+                    .take(100)
+                    .cloned()
+                    .map(Value::Data)
+                    .collect();
+                let next = match cursor + page.len() >= keys.len() {
+                    true => 0,
+                    false => cursor + page.len(),
+                };
+                Value::Array(vec![
+                    Value::Data(next.to_string().into_bytes()),
+                    Value::Array(page),
+                ])
+            }
+            _ => Value::Error("ERR unknown command".into()),
+        }
+    }
+}
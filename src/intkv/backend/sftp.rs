@@ -0,0 +1,346 @@
+//! Entries stored as files of a directory on an SFTP server, reached by
+//! running `ssh`, so its config, keys and agent apply.
+//!
+//! `write` uploads entry `index` as `<index>p`. `flush` fsyncs the uploaded
+//! files if the server supports it, then renames them over `<index>`. A
+//! flush interrupted midway leaves some entries renamed.
+
+mod protocol;
+#[cfg(test)]
+mod stub;
+
+use super::super::{Bytes, IntKv, Stats};
+use parking_lot::Mutex;
+use protocol::{Session, Transport};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+type Connect = Box<dyn Fn() -> io::Result<Session> + Send + Sync>;
+
+pub struct SftpIntKv {
+    /// Description of the server, for messages.
+    remote: String,
+    /// Directory of entries on the server.
+    path: String,
+    connect: Connect,
+    /// None until connected, or after the connection broke.
+    session: Mutex<Option<Session>>,
+    /// Entries on the server.
+    entries: BTreeSet<usize>,
+    /// Changes not yet flushed. True if uploaded as a pending file, false
+    /// if removed.
+    pending: BTreeMap<usize, bool>,
+    reconnects: AtomicU64,
+}
+
+/// How to reach an SFTP server by `ssh`.
+#[derive(Debug, Clone, Default)]
+pub struct SshOptions {
+    pub host: String,
+    /// None: the default of `ssh`.
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Private key to use instead of the agent and the default keys.
+    pub key_file: Option<String>,
+}
+
+impl SftpIntKv {
+    /// Open the directory `path` on the server, creating it if missing.
+    pub fn new(ssh: SshOptions, path: &str) -> io::Result<Self> {
+        let remote = match &ssh.user {
+            Some(user) => format!("{}@{}", user, ssh.host),
+            None => ssh.host.clone(),
+        };
+        let connect = Box::new(move || spawn_ssh(&ssh));
+        Self::with_connect(remote, path, connect)
+    }
+
+    fn with_connect(remote: String, path: &str, connect: Connect) -> io::Result<Self> {
+        let mut kv = Self {
+            remote,
+            path: path.trim_end_matches('/').to_string(),
+            connect,
+            session: Mutex::new(None),
+            entries: BTreeSet::new(),
+            pending: BTreeMap::new(),
+            reconnects: AtomicU64::new(0),
+        };
+        let dir = kv.path.clone();
+        let names = kv.with_session(|s| {
+            s.make_dir(&dir)?;
+            s.list_dir(&dir)
+        })?;
+        let mut stale = Vec::new();
+        for name in names {
+            if let Ok(index) = name.parse::<usize>() {
+                kv.entries.insert(index);
+            } else if name
+                .strip_suffix('p')
+                .is_some_and(|n| n.parse::<usize>().is_ok())
+            {
+                stale.push(format!("{}/{}", dir, name));
+            }
+        }
+        if !stale.is_empty() {
+            log::info!("Removing {} files of an interrupted write", stale.len());
+            kv.with_session(|s| s.remove_files(&stale))?;
+        }
+        Ok(kv)
+    }
+
+    /// Run `op` with the session, connecting if needed. If the connection
+    /// breaks, connect again and retry once.
+    fn with_session<T>(&self, mut op: impl FnMut(&mut Session) -> io::Result<T>) -> io::Result<T> {
+        let mut session = self.session.lock();
+        let mut retried = false;
+        loop {
+            if session.is_none() {
+                *session = Some((self.connect)()?);
+            }
+            match op(session.as_mut().unwrap()) {
+                Err(e) if is_disconnected(&e) && !retried => {
+                    log::warn!("Connection to {} broke ({}). Reconnecting.", self.remote, e);
+                    *session = None;
+                    self.reconnects.fetch_add(1, Ordering::Relaxed);
+                    retried = true;
+                }
+                Err(e) if is_disconnected(&e) => {
+                    *session = None;
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn entry_path(&self, index: usize) -> String {
+        format!("{}/{}", self.path, index)
+    }
+
+    fn pending_path(&self, index: usize) -> String {
+        format!("{}/{}p", self.path, index)
+    }
+}
+
+impl fmt::Debug for SftpIntKv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpIntKv")
+            .field("remote", &self.remote)
+            .field("path", &self.path)
+            .field("entries", &self.entries.len())
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl IntKv for SftpIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let path = match self.pending.get(&index) {
+            Some(true) => self.pending_path(index),
+            Some(false) => return Err(io::ErrorKind::NotFound.into()),
+            None if self.entries.contains(&index) => self.entry_path(index),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let data = self.with_session(|s| s.read_file(&path))?;
+        Ok(data.into())
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let path = self.pending_path(index);
+        self.with_session(|s| s.write_file(&path, &data))?;
+        self.pending.insert(index, true);
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if !self.has(index)? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if self.pending.get(&index) == Some(&true) {
+            let path = self.pending_path(index);
+            self.with_session(|s| s.remove_files(std::slice::from_ref(&path)))?;
+        }
+        self.pending.insert(index, false);
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        Ok(match self.pending.get(&index) {
+            Some(&uploaded) => uploaded,
+            None => self.entries.contains(&index),
+        })
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys = self.entries.clone();
+        for (&index, &uploaded) in &self.pending {
+            match uploaded {
+                true => keys.insert(index),
+                false => keys.remove(&index),
+            };
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut uploaded = Vec::new();
+        let mut renames = Vec::new();
+        let mut removes = Vec::new();
+        for (&index, &is_uploaded) in &self.pending {
+            match is_uploaded {
+                true => {
+                    uploaded.push(self.pending_path(index));
+                    renames.push((self.pending_path(index), self.entry_path(index)));
+                }
+                false => removes.push(self.entry_path(index)),
+            }
+        }
+        self.with_session(|s| {
+            s.fsync_files(&uploaded)?;
+            s.rename_files(&renames)?;
+            s.remove_files(&removes)
+        })?;
+        for (index, uploaded) in std::mem::take(&mut self.pending) {
+            match uploaded {
+                true => self.entries.insert(index),
+                false => self.entries.remove(&index),
+            };
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        stats.insert("sftp.entries".into(), self.entries.len() as u64);
+        stats.insert("sftp.pending".into(), self.pending.len() as u64);
+        let reconnects = self.reconnects.load(Ordering::Relaxed);
+        stats.insert("sftp.reconnects".into(), reconnects);
+        stats
+    }
+}
+
+/// Start `ssh` running the SFTP subsystem.
+fn spawn_ssh(ssh: &SshOptions) -> io::Result<Session> {
+    let mut command = Command::new("ssh");
+    // Prompts would read the pipe.
+    command.args(["-o", "BatchMode=yes"]);
+    if let Some(port) = ssh.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    if let Some(user) = &ssh.user {
+        command.arg("-l").arg(user);
+    }
+    if let Some(key_file) = &ssh.key_file {
+        command.arg("-i").arg(key_file);
+        command.args(["-o", "IdentitiesOnly=yes"]);
+    }
+    command.arg("-s").arg(&ssh.host).arg("sftp");
+    command.stdin(Stdio::piped()).stdout(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run ssh: {}", e)))?;
+    let transport: Transport = (
+        Box::new(child.stdout.take().unwrap()),
+        Box::new(child.stdin.take().unwrap()),
+    );
+    Session::new(transport, Some(child)).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("cannot start SFTP on {} (see the ssh output)", ssh.host),
+        ),
+        _ => e,
+    })
+}
+
+/// Whether `e` means the connection is gone.
+fn is_disconnected(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+fn test_kv(stub: &stub::Stub) -> io::Result<SftpIntKv> {
+    let stub = stub.clone();
+    let connect = Box::new(move || stub.connect());
+    SftpIntKv::with_connect("stub".into(), "/x79d8/", connect)
+}
+
+#[test]
+fn test_sftp_int_kv() {
+    for extensions in [true, false] {
+        let stub = stub::Stub::start(extensions);
+        let reload = |kv: Option<SftpIntKv>| {
+            drop(kv);
+            test_kv(&stub).unwrap()
+        };
+        super::super::test_int_kv(reload, 30);
+        assert!(!stub.path("x79d8/5").exists());
+    }
+}
+
+#[test]
+fn test_sftp_int_kv_reconnect() {
+    let stub = stub::Stub::start(true);
+    let mut kv = test_kv(&stub).unwrap();
+    let data = vec![7; protocol::CHUNK_SIZE * 3];
+    stub.drop_after(2, 1);
+    kv.write(1, data.clone().into()).unwrap();
+    assert_eq!(kv.read(1).unwrap().as_ref(), &data[..]);
+    assert_eq!(kv.stats()["sftp.reconnects"], 1);
+
+    // A flush broken after some renames is retried.
+    for i in 2..10 {
+        kv.write(i, vec![i as u8].into()).unwrap();
+    }
+    kv.remove(1).unwrap();
+    stub.drop_after(25, 1);
+    kv.flush().unwrap();
+    assert_eq!(kv.stats()["sftp.reconnects"], 2);
+    assert_eq!(stub.connections(), 3);
+    let kv = test_kv(&stub).unwrap();
+    assert_eq!(kv.keys().unwrap(), (2..10).collect::<Vec<_>>());
+    assert_eq!(kv.read(9).unwrap().as_ref(), &[9]);
+
+    // Broken twice in a row.
+    let kv = test_kv(&stub).unwrap();
+    stub.drop_after(0, 2);
+    let e = kv.read(2).unwrap_err();
+    assert!(is_disconnected(&e), "{}", e);
+    assert_eq!(kv.read(2).unwrap().as_ref(), &[2]);
+}
+
+/// Against a real server: `X79D8_TEST_SFTP=[user@]host:path`, with keys
+/// accepted without prompts. For example an openssh container.
+#[test]
+fn test_sftp_int_kv_ssh() {
+    let target = match std::env::var("X79D8_TEST_SFTP") {
+        Ok(target) => target,
+        Err(_) => return,
+    };
+    let (remote, path) = target.split_once(':').expect("[user@]host:path");
+    let (user, host) = match remote.split_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, remote),
+    };
+    let ssh = SshOptions {
+        host: host.to_string(),
+        user,
+        ..Default::default()
+    };
+    let reload = |kv: Option<SftpIntKv>| {
+        drop(kv);
+        SftpIntKv::new(ssh.clone(), path).unwrap()
+    };
+    super::super::test_int_kv(reload, 10);
+}
@@ -0,0 +1,542 @@
+//! Client of SFTP version 3, spoken over the stdin and stdout of `ssh -s
+//! sftp`. Uses the `posix-rename@openssh.com` and `fsync@openssh.com`
+//! extensions of OpenSSH if the server has them.
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process::Child;
+
+pub const FXP_INIT: u8 = 1;
+pub const FXP_VERSION: u8 = 2;
+pub const FXP_OPEN: u8 = 3;
+pub const FXP_CLOSE: u8 = 4;
+pub const FXP_READ: u8 = 5;
+pub const FXP_WRITE: u8 = 6;
+pub const FXP_FSTAT: u8 = 8;
+pub const FXP_OPENDIR: u8 = 11;
+pub const FXP_READDIR: u8 = 12;
+pub const FXP_REMOVE: u8 = 13;
+pub const FXP_MKDIR: u8 = 14;
+pub const FXP_STAT: u8 = 17;
+pub const FXP_RENAME: u8 = 18;
+pub const FXP_STATUS: u8 = 101;
+pub const FXP_HANDLE: u8 = 102;
+pub const FXP_DATA: u8 = 103;
+pub const FXP_NAME: u8 = 104;
+pub const FXP_ATTRS: u8 = 105;
+pub const FXP_EXTENDED: u8 = 200;
+
+pub const FXF_READ: u32 = 1;
+pub const FXF_WRITE: u32 = 2;
+pub const FXF_CREAT: u32 = 8;
+pub const FXF_TRUNC: u32 = 0x10;
+
+pub const FX_OK: u32 = 0;
+pub const FX_EOF: u32 = 1;
+pub const FX_NO_SUCH_FILE: u32 = 2;
+pub const FX_PERMISSION_DENIED: u32 = 3;
+pub const FX_OP_UNSUPPORTED: u32 = 8;
+
+pub const ATTR_SIZE: u32 = 1;
+
+pub const POSIX_RENAME: &str = "posix-rename@openssh.com";
+pub const FSYNC: &str = "fsync@openssh.com";
+
+/// Bytes read or written by one request. Servers accept at least this.
+pub const CHUNK_SIZE: usize = 32 << 10;
+
+/// Requests sent before waiting for responses. Bounded so neither side
+/// blocks on a full pipe.
+const WINDOW: usize = 64;
+
+/// Largest packet accepted.
+const MAX_PACKET: usize = 1 << 20;
+
+/// Builds the payload of a packet.
+#[derive(Default)]
+pub struct Encoder(pub Vec<u8>);
+
+impl Encoder {
+    pub fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    /// Length-prefixed bytes, also used for strings.
+    pub fn bytes(mut self, v: &[u8]) -> Self {
+        self = self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    pub fn str(self, v: &str) -> Self {
+        self.bytes(v.as_bytes())
+    }
+}
+
+/// Parses the payload of a packet.
+pub struct Decoder<'a>(pub &'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated SFTP packet",
+            ));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    /// Attributes. Return the size if present.
+    pub fn attrs(&mut self) -> io::Result<Option<u64>> {
+        let flags = self.u32()?;
+        let size = match flags & ATTR_SIZE {
+            0 => None,
+            _ => Some(self.u64()?),
+        };
+        // uid and gid, permissions, atime and mtime.
+        for (flag, len) in [(2, 8), (4, 4), (8, 8)] {
+            if flags & flag != 0 {
+                self.take(len)?;
+            }
+        }
+        if flags & 0x8000_0000 != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(size)
+    }
+}
+
+pub fn write_packet(w: &mut dyn Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    w.write_all(&(payload.len() as u32 + 1).to_be_bytes())?;
+    w.write_all(&[kind])?;
+    w.write_all(payload)
+}
+
+pub fn read_packet(r: &mut dyn Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid SFTP packet length {}", len),
+        ));
+    }
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+    let kind = data.remove(0);
+    Ok((kind, data))
+}
+
+/// Stdout and stdin of the server.
+pub type Transport = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+/// A request of `Session::batch`: the packet type, and the payload after
+/// the request id.
+pub type Request = (u8, Vec<u8>);
+
+/// A response: the packet type, and the payload after the request id.
+pub type Response = (u8, Vec<u8>);
+
+pub struct Session {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    next_id: u32,
+    extensions: HashSet<String>,
+    /// The ssh process, killed on drop.
+    child: Option<Child>,
+}
+
+impl Session {
+    /// Negotiate the version over `transport`.
+    pub fn new(transport: Transport, child: Option<Child>) -> io::Result<Self> {
+        let (reader, writer) = transport;
+        let mut session = Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            next_id: 0,
+            extensions: HashSet::new(),
+            child,
+        };
+        write_packet(&mut session.writer, FXP_INIT, &Encoder::default().u32(3).0)?;
+        session.writer.flush()?;
+        let (kind, payload) = read_packet(&mut session.reader)?;
+        if kind != FXP_VERSION {
+            return Err(unexpected(kind));
+        }
+        let mut decoder = Decoder(&payload);
+        let version = decoder.u32()?;
+        if version < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("SFTP version {} is not supported", version),
+            ));
+        }
+        while !decoder.is_empty() {
+            let name = decoder.string()?;
+            decoder.bytes()?;
+            session.extensions.insert(name);
+        }
+        Ok(session)
+    }
+
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.contains(name)
+    }
+
+    /// Send `requests`, keeping up to `WINDOW` of them in flight. Return
+    /// the responses in the order of the requests.
+    pub fn batch(&mut self, requests: Vec<Request>) -> io::Result<Vec<Response>> {
+        let first_id = self.next_id;
+        let count = requests.len();
+        let mut requests = requests.into_iter();
+        let mut responses = BTreeMap::new();
+        let mut sent = 0;
+        while responses.len() < count {
+            while sent - responses.len() < WINDOW {
+                let (kind, payload) = match requests.next() {
+                    Some(request) => request,
+                    None => break,
+                };
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                let mut packet = id.to_be_bytes().to_vec();
+                packet.extend_from_slice(&payload);
+                write_packet(&mut self.writer, kind, &packet)?;
+                sent += 1;
+            }
+            self.writer.flush()?;
+            let (kind, payload) = read_packet(&mut self.reader)?;
+            let mut decoder = Decoder(&payload);
+            let offset = decoder.u32()?.wrapping_sub(first_id) as usize;
+            if offset >= sent || responses.contains_key(&offset) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected SFTP response id",
+                ));
+            }
+            responses.insert(offset, (kind, decoder.0.to_vec()));
+        }
+        Ok(responses.into_values().collect())
+    }
+
+    fn call(&mut self, kind: u8, payload: Vec<u8>) -> io::Result<Response> {
+        Ok(self.batch(vec![(kind, payload)])?.pop().unwrap())
+    }
+
+    pub fn open(&mut self, path: &str, flags: u32) -> io::Result<Vec<u8>> {
+        let response = self.call(FXP_OPEN, open_request(path, flags))?;
+        handle(response, path)
+    }
+
+    pub fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let response = self.call(FXP_CLOSE, Encoder::default().bytes(handle).0)?;
+        status(response, "close")
+    }
+
+    /// Read a whole file.
+    pub fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let handle = self.open(path, FXF_READ)?;
+        let result = self.read_handle(&handle, path);
+        let closed = self.close(&handle);
+        let data = result?;
+        closed?;
+        Ok(data)
+    }
+
+    fn read_handle(&mut self, handle: &[u8], path: &str) -> io::Result<Vec<u8>> {
+        let (kind, payload) = self.call(FXP_FSTAT, Encoder::default().bytes(handle).0)?;
+        let size = match kind {
+            FXP_ATTRS => Decoder(&payload).attrs()?,
+            _ => return Err(status((kind, payload), path).unwrap_err()),
+        };
+        let size = match size {
+            Some(size) => size as usize,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: the server did not tell the size", path),
+                ))
+            }
+        };
+        let requests = (0..size)
+            .step_by(CHUNK_SIZE)
+            .map(|offset| {
+                let len = (size - offset).min(CHUNK_SIZE) as u32;
+                (
+                    FXP_READ,
+                    Encoder::default()
+                        .bytes(handle)
+                        .u64(offset as u64)
+                        .u32(len)
+                        .0,
+                )
+            })
+            .collect();
+        let mut data = Vec::with_capacity(size);
+        for (kind, payload) in self.batch(requests)? {
+            match kind {
+                FXP_DATA => data.extend_from_slice(Decoder(&payload).bytes()?),
+                _ => return Err(status((kind, payload), path).unwrap_err()),
+            }
+        }
+        // A short read means the file changed meanwhile.
+        if data.len() != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{}: read {} of {} bytes", path, data.len(), size),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Create or replace a file with `data`.
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        let handle = self.open(path, FXF_WRITE | FXF_CREAT | FXF_TRUNC)?;
+        let requests = data
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let offset = (i * CHUNK_SIZE) as u64;
+                (
+                    FXP_WRITE,
+                    Encoder::default().bytes(&handle).u64(offset).bytes(chunk).0,
+                )
+            })
+            .collect();
+        let result = self
+            .batch(requests)
+            .and_then(|responses| responses.into_iter().try_for_each(|r| status(r, path)));
+        let closed = self.close(&handle);
+        result?;
+        closed
+    }
+
+    /// Fsync files. Do nothing if the server cannot.
+    pub fn fsync_files(&mut self, paths: &[String]) -> io::Result<()> {
+        if !self.has_extension(FSYNC) || paths.is_empty() {
+            return Ok(());
+        }
+        let opens = paths
+            .iter()
+            .map(|path| (FXP_OPEN, open_request(path, FXF_WRITE)))
+            .collect();
+        let mut handles = Vec::with_capacity(paths.len());
+        for (response, path) in self.batch(opens)?.into_iter().zip(paths) {
+            match handle(response, path) {
+                // Renamed by an interrupted flush.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => handles.push((result?, path)),
+            }
+        }
+        let fsyncs = handles
+            .iter()
+            .map(|(h, _)| (FXP_EXTENDED, Encoder::default().str(FSYNC).bytes(h).0))
+            .collect();
+        let synced = self.batch(fsyncs).and_then(|responses| {
+            let paths = handles.iter().map(|(_, path)| path);
+            responses
+                .into_iter()
+                .zip(paths)
+                .try_for_each(|(r, path)| status(r, path))
+        });
+        let closes = handles
+            .iter()
+            .map(|(h, _)| (FXP_CLOSE, Encoder::default().bytes(h).0))
+            .collect();
+        let closed = self
+            .batch(closes)
+            .and_then(|responses| responses.into_iter().try_for_each(|r| status(r, "close")));
+        synced?;
+        closed
+    }
+
+    /// Rename files over others. Pairs whose source is missing are skipped,
+    /// as an interrupted run might have renamed them.
+    pub fn rename_files(&mut self, pairs: &[(String, String)]) -> io::Result<()> {
+        if !self.has_extension(POSIX_RENAME) {
+            // Plain renames refuse to replace files. Targets of renamed
+            // sources are the new files, and stay.
+            let stats = pairs
+                .iter()
+                .map(|(from, _)| (FXP_STAT, Encoder::default().str(from).0))
+                .collect();
+            let targets: Vec<String> = self
+                .batch(stats)?
+                .into_iter()
+                .zip(pairs)
+                .filter(|((kind, _), _)| *kind == FXP_ATTRS)
+                .map(|(_, (_, to))| to.clone())
+                .collect();
+            self.remove_files(&targets)?;
+        }
+        let requests = pairs
+            .iter()
+            .map(|(from, to)| match self.has_extension(POSIX_RENAME) {
+                true => (
+                    FXP_EXTENDED,
+                    Encoder::default().str(POSIX_RENAME).str(from).str(to).0,
+                ),
+                false => (FXP_RENAME, Encoder::default().str(from).str(to).0),
+            })
+            .collect();
+        for (response, (from, _)) in self.batch(requests)?.into_iter().zip(pairs) {
+            match status(response, from) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove files. Missing files are skipped.
+    pub fn remove_files(&mut self, paths: &[String]) -> io::Result<()> {
+        let requests = paths
+            .iter()
+            .map(|path| (FXP_REMOVE, Encoder::default().str(path).0))
+            .collect();
+        for (response, path) in self.batch(requests)?.into_iter().zip(paths) {
+            match status(response, path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Names in a directory, excluding "." and "..".
+    pub fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let response = self.call(FXP_OPENDIR, Encoder::default().str(path).0)?;
+        let handle = handle(response, path)?;
+        let mut names = Vec::new();
+        let result = loop {
+            let (kind, payload) = self.call(FXP_READDIR, Encoder::default().bytes(&handle).0)?;
+            if kind != FXP_NAME {
+                break match status_code(kind, &payload) {
+                    Some(FX_EOF) => Ok(()),
+                    _ => status((kind, payload), path).and(Err(unexpected(FXP_STATUS))),
+                };
+            }
+            let mut decoder = Decoder(&payload);
+            for _ in 0..decoder.u32()? {
+                let name = decoder.string()?;
+                decoder.string()?;
+                decoder.attrs()?;
+                if name != "." && name != ".." {
+                    names.push(name);
+                }
+            }
+        };
+        let closed = self.close(&handle);
+        result?;
+        closed?;
+        Ok(names)
+    }
+
+    /// Create a directory unless it exists.
+    pub fn make_dir(&mut self, path: &str) -> io::Result<()> {
+        let response = self.call(FXP_STAT, Encoder::default().str(path).0)?;
+        if response.0 == FXP_ATTRS {
+            return Ok(());
+        }
+        match status(response, path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => return result,
+        }
+        let response = self.call(FXP_MKDIR, Encoder::default().str(path).u32(0).0)?;
+        status(response, path)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn open_request(path: &str, flags: u32) -> Vec<u8> {
+    // No attributes.
+    Encoder::default().str(path).u32(flags).u32(0).0
+}
+
+/// Code of a status response.
+fn status_code(kind: u8, payload: &[u8]) -> Option<u32> {
+    match kind {
+        FXP_STATUS => Decoder(payload).u32().ok(),
+        _ => None,
+    }
+}
+
+fn handle((kind, payload): Response, path: &str) -> io::Result<Vec<u8>> {
+    match kind {
+        FXP_HANDLE => Ok(Decoder(&payload).bytes()?.to_vec()),
+        _ => Err(status((kind, payload), path).unwrap_err()),
+    }
+}
+
+/// Turn a status response into a result. Status codes map to error kinds.
+fn status((kind, payload): Response, path: &str) -> io::Result<()> {
+    if kind != FXP_STATUS {
+        return Err(unexpected(kind));
+    }
+    let mut decoder = Decoder(&payload);
+    let code = decoder.u32()?;
+    let message = decoder.string().unwrap_or_default();
+    let kind = match code {
+        FX_OK => return Ok(()),
+        FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+        FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        FX_OP_UNSUPPORTED => io::ErrorKind::Unsupported,
+        // No connection, or connection lost.
+        6 | 7 => io::ErrorKind::ConnectionAborted,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(
+        kind,
+        format!("{}: {} (SFTP status {})", path, message, code),
+    ))
+}
+
+fn unexpected(kind: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected SFTP packet type {}", kind),
+    )
+}
@@ -0,0 +1,236 @@
+//! In-process SFTP server for tests, serving a temporary directory over
+//! pipes. Supports what `SftpIntKv` uses.
+
+use super::protocol::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const FX_FAILURE: u32 = 4;
+
+#[derive(Clone)]
+pub struct Stub {
+    dir: Arc<tempfile::TempDir>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Advertise the OpenSSH extensions.
+    extensions: bool,
+    /// Close connections after answering this many packets, and how many
+    /// connections to close.
+    drops: Option<(usize, usize)>,
+    /// Packets answered since the last close.
+    answered: usize,
+    connections: usize,
+}
+
+enum Handle {
+    File(File),
+    /// Names not yet returned.
+    Dir(Option<Vec<String>>),
+}
+
+impl Stub {
+    pub fn start(extensions: bool) -> Self {
+        let state = State {
+            extensions,
+            ..Default::default()
+        };
+        Self {
+            dir: Arc::new(tempfile::tempdir().unwrap()),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.path().join(path.trim_start_matches('/'))
+    }
+
+    /// Break the connection after `count` more answers, `times` times.
+    pub fn drop_after(&self, count: usize, times: usize) {
+        let mut state = self.state.lock();
+        state.drops = Some((count, times));
+        state.answered = 0;
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.lock().connections
+    }
+
+    /// Start serving a connection.
+    pub fn connect(&self) -> io::Result<Session> {
+        let (client_reader, server_writer) = io::pipe()?;
+        let (server_reader, client_writer) = io::pipe()?;
+        let stub = self.clone();
+        stub.state.lock().connections += 1;
+        std::thread::spawn(move || {
+            let _ = stub.serve(server_reader, server_writer);
+        });
+        Session::new((Box::new(client_reader), Box::new(client_writer)), None)
+    }
+
+    fn serve(&self, mut reader: io::PipeReader, mut writer: io::PipeWriter) -> io::Result<()> {
+        let (kind, _) = read_packet(&mut reader)?;
+        assert_eq!(kind, FXP_INIT);
+        let mut version = Encoder::default().u32(3);
+        if self.state.lock().extensions {
+            version = version.str(POSIX_RENAME).str("1").str(FSYNC).str("1");
+        }
+        write_packet(&mut writer, FXP_VERSION, &version.0)?;
+        let mut handles = HashMap::new();
+        let mut next_handle = 0u32;
+        loop {
+            let (kind, payload) = read_packet(&mut reader)?;
+            {
+                let mut state = self.state.lock();
+                if let Some((count, times)) = state.drops {
+                    if state.answered == count {
+                        state.drops = (times > 1).then_some((count, times - 1));
+                        state.answered = 0;
+                        return Ok(());
+                    }
+                }
+                state.answered += 1;
+            }
+            let mut decoder = Decoder(&payload);
+            let id = decoder.u32()?;
+            let (kind, reply) = match self.handle(kind, &mut decoder, &mut handles) {
+                Ok(Some(handle)) => {
+                    next_handle += 1;
+                    let name = next_handle.to_be_bytes().to_vec();
+                    handles.insert(name.clone(), handle);
+                    (FXP_HANDLE, Encoder::default().bytes(&name))
+                }
+                Ok(None) => (FXP_STATUS, status_reply(FX_OK, "")),
+                Err(Reply(kind, reply)) => (kind, reply),
+            };
+            let mut packet = Encoder::default().u32(id);
+            packet.0.extend_from_slice(&reply.0);
+            write_packet(&mut writer, kind, &packet.0)?;
+        }
+    }
+
+    /// Handle a request. Return a new handle, None for an OK status, or
+    /// another reply as the error.
+    fn handle(
+        &self,
+        kind: u8,
+        d: &mut Decoder,
+        handles: &mut HashMap<Vec<u8>, Handle>,
+    ) -> Result<Option<Handle>, Reply> {
+        match kind {
+            FXP_OPEN => {
+                let path = self.path(&d.string()?);
+                let flags = d.u32()?;
+                let file = OpenOptions::new()
+                    .read(flags & FXF_READ != 0)
+                    .write(flags & FXF_WRITE != 0)
+                    .create(flags & FXF_CREAT != 0)
+                    .truncate(flags & FXF_TRUNC != 0)
+                    .open(path)?;
+                Ok(Some(Handle::File(file)))
+            }
+            FXP_OPENDIR => {
+                let mut names = vec![".".to_string(), "..".to_string()];
+                for entry in fs::read_dir(self.path(&d.string()?))? {
+                    names.push(entry?.file_name().to_string_lossy().into_owned());
+                }
+                Ok(Some(Handle::Dir(Some(names))))
+            }
+            FXP_CLOSE => match handles.remove(d.bytes()?) {
+                Some(_) => Ok(None),
+                None => Err(status_error(FX_FAILURE, "bad handle")),
+            },
+            FXP_READ | FXP_WRITE | FXP_FSTAT | FXP_READDIR => {
+                let handle = handles.get_mut(d.bytes()?);
+                match (kind, handle) {
+                    (FXP_READ, Some(Handle::File(file))) => {
+                        let offset = d.u64()?;
+                        let mut data = vec![0; d.u32()? as usize];
+                        let len = file.read_at(&mut data, offset)?;
+                        if len == 0 {
+                            return Err(status_error(FX_EOF, "end of file"));
+                        }
+                        Err(Reply(FXP_DATA, Encoder::default().bytes(&data[..len])))
+                    }
+                    (FXP_WRITE, Some(Handle::File(file))) => {
+                        let offset = d.u64()?;
+                        file.write_all_at(d.bytes()?, offset)?;
+                        Ok(None)
+                    }
+                    (FXP_FSTAT, Some(Handle::File(file))) => Err(attrs(&file.metadata()?)),
+                    (FXP_READDIR, Some(Handle::Dir(names))) => match names.take() {
+                        Some(names) => {
+                            let mut reply = Encoder::default().u32(names.len() as u32);
+                            for name in names {
+                                reply = reply.str(&name).str(&name).u32(0);
+                            }
+                            Err(Reply(FXP_NAME, reply))
+                        }
+                        None => Err(status_error(FX_EOF, "end of directory")),
+                    },
+                    _ => Err(status_error(FX_FAILURE, "bad handle")),
+                }
+            }
+            FXP_STAT => Err(attrs(&fs::metadata(self.path(&d.string()?))?)),
+            FXP_MKDIR => Ok(fs::create_dir(self.path(&d.string()?)).map(|_| None)?),
+            FXP_REMOVE => Ok(fs::remove_file(self.path(&d.string()?)).map(|_| None)?),
+            FXP_RENAME => {
+                let from = self.path(&d.string()?);
+                let to = self.path(&d.string()?);
+                if to.exists() {
+                    return Err(status_error(FX_FAILURE, "target exists"));
+                }
+                Ok(fs::rename(from, to).map(|_| None)?)
+            }
+            FXP_EXTENDED if self.state.lock().extensions => match d.string()?.as_str() {
+                POSIX_RENAME => {
+                    let from = self.path(&d.string()?);
+                    let to = self.path(&d.string()?);
+                    Ok(fs::rename(from, to).map(|_| None)?)
+                }
+                FSYNC => match handles.get(d.bytes()?) {
+                    Some(Handle::File(file)) => Ok(file.sync_all().map(|_| None)?),
+                    _ => Err(status_error(FX_FAILURE, "bad handle")),
+                },
+                _ => Err(status_error(FX_OP_UNSUPPORTED, "unknown extension")),
+            },
+            _ => Err(status_error(FX_OP_UNSUPPORTED, "unsupported")),
+        }
+    }
+}
+
+/// A reply other than a new handle or an OK status.
+struct Reply(u8, Encoder);
+
+impl From<io::Error> for Reply {
+    fn from(e: io::Error) -> Self {
+        let code = match e.kind() {
+            io::ErrorKind::NotFound => FX_NO_SUCH_FILE,
+            io::ErrorKind::PermissionDenied => FX_PERMISSION_DENIED,
+            _ => FX_FAILURE,
+        };
+        status_error(code, &e.to_string())
+    }
+}
+
+fn status_reply(code: u32, message: &str) -> Encoder {
+    Encoder::default().u32(code).str(message).str("")
+}
+
+fn status_error(code: u32, message: &str) -> Reply {
+    Reply(FXP_STATUS, status_reply(code, message))
+}
+
+fn attrs(metadata: &fs::Metadata) -> Reply {
+    Reply(
+        FXP_ATTRS,
+        Encoder::default().u32(ATTR_SIZE).u64(metadata.len()),
+    )
+}
@@ -0,0 +1,157 @@
+//! Entries stored in a sled database, for a pure-Rust embedded store with
+//! its own crash safety instead of the WAL of `FsIntKv`.
+//!
+//! Keys are big-endian indexes, so the tree lists them in order. Writes
+//! reach sled right away, and `flush` waits for them to be durable. sled
+//! flushes and compacts its log in background threads, and the database
+//! takes more space than the entries until compacted.
+//!
+//! Writers and readers lock the database directory like `FsIntKv`, so
+//! another x79d8 process waits or gets a clear error. sled has no read-only
+//! mode, so readers open it exclusively too.
+
+use super::super::{Bytes, IntKv, Stats};
+use super::fs::DirLock;
+use super::LockMode;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct SledIntKv {
+    path: PathBuf,
+    db: sled::Db,
+    _lock: Arc<DirLock>,
+}
+
+impl SledIntKv {
+    /// Open the database at `path`, waiting up to `wait` for other
+    /// processes using it. `LockMode::Exclusive` creates it if missing.
+    pub fn open(path: &Path, mode: LockMode, wait: Duration) -> io::Result<Self> {
+        if mode == LockMode::Exclusive {
+            fs::create_dir_all(path)?;
+        }
+        let lock = DirLock::acquire(path, mode, wait)?;
+        let db = sled::Config::new().path(path).open().map_err(to_io)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            db,
+            _lock: lock,
+        })
+    }
+}
+
+fn key(index: usize) -> [u8; 8] {
+    (index as u64).to_be_bytes()
+}
+
+fn to_io(e: sled::Error) -> io::Error {
+    match e {
+        sled::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl IntKv for SledIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        match self.db.get(key(index)).map_err(to_io)? {
+            Some(value) => Ok(value.to_vec().into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.db.insert(key(index), data.as_ref()).map_err(to_io)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        match self.db.remove(key(index)).map_err(to_io)? {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.db.contains_key(key(index)).map_err(to_io)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.db
+            .iter()
+            .keys()
+            .map(|key| {
+                let key = key.map_err(to_io)?;
+                let key: [u8; 8] = key.as_ref().try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bad sled key length")
+                })?;
+                Ok(u64::from_be_bytes(key) as usize)
+            })
+            .collect()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.db.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        stats.insert("sled.entries".into(), self.db.len() as u64);
+        if let Ok(size) = self.db.size_on_disk() {
+            stats.insert("sled.size_on_disk".into(), size);
+        }
+        stats
+    }
+}
+
+impl fmt::Debug for SledIntKv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SledIntKv")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[test]
+fn test_sled_int_kv() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    super::super::test_int_kv(
+        |kv: Option<SledIntKv>| {
+            // Reopen the database, so the entries come from disk.
+            drop(kv);
+            SledIntKv::open(&path, LockMode::Exclusive, Duration::ZERO).unwrap()
+        },
+        30,
+    );
+}
+
+#[test]
+fn test_sled_int_kv_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut kv = SledIntKv::open(dir.path(), LockMode::Exclusive, Duration::ZERO).unwrap();
+    for i in 1..=3 {
+        kv.write(i, vec![i as u8; 1000].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    let stats = kv.stats();
+    assert_eq!(stats["sled.entries"], 3);
+    assert!(stats["sled.size_on_disk"] > 0);
+    assert_eq!(kv.keys().unwrap(), [1, 2, 3]);
+}
+
+#[test]
+fn test_sled_int_kv_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    let open = |mode| SledIntKv::open(&path, mode, Duration::ZERO);
+    // Readers do not create it.
+    assert!(open(LockMode::Shared).is_err());
+    drop(open(LockMode::Exclusive).unwrap());
+    assert!(path.join("lock").exists());
+    drop(open(LockMode::Shared).unwrap());
+}
@@ -1,29 +1,152 @@
 pub mod backend;
 pub mod wrapper;
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
 
 pub use minibytes::Bytes;
 
+/// Statistics reported by `IntKv::stats`. Keys are prefixed by layer names.
+pub type Stats = BTreeMap<String, u64>;
+
+/// Placement hint for `IntKv::write_with_hint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hint {
+    /// Small and rewritten often, like folders.
+    Hot,
+    /// Rarely rewritten, like file contents.
+    Cold,
+}
+
+/// Limits of caches for `IntKv::set_cache_limits`. 0: no limit of the
+/// size or the number of entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+    /// Bytes of cached entries.
+    pub size: usize,
+    /// Cached entries.
+    pub entries: usize,
+    /// Bytes of pinned entries kept on eviction.
+    pub pinned_size: usize,
+}
+
 /// `IntKv` supports reading, writing, or deleting data keyed by integers.
 pub trait IntKv: fmt::Debug + Send + Sync + 'static {
     /// Read an entry.
     fn read(&self, index: usize) -> io::Result<Bytes>;
 
+    /// Read entries. Implementations may decode them in parallel. Return a
+    /// result for each index.
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        indexes.iter().map(|&index| self.read(index)).collect()
+    }
+
     /// Overwrite an entry.
     fn write(&mut self, index: usize, data: Bytes) -> io::Result<()>;
 
+    /// Overwrite an entry with a hint about how it is used. Layers that do
+    /// not choose placement ignore the hint.
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let _ = hint;
+        self.write(index, data)
+    }
+
+    /// Overwrite entries in order. Implementations may prepare them in
+    /// parallel. Return the number of entries written and the first error.
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let len = items.len();
+        for (i, (index, data)) in items.into_iter().enumerate() {
+            if let Err(e) = self.write(index, data) {
+                return (i, Err(e));
+            }
+        }
+        (len, Ok(()))
+    }
+
     /// Delete an entry.
     fn remove(&mut self, index: usize) -> io::Result<()>;
 
     /// Test if an entry exists.
     fn has(&self, index: usize) -> io::Result<bool>;
 
+    /// List existing entries in ascending order, including pending changes.
+    fn keys(&self) -> io::Result<Vec<usize>>;
+
     /// Persist pending changes.
     fn flush(&mut self) -> io::Result<()>;
+
+    /// Persist pending changes of the given entries. Other changes might
+    /// remain pending. By default, persist all changes.
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let _ = keys;
+        self.flush()
+    }
+
+    /// Hint that the given entries will be read soon.
+    /// Implementations may load them in background. Errors are ignored.
+    fn prefetch(&self, indexes: &[usize]) {
+        let _ = indexes;
+    }
+
+    /// Hint that the entry is hot and should stay cached.
+    /// Layers without a cache ignore it.
+    fn pin(&self, index: usize) {
+        let _ = index;
+    }
+
+    /// Undo `pin`.
+    fn unpin(&self, index: usize) {
+        let _ = index;
+    }
+
+    /// Statistics of this layer and layers below.
+    fn stats(&self) -> Stats {
+        Stats::new()
+    }
+
+    /// Change the limits of caches in this layer or layers below. Layers
+    /// without a cache ignore it.
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        let _ = limits;
+    }
+
+    /// Fail with `StorageFull` if `extra` bytes, on top of changes pending
+    /// in this layer and layers below, might not fit in storage. By
+    /// default, assume they fit.
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        let _ = extra;
+        Ok(())
+    }
+
+    /// Reorganize storage to reclaim space. Bounded by `max_pages` so it
+    /// can run opportunistically. By default, do nothing.
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        let _ = max_pages;
+        Ok(())
+    }
+
+    /// Flush, then copy the committed files of storage to the new
+    /// directory `dest`, hard-linking them where possible. Return the
+    /// paths of the files relative to `dest`, and whether they were linked.
+    /// By default, storage cannot be copied this way.
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        let _ = dest;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this storage does not support snapshots",
+        ))
+    }
+
+    /// Whether repeating a `write`, `remove` or `flush` that failed, or
+    /// might have partially happened, leaves the same result as doing it
+    /// once. Retrying layers only repeat them if so.
+    fn idempotent_writes(&self) -> bool {
+        true
+    }
 }
 
 impl IntKv for Box<dyn IntKv> {
@@ -31,10 +154,22 @@ impl IntKv for Box<dyn IntKv> {
         self.deref().read(index)
     }
 
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        self.deref().read_batch(indexes)
+    }
+
     fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
         self.deref_mut().write(index, data)
     }
 
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        self.deref_mut().write_with_hint(index, data, hint)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        self.deref_mut().write_batch(items)
+    }
+
     fn remove(&mut self, index: usize) -> io::Result<()> {
         self.deref_mut().remove(index)
     }
@@ -43,9 +178,53 @@ impl IntKv for Box<dyn IntKv> {
         self.deref().has(index)
     }
 
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.deref().keys()
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.deref_mut().flush()
     }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.deref_mut().flush_keys(keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.deref().prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.deref().pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.deref().unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        self.deref().stats()
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.deref().set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.deref().check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.deref_mut().compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.deref_mut().snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.deref().idempotent_writes()
+    }
 }
 
 #[cfg(test)]
@@ -55,6 +234,13 @@ where
     K: IntKv,
 {
     let mut kv = reload_kv(None);
+    // Entries left by previous tests, not touched by the first phases.
+    let base: Vec<usize> = kv.keys().unwrap().into_iter().filter(|&i| i >= n).collect();
+    let expected_keys = |extra: &mut dyn Iterator<Item = usize>| -> Vec<usize> {
+        let mut keys: std::collections::BTreeSet<usize> = base.iter().cloned().collect();
+        keys.extend(extra);
+        keys.into_iter().collect()
+    };
     for i in 0..n {
         let data = vec![i as u8; i * 541];
         kv.write(i, data.into()).unwrap();
@@ -65,6 +251,7 @@ where
             assert_eq!(kv.read(i).unwrap(), Bytes::from(data));
             assert!(kv.has(i).unwrap());
         }
+        assert_eq!(kv.keys().unwrap(), expected_keys(&mut (0..n)));
         kv.flush().unwrap();
         kv = reload_kv(Some(kv));
     }
@@ -76,6 +263,7 @@ where
         for i in 0..n {
             assert!(!kv.has(i).unwrap());
         }
+        assert_eq!(kv.keys().unwrap(), expected_keys(&mut None.into_iter()));
         kv.flush().unwrap();
         kv = reload_kv(Some(kv));
     }
@@ -102,14 +290,12 @@ where
 
     for _ in 0..(n * 10) {
         match rng.next_u32() % 3 {
-            0 => {
+            0 if !m.is_empty() => {
                 // Remove.
-                if !m.is_empty() {
-                    let id = rand_id(&mut rng, &m);
-                    kv.remove(id).unwrap();
-                    assert!(!kv.has(id).unwrap());
-                    m.remove(&id);
-                }
+                let id = rand_id(&mut rng, &m);
+                kv.remove(id).unwrap();
+                assert!(!kv.has(id).unwrap());
+                m.remove(&id);
             }
             1 => {
                 // Write.
@@ -119,15 +305,13 @@ where
                 assert!(kv.has(id).unwrap());
                 m.insert(id, data);
             }
-            2 => {
+            2 if !m.is_empty() => {
                 // Rewrite.
-                if !m.is_empty() {
-                    let id = rand_id(&mut rng, &m);
-                    let data = rand_data(&mut rng);
-                    kv.write(id, data.clone()).unwrap();
-                    assert!(kv.has(id).unwrap());
-                    m.insert(id, data);
-                }
+                let id = rand_id(&mut rng, &m);
+                let data = rand_data(&mut rng);
+                kv.write(id, data.clone()).unwrap();
+                assert!(kv.has(id).unwrap());
+                m.insert(id, data);
             }
             _ => {}
         }
@@ -148,6 +332,7 @@ where
             );
             assert_eq!(l, r);
         }
+        assert_eq!(kv.keys().unwrap(), expected_keys(&mut m.keys().cloned()));
         kv.flush().unwrap();
         kv = reload_kv(Some(kv));
     }
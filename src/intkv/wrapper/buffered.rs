@@ -1,22 +1,100 @@
-use super::super::{Bytes, IntKv};
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use super::super::{Bytes, CacheLimits, IntKv, Stats};
+use crate::util;
+use once_cell::sync::OnceCell;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 use std::{io, sync::atomic::AtomicUsize, sync::atomic::Ordering};
 
+/// Number of background threads loading prefetched entries.
+const PREFETCH_THREADS: usize = 4;
+
+/// Approximate memory overhead of a cache entry.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Default limit of cache entries.
+const DEFAULT_CACHE_ENTRIES_LIMIT: usize = 1 << 20;
+
+/// Default limit of pinned bytes protected from eviction.
+const DEFAULT_PINNED_SIZE_LIMIT: usize = 16 << 20;
+
 /// Buffered IntKv. Writes are buffered until `flush()`.
+///
+/// Reads are cached. `prefetch()` loads entries into the cache using
+/// background threads.
+///
+/// Pinned entries survive cache eviction, up to `with_pinned_size_limit()`
+/// bytes.
+///
+/// With `with_background_flush()`, buffered changes are also flushed by a
+/// background thread periodically, or when there are too many changes.
+///
+/// `flush()` applies removals to the underlying kv in ascending index
+/// order, then writes the other changes in one `write_batch()`, also in
+/// ascending index order. If an underlying operation fails, `flush()` stops
+/// and the failed and remaining changes stay buffered so `flush()` can be
+/// retried.
 #[derive(Debug)]
 pub struct BufferedIntKv {
+    /// States shared with background threads.
+    shared: Arc<Shared>,
+
+    /// Sends indexes to prefetch threads. Spawned on demand.
+    prefetcher: OnceCell<mpsc::Sender<usize>>,
+
+    /// Background flush thread.
+    flusher: Option<thread::JoinHandle<()>>,
+
+    /// Wake up the flush thread if changes exceed this size (0: unlimited).
+    max_dirty_bytes: usize,
+}
+
+#[derive(Debug)]
+struct Shared {
     /// Cached.
     cache: RwLock<HashMap<usize, State>>,
 
-    /// Cache size limit.
-    cache_size_limit: usize,
+    /// Cache size limit. Sizes include per-entry overhead.
+    cache_size_limit: AtomicUsize,
     cache_size: AtomicUsize,
 
-    /// Changed in this layer.
-    changes: HashMap<usize, Option<Bytes>>,
+    /// Cache entry count limit.
+    cache_entries_limit: AtomicUsize,
+
+    /// Entries to keep on eviction.
+    pinned: Mutex<BTreeSet<usize>>,
+
+    /// Pinned entries beyond this size are not protected from eviction.
+    pinned_size_limit: AtomicUsize,
+
+    /// Changed in this layer. BTreeMap for deterministic flush order.
+    changes: RwLock<BTreeMap<usize, Option<Bytes>>>,
 
-    kv: Box<dyn IntKv>,
+    /// Total size of data in `changes`.
+    dirty_bytes: AtomicUsize,
+
+    /// Indexes being loaded by prefetch threads.
+    inflight: Mutex<HashSet<usize>>,
+    inflight_done: Condvar,
+
+    /// Communication with the background flush thread.
+    background: Mutex<Background>,
+    background_wake: Condvar,
+
+    kv: RwLock<Box<dyn IntKv>>,
+}
+
+#[derive(Debug, Default)]
+struct Background {
+    /// Ask the background thread to exit.
+    stop: bool,
+
+    /// Error from background flush, to be reported by the next foreground
+    /// operation.
+    error: Option<io::Error>,
 }
 
 #[derive(Debug, Clone)]
@@ -31,24 +109,85 @@ enum State {
     Has(bool),
 }
 
+impl State {
+    /// Approximate memory usage in the cache.
+    fn weight(&self) -> usize {
+        match self {
+            State::Data(b) => b.len() + ENTRY_OVERHEAD,
+            _ => ENTRY_OVERHEAD,
+        }
+    }
+}
+
 impl BufferedIntKv {
     pub fn new(kv: Box<dyn IntKv>) -> Self {
-        Self {
+        let shared = Shared {
             cache: Default::default(),
-            changes: Default::default(),
-            cache_size_limit: 0,
+            cache_size_limit: AtomicUsize::new(0),
             cache_size: Default::default(),
-            kv,
+            cache_entries_limit: AtomicUsize::new(DEFAULT_CACHE_ENTRIES_LIMIT),
+            pinned: Default::default(),
+            pinned_size_limit: AtomicUsize::new(DEFAULT_PINNED_SIZE_LIMIT),
+            changes: Default::default(),
+            dirty_bytes: Default::default(),
+            inflight: Default::default(),
+            inflight_done: Default::default(),
+            background: Default::default(),
+            background_wake: Default::default(),
+            kv: RwLock::new(kv),
+        };
+        Self {
+            shared: Arc::new(shared),
+            prefetcher: Default::default(),
+            flusher: None,
+            max_dirty_bytes: 0,
         }
     }
 
-    pub fn with_cache_size_limit(mut self, limit: usize) -> Self {
-        self.cache_size_limit = limit;
+    pub fn with_cache_size_limit(self, limit: usize) -> Self {
+        self.shared.cache_size_limit.store(limit, Ordering::Release);
+        self
+    }
+
+    pub fn with_cache_entries_limit(self, limit: usize) -> Self {
+        self.shared
+            .cache_entries_limit
+            .store(limit, Ordering::Release);
+        self
+    }
+
+    pub fn with_pinned_size_limit(self, limit: usize) -> Self {
+        self.shared
+            .pinned_size_limit
+            .store(limit, Ordering::Release);
+        self
+    }
+
+    /// Flush changes in a background thread every `interval`, or when
+    /// changes exceed `max_dirty_bytes` (0: no limit).
+    ///
+    /// Errors of background flushes are reported by the next foreground
+    /// operation. Changes are flushed on drop.
+    pub fn with_background_flush(mut self, interval: Duration, max_dirty_bytes: usize) -> Self {
+        if self.flusher.is_some() {
+            return self;
+        }
+        let shared = self.shared.clone();
+        let spawned = thread::Builder::new()
+            .name("flush".to_string())
+            .spawn(move || shared.background_flush_loop(interval, max_dirty_bytes));
+        match spawned {
+            Ok(handle) => {
+                self.flusher = Some(handle);
+                self.max_dirty_bytes = max_dirty_bytes;
+            }
+            Err(e) => log::warn!("Cannot spawn flush thread: {}", e),
+        }
         self
     }
 
     fn get_changed(&self, index: usize) -> io::Result<Option<Bytes>> {
-        match self.changes.get(&index) {
+        match self.shared.changes.read().get(&index) {
             None => Ok(None),
             // Removed.
             Some(None) => Err(io::ErrorKind::NotFound.into()),
@@ -56,6 +195,67 @@ impl BufferedIntKv {
         }
     }
 
+    fn get_cache(&self, index: usize) -> State {
+        self.shared.get_cache(index)
+    }
+
+    /// Record a change. Wake up the background flush thread if there are
+    /// too many changes.
+    fn insert_change(&mut self, index: usize, data: Option<Bytes>) {
+        let new_len = data.as_ref().map(|d| d.len()).unwrap_or(0);
+        let old = self.shared.changes.write().insert(index, data);
+        let old_len = old.flatten().map(|d| d.len()).unwrap_or(0);
+        let dirty_bytes = self
+            .shared
+            .dirty_bytes
+            .fetch_add(new_len, Ordering::AcqRel)
+            .wrapping_add(new_len);
+        self.shared.dirty_bytes.fetch_sub(old_len, Ordering::AcqRel);
+        let dirty_bytes = dirty_bytes.saturating_sub(old_len);
+        if self.max_dirty_bytes > 0 && dirty_bytes >= self.max_dirty_bytes {
+            self.shared.background_wake.notify_all();
+        }
+    }
+
+    /// Report errors from the background flush thread.
+    fn check_background_error(&self) -> io::Result<()> {
+        if self.flusher.is_none() {
+            return Ok(());
+        }
+        match self.shared.background.lock().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Spawn prefetch threads. Return the channel to send indexes to them.
+    /// Threads exit once the `BufferedIntKv` is dropped.
+    fn prefetcher(&self) -> &mpsc::Sender<usize> {
+        self.prefetcher.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<usize>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for i in 0..PREFETCH_THREADS {
+                let receiver = receiver.clone();
+                let shared = self.shared.clone();
+                let spawned = thread::Builder::new()
+                    .name(format!("prefetch-{}", i))
+                    .spawn(move || loop {
+                        let index = match receiver.lock().recv() {
+                            Ok(index) => index,
+                            Err(_) => break,
+                        };
+                        shared.prefetch_one(index);
+                    });
+                if let Err(e) = spawned {
+                    log::warn!("Cannot spawn prefetch thread: {}", e);
+                }
+            }
+            sender
+        })
+    }
+}
+
+impl Shared {
     fn get_cache(&self, index: usize) -> State {
         self.cache
             .read()
@@ -63,58 +263,252 @@ impl BufferedIntKv {
             .cloned()
             .unwrap_or(State::Unknown)
     }
+
+    /// Insert an entry into the cache. Keep the cache size bounded.
+    fn insert_cache(&self, index: usize, state: State) {
+        let mut cache = self.cache.write();
+        self.insert_cache_locked(&mut cache, index, state);
+    }
+
+    fn insert_cache_locked(&self, cache: &mut HashMap<usize, State>, index: usize, state: State) {
+        if let Some(old) = cache.remove(&index) {
+            self.cache_size.fetch_sub(old.weight(), Ordering::AcqRel);
+        }
+        let weight = state.weight();
+        self.make_room(cache, weight);
+        cache.insert(index, state);
+        self.cache_size.fetch_add(weight, Ordering::AcqRel);
+    }
+
+    /// Evict entries so a new entry of `weight` fits in the limits.
+    /// Negative entries are evicted first. Pinned entries are kept.
+    fn make_room(&self, cache: &mut HashMap<usize, State>, weight: usize) {
+        let size_limit = self.cache_size_limit.load(Ordering::Acquire);
+        let entries_limit = self.cache_entries_limit.load(Ordering::Acquire);
+        let over_limit = |cache: &HashMap<usize, State>, size: usize| {
+            (size_limit > 0 && size + weight > size_limit)
+                || (entries_limit > 0 && cache.len() >= entries_limit)
+        };
+        let mut size = self.cache_size.load(Ordering::Acquire);
+        if !over_limit(cache, size) {
+            return;
+        }
+        let pinned = self.pinned.lock();
+
+        // Negative entries are cheap to recalculate.
+        cache.retain(|i, state| {
+            let keep = !matches!(state, State::Has(false)) || pinned.contains(i);
+            if !keep {
+                size -= state.weight();
+            }
+            keep
+        });
+
+        if over_limit(cache, size) {
+            log::debug!("Dropping cache (size {}, {} entries)", size, cache.len());
+            let pinned_size_limit = self.pinned_size_limit.load(Ordering::Acquire);
+            let mut kept = HashMap::new();
+            size = 0;
+            for &i in pinned.iter() {
+                if let Some(state) = cache.remove(&i) {
+                    if size + state.weight() <= pinned_size_limit {
+                        size += state.weight();
+                        kept.insert(i, state);
+                    }
+                }
+            }
+            *cache = kept;
+        }
+        self.cache_size.store(size, Ordering::Release);
+    }
+
+    /// Wait for a prefetch thread to complete loading the given index.
+    fn wait_inflight(&self, index: usize) {
+        let mut inflight = self.inflight.lock();
+        while inflight.contains(&index) {
+            self.inflight_done.wait(&mut inflight);
+        }
+    }
+
+    /// Load an entry into the cache. Errors are ignored.
+    fn prefetch_one(&self, index: usize) {
+        if !matches!(self.get_cache(index), State::Unknown | State::Has(true)) {
+            return;
+        }
+        if !self.inflight.lock().insert(index) {
+            return;
+        }
+        {
+            // Hold the kv lock so flush() cannot change the entry before the
+            // cache gets updated.
+            let kv = self.kv.read();
+            if matches!(self.get_cache(index), State::Unknown | State::Has(true)) {
+                match kv.read(index) {
+                    Ok(b) => {
+                        log::trace!("Prefetched {} ({} bytes)", index, b.len());
+                        self.insert_cache(index, State::Data(b));
+                    }
+                    Err(e) => log::trace!("Cannot prefetch {}: {}", index, e),
+                }
+            }
+        }
+        self.inflight.lock().remove(&index);
+        self.inflight_done.notify_all();
+    }
+
+    /// Write changes to the underlying kv.
+    /// If `keys` is set, only write changes of the given keys.
+    fn flush_changes(&self, keys: Option<&[usize]>) -> io::Result<()> {
+        // Hold the changes lock so readers do not observe the state where
+        // the entry is neither in `changes` nor in `cache`.
+        let mut changes = self.changes.write();
+        let mut kv = self.kv.write();
+        let mut cache = self.cache.write();
+        let to_write: Vec<usize> = match keys {
+            None => changes.keys().cloned().collect(),
+            Some(keys) => {
+                let keys: BTreeSet<usize> = keys.iter().cloned().collect();
+                keys.into_iter()
+                    .filter(|k| changes.contains_key(k))
+                    .collect()
+            }
+        };
+        // Only drop changes after they were written successfully.
+        let mut writes = Vec::new();
+        for id in to_write {
+            match &changes[&id] {
+                None => {
+                    // Need remove.
+                    if kv.has(id)? {
+                        kv.remove(id)?;
+                        self.insert_cache_locked(&mut cache, id, State::Has(false));
+                    }
+                    changes.remove(&id);
+                }
+                Some(d) => writes.push((id, d.clone())),
+            }
+        }
+        // Need write. The underlying kv might prepare them in parallel.
+        let (written, result) = kv.write_batch(writes.clone());
+        for (id, d) in writes.into_iter().take(written) {
+            self.dirty_bytes.fetch_sub(d.len(), Ordering::AcqRel);
+            self.insert_cache_locked(&mut cache, id, State::Data(d));
+            changes.remove(&id);
+        }
+        result?;
+        match keys {
+            None => kv.flush(),
+            Some(keys) => kv.flush_keys(keys),
+        }
+    }
+
+    /// Main loop of the background flush thread.
+    fn background_flush_loop(&self, interval: Duration, max_dirty_bytes: usize) {
+        let mut background = self.background.lock();
+        while !background.stop {
+            let timed_out = self
+                .background_wake
+                .wait_for(&mut background, interval)
+                .timed_out();
+            if background.stop {
+                break;
+            }
+            let dirty_bytes = self.dirty_bytes.load(Ordering::Acquire);
+            let over_limit = max_dirty_bytes > 0 && dirty_bytes >= max_dirty_bytes;
+            if !timed_out && !over_limit {
+                continue;
+            }
+            if self.changes.read().is_empty() {
+                continue;
+            }
+            log::debug!("Flushing in background ({} bytes)", dirty_bytes);
+            let result = MutexGuard::unlocked(&mut background, || self.flush_changes(None));
+            if let Err(e) = result {
+                log::warn!("Background flush failed: {}", e);
+                background.error = Some(e);
+            }
+        }
+    }
 }
 
 impl IntKv for BufferedIntKv {
     fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.check_background_error()?;
         if let Some(b) = self.get_changed(index)? {
             return Ok(b);
         }
-        match self.get_cache(index) {
+        let mut state = self.get_cache(index);
+        if matches!(state, State::Unknown | State::Has(true)) && self.prefetcher.get().is_some() {
+            // Avoid loading the same entry twice.
+            self.shared.wait_inflight(index);
+            state = self.get_cache(index);
+        }
+        match state {
             State::Has(false) => Err(io::ErrorKind::NotFound.into()),
             State::Unknown => {
                 // Load content from kv.
-                let b = match self.kv.read(index) {
+                let b = match self.shared.kv.read().read(index) {
                     Err(e) => {
                         if e.kind() == io::ErrorKind::NotFound {
-                            self.cache.write().insert(index, State::Has(false));
+                            self.shared.insert_cache(index, State::Has(false));
                         }
                         return Err(e);
                     }
                     Ok(b) => b,
                 };
-                let size = self.cache_size.fetch_add(b.len(), Ordering::AcqRel);
-                let mut cache = self.cache.write();
-                if self.cache_size_limit > 0 && size > self.cache_size_limit {
-                    // Remove cache to keep size bounded.
-                    log::debug!(
-                        "Dropping cache (size {} > limit {})",
-                        size,
-                        self.cache_size_limit
-                    );
-                    self.cache_size.fetch_sub(size, Ordering::AcqRel);
-                    cache.clear();
-                }
-                cache.insert(index, State::Data(b.clone()));
+                self.shared.insert_cache(index, State::Data(b.clone()));
                 Ok(b)
             }
             State::Has(true) => {
-                let b = self.kv.read(index)?;
-                self.cache.write().insert(index, State::Data(b.clone()));
+                let b = self.shared.kv.read().read(index)?;
+                self.shared.insert_cache(index, State::Data(b.clone()));
                 Ok(b)
             }
             State::Data(b) => Ok(b),
         }
     }
 
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        // Load uncached entries in batches so the backend can decode them
+        // in parallel. Then take the usual path.
+        let missing: Vec<usize> = indexes
+            .iter()
+            .copied()
+            .filter(|&i| {
+                matches!(self.get_changed(i), Ok(None))
+                    && matches!(self.get_cache(i), State::Unknown | State::Has(true))
+            })
+            .collect();
+        if missing.len() > 1 {
+            let kv = self.shared.kv.read();
+            let kv: &dyn IntKv = &**kv;
+            // Like prefetching, read from slow backends concurrently.
+            let chunks: Vec<&[usize]> = missing
+                .chunks(missing.len().div_ceil(PREFETCH_THREADS))
+                .collect();
+            let loaded = util::parallel_map(&chunks, PREFETCH_THREADS, |c| kv.read_batch(c));
+            for (&index, result) in missing.iter().zip(loaded.into_iter().flatten()) {
+                match result {
+                    Ok(b) => self.shared.insert_cache(index, State::Data(b)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        self.shared.insert_cache(index, State::Has(false))
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        indexes.iter().map(|&index| self.read(index)).collect()
+    }
+
     fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
-        self.changes.insert(index, Some(data));
+        self.check_background_error()?;
+        self.insert_change(index, Some(data));
         Ok(())
     }
 
     fn remove(&mut self, index: usize) -> io::Result<()> {
         if self.has(index)? {
-            self.changes.insert(index, None);
+            self.insert_change(index, None);
             Ok(())
         } else {
             Err(io::ErrorKind::NotFound.into())
@@ -122,15 +516,16 @@ impl IntKv for BufferedIntKv {
     }
 
     fn has(&self, index: usize) -> io::Result<bool> {
-        match self.changes.get(&index) {
+        self.check_background_error()?;
+        match self.shared.changes.read().get(&index) {
             Some(Some(_)) => return Ok(true),
             Some(None) => return Ok(false),
             None => {}
         }
         match self.get_cache(index) {
             State::Unknown => {
-                let b = self.kv.has(index)?;
-                self.cache.write().insert(index, State::Has(b));
+                let b = self.shared.kv.read().has(index)?;
+                self.shared.insert_cache(index, State::Has(b));
                 Ok(b)
             }
             State::Has(b) => Ok(b),
@@ -138,25 +533,117 @@ impl IntKv for BufferedIntKv {
         }
     }
 
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.check_background_error()?;
+        // Lock `changes` first, like `flush_changes`.
+        let changes = self.shared.changes.read();
+        let mut keys: BTreeSet<usize> = self.shared.kv.read().keys()?.into_iter().collect();
+        for (&index, data) in changes.iter() {
+            if data.is_some() {
+                keys.insert(index);
+            } else {
+                keys.remove(&index);
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
     fn flush(&mut self) -> io::Result<()> {
-        let mut cache = self.cache.write();
-        for (id, v) in self.changes.drain() {
-            match v {
-                None => {
-                    // Need remove.
-                    if self.kv.has(id)? {
-                        self.kv.remove(id)?;
-                        cache.insert(id, State::Has(false));
-                    }
-                }
-                Some(d) => {
-                    // Need write.
-                    self.kv.write(id, d.clone())?;
-                    cache.insert(id, State::Data(d));
-                }
+        self.check_background_error()?;
+        self.shared.flush_changes(None)
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.check_background_error()?;
+        self.shared.flush_changes(Some(keys))
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        let sender = self.prefetcher();
+        let changes = self.shared.changes.read();
+        for &index in indexes {
+            if changes.contains_key(&index) {
+                continue;
+            }
+            if let State::Unknown | State::Has(true) = self.get_cache(index) {
+                // Errors are ignored. Prefetch is only a hint.
+                let _ = sender.send(index);
+            }
+        }
+    }
+    fn pin(&self, index: usize) {
+        self.shared.pinned.lock().insert(index);
+    }
+
+    fn unpin(&self, index: usize) {
+        self.shared.pinned.lock().remove(&index);
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        let shared = &self.shared;
+        shared
+            .cache_size_limit
+            .store(limits.size, Ordering::Release);
+        shared
+            .cache_entries_limit
+            .store(limits.entries, Ordering::Release);
+        shared
+            .pinned_size_limit
+            .store(limits.pinned_size, Ordering::Release);
+        // Evict right away if the limits dropped.
+        shared.make_room(&mut shared.cache.write(), 0);
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.shared.kv.read().stats();
+        let pinned: Vec<usize> = self.shared.pinned.lock().iter().cloned().collect();
+        let cache = self.shared.cache.read();
+        let pinned_bytes: usize = pinned
+            .iter()
+            .map(|i| cache.get(i).map(|s| s.weight()).unwrap_or(0))
+            .sum();
+        let cache_bytes = self.shared.cache_size.load(Ordering::Acquire);
+        let dirty_bytes = self.shared.dirty_bytes.load(Ordering::Acquire);
+        let mut insert = |name: &str, value: usize| {
+            stats.insert(format!("buffered.{}", name), value as u64);
+        };
+        insert("cache_bytes", cache_bytes);
+        insert("cache_entries", cache.len());
+        insert("pinned_bytes", pinned_bytes);
+        insert("pinned_entries", pinned.len());
+        insert("dirty_bytes", dirty_bytes);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        let dirty_bytes = self.shared.dirty_bytes.load(Ordering::Acquire) as u64;
+        // Wait for a background flush, so a low space error is not missed
+        // while it runs.
+        self.shared.kv.read().check_space(extra + dirty_bytes)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.check_background_error()?;
+        self.shared.kv.write().compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.flush()?;
+        self.shared.kv.write().snapshot(dest)
+    }
+}
+
+impl Drop for BufferedIntKv {
+    fn drop(&mut self) {
+        if let Some(handle) = self.flusher.take() {
+            self.shared.background.lock().stop = true;
+            self.shared.background_wake.notify_all();
+            let _ = handle.join();
+            log::debug!("Flushing on drop");
+            if let Err(e) = self.shared.flush_changes(None) {
+                log::error!("Cannot flush: {:?}", e);
             }
         }
-        self.kv.flush()
     }
 }
 
@@ -171,3 +658,308 @@ fn test_buffered() {
         100,
     );
 }
+
+#[test]
+fn test_prefetch() {
+    use std::time::{Duration, Instant};
+    let mut mem = super::super::backend::MemIntKv::new();
+    for i in 0..16 {
+        mem.insert(i, vec![i as u8; 10].into());
+    }
+    let kv = super::FaultIntKv::new(Box::new(mem));
+    kv.faults()
+        .latency(super::Op::Read, Duration::from_millis(20));
+    let kv = BufferedIntKv::new(Box::new(kv));
+
+    let start = Instant::now();
+    let indexes: Vec<usize> = (0..16).chain(100..104).collect();
+    kv.prefetch(&indexes);
+    for i in 0..16 {
+        assert_eq!(kv.read(i).unwrap(), Bytes::from(vec![i as u8; 10]));
+    }
+    // Missing entries are ignored by prefetch.
+    assert!(kv.read(100).is_err());
+    // Without prefetch, it takes 16 * 20ms.
+    assert!(start.elapsed() < Duration::from_millis(16 * 20 * 3 / 4));
+}
+
+#[test]
+fn test_prefetch_cache_limit() {
+    let mut mem = super::super::backend::MemIntKv::new();
+    for i in 0..100 {
+        mem.insert(i, vec![0; 100].into());
+    }
+    let kv = BufferedIntKv::new(Box::new(mem)).with_cache_size_limit(1000);
+    let indexes: Vec<usize> = (0..100).collect();
+    kv.prefetch(&indexes);
+    for i in 0..100 {
+        kv.shared.wait_inflight(i);
+        kv.read(i).unwrap();
+    }
+    assert!(kv.shared.cache_size.load(Ordering::Acquire) <= 1100);
+}
+
+#[test]
+fn test_background_flush() {
+    use super::super::backend::FsIntKv;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let fs = || FsIntKv::new(path).unwrap();
+    let wait_until = |f: &dyn Fn() -> bool| {
+        for _ in 0..200 {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    };
+
+    // Flush periodically.
+    let mut kv =
+        BufferedIntKv::new(Box::new(fs())).with_background_flush(Duration::from_millis(20), 0);
+    kv.write(1, b"a".to_vec().into()).unwrap();
+    assert!(wait_until(&|| fs().has(1).unwrap()));
+
+    // Flush on threshold.
+    let mut kv =
+        BufferedIntKv::new(Box::new(fs())).with_background_flush(Duration::from_secs(3600), 100);
+    kv.write(2, vec![0; 10].into()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(!fs().has(2).unwrap());
+    kv.write(3, vec![0; 100].into()).unwrap();
+    assert!(wait_until(&|| fs().has(3).unwrap()));
+    assert!(fs().has(2).unwrap());
+
+    // Flush on drop.
+    kv.write(4, vec![0; 10].into()).unwrap();
+    drop(kv);
+    assert!(fs().has(4).unwrap());
+}
+
+#[test]
+fn test_background_flush_error() {
+    use super::super::backend::FsIntKv;
+    let dir = tempfile::tempdir().unwrap();
+    let fs = FsIntKv::new(dir.path()).unwrap();
+    let mut kv =
+        BufferedIntKv::new(Box::new(fs)).with_background_flush(Duration::from_millis(10), 0);
+    // Make writes fail.
+    drop(dir);
+    kv.write(1, vec![0; 10].into()).unwrap();
+    let mut error = None;
+    for _ in 0..200 {
+        if let Err(e) = kv.has(2) {
+            error = Some(e);
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(error.is_some());
+}
+
+#[test]
+fn test_flush_keys() {
+    use super::super::backend::FsIntKv;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let fs = || FsIntKv::new(path).unwrap();
+    let mut kv = BufferedIntKv::new(Box::new(fs()));
+    kv.write(1, vec![1; 10].into()).unwrap();
+    kv.write(2, vec![2; 10].into()).unwrap();
+    kv.flush_keys(&[1, 3]).unwrap();
+    assert!(fs().has(1).unwrap());
+    assert!(!fs().has(2).unwrap());
+    assert_eq!(kv.read(2).unwrap().len(), 10);
+    kv.flush().unwrap();
+    assert!(fs().has(2).unwrap());
+}
+
+#[test]
+fn test_flush_partial_failure() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    use std::sync::atomic::Ordering;
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let faults = failing.faults();
+    faults.fail_after(&[Op::Write, Op::Remove], 2);
+    let written = faults.written.clone();
+    let mut kv = BufferedIntKv::new(Box::new(failing));
+    for &i in &[5, 3, 9, 1] {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    assert!(kv.flush().is_err());
+    // Flushed in ascending order. Unwritten changes are kept.
+    assert_eq!(*written.lock(), [1, 3]);
+    assert_eq!(kv.shared.changes.read().len(), 2);
+    assert_eq!(kv.shared.dirty_bytes.load(Ordering::Acquire), 20);
+    assert_eq!(&kv.read(9).unwrap()[..], &[9; 10]);
+
+    faults.clear();
+    kv.flush().unwrap();
+    assert_eq!(*written.lock(), [1, 3, 5, 9]);
+    assert_eq!(kv.shared.dirty_bytes.load(Ordering::Acquire), 0);
+}
+
+#[test]
+fn test_flush_failed_remove() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let faults = failing.faults();
+    let mut kv = BufferedIntKv::new(Box::new(failing));
+    for i in 1..=3 {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    kv.flush().unwrap();
+
+    // The removal is kept until a flush gets it through.
+    kv.remove(2).unwrap();
+    kv.write(4, vec![4; 10].into()).unwrap();
+    faults.fail_when(|op, index| op == Op::Remove && index == Some(2));
+    assert!(kv.flush().is_err());
+    assert!(!kv.has(2).unwrap());
+    assert!(kv.read(2).is_err());
+    faults.clear();
+    kv.flush().unwrap();
+    let removes = faults.count(Op::Remove);
+    kv.flush().unwrap();
+    assert_eq!(faults.count(Op::Remove), removes);
+    assert!(!kv.shared.kv.read().has(2).unwrap());
+    assert_eq!(&kv.shared.kv.read().read(4).unwrap()[..], &[4; 10]);
+}
+
+#[test]
+fn test_pin() {
+    use super::super::backend::MemIntKv;
+    let mut mem = MemIntKv::new();
+    for i in 0..10 {
+        mem.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    let kv = BufferedIntKv::new(Box::new(mem))
+        .with_cache_size_limit(ENTRY_OVERHEAD * 3)
+        .with_pinned_size_limit(ENTRY_OVERHEAD * 2);
+    kv.pin(1);
+    kv.pin(2);
+    kv.read(1).unwrap();
+    kv.read(2).unwrap();
+    for i in 3..10 {
+        kv.read(i).unwrap();
+    }
+    // Only one pinned entry fits in the pinned size limit.
+    let cache = kv.shared.cache.read();
+    assert!(cache.contains_key(&1));
+    assert!(!cache.contains_key(&2));
+    drop(cache);
+    let stats = kv.stats();
+    assert_eq!(stats["buffered.pinned_bytes"], 10 + ENTRY_OVERHEAD as u64);
+    assert_eq!(stats["buffered.pinned_entries"], 2);
+
+    kv.unpin(1);
+    for i in 3..10 {
+        kv.read(i).unwrap();
+    }
+    assert!(!kv.shared.cache.read().contains_key(&1));
+}
+
+#[test]
+fn test_negative_cache_bounded() {
+    use super::super::backend::MemIntKv;
+    let mut mem = MemIntKv::new();
+    mem.write(0, vec![0; 10].into()).unwrap();
+    let kv = BufferedIntKv::new(Box::new(mem)).with_cache_entries_limit(1000);
+    kv.read(0).unwrap();
+    for i in 1..300_000 {
+        assert!(kv.read(i).is_err());
+        assert!(!kv.has(i + 300_000).unwrap());
+    }
+    let cache = kv.shared.cache.read();
+    assert!(cache.len() <= 1000);
+    // Negative entries are evicted first.
+    assert!(matches!(cache.get(&0), Some(State::Data(_))));
+    let size: usize = cache.values().map(|s| s.weight()).sum();
+    assert_eq!(kv.shared.cache_size.load(Ordering::Acquire), size);
+}
+
+#[test]
+fn test_set_cache_limits() {
+    use super::super::backend::MemIntKv;
+    let mut mem = MemIntKv::new();
+    for i in 0..10 {
+        mem.write(i, vec![0; 100].into()).unwrap();
+    }
+    let kv = BufferedIntKv::new(Box::new(mem));
+    for i in 0..10 {
+        kv.read(i).unwrap();
+    }
+    assert_eq!(kv.stats()["buffered.cache_entries"], 10);
+
+    // Lower limits evict right away. Pinned entries are kept.
+    kv.pin(3);
+    let weight = 100 + ENTRY_OVERHEAD;
+    let limits = CacheLimits {
+        size: weight * 4,
+        entries: 0,
+        pinned_size: weight * 2,
+    };
+    kv.set_cache_limits(limits);
+    let cache_entries = |kv: &BufferedIntKv| kv.stats()["buffered.cache_entries"];
+    assert_eq!(cache_entries(&kv), 1);
+    for i in 0..10 {
+        kv.read(i).unwrap();
+    }
+    assert!(cache_entries(&kv) <= 4);
+
+    // Higher limits keep more.
+    kv.set_cache_limits(CacheLimits {
+        size: 0,
+        entries: 8,
+        ..limits
+    });
+    for i in 0..10 {
+        kv.read(i).unwrap();
+    }
+    assert!(cache_entries(&kv) > 4);
+    assert!(cache_entries(&kv) <= 8);
+}
+
+#[test]
+fn test_check_space_during_flush() {
+    use super::super::backend::FsIntKv;
+    use super::FaultIntKv;
+    use super::Op;
+    let dir = tempfile::tempdir().unwrap();
+    let fs = FsIntKv::new(dir.path())
+        .unwrap()
+        .with_reserved_space(u64::MAX >> 2, 0);
+    let fault = FaultIntKv::new(Box::new(fs));
+
+    // Block writes of the background flush until released.
+    let (started_tx, started) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel::<()>();
+    let (started_tx, release_rx) = (Mutex::new(started_tx), Mutex::new(release_rx));
+    fault.faults().fail_when(move |op, _| {
+        if op == Op::Write {
+            let _ = started_tx.lock().send(());
+            let _ = release_rx.lock().recv();
+        }
+        false
+    });
+    let mut kv =
+        BufferedIntKv::new(Box::new(fault)).with_background_flush(Duration::from_millis(1), 0);
+    kv.write(1, vec![0; 10].into()).unwrap();
+    started.recv().unwrap();
+
+    // Checked while the flush runs. The low space is not missed.
+    let kv = Arc::new(kv);
+    let checker = {
+        let kv = kv.clone();
+        thread::spawn(move || kv.check_space(0))
+    };
+    thread::sleep(Duration::from_millis(50));
+    let waited = !checker.is_finished();
+    drop(release);
+    assert!(waited);
+    let err = checker.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+}
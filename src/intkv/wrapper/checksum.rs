@@ -0,0 +1,359 @@
+//! Detect corrupted entries on any backend, with or without encryption.
+//!
+//! Each entry starts with `MAGIC`, the id of the `Checksum` algorithm, and
+//! the digest of the rest of the entry. Entries without `MAGIC`, written
+//! before checksums were enabled, are read as is and counted by the
+//! `checksum.unchecked_entries` stat until they are rewritten.
+
+use super::super::{Bytes, Hint, IntKv, Stats};
+use blake2::{Blake2s, Digest};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Start of checksummed entries. An older entry starting with it by
+/// chance fails to read, with a 2^-40 chance per entry counting the
+/// algorithm id.
+const MAGIC: [u8; 4] = *b"x8ck";
+
+/// Digest algorithm of `ChecksumIntKv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Checksum {
+    /// BLAKE2s, truncated to 128 bits. Also catches deliberate changes if
+    /// the attacker cannot recompute it, ex. below encryption.
+    Blake2s,
+
+    /// XXH64. Faster, for accidental corruption only.
+    Xxhash,
+}
+
+impl Checksum {
+    fn id(self) -> u8 {
+        match self {
+            Checksum::Blake2s => 1,
+            Checksum::Xxhash => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Checksum::Blake2s),
+            2 => Some(Checksum::Xxhash),
+            _ => None,
+        }
+    }
+
+    fn digest_size(self) -> usize {
+        match self {
+            Checksum::Blake2s => 16,
+            Checksum::Xxhash => 8,
+        }
+    }
+
+    /// Bytes added to each entry.
+    pub fn overhead(self) -> usize {
+        MAGIC.len() + 1 + self.digest_size()
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Checksum::Blake2s => Blake2s::digest(data)[..16].to_vec(),
+            Checksum::Xxhash => xxh64(data, 0).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake2s" => Ok(Checksum::Blake2s),
+            "xxhash" => Ok(Checksum::Xxhash),
+            _ => Err(format!(
+                "unknown checksum {} (expected blake2s or xxhash)",
+                s
+            )),
+        }
+    }
+}
+
+/// An entry does not match its checksum. Reported as
+/// `io::ErrorKind::InvalidData`.
+#[derive(Debug)]
+pub struct ChecksumError {
+    pub index: usize,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "entry {} does not match its checksum", self.index)
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+#[derive(Debug)]
+pub struct ChecksumIntKv {
+    kv: Box<dyn IntKv>,
+    checksum: Checksum,
+    /// Entries read without a checksum and not rewritten since.
+    unchecked: Mutex<BTreeSet<usize>>,
+}
+
+impl ChecksumIntKv {
+    /// Add `checksum` to entries written to `kv`.
+    pub fn new(kv: Box<dyn IntKv>, checksum: Checksum) -> Self {
+        Self {
+            kv,
+            checksum,
+            unchecked: Default::default(),
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> Bytes {
+        let mut encoded = Vec::with_capacity(self.checksum.overhead() + data.len());
+        encoded.extend_from_slice(&MAGIC);
+        encoded.push(self.checksum.id());
+        encoded.extend_from_slice(&self.checksum.digest(data));
+        encoded.extend_from_slice(data);
+        encoded.into()
+    }
+
+    fn decode(&self, index: usize, data: Bytes) -> io::Result<Bytes> {
+        if !data.starts_with(&MAGIC) {
+            self.unchecked.lock().insert(index);
+            return Ok(data);
+        }
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let id = *data
+            .get(MAGIC.len())
+            .ok_or_else(|| invalid(format!("entry {} has a truncated checksum header", index)))?;
+        let checksum = Checksum::from_id(id)
+            .ok_or_else(|| invalid(format!("entry {} uses unknown checksum {}", index, id)))?;
+        let start = checksum.overhead();
+        if data.len() < start {
+            return Err(invalid(format!(
+                "entry {} has a truncated checksum header",
+                index
+            )));
+        }
+        let content = data.slice(start..);
+        if data[MAGIC.len() + 1..start] != checksum.digest(&content)[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ChecksumError { index },
+            ));
+        }
+        Ok(content)
+    }
+
+    fn forget(&self, index: usize) {
+        self.unchecked.lock().remove(&index);
+    }
+}
+
+impl IntKv for ChecksumIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.decode(index, self.kv.read(index)?)
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let results = self.kv.read_batch(indexes);
+        indexes
+            .iter()
+            .zip(results)
+            .map(|(&index, r)| r.and_then(|data| self.decode(index, data)))
+            .collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let data = self.encode(&data);
+        self.forget(index);
+        self.kv.write(index, data)
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let data = self.encode(&data);
+        self.forget(index);
+        self.kv.write_with_hint(index, data, hint)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let items = items
+            .into_iter()
+            .map(|(index, data)| {
+                self.forget(index);
+                (index, self.encode(&data))
+            })
+            .collect();
+        self.kv.write_batch(items)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.forget(index);
+        self.kv.remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.kv.has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.kv.keys()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.kv.flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.kv.flush_keys(keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        let unchecked = self.unchecked.lock().len();
+        stats.insert("checksum.unchecked_entries".into(), unchecked as u64);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+}
+
+/// XXH64 of `data`.
+fn xxh64(data: &[u8], seed: u64) -> u64 {
+    const P1: u64 = 11_400_714_785_074_694_791;
+    const P2: u64 = 14_029_467_366_897_019_727;
+    const P3: u64 = 1_609_587_929_392_839_161;
+    const P4: u64 = 9_650_029_242_287_828_579;
+    const P5: u64 = 2_870_177_450_012_600_261;
+    let round = |acc: u64, input: u64| {
+        acc.wrapping_add(input.wrapping_mul(P2))
+            .rotate_left(31)
+            .wrapping_mul(P1)
+    };
+    let merge = |acc: u64, v: u64| (acc ^ round(0, v)).wrapping_mul(P1).wrapping_add(P4);
+    let u64_at = |b: &[u8]| u64::from_le_bytes(b[..8].try_into().unwrap());
+
+    let mut rest = data;
+    let mut h = if data.len() >= 32 {
+        let mut v = [
+            seed.wrapping_add(P1).wrapping_add(P2),
+            seed.wrapping_add(P2),
+            seed,
+            seed.wrapping_sub(P1),
+        ];
+        while rest.len() >= 32 {
+            for (i, v) in v.iter_mut().enumerate() {
+                *v = round(*v, u64_at(&rest[i * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let mut h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        for &v in &v {
+            h = merge(h, v);
+        }
+        h
+    } else {
+        seed.wrapping_add(P5)
+    };
+    h = h.wrapping_add(data.len() as u64);
+    while rest.len() >= 8 {
+        h ^= round(0, u64_at(rest));
+        h = h.rotate_left(27).wrapping_mul(P1).wrapping_add(P4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        let k = u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64;
+        h ^= k.wrapping_mul(P1);
+        h = h.rotate_left(23).wrapping_mul(P2).wrapping_add(P3);
+        rest = &rest[4..];
+    }
+    for &b in rest {
+        h ^= (b as u64).wrapping_mul(P5);
+        h = h.rotate_left(11).wrapping_mul(P1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(P2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(P3);
+    h ^ (h >> 32)
+}
+
+#[test]
+fn test_checksum_int_kv() {
+    use super::super::backend::MemIntKv;
+    for &checksum in &[Checksum::Blake2s, Checksum::Xxhash] {
+        super::super::test_int_kv(
+            |kv| kv.unwrap_or_else(|| ChecksumIntKv::new(Box::new(MemIntKv::new()), checksum)),
+            50,
+        );
+    }
+}
+
+#[test]
+fn test_checksum_int_kv_corruption() {
+    use super::super::backend::MemIntKv;
+    let mut kv = ChecksumIntKv::new(Box::new(MemIntKv::new()), Checksum::Xxhash);
+    kv.write(1, b"hello".to_vec().into()).unwrap();
+    let mut stored = kv.kv.read(1).unwrap().to_vec();
+    assert_eq!(stored.len(), 5 + Checksum::Xxhash.overhead());
+    *stored.last_mut().unwrap() ^= 1;
+    kv.kv.write(1, stored.into()).unwrap();
+    let e = kv.read(1).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "entry 1 does not match its checksum");
+
+    // Entries written before checksums are read, and counted until they
+    // are rewritten. Entries of another algorithm are still checked.
+    kv.kv.write(2, b"old".to_vec().into()).unwrap();
+    assert_eq!(kv.read(2).unwrap().as_ref(), b"old");
+    assert_eq!(kv.stats()["checksum.unchecked_entries"], 1);
+    kv.write(2, b"new".to_vec().into()).unwrap();
+    assert_eq!(kv.stats()["checksum.unchecked_entries"], 0);
+    kv.checksum = Checksum::Blake2s;
+    assert_eq!(kv.read(2).unwrap().as_ref(), b"new");
+    kv.kv.write(3, b"x8ck\x09".to_vec().into()).unwrap();
+    assert!(kv.read(3).is_err());
+}
+
+#[test]
+fn test_xxh64() {
+    assert_eq!(xxh64(b"", 0), 0xef46_db37_51d8_e999);
+    assert_eq!(xxh64(b"abc", 0), 0x44bc_2cf5_ad77_0999);
+    let long = b"Nobody inspects the spammish repetition";
+    assert_eq!(xxh64(long, 0), 0xfbce_a83c_8a37_8bf1);
+}
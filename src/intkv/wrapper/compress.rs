@@ -0,0 +1,271 @@
+//! Compress entries before they reach the layers below, so encryption sees
+//! compressed data.
+//!
+//! Each entry starts with a flag byte: `RAW`, followed by the entry as is,
+//! or `ZSTD`, followed by the u32 little-endian size of the entry and a
+//! zstd frame. Small entries, entries larger than `MAX_SIZE` and entries
+//! that do not shrink enough are stored raw. Entries written before zstd
+//! was used start with `LZ`, like `ZSTD` but with an LZ4-style block.
+//!
+//! Compressed sizes depend on the content. Someone who can both choose
+//! part of an entry (ex. by sharing a file that gets copied into the vault)
+//! and watch block sizes may learn about the rest of it.
+
+use super::super::{Bytes, CacheLimits, Hint, IntKv, Stats};
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+
+mod lz;
+
+const RAW: u8 = 0;
+const LZ: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// Entries smaller than this are stored raw.
+const MIN_SIZE: usize = 64;
+
+/// Entries larger than this, the largest block size, are stored raw. Reads
+/// refuse larger sizes, so a corrupted size cannot make them allocate more.
+const MAX_SIZE: usize = 64 << 20;
+
+#[derive(Debug)]
+pub struct CompressIntKv {
+    kv: Box<dyn IntKv>,
+    level: u8,
+    /// Bytes written, before and after compression.
+    raw_bytes: u64,
+    stored_bytes: u64,
+    compressed_entries: u64,
+    raw_entries: u64,
+}
+
+impl CompressIntKv {
+    /// Default level, trading speed for ratio.
+    pub const DEFAULT_LEVEL: u8 = 3;
+
+    pub fn new(kv: Box<dyn IntKv>) -> Self {
+        Self {
+            kv,
+            level: Self::DEFAULT_LEVEL,
+            raw_bytes: 0,
+            stored_bytes: 0,
+            compressed_entries: 0,
+            raw_entries: 0,
+        }
+    }
+
+    /// zstd level, from 1 (fastest) to 9.
+    pub fn with_level(mut self, level: u8) -> Self {
+        self.level = level.clamp(1, 9);
+        self
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Bytes {
+        self.raw_bytes += data.len() as u64;
+        if (MIN_SIZE..=MAX_SIZE).contains(&data.len()) {
+            let compressed = zstd::bulk::compress(data, self.level.into()).unwrap_or_default();
+            // Keep it only if it saves at least 1/8.
+            if !compressed.is_empty() && compressed.len() + 5 <= data.len() - data.len() / 8 {
+                let mut encoded = Vec::with_capacity(compressed.len() + 5);
+                encoded.push(ZSTD);
+                encoded.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                encoded.extend_from_slice(&compressed);
+                self.stored_bytes += encoded.len() as u64;
+                self.compressed_entries += 1;
+                return encoded.into();
+            }
+        }
+        let mut encoded = Vec::with_capacity(data.len() + 1);
+        encoded.push(RAW);
+        encoded.extend_from_slice(data);
+        self.stored_bytes += encoded.len() as u64;
+        self.raw_entries += 1;
+        encoded.into()
+    }
+}
+
+fn decode(data: Bytes) -> io::Result<Bytes> {
+    let flag = match data.first() {
+        Some(&RAW) => return Ok(data.slice(1..)),
+        Some(&flag) if (flag == LZ || flag == ZSTD) && data.len() >= 5 => flag,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "entry is not in a known compression format",
+            ))
+        }
+    };
+    let raw_len = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+    if raw_len > MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("compressed entry claims {} bytes", raw_len),
+        ));
+    }
+    if flag == LZ {
+        return Ok(lz::decompress(&data[5..], raw_len)?.into());
+    }
+    match zstd::bulk::decompress(&data[5..], raw_len) {
+        Ok(raw) if raw.len() == raw_len => Ok(raw.into()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted compressed entry",
+        )),
+    }
+}
+
+impl IntKv for CompressIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        decode(self.kv.read(index)?)
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let results = self.kv.read_batch(indexes);
+        results.into_iter().map(|r| r.and_then(decode)).collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let data = self.encode(&data);
+        self.kv.write(index, data)
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let data = self.encode(&data);
+        self.kv.write_with_hint(index, data, hint)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let items = items
+            .into_iter()
+            .map(|(index, data)| (index, self.encode(&data)))
+            .collect();
+        self.kv.write_batch(items)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.kv.remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.kv.has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.kv.keys()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.kv.flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.kv.flush_keys(keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        stats.insert("compress.raw_bytes".into(), self.raw_bytes);
+        stats.insert("compress.stored_bytes".into(), self.stored_bytes);
+        stats.insert(
+            "compress.compressed_entries".into(),
+            self.compressed_entries,
+        );
+        stats.insert("compress.raw_entries".into(), self.raw_entries);
+        stats
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+}
+
+#[test]
+fn test_compress_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| CompressIntKv::new(Box::new(MemIntKv::new()))),
+        100,
+    );
+}
+
+#[test]
+fn test_compress_int_kv_ratio() {
+    use super::super::backend::MemIntKv;
+    use rand::{Rng, SeedableRng};
+    let mut kv = CompressIntKv::new(Box::new(MemIntKv::new())).with_level(9);
+    let text = "fn main() {\n    println!(\"hello\");\n}\n".repeat(100);
+    kv.write(1, text.clone().into_bytes().into()).unwrap();
+    let stored = kv.kv.read(1).unwrap();
+    assert_eq!(stored[0], ZSTD);
+    assert!(stored.len() < text.len() / 10);
+    assert_eq!(kv.read(1).unwrap().as_ref(), text.as_bytes());
+
+    // Random and small entries are kept raw.
+    let mut rng = rand_chacha::ChaChaRng::seed_from_u64(0);
+    let random: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+    kv.write(2, random.clone().into()).unwrap();
+    kv.write(3, vec![0; 10].into()).unwrap();
+    assert_eq!(kv.kv.read(2).unwrap()[0], RAW);
+    assert_eq!(kv.kv.read(2).unwrap().len(), random.len() + 1);
+    assert_eq!(kv.kv.read(3).unwrap()[0], RAW);
+    assert_eq!(kv.read(2).unwrap().as_ref(), &random[..]);
+    let stats = kv.stats();
+    assert_eq!(stats["compress.compressed_entries"], 1);
+    assert_eq!(stats["compress.raw_entries"], 2);
+
+    kv.kv.write(4, vec![9, 9].into()).unwrap();
+    assert_eq!(kv.read(4).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // Sizes above the limit are refused before allocating.
+    for &flag in &[LZ, ZSTD] {
+        let mut huge = vec![flag];
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge.push(0);
+        kv.kv.write(5, huge.into()).unwrap();
+        assert_eq!(kv.read(5).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // A wrong size is detected.
+    let mut wrong = kv.kv.read(1).unwrap().to_vec();
+    wrong[1] ^= 1;
+    kv.kv.write(6, wrong.into()).unwrap();
+    assert_eq!(kv.read(6).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_compress_int_kv_lz() {
+    use super::super::backend::MemIntKv;
+    // Entries compressed before zstd are still read.
+    let mut kv = CompressIntKv::new(Box::new(MemIntKv::new()));
+    let text = "hello hello hello hello ".repeat(20);
+    let mut stored = vec![LZ];
+    stored.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    stored.extend_from_slice(&lz::compress(text.as_bytes(), 3));
+    kv.kv.write(1, stored.into()).unwrap();
+    assert_eq!(kv.read(1).unwrap().as_ref(), text.as_bytes());
+}
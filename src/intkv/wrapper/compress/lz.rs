@@ -0,0 +1,208 @@
+//! A small LZ77 codec in the style of the LZ4 block format, to read entries
+//! compressed before zstd was used. The encoder is only kept for tests.
+//!
+//! A block is a list of sequences. Each starts with a token byte holding
+//! the literal length (high nibble) and the match length minus 4 (low
+//! nibble); nibble 15 means more length bytes follow, each adding up to
+//! 255. Then come the literals, and unless this is the last sequence, the
+//! match offset as a little-endian u16 and the remaining match length.
+
+use std::io;
+
+/// Shortest match worth encoding.
+const MIN_MATCH: usize = 4;
+
+/// Decompress a block that decompresses to `raw_len` bytes.
+pub fn decompress(data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted compressed entry");
+    // Each byte of a block expands to at most 255 bytes, by a length byte.
+    if raw_len > data.len().saturating_mul(255) {
+        return Err(invalid());
+    }
+    let mut out = Vec::with_capacity(raw_len);
+    let mut i = 0;
+    loop {
+        let token = *data.get(i).ok_or_else(invalid)?;
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_len(data, &mut i).ok_or_else(invalid)?;
+        }
+        let literals = data.get(i..i + literals).ok_or_else(invalid)?;
+        if out.len() + literals.len() > raw_len {
+            return Err(invalid());
+        }
+        out.extend_from_slice(literals);
+        i += literals.len();
+        if i == data.len() {
+            break;
+        }
+        let offset = data.get(i..i + 2).ok_or_else(invalid)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        i += 2;
+        let mut len = (token & 15) as usize + MIN_MATCH;
+        if token & 15 == 15 {
+            len += read_len(data, &mut i).ok_or_else(invalid)?;
+        }
+        if offset == 0 || offset > out.len() || out.len() + len > raw_len {
+            return Err(invalid());
+        }
+        let start = out.len() - offset;
+        if offset >= len {
+            out.extend_from_within(start..start + len);
+        } else {
+            // Overlapping, ex. a run of one byte.
+            for k in start..start + len {
+                out.push(out[k]);
+            }
+        }
+    }
+    if out.len() != raw_len {
+        return Err(invalid());
+    }
+    Ok(out)
+}
+
+fn read_len(data: &[u8], i: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *data.get(*i)?;
+        *i += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+#[cfg(test)]
+pub use encode::compress;
+
+#[cfg(test)]
+mod encode {
+    use super::MIN_MATCH;
+
+    /// Farthest a match can look back.
+    const WINDOW: usize = 1 << 16;
+
+    const HASH_BITS: u32 = 14;
+
+    /// Compress `input`. `level` (1 to 9) is how hard to look for matches.
+    pub fn compress(input: &[u8], level: u8) -> Vec<u8> {
+        let depth = 1usize << (level.clamp(1, 9) - 1);
+        let mut out = Vec::with_capacity(input.len() / 2 + 16);
+        // Most recent position of each hash, and the previous position with
+        // the same hash of each position in the window.
+        let mut head = vec![usize::MAX; 1 << HASH_BITS];
+        let mut prev = vec![usize::MAX; WINDOW.min(input.len().max(1))];
+        let mut anchor = 0;
+        let mut pos = 0;
+        while pos + MIN_MATCH <= input.len() {
+            let (mut best_len, mut best_offset) = (0, 0);
+            let mut candidate = head[hash(&input[pos..])];
+            for _ in 0..depth {
+                if candidate == usize::MAX || pos - candidate >= WINDOW {
+                    break;
+                }
+                let len = match_len(&input[candidate..], &input[pos..]);
+                if len > best_len {
+                    best_len = len;
+                    best_offset = pos - candidate;
+                }
+                let next = prev[candidate % prev.len()];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+            }
+            insert(input, pos, &mut head, &mut prev);
+            if best_len < MIN_MATCH {
+                pos += 1;
+                continue;
+            }
+            write_sequence(&mut out, &input[anchor..pos], Some((best_offset, best_len)));
+            for p in pos + 1..(pos + best_len).min(input.len() - MIN_MATCH + 1) {
+                insert(input, p, &mut head, &mut prev);
+            }
+            pos += best_len;
+            anchor = pos;
+        }
+        write_sequence(&mut out, &input[anchor..], None);
+        out
+    }
+
+    fn hash(data: &[u8]) -> usize {
+        let v = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(input: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+        let h = hash(&input[pos..]);
+        prev[pos % prev.len()] = head[h];
+        head[h] = pos;
+    }
+
+    fn match_len(a: &[u8], b: &[u8]) -> usize {
+        a.iter().zip(b).take_while(|(x, y)| x == y).count()
+    }
+
+    fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+        let match_nibble = matched.map_or(0, |(_, len)| (len - MIN_MATCH).min(15));
+        out.push(((literals.len().min(15) as u8) << 4) | match_nibble as u8);
+        if literals.len() >= 15 {
+            write_len(out, literals.len() - 15);
+        }
+        out.extend_from_slice(literals);
+        if let Some((offset, len)) = matched {
+            out.extend_from_slice(&(offset as u16).to_le_bytes());
+            if len - MIN_MATCH >= 15 {
+                write_len(out, len - MIN_MATCH - 15);
+            }
+        }
+    }
+
+    fn write_len(out: &mut Vec<u8>, mut len: usize) {
+        while len >= 255 {
+            out.push(255);
+            len -= 255;
+        }
+        out.push(len as u8);
+    }
+}
+
+#[test]
+fn test_lz_round_trip() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand_chacha::ChaChaRng::seed_from_u64(0);
+    let random: Vec<u8> = (0..5000).map(|_| rng.gen()).collect();
+    let text = "the quick brown fox jumps over the lazy dog. ".repeat(300);
+    let inputs: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"abc".to_vec(),
+        vec![7; 100_000],
+        random.clone(),
+        text.clone().into_bytes(),
+        [&random[..100], text.as_bytes(), &random[..]].concat(),
+    ];
+    for input in &inputs {
+        for level in 1..=9 {
+            let compressed = compress(input, level);
+            assert_eq!(&decompress(&compressed, input.len()).unwrap(), input);
+        }
+    }
+    assert!(compress(text.as_bytes(), 3).len() < text.len() / 20);
+    assert!(compress(&vec![7; 100_000], 1).len() < 500);
+}
+
+#[test]
+fn test_lz_corrupted() {
+    let input = "hello hello hello hello".repeat(10);
+    let compressed = compress(input.as_bytes(), 3);
+    assert!(decompress(&compressed, input.len() + 1).is_err());
+    assert!(decompress(&compressed[..compressed.len() - 1], input.len()).is_err());
+    assert!(decompress(&[], 0).is_err());
+    // A match before the start.
+    assert!(decompress(&[0x10, b'a', 2, 0], 5).is_err());
+    // More than the block can expand to.
+    assert!(decompress(&[0], usize::MAX).is_err());
+}
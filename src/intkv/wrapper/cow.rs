@@ -0,0 +1,622 @@
+//! Point-in-time views of an `IntKv` that are cheap to take: `take_snapshot`
+//! only records a new snapshot. Afterwards, the first change of each entry
+//! copies its value, or records its absence, for the latest snapshot.
+//!
+//! A snapshot sees an entry as the first copy recorded by it or a later
+//! snapshot, or as the live entry if none changed it since. Deleting a
+//! snapshot hands its copies to the previous one if it needs them, and
+//! frees the others.
+//!
+//! Copies are stored from `COPY_BASE`. The manifest of snapshots is
+//! written on flush, split into records at `MANIFEST_INDEX` and below, so
+//! it is persisted with the changes it covers.
+
+use super::super::{Bytes, CacheLimits, IntKv, Stats};
+use crate::util::{bincode_deserialize, bincode_serialize_pad};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Reserved index of the first record of the manifest. Further records
+/// are below it. Below the indexes `VersionedIntKv` reserves, so either
+/// can be on top of the other.
+const MANIFEST_INDEX: usize = usize::MAX / 2;
+
+/// Start of indexes storing copies. Entries from it are not supported.
+const COPY_BASE: usize = usize::MAX / 4 + 1;
+
+/// End of indexes storing copies, leaving room for the manifest.
+const COPY_END: usize = MANIFEST_INDEX - (1 << 20);
+
+#[derive(Debug)]
+pub struct CowIntKv {
+    state: Arc<RwLock<State>>,
+}
+
+/// Read-only view of a snapshot of a `CowIntKv`. Fails once the snapshot
+/// is deleted.
+#[derive(Debug)]
+pub struct SnapshotView {
+    state: Arc<RwLock<State>>,
+    id: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    kv: Box<dyn IntKv>,
+    manifest: Manifest,
+    record_size: usize,
+    /// Records of the manifest on storage.
+    manifest_records: usize,
+    /// Whether the manifest differs from storage.
+    dirty: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    /// Index of the next copy, from `COPY_BASE`.
+    next_copy: usize,
+    /// Oldest first.
+    snapshots: Vec<Snapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    id: u64,
+    /// Seconds since the Unix epoch.
+    taken: u64,
+    /// Values of entries changed after it, before the next snapshot. None
+    /// if the entry did not exist.
+    copies: BTreeMap<usize, Option<Saved>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Saved {
+    index: usize,
+    len: u64,
+}
+
+/// A snapshot, as listed by `CowIntKv::snapshots`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub id: u64,
+    /// Seconds since the Unix epoch.
+    pub taken: u64,
+    /// Entries copied for it.
+    pub copies: usize,
+    pub copy_bytes: u64,
+}
+
+fn is_reserved(index: usize) -> bool {
+    (COPY_BASE..=MANIFEST_INDEX).contains(&index)
+}
+
+fn reserved_error(index: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("entry {} is reserved for snapshots", index),
+    )
+}
+
+fn deleted_error(id: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("snapshot {} does not exist", id),
+    )
+}
+
+impl CowIntKv {
+    /// Keep snapshots of `kv`, loading those taken before.
+    pub fn open(kv: Box<dyn IntKv>) -> io::Result<Self> {
+        let (manifest, manifest_records) = load_manifest(kv.as_ref())?;
+        let state = State {
+            kv,
+            manifest: manifest.unwrap_or_default(),
+            record_size: 0,
+            manifest_records,
+            dirty: false,
+        };
+        Ok(Self {
+            state: Arc::new(RwLock::new(state)),
+        })
+    }
+
+    /// Pad records of the manifest to `size`, and split the manifest into
+    /// records of that size, for storage expecting entries of a fixed size.
+    pub fn with_record_size(self, size: usize) -> Self {
+        self.state.write().record_size = size;
+        self
+    }
+
+    /// Take a snapshot of the current entries, including pending changes,
+    /// then flush. Return its id.
+    pub fn take_snapshot(&mut self) -> io::Result<u64> {
+        let mut state = self.state.write();
+        let id = state.manifest.next_id;
+        state.manifest.next_id += 1;
+        let taken = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        state.manifest.snapshots.push(Snapshot {
+            id,
+            taken,
+            copies: BTreeMap::new(),
+        });
+        state.dirty = true;
+        state.flush()?;
+        Ok(id)
+    }
+
+    /// Snapshots, oldest first.
+    pub fn snapshots(&self) -> Vec<SnapshotInfo> {
+        let state = self.state.read();
+        let snapshots = state.manifest.snapshots.iter();
+        snapshots
+            .map(|s| {
+                let copies = s.copies.values().flatten();
+                SnapshotInfo {
+                    id: s.id,
+                    taken: s.taken,
+                    copies: s.copies.len(),
+                    copy_bytes: copies.map(|c| c.len).sum(),
+                }
+            })
+            .collect()
+    }
+
+    /// Read-only view of snapshot `id`.
+    pub fn view(&self, id: u64) -> io::Result<SnapshotView> {
+        self.state.read().position(id)?;
+        Ok(SnapshotView {
+            state: self.state.clone(),
+            id,
+        })
+    }
+
+    /// Delete snapshot `id`, then flush. Return the number of copies freed.
+    pub fn delete_snapshot(&mut self, id: u64) -> io::Result<usize> {
+        let mut state = self.state.write();
+        let position = state.position(id)?;
+        let snapshot = state.manifest.snapshots.remove(position);
+        let mut freed = Vec::new();
+        match position.checked_sub(1) {
+            Some(previous) => {
+                let previous = &mut state.manifest.snapshots[previous].copies;
+                for (index, copy) in snapshot.copies {
+                    // The previous snapshot saw the same value, unless it
+                    // recorded its own copy.
+                    match previous.contains_key(&index) {
+                        true => freed.extend(copy),
+                        false => {
+                            previous.insert(index, copy);
+                        }
+                    }
+                }
+            }
+            None => freed.extend(snapshot.copies.into_values().flatten()),
+        }
+        for copy in &freed {
+            state.kv.remove(copy.index)?;
+        }
+        state.dirty = true;
+        state.flush()?;
+        Ok(freed.len())
+    }
+}
+
+impl State {
+    fn position(&self, id: u64) -> io::Result<usize> {
+        let snapshots = &self.manifest.snapshots;
+        snapshots
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or_else(|| deleted_error(id))
+    }
+
+    /// Copy the values of `indexes` for the latest snapshot before their
+    /// first change after it.
+    fn copy_before_change(&mut self, indexes: &[usize]) -> io::Result<()> {
+        if let Some(&index) = indexes.iter().find(|&&i| is_reserved(i)) {
+            return Err(reserved_error(index));
+        }
+        let latest = match self.manifest.snapshots.last() {
+            Some(latest) => latest,
+            None => return Ok(()),
+        };
+        let indexes: Vec<usize> = indexes
+            .iter()
+            .filter(|i| !latest.copies.contains_key(i))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        let mut items = Vec::new();
+        let mut copies = Vec::new();
+        let mut next_copy = self.manifest.next_copy;
+        for (&index, result) in indexes.iter().zip(self.kv.read_batch(&indexes)) {
+            let data = match result {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    copies.push((index, None));
+                    continue;
+                }
+                result => result?,
+            };
+            let copy_index = next_copy
+                .checked_add(COPY_BASE)
+                .filter(|&i| i < COPY_END)
+                .ok_or_else(|| io::Error::other("no indexes are left for snapshots"))?;
+            next_copy += 1;
+            let copy = Saved {
+                index: copy_index,
+                len: data.len() as u64,
+            };
+            items.push((copy_index, data));
+            copies.push((index, Some(copy)));
+        }
+        let (_, result) = self.kv.write_batch(items);
+        result?;
+        self.manifest.next_copy = next_copy;
+        let latest = self.manifest.snapshots.last_mut().unwrap();
+        latest.copies.extend(copies);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Read `index` as snapshot `id` saw it.
+    fn read_snapshot(&self, id: u64, index: usize) -> io::Result<Bytes> {
+        if is_reserved(index) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let position = self.position(id)?;
+        for snapshot in &self.manifest.snapshots[position..] {
+            match snapshot.copies.get(&index) {
+                Some(Some(copy)) => return self.kv.read(copy.index),
+                Some(None) => return Err(io::ErrorKind::NotFound.into()),
+                None => {}
+            }
+        }
+        self.kv.read(index)
+    }
+
+    fn snapshot_keys(&self, id: u64) -> io::Result<Vec<usize>> {
+        let position = self.position(id)?;
+        let mut keys: BTreeSet<usize> = self.live_keys()?.into_iter().collect();
+        let mut decided = BTreeSet::new();
+        for snapshot in &self.manifest.snapshots[position..] {
+            for (&index, copy) in &snapshot.copies {
+                if decided.insert(index) {
+                    match copy {
+                        Some(_) => keys.insert(index),
+                        None => keys.remove(&index),
+                    };
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn live_keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys = self.kv.keys()?;
+        keys.retain(|&index| !is_reserved(index));
+        Ok(keys)
+    }
+
+    fn save_manifest(&mut self) -> io::Result<()> {
+        let data = bincode_serialize_pad(&self.manifest, 0);
+        let mut record = (data.len() as u64).to_le_bytes().to_vec();
+        record.extend_from_slice(&data);
+        let size = match self.record_size {
+            0 => record.len(),
+            size => size,
+        };
+        let items = record
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut chunk = chunk.to_vec();
+                chunk.resize(size, 0);
+                (MANIFEST_INDEX - i, chunk.into())
+            })
+            .collect::<Vec<_>>();
+        let count = items.len();
+        let (_, result) = self.kv.write_batch(items);
+        result?;
+        for i in count..self.manifest_records {
+            self.kv.remove(MANIFEST_INDEX - i)?;
+        }
+        self.manifest_records = count;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Copies must be recorded before changes are persisted.
+        if self.dirty {
+            self.save_manifest()?;
+            self.dirty = false;
+        }
+        self.kv.flush()
+    }
+}
+
+/// Read the manifest and the number of its records.
+fn load_manifest(kv: &dyn IntKv) -> io::Result<(Option<Manifest>, usize)> {
+    let first = match kv.read(MANIFEST_INDEX) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((None, 0)),
+        result => result?,
+    };
+    let corrupted = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the manifest of snapshots is corrupted",
+        )
+    };
+    let len = match first.get(..8) {
+        Some(len) => u64::from_le_bytes(len.try_into().unwrap()) as usize,
+        None => return Err(corrupted()),
+    };
+    let count = (len + 8).div_ceil(first.len());
+    let mut record = first.to_vec();
+    for i in 1..count {
+        record.extend_from_slice(&kv.read(MANIFEST_INDEX - i)?);
+    }
+    let manifest = record.get(8..8 + len).ok_or_else(corrupted)?;
+    let manifest = bincode_deserialize(manifest).map_err(|_| corrupted())?;
+    Ok((Some(manifest), count))
+}
+
+impl IntKv for CowIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        match is_reserved(index) {
+            true => Err(io::ErrorKind::NotFound.into()),
+            false => self.state.read().kv.read(index),
+        }
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        if indexes.iter().any(|&i| is_reserved(i)) {
+            return indexes.iter().map(|&i| self.read(i)).collect();
+        }
+        self.state.read().kv.read_batch(indexes)
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let mut state = self.state.write();
+        state.copy_before_change(&[index])?;
+        state.kv.write(index, data)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let mut state = self.state.write();
+        let indexes: Vec<usize> = items.iter().map(|(index, _)| *index).collect();
+        if let Err(e) = state.copy_before_change(&indexes) {
+            return (0, Err(e));
+        }
+        state.kv.write_batch(items)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let mut state = self.state.write();
+        state.copy_before_change(&[index])?;
+        state.kv.remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        match is_reserved(index) {
+            true => Ok(false),
+            false => self.state.read().kv.has(index),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.state.read().live_keys()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.write().flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let _ = keys;
+        self.flush()
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.state.read().kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.state.read().kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.state.read().kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.state.read().kv.stats();
+        let snapshots = self.snapshots();
+        let copies = snapshots.iter().map(|s| s.copies as u64).sum();
+        let copy_bytes = snapshots.iter().map(|s| s.copy_bytes).sum();
+        stats.insert("cow.snapshots".into(), snapshots.len() as u64);
+        stats.insert("cow.copies".into(), copies);
+        stats.insert("cow.copy_bytes".into(), copy_bytes);
+        stats
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.state.read().kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        let state = self.state.read();
+        match state.manifest.snapshots.is_empty() {
+            true => state.kv.check_space(extra),
+            // Changes might copy as many bytes.
+            false => state.kv.check_space(extra.saturating_mul(2)),
+        }
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.state.write().kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        let mut state = self.state.write();
+        state.flush()?;
+        state.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.state.read().kv.idempotent_writes()
+    }
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "snapshots cannot be changed",
+    )
+}
+
+impl IntKv for SnapshotView {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.state.read().read_snapshot(self.id, index)
+    }
+
+    fn write(&mut self, _index: usize, _data: Bytes) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn remove(&mut self, _index: usize) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        match self.read(index) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.state.read().position(self.id)?;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.state.read().snapshot_keys(self.id)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cow_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| CowIntKv::open(Box::new(MemIntKv::new())).unwrap()),
+        50,
+    );
+    // Also with changes copied for a snapshot.
+    super::super::test_int_kv(
+        |kv| {
+            kv.unwrap_or_else(|| {
+                let mut kv = CowIntKv::open(Box::new(MemIntKv::new())).unwrap();
+                kv.take_snapshot().unwrap();
+                kv
+            })
+        },
+        50,
+    );
+}
+
+#[test]
+fn test_cow_int_kv_views() {
+    use super::super::backend::MemIntKv;
+    let mut kv = CowIntKv::open(Box::new(MemIntKv::new())).unwrap();
+    kv.write(1, vec![1].into()).unwrap();
+    kv.write(2, vec![2].into()).unwrap();
+    let first = kv.take_snapshot().unwrap();
+    kv.write(1, vec![10].into()).unwrap();
+    kv.write(3, vec![3].into()).unwrap();
+    let second = kv.take_snapshot().unwrap();
+    kv.write(1, vec![100].into()).unwrap();
+    kv.write(2, vec![20].into()).unwrap();
+    kv.remove(3).unwrap();
+
+    let mut view = kv.view(first).unwrap();
+    assert_eq!(view.read(1).unwrap()[..], [1]);
+    assert_eq!(view.read(2).unwrap()[..], [2]);
+    assert!(!view.has(3).unwrap());
+    assert_eq!(view.keys().unwrap(), [1, 2]);
+    let view2 = kv.view(second).unwrap();
+    assert_eq!(view2.keys().unwrap(), [1, 2, 3]);
+    assert_eq!(view2.read(1).unwrap()[..], [10]);
+    assert_eq!(kv.keys().unwrap(), [1, 2]);
+    assert_eq!(kv.read(1).unwrap()[..], [100]);
+    assert!(view.write(1, vec![0].into()).is_err());
+
+    let stats = kv.stats();
+    assert_eq!(stats["cow.snapshots"], 2);
+    assert_eq!((stats["cow.copies"], stats["cow.copy_bytes"]), (5, 4));
+
+    // The first snapshot has its own records of entries 1 and 3, and
+    // takes over the copy of entry 2.
+    assert_eq!(kv.delete_snapshot(second).unwrap(), 2);
+    assert_eq!(view.read(2).unwrap()[..], [2]);
+    assert_eq!(view.keys().unwrap(), [1, 2]);
+    assert!(view2.read(1).is_err());
+    assert_eq!(kv.delete_snapshot(first).unwrap(), 2);
+    assert_eq!(kv.stats()["cow.copy_bytes"], 0);
+    assert_eq!(kv.state.read().kv.keys().unwrap(), [1, 2, MANIFEST_INDEX]);
+    assert!(kv.write(COPY_BASE, vec![0].into()).is_err());
+}
+
+#[test]
+fn test_cow_int_kv_persist() {
+    use super::super::backend::FsIntKv;
+    let dir = tempfile::tempdir().unwrap();
+    let open = || {
+        let fs_kv = FsIntKv::new(dir.path()).unwrap();
+        CowIntKv::open(Box::new(fs_kv))
+            .unwrap()
+            .with_record_size(64)
+    };
+    let mut kv = open();
+    // Enough snapshots for the manifest to take several records.
+    for i in 0..8 {
+        kv.write(i, vec![i as u8; 4].into()).unwrap();
+        kv.take_snapshot().unwrap();
+    }
+    kv.write(0, vec![9].into()).unwrap();
+    // Without a flush, a crash loses the change, but not the snapshot.
+    drop(kv);
+    let mut kv = open();
+    assert_eq!(kv.snapshots().len(), 8);
+    assert_eq!(kv.read(0).unwrap()[..], [0; 4]);
+
+    // Changes persisted by flush_keys come with the copies they need.
+    kv.write(0, vec![9].into()).unwrap();
+    kv.remove(7).unwrap();
+    kv.flush_keys(&[0, 7]).unwrap();
+    drop(kv);
+    let kv = open();
+    assert_eq!(kv.read(0).unwrap()[..], [9]);
+    assert_eq!(kv.snapshots()[7].copies, 2);
+    let view = kv.view(kv.snapshots()[0].id).unwrap();
+    assert_eq!(view.keys().unwrap(), [0]);
+    assert_eq!(view.read(0).unwrap()[..], [0; 4]);
+    let view = kv.view(kv.snapshots()[7].id).unwrap();
+    assert_eq!(view.keys().unwrap(), (0..8).collect::<Vec<_>>());
+    assert!(kv.state.read().manifest_records > 1);
+}
@@ -1,111 +1,686 @@
-use super::super::{Bytes, IntKv};
+use super::super::{Bytes, IntKv, Stats};
+use crate::util::{self, Secret};
 use aes::Aes256;
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::Aes256Gcm;
 use blake2::{Blake2s, Digest};
 use cfb_mode::cipher::{NewStreamCipher, StreamCipher};
 use cfb_mode::Cfb;
+use hmac::{Hmac, Mac, NewMac};
+use parking_lot::Mutex;
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+mod format;
+
+pub use format::HeaderVersion;
+use format::{Count, Format, Header};
 
 type Bits256 = [u8; 32];
 type Bits128 = [u8; 16];
 type AesCfb = Cfb<Aes256>;
 
-pub const IV_HEADER_SIZE: usize = 16;
+/// Reserved index storing the progress of `EncIntKv::rekey_all`.
+const REKEY_PROGRESS_INDEX: usize = usize::MAX;
+
+/// Entries to re-encrypt between progress updates in `EncIntKv::rekey_all`.
+const REKEY_FLUSH_ENTRIES: usize = 1024;
+
+/// Entries `EncIntKv::check_key` reads at most, so opening a directory of
+/// older entries stays fast.
+const KEY_CHECK_ENTRIES: usize = 16;
+
+/// Size of the authentication tag in `Cipher::Aes256Gcm` mode.
+const TAG_SIZE: usize = 16;
+
+/// Size of the MAC trailer in `Cipher::Aes256Cfb` mode.
+const MAC_SIZE: usize = 16;
+
+/// Encryption mode of `EncIntKv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Cipher {
+    /// AES256-CFB. No integrity check.
+    #[default]
+    Aes256Cfb,
+
+    /// AES256-GCM. Modified entries fail to decrypt.
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// Bytes appended to each entry: the MAC if any.
+    pub const fn trailer_size(self) -> usize {
+        match self {
+            Cipher::Aes256Cfb => MAC_SIZE,
+            Cipher::Aes256Gcm => 0,
+        }
+    }
+}
+
+/// An entry failed authentication. Reported as `io::ErrorKind::InvalidData`.
+#[derive(Debug)]
+pub struct AuthError {
+    pub index: usize,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "entry {} failed authentication (relocated or tampered)",
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl AuthError {
+    fn error(index: usize) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Self { index })
+    }
+}
+
+/// Result of `EncIntKv::audit_iv`.
+#[derive(Debug, Default)]
+pub struct IvAudit {
+    /// Entries scanned.
+    pub entries: usize,
+
+    /// Pairs of indexes whose entries use the same IV.
+    pub iv_collisions: Vec<(usize, usize)>,
+
+    /// Entries sharing their count with another entry.
+    pub shared_counts: usize,
+
+    /// Entries whose count came from the rng seeded with zeros, which older
+    /// versions used. Such counts repeat after deleting and rewriting.
+    pub weak_counts: usize,
+}
+
+impl IvAudit {
+    /// Whether IVs might have been reused.
+    pub fn affected(&self) -> bool {
+        !self.iv_collisions.is_empty() || self.shared_counts > 0 || self.weak_counts > 0
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes256cfb" => Ok(Cipher::Aes256Cfb),
+            "aes256gcm" => Ok(Cipher::Aes256Gcm),
+            _ => Err(format!(
+                "unknown cipher {} (expected aes256cfb or aes256gcm)",
+                s
+            )),
+        }
+    }
+}
 
 /// Wrap an `IntKv` with encryption.
 ///
 /// Each entry will be encrypted by AES256-CFB, with IV derived from 3 values:
-/// the master key, the integer index, and a 63-bit `Count` stored in the first
-/// 8 bytes of the block. The `Count` is preserved upon deletion to avoid
-/// reusing IVs.
+/// the entry key, the integer index, and a 128-bit `Count` stored in the
+/// header of the block. The `Count` is preserved upon deletion to avoid
+/// reusing IVs. Its highest 32 bits identify the key the entry was written
+/// with, and its `Format` unless the header records it (see `format`).
+///
+/// The entry key is derived from the master key, the index and the `Count`
+/// by HKDF, so each write uses its own key. Older entries use the master key
+/// directly and are converted when rewritten.
+///
+/// With `Cipher::Aes256Gcm`, the first 12 bytes of the IV are the nonce, the
+/// index is the associated data, and the tag is stored after the `Count`.
+/// With `Cipher::Aes256Cfb`, a keyed BLAKE2s MAC of the index, the `Count` and
+/// the ciphertext is appended. Either way, an entry copied to another index
+/// fails to authenticate. Entries written before MACs existed are still
+/// readable unless `with_required_mac` is set.
+///
+/// During a password change, entries written with the old key remain
+/// readable until `rekey_all` re-encrypts them with the new key.
 pub struct EncIntKv {
     /// The master key.
-    key: Bits256,
+    key: Secret<Bits256>,
+
+    /// The previous master key, if entries are being re-encrypted.
+    old_key: Option<Secret<Bits256>>,
+
+    /// The old key was verified by the caller, so entries telling nothing
+    /// about their key can be re-encrypted.
+    old_key_checked: bool,
+
+    /// Encryption mode.
+    cipher: Cipher,
+
+    /// Layout of entry headers.
+    version: HeaderVersion,
 
     /// Random number generator.
     rng: Box<dyn RngCore + Send + Sync>,
 
     /// The inner `IntKv` backend.
     kv: Box<dyn IntKv>,
+
+    /// Threads to encrypt or decrypt entries in batches.
+    threads: usize,
+
+    /// Reject `Cipher::Aes256Cfb` entries without a MAC.
+    required_mac: bool,
+
+    /// Entries read in older formats, until they are rewritten.
+    legacy: Mutex<BTreeMap<usize, Format>>,
+
+    /// Mix OS randomness into each new `Count`, so processes with the same
+    /// rng state do not pick the same counts. Off for deterministic tests.
+    os_entropy: bool,
 }
 
 impl fmt::Debug for EncIntKv {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("EncIntKv")
-            .field("key", &self.key)
+            .field("cipher", &self.cipher)
             .field("kv", &self.kv)
             .finish()
     }
 }
 
 impl EncIntKv {
-    pub const fn iv_header_size() -> usize {
-        IV_HEADER_SIZE
-    }
-
     pub fn from_key_rng_kv(
         key: Bits256,
         rng: Box<dyn RngCore + Send + Sync>,
         kv: Box<dyn IntKv>,
     ) -> Self {
-        Self { key, rng, kv }
+        Self {
+            key: Secret::new(key),
+            old_key: None,
+            old_key_checked: false,
+            cipher: Cipher::Aes256Cfb,
+            version: HeaderVersion::V0,
+            rng,
+            kv,
+            threads: util::default_threads(),
+            required_mac: false,
+            legacy: Default::default(),
+            os_entropy: false,
+        }
+    }
+
+    /// Set the encryption mode. It must match the mode existing entries
+    /// were written with.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Set the layout of entry headers. It must match the version the
+    /// directory was created with.
+    pub fn with_header_version(mut self, version: HeaderVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Also read entries written with `old_key`. New writes use the main key.
+    pub fn with_old_key(mut self, old_key: Bits256) -> Self {
+        self.old_key = Some(Secret::new(old_key));
+        self
+    }
+
+    /// Tell whether the old key was verified, for example against a check
+    /// value. Otherwise `rekey_all` refuses to re-encrypt entries without
+    /// key ids or MACs, as they decrypt with any key.
+    pub fn with_old_key_checked(mut self, checked: bool) -> Self {
+        self.old_key_checked = checked;
+        self
+    }
+
+    /// Reject entries without a MAC in `Cipher::Aes256Cfb` mode.
+    pub fn with_required_mac(mut self, required: bool) -> Self {
+        self.required_mac = required;
+        self
+    }
+
+    /// Keep the memory holding the keys out of swap. The `EncIntKv` should
+    /// not move afterwards, for example, by being boxed.
+    pub fn lock_key_memory(&self) -> io::Result<()> {
+        util::harden::lock_memory(&self.key[..])?;
+        if let Some(old_key) = &self.old_key {
+            util::harden::lock_memory(&old_key[..])?;
+        }
+        Ok(())
+    }
+
+    /// Set the number of threads to encrypt or decrypt entries in
+    /// `write_batch` and `read_batch`.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
     }
 
+    /// Construct with an rng seeded from the OS. Use `from_key_rng_kv` for
+    /// deterministic output.
     pub fn from_key_kv(key: Bits256, kv: Box<dyn IntKv>) -> Self {
-        let rng: rand_chacha::ChaChaRng = rand::SeedableRng::from_seed(Default::default());
-        Self::from_key_rng_kv(key, Box::new(rng), kv)
+        let rng: rand_chacha::ChaChaRng = rand::SeedableRng::from_entropy();
+        let mut kv = Self::from_key_rng_kv(key, Box::new(rng), kv);
+        kv.os_entropy = true;
+        kv
     }
 
     /// Get iv from blake2s(key, count, index).
-    fn iv(&self, index: usize, count: Count) -> Bits128 {
-        let mut b = Blake2s::new();
-        b.update(&self.key);
-        b.update(&count.to_bytes());
-        b.update(&(index as u64).to_be_bytes());
-        b.finalize().as_slice()[0..16].try_into().unwrap()
+    fn iv(key: &Bits256, index: usize, count: Count) -> Bits128 {
+        let mut b = Wiped(Blake2s::new());
+        b.update(key);
+        b.update(count.to_bytes());
+        b.update((index as u64).to_be_bytes());
+        b.finalize_reset().as_slice()[0..16].try_into().unwrap()
+    }
+
+    fn cipher(key: &Bits256, index: usize, count: Count) -> Wiped<AesCfb> {
+        let iv = Self::iv(key, index, count);
+        Wiped(AesCfb::new(key.into(), &iv.into()))
+    }
+
+    /// Pick the header for the next write of an entry.
+    fn next_header(&mut self, index: usize) -> io::Result<Header> {
+        let id = key_id(&self.key, Format::LATEST);
+        let mut header = if self.kv.has(index)? {
+            let old_data = self.kv.read(index)?;
+            self.read_header(&old_data)?.0.rewrite(&mut self.rng, id)
+        } else {
+            Header::new(self.rng.as_mut(), id)
+        };
+        if self.os_entropy {
+            header.count = header.count.mix(&mut rand::rngs::OsRng);
+        }
+        Ok(header)
+    }
+
+    fn read_header<'a>(&self, raw: &'a [u8]) -> io::Result<(Header, &'a [u8])> {
+        Header::decode(self.version, self.cipher, raw)
+    }
+
+    /// Nonce for `Cipher::Aes256Gcm`.
+    fn nonce(key: &Bits256, index: usize, count: Count) -> [u8; 12] {
+        Self::iv(key, index, count)[0..12].try_into().unwrap()
     }
 
-    fn cipher(&self, index: usize, count: Count) -> AesCfb {
-        let iv = self.iv(index, count);
-        AesCfb::new(&self.key.into(), &iv.into())
+    /// MAC of an entry in `Cipher::Aes256Cfb` mode.
+    fn mac(key: &Bits256, index: usize, count: Count, ciphertext: &[u8]) -> [u8; MAC_SIZE] {
+        let mut b = Wiped(Blake2s::new());
+        b.update(b"x79d8 mac key");
+        b.update(key);
+        let mut mac_key = b.finalize_reset();
+        let mut b = Wiped(Blake2s::with_params(mac_key.as_slice(), &[], &[]));
+        util::zeroize(mac_key.as_mut_slice());
+        b.update((index as u64).to_be_bytes());
+        b.update(count.to_bytes());
+        b.update(ciphertext);
+        b.finalize_reset().as_slice()[0..MAC_SIZE]
+            .try_into()
+            .unwrap()
+    }
+
+    /// Keys to decrypt an entry with, most likely first, and the format of
+    /// the entry (see `key_id`). Entries written before key ids existed have
+    /// random ids. They try the old key first, as the main key always writes
+    /// its id.
+    fn keys_for(&self, count: Count) -> Vec<(&Bits256, Format)> {
+        let keys: Vec<&Bits256> = std::iter::once(&*self.key)
+            .chain(self.old_key.as_deref())
+            .collect();
+        let id = count.key_id();
+        let matched: Vec<(&Bits256, Format)> = keys
+            .iter()
+            .flat_map(|&key| {
+                Format::ALL
+                    .iter()
+                    .filter(move |&&format| id == key_id(key, format))
+                    .map(move |&format| (key, format))
+            })
+            .collect();
+        if matched.is_empty() {
+            keys.into_iter()
+                .rev()
+                .map(|key| (key, Format::Unbound))
+                .collect()
+        } else {
+            matched
+        }
+    }
+
+    /// Decrypt an entry. Also report whether it was written with the old key.
+    fn decrypt(&self, index: usize, raw: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        let (data, is_old, _) = self.decrypt_format(index, raw)?;
+        Ok((data, is_old))
+    }
+
+    /// Like `decrypt`, also report the format of the entry.
+    fn decrypt_format(&self, index: usize, raw: &[u8]) -> io::Result<(Vec<u8>, bool, Format)> {
+        let (header, body) = self.read_header(raw)?;
+        let count = header.count;
+        log::info!("Decrypt {} ({} bytes)", index, body.len());
+        for (key, format) in self.keys_for(count) {
+            let format = header.format.unwrap_or(format);
+            let is_old = key != &*self.key;
+            let subkey = entry_key(key, index, count);
+            let key = match format {
+                Format::Subkey => &*subkey,
+                _ => key,
+            };
+            let bound = format != Format::Unbound;
+            let data = match self.cipher {
+                Cipher::Aes256Cfb if bound => {
+                    let split = match body.len().checked_sub(MAC_SIZE) {
+                        Some(split) => split,
+                        None => continue,
+                    };
+                    let (ciphertext, mac) = body.split_at(split);
+                    if !ct_eq(&Self::mac(key, index, count, ciphertext), mac) {
+                        continue;
+                    }
+                    let mut data = ciphertext.to_vec();
+                    Self::cipher(key, index, count).decrypt(&mut data);
+                    data
+                }
+                Cipher::Aes256Cfb => {
+                    // Without a MAC, trust the key id, or the order of
+                    // `keys_for`.
+                    if self.required_mac {
+                        continue;
+                    }
+                    log::debug!("Entry {} has no MAC", index);
+                    let mut data = body.to_vec();
+                    Self::cipher(key, index, count).decrypt(&mut data);
+                    data
+                }
+                Cipher::Aes256Gcm => {
+                    let mut data = body.to_vec();
+                    let gcm = AesGcm::new(key);
+                    let nonce = Self::nonce(key, index, count);
+                    let tag = &header.tag;
+                    // Older entries only bind the index through the nonce.
+                    let aad = if bound { index_aad(index) } else { Vec::new() };
+                    if !gcm.decrypt(&nonce, &aad, &mut data, tag) {
+                        continue;
+                    }
+                    data
+                }
+            };
+            log::debug!("Decrypt {} complete", index);
+            if format != Format::Subkey {
+                self.legacy.lock().insert(index, format);
+            }
+            return Ok((data, is_old, format));
+        }
+        Err(AuthError::error(index))
+    }
+
+    /// Write an entry without a key id or a MAC, as versions before either
+    /// existed did.
+    #[cfg(test)]
+    pub fn write_legacy(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        assert_eq!(self.cipher, Cipher::Aes256Cfb);
+        assert_eq!(self.version, HeaderVersion::V0);
+        let id = self.rng.next_u32();
+        let count = Count::new_random(self.rng.as_mut(), id);
+        let mut raw = count.to_bytes().to_vec();
+        raw.extend_from_slice(data);
+        Self::cipher(&self.key, index, count).encrypt(&mut raw[format::COUNT_SIZE..]);
+        self.kv.write(index, raw.into())
+    }
+
+    /// Check the main key against the first entries authenticated by a MAC
+    /// or a GCM tag. Return whether it decrypts, or None if no entry tells.
+    /// Entries without MACs decrypt with any key.
+    pub fn check_key(&self) -> io::Result<Option<bool>> {
+        for index in self.kv.keys()?.into_iter().take(KEY_CHECK_ENTRIES) {
+            match self.decrypt_format(index, &self.kv.read(index)?) {
+                Ok((_, _, Format::Unbound)) if self.cipher == Cipher::Aes256Cfb => {}
+                Ok(_) => return Ok(Some(true)),
+                Err(e) if e.get_ref().is_some_and(|e| e.is::<AuthError>()) => {
+                    return Ok(Some(false))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Re-encrypt an entry with the main key if it was written with the old
+    /// key. Return `true` if the entry was rewritten.
+    pub fn rekey_entry(&mut self, index: usize) -> io::Result<bool> {
+        let raw = self.kv.read(index)?;
+        let (data, is_old) = self.decrypt(index, &raw)?;
+        if is_old {
+            self.write(index, data.into())?;
+        }
+        Ok(is_old)
+    }
+
+    /// Re-encrypt all entries with the main key, then forget the old key.
+    ///
+    /// Progress is flushed to `REKEY_PROGRESS_INDEX` periodically so an
+    /// interrupted run continues where it stopped. `progress` is called with
+    /// the number of processed and total entries.
+    pub fn rekey_all(&mut self, mut progress: impl FnMut(usize, usize)) -> io::Result<()> {
+        let start = if self.kv.has(REKEY_PROGRESS_INDEX)? {
+            let data = self.read(REKEY_PROGRESS_INDEX)?;
+            match <[u8; 8]>::try_from(data.as_ref()) {
+                Ok(v) => u64::from_be_bytes(v),
+                Err(_) => return Err(io::ErrorKind::InvalidData.into()),
+            }
+        } else {
+            0
+        };
+        let keys: Vec<usize> = self
+            .kv
+            .keys()?
+            .into_iter()
+            .filter(|&i| i != REKEY_PROGRESS_INDEX && i as u64 >= start)
+            .collect();
+        if self.old_key.is_some() {
+            self.check_old_key(&keys)?;
+        }
+        for (i, &index) in keys.iter().enumerate() {
+            self.rekey_entry(index)?;
+            if (i + 1) % REKEY_FLUSH_ENTRIES == 0 {
+                let next = (index as u64 + 1).to_be_bytes().to_vec();
+                self.write(REKEY_PROGRESS_INDEX, next.into())?;
+                self.kv.flush()?;
+            }
+            progress(i + 1, keys.len());
+        }
+        if self.old_key.is_some() {
+            self.check_rekeyed()?;
+        }
+        if self.kv.has(REKEY_PROGRESS_INDEX)? {
+            self.kv.remove(REKEY_PROGRESS_INDEX)?;
+        }
+        self.kv.flush()?;
+        self.old_key = None;
+        Ok(())
+    }
+
+    /// Refuse to rekey unless the old key is known to be right: the caller
+    /// checked it, a remaining entry has its id, or the first entry without
+    /// a known id authenticates with it. Re-encrypting with a wrong old key
+    /// would destroy entries.
+    fn check_old_key(&self, keys: &[usize]) -> io::Result<()> {
+        let ids = |key: &Bits256| Format::ALL.map(|format| key_id(key, format));
+        let new_ids = ids(&self.key);
+        let old_ids = self.old_key.as_deref().map(ids);
+        let mut unknown = None;
+        for &index in keys {
+            let id = self.read_header(&self.kv.read(index)?)?.0.count.key_id();
+            if old_ids.is_some_and(|ids| ids.contains(&id)) {
+                return Ok(());
+            }
+            if !new_ids.contains(&id) {
+                unknown = unknown.or(Some(index));
+            }
+        }
+        let index = match unknown {
+            Some(index) if !self.old_key_checked => index,
+            _ => return Ok(()),
+        };
+        // Without a MAC, any key decrypts it.
+        if self.cipher == Cipher::Aes256Gcm {
+            if let Ok((_, true)) = self.decrypt(index, &self.kv.read(index)?) {
+                return Ok(());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no entry was written with the old key, or it cannot be verified",
+        ))
+    }
+
+    /// Refuse to forget the old key while an entry is not written with the
+    /// main key.
+    fn check_rekeyed(&self) -> io::Result<()> {
+        let id = key_id(&self.key, Format::LATEST);
+        for index in self.kv.keys()? {
+            if self.read_header(&self.kv.read(index)?)?.0.count.key_id() != id {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("entry {} was not re-encrypted", index),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Scan the headers of all entries for signs of IV reuse.
+    pub fn audit_iv(&self) -> io::Result<IvAudit> {
+        let keys = self.kv.keys()?;
+        let mut ivs: BTreeMap<Bits128, usize> = BTreeMap::new();
+        let mut counts: BTreeMap<[u8; 12], usize> = BTreeMap::new();
+        let weak = weak_count_values(keys.len() * 3 + 65536);
+        let mut audit = IvAudit::default();
+        for &index in &keys {
+            let raw = self.kv.read(index)?;
+            let (header, _) = self.read_header(&raw)?;
+            let count = header.count;
+            let (key, format) = self.keys_for(count)[0];
+            let format = header.format.unwrap_or(format);
+            let iv = match format {
+                Format::Subkey => Self::iv(&entry_key(key, index, count), index, count),
+                _ => Self::iv(key, index, count),
+            };
+            if let Some(&other) = ivs.get(&iv) {
+                audit.iv_collisions.push((other, index));
+            }
+            ivs.insert(iv, index);
+
+            // Ignore the key id.
+            let bits: [u8; 12] = count.to_bytes()[4..].try_into().unwrap();
+            *counts.entry(bits).or_default() += 1;
+            // Rewrites add 1 to the second half.
+            let low = count.1.saturating_sub(REWRITES_PER_WEAK_COUNT);
+            if weak.range(low..=count.1).next().is_some() {
+                audit.weak_counts += 1;
+            }
+            audit.entries += 1;
+        }
+        audit.shared_counts = counts.values().filter(|&&n| n > 1).sum();
+        Ok(audit)
+    }
+
+    fn encrypt(&self, index: usize, mut header: Header, data: &[u8]) -> Bytes {
+        let count = header.count;
+        let header_size = self.version.header_size(self.cipher);
+        let trailer_size = self.cipher.trailer_size();
+        let mut new_data = Vec::with_capacity(data.len() + header_size + trailer_size);
+        new_data.resize(header_size, 0);
+        new_data.extend_from_slice(data);
+        log::info!("Encrypt {} ({} bytes)", index, data.len());
+        let key = entry_key(&self.key, index, count);
+        match self.cipher {
+            Cipher::Aes256Cfb => {
+                let mut cipher = Self::cipher(&key, index, count);
+                cipher.encrypt(&mut new_data[header_size..]);
+                let mac = Self::mac(&key, index, count, &new_data[header_size..]);
+                new_data.extend_from_slice(&mac);
+            }
+            Cipher::Aes256Gcm => {
+                let gcm = AesGcm::new(&key);
+                let nonce = Self::nonce(&key, index, count);
+                header.tag = gcm.encrypt(&nonce, &index_aad(index), &mut new_data[header_size..]);
+            }
+        }
+        new_data[..header_size].copy_from_slice(&header.encode(self.version, self.cipher));
+        log::debug!("Encrypt {} complete", index);
+        new_data.into()
     }
 }
 
 impl IntKv for EncIntKv {
     fn read(&self, index: usize) -> io::Result<Bytes> {
-        let data = self.kv.read(index)?;
-        let count = Count::read_from(&data)?;
-        let mut cipher = self.cipher(index, count);
-        let mut data = data[IV_HEADER_SIZE..].to_vec();
-        log::info!("Decrypt {} ({} bytes)", index, data.len());
-        cipher.decrypt(&mut data);
-        log::debug!("Decrypt {} complete", index);
+        let raw = self.kv.read(index)?;
+        let (data, _) = self.decrypt(index, &raw)?;
         Ok(data.into())
     }
 
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let mut results = self.kv.read_batch(indexes);
+        let raw: Vec<(usize, Bytes)> = results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| r.as_ref().ok().map(|raw| (i, raw.clone())))
+            .collect();
+        let decrypted = util::parallel_map(&raw, self.threads, |(i, raw)| {
+            self.decrypt(indexes[*i], raw)
+        });
+        for ((i, _), result) in raw.iter().zip(decrypted) {
+            results[*i] = result.map(|(data, _)| data.into());
+        }
+        results
+    }
+
     fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
-        let count = if self.kv.has(index)? {
-            let old_data = self.kv.read(index)?;
-            Count::read_from(&old_data)?.bump(&mut self.rng)
-        } else {
-            Count::new_random(self.rng.as_mut())
-        };
-        let mut new_data = Vec::with_capacity(data.len() + IV_HEADER_SIZE);
-        new_data.extend_from_slice(&count.to_bytes());
-        new_data.extend_from_slice(&data);
-        let mut cipher = self.cipher(index, count);
-        log::info!("Encrypt {} ({} bytes)", index, data.len());
-        cipher.encrypt(&mut new_data[IV_HEADER_SIZE..]);
-        log::debug!("Encrypt {} complete", index);
-        self.kv.write(index, new_data.into())
+        let header = self.next_header(index)?;
+        let new_data = self.encrypt(index, header, &data);
+        self.legacy.lock().remove(&index);
+        self.kv.write(index, new_data)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        // Counts depend on the rng. Pick them in order.
+        let mut counted = Vec::with_capacity(items.len());
+        let mut result = Ok(());
+        for (index, data) in items {
+            match self.next_header(index) {
+                Ok(header) => {
+                    self.legacy.lock().remove(&index);
+                    counted.push((index, header, data));
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let encrypted = util::parallel_map(&counted, self.threads, |(index, header, data)| {
+            (*index, self.encrypt(*index, *header, data))
+        });
+        let (written, write_result) = self.kv.write_batch(encrypted);
+        (written, write_result.and(result))
     }
 
     fn remove(&mut self, index: usize) -> io::Result<()> {
         // This frees space and forgets about the IV header.
         // It relies on self.rng to avoid IV reuse.
+        self.legacy.lock().remove(&index);
         self.kv.remove(index)
     }
 
@@ -113,42 +688,224 @@ impl IntKv for EncIntKv {
         self.kv.has(index)
     }
 
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.kv.keys()
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.kv.flush()
     }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.kv.flush_keys(keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        let legacy = self.legacy.lock();
+        let unauthenticated = legacy.values().filter(|&&f| f == Format::Unbound).count();
+        stats.insert("enc.unauthenticated_entries".into(), unauthenticated as _);
+        stats.insert("enc.direct_key_entries".into(), legacy.len() as _);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
 }
 
-/// The "count" as the header of blocks to help avoid IV reuse.
-/// The highest bit is used to indicate "deletion".
-#[derive(Debug, Copy, Clone)]
-struct Count(u64, u64);
+/// Rewrites of an entry `audit_iv` follows back to a weak count.
+const REWRITES_PER_WEAK_COUNT: u64 = 1024;
+
+/// The first `n` 64-bit values, at any 32-bit offset, of the rng seeded with
+/// zeros.
+fn weak_count_values(n: usize) -> BTreeSet<u64> {
+    let mut rng: rand_chacha::ChaChaRng = rand::SeedableRng::from_seed(Default::default());
+    let words: Vec<u64> = (0..=n).map(|_| rng.next_u32() as u64).collect();
+    words.windows(2).map(|w| w[0] | (w[1] << 32)).collect()
+}
 
-impl Count {
-    fn new_random(rng: &mut dyn RngCore) -> Self {
-        Self(rng.next_u64(), rng.next_u64())
+/// Associated data of an entry in `Cipher::Aes256Gcm` mode.
+fn index_aad(index: usize) -> Vec<u8> {
+    (index as u64).to_be_bytes().to_vec()
+}
+
+/// Identify a key without revealing it. Each `Format` uses a different id so
+/// entries can be told apart.
+fn key_id(key: &Bits256, format: Format) -> u32 {
+    let mut b = Wiped(Blake2s::new());
+    b.update(match format {
+        Format::Unbound => &b"x79d8 key id"[..],
+        Format::Bound => b"x79d8 key id mac",
+        Format::Subkey => b"x79d8 key id subkey",
+    });
+    b.update(key);
+    u32::from_be_bytes(b.finalize_reset().as_slice()[0..4].try_into().unwrap())
+}
+
+/// Key of an entry: HKDF-BLAKE2s of the master key, with the index and the
+/// `Count` as the info.
+fn entry_key(key: &Bits256, index: usize, count: Count) -> Secret<Bits256> {
+    type HmacBlake2s = Hmac<Blake2s>;
+    let mut extract = Wiped(HmacBlake2s::new_varkey(b"x79d8 entry key").unwrap());
+    extract.update(key);
+    let mut prk = extract.finalize_reset().into_bytes();
+    let mut expand = Wiped(HmacBlake2s::new_varkey(&prk).unwrap());
+    util::zeroize(prk.as_mut_slice());
+    expand.update(&(index as u64).to_be_bytes());
+    expand.update(&count.to_bytes());
+    expand.update(&[1]);
+    Secret::new(expand.finalize_reset().into_bytes().into())
+}
+
+/// Size of a wrapped key: the encrypted key, and a verifier.
+pub const WRAPPED_KEY_SIZE: usize = 48;
+
+/// Encrypt the master key with a key-encryption key, so the password can
+/// change without re-encrypting entries.
+pub fn wrap_key(kek: &Bits256, key: &Bits256) -> [u8; WRAPPED_KEY_SIZE] {
+    let mut result = [0; WRAPPED_KEY_SIZE];
+    let pad = wrap_pad(kek);
+    for i in 0..32 {
+        result[i] = key[i] ^ pad[i];
     }
+    result[32..].copy_from_slice(&wrap_verifier(kek, key));
+    result
+}
 
-    fn read_from(data: &[u8]) -> io::Result<Self> {
-        match data.get(0..16) {
-            None => Err(io::ErrorKind::UnexpectedEof.into()),
-            Some(v) => {
-                let v1 = u64::from_be_bytes(<[u8; 8]>::try_from(&v[0..8]).unwrap());
-                let v2 = u64::from_be_bytes(<[u8; 8]>::try_from(&v[8..16]).unwrap());
-                Ok(Self(v1, v2))
-            }
-        }
+/// Decrypt a wrapped key. Return None if `kek` does not match.
+pub fn unwrap_key(kek: &Bits256, wrapped: &[u8]) -> Option<Secret<Bits256>> {
+    if wrapped.len() != WRAPPED_KEY_SIZE {
+        return None;
+    }
+    let pad = wrap_pad(kek);
+    let mut key = Secret::new([0; 32]);
+    for i in 0..32 {
+        key[i] = wrapped[i] ^ pad[i];
+    }
+    if ct_eq(&wrap_verifier(kek, &key), &wrapped[32..]) {
+        Some(key)
+    } else {
+        None
     }
+}
+
+fn wrap_pad(kek: &Bits256) -> Secret<Bits256> {
+    let mut b = Wiped(Blake2s::new());
+    b.update(b"x79d8 wrap key");
+    b.update(kek);
+    Secret::new(b.finalize_reset().into())
+}
+
+fn wrap_verifier(kek: &Bits256, key: &Bits256) -> Bits128 {
+    let mut b = Wiped(Blake2s::new());
+    b.update(b"x79d8 wrap verifier");
+    b.update(kek);
+    b.update(key);
+    b.finalize_reset().as_slice()[0..16].try_into().unwrap()
+}
+
+/// A hasher or cipher state wiped on drop, since it holds key material.
+struct Wiped<T: Plain>(T);
 
-    fn bump(self, rng: &mut dyn RngCore) -> Self {
-        let v = rng.next_u64() | 1;
-        Self(self.0.wrapping_add(v), self.1.wrapping_add(1))
+/// States `Wiped` can overwrite with zeros. Private to this module, so only
+/// the types below are wiped.
+///
+/// # Safety
+///
+/// The type must hold only integers and arrays of them, without pointers
+/// or a `Drop` of its own.
+unsafe trait Plain {}
+
+// Safety: these are fixed-size arrays of integers, for the hash state, the
+// round keys, the queued block and the GHASH key.
+unsafe impl Plain for Blake2s {}
+unsafe impl Plain for Hmac<Blake2s> {}
+unsafe impl Plain for AesCfb {}
+unsafe impl Plain for Aes256Gcm {}
+
+impl<T: Plain> std::ops::Deref for Wiped<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Plain> std::ops::DerefMut for Wiped<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Plain> Drop for Wiped<T> {
+    fn drop(&mut self) {
+        debug_assert!(!std::mem::needs_drop::<T>());
+        // Safety: guaranteed by `Plain`.
+        unsafe { util::zeroize_raw(&mut self.0) }
+    }
+}
+
+/// Check value of a master key, to tell wrong passwords early. Finding a
+/// password from it still takes the key derivation work for each guess.
+pub fn key_check(key: &Bits256) -> Bits128 {
+    let mut b = Wiped(Blake2s::new());
+    b.update(b"x79d8 key check");
+    b.update(key);
+    b.finalize_reset().as_slice()[0..16].try_into().unwrap()
+}
+
+/// Compare in constant time.
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    diff == 0 && a.len() == b.len()
+}
+
+/// AES256-GCM with 96-bit nonces, following NIST SP 800-38D. Entries pass
+/// their index as the additional data, so the tag also covers where they
+/// are stored. Older entries have no additional data.
+struct AesGcm(Wiped<Aes256Gcm>);
+
+impl AesGcm {
+    fn new(key: &Bits256) -> Self {
+        Self(Wiped(Aes256Gcm::new(key.into())))
+    }
+
+    /// Encrypt in place. Return the tag.
+    fn encrypt(&self, nonce: &[u8; 12], aad: &[u8], data: &mut [u8]) -> Bits128 {
+        self.0
+            .encrypt_in_place_detached(nonce.into(), aad, data)
+            .expect("entries are within the GCM size limit")
+            .into()
     }
 
-    fn to_bytes(self) -> [u8; IV_HEADER_SIZE] {
-        let mut result = [0u8; 16];
-        result[0..8].copy_from_slice(&self.0.to_be_bytes());
-        result[8..16].copy_from_slice(&self.1.to_be_bytes());
-        result
+    /// Decrypt in place. Return false if the tag does not match.
+    fn decrypt(&self, nonce: &[u8; 12], aad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+        tag.len() == TAG_SIZE
+            && self
+                .0
+                .decrypt_in_place_detached(nonce.into(), aad, data, tag.into())
+                .is_ok()
     }
 }
 
@@ -166,3 +923,436 @@ fn test_enc_kv() {
         50,
     );
 }
+
+#[test]
+fn test_enc_kv_write_batch() {
+    let new_kv = |threads| {
+        let kv = super::super::backend::MemIntKv::new();
+        let rng: rand_chacha::ChaChaRng = rand::SeedableRng::from_seed(Default::default());
+        EncIntKv::from_key_rng_kv([1; 32], Box::new(rng), Box::new(kv)).with_threads(threads)
+    };
+    let items: Vec<(usize, Bytes)> = (0..20)
+        .map(|i| (i, vec![i as u8; i * 100].into()))
+        .collect();
+
+    // Parallel encryption matches sequential writes.
+    let mut kv1 = new_kv(1);
+    let mut kv4 = new_kv(4);
+    for (index, data) in items.iter().take(5) {
+        kv1.write(*index, data.clone()).unwrap();
+        kv4.write(*index, data.clone()).unwrap();
+    }
+    for (index, data) in &items {
+        kv1.write(*index, data.clone()).unwrap();
+    }
+    assert_eq!(kv4.write_batch(items.clone()).0, items.len());
+    for (index, data) in &items {
+        assert_eq!(kv4.read(*index).unwrap(), data);
+        assert_eq!(kv4.kv.read(*index).unwrap(), kv1.kv.read(*index).unwrap());
+    }
+
+    // Parallel decryption reports errors per entry.
+    let indexes: Vec<usize> = (0..22).collect();
+    let results = kv4.read_batch(&indexes);
+    for (index, data) in &items {
+        assert_eq!(results[*index].as_ref().unwrap(), data);
+    }
+    assert_eq!(
+        results[21].as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn test_aes_gcm_vectors() {
+    // AES-256 test cases 13 to 16 of the GCM specification. Cases 17 and 18
+    // use nonce sizes other than 96 bits.
+    let zero_key: &str = &"00".repeat(32);
+    let zero_nonce: &str = &"00".repeat(12);
+    let key = "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308";
+    let nonce = "cafebabefacedbaddecaf888";
+    let plaintext = concat!(
+        "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+        "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255"
+    );
+    let ciphertext = concat!(
+        "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa",
+        "8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad"
+    );
+    let aad = "feedfacedeadbeeffeedfacedeadbeefabaddad2";
+    // (key, nonce, plaintext, additional data, ciphertext, tag)
+    let cases = [
+        (
+            zero_key,
+            zero_nonce,
+            "",
+            "",
+            "",
+            "530f8afbc74536b9a963b4f1c4cb738b",
+        ),
+        (
+            zero_key,
+            zero_nonce,
+            "00000000000000000000000000000000",
+            "",
+            "cea7403d4d606b6e074ec5d3baf39d18",
+            "d0d1c8a799996bf0265b98b5d48ab919",
+        ),
+        (
+            key,
+            nonce,
+            plaintext,
+            "",
+            ciphertext,
+            "b094dac5d93471bdec1a502270e3cc6c",
+        ),
+        (
+            key,
+            nonce,
+            &plaintext[..120],
+            aad,
+            &ciphertext[..120],
+            "76fc6ece0f4e1768cddf8853bb2d551b",
+        ),
+    ];
+    for (i, &(key, nonce, plaintext, aad, ciphertext, tag)) in cases.iter().enumerate() {
+        let case = i + 13;
+        let gcm = AesGcm::new(&hex::decode(key).unwrap().try_into().unwrap());
+        let nonce: [u8; 12] = hex::decode(nonce).unwrap().try_into().unwrap();
+        let aad = hex::decode(aad).unwrap();
+        let mut data = hex::decode(plaintext).unwrap();
+        let actual_tag = gcm.encrypt(&nonce, &aad, &mut data);
+        assert_eq!(hex::encode(&data), ciphertext, "case {}", case);
+        assert_eq!(hex::encode(actual_tag), tag, "case {}", case);
+        assert!(gcm.decrypt(&nonce, &aad, &mut data, &actual_tag));
+        assert_eq!(hex::encode(&data), plaintext, "case {}", case);
+
+        // Other additional data, ciphertext or tags fail, and leave the
+        // ciphertext as is.
+        let mut data = hex::decode(ciphertext).unwrap();
+        assert!(!gcm.decrypt(&nonce, b"x", &mut data, &actual_tag));
+        assert_eq!(hex::encode(&data), ciphertext, "case {}", case);
+        let mut bad_tag = actual_tag;
+        bad_tag[15] ^= 1;
+        assert!(!gcm.decrypt(&nonce, &aad, &mut data, &bad_tag));
+        assert!(!gcm.decrypt(&nonce, &aad, &mut data, &actual_tag[..12]));
+        if !data.is_empty() {
+            data[0] ^= 1;
+            assert!(!gcm.decrypt(&nonce, &aad, &mut data, &actual_tag));
+        }
+    }
+}
+
+#[test]
+fn test_enc_kv_gcm() {
+    let mut kv = EncIntKv::from_key_kv([2; 32], Box::new(super::super::backend::MemIntKv::new()))
+        .with_cipher(Cipher::Aes256Gcm);
+    let data: Bytes = (0..100u8).collect::<Vec<u8>>().into();
+    kv.write(3, data.clone()).unwrap();
+    assert_eq!(kv.read(3).unwrap(), data);
+    assert_eq!(
+        kv.kv.read(3).unwrap().len(),
+        data.len() + HeaderVersion::V0.header_size(Cipher::Aes256Gcm)
+    );
+
+    // Flipping a bit is detected.
+    let mut raw = kv.kv.read(3).unwrap().to_vec();
+    raw[40] ^= 1;
+    kv.kv.write(3, raw.into()).unwrap();
+    let err = kv.read(3).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        "entry 3 failed authentication (relocated or tampered)"
+    );
+
+    // Entries cannot be moved to other indexes.
+    kv.write(3, data.clone()).unwrap();
+    let raw = kv.kv.read(3).unwrap();
+    kv.kv.write(4, raw).unwrap();
+    assert!(kv.read(4).is_err());
+}
+
+#[test]
+fn test_enc_kv_rekey() {
+    for &cipher in &[Cipher::Aes256Cfb, Cipher::Aes256Gcm] {
+        let mut kv =
+            EncIntKv::from_key_kv([3; 32], Box::new(super::super::backend::MemIntKv::new()))
+                .with_cipher(cipher);
+        for i in 0..5 {
+            kv.write(i, vec![i as u8; 10].into()).unwrap();
+        }
+
+        // A wrong old key is refused.
+        let mut kv = EncIntKv::from_key_kv([4; 32], kv.kv)
+            .with_cipher(cipher)
+            .with_old_key([5; 32]);
+        let err = kv.rekey_all(|_, _| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Old and new entries are readable during rekeying.
+        let mut kv = EncIntKv::from_key_kv([4; 32], kv.kv)
+            .with_cipher(cipher)
+            .with_old_key([3; 32]);
+        kv.write(1, vec![10; 10].into()).unwrap();
+        assert_eq!(kv.read(0).unwrap(), vec![0; 10]);
+        assert_eq!(kv.read(1).unwrap(), vec![10; 10]);
+        assert!(kv.rekey_entry(0).unwrap());
+        assert!(!kv.rekey_entry(0).unwrap());
+        assert!(!kv.rekey_entry(1).unwrap());
+
+        let mut calls = 0;
+        kv.rekey_all(|_, _| calls += 1).unwrap();
+        assert_eq!(calls, 5);
+        assert!(!kv.has(REKEY_PROGRESS_INDEX).unwrap());
+
+        // The old key is no longer needed.
+        let kv = EncIntKv::from_key_kv([4; 32], kv.kv).with_cipher(cipher);
+        for i in 0..5 {
+            let expected = if i == 1 { 10 } else { i as u8 };
+            assert_eq!(kv.read(i).unwrap(), vec![expected; 10]);
+        }
+    }
+}
+
+#[test]
+fn test_enc_kv_rekey_legacy() {
+    // Entries without key ids or MACs, as written before either existed.
+    let old_key = [3; 32];
+    let legacy_kv = || {
+        let kv = Box::new(super::super::backend::MemIntKv::new());
+        let mut kv = EncIntKv::from_key_kv(old_key, kv);
+        for i in 0..3 {
+            kv.write_legacy(i, &[i as u8; 10]).unwrap();
+        }
+        kv.kv
+    };
+    let check = |kv: EncIntKv, n: usize| {
+        let kv = EncIntKv::from_key_kv([4; 32], kv.kv).with_required_mac(true);
+        for i in 0..n {
+            assert_eq!(kv.read(i).unwrap(), vec![i as u8; 10]);
+        }
+    };
+
+    // Nothing verifies the old key. They are left alone.
+    let mut kv = EncIntKv::from_key_kv([4; 32], legacy_kv()).with_old_key(old_key);
+    assert_eq!(kv.read(1).unwrap(), vec![1; 10]);
+    let err = kv.rekey_all(|_, _| {}).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        EncIntKv::from_key_kv(old_key, kv.kv).read(2).unwrap(),
+        vec![2; 10]
+    );
+
+    // The caller verified it.
+    let mut kv = EncIntKv::from_key_kv([4; 32], legacy_kv())
+        .with_old_key(old_key)
+        .with_old_key_checked(true);
+    kv.rekey_all(|_, _| {}).unwrap();
+    check(kv, 3);
+
+    // An entry with the id of the old key verifies it. Legacy entries are
+    // still read with the old key.
+    let mut kv = EncIntKv::from_key_kv(old_key, legacy_kv());
+    kv.write(3, vec![3; 10].into()).unwrap();
+    let mut kv = EncIntKv::from_key_kv([4; 32], kv.kv).with_old_key(old_key);
+    assert!(kv.rekey_entry(0).unwrap());
+    kv.rekey_all(|_, _| {}).unwrap();
+    check(kv, 4);
+}
+
+#[test]
+fn test_enc_kv_mac() {
+    let mut kv = EncIntKv::from_key_kv([6; 32], Box::new(super::super::backend::MemIntKv::new()));
+    let data: Bytes = vec![7; 50].into();
+    kv.write(1, data.clone()).unwrap();
+    assert_eq!(
+        kv.kv.read(1).unwrap().len(),
+        50 + format::COUNT_SIZE + MAC_SIZE
+    );
+    assert_eq!(kv.read(1).unwrap(), data);
+
+    // Modified entries are detected.
+    let mut raw = kv.kv.read(1).unwrap().to_vec();
+    raw[20] ^= 1;
+    kv.kv.write(1, raw.into()).unwrap();
+    let err = kv.read(1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.get_ref().unwrap().is::<AuthError>());
+
+    // Entries written before MACs existed are readable and counted.
+    let count = Count(1, 2);
+    let mut raw = count.to_bytes().to_vec();
+    raw.extend_from_slice(&data);
+    EncIntKv::cipher(&[6; 32], 2, count).encrypt(&mut raw[format::COUNT_SIZE..]);
+    kv.kv.write(2, raw.into()).unwrap();
+    assert_eq!(kv.read(2).unwrap(), data);
+    assert_eq!(kv.stats()["enc.unauthenticated_entries"], 1);
+    let kv = kv.with_required_mac(true);
+    assert!(kv.read(2).is_err());
+
+    // Rewriting adds the MAC.
+    let mut kv = kv.with_required_mac(false);
+    kv.write(2, data.clone()).unwrap();
+    assert_eq!(kv.stats()["enc.unauthenticated_entries"], 0);
+    let kv = kv.with_required_mac(true);
+    assert_eq!(kv.read(2).unwrap(), data);
+}
+
+#[test]
+fn test_wrap_key() {
+    let key = [8; 32];
+    let wrapped = wrap_key(&[9; 32], &key);
+    assert_eq!(unwrap_key(&[9; 32], &wrapped).as_deref(), Some(&key));
+    assert!(unwrap_key(&[10; 32], &wrapped).is_none());
+    assert!(unwrap_key(&[9; 32], &wrapped[1..]).is_none());
+
+    // The check value tells keys apart.
+    assert_ne!(key_check(&[9; 32]), key_check(&[10; 32]));
+
+    // Wrapping by another password does not change the key.
+    let wrapped = wrap_key(&[10; 32], &key);
+    assert_eq!(unwrap_key(&[10; 32], &wrapped).as_deref(), Some(&key));
+}
+
+#[test]
+fn test_enc_kv_counts_differ() {
+    let counts = |mut kv: EncIntKv| {
+        kv.write(1, vec![1].into()).unwrap();
+        kv.remove(1).unwrap();
+        kv.write(1, vec![2].into()).unwrap();
+        Count::read_from(&kv.kv.read(1).unwrap())
+            .unwrap()
+            .to_bytes()
+    };
+    let new_kv =
+        || EncIntKv::from_key_kv([11; 32], Box::new(super::super::backend::MemIntKv::new()));
+    assert_ne!(counts(new_kv()), counts(new_kv()));
+
+    // Identical rng streams still pick different counts.
+    let new_kv = || {
+        let rng: rand_chacha::ChaChaRng = rand::SeedableRng::from_seed(Default::default());
+        let kv = Box::new(super::super::backend::MemIntKv::new());
+        let mut kv = EncIntKv::from_key_rng_kv([11; 32], Box::new(rng), kv);
+        kv.os_entropy = true;
+        kv
+    };
+    assert_ne!(counts(new_kv()), counts(new_kv()));
+}
+
+#[test]
+fn test_enc_kv_debug_hides_key() {
+    let kv = EncIntKv::from_key_kv([123; 32], Box::new(super::super::backend::MemIntKv::new()))
+        .with_old_key([124; 32]);
+    let debug = format!("{:?}", kv);
+    assert!(!debug.contains("123"), "{}", debug);
+    assert!(!debug.contains("124"), "{}", debug);
+}
+
+#[test]
+fn test_enc_kv_swapped_entries() {
+    for &cipher in &[Cipher::Aes256Cfb, Cipher::Aes256Gcm] {
+        let mut kv =
+            EncIntKv::from_key_kv([12; 32], Box::new(super::super::backend::MemIntKv::new()))
+                .with_cipher(cipher);
+        kv.write(1, vec![1; 40].into()).unwrap();
+        kv.write(2, vec![2; 40].into()).unwrap();
+        let raw1 = kv.kv.read(1).unwrap();
+        let raw2 = kv.kv.read(2).unwrap();
+        kv.kv.write(1, raw2).unwrap();
+        kv.kv.write(2, raw1).unwrap();
+        for index in [1, 2] {
+            let err = kv.read(index).unwrap_err();
+            assert!(err.get_ref().unwrap().is::<AuthError>());
+            assert!(err.to_string().contains("relocated or tampered"));
+        }
+    }
+}
+
+#[test]
+fn test_enc_kv_subkeys() {
+    let key = [13; 32];
+    let mut kv = EncIntKv::from_key_kv(key, Box::new(super::super::backend::MemIntKv::new()));
+    let data: Bytes = vec![14; 50].into();
+    kv.write(1, data.clone()).unwrap();
+    assert_eq!(kv.read(1).unwrap(), data);
+    assert_eq!(kv.stats()["enc.direct_key_entries"], 0);
+
+    // Subkeys differ by index and count.
+    let count = Count(1, 2);
+    assert_ne!(*entry_key(&key, 1, count), *entry_key(&key, 2, count));
+    assert_ne!(*entry_key(&key, 1, count), *entry_key(&key, 1, Count(1, 3)));
+
+    // Entries using the master key directly are readable and counted.
+    let count = Count::new_random(&mut rand::thread_rng(), key_id(&key, Format::Bound));
+    let mut raw = count.to_bytes().to_vec();
+    raw.extend_from_slice(&data);
+    EncIntKv::cipher(&key, 2, count).encrypt(&mut raw[format::COUNT_SIZE..]);
+    let mac = EncIntKv::mac(&key, 2, count, &raw[format::COUNT_SIZE..]);
+    raw.extend_from_slice(&mac);
+    kv.kv.write(2, raw.into()).unwrap();
+    assert_eq!(kv.read(2).unwrap(), data);
+    assert_eq!(kv.stats()["enc.direct_key_entries"], 1);
+    assert_eq!(kv.stats()["enc.unauthenticated_entries"], 0);
+
+    // Rewriting converts them.
+    kv.write(2, data.clone()).unwrap();
+    assert_eq!(kv.stats()["enc.direct_key_entries"], 0);
+    assert_eq!(kv.read(2).unwrap(), data);
+}
+
+#[test]
+fn test_enc_kv_header_version() {
+    for &cipher in &[Cipher::Aes256Cfb, Cipher::Aes256Gcm] {
+        let mut kv =
+            EncIntKv::from_key_kv([15; 32], Box::new(super::super::backend::MemIntKv::new()))
+                .with_cipher(cipher)
+                .with_header_version(HeaderVersion::V1);
+        let data: Bytes = vec![16; 30].into();
+        kv.write(1, data.clone()).unwrap();
+        kv.write(1, data.clone()).unwrap();
+        assert_eq!(kv.read(1).unwrap(), data);
+        let raw = kv.kv.read(1).unwrap();
+        let size = HeaderVersion::V1.header_size(cipher) + cipher.trailer_size();
+        assert_eq!(raw.len(), data.len() + size);
+        assert_eq!(&raw[0..2], &[1, Format::LATEST as u8]);
+        assert_eq!(kv.stats()["enc.direct_key_entries"], 0);
+
+        // The format byte is authenticated through the key.
+        let mut raw = raw.to_vec();
+        raw[1] = Format::Bound as u8;
+        kv.kv.write(1, raw.into()).unwrap();
+        assert!(kv.read(1).is_err());
+    }
+}
+
+#[test]
+fn test_enc_kv_audit_iv() {
+    let rng: rand_chacha::ChaChaRng = rand::SeedableRng::from_seed(Default::default());
+    let kv = Box::new(super::super::backend::MemIntKv::new());
+    let mut kv = EncIntKv::from_key_rng_kv([17; 32], Box::new(rng), kv);
+    for i in 0..10 {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    kv.write(3, vec![0; 10].into()).unwrap();
+    let audit = kv.audit_iv().unwrap();
+    assert_eq!(audit.entries, 10);
+    assert_eq!(audit.weak_counts, 10);
+    assert!(audit.affected());
+
+    let mut kv = EncIntKv::from_key_kv([17; 32], Box::new(super::super::backend::MemIntKv::new()));
+    for i in 0..10 {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    let audit = kv.audit_iv().unwrap();
+    assert_eq!(audit.weak_counts, 0);
+    assert_eq!(audit.shared_counts, 0);
+    assert!(!audit.affected());
+
+    // A count used twice is reported.
+    let raw = kv.kv.read(1).unwrap();
+    kv.kv.write(11, raw).unwrap();
+    let audit = kv.audit_iv().unwrap();
+    assert_eq!(audit.shared_counts, 2);
+    assert!(audit.iv_collisions.is_empty());
+}
@@ -0,0 +1,278 @@
+//! On-disk header of encrypted entries.
+//!
+//! The header layout is recorded per directory as a `HeaderVersion`, so the
+//! reader knows which parser to use. Within a version, the `Format` of an
+//! entry can change as it gets rewritten. `Header::rewrite` is the single
+//! upgrade path.
+
+use super::{Bits128, Cipher, TAG_SIZE};
+use rand::RngCore;
+use std::convert::TryFrom;
+use std::io;
+
+/// Size of a `Count`.
+pub const COUNT_SIZE: usize = 16;
+
+/// Layout of entry headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderVersion {
+    /// The `Count`, then the GCM tag if any. The `Format` is told by the key
+    /// id in the `Count`.
+    #[default]
+    V0,
+
+    /// The version byte, the `Format` byte, the `Count`, then the GCM tag if
+    /// any.
+    V1,
+}
+
+impl HeaderVersion {
+    /// The version new directories use.
+    pub const LATEST: Self = HeaderVersion::V1;
+
+    /// Bytes before the encrypted data.
+    pub const fn header_size(self, cipher: Cipher) -> usize {
+        let prefix = match self {
+            HeaderVersion::V0 => 0,
+            HeaderVersion::V1 => 2,
+        };
+        let tag = match cipher {
+            Cipher::Aes256Cfb => 0,
+            Cipher::Aes256Gcm => TAG_SIZE,
+        };
+        prefix + COUNT_SIZE + tag
+    }
+}
+
+impl TryFrom<u8> for HeaderVersion {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(HeaderVersion::V0),
+            1 => Ok(HeaderVersion::V1),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported entry header version {}", value),
+            )),
+        }
+    }
+}
+
+impl From<HeaderVersion> for u8 {
+    fn from(version: HeaderVersion) -> u8 {
+        version as u8
+    }
+}
+
+/// How an entry was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The master key, without a MAC or GCM associated data.
+    Unbound,
+
+    /// The master key, authenticating the index.
+    Bound,
+
+    /// A subkey of the entry, authenticating the index.
+    Subkey,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Unbound, Format::Bound, Format::Subkey];
+
+    /// The format rewritten entries use.
+    pub const LATEST: Self = Format::Subkey;
+}
+
+impl TryFrom<u8> for Format {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match Format::ALL.get(value as usize) {
+            Some(&format) => Ok(format),
+            None => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+/// Parsed header of an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// How the entry was written. None if the version does not record it.
+    pub format: Option<Format>,
+
+    pub count: Count,
+
+    /// The GCM tag. Not stored in `Cipher::Aes256Cfb` mode.
+    pub tag: Bits128,
+}
+
+impl Header {
+    /// Header of a new entry.
+    pub fn new(rng: &mut dyn RngCore, key_id: u32) -> Self {
+        Self {
+            format: Some(Format::LATEST),
+            count: Count::new_random(rng, key_id),
+            tag: [0; 16],
+        }
+    }
+
+    /// Header to rewrite the entry with. Upgrades it to the latest format.
+    pub fn rewrite(self, rng: &mut dyn RngCore, key_id: u32) -> Self {
+        Self {
+            format: Some(Format::LATEST),
+            count: self.count.bump(rng, key_id),
+            tag: [0; 16],
+        }
+    }
+
+    /// Serialize to `version.header_size(cipher)` bytes.
+    pub fn encode(&self, version: HeaderVersion, cipher: Cipher) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(version.header_size(cipher));
+        if version == HeaderVersion::V1 {
+            let format = self.format.unwrap_or(Format::LATEST);
+            buf.push(version.into());
+            buf.push(format as u8);
+        }
+        buf.extend_from_slice(&self.count.to_bytes());
+        if cipher == Cipher::Aes256Gcm {
+            buf.extend_from_slice(&self.tag);
+        }
+        buf
+    }
+
+    /// Parse the header of an entry. Return it with the rest of the entry.
+    pub fn decode(version: HeaderVersion, cipher: Cipher, raw: &[u8]) -> io::Result<(Self, &[u8])> {
+        let size = version.header_size(cipher);
+        if raw.len() < size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (header, body) = raw.split_at(size);
+        let (format, rest) = match version {
+            HeaderVersion::V0 => (None, header),
+            HeaderVersion::V1 => {
+                if HeaderVersion::try_from(header[0])? != version {
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                (Some(Format::try_from(header[1])?), &header[2..])
+            }
+        };
+        let count = Count::read_from(rest)?;
+        let mut tag = [0; 16];
+        if cipher == Cipher::Aes256Gcm {
+            tag.copy_from_slice(&rest[COUNT_SIZE..]);
+        }
+        Ok((Self { format, count, tag }, body))
+    }
+}
+
+/// The "count" as the header of blocks to help avoid IV reuse.
+/// The highest 32 bits are the key id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Count(pub u64, pub u64);
+
+impl Count {
+    pub fn new_random(rng: &mut dyn RngCore, key_id: u32) -> Self {
+        Self(
+            ((key_id as u64) << 32) | rng.next_u32() as u64,
+            rng.next_u64(),
+        )
+    }
+
+    pub fn read_from(data: &[u8]) -> io::Result<Self> {
+        match data.get(0..16) {
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+            Some(v) => {
+                let v1 = u64::from_be_bytes(<[u8; 8]>::try_from(&v[0..8]).unwrap());
+                let v2 = u64::from_be_bytes(<[u8; 8]>::try_from(&v[8..16]).unwrap());
+                Ok(Self(v1, v2))
+            }
+        }
+    }
+
+    pub fn bump(self, rng: &mut dyn RngCore, key_id: u32) -> Self {
+        let low = (self.0 as u32).wrapping_add(rng.next_u32() | 1);
+        Self(((key_id as u64) << 32) | low as u64, self.1.wrapping_add(1))
+    }
+
+    pub fn key_id(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    /// Randomize the bits not used by the key id.
+    pub fn mix(self, rng: &mut dyn RngCore) -> Self {
+        Self(self.0 ^ rng.next_u32() as u64, self.1 ^ rng.next_u64())
+    }
+
+    pub fn to_bytes(self) -> [u8; COUNT_SIZE] {
+        let mut result = [0u8; 16];
+        result[0..8].copy_from_slice(&self.0.to_be_bytes());
+        result[8..16].copy_from_slice(&self.1.to_be_bytes());
+        result
+    }
+}
+
+#[test]
+fn test_header_round_trip() {
+    let mut rng = rand::thread_rng();
+    for &version in &[HeaderVersion::V0, HeaderVersion::V1] {
+        for &cipher in &[Cipher::Aes256Cfb, Cipher::Aes256Gcm] {
+            for &format in &Format::ALL {
+                let header = Header {
+                    format: Some(format),
+                    count: Count::new_random(&mut rng, 0x1234_5678),
+                    tag: match cipher {
+                        Cipher::Aes256Cfb => [0; 16],
+                        Cipher::Aes256Gcm => rand::random(),
+                    },
+                };
+                let mut raw = header.encode(version, cipher);
+                assert_eq!(raw.len(), version.header_size(cipher));
+                raw.extend_from_slice(b"body");
+                let (decoded, body) = Header::decode(version, cipher, &raw).unwrap();
+                assert_eq!(body, b"body");
+                assert_eq!(decoded.count, header.count);
+                assert_eq!(decoded.tag, header.tag);
+                match version {
+                    HeaderVersion::V0 => assert_eq!(decoded.format, None),
+                    HeaderVersion::V1 => assert_eq!(decoded.format, Some(format)),
+                }
+                assert!(Header::decode(version, cipher, &raw[..raw.len() - 5]).is_err());
+
+                let rewritten = header.rewrite(&mut rng, 1);
+                assert_eq!(rewritten.format, Some(Format::LATEST));
+                assert_eq!(rewritten.count.key_id(), 1);
+            }
+        }
+    }
+    for value in 0..=255u8 {
+        if let Ok(version) = HeaderVersion::try_from(value) {
+            assert_eq!(u8::from(version), value);
+        }
+    }
+}
+
+#[test]
+fn test_header_decode_fuzz() {
+    // Random input must not panic the parser.
+    let mut rng = rand::thread_rng();
+    for _ in 0..20000 {
+        let len = (rng.next_u32() % 40) as usize;
+        let mut raw = vec![0; len];
+        rng.fill_bytes(&mut raw);
+        if len > 1 && rng.next_u32() & 1 == 0 {
+            raw[0] = 1;
+            raw[1] %= 4;
+        }
+        for &version in &[HeaderVersion::V0, HeaderVersion::V1] {
+            for &cipher in &[Cipher::Aes256Cfb, Cipher::Aes256Gcm] {
+                if let Ok((header, body)) = Header::decode(version, cipher, &raw) {
+                    assert_eq!(body.len() + version.header_size(cipher), len);
+                    let encoded = header.encode(version, cipher);
+                    assert_eq!(&encoded[..], &raw[..encoded.len()]);
+                }
+            }
+        }
+    }
+}
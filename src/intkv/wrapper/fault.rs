@@ -0,0 +1,381 @@
+//! Scripted failures for testing layers above an `IntKv`.
+//!
+//! `Faults` is a handle shared with the `FaultIntKv`, so failures can be
+//! scripted after it was moved into a stack. Operations are counted as they
+//! are attempted. Injected errors have `io::ErrorKind::Other` unless
+//! changed by `Faults::error_kind`.
+//!
+//! With the `testing` feature, `X79D8_FAULTS` scripts the storage of
+//! commands, see `Faults::parse`.
+
+// Some of the scripting is only used by tests.
+#![cfg_attr(not(test), allow(dead_code))]
+
+use super::super::{Bytes, Hint, IntKv, Stats};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Kinds of operations. Batches count as an operation per entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    Read,
+    Write,
+    Remove,
+    Has,
+    Keys,
+    Flush,
+}
+
+type Predicate = Box<dyn Fn(Op, Option<usize>) -> bool + Send + Sync>;
+
+enum Rule {
+    /// Fail the `remaining`th next `op`, once.
+    Nth { op: Op, remaining: usize },
+    /// Fail `ops` once `budget` of them passed.
+    After { ops: Vec<Op>, budget: usize },
+    /// Fail operations matching the predicate, with the entry if any.
+    When(Predicate),
+    /// Flip a bit in data read from matching entries.
+    Flip(Box<dyn Fn(usize) -> bool + Send + Sync>),
+}
+
+#[derive(Default)]
+struct Script {
+    rules: Vec<Rule>,
+    latency: BTreeMap<Op, Duration>,
+    counts: BTreeMap<Op, usize>,
+    kind: Option<io::ErrorKind>,
+}
+
+#[derive(Clone, Default)]
+pub struct Faults {
+    script: Arc<Mutex<Script>>,
+    /// Entries written successfully, in order.
+    pub written: Arc<Mutex<Vec<usize>>>,
+}
+
+#[derive(Debug)]
+pub struct FaultIntKv {
+    kv: Box<dyn IntKv>,
+    faults: Faults,
+    idempotent_writes: bool,
+}
+
+impl Faults {
+    /// Fail the `n`th next `op`, counting from 1.
+    pub fn fail_nth(&self, op: Op, n: usize) {
+        assert!(n > 0, "n counts from 1");
+        let rule = Rule::Nth { op, remaining: n };
+        self.script.lock().rules.push(rule);
+    }
+
+    /// Let `budget` more of `ops` pass, then fail them all.
+    pub fn fail_after(&self, ops: &[Op], budget: usize) {
+        let rule = Rule::After {
+            ops: ops.to_vec(),
+            budget,
+        };
+        self.script.lock().rules.push(rule);
+    }
+
+    /// Fail operations for which `predicate(op, index)` is true. `index`
+    /// is None for `keys` and `flush`.
+    pub fn fail_when(&self, predicate: impl Fn(Op, Option<usize>) -> bool + Send + Sync + 'static) {
+        let rule = Rule::When(Box::new(predicate));
+        self.script.lock().rules.push(rule);
+    }
+
+    /// Flip a bit in data read from entries matching `predicate`.
+    pub fn flip_bits_when(&self, predicate: impl Fn(usize) -> bool + Send + Sync + 'static) {
+        let rule = Rule::Flip(Box::new(predicate));
+        self.script.lock().rules.push(rule);
+    }
+
+    /// Delay each `op` by `delay`.
+    pub fn latency(&self, op: Op, delay: Duration) {
+        self.script.lock().latency.insert(op, delay);
+    }
+
+    /// Make injected errors of `kind`, ex. `TimedOut` for transient ones.
+    pub fn error_kind(&self, kind: io::ErrorKind) {
+        self.script.lock().kind = Some(kind);
+    }
+
+    /// Drop all failures and latency. Counts are kept.
+    pub fn clear(&self) {
+        let mut script = self.script.lock();
+        script.rules.clear();
+        script.latency.clear();
+    }
+
+    /// Number of `op` attempted, including failed ones.
+    pub fn count(&self, op: Op) -> usize {
+        self.script.lock().counts.get(&op).copied().unwrap_or(0)
+    }
+
+    /// Parse a script of comma separated items: `fail:OP:N` fails the Nth
+    /// OP, `fail-after:OP:N` fails OP after N passed, `fail-all:OP` fails
+    /// every OP, `latency:OP:MS` delays OP, and `flip:INDEX` corrupts reads
+    /// of the entry. OP is read, write, remove, has, keys or flush.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let faults = Self::default();
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let parts: Vec<&str> = item.split(':').collect();
+            let number = |s: &str| -> Result<usize, String> {
+                s.parse()
+                    .map_err(|_| format!("bad number in fault {:?}", item))
+            };
+            match parts.as_slice() {
+                ["fail", op, n] if number(n)? > 0 => faults.fail_nth(op.parse()?, number(n)?),
+                ["fail-after", op, n] => faults.fail_after(&[op.parse()?], number(n)?),
+                ["fail-all", op] => {
+                    let op: Op = op.parse()?;
+                    faults.fail_when(move |o, _| o == op);
+                }
+                ["latency", op, ms] => {
+                    let delay = Duration::from_millis(number(ms)? as u64);
+                    faults.latency(op.parse()?, delay);
+                }
+                ["flip", index] => {
+                    let index = number(index)?;
+                    faults.flip_bits_when(move |i| i == index);
+                }
+                _ => return Err(format!("unknown fault {:?}", item)),
+            }
+        }
+        Ok(faults)
+    }
+
+    /// Count `op`, wait for its latency, and fail it if scripted.
+    fn check(&self, op: Op, index: Option<usize>) -> io::Result<()> {
+        let (fail, delay, kind) = {
+            let mut script = self.script.lock();
+            *script.counts.entry(op).or_default() += 1;
+            let mut fail = false;
+            script.rules.retain_mut(|rule| match rule {
+                Rule::Nth { op: o, remaining } if *o == op => {
+                    *remaining -= 1;
+                    fail |= *remaining == 0;
+                    *remaining > 0
+                }
+                Rule::After { ops, budget } if ops.contains(&op) => {
+                    match budget.checked_sub(1) {
+                        Some(rest) => *budget = rest,
+                        None => fail = true,
+                    }
+                    true
+                }
+                Rule::When(predicate) => {
+                    fail |= predicate(op, index);
+                    true
+                }
+                _ => true,
+            });
+            let kind = script.kind.unwrap_or(io::ErrorKind::Other);
+            (fail, script.latency.get(&op).copied(), kind)
+        };
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+        match fail {
+            true => Err(io::Error::new(
+                kind,
+                format!("injected failure of {:?}", op),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn corrupt(&self, index: usize, data: Bytes) -> Bytes {
+        let script = self.script.lock();
+        let flip = script.rules.iter().any(|rule| match rule {
+            Rule::Flip(predicate) => predicate(index),
+            _ => false,
+        });
+        if !flip || data.is_empty() {
+            return data;
+        }
+        let mut data = data.to_vec();
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        data.into()
+    }
+}
+
+impl fmt::Debug for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let script = self.script.lock();
+        f.debug_struct("Faults")
+            .field("rules", &script.rules.len())
+            .field("counts", &script.counts)
+            .finish()
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Op::Read),
+            "write" => Ok(Op::Write),
+            "remove" => Ok(Op::Remove),
+            "has" => Ok(Op::Has),
+            "keys" => Ok(Op::Keys),
+            "flush" => Ok(Op::Flush),
+            _ => Err(format!("unknown operation {}", s)),
+        }
+    }
+}
+
+impl FaultIntKv {
+    /// Wrap `kv` without faults. Script them with `faults()`.
+    pub fn new(kv: Box<dyn IntKv>) -> Self {
+        Self::with_faults(kv, Faults::default())
+    }
+
+    pub fn with_faults(kv: Box<dyn IntKv>, faults: Faults) -> Self {
+        Self {
+            kv,
+            faults,
+            idempotent_writes: true,
+        }
+    }
+
+    /// Set what `idempotent_writes` reports.
+    pub fn with_idempotent_writes(mut self, idempotent: bool) -> Self {
+        self.idempotent_writes = idempotent;
+        self
+    }
+
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+}
+
+impl IntKv for FaultIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.faults.check(Op::Read, Some(index))?;
+        let data = self.kv.read(index)?;
+        Ok(self.faults.corrupt(index, data))
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.faults.check(Op::Write, Some(index))?;
+        self.kv.write(index, data)?;
+        self.faults.written.lock().push(index);
+        Ok(())
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        self.faults.check(Op::Write, Some(index))?;
+        self.kv.write_with_hint(index, data, hint)?;
+        self.faults.written.lock().push(index);
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.faults.check(Op::Remove, Some(index))?;
+        self.kv.remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.faults.check(Op::Has, Some(index))?;
+        self.kv.has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.faults.check(Op::Keys, None)?;
+        self.kv.keys()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.faults.check(Op::Flush, None)?;
+        self.kv.flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.faults.check(Op::Flush, None)?;
+        self.kv.flush_keys(keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        self.kv.stats()
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.idempotent_writes && self.kv.idempotent_writes()
+    }
+}
+
+#[test]
+fn test_fault_int_kv() {
+    use super::super::backend::MemIntKv;
+    let kv = super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| FaultIntKv::new(Box::new(MemIntKv::new()))),
+        100,
+    );
+    assert!(kv.faults.count(Op::Write) > 0);
+    assert_eq!(kv.faults.written.lock().len(), kv.faults.count(Op::Write));
+}
+
+#[test]
+fn test_fault_int_kv_script() {
+    use super::super::backend::MemIntKv;
+    let mut kv = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let faults = kv.faults();
+    faults.fail_nth(Op::Write, 2);
+    kv.write(1, vec![1; 4].into()).unwrap();
+    assert!(kv.write(2, vec![2].into()).is_err());
+    kv.write(2, vec![2].into()).unwrap();
+    assert_eq!(faults.count(Op::Write), 3);
+    assert_eq!(*faults.written.lock(), [1, 2]);
+
+    faults.fail_when(|op, index| op == Op::Read && index == Some(2));
+    faults.flip_bits_when(|index| index == 1);
+    assert!(kv.read(2).is_err());
+    assert_eq!(&kv.read(1).unwrap()[..], &[1, 1, 0, 1]);
+    faults.clear();
+    assert_eq!(&kv.read(2).unwrap()[..], &[2]);
+
+    faults.fail_after(&[Op::Remove, Op::Flush], 1);
+    kv.remove(2).unwrap();
+    assert!(kv.flush().is_err());
+    assert!(kv.remove(1).is_err());
+    kv.write(3, vec![3].into()).unwrap();
+
+    let faults = Faults::parse("fail:read:2, fail-all:flush, latency:has:1, flip:7").unwrap();
+    assert_eq!(faults.script.lock().rules.len(), 3);
+    assert!(Faults::parse("fail:read:0").is_err());
+    assert!(Faults::parse("fail:open:1").is_err());
+}
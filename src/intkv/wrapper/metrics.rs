@@ -0,0 +1,426 @@
+//! Count operations on an `IntKv` and their latencies, to find out which
+//! layer of a stack is slow. Latencies include the layers below.
+//!
+//! Each operation costs a few relaxed atomics. Latencies go to buckets of
+//! powers of 2 microseconds, so percentiles are upper bounds within a
+//! factor of 2.
+
+use super::super::{Bytes, CacheLimits, Hint, IntKv, Stats};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read,
+    ReadBatch,
+    Write,
+    WriteBatch,
+    Remove,
+    Has,
+    Keys,
+    Flush,
+}
+
+impl Op {
+    const ALL: [Op; 8] = [
+        Op::Read,
+        Op::ReadBatch,
+        Op::Write,
+        Op::WriteBatch,
+        Op::Remove,
+        Op::Has,
+        Op::Keys,
+        Op::Flush,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::ReadBatch => "read_batch",
+            Op::Write => "write",
+            Op::WriteBatch => "write_batch",
+            Op::Remove => "remove",
+            Op::Has => "has",
+            Op::Keys => "keys",
+            Op::Flush => "flush",
+        }
+    }
+
+    fn moves_data(self) -> bool {
+        matches!(self, Op::Read | Op::ReadBatch | Op::Write | Op::WriteBatch)
+    }
+}
+
+/// Bucket `i` counts latencies below 2^i microseconds. The last one also
+/// counts slower ones.
+const BUCKETS: usize = 28;
+
+/// Percentiles in stats.
+const PERCENTILES: [(u64, &str); 3] = [(50, "p50"), (95, "p95"), (99, "p99")];
+
+#[derive(Debug, Default)]
+struct OpMetrics {
+    count: AtomicU64,
+    bytes: AtomicU64,
+    micros: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl OpMetrics {
+    fn record(&self, micros: u64, bytes: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.micros.fetch_add(micros, Ordering::Relaxed);
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Upper bound of the `percent` percentile latency in microseconds.
+    fn percentile(&self, percent: u64) -> u64 {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        let rank = (total * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, count) in counts.into_iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 1 << i;
+            }
+        }
+        0
+    }
+}
+
+#[derive(Debug)]
+pub struct MetricsIntKv {
+    kv: Box<dyn IntKv>,
+    label: String,
+    ops: [OpMetrics; Op::ALL.len()],
+}
+
+impl MetricsIntKv {
+    /// Record operations on `kv` in stats named `metrics.{label}.*`.
+    pub fn new(kv: Box<dyn IntKv>, label: &str) -> Self {
+        assert!(!label.contains('.'), "labels cannot contain '.'");
+        Self {
+            kv,
+            label: label.to_string(),
+            ops: Default::default(),
+        }
+    }
+
+    fn record(&self, op: Op, start: Instant, bytes: u64) {
+        let micros = start.elapsed().as_micros() as u64;
+        self.ops[op as usize].record(micros, bytes);
+    }
+}
+
+impl IntKv for MetricsIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let start = Instant::now();
+        let result = self.kv.read(index);
+        let len = result.as_ref().map_or(0, |data| data.len());
+        self.record(Op::Read, start, len as u64);
+        result
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let start = Instant::now();
+        let results = self.kv.read_batch(indexes);
+        let sizes = results
+            .iter()
+            .map(|r| r.as_ref().map_or(0, |data| data.len()));
+        self.record(Op::ReadBatch, start, sizes.sum::<usize>() as u64);
+        results
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let len = data.len() as u64;
+        let start = Instant::now();
+        let result = self.kv.write(index, data);
+        self.record(Op::Write, start, len);
+        result
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let len = data.len() as u64;
+        let start = Instant::now();
+        let result = self.kv.write_with_hint(index, data, hint);
+        self.record(Op::Write, start, len);
+        result
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let len = items.iter().map(|(_, data)| data.len() as u64).sum();
+        let start = Instant::now();
+        let result = self.kv.write_batch(items);
+        self.record(Op::WriteBatch, start, len);
+        result
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let start = Instant::now();
+        let result = self.kv.remove(index);
+        self.record(Op::Remove, start, 0);
+        result
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        let start = Instant::now();
+        let result = self.kv.has(index);
+        self.record(Op::Has, start, 0);
+        result
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let start = Instant::now();
+        let result = self.kv.keys();
+        self.record(Op::Keys, start, 0);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        let result = self.kv.flush();
+        self.record(Op::Flush, start, 0);
+        result
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let start = Instant::now();
+        let result = self.kv.flush_keys(keys);
+        self.record(Op::Flush, start, 0);
+        result
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        for op in Op::ALL {
+            let metrics = &self.ops[op as usize];
+            let prefix = format!("metrics.{}.{}", self.label, op.name());
+            let count = metrics.count.load(Ordering::Relaxed);
+            stats.insert(format!("{}_count", prefix), count);
+            if count == 0 {
+                continue;
+            }
+            if op.moves_data() {
+                let bytes = metrics.bytes.load(Ordering::Relaxed);
+                stats.insert(format!("{}_bytes", prefix), bytes);
+            }
+            let micros = metrics.micros.load(Ordering::Relaxed);
+            stats.insert(format!("{}_micros", prefix), micros);
+            for (percent, name) in PERCENTILES {
+                let micros = metrics.percentile(percent);
+                stats.insert(format!("{}_{}_micros", prefix, name), micros);
+            }
+        }
+        stats
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.kv.idempotent_writes()
+    }
+}
+
+/// Latencies recorded by a `MetricsIntKv`, parsed from stats.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timing {
+    pub label: String,
+    pub op: &'static str,
+    pub count: u64,
+    pub bytes: u64,
+    pub micros: u64,
+    /// Microseconds of `PERCENTILES`.
+    pub percentiles: [u64; 3],
+}
+
+/// Timings of operations run at least once, found in `stats`. For each
+/// operation, layers taking longer in total, usually those above, go
+/// first.
+pub fn timings(stats: &Stats) -> Vec<Timing> {
+    let mut found: BTreeMap<(usize, &str), Timing> = BTreeMap::new();
+    for (key, &value) in stats {
+        let rest = match key.strip_prefix("metrics.") {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let (label, rest) = match rest.split_once('.') {
+            Some(split) => split,
+            None => continue,
+        };
+        // The longest match, so "read_batch" is not taken as "read".
+        let op = Op::ALL
+            .iter()
+            .copied()
+            .filter(|op| rest.starts_with(&format!("{}_", op.name())))
+            .max_by_key(|op| op.name().len());
+        let op = match op {
+            Some(op) => op,
+            None => continue,
+        };
+        let timing = found.entry((op as usize, label)).or_insert_with(|| Timing {
+            label: label.to_string(),
+            op: op.name(),
+            ..Default::default()
+        });
+        match &rest[op.name().len() + 1..] {
+            "count" => timing.count = value,
+            "bytes" => timing.bytes = value,
+            "micros" => timing.micros = value,
+            field => {
+                for (i, (_, name)) in PERCENTILES.iter().enumerate() {
+                    if field.strip_suffix("_micros") == Some(name) {
+                        timing.percentiles[i] = value;
+                    }
+                }
+            }
+        }
+    }
+    let mut timings: Vec<((usize, &str), Timing)> =
+        found.into_iter().filter(|(_, t)| t.count > 0).collect();
+    timings.sort_by_key(|((op, _), t)| (*op, u64::MAX - t.micros));
+    timings.into_iter().map(|(_, t)| t).collect()
+}
+
+/// Render `stats` in the Prometheus text format. Timings of `MetricsIntKv`
+/// become summaries labeled by layer and operation. Other stats are
+/// gauges named after their keys.
+pub fn prometheus_text(stats: &Stats) -> String {
+    let mut out = String::new();
+    let timings = timings(stats);
+    if !timings.is_empty() {
+        out.push_str("# TYPE x79d8_layer_latency_seconds summary\n");
+        for t in &timings {
+            let labels = format!("layer=\"{}\",op=\"{}\"", t.label, t.op);
+            for (i, (percent, _)) in PERCENTILES.iter().enumerate() {
+                let seconds = t.percentiles[i] as f64 / 1e6;
+                let _ = writeln!(
+                    out,
+                    "x79d8_layer_latency_seconds{{{},quantile=\"{}\"}} {}",
+                    labels,
+                    *percent as f64 / 100.0,
+                    seconds
+                );
+            }
+            let sum = t.micros as f64 / 1e6;
+            let _ = writeln!(out, "x79d8_layer_latency_seconds_sum{{{}}} {}", labels, sum);
+            let _ = writeln!(
+                out,
+                "x79d8_layer_latency_seconds_count{{{}}} {}",
+                labels, t.count
+            );
+        }
+        out.push_str("# TYPE x79d8_layer_bytes_total counter\n");
+        for t in timings.iter().filter(|t| t.bytes > 0) {
+            let _ = writeln!(
+                out,
+                "x79d8_layer_bytes_total{{layer=\"{}\",op=\"{}\"}} {}",
+                t.label, t.op, t.bytes
+            );
+        }
+    }
+    for (key, value) in stats {
+        if key.starts_with("metrics.") {
+            continue;
+        }
+        let name: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let _ = writeln!(out, "# TYPE x79d8_{} gauge\nx79d8_{} {}", name, name, value);
+    }
+    out
+}
+
+#[test]
+fn test_metrics_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| MetricsIntKv::new(Box::new(MemIntKv::new()), "MemIntKv")),
+        50,
+    );
+}
+
+#[test]
+fn test_metrics_int_kv_stats() {
+    use super::super::backend::MemIntKv;
+    let inner = MetricsIntKv::new(Box::new(MemIntKv::new()), "MemIntKv");
+    let mut kv = MetricsIntKv::new(Box::new(inner), "Top");
+    kv.write(1, vec![0; 10].into()).unwrap();
+    kv.write(2, vec![0; 20].into()).unwrap();
+    kv.read(1).unwrap();
+    kv.read_batch(&[1, 2, 3]);
+    kv.flush().unwrap();
+
+    let stats = kv.stats();
+    assert_eq!(stats["metrics.Top.write_count"], 2);
+    assert_eq!(stats["metrics.MemIntKv.write_bytes"], 30);
+    assert_eq!(stats["metrics.MemIntKv.read_batch_bytes"], 30);
+    assert!(!stats.contains_key("metrics.Top.remove_micros"));
+
+    let timings = timings(&stats);
+    let names: Vec<(&str, &str)> = timings
+        .iter()
+        .map(|t| (t.op, t.label.as_str()))
+        .filter(|&(op, _)| op != "flush")
+        .collect();
+    assert_eq!(names.len(), 6);
+    assert_eq!(names[0].0, "read");
+    assert_eq!(names[2].0, "read_batch");
+    assert!(timings.iter().all(|t| t.percentiles[0] <= t.percentiles[2]));
+
+    let text = prometheus_text(&stats);
+    assert!(text.contains("x79d8_layer_latency_seconds_count{layer=\"Top\",op=\"read\"} 1\n"));
+    assert!(text.contains("x79d8_layer_bytes_total{layer=\"MemIntKv\",op=\"read\"} 10\n"));
+}
+
+#[test]
+fn test_metrics_percentile() {
+    let metrics = OpMetrics::default();
+    assert_eq!(metrics.percentile(50), 0);
+    for micros in 0..100 {
+        // 90 fast operations and 10 slow ones.
+        metrics.record(if micros < 90 { 3 } else { 5000 }, 0);
+    }
+    assert_eq!(metrics.percentile(50), 4);
+    assert_eq!(metrics.percentile(90), 4);
+    assert_eq!(metrics.percentile(95), 8192);
+    metrics.record(u64::MAX, 0);
+    assert_eq!(metrics.percentile(100), 1 << (BUCKETS - 1));
+}
@@ -0,0 +1,365 @@
+//! Move entries from one `IntKv` to another while both stay in use.
+//!
+//! Reads prefer the new storage and fall back to the old. Changes go to
+//! both, the new first. `drain` copies entries missing in the new storage
+//! in index order, and records how far it got in a cursor file after
+//! flushing the new storage, so an interrupted move resumes there. Entries
+//! the new storage has are skipped, since changes went there already.
+//!
+//! Once drained, the new storage has every entry and can be used alone.
+//! A flush interrupted by a crash may leave some of its changes applied to
+//! one storage only, like the other backends with several members.
+
+use super::super::{Bytes, IntKv, Stats};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct MigrateIntKv {
+    old: Box<dyn IntKv>,
+    new: Box<dyn IntKv>,
+    cursor_path: PathBuf,
+    /// Entries of the old storage below it are in the new storage.
+    cursor: usize,
+    drained: bool,
+    /// Entries of the old storage from `cursor` on, highest first. Listed
+    /// on the first `drain`.
+    remaining: Option<Vec<usize>>,
+    copied: u64,
+}
+
+impl MigrateIntKv {
+    /// Move entries of `old` to `new`. `cursor_path` records the progress.
+    /// Without it, `new` must be empty.
+    pub fn open(old: Box<dyn IntKv>, new: Box<dyn IntKv>, cursor_path: &Path) -> io::Result<Self> {
+        let corrupted = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupted", cursor_path.display()),
+            )
+        };
+        let (cursor, drained, started) = match fs::read_to_string(cursor_path) {
+            Ok(text) => match text.trim_end() {
+                "done" => (0, true, true),
+                text => (text.parse().map_err(|_| corrupted())?, false, true),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !new.keys()?.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the new storage has entries, but no record of moving them",
+                    ));
+                }
+                (0, false, false)
+            }
+            Err(e) => return Err(e),
+        };
+        let kv = Self {
+            old,
+            new,
+            cursor_path: cursor_path.to_path_buf(),
+            cursor,
+            drained,
+            remaining: None,
+            copied: 0,
+        };
+        // Changes go to `new` from now on.
+        if !started {
+            kv.save_cursor()?;
+        }
+        Ok(kv)
+    }
+
+    /// Copy up to `batch_size` entries missing in the new storage, then
+    /// flush it and record the progress. Return the number copied.
+    pub fn drain(&mut self, batch_size: usize) -> io::Result<usize> {
+        if self.drained {
+            return Ok(0);
+        }
+        self.remaining()?;
+        let remaining = self.remaining.as_ref().unwrap();
+        let batch: Vec<usize> = remaining.iter().rev().take(batch_size).cloned().collect();
+        let mut items = Vec::new();
+        for &index in &batch {
+            if self.new.has(index)? {
+                continue;
+            }
+            match self.old.read(index) {
+                // Removed since listed.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => items.push((index, result?)),
+            }
+        }
+        let count = items.len();
+        let (_, result) = self.new.write_batch(items);
+        result?;
+        self.new.flush()?;
+
+        let remaining = self.remaining.as_mut().unwrap();
+        remaining.truncate(remaining.len() - batch.len());
+        match batch.last() {
+            _ if remaining.is_empty() => self.drained = true,
+            Some(&last) => self.cursor = last + 1,
+            None => {}
+        }
+        self.copied += count as u64;
+        self.save_cursor()?;
+        Ok(count)
+    }
+
+    /// Entries left for `drain` to check, listing them if needed.
+    pub fn remaining(&mut self) -> io::Result<usize> {
+        if self.drained {
+            return Ok(0);
+        }
+        if self.remaining.is_none() {
+            let mut keys = self.old.keys()?;
+            keys.retain(|&index| index >= self.cursor);
+            keys.reverse();
+            self.remaining = Some(keys);
+        }
+        Ok(self.remaining.as_ref().unwrap().len())
+    }
+
+    /// Whether the new storage has every entry.
+    pub fn is_drained(&self) -> bool {
+        self.drained
+    }
+
+    fn save_cursor(&self) -> io::Result<()> {
+        let text = match self.drained {
+            true => "done\n".to_string(),
+            false => format!("{}\n", self.cursor),
+        };
+        let tmp_path = self.cursor_path.with_extension("tmp");
+        fs::write(&tmp_path, text)?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &self.cursor_path)
+    }
+}
+
+impl IntKv for MigrateIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        match self.new.read(index) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.old.read(index),
+            result => result,
+        }
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let mut results = self.new.read_batch(indexes);
+        let missing: Vec<usize> = results
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| matches!(r, Err(e) if e.kind() == io::ErrorKind::NotFound))
+            .map(|(i, _)| i)
+            .collect();
+        if !missing.is_empty() {
+            let old: Vec<usize> = missing.iter().map(|&i| indexes[i]).collect();
+            for (i, result) in missing.into_iter().zip(self.old.read_batch(&old)) {
+                results[i] = result;
+            }
+        }
+        results
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.new.write(index, data.clone())?;
+        self.old.write(index, data)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let (written, result) = self.new.write_batch(items.clone());
+        if result.is_err() {
+            return (written, result);
+        }
+        self.old.write_batch(items)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let in_new = self.new.has(index)?;
+        let in_old = self.old.has(index)?;
+        if !in_new && !in_old {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if in_new {
+            self.new.remove(index)?;
+        }
+        if in_old {
+            self.old.remove(index)?;
+        }
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        Ok(self.new.has(index)? || self.old.has(index)?)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys = self.new.keys()?;
+        keys.extend(self.old.keys()?);
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.new.flush()?;
+        self.old.flush()
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.new.prefetch(indexes);
+        self.old.prefetch(indexes);
+    }
+
+    fn pin(&self, index: usize) {
+        self.new.pin(index);
+        self.old.pin(index);
+    }
+
+    fn unpin(&self, index: usize) {
+        self.new.unpin(index);
+        self.old.unpin(index);
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        for (name, kv) in [("old", &self.old), ("new", &self.new)] {
+            for (key, value) in kv.stats() {
+                stats.insert(format!("migrate.{}.{}", name, key), value);
+            }
+        }
+        stats.insert("migrate.copied".into(), self.copied);
+        let remaining = match (self.drained, &self.remaining) {
+            (true, _) => Some(0),
+            (false, remaining) => remaining.as_ref().map(|r| r.len() as u64),
+        };
+        if let Some(remaining) = remaining {
+            stats.insert("migrate.remaining".into(), remaining);
+        }
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.new.check_space(extra)?;
+        self.old.check_space(extra)
+    }
+
+    /// Also copy up to `max_pages` entries by `drain`, so moving continues
+    /// while the storage is in use.
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.new.compact_step(max_pages)?;
+        self.old.compact_step(max_pages)?;
+        self.drain(max_pages)?;
+        Ok(())
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.new.idempotent_writes() && self.old.idempotent_writes()
+    }
+}
+
+#[test]
+fn test_migrate_int_kv() {
+    use super::super::backend::MemIntKv;
+    let dir = tempfile::tempdir().unwrap();
+    let cursor_path = dir.path().join("cursor");
+    super::super::test_int_kv(
+        |kv: Option<MigrateIntKv>| {
+            let (old, new): (Box<dyn IntKv>, Box<dyn IntKv>) = match kv {
+                Some(mut kv) => {
+                    kv.drain(5).unwrap();
+                    (kv.old, kv.new)
+                }
+                None => {
+                    let mut old = MemIntKv::new();
+                    old.write(70, vec![7].into()).unwrap();
+                    (Box::new(old), Box::new(MemIntKv::new()))
+                }
+            };
+            MigrateIntKv::open(old, new, &cursor_path).unwrap()
+        },
+        50,
+    );
+}
+
+#[test]
+fn test_migrate_int_kv_drain() {
+    use super::super::backend::MemIntKv;
+    let dir = tempfile::tempdir().unwrap();
+    let cursor_path = dir.path().join("cursor");
+    let mut old = MemIntKv::new();
+    for i in 0..10 {
+        old.write(i * 2, vec![i as u8].into()).unwrap();
+    }
+    let mut kv =
+        MigrateIntKv::open(Box::new(old), Box::new(MemIntKv::new()), &cursor_path).unwrap();
+    assert_eq!(kv.remaining().unwrap(), 10);
+    assert_eq!(kv.drain(3).unwrap(), 3);
+    assert_eq!(fs::read_to_string(&cursor_path).unwrap(), "5\n");
+    // Changes go to both. Entries the new storage has are skipped.
+    kv.write(6, vec![30].into()).unwrap();
+    kv.remove(8).unwrap();
+    kv.write(1, vec![10].into()).unwrap();
+    assert_eq!(kv.drain(3).unwrap(), 1);
+    assert_eq!(kv.stats()["migrate.remaining"], 4);
+    assert_eq!(kv.read(6).unwrap(), vec![30]);
+    assert_eq!(kv.read(12).unwrap(), vec![6]);
+    assert!(!kv.is_drained());
+    kv.compact_step(10).unwrap();
+    assert!(kv.is_drained());
+    assert_eq!(kv.stats()["migrate.copied"], 8);
+    let MigrateIntKv { old, new, .. } = kv;
+    assert_eq!(new.keys().unwrap(), old.keys().unwrap());
+    assert_eq!(new.read(1).unwrap(), vec![10]);
+    assert_eq!(fs::read_to_string(&cursor_path).unwrap(), "done\n");
+
+    // A new storage with entries needs the record of the move.
+    fs::remove_file(&cursor_path).unwrap();
+    let err = MigrateIntKv::open(old, new, &cursor_path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_migrate_int_kv_resume() {
+    use super::super::backend::FsIntKv;
+    use super::{FaultIntKv, Op};
+    let dir = tempfile::tempdir().unwrap();
+    let (old_dir, new_dir) = (dir.path().join("old"), dir.path().join("new"));
+    fs::create_dir(&old_dir).unwrap();
+    fs::create_dir(&new_dir).unwrap();
+    let cursor_path = dir.path().join("cursor");
+    let mut old = FsIntKv::new(&old_dir).unwrap();
+    for i in 0..20 {
+        old.write(i, vec![i as u8].into()).unwrap();
+    }
+    old.flush().unwrap();
+    drop(old);
+    let open = |budget: usize| {
+        let old = FsIntKv::new(&old_dir).unwrap();
+        let new = FaultIntKv::new(Box::new(FsIntKv::new(&new_dir).unwrap()));
+        new.faults().fail_after(&[Op::Write], budget);
+        MigrateIntKv::open(Box::new(old), Box::new(new), &cursor_path).unwrap()
+    };
+
+    // Crash after a drained batch, then during one.
+    let mut kv = open(usize::MAX);
+    assert_eq!(kv.drain(8).unwrap(), 8);
+    drop(kv);
+    let mut kv = open(3);
+    kv.drain(8).unwrap_err();
+    drop(kv);
+    assert_eq!(fs::read_to_string(&cursor_path).unwrap(), "8\n");
+
+    let mut kv = open(usize::MAX);
+    while !kv.is_drained() {
+        kv.drain(5).unwrap();
+    }
+    assert_eq!(kv.stats()["migrate.copied"], 12);
+    drop(kv);
+    let new = FsIntKv::new(&new_dir).unwrap();
+    assert_eq!(new.keys().unwrap(), (0..20).collect::<Vec<_>>());
+    assert_eq!(new.read(19).unwrap(), vec![19]);
+}
@@ -0,0 +1,424 @@
+//! Keep a second copy of every entry in another `IntKv`, so either copy
+//! alone can restore the data.
+//!
+//! Reads go to the primary, and fall back to the secondary if an entry is
+//! missing or corrupted. Changes go to both. In degraded mode a failing
+//! secondary is taken offline instead of failing operations, and entries
+//! changed meanwhile are recorded in a file so `repair` can copy them.
+
+use super::super::{Bytes, IntKv, Stats};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug)]
+pub struct MirrorIntKv {
+    primary: Box<dyn IntKv>,
+    /// None if offline.
+    secondary: Option<Box<dyn IntKv>>,
+    /// Where to record `resync`. Set in degraded mode.
+    resync_path: Option<PathBuf>,
+    /// Entries to copy to the secondary, changed while it was offline.
+    resync: BTreeSet<usize>,
+    /// `resync` differs from the recorded list.
+    resync_dirty: bool,
+    /// Entries changed in the secondary since it was last flushed. Added
+    /// to `resync` if the secondary goes offline before that.
+    unflushed: BTreeSet<usize>,
+    fallbacks: AtomicU64,
+}
+
+impl MirrorIntKv {
+    /// Mirror `primary` to `secondary`. Errors of either fail operations.
+    pub fn new(primary: Box<dyn IntKv>, secondary: Box<dyn IntKv>) -> Self {
+        Self::with_parts(primary, Some(secondary))
+    }
+
+    /// Mirror with the secondary offline from the start, in degraded mode.
+    pub fn offline(primary: Box<dyn IntKv>, resync_path: &Path) -> io::Result<Self> {
+        Self::with_parts(primary, None).with_degraded(resync_path)
+    }
+
+    fn with_parts(primary: Box<dyn IntKv>, secondary: Option<Box<dyn IntKv>>) -> Self {
+        Self {
+            primary,
+            secondary,
+            resync_path: None,
+            resync: BTreeSet::new(),
+            resync_dirty: false,
+            unflushed: BTreeSet::new(),
+            fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep working if the secondary fails: take it offline, and record
+    /// entries changed meanwhile in `resync_path`.
+    pub fn with_degraded(mut self, resync_path: &Path) -> io::Result<Self> {
+        self.resync = read_resync(resync_path)?;
+        self.resync_path = Some(resync_path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Whether the secondary is offline.
+    pub fn is_degraded(&self) -> bool {
+        self.secondary.is_none()
+    }
+
+    /// Entries waiting for `repair`.
+    pub fn resync_len(&self) -> usize {
+        self.resync.len()
+    }
+
+    /// Entries `repair` would copy to the secondary, and to the primary.
+    pub fn differences(&self) -> io::Result<(BTreeSet<usize>, BTreeSet<usize>)> {
+        let secondary = self.online_secondary()?;
+        let primary_keys: BTreeSet<usize> = self.primary.keys()?.into_iter().collect();
+        let secondary_keys: BTreeSet<usize> = secondary.keys()?.into_iter().collect();
+        let mut to_secondary = self.resync.clone();
+        to_secondary.extend(primary_keys.difference(&secondary_keys));
+        let to_primary = secondary_keys
+            .difference(&primary_keys)
+            .filter(|index| !self.resync.contains(index))
+            .cloned()
+            .collect();
+        Ok((to_secondary, to_primary))
+    }
+
+    /// Make both sides equal: copy recorded entries to the secondary, and
+    /// entries present on one side only to the other. Return the number
+    /// of entries copied or removed.
+    pub fn repair(&mut self) -> io::Result<usize> {
+        let (to_secondary, to_primary) = self.differences()?;
+        let secondary = self.secondary.as_mut().unwrap();
+        let count = to_secondary.len() + to_primary.len();
+        for index in to_secondary {
+            match self.primary.read(index) {
+                Ok(data) => secondary.write(index, data)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => match secondary.remove(index) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        for index in to_primary {
+            self.primary.write(index, secondary.read(index)?)?;
+        }
+        secondary.flush()?;
+        self.primary.flush()?;
+        if !self.resync.is_empty() {
+            self.resync.clear();
+            self.resync_dirty = true;
+            self.save_resync()?;
+        }
+        Ok(count)
+    }
+
+    fn online_secondary(&self) -> io::Result<&dyn IntKv> {
+        match &self.secondary {
+            Some(secondary) => Ok(secondary.as_ref()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the mirror is offline",
+            )),
+        }
+    }
+
+    /// Run `op` on the secondary for a change of `indexes`. In degraded
+    /// mode, take the secondary offline if it fails.
+    fn on_secondary(
+        &mut self,
+        indexes: &[usize],
+        op: impl FnOnce(&mut dyn IntKv) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let result = match &mut self.secondary {
+            Some(secondary) => op(secondary.as_mut()),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        };
+        match result {
+            Ok(()) => {
+                self.unflushed.extend(indexes);
+                Ok(())
+            }
+            Err(e) if self.resync_path.is_some() => {
+                if self.secondary.take().is_some() {
+                    log::warn!("Mirror is offline ({}). Recording changes for repair.", e);
+                    self.resync.append(&mut self.unflushed);
+                    self.resync_dirty = true;
+                }
+                if !indexes.is_empty() {
+                    self.resync.extend(indexes);
+                    self.resync_dirty = true;
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save_resync(&mut self) -> io::Result<()> {
+        let path = match (&self.resync_path, self.resync_dirty) {
+            (Some(path), true) => path,
+            _ => return Ok(()),
+        };
+        if self.resync.is_empty() {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        } else {
+            let text: String = self.resync.iter().map(|i| format!("{}\n", i)).collect();
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, text)?;
+            fs::File::open(&tmp_path)?.sync_all()?;
+            fs::rename(&tmp_path, path)?;
+        }
+        self.resync_dirty = false;
+        Ok(())
+    }
+
+    /// Read from the secondary after the primary failed with `e`. Entries
+    /// to resync are stale there.
+    fn fallback(&self, index: usize, e: io::Error) -> io::Result<Bytes> {
+        let secondary = match &self.secondary {
+            Some(secondary) if is_bad_entry(&e) && !self.resync.contains(&index) => secondary,
+            _ => return Err(e),
+        };
+        match secondary.read(index) {
+            Ok(data) => {
+                log::warn!("Entry {} read from the mirror ({})", index, e);
+                self.fallbacks.fetch_add(1, Ordering::Relaxed);
+                Ok(data)
+            }
+            Err(_) => Err(e),
+        }
+    }
+}
+
+impl IntKv for MirrorIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.primary
+            .read(index)
+            .or_else(|e| self.fallback(index, e))
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let results = self.primary.read_batch(indexes);
+        results
+            .into_iter()
+            .zip(indexes)
+            .map(|(result, &index)| result.or_else(|e| self.fallback(index, e)))
+            .collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.primary.write(index, data.clone())?;
+        self.on_secondary(&[index], |kv| kv.write(index, data))
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let (written, result) = self.primary.write_batch(items.clone());
+        let items: Vec<_> = items.into_iter().take(written).collect();
+        let indexes: Vec<usize> = items.iter().map(|(index, _)| *index).collect();
+        let mirrored = self.on_secondary(&indexes, |kv| kv.write_batch(items).1);
+        (written, result.and(mirrored))
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.primary.remove(index)?;
+        self.on_secondary(&[index], |kv| match kv.remove(index) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        })
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.primary.has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.primary.keys()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mirrored = self.on_secondary(&[], |kv| kv.flush());
+        if mirrored.is_ok() && self.secondary.is_some() {
+            self.unflushed.clear();
+        }
+        // Recorded before the changes are persisted, so none is missed.
+        self.save_resync()?;
+        let result = self.primary.flush();
+        result.and(mirrored)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.primary.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.primary.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.primary.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.primary.stats();
+        if let Some(secondary) = &self.secondary {
+            for (key, value) in secondary.stats() {
+                stats.insert(format!("mirror.secondary.{}", key), value);
+            }
+        }
+        let fallbacks = self.fallbacks.load(Ordering::Relaxed);
+        stats.insert("mirror.fallbacks".into(), fallbacks);
+        stats.insert("mirror.degraded".into(), self.is_degraded() as u64);
+        stats.insert("mirror.resync".into(), self.resync.len() as u64);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.primary.check_space(extra)?;
+        match &self.secondary {
+            Some(secondary) if self.resync_path.is_none() => secondary.check_space(extra),
+            _ => Ok(()),
+        }
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.primary.compact_step(max_pages)?;
+        self.on_secondary(&[], |kv| kv.compact_step(max_pages))
+    }
+
+    /// Copy the primary only.
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.flush()?;
+        self.primary.snapshot(dest)
+    }
+}
+
+/// Whether `e` means the entry is missing or corrupted, so the other copy
+/// might be good.
+fn is_bad_entry(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+fn read_resync(path: &Path) -> io::Result<BTreeSet<usize>> {
+    let text = match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        result => result?,
+    };
+    text.lines()
+        .map(|line| {
+            line.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is corrupted", path.display()),
+                )
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_mirror_int_kv() {
+    use super::super::backend::MemIntKv;
+    let kv = super::super::test_int_kv(
+        |kv| {
+            kv.unwrap_or_else(|| {
+                MirrorIntKv::new(Box::new(MemIntKv::new()), Box::new(MemIntKv::new()))
+            })
+        },
+        50,
+    );
+    let secondary = kv.secondary.as_ref().unwrap();
+    assert_eq!(secondary.keys().unwrap(), kv.primary.keys().unwrap());
+}
+
+#[test]
+fn test_mirror_int_kv_divergence() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    let mut kv = MirrorIntKv::new(Box::new(MemIntKv::new()), Box::new(MemIntKv::new()));
+    for i in 0..4 {
+        kv.write(i, vec![i as u8].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    kv.primary.remove(1).unwrap();
+    kv.primary.write(5, vec![5].into()).unwrap();
+    kv.secondary.as_mut().unwrap().remove(2).unwrap();
+    assert_eq!(kv.read(1).unwrap().as_ref(), &[1]);
+    assert_eq!(kv.read_batch(&[2, 1])[1].as_ref().unwrap().as_ref(), &[1]);
+    assert_eq!(kv.stats()["mirror.fallbacks"], 2);
+
+    let (to_secondary, to_primary) = kv.differences().unwrap();
+    assert_eq!(to_secondary.into_iter().collect::<Vec<_>>(), [2, 5]);
+    assert_eq!(to_primary.into_iter().collect::<Vec<_>>(), [1]);
+    assert_eq!(kv.repair().unwrap(), 3);
+    let (to_secondary, to_primary) = kv.differences().unwrap();
+    assert!(to_secondary.is_empty() && to_primary.is_empty());
+    assert_eq!(kv.primary.read(1).unwrap().as_ref(), &[1]);
+
+    // Without degraded mode, errors of the secondary fail operations.
+    let secondary = FaultIntKv::new(Box::new(MemIntKv::new()));
+    secondary.faults().fail_after(&[Op::Write, Op::Remove], 0);
+    let mut kv = MirrorIntKv::new(Box::new(MemIntKv::new()), Box::new(secondary));
+    assert!(kv.write(1, vec![1].into()).is_err());
+    assert!(!kv.is_degraded());
+}
+
+#[test]
+fn test_mirror_int_kv_degraded() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    let dir = tempfile::tempdir().unwrap();
+    let resync_path = dir.path().join("resync");
+    let secondary = FaultIntKv::new(Box::new(MemIntKv::new()));
+    secondary.faults().fail_after(&[Op::Write, Op::Remove], 2);
+    let mut kv = MirrorIntKv::new(Box::new(MemIntKv::new()), Box::new(secondary))
+        .with_degraded(&resync_path)
+        .unwrap();
+    kv.write(1, b"a".to_vec().into()).unwrap();
+    kv.write(2, b"a".to_vec().into()).unwrap();
+    // The secondary fails from here.
+    kv.write(1, b"b".to_vec().into()).unwrap();
+    kv.remove(2).unwrap();
+    kv.write(3, b"b".to_vec().into()).unwrap();
+    assert!(kv.is_degraded());
+    kv.flush().unwrap();
+    let recorded: Vec<usize> = read_resync(&resync_path).unwrap().into_iter().collect();
+    assert_eq!(recorded, [1, 2, 3]);
+
+    // Reopened with the secondary back, holding the older entries.
+    let MirrorIntKv { primary, .. } = kv;
+    let mut secondary = MemIntKv::new();
+    secondary.insert(1, b"a".to_vec().into());
+    secondary.insert(2, b"a".to_vec().into());
+    let mut kv = MirrorIntKv::new(primary, Box::new(secondary))
+        .with_degraded(&resync_path)
+        .unwrap();
+    assert_eq!(kv.resync_len(), 3);
+    // The removed entry does not come back from the secondary.
+    assert_eq!(kv.read(2).unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(
+        kv.read_batch(&[2])[0].as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(kv.repair().unwrap(), 3);
+    assert!(!resync_path.exists());
+    let secondary = kv.secondary.as_ref().unwrap();
+    assert_eq!(secondary.keys().unwrap(), [1, 3]);
+    assert_eq!(secondary.read(1).unwrap().as_ref(), b"b");
+
+    let kv = MirrorIntKv::offline(Box::new(MemIntKv::new()), &resync_path).unwrap();
+    assert!(kv.is_degraded());
+    assert_eq!(
+        kv.differences().unwrap_err().kind(),
+        io::ErrorKind::NotConnected
+    );
+}
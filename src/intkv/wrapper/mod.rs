@@ -1,7 +1,45 @@
 mod buffered;
+mod checksum;
+mod compress;
+mod cow;
 mod enc;
+#[cfg(any(test, feature = "testing"))]
+mod fault;
+mod metrics;
+mod migrate;
+mod mirror;
 mod page;
+mod parity;
+mod quota;
+mod retry;
+mod sharded;
+mod stripe;
+mod throttle;
+mod tiered;
+mod trace;
+mod versioned;
 
 pub use buffered::BufferedIntKv;
-pub use enc::EncIntKv;
+pub use checksum::{Checksum, ChecksumIntKv};
+pub use compress::CompressIntKv;
+pub use cow::CowIntKv;
+pub use enc::{key_check, unwrap_key, wrap_key, Cipher, EncIntKv, HeaderVersion};
+#[cfg(any(test, feature = "testing"))]
+pub use fault::FaultIntKv;
+#[cfg(feature = "testing")]
+pub use fault::Faults;
+#[cfg(test)]
+pub use fault::Op;
+pub use metrics::{prometheus_text, timings, MetricsIntKv};
+pub use migrate::MigrateIntKv;
+pub use mirror::MirrorIntKv;
 pub use page::PageIntKv;
+pub use parity::ParityIntKv;
+pub use quota::QuotaIntKv;
+pub use retry::RetryIntKv;
+pub use sharded::ShardedIntKv;
+pub use stripe::{StripeIntKv, DEFAULT_STRIPE_WIDTH};
+pub use throttle::ThrottleIntKv;
+pub use tiered::TieredIntKv;
+pub use trace::{replay, TraceIntKv};
+pub use versioned::VersionedIntKv;
@@ -1,12 +1,20 @@
-use super::super::{Bytes, IntKv};
-use crate::util::bincode_deserialize;
-use crate::util::bincode_serialize_pad;
-use crate::util::bincode_size;
-use serde::{Deserialize, Serialize};
+use super::super::{Bytes, CacheLimits, Hint, IntKv, Stats};
+use crate::util::bincode_deserialize_with;
+use crate::util::bincode_serialize_pad_with;
+use crate::util::bincode_size_with;
+use crate::util::parallel_map;
+use crate::util::Encoding;
+use parking_lot::RwLock;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fmt;
 use std::io;
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Normalize requests so only fixed-sized sized pages are
 /// written.
@@ -15,12 +23,26 @@ use std::io;
 /// A meta page consists of:
 /// - logical index -> physical data page index mapping
 /// - physical data page -> logical
+///
+/// Meta page 0 and pages linked from it form a directory of leaf meta
+/// pages. Each leaf stores the mappings of a fixed range of keys, so a
+/// change only rewrites the leaf covering it, and the directory.
+///
 /// A data page consists of:
 /// - logical index -> (data chunk, Option<next page index>)
+///
 /// To reconstruct data, first lookup from the meta page,
 /// then follow the linked list in data pages and concat
 /// all data chunks.
 ///
+/// Pages are stamped with the generation of the flush writing them. The
+/// parent meta page records the generation, so an older copy of a page
+/// is detected as stale on read.
+///
+/// Large data stores its beginning in extents: pages of raw
+/// bytes listed in the meta page. They can be read without
+/// walking a chain. The rest uses the linked list.
+///
 /// Modifications are buffered. Meta pages are eagerly
 /// loaded into memory on construction.
 #[derive(Debug)]
@@ -28,25 +50,171 @@ pub struct PageIntKv {
     // Desired page size.
     page_size: u64,
 
-    // Physical page indexes.
-    // Together with data_page_sizes for finding free pages.
+    // Integer encoding of meta pages and data pages.
+    encoding: Encoding,
+
+    // Physical page indexes: the directory starting from page 0, then
+    // leaves. Together with data_page_sizes for finding free pages.
     meta_pages: Vec<u64>,
 
+    // Start of the range of each leaf meta page -> physical page index.
+    // Empty in older vaults, which store mappings in the directory.
+    meta_ranges: BTreeMap<MetaKey, u64>,
+
+    // Physical meta page index -> content written. Unchanged meta pages
+    // are not rewritten.
+    meta_page_contents: HashMap<u64, Bytes>,
+
     // physical data page index -> physical data page size.
     // Also serves as a way to get all data pages.
-    data_page_sizes: BTreeMap<u64, u64>,
+    data_page_sizes: DataPageSizes,
 
     // Data pages that are changed, not flushed.
     // Empty pages will be deleted on flush.
-    dirty_data_pages: BTreeMap<u64, DataPage>,
+    // Shared with readers. Mutations copy the page.
+    dirty_data_pages: BTreeMap<u64, Arc<DataPage>>,
 
     // logical -> first physical page index.
     map_index: BTreeMap<u64, u64>,
 
+    // Changed logical indexes, not flushed -> flushed map_index value.
+    dirty_map_index: BTreeMap<u64, Option<u64>>,
+
+    // logical -> extents storing the beginning of large data.
+    extents: BTreeMap<u64, Vec<Extent>>,
+
+    // Changed logical indexes, not flushed -> flushed extents value.
+    dirty_extents: BTreeMap<u64, Option<Vec<Extent>>>,
+
+    // Extent pages that are changed, not flushed -> (logical index,
+    // data to write, or None to delete).
+    dirty_extent_pages: BTreeMap<u64, (u64, Option<Bytes>)>,
+
+    // Changed data page -> logical indexes that changed the page.
+    // Used to find dependencies in `flush_keys`.
+    dirty_page_keys: BTreeMap<u64, BTreeSet<u64>>,
+
+    // Changed data page -> flushed data_page_sizes value.
+    dirty_page_sizes: BTreeMap<u64, Option<u64>>,
+
+    // Data page -> change of incoming links (from map_index or chunks) since
+    // the page was written. Checked against the change of chunks on flush.
+    ref_deltas: BTreeMap<u64, i64>,
+
+    // Links on disk are unknown after salvaging or a failed flush. Skip
+    // checking `ref_deltas` until the next full flush.
+    refs_unknown: bool,
+
+    // Physical page index -> generation the page was written with, for data
+    // pages and meta pages except page 0. Missing for pages written by older
+    // versions, and data pages too full to store a generation.
+    page_generations: BTreeMap<u64, u64>,
+
+    // Generation of page 0. Pages written by the next flush use the next
+    // generation.
+    generation: u64,
+
+    // Next never-used physical page index. Grows monotonically.
+    next_page_id: u64,
+
+    // Deleted physical page indexes that can be reused.
+    free_pages: BTreeSet<u64>,
+
+    // logical -> physical data pages of a multi-page chain, learned from
+    // previous reads or writes. Used as prefetch hints. Might be outdated.
+    chain_hints: RwLock<HashMap<u64, Vec<u64>>>,
+
+    // Decoded clean data pages recently visited by `read`. Dropped when a
+    // page becomes dirty.
+    page_cache: RwLock<HashMap<u64, Arc<DataPage>>>,
+
+    // Run verify() on load and after flush.
+    paranoid_checks: bool,
+
+    // Fill pages up to this ratio when placing data that fits in a page.
+    fill_factor: f64,
+
+    // Allocate page indexes sequentially instead of randomly.
+    sequential_allocation: bool,
+
+    // Drop writes of data equal to what the entry already has.
+    skip_identical_writes: bool,
+
+    // Threads to serialize data pages on flush.
+    threads: usize,
+
+    // Data pages dedicated to entries written with `Hint::Hot`. Not
+    // persisted. Learned again from later writes.
+    hot_pages: BTreeSet<u64>,
+
     // Underlying kv.
     kv: Box<dyn IntKv>,
+
+    // Number of page allocation calls.
+    #[cfg(test)]
+    alloc_calls: usize,
+}
+
+/// Number of pages to prefetch ahead when reading a chain.
+const PREFETCH_PAGES: usize = 4;
+
+/// Maximum number of chains to remember for prefetching.
+const CHAIN_HINTS_LIMIT: usize = 4096;
+
+/// Maximum number of entries in `page_cache`.
+const PAGE_CACHE_LIMIT: usize = 16;
+
+/// bincode size of the next page index of a chunk. Fixed with either
+/// encoding so relinking a chunk does not change the size of its page.
+const NEXT_PAGE_INDEX_SIZE: u64 = 8;
+
+/// Bytes at the end of a data page storing its generation.
+const GENERATION_SIZE: u64 = 8;
+
+/// Largest growth of the bincode size of a collection length by one item.
+fn count_growth(encoding: Encoding) -> u64 {
+    encoding.max_int_size() - encoding.int_size(0)
+}
+
+/// bincode size of an empty `DataPage`: the number of chunks.
+fn empty_data_page_size(encoding: Encoding) -> u64 {
+    encoding.int_size(0)
+}
+
+/// bincode size of a chunk entry: logical index, next page index, data
+/// length, data.
+fn chunk_size(encoding: Encoding, logical_index: u64, len: u64) -> u64 {
+    encoding.int_size(logical_index) + NEXT_PAGE_INDEX_SIZE + encoding.int_size(len) + len
+}
+
+/// Largest bincode size a chunk adds to a page, excluding its data.
+fn chunk_overhead(encoding: Encoding) -> u64 {
+    encoding.max_int_size() * 2 + NEXT_PAGE_INDEX_SIZE + count_growth(encoding)
+}
+
+/// Largest bincode size of an empty `MetaPage`: next page, lengths of 6
+/// collections, next page id, generation.
+fn empty_meta_page_size(encoding: Encoding) -> u64 {
+    encoding.max_int_size() * 9
+}
+
+/// Largest bincode size of a range in the directory: start, leaf page
+/// index, and the generation of the leaf.
+fn meta_range_size(encoding: Encoding) -> u64 {
+    1 + encoding.max_int_size() * 4
+}
+
+/// Largest bincode size of the generation of the next directory page.
+fn meta_next_generation_size(encoding: Encoding) -> u64 {
+    encoding.max_int_size() * 2
 }
 
+/// Chains longer than this ratio of the ideal length get rewritten.
+const MAX_CHAIN_RATIO: usize = 2;
+
+/// Data of at least this many pages uses extents.
+const EXTENT_MIN_PAGES: usize = 4;
+
 #[derive(Serialize, Deserialize, Default)]
 struct MetaPage {
     // physical page index for the next meta page (0: end)
@@ -58,15 +226,68 @@ struct MetaPage {
     // physical data page index -> physical data page size
     data_size_indexes: BTreeMap<u64, u64>,
 
+    // Next never-used physical page index. Only set in the first page.
+    // Missing (0) in older vaults.
+    next_page_id: u64,
+
+    // Reusable physical page indexes. Missing in older vaults.
+    free_pages: BTreeSet<u64>,
+
+    // logical -> extents. Missing in older vaults.
+    extents: BTreeMap<u64, Vec<Extent>>,
+
+    // Start of a range -> leaf meta page storing entries in the range.
+    // Only used by the directory. Missing in older vaults.
+    ranges: BTreeMap<MetaKey, u64>,
+
+    // Physical page index -> generation it was written with. Leaves record
+    // data pages. The directory records leaves and the next directory page.
+    // Missing in older vaults.
+    generations: BTreeMap<u64, u64>,
+
+    // Generation of this page. Missing (0) in older vaults.
+    generation: u64,
+
     #[serde(skip)]
     page_index: u64,
 }
 
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+/// Position of a meta entry: kind, then the logical or physical index.
+/// Leaf meta pages cover ranges of positions.
+type MetaKey = (u8, u64);
+
+const META_DATA_SIZE: u8 = 0;
+const META_FREE_PAGE: u8 = 1;
+const META_MAP_INDEX: u8 = 2;
+const META_EXTENTS: u8 = 3;
+
+/// An entry in a leaf meta page.
+enum MetaEntry {
+    // Size and generation (0: none).
+    DataSize(u64, u64),
+    FreePage,
+    MapIndex(u64),
+    Extents(Vec<Extent>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct DataPage {
     // logical index, chunk of data
     chunks: BTreeMap<u64, Chunk>,
 
+    // bincode size, maintained by insert_chunk and remove_chunk.
+    #[serde(skip)]
+    size: u64,
+
+    #[serde(skip)]
+    encoding: Encoding,
+
+    // Number of chunks and their links when the page was loaded.
+    #[serde(skip)]
+    loaded_chunks: usize,
+    #[serde(skip)]
+    loaded_links: Vec<u64>,
+
     #[serde(skip)]
     page_index: u64,
 }
@@ -74,10 +295,227 @@ struct DataPage {
 struct Chunk {
     // Physical page index for the next data page containing the next
     // part of the data belonging to a single logical index (0: end).
+    #[serde(with = "fixed_u64")]
     next_page_index: u64,
     data: Bytes,
 }
 
+/// A physical page storing raw bytes of large data.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+struct Extent {
+    page_index: u64,
+    len: u64,
+}
+
+/// Result of `PageIntKv::compact`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompactReport {
+    /// Chunks moved to other pages.
+    pub chunks_moved: usize,
+
+    /// Data pages that become empty. They are deleted on flush.
+    pub pages_reclaimed: usize,
+
+    /// Physical bytes of reclaimed pages.
+    pub bytes_reclaimed: u64,
+}
+
+/// Space usage of `PageIntKv`. See `PageIntKv::frag_stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FragStats {
+    pub data_pages: usize,
+    pub meta_pages: usize,
+    pub extent_pages: usize,
+
+    /// Bytes of all pages on the backend.
+    pub physical_bytes: u64,
+
+    /// Bytes used by data pages and extents.
+    pub used_bytes: u64,
+
+    /// Number of data pages by fill ratio, in 10% steps.
+    pub fill_histogram: [usize; 10],
+
+    /// Most data pages used by an entry. Only calculated in deep mode.
+    pub longest_chain: Option<usize>,
+
+    /// Entries using more than one page. Only calculated in deep mode.
+    pub multi_page_entries: Option<usize>,
+}
+
+/// Problems found by `PageIntKv::verify_fast` or `verify_full`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    /// Convert to an `InvalidData` error if there are problems.
+    pub fn into_result(self) -> io::Result<()> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                self.problems.join("; "),
+            ))
+        }
+    }
+}
+
+impl Default for DataPage {
+    fn default() -> Self {
+        Self::empty(Encoding::Fixint)
+    }
+}
+
+impl DataPage {
+    fn empty(encoding: Encoding) -> Self {
+        Self {
+            chunks: Default::default(),
+            size: empty_data_page_size(encoding),
+            encoding,
+            loaded_chunks: 0,
+            loaded_links: Vec::new(),
+            page_index: 0,
+        }
+    }
+
+    fn parse(data: &[u8], page_index: u64, encoding: Encoding) -> io::Result<Self> {
+        let mut page: DataPage = bincode_deserialize_with(encoding, data)?;
+        let chunks_size: u64 = page.chunks.iter().map(|(&k, c)| c.size(encoding, k)).sum();
+        page.size = encoding.int_size(page.chunks.len() as u64) + chunks_size;
+        page.encoding = encoding;
+        page.page_index = page_index;
+        page.loaded_chunks = page.chunks.len();
+        page.loaded_links = page.links().collect();
+        Ok(page)
+    }
+
+    /// bincode size of the page, without serializing it.
+    fn size(&self) -> u64 {
+        debug_assert_eq!(self.size, bincode_size_with(self.encoding, self));
+        self.size
+    }
+
+    /// bincode size of the page after inserting a new chunk of `len` bytes.
+    fn size_with_chunk(&self, logical_index: u64, len: u64) -> u64 {
+        let n = self.chunks.len() as u64;
+        let encoding = self.encoding;
+        self.size() + encoding.int_size(n + 1) - encoding.int_size(n)
+            + chunk_size(encoding, logical_index, len)
+    }
+
+    /// Longest data of a new chunk that keeps the page within `limit`.
+    /// The empty chunk must fit.
+    fn max_chunk_len(&self, logical_index: u64, limit: u64) -> u64 {
+        let encoding = self.encoding;
+        // Bytes for the data and its length.
+        let room = limit - self.size_with_chunk(logical_index, 0) + encoding.int_size(0);
+        let mut len = room.saturating_sub(encoding.int_size(room));
+        while len + 1 + encoding.int_size(len + 1) <= room {
+            len += 1;
+        }
+        len
+    }
+
+    fn insert_chunk(&mut self, logical_index: u64, chunk: Chunk) {
+        let encoding = self.encoding;
+        let n = self.chunks.len() as u64;
+        self.size += chunk.size(encoding, logical_index);
+        match self.chunks.insert(logical_index, chunk) {
+            Some(old) => self.size -= old.size(encoding, logical_index),
+            None => self.size = self.size + encoding.int_size(n + 1) - encoding.int_size(n),
+        }
+    }
+
+    fn remove_chunk(&mut self, logical_index: u64) -> Option<Chunk> {
+        let chunk = self.chunks.remove(&logical_index)?;
+        let encoding = self.encoding;
+        let n = self.chunks.len() as u64;
+        self.size = self.size + encoding.int_size(n) - encoding.int_size(n + 1);
+        self.size -= chunk.size(encoding, logical_index);
+        Some(chunk)
+    }
+
+    /// Test if the generation fits after the chunks. Pages written by older
+    /// versions might be too full for it.
+    fn has_room_for_generation(&self, page_size: u64) -> bool {
+        self.size() + GENERATION_SIZE <= page_size
+    }
+
+    /// Next page indexes linked from chunks.
+    fn links(&self) -> impl Iterator<Item = u64> + '_ {
+        self.chunks
+            .values()
+            .map(|c| c.next_page_index)
+            .filter(|&i| i != 0)
+    }
+}
+
+impl MetaPage {
+    /// Positions of entries in the page.
+    fn keys(&self) -> impl Iterator<Item = MetaKey> + '_ {
+        let sizes = self.data_size_indexes.keys().map(|&k| (META_DATA_SIZE, k));
+        let free = self.free_pages.iter().map(|&k| (META_FREE_PAGE, k));
+        let map = self.map_index.keys().map(|&k| (META_MAP_INDEX, k));
+        let extents = self.extents.keys().map(|&k| (META_EXTENTS, k));
+        sizes.chain(free).chain(map).chain(extents)
+    }
+}
+
+impl MetaEntry {
+    /// bincode size of the entry at `key` in a `MetaPage`, excluding the
+    /// lengths of collections.
+    fn size(&self, key: u64, encoding: Encoding) -> u64 {
+        let int = |value: u64| encoding.int_size(value);
+        match self {
+            // (key, value) pairs.
+            MetaEntry::DataSize(size, 0) => int(key) + int(*size),
+            MetaEntry::MapIndex(index) => int(key) + int(*index),
+            // Also in generations.
+            MetaEntry::DataSize(size, generation) => int(key) * 2 + int(*size) + int(*generation),
+            // Page index.
+            MetaEntry::FreePage => int(key),
+            // (key, extents).
+            MetaEntry::Extents(extents) => {
+                let extents_size: u64 =
+                    extents.iter().map(|e| int(e.page_index) + int(e.len)).sum();
+                int(key) + int(extents.len() as u64) + extents_size
+            }
+        }
+    }
+}
+
+impl Chunk {
+    /// bincode size of the chunk entry at `logical_index` in a `DataPage`.
+    fn size(&self, encoding: Encoding, logical_index: u64) -> u64 {
+        chunk_size(encoding, logical_index, self.data.len() as u64)
+    }
+}
+
+/// Serialize a `u64` as 8 big-endian bytes with either encoding. Same as
+/// the fixint encoding.
+mod fixed_u64 {
+    use super::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        value.to_be_bytes().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        <[u8; 8]>::deserialize(deserializer).map(u64::from_be_bytes)
+    }
+}
+
 impl fmt::Debug for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Chunk")
@@ -87,132 +525,692 @@ impl fmt::Debug for Chunk {
     }
 }
 
+/// Sizes of data pages, also indexed by free space in buckets of powers of
+/// two, so finding a page with room skips full pages.
+#[derive(Debug)]
+struct DataPageSizes {
+    sizes: BTreeMap<u64, u64>,
+    // Bytes a data page can store, for the free space of pages.
+    capacity: u64,
+    // `size_bucket` of the free space -> data pages.
+    buckets: Vec<BTreeSet<u64>>,
+    // (free space, data page) of every data page, to visit only pages with
+    // room in the bucket needing a size check.
+    by_free: BTreeSet<(u64, u64)>,
+}
+
+/// Sizes from 2^(n-1) to 2^n-1 are in bucket n.
+fn size_bucket(size: u64) -> usize {
+    (u64::BITS - size.leading_zeros()) as usize
+}
+
+impl DataPageSizes {
+    fn new(sizes: BTreeMap<u64, u64>, capacity: u64) -> Self {
+        let mut result = Self {
+            sizes: Default::default(),
+            capacity,
+            buckets: Default::default(),
+            by_free: Default::default(),
+        };
+        for (index, size) in sizes {
+            result.insert(index, size);
+        }
+        result
+    }
+
+    fn free(&self, size: u64) -> u64 {
+        self.capacity.saturating_sub(size)
+    }
+
+    fn insert(&mut self, index: u64, size: u64) -> Option<u64> {
+        let old = self.remove(&index);
+        self.sizes.insert(index, size);
+        let free = self.free(size);
+        let bucket = size_bucket(free);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize_with(bucket + 1, Default::default);
+        }
+        self.buckets[bucket].insert(index);
+        self.by_free.insert((free, index));
+        old
+    }
+
+    fn remove(&mut self, index: &u64) -> Option<u64> {
+        let old = self.sizes.remove(index);
+        if let Some(old) = old {
+            let free = self.free(old);
+            self.buckets[size_bucket(free)].remove(index);
+            self.by_free.remove(&(free, *index));
+        }
+        old
+    }
+
+    /// The lowest page index with at most `max_size` bytes and accepted by
+    /// `accept`, like the first match of a scan in index order.
+    fn find_first(&self, max_size: u64, mut accept: impl FnMut(u64) -> bool) -> Option<u64> {
+        let min_free = self.free(max_size);
+        let first = size_bucket(min_free);
+        // Pages of the first bucket might not have room. Only visit those
+        // that have.
+        let end = 1u64.checked_shl(first as u32).unwrap_or(u64::MAX);
+        let mut found = self
+            .by_free
+            .range((min_free, 0)..(end, 0))
+            .map(|&(_, index)| index)
+            .filter(|index| self.sizes[index] <= max_size && accept(*index))
+            .min();
+        // Pages of later buckets all have room.
+        for pages in self.buckets.iter().skip(first + 1) {
+            let lower = pages
+                .iter()
+                .copied()
+                .take_while(|&index| found.is_none_or(|f| index < f))
+                .find(|&index| accept(index));
+            if lower.is_some() {
+                found = lower;
+            }
+        }
+        found
+    }
+
+    /// The lowest page index with exactly `size` bytes.
+    fn find_sized(&self, size: u64) -> Option<u64> {
+        let free = self.free(size);
+        self.by_free
+            .range((free, 0)..=(free, u64::MAX))
+            .map(|&(_, index)| index)
+            .find(|index| self.sizes[index] == size)
+    }
+}
+
+impl Deref for DataPageSizes {
+    type Target = BTreeMap<u64, u64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sizes
+    }
+}
+
 impl PageIntKv {
     /// Create a new `PageIntKv` with specified page size.
     pub fn new(page_size: u64, kv: Box<dyn IntKv>) -> io::Result<Self> {
-        let (meta_pages, map_index, data_page_sizes) = load_metadata(kv.as_ref())?;
+        Self::new_with_encoding(page_size, kv, Encoding::Fixint)
+    }
+
+    /// Create a new `PageIntKv` whose pages use the given encoding. It must
+    /// match the encoding the existing pages were written with.
+    pub fn new_with_encoding(
+        page_size: u64,
+        kv: Box<dyn IntKv>,
+        encoding: Encoding,
+    ) -> io::Result<Self> {
+        let min_page_size = Self::min_page_size(encoding);
+        if page_size < min_page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "page size {} is too small (minimum {})",
+                    page_size, min_page_size
+                ),
+            ));
+        }
+        let meta = load_metadata(kv.as_ref(), encoding)?;
         let result = Self {
             page_size,
+            encoding,
             kv,
-            meta_pages,
-            map_index,
-            data_page_sizes,
+            meta_pages: meta.meta_pages,
+            meta_ranges: meta.meta_ranges,
+            meta_page_contents: meta.meta_page_contents,
+            map_index: meta.map_index,
+            data_page_sizes: DataPageSizes::new(meta.data_page_sizes, page_size - GENERATION_SIZE),
+            next_page_id: meta.next_page_id.max(1),
+            free_pages: meta.free_pages,
+            extents: meta.extents,
             dirty_data_pages: Default::default(),
+            dirty_map_index: Default::default(),
+            dirty_extents: Default::default(),
+            dirty_extent_pages: Default::default(),
+            dirty_page_keys: Default::default(),
+            dirty_page_sizes: Default::default(),
+            ref_deltas: Default::default(),
+            refs_unknown: false,
+            page_generations: meta.page_generations,
+            generation: meta.generation,
+            chain_hints: Default::default(),
+            page_cache: Default::default(),
+            paranoid_checks: cfg!(debug_assertions),
+            fill_factor: 1.0,
+            sequential_allocation: false,
+            skip_identical_writes: false,
+            threads: crate::util::default_threads(),
+            hot_pages: Default::default(),
+            #[cfg(test)]
+            alloc_calls: 0,
         };
-        #[cfg(debug_assertions)]
-        result.verify()?;
+        if result.paranoid_checks {
+            result.verify()?;
+        }
         Ok(result)
     }
 
-    /// Check integrity: page sizes are correct, all pages are referred,
-    /// no page exceeds the limited size.
-    #[cfg(debug_assertions)]
+    /// Logical indexes of existing entries, including pending changes.
+    pub fn logical_keys(&self) -> impl Iterator<Item = u64> + '_ {
+        self.map_index.keys().cloned()
+    }
+
+    /// Number of pages without a generation. They were written by older
+    /// versions and get stamped when rewritten.
+    pub fn unstamped_pages(&self) -> usize {
+        let data = self
+            .data_page_sizes
+            .keys()
+            .filter(|i| !self.page_generations.contains_key(i))
+            .count();
+        let meta = self
+            .meta_pages
+            .iter()
+            .filter(|&&i| match i {
+                0 => self.generation == 0,
+                _ => !self.page_generations.contains_key(&i),
+            })
+            .count();
+        data + meta
+    }
+
+    /// Smallest page size accepted by `new_with_encoding`. A directory page
+    /// needs room for at least two ranges so the directory is shorter than
+    /// its leaves.
+    pub fn min_page_size(encoding: Encoding) -> u64 {
+        empty_meta_page_size(encoding)
+            + meta_next_generation_size(encoding)
+            + meta_range_size(encoding) * 2
+    }
+
+    /// Run integrity checks after changes. Enabled by default in debug
+    /// builds.
+    pub fn with_paranoid_checks(mut self, enabled: bool) -> Self {
+        self.paranoid_checks = enabled;
+        self
+    }
+
+    /// Leave headroom in pages so small growth of the last chunk of data
+    /// can be rewritten in place. For example, 0.9 fills pages up to 90%
+    /// when placing new data. Compaction still fills pages completely.
+    pub fn with_fill_factor(mut self, fill_factor: f64) -> Self {
+        self.fill_factor = fill_factor.clamp(0.5, 1.0);
+        self
+    }
+
+    /// Allocate new page indexes sequentially, for reproducible layouts and
+    /// fewer changed files to sync, instead of randomly (default) so indexes
+    /// do not reveal the write order. Only affects new allocations.
+    pub fn with_sequential_allocation(mut self, enabled: bool) -> Self {
+        self.sequential_allocation = enabled;
+        self
+    }
+
+    /// Compare writes with the existing data, and drop them if equal, so
+    /// no page is rewritten. Costs a read per write.
+    pub fn with_skip_identical_writes(mut self, enabled: bool) -> Self {
+        self.skip_identical_writes = enabled;
+        self
+    }
+
+    /// Set the number of threads to serialize data pages on flush.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Check integrity. Return an error describing all problems found.
     pub fn verify(&self) -> io::Result<()> {
-        fn error(message: impl ToString) -> io::Result<()> {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                message.to_string(),
-            ))
+        self.verify_full().into_result()
+    }
+
+    /// Check in-memory invariants without reading pages: mapped pages exist,
+    /// dirty pages are tracked, meta pages do not form a cycle, free pages
+    /// and extent pages are not used elsewhere.
+    pub fn verify_fast(&self) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        for (&logical_index, &data_index) in &self.map_index {
+            if !self.data_page_sizes.contains_key(&data_index) {
+                report.add(format!(
+                    "entry {} maps to unknown data page {}",
+                    logical_index, data_index
+                ));
+            }
+        }
+        for &index in self.dirty_data_pages.keys() {
+            if !self.data_page_sizes.contains_key(&index) {
+                report.add(format!("dirty data page {} is not tracked", index));
+            }
+        }
+        if !self.meta_pages.is_empty() && self.meta_pages[0] != 0 {
+            report.add(format!("first meta page is {}", self.meta_pages[0]));
+        }
+        if let Some((&start, _)) = self.meta_ranges.iter().next() {
+            if start != (0, 0) {
+                report.add(format!("first meta range starts at {:?}", start));
+            }
+        }
+        let mut meta_pages: BTreeSet<u64> = Default::default();
+        for &index in &self.meta_pages {
+            if !meta_pages.insert(index) {
+                report.add(format!("meta pages form a cycle ({})", index));
+            }
+            if self.data_page_sizes.contains_key(&index) {
+                report.add(format!("meta page {} is also a data page", index));
+            }
+        }
+        for &index in &self.free_pages {
+            if self.data_page_sizes.contains_key(&index) || meta_pages.contains(&index) {
+                report.add(format!("free page {} is in use", index));
+            }
+        }
+        let mut extent_pages: BTreeSet<u64> = Default::default();
+        for (&logical_index, extents) in &self.extents {
+            if !self.map_index.contains_key(&logical_index) {
+                report.add(format!("entry {} has extents but no chunks", logical_index));
+            }
+            for extent in extents {
+                let index = extent.page_index;
+                if !extent_pages.insert(index)
+                    || self.data_page_sizes.contains_key(&index)
+                    || meta_pages.contains(&index)
+                    || self.free_pages.contains(&index)
+                {
+                    report.add(format!("extent page {} is used elsewhere", index));
+                }
+                if extent.len > self.page_size {
+                    report.add(format!("extent page {} is too long", index));
+                }
+                if let Some((_, None)) = self.dirty_extent_pages.get(&index) {
+                    report.add(format!("extent page {} is being deleted", index));
+                }
+            }
         }
+        report
+    }
+
+    /// Check integrity by reading all pages: page sizes are correct, all
+    /// pages are referred, no page exceeds the limited size. Includes
+    /// `verify_fast` checks.
+    ///
+    /// Free pages might still exist after a crash. They are not checked.
+    pub fn verify_full(&self) -> VerifyReport {
+        self.verify_full_with_progress(|_, _| {})
+    }
+
+    /// `verify_full`, calling `progress` with the number of data pages read
+    /// and the total after each.
+    pub fn verify_full_with_progress(
+        &self,
+        mut progress: impl FnMut(usize, usize),
+    ) -> VerifyReport {
+        let mut report = self.verify_fast();
 
         // Check page sizes.
-        for (&index, &size) in &self.data_page_sizes {
-            let data = self.read_data_page(index as _)?;
-            let actual_size = bincode_size(&data);
+        let total = self.data_page_sizes.len();
+        for (done, (&index, &size)) in self.data_page_sizes.iter().enumerate() {
+            progress(done + 1, total);
+            let data = match self.read_data_page(index as _) {
+                Ok(data) => data,
+                Err(e) => {
+                    report.add(format!("cannot read data page {}: {}", index, e));
+                    continue;
+                }
+            };
+            let actual_size = data.size();
             if actual_size != size {
-                return error(format!(
+                report.add(format!(
                     "data page {} has mismatched size: actual {} vs recorded {}",
                     index, actual_size, size
                 ));
             }
         }
 
-        if !self.has(0)? {
-            return Ok(());
+        // Page 0 is the first meta page.
+        if !self.kv.has(0).unwrap_or(false) {
+            return report;
         }
 
-        // Check referred data pages.
-        let mut data_referred: BTreeSet<u64> = Default::default();
+        // Read meta pages: the directory, then leaves.
+        let mut metas: Vec<MetaPage> = Vec::new();
+        let mut meta_visited: BTreeSet<u64> = Default::default();
+        let mut ranges: BTreeMap<MetaKey, u64> = Default::default();
         let mut meta_index = 0;
-        loop {
-            let meta = self.read_meta_page(meta_index)?;
-            // Check logical -> data mapping.
-            for (&logical_index, &data_index) in &meta.map_index {
-                let page = self.read_data_page(data_index as _)?;
-                if !page.chunks.contains_key(&logical_index) {
-                    return error(format!(
-                        "data page {} does not contain expected entry {}",
-                        data_index, logical_index,
-                    ));
+        while meta_visited.insert(meta_index) {
+            let mut meta = match self.read_meta_page(meta_index as _) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    report.add(format!("cannot read meta page {}: {}", meta_index, e));
+                    break;
+                }
+            };
+            if let Err(e) = self.check_meta_generation(meta_index, &meta) {
+                report.add(e.to_string());
+            }
+            ranges.append(&mut meta.ranges);
+            meta_index = meta.next_page_index;
+            metas.push(meta);
+            if meta_index == 0 {
+                break;
+            }
+        }
+
+        // Check ranges of leaves are complete and disjoint.
+        if let Some((&start, _)) = ranges.iter().next() {
+            if start != (0, 0) {
+                report.add(format!("first meta range starts at {:?}", start));
+            }
+        }
+        let starts: Vec<MetaKey> = ranges.keys().cloned().collect();
+        for (i, (&start, &index)) in ranges.iter().enumerate() {
+            if !meta_visited.insert(index) {
+                report.add(format!("meta page {} is used more than once", index));
+                continue;
+            }
+            let meta = match self.read_meta_page(index as _) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    report.add(format!("cannot read meta page {}: {}", index, e));
+                    continue;
                 }
+            };
+            if let Err(e) = self.check_meta_generation(index, &meta) {
+                report.add(e.to_string());
+            }
+            let end = starts.get(i + 1);
+            if meta
+                .keys()
+                .any(|k| k < start || end.is_some_and(|end| k >= *end))
+            {
+                report.add(format!(
+                    "meta page {} has entries outside its range {:?}..{:?}",
+                    index, start, end
+                ));
             }
+            if meta.next_page_index != 0 || !meta.ranges.is_empty() {
+                report.add(format!("meta page {} links to other meta pages", index));
+            }
+            metas.push(meta);
+        }
+
+        // Check referred data pages.
+        let mut data_referred: BTreeSet<u64> = Default::default();
+        for meta in &metas {
             // Collect referred data pages from this meta page.
-            for &data_index in meta.map_index.values() {
+            // Check logical -> data mapping.
+            for (&logical_index, &data_index) in &meta.map_index {
                 let mut to_visit = vec![data_index];
                 while let Some(data_index) = to_visit.pop() {
-                    if data_referred.insert(data_index) {
-                        let data = self.read_data_page(data_index as _)?;
-                        let indexes = data
-                            .chunks
-                            .iter()
-                            .map(|(_, c)| c.next_page_index)
-                            .filter(|&i| i != 0);
-                        to_visit.extend(indexes);
+                    if !data_referred.insert(data_index) {
+                        continue;
                     }
+                    let data = match self.read_data_page(data_index as _) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            report.add(format!("cannot read data page {}: {}", data_index, e));
+                            continue;
+                        }
+                    };
+                    let indexes = data
+                        .chunks
+                        .values()
+                        .map(|c| c.next_page_index)
+                        .filter(|&i| i != 0);
+                    to_visit.extend(indexes);
+                }
+                match self.read_data_page(data_index as _) {
+                    Ok(page) if !page.chunks.contains_key(&logical_index) => {
+                        report.add(format!(
+                            "data page {} does not contain expected entry {}",
+                            data_index, logical_index,
+                        ));
+                    }
+                    _ => {}
                 }
-            }
-            meta_index = meta.next_page_index as _;
-            if meta_index == 0 {
-                break;
             }
         }
         let data_recorded = self.data_page_sizes.keys().cloned().collect();
         if data_referred != data_recorded {
-            return error(format!(
+            report.add(format!(
                 "data pages mismatch: actual{:?} recorded {:?}",
                 data_referred, &data_recorded,
             ));
         }
 
         // Check page sizes
-        for &i in self.meta_pages.iter().chain(data_referred.iter()) {
-            let data = self.kv.read(i as _)?;
-            let len = data.len();
-            if len != self.page_size as usize {
-                return error(format!(
-                    "page {} size mismatch: actual {:?} expected {:?}",
-                    i, len, self.page_size,
-                ));
+        let extent_pages = self
+            .extents
+            .values()
+            .flatten()
+            .map(|e| e.page_index)
+            .filter(|i| !self.dirty_extent_pages.contains_key(i));
+        for i in self
+            .meta_pages
+            .iter()
+            .chain(data_referred.iter())
+            .cloned()
+            .chain(extent_pages)
+        {
+            match self.kv.read(i as _) {
+                Ok(data) if data.len() != self.page_size as usize => {
+                    report.add(format!(
+                        "page {} size mismatch: actual {:?} expected {:?}",
+                        i,
+                        data.len(),
+                        self.page_size,
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => report.add(format!("cannot read page {}: {}", i, e)),
             }
         }
 
-        Ok(())
+        report
+    }
+
+    /// Calculate space usage from in-memory states without reading pages.
+    /// If `deep` is true, also walk chains to calculate chain lengths.
+    pub fn frag_stats(&self, deep: bool) -> io::Result<FragStats> {
+        let mut stats = FragStats {
+            data_pages: self.data_page_sizes.len(),
+            meta_pages: self.meta_pages.len(),
+            extent_pages: self.extents.values().map(|e| e.len()).sum(),
+            ..Default::default()
+        };
+        let total_pages = stats.data_pages + stats.meta_pages + stats.extent_pages;
+        stats.physical_bytes = total_pages as u64 * self.page_size;
+        for &size in self.data_page_sizes.values() {
+            stats.used_bytes += size;
+            let bucket = (size * 10 / self.page_size.max(1)).min(9) as usize;
+            stats.fill_histogram[bucket] += 1;
+        }
+        stats.used_bytes += self.extents.values().flatten().map(|e| e.len).sum::<u64>();
+
+        if deep {
+            let mut longest_chain = 0;
+            let mut multi_page_entries = 0;
+            for (&logical_index, &first_index) in &self.map_index {
+                let mut chain_len = 0;
+                let mut index = first_index;
+                while index != 0 {
+                    chain_len += 1;
+                    let page = self.read_data_page(index as _)?;
+                    index = match page.chunks.get(&logical_index) {
+                        None => return Err(not_found()),
+                        Some(chunk) => chunk.next_page_index,
+                    };
+                }
+                longest_chain = longest_chain.max(chain_len);
+                let extent_len = self.extents.get(&logical_index).map_or(0, |e| e.len());
+                if chain_len + extent_len > 1 {
+                    multi_page_entries += 1;
+                }
+            }
+            stats.longest_chain = Some(longest_chain);
+            stats.multi_page_entries = Some(multi_page_entries);
+        }
+
+        Ok(stats)
+    }
+
+    /// Find data pages that cannot be decoded.
+    pub fn corrupted_pages(&self) -> Vec<u64> {
+        self.data_page_sizes
+            .keys()
+            .cloned()
+            .filter(|&index| {
+                matches!(self.read_data_page(index as _),
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData)
+            })
+            .collect()
+    }
+
+    /// Find logical indexes whose chains go through the given data page.
+    ///
+    /// If `drop` is true, also remove those entries and the page. Chunks of
+    /// the entries in other pages are removed too, so the remaining pages
+    /// become consistent after `flush`.
+    pub fn salvage(&mut self, page_index: u64, drop: bool) -> io::Result<Vec<u64>> {
+        if !self.data_page_sizes.contains_key(&page_index) {
+            return Err(not_found());
+        }
+        let mut affected = Vec::new();
+        for (&key, &first_index) in &self.map_index {
+            let mut index = first_index;
+            while index != 0 {
+                if index == page_index {
+                    affected.push(key);
+                    break;
+                }
+                index = match self.read_data_page(index as _) {
+                    Ok(page) => page.chunks.get(&key).map_or(0, |c| c.next_page_index),
+                    // Another broken page. It needs to be salvaged separately.
+                    Err(_) => 0,
+                };
+            }
+        }
+        if !drop {
+            return Ok(affected);
+        }
+
+        // Links from the broken page are unknown.
+        self.refs_unknown = true;
+        for &key in &affected {
+            let flushed_index = self.map_index.remove(&key);
+            self.dirty_map_index.entry(key).or_insert(flushed_index);
+            self.replace_extents(key, &mut None);
+            self.chain_hints.write().remove(&key);
+        }
+        let affected_set: BTreeSet<u64> = affected.iter().cloned().collect();
+        let indexes: Vec<u64> = self.data_page_sizes.keys().cloned().collect();
+        for index in indexes {
+            if index == page_index {
+                continue;
+            }
+            let mut page = match self.read_data_page_mut(index as _) {
+                Ok(page) => page,
+                Err(_) => continue,
+            };
+            let keys: Vec<u64> = page
+                .chunks
+                .keys()
+                .filter(|k| affected_set.contains(k))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            for &key in &keys {
+                page.remove_chunk(key);
+            }
+            self.dirty_page_keys.entry(index).or_default().extend(keys);
+            self.write_data_page(page);
+        }
+
+        // An empty page is deleted on flush.
+        self.dirty_page_keys
+            .entry(page_index)
+            .or_default()
+            .extend(affected.iter().cloned());
+        self.write_data_page(DataPage {
+            page_index,
+            ..DataPage::empty(self.encoding)
+        });
+        Ok(affected)
     }
 
-    #[cfg(debug_assertions)]
     fn read_meta_page(&self, index: usize) -> io::Result<MetaPage> {
         let data = self.kv.read(index)?;
-        bincode_deserialize(&data)
+        parse_meta_page(&data, self.encoding)
     }
 
-    fn read_data_page(&self, index: usize) -> io::Result<DataPage> {
-        match self.dirty_data_pages.get(&(index as _)) {
-            Some(page) => Ok(page.clone()),
-            None => {
-                let data = self.kv.read(index)?;
-                let mut page: DataPage = bincode_deserialize(&data)?;
-                page.page_index = index as _;
-                Ok(page)
-            }
+    /// Check the generation of a meta page read from `index`.
+    fn check_meta_generation(&self, index: u64, page: &MetaPage) -> io::Result<()> {
+        let expected = match index {
+            0 => Some(&self.generation),
+            _ => self.page_generations.get(&index),
+        };
+        check_generation("meta", index, page.generation, expected, self.generation)
+    }
+
+    fn read_data_page(&self, index: usize) -> io::Result<Arc<DataPage>> {
+        if let Some(page) = self.dirty_data_pages.get(&(index as _)) {
+            return Ok(page.clone());
+        }
+        let corrupted = |e: io::Error| match e.kind() {
+            io::ErrorKind::InvalidData => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data page {} is corrupted: {}", index, e),
+            ),
+            _ => e,
+        };
+        let data = self.kv.read(index).map_err(corrupted)?;
+        let page = DataPage::parse(&data, index as _, self.encoding).map_err(corrupted)?;
+        let expected = self.page_generations.get(&(index as _));
+        let actual = data_page_generation(&data);
+        check_generation("data", index as _, actual, expected, self.generation)?;
+        Ok(Arc::new(page))
+    }
+
+    /// Read a data page for mutation.
+    fn read_data_page_mut(&self, index: usize) -> io::Result<DataPage> {
+        self.read_data_page(index).map(Arc::unwrap_or_clone)
+    }
+
+    /// Read a data page. Keep clean pages decoded in `page_cache`.
+    fn read_data_page_cached(&self, index: u64) -> io::Result<Arc<DataPage>> {
+        if let Some(page) = self.dirty_data_pages.get(&index) {
+            return Ok(page.clone());
+        }
+        if let Some(page) = self.page_cache.read().get(&index) {
+            return Ok(page.clone());
+        }
+        let page = self.read_data_page(index as _)?;
+        let mut cache = self.page_cache.write();
+        if cache.len() >= PAGE_CACHE_LIMIT {
+            cache.clear();
         }
+        cache.insert(index, page.clone());
+        Ok(page)
     }
 
     fn create_data_page(&mut self) -> io::Result<DataPage> {
-        let index = self.find_free_page_index()?;
-        let mut page = DataPage::default();
-        page.page_index = index;
+        let index = self.find_free_page_index();
+        Ok(self.create_data_page_at(index))
+    }
+
+    /// Create a data page using an allocated page index.
+    fn create_data_page_at(&mut self, index: u64) -> DataPage {
+        let page = DataPage {
+            page_index: index,
+            ..DataPage::empty(self.encoding)
+        };
         self.write_data_page(page.clone());
-        Ok(page)
+        page
     }
 
     /// Update chunk in a data page.
@@ -221,22 +1219,28 @@ impl PageIntKv {
     /// the given logical index. With the maximum size limit.
     /// If data is None, remove the data entry.
     ///
-    /// Return the next page and the remaining data for writing.
+    /// Return the next page and the remaining data for writing. New pages
+    /// are taken from `reserved`, which is refilled for the remaining data
+    /// at once.
     fn update_chunk(
         &mut self,
         mut page: DataPage,
         logical_index: u64,
         data: Option<Bytes>,
+        reserved: &mut Vec<u64>,
     ) -> io::Result<Option<(DataPage, Option<Bytes>)>> {
-        log::debug!(
-            "UpdateChunk {} (len {:?}) to DataPage {}",
-            logical_index,
-            data.as_ref().map(|d| d.len()),
-            page.page_index,
+        crate::log_kv!(
+            log::Level::Debug, "UpdateChunk";
+            index = logical_index,
+            len = data.as_ref().map(|d| d.len()),
+            page = page.page_index,
         );
 
         // Remove old data.
-        let orig_chunk = page.chunks.remove(&logical_index);
+        let orig_chunk = page.remove_chunk(logical_index);
+        if let Some(chunk) = &orig_chunk {
+            self.add_ref(chunk.next_page_index, -1);
+        }
 
         // Find the next page by following the existing data.
         let mut next_page = {
@@ -245,31 +1249,44 @@ impl PageIntKv {
             if index == 0 {
                 None
             } else {
-                Some(self.read_data_page(index as _)?)
+                Some(self.read_data_page_mut(index as _)?)
             }
         };
         let mut next_data = None;
 
         // Rewrite chunk and find the next page.
         if let Some(data) = data {
-            let max_page_size = self.page_size;
-            let overhead = 8 * 3;
-            let current_page_size = bincode_size(&page) + overhead;
+            let current_page_size = page.size_with_chunk(logical_index, 0);
+            let max_page_size = match current_page_size > self.page_capacity() {
+                // Pages written by older versions might be too full for a
+                // generation. They stay unstamped.
+                true => self.page_size,
+                false => self.page_capacity(),
+            };
             if current_page_size > max_page_size {
                 // Cannot satisfy the max_page_size limit.
                 return Err(io::ErrorKind::WriteZero.into());
             }
-            let size = ((max_page_size - current_page_size) as usize).min(data.len());
+            let size = (page.max_chunk_len(logical_index, max_page_size) as usize).min(data.len());
             let part = data.slice(0..size);
             if part.len() < data.len() {
                 // Both next_data and next_page are needed.
-                next_data = Some(data.slice(part.len()..));
+                let rest = data.slice(part.len()..);
                 // Allocate next_page on demand.
                 if next_page.is_none() {
-                    let new_page = self.create_data_page()?;
+                    if reserved.is_empty() {
+                        let n = rest.len().div_ceil(self.usable_page_size());
+                        *reserved = self.find_free_index_in_batch(n, &BTreeSet::new());
+                        reserved.reverse();
+                    }
+                    let new_page = match reserved.pop() {
+                        Some(index) => self.create_data_page_at(index),
+                        None => self.create_data_page()?,
+                    };
                     debug_assert_ne!(new_page.page_index, page.page_index);
                     next_page = Some(new_page);
                 }
+                next_data = Some(rest);
             }
             let chunk = Chunk {
                 data: part,
@@ -281,12 +1298,18 @@ impl PageIntKv {
                     None => 0,
                 },
             };
-            page.chunks.insert(logical_index, chunk);
+            self.add_ref(chunk.next_page_index, 1);
+            page.insert_chunk(logical_index, chunk);
             if next_data.is_some() {
-                // Should fill up the current page if there are remaining data.
-                debug_assert_eq!(bincode_size(&page), max_page_size);
+                // Should fill up the current page if there are remaining data,
+                // except bytes a longer length would take.
+                debug_assert!(max_page_size - page.size() <= count_growth(self.encoding));
             }
         }
+        self.dirty_page_keys
+            .entry(page.page_index)
+            .or_default()
+            .insert(logical_index);
         self.write_data_page(page);
 
         if next_data.is_some() {
@@ -297,356 +1320,2630 @@ impl PageIntKv {
         Ok(next_page.map(|p| (p, next_data)))
     }
 
+    /// Whether `data` can be dropped by `with_skip_identical_writes`.
+    fn is_identical(&self, index: usize, data: &Bytes) -> io::Result<bool> {
+        if !self.skip_identical_writes || !self.map_index.contains_key(&(index as _)) {
+            return Ok(false);
+        }
+        Ok(self.read(index)? == *data)
+    }
+
     /// Update logical data. Rewrite the linked data pages.
     /// If data is None, remove the data from all linked lists.
-    fn update_logical_data(&mut self, index: usize, mut data: Option<Bytes>) -> io::Result<()> {
-        let mut data_page = match self.map_index.get(&(index as _)) {
+    /// Data is moved to another page if its first page does not match `hint`.
+    fn update_logical_data(
+        &mut self,
+        index: usize,
+        mut data: Option<Bytes>,
+        hint: Option<Hint>,
+    ) -> io::Result<()> {
+        let flushed_index = self.map_index.get(&(index as _)).cloned();
+        if flushed_index.is_none() && data.is_none() {
+            // Cannot remove if the data does not exist.
+            return Err(not_found());
+        }
+        self.dirty_map_index
+            .entry(index as _)
+            .or_insert(flushed_index);
+        self.replace_extents(index as _, &mut data);
+        let mut data_page = match (self.map_index.get(&(index as _)).cloned(), &data) {
+            // Cannot remove if the data does not exist.
+            (None, None) => return Err(not_found()),
             // Find a suitable page from existing pages.
-            None => match &data {
-                // Cannot remove if the data does not exist.
-                None => return Err(not_found()),
-                Some(data) => {
-                    let page = self.find_first_page_for_size(data.len() as _)?;
-                    self.map_index.insert(index as _, page.page_index);
-                    page
-                }
-            },
+            (None, Some(data)) => {
+                let page = self.find_first_page_for_size(data.len() as _, hint)?;
+                self.set_map_index(index as _, Some(page.page_index));
+                page
+            }
+            // Move to a page matching the hint.
+            (Some(id), Some(data)) if !self.is_page_for_hint(id, hint) => {
+                let len = data.len();
+                let old_page = self.read_data_page_mut(id as _)?;
+                self.write_chain(old_page, index as _, None)?;
+                let page = self.find_first_page_for_size(len as _, hint)?;
+                self.set_map_index(index as _, Some(page.page_index));
+                page
+            }
             // Using the existing data page via mapping.
-            Some(&id) => self.read_data_page(id as _)?,
+            (Some(id), _) => self.read_data_page_mut(id as _)?,
         };
         if data.is_none() {
-            self.map_index.remove(&(index as _));
+            self.set_map_index(index as _, None);
         }
-        loop {
-            match self.update_chunk(data_page, index as _, data)? {
-                Some((next_page, next_data)) => {
-                    data_page = next_page;
-                    data = next_data;
-                }
+        let tail = data.clone();
+        let mut chain = vec![data_page.page_index];
+        // Skip unchanged leading chunks. They are not rewritten.
+        while let Some(rest) = &data {
+            let chunk = match data_page.chunks.get(&(index as _)) {
+                Some(chunk) => chunk,
                 None => break,
+            };
+            let len = chunk.data.len();
+            if chunk.next_page_index == 0 || rest.len() <= len || rest[..len] != chunk.data[..] {
+                break;
+            }
+            let next_index = chunk.next_page_index;
+            data = Some(rest.slice(len..));
+            data_page = self.read_data_page_mut(next_index as _)?;
+            chain.push(next_index);
+        }
+        chain.extend(self.write_chain(data_page, index as _, data)?);
+        if let Some(tail) = tail {
+            if chain.len() > self.max_chain_len(tail.len()) {
+                // Rewrite the chain into new pages.
+                crate::log_kv!(log::Level::Debug, "Rewriting chain"; index = index, pages = chain.len());
+                let first_page = self.read_data_page_mut(chain[0] as _)?;
+                self.write_chain(first_page, index as _, None)?;
+                let first_page = self.create_data_page()?;
+                self.set_map_index(index as _, Some(first_page.page_index));
+                chain = vec![first_page.page_index];
+                chain.extend(self.write_chain(first_page, index as _, Some(tail))?);
             }
         }
+        if self.map_index.contains_key(&(index as _)) {
+            self.set_chain_hint(index as _, chain);
+        } else {
+            self.chain_hints.write().remove(&(index as _));
+        }
         Ok(())
     }
 
-    /// Find a page index that can store the given sized data as the first
-    /// page.
-    fn find_first_page_for_size(&mut self, size: u64) -> io::Result<DataPage> {
-        let overhead = 8 * 3;
-        let needed_size = size + overhead;
-        if needed_size > self.page_size {
-            // Pick a page with maximum free space.
-            match self
-                .data_page_sizes
-                .iter()
-                .min_by_key(|(_, page_size)| *page_size)
-            {
-                Some((&page_index, &page_size)) => {
-                    if page_size + overhead < self.page_size {
-                        return Ok(self.read_data_page(page_index as _)?);
-                    }
-                }
-                None => {}
+    /// Write data starting from the given page, following and extending the
+    /// existing chain. Return pages after the first page that store data.
+    fn write_chain(
+        &mut self,
+        mut page: DataPage,
+        logical_index: u64,
+        mut data: Option<Bytes>,
+    ) -> io::Result<Vec<u64>> {
+        let mut chain = Vec::new();
+        let mut reserved = Vec::new();
+        while let Some((next_page, next_data)) =
+            self.update_chunk(page, logical_index, data, &mut reserved)?
+        {
+            page = next_page;
+            data = next_data;
+            if data.is_some() {
+                chain.push(page.page_index);
             }
         }
-        // PERF: This can probably be improved.
-        for (&page_index, &page_size) in &self.data_page_sizes {
-            if page_size + needed_size <= self.page_size {
-                return Ok(self.read_data_page(page_index as _)?);
-            }
+        // Return unused reservations.
+        for index in reserved {
+            self.free_page_index(index);
         }
-        // Allocate a new page.
-        Ok(self.create_data_page()?)
-    }
-
-    /// Find an unused page index.
-    fn find_free_page_index(&self) -> io::Result<u64> {
-        Ok(self
-            .find_free_index_in_batch(1)?
-            .iter()
-            .next()
-            .cloned()
-            .unwrap())
+        Ok(chain)
     }
 
-    /// Find free pages.
-    fn find_free_index_in_batch(&self, n: usize) -> io::Result<BTreeSet<u64>> {
-        // PERF: This can be improved.
-        let mut result: BTreeSet<u64> = Default::default();
-        while result.len() < n {
-            let i: u32 = rand::random();
-            if !self.has(i as _)? {
-                result.insert(i as _);
-            }
-        }
-        Ok(result)
+    /// Maximum chain length of data not in extents before it gets rewritten.
+    fn max_chain_len(&self, len: usize) -> usize {
+        let ideal = len.div_ceil(self.usable_page_size()).max(1);
+        ideal * MAX_CHAIN_RATIO
     }
 
-    /// Mark a page for writing on flush.
-    fn write_data_page(&mut self, page: DataPage) {
-        let index = page.page_index;
-        // Keep empty pages in data_page_sizes cache. They can be mutable.
-        // They will be deleted on flush.
-        let page_size = bincode_size(&page);
-        self.data_page_sizes.insert(index, page_size);
-        self.dirty_data_pages.insert(index, page);
+    /// Bytes of a data page available for chunks, excluding the generation.
+    fn page_capacity(&self) -> u64 {
+        self.page_size - GENERATION_SIZE
     }
 
-    /// Write a meta page to the underlying IntKv.
-    fn write_meta_page(&mut self, page: &MetaPage) -> io::Result<()> {
-        let index = page.page_index;
-        let bytes = bincode_serialize_pad(page, self.page_size);
-        self.kv.write(index as _, bytes.into())?;
-        Ok(())
+    /// Bytes of data an empty data page can store.
+    fn usable_page_size(&self) -> usize {
+        let encoding = self.encoding;
+        let overhead = empty_data_page_size(encoding) + chunk_overhead(encoding);
+        (self.page_capacity() - overhead).max(1) as usize
     }
-}
 
-impl IntKv for PageIntKv {
-    fn read(&self, index: usize) -> io::Result<Bytes> {
-        let mut mapped_index = match self.map_index.get(&(index as _)) {
-            None => return Err(not_found()),
-            Some(&mapped_index) => mapped_index,
-        };
-        let mut result = Vec::new();
-        while mapped_index != 0 {
-            let page: DataPage = self.read_data_page(mapped_index as _)?;
-            match page.chunks.get(&(index as _)) {
-                Some(chunk) => {
-                    mapped_index = chunk.next_page_index as _;
-                    if mapped_index == 0 && result.is_empty() {
-                        // Fast path: single chunk data.
-                        return Ok(chunk.data.clone());
-                    } else {
-                        // Multiple chunks. Concat them.
-                        result.extend_from_slice(&chunk.data)
+    /// Move the beginning of large new data to extents, leaving the rest in
+    /// `data`. Reuse unchanged extents. Release other extents of the old data.
+    fn replace_extents(&mut self, index: u64, data: &mut Option<Bytes>) {
+        let old_extents = self.extents.remove(&index);
+        let mut new_extents = Vec::new();
+        if let Some(data) = data {
+            let page_size = self.page_size as usize;
+            if data.len() >= page_size * EXTENT_MIN_PAGES {
+                let n = (data.len() / page_size).min(self.max_extents());
+                let parts: Vec<Bytes> = (0..n)
+                    .map(|i| data.slice(i * page_size..(i + 1) * page_size))
+                    .collect();
+                // Reuse unchanged extents.
+                let reused: Vec<Option<Extent>> = parts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, part)| {
+                        let old = *old_extents.as_ref()?.get(i)?;
+                        match self.read_extent(&old) {
+                            Ok(old_part) if old_part == part => Some(old),
+                            _ => None,
+                        }
+                    })
+                    .collect();
+                let changed = reused.iter().filter(|e| e.is_none()).count();
+                let old_pages = old_extents.iter().flatten().map(|e| e.page_index).collect();
+                let mut allocated = self
+                    .find_free_index_in_batch(changed, &old_pages)
+                    .into_iter();
+                for (part, reused) in parts.into_iter().zip(reused) {
+                    if let Some(extent) = reused {
+                        new_extents.push(extent);
+                        continue;
                     }
+                    let page_index = allocated.next().unwrap();
+                    self.dirty_extent_pages
+                        .insert(page_index, (index, Some(part)));
+                    new_extents.push(Extent {
+                        page_index,
+                        len: page_size as _,
+                    });
                 }
-                None => return Err(not_found()),
+                *data = data.slice(n * page_size..);
             }
         }
-        Ok(result.into())
+        for extent in old_extents.iter().flatten() {
+            if !new_extents.contains(extent) {
+                self.dirty_extent_pages
+                    .insert(extent.page_index, (index, None));
+            }
+        }
+        if old_extents.is_some() || !new_extents.is_empty() {
+            self.dirty_extents.entry(index).or_insert(old_extents);
+        }
+        if !new_extents.is_empty() {
+            self.extents.insert(index, new_extents);
+        }
     }
 
-    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
-        self.update_logical_data(index, Some(data))
+    /// Maximum number of extents of a logical entry. Limits the meta page
+    /// space used by an entry to a quarter page.
+    fn max_extents(&self) -> usize {
+        // Largest bincode size for an extent, or the (key, len) header.
+        let size = self.encoding.max_int_size() * 2;
+        ((self.page_size / 4).saturating_sub(size) / size) as usize
     }
 
-    fn remove(&mut self, index: usize) -> io::Result<()> {
-        self.update_logical_data(index, None)
+    /// Read the data of an extent.
+    fn read_extent(&self, extent: &Extent) -> io::Result<Bytes> {
+        check_extent(extent, self.read_extent_data(extent)?)
     }
 
-    fn has(&self, index: usize) -> io::Result<bool> {
-        Ok(self.map_index.contains_key(&(index as _)))
+    /// Read the page of an extent, including padding.
+    fn read_extent_data(&self, extent: &Extent) -> io::Result<Bytes> {
+        match self.dirty_extent_pages.get(&extent.page_index) {
+            Some((_, Some(data))) => Ok(data.clone()),
+            Some((_, None)) => Err(not_found()),
+            None => self.kv.read(extent.page_index as _),
+        }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        // Nothing changed?
-        if self.dirty_data_pages.is_empty() {
-            return Ok(());
+    /// Write out dirty extent pages of the given logical indexes. Schedule
+    /// released ones for deletion. If `keys` is None, write out all of them.
+    fn write_extent_pages(
+        &mut self,
+        keys: Option<&BTreeSet<u64>>,
+        pending: &mut Pending,
+    ) -> io::Result<()> {
+        let indexes: Vec<u64> = self
+            .dirty_extent_pages
+            .iter()
+            .filter(|(_, (key, _))| keys.is_none_or(|keys| keys.contains(key)))
+            .map(|(&i, _)| i)
+            .collect();
+        let mut writes = Vec::new();
+        for &index in &indexes {
+            if let Some(data) = &self.dirty_extent_pages[&index].1 {
+                let mut bytes = data.to_vec();
+                bytes.resize(self.page_size as usize, 0);
+                writes.push((index as usize, bytes.into()));
+            }
         }
-
-        // Write out data pages.
-        for (&index, page) in &self.dirty_data_pages {
-            log::debug!(
-                "Flushing DataPage {} with chunks {:?}",
-                index,
-                page.chunks.keys().collect::<Vec<_>>()
-            );
-            if page.chunks.is_empty() {
-                // Delete empty pages.
-                debug_assert!(!self.map_index.values().any(|&p| p == index));
-                if self.kv.has(index as _)? {
-                    self.kv.remove(index as _)?;
+        let (written, result) = self.kv.write_batch(writes);
+        let mut written_count = 0;
+        for index in indexes {
+            let is_write = self.dirty_extent_pages[&index].1.is_some();
+            if is_write {
+                if written_count == written {
+                    break;
                 }
-                self.data_page_sizes.remove(&index);
+                written_count += 1;
+                pending.written.push(index);
             } else {
-                let bytes = bincode_serialize_pad(page, self.page_size);
-                self.kv.write(index as _, bytes.into())?;
+                pending.deleted.push(index);
             }
+            self.dirty_extent_pages.remove(&index);
         }
-        self.dirty_data_pages.clear();
+        result
+    }
 
-        // Prepare meta pages.
-        let mut to_insert = self.map_index.len() + self.data_page_sizes.len();
-        let mut new_meta_pages: Vec<MetaPage> = vec![MetaPage::default()];
-        let mut map_iter = self.map_index.iter();
-        let mut data_size_iter = self.data_page_sizes.iter();
-        while to_insert > 0 {
-            let page = new_meta_pages.last_mut().unwrap();
-            let size = bincode_size(page);
-
-            // 16: bincode size for (key, value) pair.
-            let n = ((self.page_size - size) as usize) / 16;
-            for _ in 0..n {
-                if let Some((&k, &v)) = map_iter.next() {
-                    page.map_index.insert(k, v);
-                    to_insert -= 1;
-                }
+    /// Remember physical pages used by a logical entry.
+    fn set_chain_hint(&self, index: u64, chain: Vec<u64>) {
+        let mut hints = self.chain_hints.write();
+        if chain.len() <= 1 {
+            hints.remove(&index);
+            return;
+        }
+        if hints.len() >= CHAIN_HINTS_LIMIT {
+            hints.clear();
+        }
+        hints.insert(index, chain);
+    }
+
+    /// Prefetch pages `chain[start..end]` that are not dirty.
+    fn prefetch_chain(&self, chain: &[u64], start: usize, end: usize) {
+        let end = end.min(chain.len());
+        if start >= end {
+            return;
+        }
+        let indexes: Vec<usize> = chain[start..end]
+            .iter()
+            .filter(|i| !self.dirty_data_pages.contains_key(i))
+            .map(|&i| i as usize)
+            .collect();
+        if !indexes.is_empty() {
+            self.kv.prefetch(&indexes);
+        }
+    }
+
+    /// Find a page index that can store the given sized data as the first
+    /// page. With a hint, only use pages for the same kind of entries.
+    fn find_first_page_for_size(&mut self, size: u64, hint: Option<Hint>) -> io::Result<DataPage> {
+        let needed_size = size + chunk_overhead(self.encoding);
+        let page = if needed_size > self.page_capacity() {
+            // Start in an empty page so the chain stays short.
+            let empty_page = self
+                .data_page_sizes
+                .find_sized(empty_data_page_size(self.encoding));
+            match empty_page {
+                Some(page_index) => self.read_data_page_mut(page_index as _)?,
+                None => self.create_data_page()?,
             }
-            let orig_size = size;
-            let size = bincode_size(page);
-            assert!(
-                size <= self.page_size,
-                "{} <= {}, n={}, orig={}",
-                size,
-                self.page_size,
-                n,
-                orig_size
-            );
+        } else {
+            // The data fits in a page. Leave headroom for its growth.
+            let fill_limit = ((self.page_capacity() as f64) * self.fill_factor) as u64;
+            let found = fill_limit.checked_sub(needed_size).and_then(|max_size| {
+                self.data_page_sizes.find_first(max_size, |page_index| {
+                    self.is_page_for_hint(page_index, hint)
+                })
+            });
+            match found {
+                Some(page_index) => self.read_data_page_mut(page_index as _)?,
+                // Allocate a new page.
+                None => self.create_data_page()?,
+            }
+        };
+        // An empty page can be used for either kind.
+        match hint {
+            Some(Hint::Hot) => self.hot_pages.insert(page.page_index),
+            Some(Hint::Cold) => self.hot_pages.remove(&page.page_index),
+            None => false,
+        };
+        Ok(page)
+    }
 
-            let m = ((self.page_size - size) as usize) / 16;
-            for _ in 0..m {
-                if let Some((&k, &v)) = data_size_iter.next() {
-                    page.data_size_indexes.insert(k, v);
-                    to_insert -= 1;
+    /// Test if a data page can be the first page of an entry with the hint.
+    fn is_page_for_hint(&self, page_index: u64, hint: Option<Hint>) -> bool {
+        match hint {
+            None => true,
+            Some(Hint::Hot) => self.hot_pages.contains(&page_index),
+            Some(Hint::Cold) => !self.hot_pages.contains(&page_index),
+        }
+    }
+
+    /// Allocate an unused page index. Prefer reusing deleted pages.
+    fn find_free_page_index(&mut self) -> u64 {
+        self.find_free_index_in_batch(1, &BTreeSet::new())[0]
+    }
+
+    fn next_free_page_index(&mut self, taken: &BTreeSet<u64>) -> u64 {
+        if let Some(index) = self.free_pages.pop_first() {
+            return index;
+        }
+        if !self.sequential_allocation {
+            loop {
+                let index = rand::random::<u32>() as u64;
+                if index != 0 && !taken.contains(&index) && !self.is_page_used(index) {
+                    return index;
                 }
             }
-            let orig_size = size;
-            let size = bincode_size(page);
-            assert!(
-                size <= self.page_size,
-                "{} <= {}, m={}, orig={}",
-                size,
-                self.page_size,
-                m,
-                orig_size
-            );
-
-            if n + m == 0 && to_insert > 0 {
-                // Need a new page.
-                drop(page);
-                new_meta_pages.push(MetaPage::default());
+        }
+        loop {
+            let index = self.next_page_id;
+            self.next_page_id += 1;
+            // Pages might be allocated randomly. Skip used pages.
+            if !self.is_page_used(index) {
+                return index;
             }
         }
+    }
 
-        // Fix meta page indexes.
-        let mut next_free_index = {
-            let free_indexes = self.find_free_index_in_batch(new_meta_pages.len())?;
-            let mut iter = free_indexes.into_iter();
-            move || iter.next().unwrap()
-        };
-        for i in 1..new_meta_pages.len() {
-            new_meta_pages[i].page_index = match self.meta_pages.get(i) {
-                None => {
-                    let id = next_free_index();
-                    if self.has(id as _)? {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!(
-                                "page {} should not be taken (bug in find_free_index_in_batch)",
-                                id
-                            ),
-                        ));
-                    }
-                    id
-                }
-                Some(&id) => id,
-            };
+    /// Test if a page index is used by data, meta, or extent pages.
+    fn is_page_used(&self, index: u64) -> bool {
+        self.data_page_sizes.contains_key(&index)
+            || self.meta_pages.contains(&index)
+            || self.dirty_extent_pages.contains_key(&index)
+            || self
+                .extents
+                .values()
+                .flatten()
+                .any(|e| e.page_index == index)
+    }
+
+    /// Mark a deleted page as reusable.
+    fn free_page_index(&mut self, index: u64) {
+        self.free_pages.insert(index);
+        // Shrink the free list if the highest pages are free.
+        while let Some(&last) = self.free_pages.iter().next_back() {
+            if last + 1 != self.next_page_id {
+                break;
+            }
+            self.free_pages.remove(&last);
+            self.next_page_id = last;
         }
+    }
 
-        // Fix linked list.
-        for i in 0..(new_meta_pages.len() - 1) {
-            new_meta_pages[i].next_page_index = new_meta_pages[i + 1].page_index;
+    /// Allocate `n` unused page indexes, excluding `exclude`. The exclusion
+    /// only matters for random allocation, which does not know about pages
+    /// being deleted.
+    fn find_free_index_in_batch(&mut self, n: usize, exclude: &BTreeSet<u64>) -> Vec<u64> {
+        #[cfg(test)]
+        {
+            self.alloc_calls += 1;
+        }
+        let mut taken = exclude.clone();
+        let mut result = Vec::with_capacity(n);
+        for _ in 0..n {
+            let index = self.next_free_page_index(&taken);
+            taken.insert(index);
+            result.push(index);
         }
+        result
+    }
 
-        // Write out new meta pages.
-        for page in &new_meta_pages {
-            self.write_meta_page(page)?;
+    /// Mark a page for writing on flush.
+    fn write_data_page(&mut self, page: DataPage) {
+        let index = page.page_index;
+        // Keep empty pages in data_page_sizes cache. They can be mutable.
+        // They will be deleted on flush.
+        let page_size = page.size();
+        let flushed_size = self.data_page_sizes.insert(index, page_size);
+        if !self.dirty_data_pages.contains_key(&index) {
+            self.dirty_page_sizes.entry(index).or_insert(flushed_size);
         }
+        self.dirty_data_pages.insert(index, Arc::new(page));
+        self.page_cache.write().remove(&index);
+    }
 
-        // Remove unused pages.
-        if let Some(indexes) = self.meta_pages.get(new_meta_pages.len()..) {
-            for &i in indexes {
-                self.kv.remove(i as _)?;
+    /// Move chunks out of the most under-filled data pages so they can be
+    /// deleted on flush. Visit at most `max_pages_to_move` pages.
+    pub fn compact(&mut self, max_pages_to_move: usize) -> io::Result<CompactReport> {
+        let mut report = CompactReport::default();
+        let empty_size = empty_data_page_size(self.encoding);
+        let mut candidates: Vec<(u64, u64)> = self
+            .data_page_sizes
+            .iter()
+            .filter(|(_, &size)| size > empty_size && size <= self.page_size / 2)
+            .map(|(&index, &size)| (size, index))
+            .collect();
+        candidates.sort_unstable();
+        candidates.truncate(max_pages_to_move);
+        let sources: BTreeSet<u64> = candidates.iter().map(|&(_, i)| i).collect();
+        for (_, page_index) in candidates {
+            if self.vacate_page(page_index, &sources, &mut report)? {
+                report.pages_reclaimed += 1;
+                report.bytes_reclaimed += self.page_size;
             }
         }
+        log::debug!("Compacted: {:?}", &report);
+        Ok(report)
+    }
 
-        self.kv.flush()?;
+    /// Move chunks in a data page to other pages, excluding `exclude`.
+    /// Return true if the page becomes empty.
+    fn vacate_page(
+        &mut self,
+        page_index: u64,
+        exclude: &BTreeSet<u64>,
+        report: &mut CompactReport,
+    ) -> io::Result<bool> {
+        let mut page = self.read_data_page_mut(page_index as _)?;
+        let keys: Vec<u64> = page.chunks.keys().cloned().collect();
+        for key in keys {
+            let chunk = page.chunks[&key].clone();
+            let size = chunk.size(self.encoding, key) + count_growth(self.encoding);
+            let hot = self.hot_pages.contains(&page_index);
+            let mut target = match self.find_compact_target(key, size, hot, exclude)? {
+                None => continue,
+                Some(target) => target,
+            };
 
-        // Update internal state.
-        self.meta_pages = new_meta_pages.into_iter().map(|p| p.page_index).collect();
-        self.dirty_data_pages.clear();
+            // Update the reference to the chunk. It is either the head of
+            // the chain, or in the middle of the chain.
+            let flushed_index = self.map_index.get(&key).cloned();
+            self.dirty_map_index.entry(key).or_insert(flushed_index);
+            if flushed_index == Some(page_index) {
+                self.set_map_index(key, Some(target.page_index));
+            } else {
+                let mut prev = self.find_prev_page(key, page_index)?;
+                if let Some(prev_chunk) = prev.chunks.get_mut(&key) {
+                    prev_chunk.next_page_index = target.page_index;
+                    self.add_ref(page_index, -1);
+                    self.add_ref(target.page_index, 1);
+                }
+                self.mark_page_dirty(prev, key);
+            }
+            self.chain_hints.write().remove(&key);
 
-        #[cfg(debug_assertions)]
-        self.verify()?;
-        Ok(())
+            target.insert_chunk(key, chunk);
+            self.mark_page_dirty(target, key);
+            page.remove_chunk(key);
+            self.mark_page_dirty(page.clone(), key);
+            report.chunks_moved += 1;
+        }
+        Ok(page.chunks.is_empty())
     }
-}
 
-fn load_metadata(kv: &dyn IntKv) -> io::Result<(Vec<u64>, BTreeMap<u64, u64>, BTreeMap<u64, u64>)> {
-    let mut meta_pages: Vec<u64> = Default::default();
-    let mut map_index: BTreeMap<u64, u64> = Default::default();
-    let mut data_page_sizes: BTreeMap<u64, u64> = Default::default();
-    // Page 0 is reserved as an index page.
-    if kv.has(0)? {
-        let mut index = 0;
+    /// Find the fullest page that can take a chunk of `size` bytes for the
+    /// logical index `key`. Hot pages only take chunks from hot pages.
+    fn find_compact_target(
+        &self,
+        key: u64,
+        size: u64,
+        hot: bool,
+        exclude: &BTreeSet<u64>,
+    ) -> io::Result<Option<DataPage>> {
+        let empty_size = empty_data_page_size(self.encoding);
+        let mut candidates: Vec<(u64, u64)> = self
+            .data_page_sizes
+            .iter()
+            .filter(|(index, &page_size)| {
+                page_size > empty_size
+                    && page_size + size <= self.page_capacity()
+                    && self.hot_pages.contains(index) == hot
+                    && !exclude.contains(index)
+            })
+            .map(|(&index, &page_size)| (page_size, index))
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        for (_, index) in candidates {
+            // A page can only have one chunk per logical index.
+            let page = self.read_data_page(index as _)?;
+            if !page.chunks.contains_key(&key) {
+                return Ok(Some(Arc::unwrap_or_clone(page)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the page containing the chunk of `key` that links to `page_index`.
+    fn find_prev_page(&self, key: u64, page_index: u64) -> io::Result<DataPage> {
+        let mut index = match self.map_index.get(&key) {
+            None => return Err(not_found()),
+            Some(&index) => index,
+        };
         loop {
-            if meta_pages.contains(&(index as _)) {
-                // Meta pages must not form a cycle.
+            let page = self.read_data_page(index as _)?;
+            let next = match page.chunks.get(&key) {
+                None => 0,
+                Some(chunk) => chunk.next_page_index,
+            };
+            if next == page_index {
+                return Ok(Arc::unwrap_or_clone(page));
+            }
+            if next == 0 {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!("meta pages form a cycle ({})", index),
+                    format!("chain of {} does not contain page {}", key, page_index),
                 ));
             }
-            meta_pages.push(index as _);
-            let data = kv.read(index)?;
-            let mut page: MetaPage = bincode_deserialize(&data)?;
-            // Merge the index map into the global index map.
-            map_index.append(&mut page.map_index);
-            // Merge the data page size map.
-            data_page_sizes.append(&mut page.data_size_indexes);
-            index = page.next_page_index as usize;
-            if index == 0 {
-                // No more meta page to load.
-                break;
-            }
+            index = next;
         }
     }
-    Ok((meta_pages, map_index, data_page_sizes))
-}
-
-fn not_found() -> io::Error {
-    io::ErrorKind::NotFound.into()
-}
 
-#[cfg(test)]
-fn test_page_kv_size(size: u64, n: usize) {
-    let kv = super::super::test_int_kv(
-        |kv| {
-            kv.unwrap_or_else(|| {
-                let kv = super::super::backend::MemIntKv::new();
-                PageIntKv::new(size, Box::new(kv)).unwrap()
-            })
-        },
-        n,
-    );
-    kv.verify().unwrap();
+    /// Mark a page changed by the logical index for writing on flush.
+    fn mark_page_dirty(&mut self, page: DataPage, key: u64) {
+        self.dirty_page_keys
+            .entry(page.page_index)
+            .or_default()
+            .insert(key);
+        self.write_data_page(page);
+    }
 
-    // Reconstruct from the underlying kv.
-    let mut orig_kv = Some(kv.kv);
-    let kv = super::super::test_int_kv(
-        |kv| {
-            kv.unwrap_or_else(|| {
-                let kv = orig_kv.take().unwrap();
-                PageIntKv::new(size, kv).unwrap()
-            })
-        },
-        n,
-    );
-    kv.verify().unwrap();
-}
+    /// Point a logical index to its first data page, or remove it.
+    fn set_map_index(&mut self, key: u64, page_index: Option<u64>) {
+        let old = match page_index {
+            Some(page_index) => {
+                self.add_ref(page_index, 1);
+                self.map_index.insert(key, page_index)
+            }
+            None => self.map_index.remove(&key),
+        };
+        if let Some(old) = old {
+            self.add_ref(old, -1);
+        }
+    }
 
-#[test]
-fn test_page_kv_64() {
-    test_page_kv_size(64, 10);
-}
+    /// Track a link to a data page being added (1) or removed (-1).
+    fn add_ref(&mut self, page_index: u64, delta: i64) {
+        if page_index == 0 {
+            return;
+        }
+        let value = self.ref_deltas.entry(page_index).or_default();
+        *value += delta;
+        if *value == 0 {
+            self.ref_deltas.remove(&page_index);
+        }
+    }
 
-#[test]
-fn test_page_kv_1024() {
-    test_page_kv_size(1024, 100);
-}
+    /// Check links to the given data pages (None: all changed pages) against
+    /// `ref_deltas` and their chunks. Every chunk has exactly one incoming
+    /// link, so a mismatch means a page is referenced twice, or leaked. Only
+    /// visits changed states.
+    fn check_refs(&self, pages: Option<&BTreeSet<u64>>) -> io::Result<()> {
+        if self.refs_unknown {
+            return Ok(());
+        }
 
-#[test]
+        // Count changed links from dirty pages and changed mappings.
+        let mut link_deltas: BTreeMap<u64, i64> = Default::default();
+        for page in self.dirty_data_pages.values() {
+            for index in page.links() {
+                *link_deltas.entry(index).or_default() += 1;
+            }
+            for &index in &page.loaded_links {
+                *link_deltas.entry(index).or_default() -= 1;
+            }
+        }
+        for (key, &flushed) in &self.dirty_map_index {
+            if let Some(&index) = self.map_index.get(key) {
+                *link_deltas.entry(index).or_default() += 1;
+            }
+            if let Some(index) = flushed {
+                *link_deltas.entry(index).or_default() -= 1;
+            }
+        }
+
+        let pages: BTreeSet<u64> = match pages {
+            Some(pages) => pages.clone(),
+            None => self
+                .dirty_data_pages
+                .keys()
+                .chain(self.ref_deltas.keys())
+                .chain(link_deltas.keys())
+                .cloned()
+                .collect(),
+        };
+        let mut report = VerifyReport::default();
+        for index in pages {
+            let link_delta = link_deltas.get(&index).cloned().unwrap_or(0);
+            let ref_delta = self.ref_deltas.get(&index).cloned().unwrap_or(0);
+            if link_delta != ref_delta {
+                report.add(format!(
+                    "data page {} changed by {} links but {} were tracked",
+                    index, link_delta, ref_delta
+                ));
+            }
+            let chunk_delta = match self.dirty_data_pages.get(&index) {
+                Some(page) => page.chunks.len() as i64 - page.loaded_chunks as i64,
+                None => 0,
+            };
+            if link_delta != chunk_delta {
+                let keys = self.dirty_page_keys.get(&index);
+                report.add(format!(
+                    "data page {} changed by {} links but {} chunks (changed by {:?})",
+                    index, link_delta, chunk_delta, keys
+                ));
+            }
+        }
+        report.into_result().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("refusing to flush inconsistent pages: {}", e),
+            )
+        })
+    }
+
+    /// Find changed logical indexes and data pages that need to be flushed
+    /// together with the given logical indexes.
+    ///
+    /// A data page can contain changes from multiple logical indexes. Writing
+    /// the page requires flushing all of them.
+    fn flush_keys_closure(&self, keys: &[usize]) -> (BTreeSet<u64>, BTreeSet<u64>) {
+        let mut key_pages: BTreeMap<u64, Vec<u64>> = Default::default();
+        for (&page_index, page_keys) in &self.dirty_page_keys {
+            for &key in page_keys {
+                key_pages.entry(key).or_default().push(page_index);
+            }
+        }
+        let mut result_keys: BTreeSet<u64> = Default::default();
+        let mut result_pages: BTreeSet<u64> = Default::default();
+        let mut to_visit: Vec<u64> = keys
+            .iter()
+            .map(|&k| k as u64)
+            .filter(|k| self.dirty_map_index.contains_key(k))
+            .collect();
+        while let Some(key) = to_visit.pop() {
+            if !result_keys.insert(key) {
+                continue;
+            }
+            for page_index in key_pages.get(&key).into_iter().flatten() {
+                if result_pages.insert(*page_index) {
+                    to_visit.extend(self.dirty_page_keys[page_index].iter().cloned());
+                }
+            }
+        }
+        (result_keys, result_pages)
+    }
+
+    /// Write out the given dirty data pages. Schedule empty pages for
+    /// deletion.
+    fn write_data_pages(
+        &mut self,
+        indexes: &BTreeSet<u64>,
+        pending: &mut Pending,
+    ) -> io::Result<()> {
+        let mut pages = Vec::new();
+        for &index in indexes {
+            let page = match self.dirty_data_pages.get(&index) {
+                None => continue,
+                Some(page) => page,
+            };
+            crate::log_kv!(
+                log::Level::Debug, "Flushing DataPage";
+                page = index,
+                chunks = page.chunks.keys().copied().collect::<Vec<_>>(),
+            );
+            if page.chunks.is_empty() {
+                // Delete empty pages.
+                debug_assert!(!self.map_index.values().any(|&p| p == index));
+                self.data_page_sizes.remove(&index);
+                self.hot_pages.remove(&index);
+                pending.deleted.push(index);
+                self.dirty_data_pages.remove(&index);
+            } else {
+                pages.push((index, page.clone()));
+            }
+        }
+
+        // Serialize in parallel. Pages stay dirty until written.
+        let page_size = self.page_size;
+        let generation = self.generation + 1;
+        let writes = parallel_map(&pages, self.threads, |(index, page)| {
+            let mut bytes = bincode_serialize_pad_with(page.encoding, page.as_ref(), page_size);
+            if page.has_room_for_generation(page_size) {
+                let start = (page_size - GENERATION_SIZE) as usize;
+                bytes[start..].copy_from_slice(&generation.to_be_bytes());
+            }
+            (*index as usize, Bytes::from(bytes))
+        });
+        let (written, result) = self.kv.write_batch(writes);
+        for (index, page) in pages.into_iter().take(written) {
+            if page.has_room_for_generation(page_size) {
+                self.page_generations.insert(index, generation);
+            } else {
+                self.page_generations.remove(&index);
+            }
+            self.dirty_data_pages.remove(&index);
+            pending.written.push(index);
+        }
+        result
+    }
+
+    /// Write out meta pages, then delete pages scheduled for deletion.
+    ///
+    /// A crash leaves either the old or the new meta pages: changed meta
+    /// pages except page 0 are written to fresh indexes and flushed together
+    /// with data pages first. Then page 0 is rewritten to switch to them.
+    /// Pages no longer used are deleted last. They are already recorded as
+    /// free so a crash before deleting them does not leak them.
+    fn commit(
+        &mut self,
+        entries: Vec<(MetaKey, MetaEntry)>,
+        mut pending: Pending,
+    ) -> io::Result<()> {
+        let (mut dir_pages, leaves, ranges) = self.pack_meta_pages(entries, &pending.deleted);
+        let new_indexes: Vec<u64> = dir_pages
+            .iter()
+            .chain(&leaves)
+            .map(|p| p.page_index)
+            .collect();
+        let unused: Vec<u64> = self
+            .meta_pages
+            .iter()
+            .filter(|i| !new_indexes.contains(i))
+            .cloned()
+            .collect();
+        // Use the reserved space in page 0 to record unused meta pages.
+        // Leave room for the generation set by stamp_meta_page, and a longer
+        // length of free pages.
+        let encoding = self.encoding;
+        let page = &dir_pages[0];
+        let mut size = bincode_size_with(encoding, page) + encoding.max_int_size() * 2
+            - encoding.int_size(page.generation)
+            - encoding.int_size(page.free_pages.len() as u64);
+        for &index in &unused {
+            let entry_size = encoding.int_size(index);
+            if size + entry_size > self.page_size {
+                break;
+            }
+            dir_pages[0].free_pages.insert(index);
+            size += entry_size;
+        }
+
+        self.stamp_meta_page(0, &mut dir_pages[0]);
+
+        for page in leaves.iter().chain(&dir_pages[1..]) {
+            if self.write_meta_page(page)? {
+                pending.written.push(page.page_index);
+            }
+        }
+        self.flush_underlying(&pending.written)?;
+        if self.write_meta_page(&dir_pages[0])? {
+            self.flush_underlying(&[0])?;
+        }
+        self.generation = dir_pages[0].generation;
+        for page in leaves.iter().chain(&dir_pages[1..]) {
+            self.page_generations
+                .insert(page.page_index, page.generation);
+        }
+
+        for &index in &unused {
+            self.meta_page_contents.remove(&index);
+        }
+        pending.deleted.extend(unused);
+        self.meta_pages = new_indexes;
+        self.meta_ranges = ranges;
+        for &index in &pending.deleted {
+            self.page_generations.remove(&index);
+            if self.kv.has(index as _)? {
+                self.kv.remove(index as _)?;
+            }
+            self.free_page_index(index);
+        }
+        self.flush_underlying(&pending.deleted)
+    }
+
+    /// Space reserved in meta page 0 for recording unused meta pages.
+    /// It does not depend on the number of meta pages so the layout of
+    /// meta pages stays stable.
+    fn meta_page_reserve(&self) -> u64 {
+        self.page_size / 16
+    }
+
+    /// Pack meta entries into leaf pages and the directory linking to them,
+    /// and assign their indexes. Unchanged pages keep their indexes. Changed
+    /// pages and directory pages linking to them use fresh indexes so the old
+    /// meta pages stay intact until page 0 is rewritten. Pages in `deleted`
+    /// are not reused.
+    ///
+    /// Free pages first use the reserved space in page 0. If everything else
+    /// fits in page 0 too, there are no leaves.
+    ///
+    /// Return directory pages starting from page 0, leaf pages, and the
+    /// ranges of leaf pages.
+    fn pack_meta_pages(
+        &mut self,
+        mut entries: Vec<(MetaKey, MetaEntry)>,
+        deleted: &[u64],
+    ) -> (Vec<MetaPage>, Vec<MetaPage>, BTreeMap<MetaKey, u64>) {
+        let starts: Vec<MetaKey> = self.meta_ranges.keys().cloned().collect();
+        let old_leaves: BTreeSet<u64> = self.meta_ranges.values().cloned().collect();
+        let old_dir_pages: Vec<u64> = self
+            .meta_pages
+            .iter()
+            .filter(|i| !old_leaves.contains(i))
+            .cloned()
+            .collect();
+        let reserve = self.meta_page_reserve();
+        let free_start = entries.partition_point(|(k, _)| k.0 < META_FREE_PAGE);
+        let free_end = entries.partition_point(|(k, _)| k.0 <= META_FREE_PAGE);
+        let n = (free_end - free_start).min((reserve / self.encoding.max_int_size()) as usize);
+        let mut first_free: BTreeSet<u64> = entries
+            .drain(free_start..free_start + n)
+            .map(|((_, k), _)| k)
+            .collect();
+        let mut reserved: Vec<u64> = Vec::new();
+        loop {
+            let mut fresh = reserved.iter().cloned();
+            let mut missing = 0;
+            let mut leaves = Vec::new();
+            let mut ranges = BTreeMap::new();
+            let encoding = self.encoding;
+            let size: u64 = entries.iter().map(|(k, e)| e.size(k.1, encoding)).sum();
+            let split = if empty_meta_page_size(encoding) + size <= self.page_size - reserve {
+                Vec::new()
+            } else {
+                split_meta_ranges(encoding, self.page_size, &starts, &entries)
+            };
+            let mut generations = BTreeMap::new();
+            for (start, range) in split {
+                let mut page = leaf_meta_page(&entries[range]);
+                page.page_index = match self.meta_ranges.get(&start) {
+                    Some(&index) if self.stamp_meta_page(index, &mut page) => index,
+                    _ => fresh.next().unwrap_or_else(|| {
+                        missing += 1;
+                        u64::MAX
+                    }),
+                };
+                ranges.insert(start, page.page_index);
+                generations.insert(page.page_index, page.generation);
+                leaves.push(page);
+            }
+            let mut pages =
+                pack_meta_directory(encoding, self.page_size, reserve, &ranges, &generations);
+            if ranges.is_empty() {
+                pages[0] = leaf_meta_page(&entries);
+            }
+            pages[0].free_pages.extend(&first_free);
+            let mut next_index = 0;
+            let mut next_generation = 0;
+            for (i, page) in pages.iter_mut().enumerate().skip(1).rev() {
+                page.next_page_index = next_index;
+                if next_index != 0 {
+                    page.generations.insert(next_index, next_generation);
+                }
+                page.page_index = match old_dir_pages.get(i) {
+                    Some(&index) if self.stamp_meta_page(index, page) => index,
+                    _ => fresh.next().unwrap_or_else(|| {
+                        missing += 1;
+                        u64::MAX
+                    }),
+                };
+                next_index = page.page_index;
+                next_generation = page.generation;
+            }
+            if next_index != 0 {
+                pages[0].generations.insert(next_index, next_generation);
+            }
+            pages[0].page_index = 0;
+            pages[0].next_page_index = next_index;
+            if missing == 0 {
+                for id in fresh.collect::<Vec<_>>() {
+                    self.free_page_index(id);
+                }
+                pages[0].next_page_id = self.next_page_id;
+                return (pages, leaves, ranges);
+            }
+            let exclude = deleted.iter().chain(&reserved).cloned().collect();
+            let allocated = self.find_free_index_in_batch(missing, &exclude);
+            // Reserved pages are no longer free.
+            entries.retain(|&((kind, index), _)| {
+                kind != META_FREE_PAGE || !allocated.contains(&index)
+            });
+            for id in &allocated {
+                first_free.remove(id);
+            }
+            reserved.extend(allocated);
+        }
+    }
+
+    /// Set the generation of a meta page to be written to the index. If a
+    /// page with the same content was written to the index, keep its
+    /// generation and return true. Otherwise, use the next generation.
+    fn stamp_meta_page(&self, index: u64, page: &mut MetaPage) -> bool {
+        page.generation = match index {
+            0 => self.generation,
+            _ => self.page_generations.get(&index).cloned().unwrap_or(0),
+        };
+        let bytes = bincode_serialize_pad_with(self.encoding, page, self.page_size);
+        let unchanged = self
+            .meta_page_contents
+            .get(&index)
+            .is_some_and(|b| b[..] == bytes[..]);
+        if !unchanged {
+            page.generation = self.generation + 1;
+        }
+        unchanged
+    }
+
+    /// Flush the given pages in the underlying IntKv.
+    fn flush_underlying(&mut self, indexes: &[u64]) -> io::Result<()> {
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        let indexes: Vec<usize> = indexes.iter().map(|&i| i as usize).collect();
+        self.kv.flush_keys(&indexes)
+    }
+
+    /// Write a meta page to the underlying IntKv if it was changed.
+    /// Return true if the page was written.
+    fn write_meta_page(&mut self, page: &MetaPage) -> io::Result<bool> {
+        let index = page.page_index;
+        let bytes: Bytes = bincode_serialize_pad_with(self.encoding, page, self.page_size).into();
+        if let Some(written) = self.meta_page_contents.get(&index) {
+            if written[..] == bytes[..] {
+                return Ok(false);
+            }
+        }
+        self.kv.write(index as _, bytes.clone())?;
+        self.meta_page_contents.insert(index, bytes);
+        Ok(true)
+    }
+}
+
+impl IntKv for PageIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let mut mapped_index = match self.map_index.get(&(index as _)) {
+            None => return Err(not_found()),
+            Some(&mapped_index) => mapped_index,
+        };
+        let hint = self.chain_hints.read().get(&(index as _)).cloned();
+        let mut chain = Vec::new();
+        let mut result = Vec::new();
+        if let Some(extents) = self.extents.get(&(index as _)) {
+            let is_clean = |e: &&Extent| !self.dirty_extent_pages.contains_key(&e.page_index);
+            let indexes: Vec<usize> = extents
+                .iter()
+                .filter(is_clean)
+                .map(|e| e.page_index as usize)
+                .collect();
+            // Clean extent pages are read in one batch to decode in parallel.
+            let mut clean_pages = self.kv.read_batch(&indexes).into_iter();
+            for extent in extents {
+                let data = if is_clean(&extent) {
+                    clean_pages.next().unwrap()?
+                } else {
+                    self.read_extent_data(extent)?
+                };
+                result.extend_from_slice(&check_extent(extent, data)?);
+            }
+        }
+        while mapped_index != 0 {
+            if let Some(hint) = &hint {
+                // Prefetch the next few pages if the chain matches the hint.
+                let pos = chain.len();
+                if hint.get(pos) == Some(&mapped_index) {
+                    let start = if pos == 0 { 1 } else { pos + PREFETCH_PAGES };
+                    self.prefetch_chain(hint, start, pos + PREFETCH_PAGES + 1);
+                }
+            }
+            chain.push(mapped_index);
+            let page = self.read_data_page_cached(mapped_index)?;
+            match page.chunks.get(&(index as _)) {
+                Some(chunk) => {
+                    mapped_index = chunk.next_page_index as _;
+                    if mapped_index == 0 && result.is_empty() {
+                        // Fast path: single chunk data.
+                        return Ok(chunk.data.clone());
+                    } else {
+                        // Multiple chunks. Concat them.
+                        result.extend_from_slice(&chunk.data)
+                    }
+                }
+                None => return Err(not_found()),
+            }
+        }
+        if hint.as_ref() != Some(&chain) {
+            self.set_chain_hint(index as _, chain);
+        }
+        Ok(result.into())
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        if self.is_identical(index, &data)? {
+            return Ok(());
+        }
+        self.update_logical_data(index, Some(data), None)
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        if self.is_identical(index, &data)? {
+            return Ok(());
+        }
+        self.update_logical_data(index, Some(data), Some(hint))
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.update_logical_data(index, None, None)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        Ok(self.map_index.contains_key(&(index as _)))
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        Ok(self.logical_keys().map(|i| i as usize).collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_refs(None)?;
+
+        // Nothing changed?
+        if self.dirty_data_pages.is_empty() {
+            return Ok(());
+        }
+
+        // Write out extent and data pages.
+        self.refs_unknown = true;
+        let mut pending = Pending::default();
+        let pages: BTreeSet<u64> = self.dirty_data_pages.keys().cloned().collect();
+        self.write_extent_pages(None, &mut pending)?;
+        self.write_data_pages(&pages, &mut pending)?;
+
+        // Write out meta pages.
+        let mut free_pages = self.free_pages.clone();
+        free_pages.extend(&pending.deleted);
+        let entries = meta_entries(
+            &self.map_index,
+            &self.data_page_sizes,
+            &self.page_generations,
+            &free_pages,
+            &self.extents,
+        );
+        self.commit(entries, pending)?;
+
+        self.kv.flush()?;
+
+        // Update internal state.
+        self.dirty_data_pages.clear();
+        self.dirty_map_index.clear();
+        self.dirty_extents.clear();
+        self.dirty_page_keys.clear();
+        self.dirty_page_sizes.clear();
+        self.ref_deltas.clear();
+        self.refs_unknown = false;
+
+        if self.paranoid_checks {
+            self.verify()?;
+        }
+        Ok(())
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let (keys, pages) = self.flush_keys_closure(keys);
+        if pages.is_empty() {
+            return Ok(());
+        }
+        log::debug!("Flushing keys {:?} with DataPages {:?}", &keys, &pages);
+        self.check_refs(Some(&pages))?;
+
+        // Write out extent and data pages.
+        let refs_unknown = std::mem::replace(&mut self.refs_unknown, true);
+        let mut pending = Pending::default();
+        self.write_extent_pages(Some(&keys), &mut pending)?;
+        self.write_data_pages(&pages, &mut pending)?;
+
+        // Meta pages describe flushed states, plus changes of `keys`.
+        let mut map_index = self.map_index.clone();
+        for (&key, &flushed) in &self.dirty_map_index {
+            if !keys.contains(&key) {
+                match flushed {
+                    Some(page_index) => map_index.insert(key, page_index),
+                    None => map_index.remove(&key),
+                };
+            }
+        }
+        let mut data_page_sizes = self.data_page_sizes.sizes.clone();
+        let mut free_pages = self.free_pages.clone();
+        free_pages.extend(&pending.deleted);
+        for (&page_index, &flushed) in &self.dirty_page_sizes {
+            if !pages.contains(&page_index) {
+                match flushed {
+                    Some(size) => data_page_sizes.insert(page_index, size),
+                    None => {
+                        // New pages that are not written are free on disk.
+                        free_pages.insert(page_index);
+                        data_page_sizes.remove(&page_index)
+                    }
+                };
+            }
+        }
+        let mut extents = self.extents.clone();
+        for (&key, flushed) in &self.dirty_extents {
+            if !keys.contains(&key) {
+                match flushed {
+                    Some(flushed) => extents.insert(key, flushed.clone()),
+                    None => extents.remove(&key),
+                };
+            }
+        }
+        for (&page_index, (_, data)) in &self.dirty_extent_pages {
+            if data.is_some() {
+                // New extent pages that are not written are free on disk.
+                free_pages.insert(page_index);
+            }
+        }
+        let entries = meta_entries(
+            &map_index,
+            &data_page_sizes,
+            &self.page_generations,
+            &free_pages,
+            &extents,
+        );
+        self.commit(entries, pending)?;
+
+        // Update internal state.
+        for key in &keys {
+            self.dirty_map_index.remove(key);
+            self.dirty_extents.remove(key);
+        }
+        for page_index in &pages {
+            self.dirty_page_keys.remove(page_index);
+            self.dirty_page_sizes.remove(page_index);
+            self.ref_deltas.remove(page_index);
+        }
+        self.refs_unknown = refs_unknown;
+
+        if self.paranoid_checks && self.dirty_data_pages.is_empty() {
+            self.verify()?;
+        }
+        Ok(())
+    }
+    // `pin` is not forwarded. Logical indexes do not map to pages 1:1.
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        if let Ok(frag) = self.frag_stats(false) {
+            stats.insert("page.data_pages".into(), frag.data_pages as _);
+            stats.insert("page.meta_pages".into(), frag.meta_pages as _);
+            stats.insert("page.extent_pages".into(), frag.extent_pages as _);
+            stats.insert("page.physical_bytes".into(), frag.physical_bytes);
+            stats.insert("page.used_bytes".into(), frag.used_bytes);
+            for (i, &count) in frag.fill_histogram.iter().enumerate() {
+                stats.insert(format!("page.fill_{}", i * 10), count as _);
+            }
+        }
+        stats
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        let dirty_bytes = self.dirty_data_pages.len() as u64 * self.page_size;
+        self.kv.check_space(extra + dirty_bytes)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        let report = self.compact(max_pages)?;
+        if report.pages_reclaimed > 0 {
+            crate::log_kv!(
+                log::Level::Info, "Compacted pages";
+                pages = report.pages_reclaimed,
+                bytes = report.bytes_reclaimed,
+                chunks_moved = report.chunks_moved,
+            );
+        }
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.flush()?;
+        self.kv.snapshot(dest)
+    }
+}
+
+/// Collect meta entries sorted by position.
+fn meta_entries(
+    map_index: &BTreeMap<u64, u64>,
+    data_page_sizes: &BTreeMap<u64, u64>,
+    page_generations: &BTreeMap<u64, u64>,
+    free_pages: &BTreeSet<u64>,
+    extents: &BTreeMap<u64, Vec<Extent>>,
+) -> Vec<(MetaKey, MetaEntry)> {
+    let len = map_index.len() + data_page_sizes.len() + free_pages.len() + extents.len();
+    let mut entries = Vec::with_capacity(len);
+    entries.extend(data_page_sizes.iter().map(|(&k, &v)| {
+        let generation = page_generations.get(&k).cloned().unwrap_or(0);
+        ((META_DATA_SIZE, k), MetaEntry::DataSize(v, generation))
+    }));
+    entries.extend(
+        free_pages
+            .iter()
+            .map(|&k| ((META_FREE_PAGE, k), MetaEntry::FreePage)),
+    );
+    entries.extend(
+        map_index
+            .iter()
+            .map(|(&k, &v)| ((META_MAP_INDEX, k), MetaEntry::MapIndex(v))),
+    );
+    entries.extend(
+        extents
+            .iter()
+            .map(|(&k, v)| ((META_EXTENTS, k), MetaEntry::Extents(v.clone()))),
+    );
+    entries
+}
+
+/// Find ranges of entries for leaf meta pages, starting from the existing
+/// ranges. A range is split in halves if it no longer fits in a page, and
+/// merged into its neighbour if it becomes empty or small. Other ranges are
+/// kept so their leaf pages stay unchanged.
+///
+/// Return the start of each range, and the indexes of its entries.
+fn split_meta_ranges(
+    encoding: Encoding,
+    page_size: u64,
+    starts: &[MetaKey],
+    entries: &[(MetaKey, MetaEntry)],
+) -> Vec<(MetaKey, Range<usize>)> {
+    let entry_size = |i: usize| -> u64 {
+        let ((_, key), entry) = &entries[i];
+        entry.size(*key, encoding)
+    };
+    let entries_size = |range: &Range<usize>| -> u64 { range.clone().map(entry_size).sum() };
+    let empty_size = empty_meta_page_size(encoding);
+    let mut starts = starts.to_vec();
+    if starts.first() != Some(&(0, 0)) {
+        starts.insert(0, (0, 0));
+    }
+
+    let mut split: Vec<(MetaKey, Range<usize>)> = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = match starts.get(i + 1) {
+            Some(next) => entries.partition_point(|(k, _)| k < next),
+            None => entries.len(),
+        };
+        let begin = entries.partition_point(|(k, _)| *k < start);
+        let mut to_split = vec![(start, begin..end)];
+        while let Some((start, range)) = to_split.pop() {
+            let size = entries_size(&range);
+            if empty_size + size <= page_size || range.len() <= 1 {
+                split.push((start, range));
+                continue;
+            }
+            // Split in the middle by size.
+            let mut mid = range.start + 1;
+            let mut acc = 0;
+            for j in range.clone() {
+                acc += entry_size(j);
+                if acc * 2 >= size {
+                    mid = (j + 1).clamp(range.start + 1, range.end - 1);
+                    break;
+                }
+            }
+            to_split.push((entries[mid].0, mid..range.end));
+            to_split.push((start, range.start..mid));
+        }
+    }
+
+    let mut result: Vec<(MetaKey, Range<usize>)> = Vec::new();
+    for (start, range) in split {
+        if let Some((_, last)) = result.last_mut() {
+            let merged = last.start..range.end;
+            if Range::is_empty(last)
+                || range.is_empty()
+                || empty_size + entries_size(&merged) <= page_size / 2
+            {
+                *last = merged;
+                continue;
+            }
+        }
+        result.push((start, range));
+    }
+    result
+}
+
+/// Create a leaf meta page storing the given entries.
+fn leaf_meta_page(entries: &[(MetaKey, MetaEntry)]) -> MetaPage {
+    let mut page = MetaPage::default();
+    for ((_, key), entry) in entries {
+        match entry {
+            MetaEntry::DataSize(size, generation) => {
+                page.data_size_indexes.insert(*key, *size);
+                if *generation != 0 {
+                    page.generations.insert(*key, *generation);
+                }
+            }
+            MetaEntry::FreePage => {
+                page.free_pages.insert(*key);
+            }
+            MetaEntry::MapIndex(index) => {
+                page.map_index.insert(*key, *index);
+            }
+            MetaEntry::Extents(extents) => {
+                page.extents.insert(*key, extents.clone());
+            }
+        }
+    }
+    page
+}
+
+/// Pack ranges of leaf meta pages and their generations into the
+/// directory: page 0, then pages linked from it. Page indexes and links are
+/// not set. Page 0 leaves `first_page_reserve` bytes unused.
+fn pack_meta_directory(
+    encoding: Encoding,
+    page_size: u64,
+    first_page_reserve: u64,
+    ranges: &BTreeMap<MetaKey, u64>,
+    generations: &BTreeMap<u64, u64>,
+) -> Vec<MetaPage> {
+    let empty_size = empty_meta_page_size(encoding) + meta_next_generation_size(encoding);
+    let range_size = meta_range_size(encoding);
+    let mut pages = vec![MetaPage::default()];
+    let mut size = empty_size;
+    let mut limit = page_size.saturating_sub(first_page_reserve);
+    for (&start, &index) in ranges {
+        if size + range_size > limit {
+            pages.push(MetaPage::default());
+            size = empty_size;
+            limit = page_size;
+        }
+        let page = pages.last_mut().unwrap();
+        page.ranges.insert(start, index);
+        if let Some(&generation) = generations.get(&index) {
+            page.generations.insert(index, generation);
+        }
+        size += range_size;
+    }
+    pages
+}
+
+/// Pages written or to be deleted by a flush.
+#[derive(Default)]
+struct Pending {
+    written: Vec<u64>,
+    deleted: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Metadata {
+    meta_pages: Vec<u64>,
+    meta_ranges: BTreeMap<MetaKey, u64>,
+    meta_page_contents: HashMap<u64, Bytes>,
+    map_index: BTreeMap<u64, u64>,
+    data_page_sizes: BTreeMap<u64, u64>,
+    next_page_id: u64,
+    free_pages: BTreeSet<u64>,
+    extents: BTreeMap<u64, Vec<Extent>>,
+    page_generations: BTreeMap<u64, u64>,
+    generation: u64,
+}
+
+fn load_metadata(kv: &dyn IntKv, encoding: Encoding) -> io::Result<Metadata> {
+    let mut result = Metadata::default();
+    let Metadata {
+        meta_pages,
+        meta_ranges,
+        meta_page_contents,
+        map_index,
+        data_page_sizes,
+        next_page_id,
+        free_pages,
+        extents,
+        page_generations,
+        generation,
+    } = &mut result;
+    // Page 0 is reserved as an index page.
+    if kv.has(0)? {
+        let mut load = |index: u64| -> io::Result<MetaPage> {
+            if meta_pages.contains(&index) {
+                // Meta pages must not form a cycle.
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("meta pages form a cycle ({})", index),
+                ));
+            }
+            meta_pages.push(index);
+            let data = kv.read(index as _)?;
+            let mut page = parse_meta_page(&data, encoding)?;
+            if index == 0 {
+                *generation = page.generation;
+            }
+            let expected = page_generations.get(&index);
+            check_generation("meta", index, page.generation, expected, *generation)?;
+            page_generations.append(&mut page.generations);
+            meta_page_contents.insert(index, data);
+            // Merge the index map into the global index map.
+            map_index.append(&mut page.map_index);
+            // Merge the data page size map.
+            data_page_sizes.append(&mut page.data_size_indexes);
+            // Merge allocation states.
+            *next_page_id = (*next_page_id).max(page.next_page_id);
+            free_pages.append(&mut page.free_pages);
+            extents.append(&mut page.extents);
+            Ok(page)
+        };
+        // Load the directory. Older vaults store everything in it.
+        let mut index = 0;
+        loop {
+            let mut page = load(index)?;
+            meta_ranges.append(&mut page.ranges);
+            index = page.next_page_index;
+            if index == 0 {
+                break;
+            }
+        }
+        // Load leaves.
+        for &index in meta_ranges.values() {
+            load(index)?;
+        }
+    }
+    Ok(result)
+}
+
+/// Parse a meta page. Meta pages written by older versions might be full
+/// without space for newer fields. Pad zeros so those fields are empty.
+fn parse_meta_page(data: &[u8], encoding: Encoding) -> io::Result<MetaPage> {
+    let mut data = data.to_vec();
+    data.resize(data.len() + 48, 0);
+    bincode_deserialize_with(encoding, &data)
+}
+
+/// Generation stored at the end of a data page.
+fn data_page_generation(data: &[u8]) -> u64 {
+    match data.len().checked_sub(GENERATION_SIZE as usize) {
+        Some(start) => u64::from_be_bytes(data[start..].try_into().unwrap()),
+        None => 0,
+    }
+}
+
+/// Check the generation of a page against the recorded one, if any. A page
+/// older than recorded is stale. A page newer than recorded was written by an
+/// interrupted flush, after the `latest` generation, and is accepted.
+fn check_generation(
+    kind: &str,
+    index: u64,
+    actual: u64,
+    expected: Option<&u64>,
+    latest: u64,
+) -> io::Result<()> {
+    match expected {
+        Some(&expected) if actual < expected || actual > latest + 1 => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} page {} is stale (generation {}, expected {})",
+                kind, index, actual, expected
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Strip the padding of an extent page.
+fn check_extent(extent: &Extent, data: Bytes) -> io::Result<Bytes> {
+    if data.len() < extent.len as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("extent page {} is truncated", extent.page_index),
+        ));
+    }
+    Ok(data.slice(0..extent.len as usize))
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+#[cfg(test)]
+fn test_page_kv_size(size: u64, n: usize) {
+    test_page_kv_size_encoding(size, n, Encoding::Fixint);
+}
+
+#[cfg(test)]
+fn test_page_kv_size_encoding(size: u64, n: usize, encoding: Encoding) {
+    // Reloading switches the allocation mode. Both modes share the layout.
+    for sequential in [true, false] {
+        let kv = super::super::test_int_kv(
+            |kv| {
+                kv.unwrap_or_else(|| {
+                    let kv = super::super::backend::MemIntKv::new();
+                    PageIntKv::new_with_encoding(size, Box::new(kv), encoding)
+                        .unwrap()
+                        .with_sequential_allocation(sequential)
+                })
+            },
+            n,
+        );
+        kv.verify().unwrap();
+
+        // Reconstruct from the underlying kv.
+        let mut orig_kv = Some(kv.kv);
+        let kv = super::super::test_int_kv(
+            |kv| {
+                kv.unwrap_or_else(|| {
+                    let kv = orig_kv.take().unwrap();
+                    PageIntKv::new_with_encoding(size, kv, encoding)
+                        .unwrap()
+                        .with_sequential_allocation(!sequential)
+                })
+            },
+            n,
+        );
+        kv.verify().unwrap();
+    }
+}
+
+#[test]
+fn test_page_kv_256() {
+    test_page_kv_size(256, 10);
+}
+
+#[test]
+fn test_page_kv_1024() {
+    test_page_kv_size(1024, 100);
+}
+
+#[test]
 fn test_page_kv_16384() {
     test_page_kv_size(16384, 100);
 }
+
+#[test]
+fn test_page_kv_varint() {
+    let min_page_size = PageIntKv::min_page_size(Encoding::Varint);
+    test_page_kv_size_encoding(min_page_size, 10, Encoding::Varint);
+    test_page_kv_size_encoding(1024, 100, Encoding::Varint);
+}
+
+#[test]
+fn test_page_kv_varint_meta_pages() {
+    use super::super::backend::MemIntKv;
+    let meta_pages = |encoding| {
+        let mut kv = PageIntKv::new_with_encoding(1024, Box::new(MemIntKv::new()), encoding)
+            .unwrap()
+            .with_paranoid_checks(true)
+            .with_sequential_allocation(true);
+        for i in 0..2000 {
+            kv.write(i, vec![1; 20].into()).unwrap();
+        }
+        kv.flush().unwrap();
+        let count = kv.meta_pages.len();
+        let kv = PageIntKv::new_with_encoding(1024, kv.kv, encoding).unwrap();
+        assert_eq!(kv.read(1999).unwrap(), vec![1; 20]);
+        count
+    };
+    let fixint = meta_pages(Encoding::Fixint);
+    let varint = meta_pages(Encoding::Varint);
+    assert!(varint * 3 < fixint, "{} vs {}", varint, fixint);
+}
+
+#[test]
+fn test_page_kv_prefetch() {
+    use super::super::backend::FsIntKv;
+    use super::{BufferedIntKv, FaultIntKv, Op};
+    use std::time::{Duration, Instant};
+
+    // Prepare a long chain.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let mut kv = PageIntKv::new(1024, Box::new(FsIntKv::new(path).unwrap())).unwrap();
+    let data: Bytes = (0..24 * 1000).map(|i| i as u8).collect::<Vec<u8>>().into();
+    kv.write(1, data.clone()).unwrap();
+    kv.flush().unwrap();
+
+    let slow_kv = || -> Box<dyn IntKv> {
+        let kv = FsIntKv::new(path).unwrap();
+        let kv = FaultIntKv::new(Box::new(kv));
+        kv.faults().latency(Op::Read, Duration::from_millis(10));
+        Box::new(BufferedIntKv::new(Box::new(kv)))
+    };
+
+    // The first read learns about the chain.
+    // Swap the kv since `verify()` in debug build warms up the cache.
+    let mut kv = PageIntKv::new(1024, slow_kv()).unwrap();
+    kv.kv = slow_kv();
+    let start = Instant::now();
+    assert_eq!(kv.read(1).unwrap(), data);
+    let cold = start.elapsed();
+
+    // Use a cold cache for the second read. Prefetch makes it faster.
+    kv.kv = slow_kv();
+    kv.page_cache.write().clear();
+    let start = Instant::now();
+    assert_eq!(kv.read(1).unwrap(), data);
+    let prefetched = start.elapsed();
+    assert!(
+        prefetched < cold / 2,
+        "prefetched {:?} cold {:?}",
+        prefetched,
+        cold
+    );
+}
+
+#[test]
+fn test_page_kv_flush_keys() {
+    use super::super::backend::FsIntKv;
+    use rand::{RngCore, SeedableRng};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let open = || PageIntKv::new(1024, Box::new(FsIntKv::new(path).unwrap())).unwrap();
+    let mut kv = open();
+    let mut rng = rand_chacha::ChaChaRng::from_seed(Default::default());
+
+    // Logical index -> (flushed data, current data).
+    let mut model: BTreeMap<usize, (Option<Bytes>, Option<Bytes>)> = Default::default();
+    for _ in 0..30 {
+        for _ in 0..10 {
+            let key = (rng.next_u32() % 20) as usize;
+            let entry = model.entry(key).or_default();
+            if rng.next_u32() % 4 == 0 {
+                if entry.1.is_some() {
+                    kv.remove(key).unwrap();
+                    entry.1 = None;
+                }
+            } else {
+                let len = (rng.next_u32() % 3000) as usize;
+                let data: Bytes = vec![rng.next_u32() as u8; len].into();
+                kv.write(key, data.clone()).unwrap();
+                entry.1 = Some(data);
+            }
+        }
+
+        let keys: Vec<usize> = (0..20).filter(|_| rng.next_u32() % 3 == 0).collect();
+        kv.flush_keys(&keys).unwrap();
+        for key in &keys {
+            if let Some(entry) = model.get_mut(key) {
+                entry.0 = entry.1.clone();
+            }
+        }
+
+        // Listed keys are flushed. Other keys are either flushed or not.
+        let reloaded = open();
+        reloaded.verify().unwrap();
+        for (key, (flushed, current)) in model.iter_mut() {
+            let actual = reloaded.read(*key).ok();
+            if keys.contains(key) {
+                assert_eq!(&actual, current);
+            } else {
+                assert!(&actual == current || &actual == flushed);
+                *flushed = actual;
+            }
+        }
+    }
+
+    kv.flush().unwrap();
+    let reloaded = open();
+    for (key, (_, current)) in model.iter() {
+        assert_eq!(&reloaded.read(*key).ok(), current);
+    }
+
+    // Unrelated changes are not flushed.
+    // Each entry takes a full page so they cannot share a page.
+    kv.write(100, vec![1; 990].into()).unwrap();
+    kv.write(200, vec![2; 990].into()).unwrap();
+    kv.flush_keys(&[100]).unwrap();
+    let reloaded = open();
+    assert!(reloaded.has(100).unwrap());
+    assert!(!reloaded.has(200).unwrap());
+}
+
+#[test]
+fn test_page_kv_free_pages() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    for i in 0..10 {
+        kv.write(i, vec![i as u8; 3000].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    let next_page_id = kv.next_page_id;
+    for i in 0..5 {
+        kv.remove(i).unwrap();
+    }
+    kv.flush().unwrap();
+    assert!(!kv.free_pages.is_empty());
+
+    // Free pages are persisted and reused.
+    let mut kv = PageIntKv::new(1024, kv.kv).unwrap();
+    assert!(!kv.free_pages.is_empty());
+    for i in 0..5 {
+        kv.write(i, vec![i as u8; 3000].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    assert_eq!(kv.next_page_id, next_page_id);
+    for i in 0..10 {
+        assert_eq!(kv.read(i).unwrap().len(), 3000);
+    }
+}
+
+#[cfg(test)]
+impl DataPageSizes {
+    /// `find_first` by visiting every page.
+    fn find_first_linear(&self, max_size: u64, mut accept: impl FnMut(u64) -> bool) -> Option<u64> {
+        self.sizes
+            .iter()
+            .find(|(&index, &size)| size <= max_size && accept(index))
+            .map(|(&index, _)| index)
+    }
+}
+
+#[test]
+fn test_data_page_sizes_find() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+    let mut sizes = DataPageSizes::new(BTreeMap::new(), 4000);
+    for _ in 0..2000 {
+        let index = rng.gen_range(0..300);
+        match rng.gen_range(0..4) {
+            0 => {
+                let expected = sizes.get(&index).copied();
+                assert_eq!(sizes.remove(&index), expected);
+            }
+            _ => drop(sizes.insert(index, rng.gen_range(0..4096))),
+        }
+        let max_size = rng.gen_range(0..5000);
+        let parity = rng.gen_range(0..3);
+        let accept = |index: u64| parity == 2 || index % 2 == parity;
+        assert_eq!(
+            sizes.find_first(max_size, accept),
+            sizes.find_first_linear(max_size, accept)
+        );
+    }
+    // Buckets only have pages of their free space.
+    for (bucket, pages) in sizes.buckets.iter().enumerate() {
+        for index in pages {
+            assert_eq!(size_bucket(sizes.free(sizes[index])), bucket);
+        }
+    }
+    assert_eq!(
+        sizes.buckets.iter().map(|b| b.len()).sum::<usize>(),
+        sizes.len()
+    );
+    assert_eq!(sizes.by_free.len(), sizes.len());
+
+    sizes.insert(1000, 1);
+    sizes.insert(999, 1);
+    sizes.insert(998, 0);
+    assert_eq!(
+        sizes.find_sized(1),
+        sizes.iter().find(|(_, &s)| s == 1).map(|(&i, _)| i)
+    );
+    assert_eq!(sizes.find_first(0, |_| true), sizes.find_sized(0));
+    assert_eq!(sizes.find_sized(u64::MAX), None);
+}
+
+#[test]
+#[ignore]
+fn bench_page_kv_small_writes() {
+    use super::super::backend::MemIntKv;
+    use std::time::Instant;
+
+    // Run with `cargo test --release -- --ignored bench_page_kv_small`.
+    let mut kv = PageIntKv::new(4096, Box::new(MemIntKv::new()))
+        .unwrap()
+        .with_paranoid_checks(false);
+    let start = Instant::now();
+    for i in 0..100_000u64 {
+        kv.write(i as _, vec![i as u8; 100].into()).unwrap();
+    }
+    eprintln!(
+        "Writing 100k small entries to {} pages took {:?}",
+        kv.data_page_sizes.len(),
+        start.elapsed()
+    );
+    let max_size = kv.page_capacity() - 100 - chunk_overhead(kv.encoding);
+    let start = Instant::now();
+    for _ in 0..1000 {
+        kv.data_page_sizes.find_first(max_size, |_| true);
+    }
+    let indexed = start.elapsed();
+    let start = Instant::now();
+    for _ in 0..1000 {
+        kv.data_page_sizes.find_first_linear(max_size, |_| true);
+    }
+    eprintln!(
+        "Finding a page with room 1000 times took {:?}, {:?} with a linear scan",
+        indexed,
+        start.elapsed()
+    );
+}
+
+#[test]
+fn test_page_kv_old_meta_page() {
+    use crate::util::bincode_serialize_pad;
+
+    // Meta page without allocation states. It fills the page.
+    #[derive(Serialize)]
+    struct OldMetaPage {
+        next_page_index: u64,
+        map_index: BTreeMap<u64, u64>,
+        data_size_indexes: BTreeMap<u64, u64>,
+    }
+    let chunk = |data: &'static [u8]| Chunk {
+        next_page_index: 0,
+        data: data.into(),
+    };
+    let mut data_page = DataPage::default();
+    data_page.insert_chunk(4, chunk(b"hi"));
+    data_page.insert_chunk(5, chunk(b"hello"));
+    let mut data_page2 = DataPage::default();
+    data_page2.insert_chunk(2, chunk(b"foo"));
+    data_page2.insert_chunk(3, chunk(b"bar"));
+    for i in 9..12 {
+        data_page2.insert_chunk(i, chunk(b"baz"));
+    }
+    let page_size = 168;
+    let meta_page = OldMetaPage {
+        next_page_index: 0,
+        map_index: vec![(2, 8), (3, 8), (4, 7), (5, 7), (9, 8), (10, 8), (11, 8)]
+            .into_iter()
+            .collect(),
+        data_size_indexes: vec![(7, data_page.size()), (8, data_page2.size())]
+            .into_iter()
+            .collect(),
+    };
+    assert_eq!(bincode_size_with(Encoding::Fixint, &meta_page), page_size);
+    let mut mem = super::super::backend::MemIntKv::new();
+    mem.write(0, bincode_serialize_pad(&meta_page, page_size).into())
+        .unwrap();
+    mem.write(7, bincode_serialize_pad(&data_page, page_size).into())
+        .unwrap();
+    mem.write(8, bincode_serialize_pad(&data_page2, page_size).into())
+        .unwrap();
+
+    let mut kv = PageIntKv::new(page_size, Box::new(mem)).unwrap();
+    assert_eq!(kv.unstamped_pages(), 3);
+    assert_eq!(kv.read(5).unwrap(), &b"hello"[..]);
+    kv.write(6, b"world"[..].into()).unwrap();
+    kv.flush().unwrap();
+    // Converted to leaves.
+    assert!(!kv.meta_ranges.is_empty());
+    // Unchanged data pages stay unstamped.
+    assert!(kv.unstamped_pages() <= 2);
+    let kv = PageIntKv::new(page_size, kv.kv).unwrap();
+    assert_eq!(kv.read(2).unwrap(), &b"foo"[..]);
+    assert_eq!(kv.read(4).unwrap(), &b"hi"[..]);
+    assert_eq!(kv.read(5).unwrap(), &b"hello"[..]);
+    assert_eq!(kv.read(6).unwrap(), &b"world"[..]);
+}
+
+#[test]
+fn test_page_kv_compact() {
+    use rand::{RngCore, SeedableRng};
+
+    let mut kv = PageIntKv::new(256, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    let mut rng = rand_chacha::ChaChaRng::from_seed(Default::default());
+    let mut model: BTreeMap<usize, Bytes> = Default::default();
+    let mut reclaimed = 0;
+    for round in 0..100 {
+        for _ in 0..5 {
+            let key = (rng.next_u32() % 30) as usize;
+            if rng.next_u32() % 3 == 0 {
+                if model.remove(&key).is_some() {
+                    kv.remove(key).unwrap();
+                }
+            } else {
+                let len = (rng.next_u32() % 600) as usize;
+                let data: Bytes = vec![rng.next_u32() as u8; len].into();
+                kv.write(key, data.clone()).unwrap();
+                model.insert(key, data);
+            }
+        }
+        let report = kv.compact((rng.next_u32() % 4) as usize).unwrap();
+        reclaimed += report.pages_reclaimed;
+        for (&key, data) in &model {
+            assert_eq!(&kv.read(key).unwrap(), data);
+        }
+        if round % 3 == 0 {
+            kv.flush().unwrap();
+            kv.verify().unwrap();
+        }
+    }
+    assert!(reclaimed > 0);
+
+    kv.flush().unwrap();
+    let kv = PageIntKv::new(256, kv.kv).unwrap();
+    assert_eq!(kv.map_index.len(), model.len());
+    for (&key, data) in &model {
+        assert_eq!(&kv.read(key).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_page_kv_verify_report() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    kv.write(1, vec![1; 100].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(kv.verify_fast().is_ok());
+    assert!(kv.verify_full().is_ok());
+
+    // All problems are reported.
+    kv.map_index.insert(2, 12345);
+    let page_index = kv.map_index[&1];
+    kv.free_pages.insert(page_index);
+    kv.data_page_sizes.insert(page_index, 1);
+    let report = kv.verify_fast();
+    assert_eq!(report.problems.len(), 2, "{:?}", &report);
+    let report = kv.verify_full();
+    assert!(report.problems.len() >= 3, "{:?}", &report);
+    assert!(kv.verify().is_err());
+}
+
+#[test]
+fn test_page_kv_unchanged_meta_pages() {
+    use super::super::backend::MemIntKv;
+    use super::FaultIntKv;
+
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let written = failing.faults().written;
+    let mut kv = PageIntKv::new(256, Box::new(failing)).unwrap();
+    for i in 0..200 {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    assert!(kv.meta_pages.len() >= 10);
+
+    // Changing a page size only writes the meta page recording the size to
+    // a new index, and page 0 linking to it.
+    written.lock().clear();
+    kv.write(100, vec![1; 11].into()).unwrap();
+    kv.flush().unwrap();
+    let meta_written = written
+        .lock()
+        .iter()
+        .filter(|&&i| kv.meta_pages.contains(&(i as u64)))
+        .count();
+    assert_eq!(meta_written, 2);
+
+    // The size is unchanged. The leaf still records the new generation.
+    written.lock().clear();
+    kv.write(100, vec![2; 11].into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(written.lock().len(), 3);
+
+    // Nothing changed.
+    written.lock().clear();
+    kv.flush().unwrap();
+    assert!(written.lock().is_empty());
+
+    let kv = PageIntKv::new(256, kv.kv).unwrap();
+    for i in 0..200 {
+        assert!(kv.has(i).unwrap());
+    }
+    assert_eq!(&kv.read(100).unwrap()[..], &[2; 11]);
+}
+
+#[test]
+fn test_page_kv_extents() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    let large: Bytes = (0..10000).map(|i| i as u8).collect::<Vec<u8>>().into();
+
+    // Grow a small entry past the threshold.
+    kv.write(1, vec![1; 100].into()).unwrap();
+    kv.flush().unwrap();
+    kv.write(1, large.clone()).unwrap();
+    assert_eq!(kv.extents[&1].len(), 9);
+    assert_eq!(kv.read(1).unwrap(), large);
+    kv.flush().unwrap();
+
+    let mut kv = PageIntKv::new(1024, kv.kv).unwrap();
+    assert_eq!(kv.read(1).unwrap(), large);
+    kv.verify().unwrap();
+
+    // Shrink it. Extent pages are deleted.
+    let extent_pages: Vec<u64> = kv.extents[&1].iter().map(|e| e.page_index).collect();
+    kv.write(1, vec![2; 100].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(kv.extents.is_empty());
+    for i in extent_pages {
+        assert!(!kv.kv.has(i as _).unwrap());
+    }
+
+    // Extents of unrelated entries are not flushed.
+    kv.write(2, large.clone()).unwrap();
+    kv.write(3, large.clone()).unwrap();
+    kv.flush_keys(&[2]).unwrap();
+    let mut kv = PageIntKv::new(1024, kv.kv).unwrap();
+    kv.verify().unwrap();
+    assert_eq!(kv.read(1).unwrap(), vec![2; 100]);
+    assert_eq!(kv.read(2).unwrap(), large);
+    assert!(!kv.has(3).unwrap());
+    kv.remove(2).unwrap();
+    kv.flush().unwrap();
+    kv.verify().unwrap();
+}
+
+#[test]
+fn test_page_kv_frag_stats() {
+    use super::super::backend::MemIntKv;
+
+    let mut kv = PageIntKv::new(1024, Box::new(MemIntKv::new())).unwrap();
+    kv.write(1, vec![1; 100].into()).unwrap();
+    kv.write(2, vec![2; 2000].into()).unwrap();
+    kv.write(3, vec![3; 5000].into()).unwrap();
+    kv.flush().unwrap();
+
+    // The cheap mode does not read pages.
+    let orig_kv = std::mem::replace(&mut kv.kv, Box::new(MemIntKv::new()));
+    let stats = kv.frag_stats(false).unwrap();
+    assert_eq!(stats.meta_pages, 1);
+    assert_eq!(stats.extent_pages, 4);
+    assert_eq!(stats.data_pages, 4);
+    assert_eq!(stats.physical_bytes, 9 * 1024);
+    assert_eq!(stats.fill_histogram.iter().sum::<usize>(), 4);
+    assert!(stats.used_bytes > 7100);
+    assert_eq!(stats.longest_chain, None);
+    assert!(kv.frag_stats(true).is_err());
+
+    kv.kv = orig_kv;
+    let stats = kv.frag_stats(true).unwrap();
+    assert_eq!(stats.longest_chain, Some(3));
+    assert_eq!(stats.multi_page_entries, Some(2));
+    assert_eq!(kv.stats()["page.extent_pages"], 4);
+}
+
+#[test]
+fn test_page_kv_fill_factor() {
+    let count_pages = |fill_factor: f64| -> u64 {
+        let kv = Box::new(super::super::backend::MemIntKv::new());
+        let mut kv = PageIntKv::new(1024, kv)
+            .unwrap()
+            .with_fill_factor(fill_factor)
+            .with_sequential_allocation(true);
+        let mut values: Vec<Vec<u8>> = (0..30).map(|i| vec![i as u8; 80]).collect();
+        for (i, value) in values.iter().enumerate() {
+            kv.write(i, value.clone().into()).unwrap();
+        }
+        // Append to entries.
+        for _ in 0..3 {
+            for (i, value) in values.iter_mut().enumerate() {
+                value.extend_from_slice(&[i as u8; 8]);
+                kv.write(i, value.clone().into()).unwrap();
+            }
+        }
+        kv.flush().unwrap();
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(kv.read(i).unwrap(), &value[..]);
+        }
+        kv.next_page_id
+    };
+    let packed = count_pages(1.0);
+    let with_headroom = count_pages(0.8);
+    assert!(
+        with_headroom * 2 < packed,
+        "{} vs {}",
+        with_headroom,
+        packed
+    );
+}
+
+#[test]
+fn test_page_kv_partial_overwrite() {
+    use rand::{RngCore, SeedableRng};
+
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    let mut data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    kv.write(1, data.clone().into()).unwrap();
+    kv.flush().unwrap();
+    let head = kv.map_index[&1];
+    let head_len = kv.read_data_page(head as _).unwrap().chunks[&1].data.len();
+
+    // Appending only rewrites the last page.
+    data.extend_from_slice(&[1; 10]);
+    kv.write(1, data.clone().into()).unwrap();
+    assert_eq!(kv.dirty_data_pages.len(), 1);
+    assert!(!kv.dirty_data_pages.contains_key(&head));
+    kv.flush().unwrap();
+
+    // Changes right after the first page edge do not rewrite the first page.
+    data[head_len] ^= 1;
+    kv.write(1, data.clone().into()).unwrap();
+    assert!(!kv.dirty_data_pages.contains_key(&head));
+    kv.flush().unwrap();
+
+    // Changes right before the edge rewrite the first page.
+    data[head_len - 1] ^= 1;
+    kv.write(1, data.clone().into()).unwrap();
+    assert!(kv.dirty_data_pages.contains_key(&head));
+    kv.flush().unwrap();
+
+    // Truncate at the page edge.
+    data.truncate(head_len);
+    kv.write(1, data.clone().into()).unwrap();
+    assert_eq!(kv.read(1).unwrap(), &data[..]);
+    kv.flush().unwrap();
+    kv.verify().unwrap();
+
+    // Unchanged extents are reused.
+    let mut data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    kv.write(2, data.clone().into()).unwrap();
+    kv.flush().unwrap();
+    data.extend_from_slice(&[1; 10]);
+    kv.write(2, data.clone().into()).unwrap();
+    assert!(kv.dirty_extent_pages.is_empty());
+    data[5000] ^= 1;
+    kv.write(2, data.clone().into()).unwrap();
+    assert_eq!(kv.dirty_extent_pages.len(), 2);
+    kv.flush().unwrap();
+
+    // Random edits.
+    let mut rng = rand_chacha::ChaChaRng::from_seed(Default::default());
+    for _ in 0..200 {
+        let len = data.len();
+        match rng.next_u32() % 3 {
+            0 => data.resize(len + (rng.next_u32() % 2000) as usize, rng.next_u32() as u8),
+            1 => data.truncate((rng.next_u32() as usize) % (len + 1)),
+            _ if len > 0 => data[(rng.next_u32() as usize) % len] ^= 1,
+            _ => {}
+        }
+        kv.write(2, data.clone().into()).unwrap();
+        assert_eq!(kv.read(2).unwrap(), &data[..]);
+        if rng.next_u32() % 4 == 0 {
+            kv.flush().unwrap();
+        }
+    }
+    kv.flush().unwrap();
+    let kv = PageIntKv::new(1024, kv.kv).unwrap();
+    assert_eq!(kv.read(2).unwrap(), &data[..]);
+}
+
+#[test]
+fn test_page_kv_crash_during_flush() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+
+    let value = |i: usize| -> Bytes { vec![i as u8; 990].into() };
+    let old: Vec<usize> = (0..60).collect();
+    let new: Vec<usize> = (30..90).collect();
+
+    // Returns false if the flush completed.
+    let crash_after = |budget: usize| -> bool {
+        let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+        let faults = failing.faults();
+        let mut kv = PageIntKv::new(1024, Box::new(failing)).unwrap();
+        for &i in &old {
+            kv.write(i, value(i)).unwrap();
+        }
+        kv.flush().unwrap();
+        assert!(kv.meta_pages.len() >= 2);
+
+        // Changes every meta page. Data pages are not rewritten in place.
+        for &i in &new {
+            kv.write(i, value(i)).unwrap();
+        }
+        for &i in &old {
+            if !new.contains(&i) {
+                kv.remove(i).unwrap();
+            }
+        }
+        faults.fail_after(&[Op::Write, Op::Remove], budget);
+        let crashed = kv.flush().is_err();
+
+        // Either fully old or fully new.
+        faults.clear();
+        let kv = PageIntKv::new(1024, kv.kv).unwrap();
+        kv.verify().unwrap();
+        let expected = if kv.has(0).unwrap() { &old } else { &new };
+        assert!(crashed || expected == &new);
+        for i in 0..90 {
+            if expected.contains(&i) {
+                assert_eq!(kv.read(i).unwrap(), value(i));
+            } else {
+                assert!(!kv.has(i).unwrap());
+            }
+        }
+        crashed
+    };
+
+    let mut budget = 0;
+    while crash_after(budget) {
+        budget += 1;
+    }
+    assert!(budget > 30);
+}
+
+#[test]
+fn test_page_kv_flush_retry() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+
+    let value = |i: usize, round: u8| -> Bytes { vec![round; 300 + i].into() };
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let faults = failing.faults();
+    let mut kv = PageIntKv::new(1024, Box::new(failing)).unwrap();
+    for i in 0..40 {
+        kv.write(i, value(i, 1)).unwrap();
+    }
+    kv.flush().unwrap();
+
+    // Each flush fails at a later write, then the same instance retries.
+    for (round, nth) in (2..6).zip([1, 2, 3, 4]) {
+        for i in (0..40).step_by(round as usize) {
+            kv.write(i, value(i, round)).unwrap();
+        }
+        faults.fail_nth(Op::Write, nth);
+        assert!(kv.flush().is_err(), "round {}", round);
+        kv.flush().unwrap();
+    }
+    let kv = PageIntKv::new(1024, kv.kv).unwrap();
+    kv.verify().unwrap();
+    for i in 0..40 {
+        let round = (2..6).rev().find(|&r| i % r as usize == 0).unwrap_or(1);
+        assert_eq!(kv.read(i).unwrap(), value(i, round), "entry {}", i);
+    }
+}
+
+#[test]
+fn test_page_kv_page_cache() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    for i in 0..3 {
+        kv.write(i, vec![i as u8; 100].into()).unwrap();
+    }
+    kv.flush().unwrap();
+
+    // Reads of clean pages share the decoded page.
+    assert_eq!(kv.read(1).unwrap(), vec![1; 100]);
+    let page_index = kv.map_index[&1];
+    assert!(kv.page_cache.read().contains_key(&page_index));
+
+    // Changing the page drops it from the cache.
+    kv.write(2, vec![5; 200].into()).unwrap();
+    assert!(kv.page_cache.read().is_empty());
+    assert_eq!(kv.read(2).unwrap(), vec![5; 200]);
+    kv.flush().unwrap();
+    assert_eq!(kv.read(2).unwrap(), vec![5; 200]);
+    kv.write(1, vec![6; 50].into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(kv.read(1).unwrap(), vec![6; 50]);
+    assert_eq!(kv.read(2).unwrap(), vec![5; 200]);
+}
+
+#[test]
+fn test_page_kv_salvage() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    let value = |i: usize| -> Bytes { vec![i as u8; if i == 3 { 3000 } else { 100 }].into() };
+    for i in [3, 1, 2] {
+        kv.write(i, value(i)).unwrap();
+    }
+    kv.flush().unwrap();
+    let chain = kv.chain_hints.read()[&3].clone();
+    assert!(chain.len() > 2);
+
+    // Corrupt the middle page of the chain.
+    let bad = chain[1];
+    kv.kv.write(bad as _, vec![0xff; 1024].into()).unwrap();
+    let err = kv.read(3).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains(&format!("data page {} ", bad)));
+    assert_eq!(kv.corrupted_pages(), vec![bad]);
+    assert!(!kv.verify_full().is_ok());
+
+    // Report, then drop affected entries.
+    assert_eq!(kv.salvage(bad, false).unwrap(), vec![3]);
+    assert_eq!(kv.salvage(bad, true).unwrap(), vec![3]);
+    kv.flush().unwrap();
+    let kv = PageIntKv::new(1024, kv.kv).unwrap();
+    kv.verify().unwrap();
+    assert!(!kv.has(3).unwrap());
+    for i in [1, 2] {
+        assert_eq!(kv.read(i).unwrap(), value(i));
+    }
+    assert!(kv.corrupted_pages().is_empty());
+}
+
+#[test]
+fn test_page_kv_min_page_size() {
+    let new_kv =
+        |page_size| PageIntKv::new(page_size, Box::new(super::super::backend::MemIntKv::new()));
+    let min_page_size = PageIntKv::min_page_size(Encoding::Fixint);
+    let err = new_kv(min_page_size - 1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(err.to_string(), "page size 153 is too small (minimum 154)");
+
+    // The smallest page size can store data and metadata.
+    let mut kv = new_kv(min_page_size).unwrap();
+    for i in 0..20 {
+        kv.write(i, vec![i as u8; 100].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    let kv = PageIntKv::new(min_page_size, kv.kv).unwrap();
+    for i in 0..20 {
+        assert_eq!(kv.read(i).unwrap(), vec![i as u8; 100]);
+    }
+}
+
+#[test]
+fn test_page_kv_chain_len() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    let usable = kv.usable_page_size();
+    // Grow a value while small values fill the remaining space of its pages.
+    for step in 1..=35 {
+        kv.write(1000, vec![step as u8; step * 100].into()).unwrap();
+        for i in 0..3 {
+            kv.write(step * 3 + i, vec![0; 150].into()).unwrap();
+        }
+        let len = step * 100;
+        let chain_len = kv.chain_hints.read().get(&1000).map_or(1, |c| c.len());
+        assert!(chain_len <= len.div_ceil(usable) * MAX_CHAIN_RATIO);
+    }
+    kv.flush().unwrap();
+    assert_eq!(kv.read(1000).unwrap(), vec![35; 3500]);
+}
+
+#[test]
+fn test_page_kv_batch_alloc() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new()))
+        .unwrap()
+        .with_sequential_allocation(true);
+    // One call for the first page, one for the rest of the chain.
+    kv.write(1, vec![1; 3000].into()).unwrap();
+    assert_eq!(kv.alloc_calls, 2);
+    let chain = kv.chain_hints.read()[&1].clone();
+    assert_eq!(chain.len(), 4);
+    assert!(chain.windows(2).all(|w| w[0] + 1 == w[1]));
+
+    // One call for the extents. The rest fits in an existing page.
+    kv.alloc_calls = 0;
+    kv.write(2, vec![2; 10000].into()).unwrap();
+    assert_eq!(kv.alloc_calls, 1);
+    assert_eq!(kv.extents[&2].len(), 9);
+    kv.flush().unwrap();
+    assert_eq!(kv.read(1).unwrap(), vec![1; 3000]);
+    assert_eq!(kv.read(2).unwrap(), vec![2; 10000]);
+}
+
+#[test]
+fn test_page_kv_random_allocation() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new()))
+        .unwrap()
+        .with_sequential_allocation(false);
+    for i in 0..10 {
+        kv.write(i, vec![i as u8; 5000].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    // Page indexes are not in the write order.
+    let pages: Vec<u64> = kv.data_page_sizes.keys().cloned().collect();
+    assert!(pages.iter().any(|&i| i > 1000));
+    assert!(kv.next_page_id < 10);
+    for i in 0..10 {
+        assert_eq!(kv.read(i).unwrap(), vec![i as u8; 5000]);
+    }
+}
+
+#[test]
+#[ignore]
+fn bench_page_kv_flush_threads() {
+    use super::super::backend::MemIntKv;
+    use super::EncIntKv;
+    use std::time::Instant;
+
+    // Run with `cargo test --release -- --ignored bench_page_kv_flush`.
+    for &threads in &[1, crate::util::default_threads()] {
+        let enc = EncIntKv::from_key_kv([0; 32], Box::new(MemIntKv::new())).with_threads(threads);
+        let mut kv = PageIntKv::new(4096, Box::new(enc))
+            .unwrap()
+            .with_paranoid_checks(false)
+            .with_threads(threads);
+        for i in 0..10000u64 {
+            kv.write(i as _, vec![i as u8; 4000].into()).unwrap();
+        }
+        let start = Instant::now();
+        kv.flush().unwrap();
+        eprintln!(
+            "Flushing 10k pages with {} threads took {:?}",
+            threads,
+            start.elapsed()
+        );
+    }
+}
+
+#[test]
+fn test_page_kv_ref_check() {
+    use super::super::backend::MemIntKv;
+    let new_kv = || {
+        let mut kv = PageIntKv::new(1024, Box::new(MemIntKv::new()))
+            .unwrap()
+            .with_paranoid_checks(false);
+        kv.write(1, vec![1; 2500].into()).unwrap();
+        kv.write(2, vec![2; 100].into()).unwrap();
+        kv
+    };
+
+    // Normal changes pass the check.
+    let mut kv = new_kv();
+    kv.flush().unwrap();
+    kv.write(1, vec![1; 300].into()).unwrap();
+    kv.remove(2).unwrap();
+    kv.compact(10).unwrap();
+    kv.flush().unwrap();
+
+    // A page referenced from two chains.
+    let mut kv = new_kv();
+    let head = kv.map_index[&1];
+    let mut page = kv.read_data_page_mut(kv.map_index[&2] as _).unwrap();
+    page.chunks.get_mut(&2).unwrap().next_page_index = head;
+    kv.dirty_data_pages.insert(page.page_index, Arc::new(page));
+    let err = kv.flush().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains(&format!("data page {} ", head)));
+
+    // A page that is no longer referenced.
+    let mut kv = new_kv();
+    kv.flush().unwrap();
+    let mut page = kv.read_data_page_mut(kv.map_index[&1] as _).unwrap();
+    let chunk = page.chunks.get_mut(&1).unwrap();
+    let next = chunk.next_page_index;
+    chunk.next_page_index = 0;
+    kv.dirty_data_pages.insert(page.page_index, Arc::new(page));
+    let err = kv.flush().unwrap_err();
+    assert!(err.to_string().contains(&format!("data page {} ", next)));
+}
+
+#[test]
+fn test_page_kv_meta_ranges() {
+    use super::super::backend::MemIntKv;
+    use super::FaultIntKv;
+
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let written = failing.faults().written;
+    let mut kv = PageIntKv::new(1024, Box::new(failing)).unwrap();
+    for i in 0..2000 {
+        kv.write(i * 2, vec![i as u8; 10].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    assert!(kv.meta_ranges.len() >= 10);
+
+    // A new mapping in the middle only rewrites a few meta pages: leaves of
+    // the mapping and the page size, and directory pages linking to them.
+    for i in [1001, 1003, 3001] {
+        written.lock().clear();
+        kv.write(i, vec![1; 10].into()).unwrap();
+        kv.flush().unwrap();
+        let meta_written = written
+            .lock()
+            .iter()
+            .filter(|&&i| kv.meta_pages.contains(&(i as u64)))
+            .count();
+        assert!(meta_written <= 4, "{} meta pages written", meta_written);
+    }
+
+    // Ranges survive reloading.
+    let ranges = kv.meta_ranges.clone();
+    let mut kv = PageIntKv::new(1024, kv.kv).unwrap();
+    assert_eq!(kv.meta_ranges, ranges);
+    assert_eq!(kv.read(1003).unwrap(), vec![1; 10]);
+
+    // Ranges shrink after removing entries.
+    for i in 0..2000 {
+        kv.remove(i * 2).unwrap();
+    }
+    kv.flush().unwrap();
+    assert!(kv.meta_ranges.is_empty());
+    assert_eq!(kv.meta_pages, [0]);
+    assert_eq!(kv.read(1003).unwrap(), vec![1; 10]);
+}
+
+#[test]
+fn test_page_kv_verify_meta_ranges() {
+    use crate::util::bincode_serialize_pad;
+
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    for i in 0..500 {
+        kv.write(i, vec![i as u8; 10].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    assert!(kv.verify_full().is_ok());
+
+    // Swap leaves of two ranges.
+    let mut page = kv.read_meta_page(0).unwrap();
+    let starts: Vec<MetaKey> = page.ranges.keys().cloned().collect();
+    let (a, b) = (page.ranges[&starts[0]], page.ranges[&starts[1]]);
+    page.ranges.insert(starts[0], b);
+    page.ranges.insert(starts[1], a);
+    kv.kv
+        .write(0, bincode_serialize_pad(&page, 1024).into())
+        .unwrap();
+    let report = kv.verify_full();
+    assert!(
+        report
+            .problems
+            .iter()
+            .any(|p| p.contains("outside its range")),
+        "{:?}",
+        &report
+    );
+}
+
+#[test]
+fn test_page_kv_generations() {
+    let mut kv = PageIntKv::new(1024, Box::new(super::super::backend::MemIntKv::new())).unwrap();
+    for i in 0..10 {
+        kv.write(i, vec![i as u8; 100].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    assert_eq!(kv.unstamped_pages(), 0);
+
+    // An older copy of a data page comes back.
+    let index = kv.map_index[&1];
+    let old = kv.kv.read(index as _).unwrap();
+    kv.write(1, vec![9; 100].into()).unwrap();
+    kv.flush().unwrap();
+    kv.kv.write(index as _, old).unwrap();
+    let err = kv.read_data_page(index as _).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        format!("data page {} is stale (generation 1, expected 2)", index)
+    );
+    assert_eq!(kv.corrupted_pages(), vec![index]);
+}
+
+#[test]
+fn test_page_kv_write_hints() {
+    use super::super::backend::MemIntKv;
+    use super::FaultIntKv;
+
+    // Rewrite small "trees" next to large "blobs". Return bytes of data
+    // pages written by rewriting trees.
+    let rewrite_bytes = |hints: bool| -> usize {
+        let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+        let written = failing.faults().written;
+        let mut kv = PageIntKv::new(1024, Box::new(failing)).unwrap();
+        let write = |kv: &mut PageIntKv, index: usize, len: usize, hint: Hint| {
+            let data: Bytes = vec![index as u8; len].into();
+            match hints {
+                true => kv.write_with_hint(index, data, hint).unwrap(),
+                false => kv.write(index, data).unwrap(),
+            }
+        };
+        for i in 0..10 {
+            write(&mut kv, 100 + i, 700, Hint::Cold);
+            write(&mut kv, i, 50, Hint::Hot);
+        }
+        kv.flush().unwrap();
+        written.lock().clear();
+        for round in 0..5 {
+            for i in 0..10 {
+                write(&mut kv, i, 50 + round, Hint::Hot);
+            }
+            kv.flush().unwrap();
+        }
+        let data_pages = written
+            .lock()
+            .iter()
+            .filter(|&&i| kv.data_page_sizes.contains_key(&(i as u64)))
+            .count();
+        for i in 0..10 {
+            assert_eq!(kv.read(100 + i).unwrap().len(), 700);
+            assert_eq!(kv.read(i).unwrap().len(), 54);
+        }
+        data_pages * kv.page_size as usize
+    };
+
+    let without_hints = rewrite_bytes(false);
+    let with_hints = rewrite_bytes(true);
+    assert!(
+        with_hints * 2 < without_hints,
+        "{} bytes with hints, {} bytes without",
+        with_hints,
+        without_hints
+    );
+}
+
+#[test]
+fn test_page_kv_skip_identical_writes() {
+    use super::super::backend::MemIntKv;
+    use super::FaultIntKv;
+
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let written = failing.faults().written;
+    let mut kv = PageIntKv::new(256, Box::new(failing))
+        .unwrap()
+        .with_skip_identical_writes(true);
+    for i in 0..20 {
+        kv.write(i, vec![i as u8; 100].into()).unwrap();
+    }
+    kv.flush().unwrap();
+
+    written.lock().clear();
+    for i in 0..20 {
+        kv.write(i, vec![i as u8; 100].into()).unwrap();
+    }
+    kv.write_with_hint(3, vec![3; 100].into(), Hint::Hot)
+        .unwrap();
+    kv.flush().unwrap();
+    assert!(written.lock().is_empty());
+
+    kv.write(3, vec![4; 100].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(!written.lock().is_empty());
+    assert_eq!(&kv.read(3).unwrap()[..], &[4; 100]);
+}
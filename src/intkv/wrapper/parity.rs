@@ -0,0 +1,733 @@
+//! Split entries across several `IntKv`s, with their XOR in one more, so
+//! any one of them can be lost without losing data, using less space than
+//! mirroring.
+//!
+//! Each entry is split into a share per data member, `share_size` bytes
+//! each, padded with zeros. Each share ends with the length of the entry.
+//! The parity member holds the XOR of the shares. Reads reconstruct a
+//! share that fails to read, or a member that is offline, from the others.
+//!
+//! Changing the shares of an entry is not atomic. Entries are listed in a
+//! journal entry of the parity member before they are changed until the
+//! next flush, so `recover` can make their parity match after a crash.
+//!
+//! Shares have a fixed size, so this is meant below `PageIntKv`, for
+//! entries that are blocks of the same size.
+
+use super::super::{Bytes, IntKv, Stats};
+use crate::util::parallel_map;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Reserved index of the journal in the parity member.
+const JOURNAL_INDEX: usize = usize::MAX - 3;
+
+/// Bytes after each share: the length of the entry.
+const LEN_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub struct ParityIntKv {
+    /// Data members, then the parity member. None if offline.
+    members: Vec<Option<Box<dyn IntKv>>>,
+    share_size: usize,
+    /// Entries listed in the journal.
+    journal: BTreeSet<usize>,
+    /// Entries listed in the journal on open. Their parity might not match.
+    stale: BTreeSet<usize>,
+    reconstructed: AtomicU64,
+}
+
+impl ParityIntKv {
+    /// Split entries of up to `entry_size` bytes across `data`, with their
+    /// parity in `parity`. One member can be None if it is unavailable.
+    pub fn open(
+        data: Vec<Option<Box<dyn IntKv>>>,
+        parity: Option<Box<dyn IntKv>>,
+        entry_size: usize,
+    ) -> io::Result<Self> {
+        assert!(!data.is_empty(), "no data members");
+        let share_size = entry_size.div_ceil(data.len()).max(1);
+        let mut members = data;
+        members.push(parity);
+        let offline = members.iter().filter(|m| m.is_none()).count();
+        if offline > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("{} members of the parity set are unavailable", offline),
+            ));
+        }
+        let journal = match members.last().unwrap() {
+            Some(parity) => read_journal(parity.as_ref())?,
+            None => BTreeSet::new(),
+        };
+        Ok(Self {
+            members,
+            share_size,
+            stale: journal.clone(),
+            journal,
+            reconstructed: AtomicU64::new(0),
+        })
+    }
+
+    /// Positions of unavailable members. The last position is the parity
+    /// member.
+    pub fn offline(&self) -> Vec<usize> {
+        (0..self.members.len())
+            .filter(|&i| self.members[i].is_none())
+            .collect()
+    }
+
+    /// Whether a member is unavailable, so losing another loses data.
+    pub fn is_degraded(&self) -> bool {
+        self.members.iter().any(Option::is_none)
+    }
+
+    /// Entries whose parity might not match, changed before a crash.
+    pub fn stale_len(&self) -> usize {
+        self.stale.len()
+    }
+
+    /// Positions of members without entries while others have some, ex.
+    /// replaced disks.
+    pub fn empty_members(&self) -> io::Result<Vec<usize>> {
+        let mut empty = Vec::new();
+        let mut any = false;
+        for (i, member) in self.members.iter().enumerate() {
+            if let Some(member) = member {
+                match member.keys()?.iter().any(|&k| k != JOURNAL_INDEX) {
+                    true => any = true,
+                    false => empty.push(i),
+                }
+            }
+        }
+        Ok(if any { empty } else { Vec::new() })
+    }
+
+    /// Make the parity of stale entries match their data. Entries missing
+    /// on some data members, partially written or removed, are removed.
+    /// Return the number of entries changed.
+    pub fn recover(&mut self) -> io::Result<usize> {
+        let stale: Vec<usize> = self.stale.iter().cloned().collect();
+        let count = self.resync(&stale)?;
+        self.stale.clear();
+        Ok(count)
+    }
+
+    /// Entries whose parity does not match their data.
+    pub fn mismatches(&self) -> io::Result<Vec<usize>> {
+        self.require_online()?;
+        let n = self.data_count();
+        let keys = self.keys()?;
+        let mut shares = self.read_shares(&keys, 0..=n);
+        let mut mismatches = Vec::new();
+        for (i, &index) in keys.iter().enumerate() {
+            let shares: Vec<Bytes> = match take_column(&mut shares, i).into_iter().collect() {
+                Ok(shares) => shares,
+                Err(_) => {
+                    mismatches.push(index);
+                    continue;
+                }
+            };
+            let sizes_ok = (0..=n).all(|m| self.share_len(index, m, &shares[m]).is_ok());
+            if !sizes_ok || self.parity_of(&shares[..n]) != shares[n] {
+                mismatches.push(index);
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Rewrite the parity of `indexes` from their data, or remove entries
+    /// missing on some data members. Return the number of entries changed.
+    pub fn resync(&mut self, indexes: &[usize]) -> io::Result<usize> {
+        self.require_online()?;
+        let n = self.data_count();
+        let mut shares = self.read_shares(indexes, 0..n);
+        let mut items = Vec::new();
+        let mut removed = Vec::new();
+        for (i, &index) in indexes.iter().enumerate() {
+            let shares: Vec<io::Result<Bytes>> = take_column(&mut shares, i);
+            let missing =
+                |r: &io::Result<Bytes>| matches!(r, Err(e) if e.kind() == io::ErrorKind::NotFound);
+            if shares.iter().any(missing) {
+                removed.push(index);
+                continue;
+            }
+            let shares: Vec<Bytes> = shares.into_iter().collect::<io::Result<_>>()?;
+            for (member, share) in shares.iter().enumerate() {
+                self.share_len(index, member, share)?;
+            }
+            items.push((index, self.parity_of(&shares)));
+        }
+        let count = items.len() + removed.len();
+        let parity = self.members[n].as_mut().unwrap();
+        parity.write_batch(items).1?;
+        for index in removed {
+            for member in self.members.iter_mut().flatten() {
+                remove_if_exists(member.as_mut(), index)?;
+            }
+        }
+        self.flush()?;
+        Ok(count)
+    }
+
+    /// Rebuild the shares of `member`, ex. a replaced disk, from the
+    /// others. Skip entries whose other shares fail to read. Return the
+    /// number of entries rebuilt.
+    pub fn repair(&mut self, member: usize) -> io::Result<usize> {
+        self.require_online()?;
+        let n = self.data_count();
+        if member == n {
+            let keys = self.keys()?;
+            return self.resync(&keys);
+        }
+        let mut keys = BTreeSet::new();
+        for (i, kv) in self.members.iter().enumerate() {
+            if i != member {
+                keys.extend(kv.as_ref().unwrap().keys()?);
+            }
+        }
+        keys.remove(&JOURNAL_INDEX);
+        let keys: Vec<usize> = keys.into_iter().collect();
+        let others: Vec<usize> = (0..=n).filter(|&i| i != member).collect();
+        let mut count = 0;
+        for chunk in keys.chunks(256) {
+            let mut shares = self.read_shares(chunk, others.iter().cloned());
+            let mut items = Vec::with_capacity(chunk.len());
+            for (i, &index) in chunk.iter().enumerate() {
+                if self.stale.contains(&index) {
+                    log::warn!("Entry {} might be rebuilt incorrectly after a crash", index);
+                }
+                let shares: io::Result<Vec<Bytes>> =
+                    take_column(&mut shares, i).into_iter().collect();
+                let checked = shares.and_then(|shares| {
+                    for (&other, share) in others.iter().zip(&shares) {
+                        self.share_len(index, other, share)?;
+                    }
+                    Ok(shares)
+                });
+                let shares = match checked {
+                    Ok(shares) => shares,
+                    Err(e) => {
+                        log::warn!("Entry {} cannot be rebuilt ({})", index, e);
+                        continue;
+                    }
+                };
+                // The missing share is the XOR of the others.
+                items.push((index, self.parity_of(&shares)));
+            }
+            count += items.len();
+            let kv = self.members[member].as_mut().unwrap();
+            kv.write_batch(items).1?;
+        }
+        self.flush()?;
+        Ok(count)
+    }
+
+    fn data_count(&self) -> usize {
+        self.members.len() - 1
+    }
+
+    /// XOR of `shares`, with the length of the entry after it.
+    fn parity_of(&self, shares: &[Bytes]) -> Bytes {
+        let mut parity = vec![0; self.share_size + LEN_SIZE];
+        for share in shares {
+            xor_into(&mut parity[..self.share_size], &share[..self.share_size]);
+        }
+        parity[self.share_size..].copy_from_slice(&shares[0][self.share_size..]);
+        parity.into()
+    }
+
+    fn require_online(&self) -> io::Result<()> {
+        match self.offline().first() {
+            Some(&i) => Err(offline_error(i)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the size of a share of entry `index` read from `member`.
+    /// Return the length of the entry.
+    fn share_len(&self, index: usize, member: usize, share: &[u8]) -> io::Result<usize> {
+        let len = match share.len() == self.share_size + LEN_SIZE {
+            true => u32::from_le_bytes(share[self.share_size..].try_into().unwrap()) as usize,
+            false => usize::MAX,
+        };
+        if len > self.data_count() * self.share_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("share {} of entry {} is corrupted", member + 1, index),
+            ));
+        }
+        Ok(len)
+    }
+
+    /// Shares of `data` for each member, the last one holding parity.
+    fn encode(&self, index: usize, data: &[u8]) -> io::Result<Vec<Bytes>> {
+        let n = self.data_count();
+        if data.len() > n * self.share_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "entry {} has {} bytes, more than {} fitting in the parity set",
+                    index,
+                    data.len(),
+                    n * self.share_size
+                ),
+            ));
+        }
+        let len = (data.len() as u32).to_le_bytes();
+        let mut parity = vec![0; self.share_size + LEN_SIZE];
+        let mut shares = Vec::with_capacity(n + 1);
+        for i in 0..n {
+            let mut share = vec![0; self.share_size + LEN_SIZE];
+            let start = (i * self.share_size).min(data.len());
+            let end = ((i + 1) * self.share_size).min(data.len());
+            share[..end - start].copy_from_slice(&data[start..end]);
+            share[self.share_size..].copy_from_slice(&len);
+            xor_into(&mut parity[..self.share_size], &share[..self.share_size]);
+            shares.push(share.into());
+        }
+        parity[self.share_size..].copy_from_slice(&len);
+        shares.push(parity.into());
+        Ok(shares)
+    }
+
+    /// Read shares of `indexes` from `members`, in parallel.
+    fn read_shares(
+        &self,
+        indexes: &[usize],
+        members: impl Iterator<Item = usize>,
+    ) -> Vec<Vec<io::Result<Bytes>>> {
+        let members: Vec<usize> = members.collect();
+        parallel_map(&members, members.len(), |&i| match &self.members[i] {
+            Some(member) => member.read_batch(indexes),
+            None => indexes.iter().map(|_| Err(offline_error(i))).collect(),
+        })
+    }
+
+    /// Join the shares of entry `index` read from the data members.
+    /// Reconstruct one that failed from the parity member.
+    fn join(&self, index: usize, mut shares: Vec<io::Result<Bytes>>) -> io::Result<Bytes> {
+        for (member, share) in shares.iter_mut().enumerate() {
+            if let Ok(data) = share {
+                if let Err(e) = self.share_len(index, member, data) {
+                    *share = Err(e);
+                }
+            }
+        }
+        let failed: Vec<usize> = (0..shares.len()).filter(|&i| shares[i].is_err()).collect();
+        match failed[..] {
+            [] => {}
+            [member] => {
+                let e = std::mem::replace(&mut shares[member], Ok(Bytes::new())).unwrap_err();
+                shares[member] = Ok(self.reconstruct(index, member, &shares, e)?);
+            }
+            _ => return Err(shares.into_iter().find_map(Result::err).unwrap()),
+        }
+        let shares: Vec<Bytes> = shares.into_iter().map(Result::unwrap).collect();
+        let len = self.share_len(index, 0, &shares[0])?;
+        if shares
+            .iter()
+            .any(|s| s[self.share_size..] != shares[0][self.share_size..])
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("shares of entry {} do not match", index),
+            ));
+        }
+        let mut data = Vec::with_capacity(shares.len() * self.share_size);
+        for share in &shares {
+            data.extend_from_slice(&share[..self.share_size]);
+        }
+        data.truncate(len);
+        Ok(data.into())
+    }
+
+    /// Compute the share of `member`, which failed with `e`, from the other
+    /// data `shares` and the parity.
+    fn reconstruct(
+        &self,
+        index: usize,
+        member: usize,
+        shares: &[io::Result<Bytes>],
+        e: io::Error,
+    ) -> io::Result<Bytes> {
+        let n = self.data_count();
+        let parity = match &self.members[n] {
+            Some(parity) if !self.stale.contains(&index) => parity,
+            _ => return Err(e),
+        };
+        let mut share = match parity.read(index) {
+            Ok(share) if self.share_len(index, n, &share).is_ok() => share.to_vec(),
+            _ => return Err(e),
+        };
+        for (i, other) in shares.iter().enumerate() {
+            if i != member {
+                xor_into(
+                    &mut share[..self.share_size],
+                    &other.as_ref().unwrap()[..self.share_size],
+                );
+            }
+        }
+        if self.members[member].is_some() {
+            log::warn!(
+                "Entry {} reconstructed from parity, member {} failed ({})",
+                index,
+                member + 1,
+                e
+            );
+        }
+        self.reconstructed.fetch_add(1, Ordering::Relaxed);
+        Ok(share.into())
+    }
+
+    /// List `indexes` in the journal before changing them.
+    fn journal_add(&mut self, indexes: impl Iterator<Item = usize>) -> io::Result<()> {
+        let mut journal = self.journal.clone();
+        journal.extend(indexes);
+        if journal.len() > self.journal.len() {
+            if let Some(parity) = self.members.last_mut().unwrap() {
+                save_journal(parity.as_mut(), &journal)?;
+            }
+            self.journal = journal;
+        }
+        Ok(())
+    }
+
+    /// Run `op` on each online member, in parallel.
+    fn on_members<R: Send>(
+        &mut self,
+        ops: Vec<impl FnOnce(&mut dyn IntKv) -> R + Send>,
+    ) -> Vec<Option<R>> {
+        std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .members
+                .iter_mut()
+                .zip(ops)
+                .map(|(member, op)| {
+                    s.spawn(move || member.as_mut().map(|member| op(member.as_mut())))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    }
+}
+
+impl IntKv for ParityIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.read_batch(&[index]).pop().unwrap()
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let mut shares = self.read_shares(indexes, 0..self.data_count());
+        indexes
+            .iter()
+            .enumerate()
+            .map(|(i, &index)| self.join(index, take_column(&mut shares, i)))
+            .collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.write_batch(vec![(index, data)]).1
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let mut batches = vec![Vec::with_capacity(items.len()); self.members.len()];
+        let mut result = Ok(());
+        for (index, data) in &items {
+            match self.encode(*index, data) {
+                Ok(shares) => {
+                    for (batch, share) in batches.iter_mut().zip(shares) {
+                        batch.push((*index, share));
+                    }
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let len = batches[0].len();
+        if let Err(e) = self.journal_add(items[..len].iter().map(|(index, _)| *index)) {
+            return (0, Err(e));
+        }
+        let ops = batches
+            .into_iter()
+            .map(|batch| move |kv: &mut dyn IntKv| kv.write_batch(batch))
+            .collect();
+        // An entry is written once all its shares are.
+        let mut written = len;
+        for (member_written, member_result) in self.on_members(ops).into_iter().flatten() {
+            written = written.min(member_written);
+            if member_result.is_err() && result.is_ok() {
+                result = member_result;
+            }
+        }
+        (written, result)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if !self.has(index)? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.journal_add(Some(index).into_iter())?;
+        for member in self.members.iter_mut().flatten() {
+            remove_if_exists(member.as_mut(), index)?;
+        }
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        // Entries are on all members. Any member answering will do.
+        let mut error = None;
+        let mut answered = false;
+        for member in self.members.iter().flatten() {
+            match member.has(index) {
+                Ok(true) => return Ok(true),
+                Ok(false) => answered = true,
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        match error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(false),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let members: Vec<usize> = (0..self.members.len()).collect();
+        let all = parallel_map(&members, members.len(), |&i| {
+            self.members[i].as_ref().map(|member| member.keys())
+        });
+        let mut keys = BTreeSet::new();
+        let mut errors = Vec::new();
+        for member_keys in all.into_iter().flatten() {
+            match member_keys {
+                Ok(member_keys) => keys.extend(member_keys),
+                Err(e) => errors.push(e),
+            }
+        }
+        // Without one member, the others have the entries.
+        if errors.len() + self.offline().len() > 1 {
+            return Err(errors.pop().unwrap());
+        }
+        keys.remove(&JOURNAL_INDEX);
+        Ok(keys.into_iter().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let ops = (0..self.members.len())
+            .map(|_| |kv: &mut dyn IntKv| kv.flush())
+            .collect();
+        let results: io::Result<()> = self.on_members(ops).into_iter().flatten().collect();
+        results?;
+        // Shares are persisted. Entries no longer need to be listed.
+        if !self.journal.is_empty() {
+            if let Some(parity) = self.members.last_mut().unwrap() {
+                save_journal(parity.as_mut(), &BTreeSet::new())?;
+            }
+            self.journal.clear();
+        }
+        Ok(())
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        for member in self.members[..self.data_count()].iter().flatten() {
+            member.prefetch(indexes);
+        }
+    }
+
+    fn pin(&self, index: usize) {
+        for member in self.members[..self.data_count()].iter().flatten() {
+            member.pin(index);
+        }
+    }
+
+    fn unpin(&self, index: usize) {
+        for member in self.members[..self.data_count()].iter().flatten() {
+            member.unpin(index);
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        for (i, member) in self.members.iter().enumerate() {
+            for (key, value) in member.iter().flat_map(|m| m.stats()) {
+                stats.insert(format!("parity.{}.{}", i, key), value);
+            }
+        }
+        let reconstructed = self.reconstructed.load(Ordering::Relaxed);
+        stats.insert("parity.reconstructed".into(), reconstructed);
+        stats.insert("parity.degraded".into(), self.is_degraded() as u64);
+        stats.insert("parity.stale".into(), self.stale.len() as u64);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        // Each member gets a share of the extra bytes.
+        let share = extra.div_ceil(self.data_count() as u64);
+        for member in self.members.iter().flatten() {
+            member.check_space(share)?;
+        }
+        Ok(())
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        for member in self.members.iter_mut().flatten() {
+            member.compact_step(max_pages)?;
+        }
+        Ok(())
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.members.iter().flatten().all(|m| m.idempotent_writes())
+    }
+}
+
+fn xor_into(dest: &mut [u8], src: &[u8]) {
+    for (d, s) in dest.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn offline_error(member: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("member {} of the parity set is unavailable", member + 1),
+    )
+}
+
+/// Take the results of the `i`-th entry from the results of each member.
+fn take_column(shares: &mut [Vec<io::Result<Bytes>>], i: usize) -> Vec<io::Result<Bytes>> {
+    shares
+        .iter_mut()
+        .map(|s| std::mem::replace(&mut s[i], Ok(Bytes::new())))
+        .collect()
+}
+
+fn remove_if_exists(kv: &mut dyn IntKv, index: usize) -> io::Result<()> {
+    match kv.remove(index) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn read_journal(parity: &dyn IntKv) -> io::Result<BTreeSet<usize>> {
+    let data = match parity.read(JOURNAL_INDEX) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        result => result?,
+    };
+    if data.len() % 8 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the parity journal is corrupted",
+        ));
+    }
+    let journal = data
+        .chunks(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as usize)
+        .collect();
+    Ok(journal)
+}
+
+/// Persist the list of entries being changed before changing them.
+fn save_journal(parity: &mut dyn IntKv, journal: &BTreeSet<usize>) -> io::Result<()> {
+    if journal.is_empty() {
+        remove_if_exists(parity, JOURNAL_INDEX)?;
+    } else {
+        let data: Vec<u8> = journal
+            .iter()
+            .flat_map(|&i| (i as u64).to_le_bytes())
+            .collect();
+        parity.write(JOURNAL_INDEX, data.into())?;
+    }
+    parity.flush()
+}
+
+#[cfg(test)]
+fn mem_parity_kv(n: usize, entry_size: usize) -> ParityIntKv {
+    use super::super::backend::MemIntKv;
+    let data = (0..n)
+        .map(|_| Some(Box::new(MemIntKv::new()) as Box<dyn IntKv>))
+        .collect();
+    ParityIntKv::open(data, Some(Box::new(MemIntKv::new())), entry_size).unwrap()
+}
+
+#[test]
+fn test_parity_int_kv() {
+    super::super::test_int_kv(|kv| kv.unwrap_or_else(|| mem_parity_kv(3, 1 << 17)), 50);
+}
+
+#[test]
+fn test_parity_int_kv_degraded() {
+    use super::super::backend::MemIntKv;
+    let mut kv = mem_parity_kv(2, 100);
+    for i in 0..5 {
+        kv.write(i, vec![i as u8 + 1; 20 * i + 1].into()).unwrap();
+    }
+    kv.flush().unwrap();
+    assert!(kv.mismatches().unwrap().is_empty());
+
+    // Corrupted or missing shares are reconstructed.
+    kv.members[0].as_mut().unwrap().remove(1).unwrap();
+    kv.members[1]
+        .as_mut()
+        .unwrap()
+        .write(2, vec![0; 3].into())
+        .unwrap();
+    assert_eq!(&kv.read(1).unwrap()[..], &[2; 21]);
+    assert_eq!(&kv.read_batch(&[2])[0].as_ref().unwrap()[..], &[3; 41]);
+    assert_eq!(kv.stats()["parity.reconstructed"], 2);
+    assert_eq!(kv.mismatches().unwrap(), [1, 2]);
+    let mut share = kv.members[0].as_ref().unwrap().read(3).unwrap().to_vec();
+    share[0] ^= 1;
+    kv.members[0]
+        .as_mut()
+        .unwrap()
+        .write(3, share.into())
+        .unwrap();
+    assert_eq!(kv.mismatches().unwrap(), [1, 2, 3]);
+
+    // Members lost and replaced are rebuilt, except entries with other
+    // shares corrupted.
+    kv.members[0] = Some(Box::new(MemIntKv::new()));
+    assert_eq!(kv.empty_members().unwrap(), [0]);
+    assert_eq!(&kv.read(4).unwrap()[..], &[5; 81]);
+    assert_eq!(kv.repair(0).unwrap(), 4);
+    kv.write(2, vec![3; 41].into()).unwrap();
+    kv.members[1] = None;
+    assert!(kv.is_degraded());
+    assert_eq!(kv.keys().unwrap(), [0, 1, 2, 3, 4]);
+    for i in 0..5 {
+        assert_eq!(&kv.read(i).unwrap()[..], &vec![i as u8 + 1; 20 * i + 1][..]);
+    }
+    kv.write(5, vec![6; 100].into()).unwrap();
+    kv.remove(0).unwrap();
+    assert_eq!(&kv.read(5).unwrap()[..], &[6; 100]);
+    assert!(kv.write(6, vec![7; 101].into()).is_err());
+}
+
+#[test]
+fn test_parity_int_kv_journal() {
+    let mut kv = mem_parity_kv(2, 100);
+    kv.write(1, vec![1; 60].into()).unwrap();
+    kv.flush().unwrap();
+    // Crash after changing one share.
+    kv.write(1, vec![2; 60].into()).unwrap();
+    kv.write(2, vec![2; 60].into()).unwrap();
+    let ParityIntKv { mut members, .. } = kv;
+    members[1]
+        .as_mut()
+        .unwrap()
+        .write(1, vec![0; 50 + LEN_SIZE].into())
+        .unwrap();
+    members[1].as_mut().unwrap().remove(2).unwrap();
+    let parity = members.pop().unwrap();
+    let mut kv = ParityIntKv::open(members, parity, 100).unwrap();
+    assert_eq!(kv.stale_len(), 2);
+    assert_eq!(kv.recover().unwrap(), 2);
+    assert_eq!(kv.keys().unwrap(), [1]);
+    assert!(kv.mismatches().unwrap().is_empty());
+    assert!(kv.members[2].as_ref().unwrap().keys().unwrap() == [1]);
+}
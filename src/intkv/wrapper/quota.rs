@@ -0,0 +1,402 @@
+//! Bound the number and total size of entries stored below, so a bug above
+//! cannot fill the storage with blocks.
+//!
+//! Usage is kept at `QUOTA_INDEX` as two u64 little-endian numbers, the
+//! entries and the bytes, written on each flush. If it is missing, or the
+//! number of entries does not match `keys()` because the storage was
+//! changed without this layer, usage is recomputed by reading all entries.
+
+use super::super::{Bytes, CacheLimits, Hint, IntKv, Stats};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Reserved index storing the usage.
+const QUOTA_INDEX: usize = usize::MAX - 1;
+
+/// A write would exceed a limit of `QuotaIntKv`. Reported as
+/// `io::ErrorKind::QuotaExceeded`.
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub index: usize,
+    /// "entries" or "bytes".
+    pub limit: &'static str,
+    pub max: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "writing entry {} would exceed the quota of {} {}",
+            self.index, self.max, self.limit
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+#[derive(Debug)]
+pub struct QuotaIntKv {
+    kv: Box<dyn IntKv>,
+    /// Limits. 0 means no limit.
+    max_entries: u64,
+    max_bytes: u64,
+    record_size: usize,
+    entries: u64,
+    bytes: u64,
+    /// Known sizes of entries. Others are read when needed.
+    sizes: BTreeMap<usize, u64>,
+    /// Whether usage changed since it was written.
+    dirty: bool,
+}
+
+impl QuotaIntKv {
+    /// Load or recompute the usage of `kv`. Without limits until set by
+    /// `with_limits`.
+    pub fn open(kv: Box<dyn IntKv>) -> io::Result<Self> {
+        let mut quota = Self {
+            kv,
+            max_entries: 0,
+            max_bytes: 0,
+            record_size: 0,
+            entries: 0,
+            bytes: 0,
+            sizes: BTreeMap::new(),
+            dirty: false,
+        };
+        let keys = quota.keys()?;
+        let usage = match quota.kv.has(QUOTA_INDEX)? {
+            true => {
+                let data = quota.kv.read(QUOTA_INDEX)?;
+                let number = |i: usize| {
+                    data.get(i..i + 8)
+                        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                };
+                number(0).zip(number(8))
+            }
+            false => None,
+        };
+        match usage {
+            Some((entries, bytes)) if entries == keys.len() as u64 => {
+                quota.entries = entries;
+                quota.bytes = bytes;
+            }
+            _ => {
+                let results = quota.kv.read_batch(&keys);
+                for (index, result) in keys.into_iter().zip(results) {
+                    let size = result?.len() as u64;
+                    quota.sizes.insert(index, size);
+                    quota.bytes += size;
+                }
+                quota.entries = quota.sizes.len() as u64;
+                quota.dirty = true;
+            }
+        }
+        Ok(quota)
+    }
+
+    /// Limit the number of entries and their total size. 0 means no limit.
+    pub fn with_limits(mut self, max_entries: u64, max_bytes: u64) -> Self {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Pad the usage record to `size` bytes, for storage expecting entries
+    /// of a fixed size.
+    pub fn with_record_size(mut self, size: usize) -> Self {
+        self.record_size = size;
+        self
+    }
+
+    /// Size of entry `index`, or None if it does not exist.
+    fn size(&mut self, index: usize) -> io::Result<Option<u64>> {
+        if let Some(&size) = self.sizes.get(&index) {
+            return Ok(Some(size));
+        }
+        if !self.kv.has(index)? {
+            return Ok(None);
+        }
+        let size = self.kv.read(index)?.len() as u64;
+        self.sizes.insert(index, size);
+        Ok(Some(size))
+    }
+
+    /// Usage after writing `len` bytes to `index` of size `old`, starting
+    /// from `usage`, if within limits.
+    fn check(
+        &self,
+        usage: (u64, u64),
+        index: usize,
+        old: Option<u64>,
+        len: usize,
+    ) -> io::Result<(u64, u64)> {
+        let (entries, bytes) = usage;
+        let new_entries = entries + old.is_none() as u64;
+        let new_bytes = bytes.saturating_sub(old.unwrap_or(0)) + len as u64;
+        let exceeded = |limit, max| {
+            io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                QuotaExceeded { index, limit, max },
+            )
+        };
+        if self.max_entries > 0 && new_entries > self.max_entries.max(entries) {
+            return Err(exceeded("entries", self.max_entries));
+        }
+        // Shrinking entries is fine even if over the limit.
+        if self.max_bytes > 0 && new_bytes > self.max_bytes.max(bytes) {
+            return Err(exceeded("bytes", self.max_bytes));
+        }
+        Ok((new_entries, new_bytes))
+    }
+
+    fn record(&mut self, index: usize, len: usize, usage: (u64, u64)) {
+        self.sizes.insert(index, len as u64);
+        self.entries = usage.0;
+        self.bytes = usage.1;
+        self.dirty = true;
+    }
+
+    fn write_usage(&mut self) -> io::Result<()> {
+        if self.dirty {
+            let mut data = self.entries.to_le_bytes().to_vec();
+            data.extend_from_slice(&self.bytes.to_le_bytes());
+            data.resize(data.len().max(self.record_size), 0);
+            self.kv.write(QUOTA_INDEX, data.into())?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl IntKv for QuotaIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.kv.read(index)
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        self.kv.read_batch(indexes)
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let (len, old) = (data.len(), self.size(index)?);
+        let usage = self.check((self.entries, self.bytes), index, old, len)?;
+        self.kv.write(index, data)?;
+        self.record(index, len, usage);
+        Ok(())
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let (len, old) = (data.len(), self.size(index)?);
+        let usage = self.check((self.entries, self.bytes), index, old, len)?;
+        self.kv.write_with_hint(index, data, hint)?;
+        self.record(index, len, usage);
+        Ok(())
+    }
+
+    fn write_batch(&mut self, mut items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        // Write items up to the first one over the limits, then report it.
+        let mut usage = (self.entries, self.bytes);
+        let mut pending = BTreeMap::new();
+        let mut usages = Vec::with_capacity(items.len());
+        let mut error = None;
+        for (index, data) in &items {
+            let old = match pending.get(index) {
+                Some(&size) => Ok(Some(size)),
+                None => self.size(*index),
+            };
+            match old.and_then(|old| self.check(usage, *index, old, data.len())) {
+                Ok(next) => {
+                    usage = next;
+                    usages.push(next);
+                    pending.insert(*index, data.len() as u64);
+                }
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        items.truncate(usages.len());
+        let lens: Vec<(usize, usize)> = items.iter().map(|(i, d)| (*i, d.len())).collect();
+        let (written, result) = self.kv.write_batch(items);
+        for (&(index, len), &usage) in lens.iter().zip(&usages).take(written) {
+            self.record(index, len, usage);
+        }
+        match (result, error) {
+            (Err(e), _) | (Ok(()), Some(e)) => (written, Err(e)),
+            (Ok(()), None) => (written, Ok(())),
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let old = self.size(index)?;
+        self.kv.remove(index)?;
+        if let Some(size) = old {
+            self.sizes.remove(&index);
+            self.entries = self.entries.saturating_sub(1);
+            self.bytes = self.bytes.saturating_sub(size);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.kv.has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys = self.kv.keys()?;
+        keys.retain(|&i| i != QUOTA_INDEX);
+        Ok(keys)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_usage()?;
+        self.kv.flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.write_usage()?;
+        let mut keys = keys.to_vec();
+        keys.push(QUOTA_INDEX);
+        self.kv.flush_keys(&keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        stats.insert("quota.entries".into(), self.entries);
+        stats.insert("quota.bytes".into(), self.bytes);
+        stats
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        if self.max_bytes > 0 && self.bytes + extra > self.max_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!(
+                    "writing {} bytes would exceed the quota of {} bytes",
+                    extra, self.max_bytes
+                ),
+            ));
+        }
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.write_usage()?;
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.kv.idempotent_writes()
+    }
+}
+
+#[test]
+fn test_quota_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| QuotaIntKv::open(Box::new(MemIntKv::new())).unwrap()),
+        50,
+    );
+}
+
+#[test]
+fn test_quota_int_kv_limits() {
+    use super::super::backend::MemIntKv;
+    let mut kv = QuotaIntKv::open(Box::new(MemIntKv::new()))
+        .unwrap()
+        .with_limits(3, 100);
+    let usage = |kv: &QuotaIntKv| {
+        let stats = kv.stats();
+        (stats["quota.entries"], stats["quota.bytes"])
+    };
+    kv.write(1, vec![0; 40].into()).unwrap();
+    kv.write(2, vec![0; 40].into()).unwrap();
+    // Overwrites count the difference. The limit can be reached exactly.
+    kv.write(1, vec![0; 10].into()).unwrap();
+    kv.write(3, vec![0; 50].into()).unwrap();
+    assert_eq!(usage(&kv), (3, 100));
+    let e = kv.write(4, vec![].into()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::QuotaExceeded);
+    assert_eq!(
+        e.to_string(),
+        "writing entry 4 would exceed the quota of 3 entries"
+    );
+    let e = kv.write(1, vec![0; 11].into()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::QuotaExceeded);
+    assert!(!kv.has(4).unwrap());
+    assert_eq!(kv.read(1).unwrap().len(), 10);
+    assert!(kv.check_space(1).is_err());
+
+    // Removes free space, and a batch stops at the first item over limits.
+    kv.remove(3).unwrap();
+    assert_eq!(usage(&kv), (2, 50));
+    let items = vec![
+        (1, vec![0; 30].into()),
+        (5, vec![0; 40].into()),
+        (6, vec![].into()),
+    ];
+    let (written, result) = kv.write_batch(items);
+    assert_eq!(written, 1);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+    assert_eq!(usage(&kv), (2, 70));
+    assert!(!kv.has(5).unwrap());
+}
+
+#[test]
+fn test_quota_int_kv_reopen() {
+    use super::super::backend::MemIntKv;
+    let mut kv = QuotaIntKv::open(Box::new(MemIntKv::new()))
+        .unwrap()
+        .with_record_size(64);
+    kv.write(1, vec![0; 10].into()).unwrap();
+    kv.write(2, vec![0; 20].into()).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(kv.keys().unwrap(), [1, 2]);
+    assert_eq!(kv.kv.read(QUOTA_INDEX).unwrap().len(), 64);
+
+    // Usage is reloaded, and sizes are read when needed.
+    let mut kv = QuotaIntKv::open(kv.kv).unwrap();
+    assert!(kv.sizes.is_empty());
+    kv.write(2, vec![0; 5].into()).unwrap();
+    assert_eq!(kv.stats()["quota.bytes"], 15);
+    kv.flush().unwrap();
+
+    // Without the record, or with one not matching the entries, usage is
+    // recomputed.
+    let mut inner = kv.kv;
+    inner.write(3, vec![0; 7].into()).unwrap();
+    let kv = QuotaIntKv::open(inner).unwrap();
+    assert_eq!(kv.stats()["quota.entries"], 3);
+    assert_eq!(kv.stats()["quota.bytes"], 22);
+    let mut inner = kv.kv;
+    inner.remove(QUOTA_INDEX).unwrap();
+    let kv = QuotaIntKv::open(inner).unwrap();
+    assert_eq!(kv.stats()["quota.entries"], 3);
+    assert_eq!(kv.stats()["quota.bytes"], 22);
+}
@@ -0,0 +1,299 @@
+//! Retry operations of a flaky `IntKv`, ex. over the network, failing with
+//! errors that might go away.
+//!
+//! Reads are always retried. Writes, removes and flushes are only retried
+//! if the `IntKv` says repeating them is harmless, see
+//! `IntKv::idempotent_writes`.
+
+use super::super::{Bytes, Hint, IntKv, Stats};
+use rand::Rng;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Longest delay between tries.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct RetryIntKv {
+    kv: Box<dyn IntKv>,
+    backoff: Backoff,
+}
+
+#[derive(Debug)]
+struct Backoff {
+    attempts: u32,
+    delay: Duration,
+    retries: AtomicU64,
+}
+
+/// Whether an operation failing with `e` might succeed if tried again:
+/// interruptions, timeouts and connection errors.
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+impl RetryIntKv {
+    /// Try up to 3 times, waiting 100ms before the first retry.
+    pub fn new(kv: Box<dyn IntKv>) -> Self {
+        let backoff = Backoff {
+            attempts: 3,
+            delay: Duration::from_millis(100),
+            retries: AtomicU64::new(0),
+        };
+        Self { kv, backoff }
+    }
+
+    /// Try up to `attempts` times. The delay before a retry doubles each
+    /// time, and is shortened by up to half at random to spread out clients.
+    pub fn with_retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.backoff.attempts = attempts.max(1);
+        self.backoff.delay = delay;
+        self
+    }
+
+    /// Attempts of operations that change entries.
+    fn write_attempts(&self) -> u32 {
+        match self.kv.idempotent_writes() {
+            true => self.backoff.attempts,
+            false => 1,
+        }
+    }
+}
+
+impl Backoff {
+    /// Run `op` on entry `index` (None: all entries) up to `attempts`
+    /// times, while it fails with transient errors.
+    fn run<T>(
+        &self,
+        name: &str,
+        index: Option<usize>,
+        attempts: u32,
+        mut op: impl FnMut() -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut attempt = 1;
+        let mut delay = self.delay;
+        loop {
+            match op() {
+                Err(e) if is_transient(&e) && attempt < attempts => {
+                    let target = match index {
+                        Some(index) => format!("{} of entry {}", name, index),
+                        None => name.to_string(),
+                    };
+                    log::warn!(
+                        "Retrying {} after {} (attempt {} of {})",
+                        target,
+                        e,
+                        attempt,
+                        attempts
+                    );
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+                    std::thread::sleep(delay.mul_f64(jitter));
+                    delay = (delay * 2).min(MAX_DELAY);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl IntKv for RetryIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let attempts = self.backoff.attempts;
+        self.backoff
+            .run("read", Some(index), attempts, || self.kv.read(index))
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let results = self.kv.read_batch(indexes);
+        indexes
+            .iter()
+            .zip(results)
+            .map(|(&index, result)| match result {
+                Err(e) if is_transient(&e) && self.backoff.attempts > 1 => self.read(index),
+                result => result,
+            })
+            .collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let attempts = self.write_attempts();
+        let kv = &mut self.kv;
+        self.backoff.run("write", Some(index), attempts, || {
+            kv.write(index, data.clone())
+        })
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let attempts = self.write_attempts();
+        let kv = &mut self.kv;
+        self.backoff.run("write", Some(index), attempts, || {
+            kv.write_with_hint(index, data.clone(), hint)
+        })
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let (written, result) = self.kv.write_batch(items.clone());
+        match result {
+            // Entries before the failed one are written. Write the rest one
+            // by one.
+            Err(e) if is_transient(&e) && self.write_attempts() > 1 => {
+                let mut count = written;
+                for (index, data) in items.into_iter().skip(written) {
+                    if let Err(e) = self.write(index, data) {
+                        return (count, Err(e));
+                    }
+                    count += 1;
+                }
+                (count, Ok(()))
+            }
+            result => (written, result),
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let attempts = self.write_attempts();
+        let kv = &mut self.kv;
+        self.backoff
+            .run("remove", Some(index), attempts, || kv.remove(index))
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        let attempts = self.backoff.attempts;
+        self.backoff
+            .run("has", Some(index), attempts, || self.kv.has(index))
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let attempts = self.backoff.attempts;
+        self.backoff.run("keys", None, attempts, || self.kv.keys())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let attempts = self.write_attempts();
+        let kv = &mut self.kv;
+        self.backoff.run("flush", None, attempts, || kv.flush())
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let attempts = self.write_attempts();
+        let kv = &mut self.kv;
+        self.backoff
+            .run("flush", None, attempts, || kv.flush_keys(keys))
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        let retries = self.backoff.retries.load(Ordering::Relaxed);
+        stats.insert("retry.retries".into(), retries);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.kv.idempotent_writes()
+    }
+}
+
+#[test]
+fn test_retry_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| RetryIntKv::new(Box::new(MemIntKv::new()))),
+        50,
+    );
+}
+
+#[test]
+fn test_retry_int_kv_faults() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    let fault_kv = FaultIntKv::new(Box::new(MemIntKv::new()));
+    let faults = fault_kv.faults();
+    let mut kv = RetryIntKv::new(Box::new(fault_kv)).with_retry(3, Duration::from_millis(1));
+
+    // Transient errors are retried, up to 3 tries.
+    faults.error_kind(io::ErrorKind::TimedOut);
+    faults.fail_nth(Op::Write, 1);
+    faults.fail_nth(Op::Write, 2);
+    kv.write(1, vec![1].into()).unwrap();
+    assert_eq!(faults.count(Op::Write), 3);
+    faults.fail_after(&[Op::Read], 0);
+    assert_eq!(kv.read(1).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert_eq!(faults.count(Op::Read), 3);
+    assert_eq!(kv.stats()["retry.retries"], 4);
+    faults.clear();
+
+    // Permanent errors are not.
+    faults.error_kind(io::ErrorKind::PermissionDenied);
+    faults.fail_nth(Op::Remove, 1);
+    assert!(kv.remove(1).is_err());
+    assert_eq!(faults.count(Op::Remove), 1);
+    assert_eq!(&kv.read(1).unwrap()[..], &[1]);
+
+    // A batch continues after the written entries.
+    faults.error_kind(io::ErrorKind::ConnectionReset);
+    faults.fail_nth(Op::Write, 3);
+    let items = (2..6).map(|i| (i, vec![i as u8].into())).collect();
+    let (written, result) = kv.write_batch(items);
+    assert_eq!(written, 4);
+    result.unwrap();
+    assert_eq!(*faults.written.lock(), [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn test_retry_int_kv_not_idempotent() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    let fault_kv = FaultIntKv::new(Box::new(MemIntKv::new())).with_idempotent_writes(false);
+    let faults = fault_kv.faults();
+    let mut kv = RetryIntKv::new(Box::new(fault_kv)).with_retry(3, Duration::from_millis(1));
+    faults.error_kind(io::ErrorKind::TimedOut);
+    faults.fail_nth(Op::Write, 1);
+    faults.fail_nth(Op::Flush, 1);
+    faults.fail_nth(Op::Read, 1);
+    assert!(kv.write(1, vec![1].into()).is_err());
+    assert!(kv.flush().is_err());
+    assert_eq!(faults.count(Op::Write), 1);
+    assert_eq!(faults.count(Op::Flush), 1);
+    kv.write(1, vec![1].into()).unwrap();
+    // Reads are still retried.
+    assert_eq!(&kv.read(1).unwrap()[..], &[1]);
+    assert_eq!(faults.count(Op::Read), 2);
+}
@@ -0,0 +1,162 @@
+//! Spread entries across several `IntKv`s, for example directories on
+//! different disks. Entry `index` belongs to shard `index % N`.
+//!
+//! Errors of a shard fail the operation. Nothing is redirected to another
+//! shard, as it would be lost once the shard is back. Entries a shard holds
+//! but does not own, left by an interrupted rebalance, are ignored.
+
+use super::super::{Bytes, Hint, IntKv, Stats};
+use std::io;
+
+#[derive(Debug)]
+pub struct ShardedIntKv {
+    shards: Vec<Box<dyn IntKv>>,
+}
+
+impl ShardedIntKv {
+    /// Spread entries across `shards`, which must not be empty. The order
+    /// decides which shard owns an entry.
+    pub fn new(shards: Vec<Box<dyn IntKv>>) -> Self {
+        assert!(!shards.is_empty(), "no shards");
+        Self { shards }
+    }
+
+    /// The shard owning entry `index`, out of `count`.
+    pub fn shard_of(index: usize, count: usize) -> usize {
+        index % count
+    }
+
+    fn shard(&self, index: usize) -> &dyn IntKv {
+        &*self.shards[Self::shard_of(index, self.shards.len())]
+    }
+
+    fn shard_mut(&mut self, index: usize) -> &mut Box<dyn IntKv> {
+        let count = self.shards.len();
+        &mut self.shards[Self::shard_of(index, count)]
+    }
+}
+
+impl IntKv for ShardedIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.shard(index).read(index)
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.shard_mut(index).write(index, data)
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        self.shard_mut(index).write_with_hint(index, data, hint)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.shard_mut(index).remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.shard(index).has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let count = self.shards.len();
+        let mut keys = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let owned = shard.keys()?.into_iter();
+            keys.extend(owned.filter(|&index| Self::shard_of(index, count) == i));
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Flush all shards even if one fails, so less is left pending.
+        let mut result = Ok(());
+        for shard in &mut self.shards {
+            let flushed = shard.flush();
+            if result.is_ok() {
+                result = flushed;
+            }
+        }
+        result
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        let count = self.shards.len();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let owned: Vec<usize> = indexes
+                .iter()
+                .cloned()
+                .filter(|&index| Self::shard_of(index, count) == i)
+                .collect();
+            if !owned.is_empty() {
+                shard.prefetch(&owned);
+            }
+        }
+    }
+
+    fn pin(&self, index: usize) {
+        self.shard(index).pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.shard(index).unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            for (key, value) in shard.stats() {
+                stats.insert(format!("sharded.{}.{}", i, key), value);
+            }
+        }
+        stats.insert("sharded.shards".into(), self.shards.len() as u64);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        // Where the extra bytes go is unknown.
+        for shard in &self.shards {
+            shard.check_space(extra)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sharded_int_kv() {
+    use super::super::backend::MemIntKv;
+    let kv = super::super::test_int_kv(
+        |kv| {
+            kv.unwrap_or_else(|| {
+                let shards: Vec<Box<dyn IntKv>> =
+                    (0..3).map(|_| Box::new(MemIntKv::new()) as _).collect();
+                ShardedIntKv::new(shards)
+            })
+        },
+        50,
+    );
+    for (i, shard) in kv.shards.iter().enumerate() {
+        let keys = shard.keys().unwrap();
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|k| k % 3 == i));
+    }
+}
+
+#[test]
+fn test_sharded_int_kv_errors() {
+    use super::super::backend::MemIntKv;
+    use super::{FaultIntKv, Op};
+    let failing = FaultIntKv::new(Box::new(MemIntKv::new()));
+    failing.faults().fail_after(&[Op::Write, Op::Remove], 0);
+    let shards: Vec<Box<dyn IntKv>> = vec![Box::new(MemIntKv::new()), Box::new(failing)];
+    let mut kv = ShardedIntKv::new(shards);
+    kv.write(0, vec![0].into()).unwrap();
+    kv.write(1, vec![1].into()).unwrap_err();
+    assert!(!kv.has(1).unwrap());
+    assert!(matches!(kv.read(1), Err(e) if e.kind() == io::ErrorKind::NotFound));
+
+    // An entry in the wrong shard is not seen.
+    kv.shards[0].write(3, vec![3].into()).unwrap();
+    assert_eq!(kv.keys().unwrap(), vec![0]);
+    assert!(!kv.has(3).unwrap());
+}
@@ -0,0 +1,279 @@
+//! Stripe entries across several `IntKv`s, ex. directories on different
+//! disks, so large transfers use all of them at once. Runs of `width`
+//! consecutive entries go to the members in turn.
+//!
+//! Unlike `ShardedIntKv`, batches, keys and flushes run on all members in
+//! parallel. Each member holds a marker at `MARKER_INDEX` naming the stripe
+//! and its position, checked on open, since mixing up members loses data.
+
+use super::super::{Bytes, IntKv, Stats};
+use crate::util::parallel_map;
+use std::io;
+
+/// Reserved index of the marker in each member.
+const MARKER_INDEX: usize = usize::MAX - 2;
+
+/// Consecutive entries per member, by default.
+pub const DEFAULT_STRIPE_WIDTH: usize = 8;
+
+#[derive(Debug)]
+pub struct StripeIntKv {
+    members: Vec<Box<dyn IntKv>>,
+    width: usize,
+}
+
+impl StripeIntKv {
+    /// Stripe entries across `members` in order, `width` consecutive
+    /// entries each. Write markers to empty members, and check them on the
+    /// others. `marker_size` pads markers for storage expecting entries of
+    /// a fixed size.
+    pub fn open(
+        mut members: Vec<Box<dyn IntKv>>,
+        id: &str,
+        width: usize,
+        marker_size: usize,
+    ) -> io::Result<Self> {
+        assert!(!members.is_empty(), "no members");
+        let width = width.max(1);
+        let count = members.len();
+        for (position, member) in members.iter_mut().enumerate() {
+            let marker = format!("x79d8 stripe {} {} {} {}", id, position, count, width);
+            if member.has(MARKER_INDEX)? {
+                let data = member.read(MARKER_INDEX)?;
+                let found = String::from_utf8_lossy(&data);
+                let found = found.trim_end_matches('\0');
+                if found != marker {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "member {} of the stripe does not match ({:?}, expected {:?})",
+                            position + 1,
+                            found,
+                            marker
+                        ),
+                    ));
+                }
+            } else if member.keys()?.is_empty() {
+                let mut data = marker.into_bytes();
+                data.resize(data.len().max(marker_size), 0);
+                member.write(MARKER_INDEX, data.into())?;
+                member.flush()?;
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("member {} of the stripe has no marker", position + 1),
+                ));
+            }
+        }
+        Ok(Self { members, width })
+    }
+
+    fn member_of(&self, index: usize) -> usize {
+        (index / self.width) % self.members.len()
+    }
+
+    /// Positions in `indexes` of the entries of each member.
+    fn split(&self, indexes: impl Iterator<Item = usize>) -> Vec<Vec<usize>> {
+        let mut positions = vec![Vec::new(); self.members.len()];
+        for (i, index) in indexes.enumerate() {
+            positions[self.member_of(index)].push(i);
+        }
+        positions
+    }
+}
+
+impl IntKv for StripeIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.members[self.member_of(index)].read(index)
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let positions = self.split(indexes.iter().cloned());
+        let jobs: Vec<(usize, Vec<usize>)> = positions.into_iter().enumerate().collect();
+        let batches = parallel_map(&jobs, jobs.len(), |(member, positions)| {
+            let owned: Vec<usize> = positions.iter().map(|&i| indexes[i]).collect();
+            match owned.is_empty() {
+                true => Vec::new(),
+                false => self.members[*member].read_batch(&owned),
+            }
+        });
+        let mut results: Vec<Option<io::Result<Bytes>>> = indexes.iter().map(|_| None).collect();
+        for ((_, positions), batch) in jobs.iter().zip(batches) {
+            for (&i, result) in positions.iter().zip(batch) {
+                results[i] = Some(result);
+            }
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let member = self.member_of(index);
+        self.members[member].write(index, data)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let positions = self.split(items.iter().map(|(index, _)| *index));
+        let len = items.len();
+        let mut items: Vec<Option<(usize, Bytes)>> = items.into_iter().map(Some).collect();
+        let batches: Vec<(Vec<usize>, Vec<_>)> = positions
+            .into_iter()
+            .map(|positions| {
+                let batch = positions
+                    .iter()
+                    .map(|&i| items[i].take().unwrap())
+                    .collect();
+                (positions, batch)
+            })
+            .collect();
+        // Members write their items in order. Report the first item, in
+        // the order given, that was not written.
+        let results: Vec<(usize, io::Result<()>, Vec<usize>)> = std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .members
+                .iter_mut()
+                .zip(batches)
+                .map(|(member, (positions, batch))| {
+                    s.spawn(move || match batch.is_empty() {
+                        true => (0, Ok(()), positions),
+                        false => {
+                            let (written, result) = member.write_batch(batch);
+                            (written, result, positions)
+                        }
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        let mut first_failed = (len, Ok(()));
+        for (written, result, positions) in results {
+            if let (Err(e), Some(&i)) = (result, positions.get(written)) {
+                if i < first_failed.0 {
+                    first_failed = (i, Err(e));
+                }
+            }
+        }
+        first_failed
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let member = self.member_of(index);
+        self.members[member].remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.members[self.member_of(index)].has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let members: Vec<usize> = (0..self.members.len()).collect();
+        let all = parallel_map(&members, members.len(), |&i| self.members[i].keys());
+        let mut keys = Vec::new();
+        for (i, member_keys) in all.into_iter().enumerate() {
+            let member_keys = member_keys?.into_iter();
+            keys.extend(
+                member_keys.filter(|&index| index != MARKER_INDEX && self.member_of(index) == i),
+            );
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Wait for all members even if one fails, so less is left pending.
+        let results: Vec<io::Result<()>> = std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .members
+                .iter_mut()
+                .map(|member| s.spawn(move || member.flush()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        results.into_iter().collect()
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        for (member, positions) in self.split(indexes.iter().cloned()).into_iter().enumerate() {
+            if !positions.is_empty() {
+                let owned: Vec<usize> = positions.iter().map(|&i| indexes[i]).collect();
+                self.members[member].prefetch(&owned);
+            }
+        }
+    }
+
+    fn pin(&self, index: usize) {
+        self.members[self.member_of(index)].pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.members[self.member_of(index)].unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        for (i, member) in self.members.iter().enumerate() {
+            for (key, value) in member.stats() {
+                stats.insert(format!("stripe.{}.{}", i, key), value);
+            }
+        }
+        stats.insert("stripe.members".into(), self.members.len() as u64);
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        // The extra bytes are spread across members.
+        let share = extra.div_ceil(self.members.len() as u64);
+        for member in &self.members {
+            member.check_space(share)?;
+        }
+        Ok(())
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.members.iter().all(|m| m.idempotent_writes())
+    }
+}
+
+#[cfg(test)]
+fn mem_members(n: usize) -> Vec<Box<dyn IntKv>> {
+    use super::super::backend::MemIntKv;
+    (0..n)
+        .map(|_| Box::new(MemIntKv::new()) as Box<dyn IntKv>)
+        .collect()
+}
+
+#[test]
+fn test_stripe_int_kv() {
+    super::super::test_int_kv(
+        |kv| kv.unwrap_or_else(|| StripeIntKv::open(mem_members(3), "t", 2, 0).unwrap()),
+        50,
+    );
+}
+
+#[test]
+fn test_stripe_int_kv_markers() {
+    let mut kv = StripeIntKv::open(mem_members(3), "t", 2, 16).unwrap();
+    let items = (0..12).map(|i| (i, vec![i as u8].into())).collect();
+    let (written, result) = kv.write_batch(items);
+    assert_eq!(written, 12);
+    result.unwrap();
+    kv.flush().unwrap();
+    // Runs of 2 entries go to the members in turn.
+    assert_eq!(kv.members[1].keys().unwrap(), [2, 3, 8, 9, MARKER_INDEX]);
+    assert_eq!(kv.members[2].read(MARKER_INDEX).unwrap().len(), 20);
+    assert_eq!(kv.keys().unwrap(), (0..12).collect::<Vec<_>>());
+    let results = kv.read_batch(&[11, 0, 5]);
+    let data: Vec<u8> = results.into_iter().map(|r| r.unwrap()[0]).collect();
+    assert_eq!(data, [11, 0, 5]);
+
+    let StripeIntKv { mut members, .. } = kv;
+    members.swap(0, 1);
+    let err = StripeIntKv::open(members, "t", 2, 0).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("member 1 of the stripe does not match"));
+
+    let mut members = mem_members(2);
+    members[1].write(0, vec![0].into()).unwrap();
+    let err = StripeIntKv::open(members, "t", 2, 0).unwrap_err();
+    assert_eq!(err.to_string(), "member 2 of the stripe has no marker");
+}
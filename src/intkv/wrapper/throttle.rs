@@ -0,0 +1,263 @@
+//! Cap the bandwidth of reads and writes, so a large transfer does not
+//! starve other programs using the same disk.
+//!
+//! Each direction is a token bucket refilled at the limit, holding up to
+//! `burst` bytes. Writes take their bytes first, reads once they return,
+//! as their size is unknown before. Either may take more than is left; the
+//! next operation in that direction waits until the debt is paid, by
+//! sleeping in the calling thread.
+
+use super::super::{Bytes, Hint, IntKv, Stats};
+use parking_lot::Mutex;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second. 0: no limit.
+    rate: u64,
+    burst: u64,
+    /// Negative after taking more than available.
+    tokens: f64,
+    refilled: Instant,
+    /// Total time spent waiting.
+    waited: Duration,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: u64) -> Self {
+        let burst = match burst {
+            0 => rate,
+            n => n,
+        };
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            refilled: Instant::now(),
+            waited: Duration::ZERO,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.refilled = now;
+    }
+
+    /// Take `bytes`, then return how long to wait until the bucket is not
+    /// in debt.
+    fn take(&mut self, bytes: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        self.tokens -= bytes as f64;
+        let wait = match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate as f64),
+            false => Duration::ZERO,
+        };
+        self.waited += wait;
+        wait
+    }
+}
+
+#[derive(Debug)]
+pub struct ThrottleIntKv {
+    kv: Box<dyn IntKv>,
+    read: Mutex<Bucket>,
+    write: Mutex<Bucket>,
+}
+
+/// Sleep without stalling other tasks if called from the async runtime,
+/// which must be multi-threaded, as `serve` uses.
+fn sleep(duration: Duration) {
+    if duration.is_zero() {
+        return;
+    }
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => tokio::task::block_in_place(|| std::thread::sleep(duration)),
+        Err(_) => std::thread::sleep(duration),
+    }
+}
+
+impl ThrottleIntKv {
+    /// Forward to `kv` without limits.
+    pub fn new(kv: Box<dyn IntKv>) -> Self {
+        Self {
+            kv,
+            read: Mutex::new(Bucket::new(0, 0)),
+            write: Mutex::new(Bucket::new(0, 0)),
+        }
+    }
+
+    /// Limit reads and writes to the given bytes per second (0: no limit).
+    /// Up to `burst` bytes pass at once after being idle (0: a second of
+    /// the limit).
+    pub fn with_limits(self, read: u64, write: u64, burst: u64) -> Self {
+        Self {
+            read: Mutex::new(Bucket::new(read, burst)),
+            write: Mutex::new(Bucket::new(write, burst)),
+            ..self
+        }
+    }
+
+    fn wait_read(&self) {
+        let wait = self.read.lock().take(0);
+        sleep(wait);
+    }
+
+    fn took_read(&self, bytes: usize) {
+        // Paid by the next read, so the current one returns now.
+        self.read.lock().take(bytes as u64);
+    }
+
+    fn wait_write(&self, bytes: usize) {
+        let wait = self.write.lock().take(bytes as u64);
+        sleep(wait);
+    }
+}
+
+impl IntKv for ThrottleIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.wait_read();
+        let result = self.kv.read(index);
+        self.took_read(result.as_ref().map_or(0, |data| data.len()));
+        result
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        self.wait_read();
+        let results = self.kv.read_batch(indexes);
+        let sizes = results
+            .iter()
+            .map(|r| r.as_ref().map_or(0, |data| data.len()));
+        self.took_read(sizes.sum());
+        results
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.wait_write(data.len());
+        self.kv.write(index, data)
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        self.wait_write(data.len());
+        self.kv.write_with_hint(index, data, hint)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        self.wait_write(items.iter().map(|(_, data)| data.len()).sum());
+        self.kv.write_batch(items)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        self.kv.remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        self.kv.has(index)
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        self.kv.keys()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.kv.flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        self.kv.flush_keys(keys)
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        for (name, bucket) in [("read", &self.read), ("write", &self.write)] {
+            let mut bucket = bucket.lock();
+            bucket.refill();
+            stats.insert(format!("throttle.{}_limit", name), bucket.rate);
+            stats.insert(format!("throttle.{}_burst", name), bucket.burst);
+            stats.insert(format!("throttle.{}_tokens", name), bucket.tokens as u64);
+            let waited = bucket.waited.as_micros() as u64;
+            stats.insert(format!("throttle.{}_waited_micros", name), waited);
+        }
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.kv.idempotent_writes()
+    }
+}
+
+#[test]
+fn test_throttle_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| {
+            kv.unwrap_or_else(|| {
+                ThrottleIntKv::new(Box::new(MemIntKv::new())).with_limits(0, 1 << 30, 0)
+            })
+        },
+        50,
+    );
+}
+
+#[test]
+fn test_throttle_int_kv_throughput() {
+    use super::super::backend::MemIntKv;
+    const LIMIT: u64 = 1 << 20;
+    const BURST: u64 = 64 << 10;
+    const SIZE: usize = 16 << 10;
+    let mut kv = ThrottleIntKv::new(Box::new(MemIntKv::new())).with_limits(LIMIT, LIMIT, BURST);
+
+    // Beyond the burst, writes run at the limit.
+    let start = Instant::now();
+    for i in 0..24 {
+        kv.write(i, vec![0; SIZE].into()).unwrap();
+    }
+    let rate = (24 * SIZE as u64 - BURST) as f64 / start.elapsed().as_secs_f64();
+    assert!((rate / LIMIT as f64 - 1.0).abs() < 0.1, "{}", rate);
+
+    // Reads are limited independently. The bucket is full again after
+    // waiting.
+    std::thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    for i in 0..24 {
+        kv.read(i).unwrap();
+    }
+    // The last read is paid by the next one.
+    let rate = (23 * SIZE as u64 - BURST) as f64 / start.elapsed().as_secs_f64();
+    assert!((rate / LIMIT as f64 - 1.0).abs() < 0.1, "{}", rate);
+
+    let stats = kv.stats();
+    assert_eq!(stats["throttle.read_limit"], LIMIT);
+    assert!(stats["throttle.write_waited_micros"] > 250_000);
+    assert!(stats["throttle.write_tokens"] <= BURST);
+}
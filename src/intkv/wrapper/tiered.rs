@@ -0,0 +1,450 @@
+//! Local cache tier in front of a slow remote `IntKv`.
+//!
+//! Reads check the local tier first, and copy entries missing there from
+//! the remote. Changes go to the local tier and are queued for upload.
+//! `flush()` uploads the queue, then flushes the remote. With
+//! `with_write_through()`, changes are sent to the remote right away
+//! instead, and `flush()` only flushes both.
+//!
+//! Queued entries are recorded in a journal before the local tier is
+//! flushed. On open, journaled removals are queued again, and so are
+//! journaled writes if the local tier has them. A flush interrupted by a crash
+//! may leave some of its changes applied to the remote, like the remote
+//! backends themselves.
+//!
+//! The local tier holds up to `with_cache_size_limit()` bytes beyond
+//! queued entries. The least recently used entries are evicted.
+
+use super::super::{Bytes, IntKv, Stats};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Default limit of the local tier.
+const DEFAULT_CACHE_SIZE_LIMIT: u64 = 1 << 30;
+
+#[derive(Debug)]
+pub struct TieredIntKv {
+    local: Mutex<Local>,
+    remote: Box<dyn IntKv>,
+    /// Changes not yet flushed to the remote. True if written, false if
+    /// removed.
+    queue: BTreeMap<usize, bool>,
+    journal_path: PathBuf,
+    /// `queue` differs from the journal.
+    journal_dirty: bool,
+    write_through: bool,
+    cache_size_limit: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// The local tier, with the sizes and use order of entries.
+#[derive(Debug)]
+struct Local {
+    kv: Box<dyn IntKv>,
+    /// Size and last use of entries.
+    entries: HashMap<usize, (u64, u64)>,
+    /// Entries by last use.
+    order: BTreeMap<u64, usize>,
+    tick: u64,
+    size: u64,
+}
+
+impl TieredIntKv {
+    /// Cache `remote` in `local`. `journal_path` records queued entries,
+    /// and should be next to the local tier.
+    pub fn new(
+        local: Box<dyn IntKv>,
+        remote: Box<dyn IntKv>,
+        journal_path: &Path,
+    ) -> io::Result<Self> {
+        let mut local = Local {
+            kv: local,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+        };
+        let journal = read_journal(journal_path)?;
+        let journaled: HashMap<usize, bool> = journal.iter().cloned().collect();
+        for index in local.kv.keys()? {
+            match local.kv.read(index) {
+                Ok(data) => local.touch(index, data.len() as u64),
+                Err(e) if !journaled.contains_key(&index) => {
+                    log::warn!("Dropping entry {} of the local tier: {}", index, e);
+                    local.kv.remove(index)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let mut queue = BTreeMap::new();
+        for (index, written) in journal {
+            if local.entries.contains_key(&index) {
+                queue.insert(index, true);
+            } else if !written {
+                queue.insert(index, false);
+            }
+        }
+        if !queue.is_empty() {
+            log::info!("{} changes are waiting for upload", queue.len());
+        }
+        Ok(Self {
+            local: Mutex::new(local),
+            remote,
+            queue,
+            journal_path: journal_path.to_path_buf(),
+            journal_dirty: true,
+            write_through: false,
+            cache_size_limit: DEFAULT_CACHE_SIZE_LIMIT,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        })
+    }
+
+    /// Bytes of entries kept locally, not counting queued entries.
+    pub fn with_cache_size_limit(mut self, limit: u64) -> Self {
+        self.cache_size_limit = limit;
+        self
+    }
+
+    /// Send changes to the remote at once instead of on `flush()`.
+    pub fn with_write_through(mut self, write_through: bool) -> Self {
+        self.write_through = write_through;
+        self
+    }
+
+    fn save_journal(&mut self) -> io::Result<()> {
+        if !self.journal_dirty {
+            return Ok(());
+        }
+        if self.queue.is_empty() {
+            match fs::remove_file(&self.journal_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        } else {
+            let text: String = self
+                .queue
+                .iter()
+                .map(|(index, &written)| format!("{} {}\n", if written { 'w' } else { 'r' }, index))
+                .collect();
+            let tmp_path = self.journal_path.with_extension("tmp");
+            fs::write(&tmp_path, text)?;
+            fs::File::open(&tmp_path)?.sync_all()?;
+            fs::rename(&tmp_path, &self.journal_path)?;
+        }
+        self.journal_dirty = false;
+        Ok(())
+    }
+
+    /// Send queued changes to the remote.
+    fn upload(&mut self) -> io::Result<()> {
+        let mut items = Vec::new();
+        for (&index, &written) in &self.queue {
+            if written {
+                items.push((index, self.local.lock().kv.read(index)?));
+            } else {
+                match self.remote.remove(index) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+        }
+        self.remote.write_batch(items).1
+    }
+}
+
+impl Local {
+    fn touch(&mut self, index: usize, size: u64) {
+        self.forget(index);
+        self.tick += 1;
+        self.entries.insert(index, (size, self.tick));
+        self.order.insert(self.tick, index);
+        self.size += size;
+    }
+
+    fn forget(&mut self, index: usize) {
+        if let Some((size, tick)) = self.entries.remove(&index) {
+            self.order.remove(&tick);
+            self.size -= size;
+        }
+    }
+
+    /// Remove the least recently used entries not in `queue`, until
+    /// entries not in `queue` fit in `limit` bytes. Return the number of
+    /// entries removed.
+    fn evict(&mut self, limit: u64, queue: &BTreeMap<usize, bool>) -> io::Result<u64> {
+        let queued: u64 = queue
+            .keys()
+            .filter_map(|index| self.entries.get(index))
+            .map(|&(size, _)| size)
+            .sum();
+        let mut count = 0;
+        while self.size > limit + queued {
+            let oldest = self.order.values().find(|i| !queue.contains_key(i));
+            let index = match oldest {
+                Some(&index) => index,
+                None => break,
+            };
+            self.kv.remove(index)?;
+            self.forget(index);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl IntKv for TieredIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        if self.queue.get(&index) == Some(&false) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let mut local = self.local.lock();
+        if local.entries.contains_key(&index) {
+            match local.kv.read(index) {
+                Ok(data) => {
+                    local.touch(index, data.len() as u64);
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(data);
+                }
+                Err(e) if self.queue.contains_key(&index) => return Err(e),
+                Err(e) => {
+                    log::warn!("Cannot read entry {} from the local tier: {}", index, e);
+                    local.forget(index);
+                }
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = self.remote.read(index)?;
+        // Fails if the local tier is read-only. Reads still work.
+        match local.kv.write(index, data.clone()) {
+            Ok(()) => {
+                local.touch(index, data.len() as u64);
+                let count = local.evict(self.cache_size_limit, &self.queue)?;
+                self.evictions.fetch_add(count, Ordering::Relaxed);
+            }
+            Err(e) => log::debug!("Cannot cache entry {}: {}", index, e),
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let size = data.len() as u64;
+        if self.write_through {
+            self.remote.write(index, data.clone())?;
+        }
+        let local = self.local.get_mut();
+        local.kv.write(index, data)?;
+        local.touch(index, size);
+        self.queue.insert(index, true);
+        self.journal_dirty = true;
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if !self.has(index)? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if self.write_through {
+            match self.remote.remove(index) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        let local = self.local.get_mut();
+        if local.entries.contains_key(&index) {
+            local.kv.remove(index)?;
+            local.forget(index);
+        }
+        self.queue.insert(index, false);
+        self.journal_dirty = true;
+        Ok(())
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        match self.queue.get(&index) {
+            Some(&written) => Ok(written),
+            None if self.local.lock().entries.contains_key(&index) => Ok(true),
+            None => self.remote.has(index),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys: std::collections::BTreeSet<usize> = self.remote.keys()?.into_iter().collect();
+        for (&index, &written) in &self.queue {
+            match written {
+                true => keys.insert(index),
+                false => keys.remove(&index),
+            };
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Recorded before the local tier, so no change is missed.
+        self.save_journal()?;
+        self.local.get_mut().kv.flush()?;
+        if !self.write_through {
+            self.upload()?;
+        }
+        self.remote.flush()?;
+        if !self.queue.is_empty() {
+            self.queue.clear();
+            self.journal_dirty = true;
+        }
+        let local = self.local.get_mut();
+        let count = local.evict(self.cache_size_limit, &self.queue)?;
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+        local.kv.flush()?;
+        self.save_journal()
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.remote.prefetch(indexes)
+    }
+
+    fn stats(&self) -> Stats {
+        let local = self.local.lock();
+        let mut stats = self.remote.stats();
+        for (key, value) in local.kv.stats() {
+            stats.insert(format!("tiered.local.{}", key), value);
+        }
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        stats.insert("tiered.queue".into(), self.queue.len() as u64);
+        stats.insert("tiered.cached_bytes".into(), local.size);
+        stats.insert("tiered.hits".into(), load(&self.hits));
+        stats.insert("tiered.misses".into(), load(&self.misses));
+        stats.insert("tiered.evictions".into(), load(&self.evictions));
+        stats
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.local.lock().kv.check_space(extra)?;
+        self.remote.check_space(extra)
+    }
+}
+
+/// Read the journal: indexes, and whether they were written or removed.
+fn read_journal(path: &Path) -> io::Result<Vec<(usize, bool)>> {
+    let text = match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result?,
+    };
+    text.lines()
+        .map(|line| {
+            let entry = match line.split_once(' ') {
+                Some(("w", index)) => index.parse().ok().map(|i| (i, true)),
+                Some(("r", index)) => index.parse().ok().map(|i| (i, false)),
+                _ => None,
+            };
+            entry.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is corrupted", path.display()),
+                )
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_tiered_int_kv() {
+    use super::super::backend::FsIntKv;
+    for write_through in [false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let (local, remote) = (dir.path().join("local"), dir.path().join("remote"));
+        fs::create_dir(&local).unwrap();
+        fs::create_dir(&remote).unwrap();
+        let journal_path = dir.path().join("journal");
+        let reload = |kv: Option<TieredIntKv>| {
+            drop(kv);
+            let local = FsIntKv::new(&local).unwrap();
+            let remote = FsIntKv::new(&remote).unwrap();
+            TieredIntKv::new(Box::new(local), Box::new(remote), &journal_path)
+                .unwrap()
+                .with_cache_size_limit(10000)
+                .with_write_through(write_through)
+        };
+        let kv = super::super::test_int_kv(reload, 30);
+        assert!(kv.stats()["tiered.cached_bytes"] <= 10000);
+        assert!(!journal_path.exists());
+    }
+}
+
+#[test]
+fn test_tiered_int_kv_journal() {
+    use super::super::backend::FsIntKv;
+    use super::{FaultIntKv, Op};
+    let dir = tempfile::tempdir().unwrap();
+    let (local, remote) = (dir.path().join("local"), dir.path().join("remote"));
+    fs::create_dir(&local).unwrap();
+    fs::create_dir(&remote).unwrap();
+    let journal_path = dir.path().join("journal");
+    let open = |budget: usize| {
+        let local = FsIntKv::new(&local).unwrap();
+        let remote = FaultIntKv::new(Box::new(FsIntKv::new(&remote).unwrap()));
+        remote.faults().fail_after(&[Op::Write, Op::Remove], budget);
+        TieredIntKv::new(Box::new(local), Box::new(remote), &journal_path).unwrap()
+    };
+    let mut kv = open(usize::MAX);
+    kv.write(1, b"a".to_vec().into()).unwrap();
+    kv.write(2, b"b".to_vec().into()).unwrap();
+    kv.flush().unwrap();
+
+    // Uploads fail after the local tier is flushed.
+    let mut kv = open(0);
+    kv.remove(1).unwrap();
+    kv.write(3, b"c".to_vec().into()).unwrap();
+    assert_eq!(kv.stats()["tiered.queue"], 2);
+    kv.flush().unwrap_err();
+    drop(kv);
+    let remote_keys = || FsIntKv::new(&remote).unwrap().keys().unwrap();
+    assert_eq!(remote_keys(), [1, 2]);
+
+    let mut kv = open(usize::MAX);
+    assert_eq!(kv.stats()["tiered.queue"], 2);
+    assert_eq!(kv.keys().unwrap(), [2, 3]);
+    kv.flush().unwrap();
+    drop(kv);
+    assert_eq!(remote_keys(), [2, 3]);
+    assert!(!journal_path.exists());
+}
+
+#[test]
+fn test_tiered_int_kv_eviction() {
+    use super::super::backend::{FsIntKv, MemIntKv};
+    let dir = tempfile::tempdir().unwrap();
+    let mut remote = MemIntKv::new();
+    for i in 0..5 {
+        remote.insert(i, vec![i as u8; 40].into());
+    }
+    let local = FsIntKv::new(dir.path()).unwrap();
+    let journal_path = dir.path().join("journal");
+    let mut kv = TieredIntKv::new(Box::new(local), Box::new(remote), &journal_path)
+        .unwrap()
+        .with_cache_size_limit(100);
+    for i in [0, 1, 0, 2, 0, 3, 0] {
+        assert_eq!(kv.read(i).unwrap().as_ref(), &[i as u8; 40]);
+    }
+    let stats = kv.stats();
+    assert_eq!(stats["tiered.hits"], 3);
+    assert_eq!(stats["tiered.misses"], 4);
+    assert_eq!(stats["tiered.evictions"], 2);
+    assert_eq!(stats["tiered.cached_bytes"], 80);
+
+    // Queued entries are kept until uploaded.
+    kv = kv.with_cache_size_limit(0);
+    kv.write(10, vec![1; 40].into()).unwrap();
+    kv.write(11, vec![1; 40].into()).unwrap();
+    kv.read(4).unwrap();
+    assert_eq!(kv.stats()["tiered.cached_bytes"], 80);
+    kv.flush().unwrap();
+    assert_eq!(kv.stats()["tiered.cached_bytes"], 0);
+    assert_eq!(kv.read(10).unwrap().as_ref(), &[1; 40]);
+}
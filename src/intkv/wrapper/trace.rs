@@ -0,0 +1,419 @@
+//! Record operations on an `IntKv` to a file, so problems seen by users
+//! can be replayed locally by `replay`.
+//!
+//! A trace starts with `MAGIC`, then a `Header` and a `Record` for each
+//! operation once it returns, each written as a u32 big-endian length and
+//! the bincode of it. Data is identified by the first 16 bytes of its
+//! BLAKE2s hash, and only included if the trace was created with `full`.
+
+use super::super::backend::MemIntKv;
+use super::super::{Bytes, CacheLimits, Hint, IntKv, Stats};
+use super::PageIntKv;
+use crate::util::{bincode_deserialize, bincode_serialize_pad};
+use blake2::{Blake2s, Digest};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 4] = *b"x8tr";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TraceOp {
+    Read,
+    Write,
+    Remove,
+    Has,
+    Keys,
+    Flush,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    /// Page size of `PageIntKv` to replay on. 0: entries are stored as is.
+    page_size: u64,
+    full: bool,
+    /// Entries when tracing started. Replays of traces started on existing
+    /// entries add them as they are read.
+    entries: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub op: TraceOp,
+    /// 0 for `keys` and `flush`.
+    pub index: u64,
+    /// Size of the data read or written, number of keys, or whether `has`
+    /// found the entry.
+    pub len: u64,
+    pub digest: [u8; 16],
+    pub ok: bool,
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct TraceIntKv {
+    kv: Box<dyn IntKv>,
+    full: bool,
+    /// None after failing to write the trace.
+    out: Mutex<Option<BufWriter<fs::File>>>,
+}
+
+fn digest(data: &[u8]) -> [u8; 16] {
+    let mut digest = [0; 16];
+    digest.copy_from_slice(&Blake2s::digest(data)[..16]);
+    digest
+}
+
+impl TraceIntKv {
+    /// Trace operations on `kv` to a new file at `path`, to be replayed on
+    /// a `PageIntKv` of `page_size` (0: without pages). Include data if
+    /// `full`.
+    pub fn create(kv: Box<dyn IntKv>, path: &Path, page_size: u64, full: bool) -> io::Result<Self> {
+        let entries = kv.keys()?.len() as u64;
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        out.write_all(&MAGIC)?;
+        let header = Header {
+            page_size,
+            full,
+            entries,
+        };
+        write_item(&mut out, &header)?;
+        out.flush()?;
+        Ok(Self {
+            kv,
+            full,
+            out: Mutex::new(Some(out)),
+        })
+    }
+
+    /// Log `op` on `index` with the data read or written, if any.
+    fn log(&self, op: TraceOp, index: usize, data: Option<&[u8]>, ok: bool) {
+        let record = Record {
+            op,
+            index: index as u64,
+            len: data.map_or(0, |d| d.len() as u64),
+            digest: data.map_or([0; 16], digest),
+            ok,
+            data: data.filter(|_| self.full).map(|d| d.to_vec()),
+        };
+        self.log_record(&record);
+    }
+
+    fn log_record(&self, record: &Record) {
+        let mut out = self.out.lock();
+        if let Some(writer) = out.as_mut() {
+            let result = write_item(writer, record);
+            let result = match record.op {
+                TraceOp::Flush => result.and_then(|()| writer.flush()),
+                _ => result,
+            };
+            if let Err(e) = result {
+                log::warn!("Cannot write trace: {}. Stopped tracing.", e);
+                *out = None;
+            }
+        }
+    }
+}
+
+impl IntKv for TraceIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        let result = self.kv.read(index);
+        let data = result.as_ref().ok().map(|d| d.as_ref());
+        self.log(TraceOp::Read, index, data, result.is_ok());
+        result
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        let results = self.kv.read_batch(indexes);
+        for (&index, result) in indexes.iter().zip(&results) {
+            let data = result.as_ref().ok().map(|d| d.as_ref());
+            self.log(TraceOp::Read, index, data, result.is_ok());
+        }
+        results
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        let result = self.kv.write(index, data.clone());
+        self.log(TraceOp::Write, index, Some(&data), result.is_ok());
+        result
+    }
+
+    fn write_with_hint(&mut self, index: usize, data: Bytes, hint: Hint) -> io::Result<()> {
+        let result = self.kv.write_with_hint(index, data.clone(), hint);
+        self.log(TraceOp::Write, index, Some(&data), result.is_ok());
+        result
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let (written, result) = self.kv.write_batch(items.clone());
+        // Items after the failed one were not attempted.
+        for (i, (index, data)) in items.iter().enumerate().take(written + 1) {
+            self.log(TraceOp::Write, *index, Some(data), i < written);
+        }
+        (written, result)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let result = self.kv.remove(index);
+        self.log(TraceOp::Remove, index, None, result.is_ok());
+        result
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        let result = self.kv.has(index);
+        let len = matches!(result, Ok(true)) as u64;
+        self.log_record(&Record {
+            op: TraceOp::Has,
+            index: index as u64,
+            len,
+            digest: [0; 16],
+            ok: result.is_ok(),
+            data: None,
+        });
+        result
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let result = self.kv.keys();
+        self.log_record(&Record {
+            op: TraceOp::Keys,
+            index: 0,
+            len: result.as_ref().map_or(0, |keys| keys.len() as u64),
+            digest: [0; 16],
+            ok: result.is_ok(),
+            data: None,
+        });
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.kv.flush();
+        self.log(TraceOp::Flush, 0, None, result.is_ok());
+        result
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let result = self.kv.flush_keys(keys);
+        self.log(TraceOp::Flush, 0, None, result.is_ok());
+        result
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        self.kv.stats()
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        self.kv.check_space(extra)
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.kv.idempotent_writes()
+    }
+}
+
+/// Replay the trace at `path` on a new `PageIntKv` in memory. Operations
+/// that failed when traced are skipped. After each flush, check the pages.
+/// Reads are compared with the last write, both as traced and as replayed.
+///
+/// Return the number of operations, or an error naming the first one that
+/// went wrong.
+pub fn replay(path: &Path) -> io::Result<usize> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut input = io::BufReader::new(fs::File::open(path)?);
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    let header: Option<Header> = match magic == MAGIC {
+        true => read_item(&mut input)?,
+        false => None,
+    };
+    let header = header.ok_or_else(|| invalid(format!("{} is not a trace", path.display())))?;
+    let mut page_kv = match header.page_size {
+        0 => None,
+        page_size => Some(PageIntKv::new(page_size, Box::new(MemIntKv::new()))?),
+    };
+    let mut mem_kv = MemIntKv::new();
+    // Only complete if the trace started without entries.
+    let complete = header.entries == 0;
+    // Entries as traced. Entries existing before tracing are added when
+    // first read.
+    let mut traced: BTreeMap<u64, [u8; 16]> = BTreeMap::new();
+    let mut removed: BTreeSet<u64> = BTreeSet::new();
+    let mut count = 0;
+    while let Some(record) = read_item::<Record>(&mut input)? {
+        count += 1;
+        if !record.ok {
+            continue;
+        }
+        let kv: &mut dyn IntKv = match page_kv.as_mut() {
+            Some(page_kv) => page_kv,
+            None => &mut mem_kv,
+        };
+        let fail = |e: String| invalid(format!("operation {} ({:?}): {}", count, record.op, e));
+        let index = record.index as usize;
+        let data = || match &record.data {
+            Some(data) => data.clone(),
+            // Any data of the same size takes the same space.
+            None => vec![record.index as u8; record.len as usize],
+        };
+        let existed = !complete && !removed.contains(&record.index);
+        match record.op {
+            TraceOp::Write => {
+                kv.write(index, data().into())
+                    .map_err(|e| fail(e.to_string()))?;
+                traced.insert(record.index, record.digest);
+            }
+            TraceOp::Remove => {
+                kv.remove(index).map_err(|e| fail(e.to_string()))?;
+                traced.remove(&record.index);
+                removed.insert(record.index);
+            }
+            TraceOp::Read if existed && !traced.contains_key(&record.index) => {
+                kv.write(index, data().into())
+                    .map_err(|e| fail(e.to_string()))?;
+                traced.insert(record.index, record.digest);
+            }
+            TraceOp::Read => {
+                if traced.get(&record.index) != Some(&record.digest) {
+                    return Err(fail(format!(
+                        "entry {} as traced differs from its last write",
+                        index
+                    )));
+                }
+                let read = kv.read(index).map_err(|e| fail(e.to_string()))?;
+                if read.len() as u64 != record.len
+                    || (header.full && digest(&read) != record.digest)
+                {
+                    return Err(fail(format!(
+                        "entry {} as replayed differs from its last write",
+                        index
+                    )));
+                }
+            }
+            TraceOp::Has => {
+                let found = kv.has(index).map_err(|e| fail(e.to_string()))?;
+                if found != (record.len > 0) && !(existed && record.len > 0) {
+                    return Err(fail(format!(
+                        "entry {} is {}, traced otherwise",
+                        index,
+                        if found { "replayed" } else { "missing" }
+                    )));
+                }
+            }
+            TraceOp::Keys => {
+                let keys = kv.keys().map_err(|e| fail(e.to_string()))?;
+                if complete && keys.len() as u64 != record.len {
+                    return Err(fail(format!(
+                        "{} entries replayed, {} traced",
+                        keys.len(),
+                        record.len
+                    )));
+                }
+            }
+            TraceOp::Flush => {
+                kv.flush().map_err(|e| fail(e.to_string()))?;
+                if let Some(page_kv) = &page_kv {
+                    page_kv.verify().map_err(|e| fail(e.to_string()))?;
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
+fn write_item(out: &mut impl Write, item: &impl Serialize) -> io::Result<()> {
+    let encoded = bincode_serialize_pad(item, 0);
+    out.write_all(&(encoded.len() as u32).to_be_bytes())?;
+    out.write_all(&encoded)
+}
+
+/// Read an item. None at the end, including an item cut short by a crash.
+fn read_item<T: for<'a> Deserialize<'a>>(input: &mut impl Read) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    let mut encoded = Vec::new();
+    let result = input.read_exact(&mut len).and_then(|()| {
+        encoded.resize(u32::from_be_bytes(len) as usize, 0);
+        input.read_exact(&mut encoded)
+    });
+    match result {
+        Ok(()) => Ok(Some(bincode_deserialize(&encoded)?)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[test]
+fn test_trace_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    for &full in &[false, true] {
+        let path = dir.path().join(format!("trace-{}", full));
+        let kv = super::super::test_int_kv(
+            |kv| {
+                kv.unwrap_or_else(|| {
+                    let page_kv = PageIntKv::new(1024, Box::new(MemIntKv::new())).unwrap();
+                    TraceIntKv::create(Box::new(page_kv), &path, 1024, full).unwrap()
+                })
+            },
+            30,
+        );
+        drop(kv);
+        assert!(replay(&path).unwrap() > 30);
+
+        // A crash can cut the last record short.
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        replay(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_replay_problem() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace");
+    let mut kv = TraceIntKv::create(Box::new(MemIntKv::new()), &path, 0, false).unwrap();
+    kv.write(1, vec![1; 10].into()).unwrap();
+    kv.flush().unwrap();
+    assert!(kv.read(2).is_err());
+    // A bug below changes the entry.
+    kv.kv.write(1, vec![2; 10].into()).unwrap();
+    kv.read(1).unwrap();
+    kv.flush().unwrap();
+    let e = replay(&path).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "operation 4 (Read): entry 1 as traced differs from its last write"
+    );
+    fs::write(&path, b"x79d8").unwrap();
+    assert!(replay(&path).is_err());
+}
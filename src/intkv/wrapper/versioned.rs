@@ -0,0 +1,537 @@
+//! Keep older values of each entry, to recover from a bug above writing
+//! bad data: read an entry as of a few flushes ago with `read_version`.
+//!
+//! Entries are stored at their index as usual. The first change of an
+//! entry after a flush copies its value to one of `keep + 1` slots at
+//! indexes derived from the entry index, from `HISTORY_BASE`. The extra
+//! slot is never referenced by the map on storage, so a crash before the
+//! next flush keeps the recorded values intact. Removed entries keep
+//! their older values until `prune`.
+//!
+//! The version map, the flush and change generations of each value, is
+//! written on flush, split into records at `MAP_INDEX` and below. Only
+//! `flush` counts as a generation. `flush_keys` persists changes without
+//! one, since layers above use it for intermediate steps of a commit.
+
+use super::super::{Bytes, CacheLimits, IntKv, Stats};
+use crate::util::{bincode_deserialize, bincode_serialize_pad};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Reserved index of the first record of the version map. Further records
+/// are below it.
+const MAP_INDEX: usize = usize::MAX - 4;
+
+/// Start of indexes storing older values. Entries above are not supported.
+const HISTORY_BASE: usize = usize::MAX / 2 + 1;
+
+/// End of indexes storing older values, leaving room for the version map.
+const HISTORY_END: usize = MAP_INDEX - (1 << 20);
+
+#[derive(Debug)]
+pub struct VersionedIntKv {
+    kv: Box<dyn IntKv>,
+    map: VersionMap,
+    record_size: usize,
+    /// Records of the version map on storage.
+    map_records: usize,
+    /// Entries changed since the last flush. Their older value is saved.
+    changed: BTreeSet<usize>,
+    /// Whether the map differs from storage.
+    dirty: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VersionMap {
+    /// Older values kept per entry.
+    keep: usize,
+    /// Flushes with changes.
+    generation: u64,
+    entries: BTreeMap<usize, Versions>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Versions {
+    /// Generation the current value was flushed in. 0 for entries written
+    /// before versioning.
+    since: u64,
+    /// Older values, newest first.
+    older: Vec<Version>,
+    /// Last generation of the older values dropped to keep `keep` of them.
+    dropped_until: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Version {
+    /// Generations the value was current in.
+    since: u64,
+    until: u64,
+    slot: usize,
+    len: u64,
+}
+
+impl VersionedIntKv {
+    /// Keep `keep` older values of each entry of `kv`. It cannot change
+    /// once values are kept.
+    pub fn open(kv: Box<dyn IntKv>, keep: usize) -> io::Result<Self> {
+        let (map, map_records) = load_map(kv.as_ref())?;
+        let mut map = match map {
+            Some(map) if map.keep != keep && !map.entries.is_empty() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "cannot keep {} older values of entries, keeping {} already",
+                        keep, map.keep
+                    ),
+                ))
+            }
+            Some(map) => VersionMap { keep, ..map },
+            None => VersionMap {
+                keep,
+                ..Default::default()
+            },
+        };
+        // Changes persisted by `flush_keys` before a crash count as a flush.
+        if map.entries.values().any(|v| v.since > map.generation) {
+            map.generation += 1;
+        }
+        Ok(Self {
+            kv,
+            map,
+            record_size: 0,
+            map_records,
+            changed: BTreeSet::new(),
+            dirty: false,
+        })
+    }
+
+    /// Pad records of the version map to `size`, and split the map into
+    /// records of that size, for storage expecting entries of a fixed size.
+    pub fn with_record_size(mut self, size: usize) -> Self {
+        self.record_size = size;
+        self
+    }
+
+    /// Read `index` as it was `age` flushes with changes before the last
+    /// one. 0 reads the current value, including pending changes.
+    pub fn read_version(&self, index: usize, age: u64) -> io::Result<Bytes> {
+        if age == 0 {
+            return self.kv.read(index);
+        }
+        let target = match self.map.generation.checked_sub(age) {
+            Some(target) => target,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("only {} flushes are recorded", self.map.generation),
+                ))
+            }
+        };
+        let versions = self.map.entries.get(&index);
+        let since = match self.changed.contains(&index) {
+            true => self.map.generation + 1,
+            false => versions.map_or(0, |v| v.since),
+        };
+        if since <= target && self.kv.has(index)? {
+            return self.kv.read(index);
+        }
+        let version = versions
+            .into_iter()
+            .flat_map(|v| &v.older)
+            .find(|v| v.since <= target && target <= v.until);
+        match version {
+            None if versions.and_then(|v| v.dropped_until) >= Some(target) => Err(
+                io::Error::other(format!("older value of entry {} was dropped", index)),
+            ),
+            Some(version) => {
+                let data = self.kv.read(self.slot_index(index, version.slot)?)?;
+                if data.len() as u64 != version.len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("older value of entry {} is corrupted", index),
+                    ));
+                }
+                Ok(data)
+            }
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Flushes with changes since versioning was enabled.
+    pub fn generation(&self) -> u64 {
+        self.map.generation
+    }
+
+    /// Entries with older values, including removed ones.
+    pub fn versioned_keys(&self) -> Vec<usize> {
+        let entries = self.map.entries.iter();
+        entries
+            .filter(|(_, v)| !v.older.is_empty())
+            .map(|(&index, _)| index)
+            .collect()
+    }
+
+    /// Drop the older values of removed entries. Return the number of
+    /// values dropped.
+    pub fn prune(&mut self) -> io::Result<usize> {
+        let mut removed = Vec::new();
+        for &index in self.map.entries.keys() {
+            if !self.kv.has(index)? {
+                removed.push(index);
+            }
+        }
+        let mut count = 0;
+        for index in removed {
+            let versions = self.map.entries.remove(&index).unwrap();
+            count += versions.older.len();
+            for slot in 0..=self.map.keep {
+                match self.kv.remove(self.slot_index(index, slot)?) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+            self.dirty = true;
+        }
+        Ok(count)
+    }
+
+    /// Index storing older value `slot` of `index`.
+    fn slot_index(&self, index: usize, slot: usize) -> io::Result<usize> {
+        index
+            .checked_mul(self.map.keep + 1)
+            .and_then(|i| i.checked_add(HISTORY_BASE + slot))
+            .filter(|&i| i < HISTORY_END)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("entry {} is too large to keep older values", index),
+                )
+            })
+    }
+
+    /// Save the flushed values of `indexes` before their first change
+    /// after a flush.
+    fn save_older(&mut self, indexes: &[usize]) -> io::Result<()> {
+        let indexes: Vec<usize> = indexes
+            .iter()
+            .filter(|i| !self.changed.contains(i))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if let Some(&index) = indexes.iter().find(|&&i| i >= HISTORY_BASE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("entry {} is reserved for older values", index),
+            ));
+        }
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        let mut items = Vec::new();
+        let mut saved = Vec::new();
+        for (&index, result) in indexes.iter().zip(self.kv.read_batch(&indexes)) {
+            let data = match result {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            let versions = self.map.entries.get(&index);
+            let older = versions.map_or(&[][..], |v| &v.older[..]);
+            let slot = (0..=self.map.keep)
+                .find(|s| older.iter().all(|v| v.slot != *s))
+                .unwrap();
+            items.push((self.slot_index(index, slot)?, data.clone()));
+            let version = Version {
+                since: versions.map_or(0, |v| v.since),
+                until: self.map.generation,
+                slot,
+                len: data.len() as u64,
+            };
+            saved.push((index, version));
+        }
+        let (_, result) = self.kv.write_batch(items);
+        result?;
+        for (index, version) in saved {
+            let versions = self.map.entries.entry(index).or_default();
+            versions.older.insert(0, version);
+            if let Some(dropped) = versions.older.get(self.map.keep) {
+                versions.dropped_until = versions.dropped_until.max(Some(dropped.until));
+            }
+            versions.older.truncate(self.map.keep);
+        }
+        for &index in &indexes {
+            self.map.entries.entry(index).or_default().since = self.map.generation + 1;
+        }
+        self.changed.extend(indexes);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn save_map(&mut self) -> io::Result<()> {
+        let data = bincode_serialize_pad(&self.map, 0);
+        let mut record = (data.len() as u64).to_le_bytes().to_vec();
+        record.extend_from_slice(&data);
+        let size = match self.record_size {
+            0 => record.len(),
+            size => size,
+        };
+        let records: Vec<Vec<u8>> = record
+            .chunks(size)
+            .map(|chunk| {
+                let mut chunk = chunk.to_vec();
+                chunk.resize(size, 0);
+                chunk
+            })
+            .collect();
+        let count = records.len();
+        let items = records
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| (MAP_INDEX - i, r.into()))
+            .collect();
+        let (_, result) = self.kv.write_batch(items);
+        result?;
+        for i in count..self.map_records {
+            self.kv.remove(MAP_INDEX - i)?;
+        }
+        self.map_records = count;
+        Ok(())
+    }
+}
+
+/// Read the version map and the number of its records.
+fn load_map(kv: &dyn IntKv) -> io::Result<(Option<VersionMap>, usize)> {
+    let first = match kv.read(MAP_INDEX) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((None, 0)),
+        result => result?,
+    };
+    let corrupted = || io::Error::new(io::ErrorKind::InvalidData, "the version map is corrupted");
+    let len = match first.get(..8) {
+        Some(len) => u64::from_le_bytes(len.try_into().unwrap()) as usize,
+        None => return Err(corrupted()),
+    };
+    let count = (len + 8).div_ceil(first.len());
+    let mut record = first.to_vec();
+    for i in 1..count {
+        record.extend_from_slice(&kv.read(MAP_INDEX - i)?);
+    }
+    let map = record.get(8..8 + len).ok_or_else(corrupted)?;
+    let map = bincode_deserialize(map).map_err(|_| corrupted())?;
+    Ok((Some(map), count))
+}
+
+impl IntKv for VersionedIntKv {
+    fn read(&self, index: usize) -> io::Result<Bytes> {
+        self.kv.read(index)
+    }
+
+    fn read_batch(&self, indexes: &[usize]) -> Vec<io::Result<Bytes>> {
+        self.kv.read_batch(indexes)
+    }
+
+    fn write(&mut self, index: usize, data: Bytes) -> io::Result<()> {
+        self.save_older(&[index])?;
+        self.kv.write(index, data)
+    }
+
+    fn write_batch(&mut self, items: Vec<(usize, Bytes)>) -> (usize, io::Result<()>) {
+        let indexes: Vec<usize> = items.iter().map(|(index, _)| *index).collect();
+        if let Err(e) = self.save_older(&indexes) {
+            return (0, Err(e));
+        }
+        self.kv.write_batch(items)
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if !self.has(index)? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.save_older(&[index])?;
+        self.kv.remove(index)
+    }
+
+    fn has(&self, index: usize) -> io::Result<bool> {
+        match index < HISTORY_BASE {
+            true => self.kv.has(index),
+            false => Ok(false),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<usize>> {
+        let mut keys = self.kv.keys()?;
+        keys.retain(|&index| index < HISTORY_BASE);
+        Ok(keys)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.changed.is_empty() {
+            self.map.generation += 1;
+            self.changed.clear();
+            self.dirty = true;
+        }
+        if self.dirty {
+            self.save_map()?;
+            self.dirty = false;
+        }
+        self.kv.flush()
+    }
+
+    fn flush_keys(&mut self, keys: &[usize]) -> io::Result<()> {
+        let _ = keys;
+        // Older values must be recorded before changes are persisted.
+        if self.dirty {
+            self.save_map()?;
+            self.dirty = false;
+        }
+        self.kv.flush()
+    }
+
+    fn prefetch(&self, indexes: &[usize]) {
+        self.kv.prefetch(indexes)
+    }
+
+    fn pin(&self, index: usize) {
+        self.kv.pin(index)
+    }
+
+    fn unpin(&self, index: usize) {
+        self.kv.unpin(index)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.kv.stats();
+        let older = self.map.entries.values().flat_map(|v| &v.older);
+        let (values, bytes) = older.fold((0, 0), |(n, b), v| (n + 1, b + v.len));
+        stats.insert("versions.values".into(), values);
+        stats.insert("versions.bytes".into(), bytes);
+        stats.insert("versions.generation".into(), self.map.generation);
+        stats
+    }
+
+    fn set_cache_limits(&self, limits: CacheLimits) {
+        self.kv.set_cache_limits(limits)
+    }
+
+    fn check_space(&self, extra: u64) -> io::Result<()> {
+        // Changes might save as many bytes of older values.
+        self.kv.check_space(extra.saturating_mul(2))
+    }
+
+    fn compact_step(&mut self, max_pages: usize) -> io::Result<()> {
+        self.kv.compact_step(max_pages)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+        self.flush()?;
+        self.kv.snapshot(dest)
+    }
+
+    fn idempotent_writes(&self) -> bool {
+        self.kv.idempotent_writes()
+    }
+}
+
+#[test]
+fn test_versioned_int_kv() {
+    use super::super::backend::MemIntKv;
+    super::super::test_int_kv(
+        |kv| {
+            let inner: Box<dyn IntKv> = match kv {
+                Some(VersionedIntKv { kv, .. }) => kv,
+                None => Box::new(MemIntKv::new()),
+            };
+            VersionedIntKv::open(inner, 2).unwrap().with_record_size(64)
+        },
+        50,
+    );
+}
+
+#[test]
+fn test_versioned_int_kv_read_version() {
+    use super::super::backend::MemIntKv;
+    let mut kv = VersionedIntKv::open(Box::new(MemIntKv::new()), 2)
+        .unwrap()
+        .with_record_size(64);
+    let value = |s: &str| Bytes::from(s.as_bytes().to_vec());
+    kv.write(1, value("a")).unwrap();
+    kv.flush().unwrap();
+    kv.write(1, value("b")).unwrap();
+    kv.write(1, value("c")).unwrap();
+    kv.flush().unwrap();
+    kv.remove(1).unwrap();
+    // Flushes without changes do not count.
+    kv.flush().unwrap();
+    kv.flush().unwrap();
+    kv.write(1, value("d")).unwrap();
+    kv.write(2, value("x")).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(kv.generation(), 4);
+    assert_eq!(kv.read_version(1, 0).unwrap(), value("d"));
+    assert_eq!(
+        kv.read_version(1, 1).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(kv.read_version(1, 2).unwrap(), value("c"));
+    assert_eq!(kv.read_version(1, 3).unwrap(), value("a"));
+    assert_eq!(
+        kv.read_version(1, 4).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        kv.read_version(2, 1).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(kv.keys().unwrap(), [1, 2]);
+
+    // Reopened, only 2 older values are kept. Pending changes are seen.
+    let VersionedIntKv { kv: inner, .. } = kv;
+    let mut kv = VersionedIntKv::open(inner, 2).unwrap();
+    kv.write(1, value("e")).unwrap();
+    assert_eq!(kv.read_version(1, 0).unwrap(), value("e"));
+    assert_eq!(kv.read_version(1, 2).unwrap(), value("c"));
+    kv.flush().unwrap();
+    assert_eq!(kv.read_version(1, 1).unwrap(), value("d"));
+    assert_eq!(kv.read_version(1, 3).unwrap(), value("c"));
+    let err = kv.read_version(1, 4).unwrap_err();
+    assert_eq!(err.to_string(), "older value of entry 1 was dropped");
+    assert_eq!(kv.stats()["versions.values"], 2);
+
+    // Removed entries keep older values until pruned.
+    kv.remove(2).unwrap();
+    kv.flush().unwrap();
+    assert_eq!(kv.versioned_keys(), [1, 2]);
+    assert_eq!(kv.prune().unwrap(), 1);
+    kv.flush().unwrap();
+    assert_eq!(kv.versioned_keys(), [1]);
+    assert_eq!(kv.stats()["versions.values"], 2);
+    let VersionedIntKv { kv: inner, .. } = kv;
+    assert!(VersionedIntKv::open(inner, 3).is_err());
+}
+
+#[test]
+fn test_versioned_int_kv_flush_keys() {
+    use super::super::backend::MemIntKv;
+    let mut kv = VersionedIntKv::open(Box::new(MemIntKv::new()), 1).unwrap();
+    let value = |s: &str| Bytes::from(s.as_bytes().to_vec());
+    kv.write(1, value("a")).unwrap();
+    kv.flush().unwrap();
+    kv.write(1, value("b")).unwrap();
+    kv.flush_keys(&[1]).unwrap();
+    kv.write(2, value("x")).unwrap();
+    kv.flush_keys(&[2]).unwrap();
+    assert_eq!(kv.generation(), 1);
+    kv.flush().unwrap();
+    assert_eq!(kv.generation(), 2);
+    assert_eq!(kv.read_version(1, 1).unwrap(), value("a"));
+
+    // Changes persisted without a flush count as one after a crash.
+    kv.write(1, value("c")).unwrap();
+    kv.flush_keys(&[1]).unwrap();
+    let VersionedIntKv { kv: inner, .. } = kv;
+    let kv = VersionedIntKv::open(inner, 1).unwrap();
+    assert_eq!(kv.generation(), 3);
+    assert_eq!(kv.read_version(1, 1).unwrap(), value("b"));
+}
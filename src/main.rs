@@ -1,23 +1,119 @@
 use cli::Opt;
+use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
+use util::logfile::LogFile;
+use util::logging::LogFormat;
+use util::progress::ProgressMode;
+use util::size::parse_size;
 
 mod cli;
 mod ftpfs;
 mod intkv;
 mod util;
 
+#[derive(Debug, StructOpt)]
+#[structopt(name = "x79d8", about = "Serve encrypted files via local FTP.")]
+struct Args {
+    /// Format of log lines: "human" or "json", one object per line. Also
+    /// set by X79D8_LOG_FORMAT. [default: human]
+    #[structopt(long, global = true)]
+    log_format: Option<LogFormat>,
+
+    /// Write logs to this file instead of stderr. Reopened on SIGHUP, for
+    /// logrotate.
+    #[structopt(long, global = true, parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file before it exceeds this size (0: never).
+    #[structopt(long, global = true, default_value = "10M", parse(try_from_str = parse_size))]
+    log_max_size: u64,
+
+    /// Rotated log files to keep, as FILE.1 (newest) to FILE.N.
+    #[structopt(long, global = true, default_value = "5")]
+    log_keep: usize,
+
+    /// Do not show progress of long-running commands.
+    #[structopt(short, long, global = true)]
+    quiet: bool,
+
+    /// Write progress of long-running commands to stderr as JSON objects,
+    /// one per line, with "event" being "start", "progress" or "finish".
+    #[structopt(long, global = true, conflicts_with = "quiet")]
+    json_progress: bool,
+
+    #[structopt(subcommand)]
+    opt: Opt,
+}
+
 #[tokio::main]
 pub async fn main() {
-    init();
-    let opt = Opt::from_args();
-    match opt.run().await {
-        Err(e) => eprintln!("Error: {} ({:?})", &e, &e),
-        Ok(_) => {}
+    let args = Args::from_args();
+    if let Err(e) = init(&args) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
+    if let Err(e) = args.opt.run().await {
+        eprintln!("Error: {} ({:?})", &e, &e);
+        std::process::exit(cli::exit_code(&e));
+    }
+}
+
+fn init(args: &Args) -> Result<(), String> {
+    let format = match (args.log_format, std::env::var("X79D8_LOG_FORMAT")) {
+        (Some(format), _) => format,
+        (None, Ok(format)) => format.parse()?,
+        (None, Err(_)) => LogFormat::Human,
+    };
+    let file = match &args.log_file {
+        None => None,
+        Some(path) => {
+            let file = LogFile::open(path, args.log_max_size, args.log_keep);
+            Some(Arc::new(file.map_err(|e| e.to_string())?))
+        }
+    };
+    util::logging::init(format, file.clone());
+    util::progress::set_mode(match (args.quiet, args.json_progress) {
+        (true, _) => ProgressMode::Quiet,
+        (false, true) => ProgressMode::Json,
+        (false, false) => ProgressMode::Auto,
+    });
+    if let Some(file) = file {
+        log_panics(file.clone());
+        reopen_on_signal(file);
+    }
+    Ok(())
 }
 
-fn init() {
-    env_logger::Builder::from_env("X79D8_LOG")
-        .format_timestamp_millis()
-        .init();
+/// Log panics, which would only go to stderr, and write the log to disk
+/// before the process ends.
+fn log_panics(file: Arc<LogFile>) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        log::error!("{}", info);
+        let _ = file.sync();
+        previous(info);
+    }));
 }
+
+#[cfg(unix)]
+fn reopen_on_signal(file: Arc<LogFile>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::warn!("Cannot reopen the log file on SIGHUP: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(e) = file.reopen() {
+                eprintln!("Error: cannot reopen the log file: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reopen_on_signal(_file: Arc<LogFile>) {}
@@ -0,0 +1,91 @@
+//! Best-effort protection of key material in memory.
+
+use std::io;
+
+/// Disable core dumps. On Linux, also mark the process non-dumpable so
+/// other processes of the same user cannot attach to it.
+#[cfg(unix)]
+pub fn disable_core_dumps() -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: `limit` is a valid rlimit.
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // Safety: PR_SET_DUMPABLE takes an integer argument.
+        if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn disable_core_dumps() -> io::Result<()> {
+    Ok(())
+}
+
+/// Keep the pages holding `data` out of swap. Fails if `RLIMIT_MEMLOCK` is
+/// too low.
+#[cfg(unix)]
+pub fn lock_memory(data: &[u8]) -> io::Result<()> {
+    // Safety: the range is valid memory.
+    if unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, data.len()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn lock_memory(_data: &[u8]) -> io::Result<()> {
+    Ok(())
+}
+
+#[test]
+fn test_harden() {
+    // Hardening is permanent, so do it in a child running only this test,
+    // not in the process shared by other tests.
+    if std::env::var_os("X79D8_TEST_HARDEN").is_none() {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["util::harden::test_harden", "--exact", "--nocapture"])
+            .env("X79D8_TEST_HARDEN", "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}{}", stdout, stderr);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        return;
+    }
+
+    disable_core_dumps().unwrap();
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 1,
+            rlim_max: 1,
+        };
+        // Safety: `limit` is a valid rlimit.
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) }, 0);
+        assert_eq!((limit.rlim_cur, limit.rlim_max), (0, 0));
+    }
+    #[cfg(target_os = "linux")]
+    // Safety: PR_GET_DUMPABLE takes no arguments.
+    assert_eq!(unsafe { libc::prctl(libc::PR_GET_DUMPABLE) }, 0);
+
+    // Locking may be refused by the limit, but must not fail otherwise.
+    let data = Box::new([1u8; 32]);
+    let result = lock_memory(&data[..]);
+    #[cfg(unix)]
+    if let Err(e) = result {
+        assert!(
+            matches!(e.raw_os_error(), Some(libc::ENOMEM) | Some(libc::EPERM)),
+            "{}",
+            e
+        );
+    }
+}
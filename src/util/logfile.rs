@@ -0,0 +1,172 @@
+//! A log file rotated by size, for running without anything collecting
+//! stderr.
+//!
+//! Once a line would take the file past the limit, `PATH.1` becomes
+//! `PATH.2` and so on, `PATH` becomes `PATH.1`, and a new `PATH` is
+//! started. Each step is a rename, so readers always find complete files.
+//! `reopen` starts writing a new `PATH` after logrotate moved it.
+
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    /// Rotate before exceeding this many bytes (0: never).
+    max_size: u64,
+    /// Rotated files to keep.
+    keep: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &Path) -> io::Result<State> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(State { file, size })
+}
+
+impl LogFile {
+    /// Append to `path`, keeping up to `keep` rotated files of up to
+    /// `max_size` bytes (0: no limit).
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let state = open_append(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("cannot open {}: {}", path.display(), e))
+        })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Path of the `n`-th rotated file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Write a line, rotating first if it would not fit.
+    pub fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut state = self.state.lock();
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && state.size > 0 && state.size + len > self.max_size {
+            self.rotate(&mut state)?;
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        data.extend_from_slice(line.as_bytes());
+        data.push(b'\n');
+        state.file.write_all(&data)?;
+        state.size += len;
+        Ok(())
+    }
+
+    fn rotate(&self, state: &mut State) -> io::Result<()> {
+        match self.keep {
+            0 => fs::remove_file(&self.path)?,
+            keep => {
+                for n in (1..keep).rev() {
+                    match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                fs::rename(&self.path, self.rotated_path(1))?;
+            }
+        }
+        *state = open_append(&self.path)?;
+        Ok(())
+    }
+
+    /// Write to a new file at the path, after it was moved away.
+    pub fn reopen(&self) -> io::Result<()> {
+        *self.state.lock() = open_append(&self.path)?;
+        Ok(())
+    }
+
+    /// Write the file to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.state.lock().file.sync_data()
+    }
+}
+
+#[cfg(test)]
+fn read_logs(dir: &Path) -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| {
+            let path = e.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_log_file_rotate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x.log");
+    let log = LogFile::open(&path, 20, 2).unwrap();
+    for i in 0..10 {
+        log.write_line(&format!("line {}", i)).unwrap();
+    }
+    // 7 bytes per line, 2 lines per file.
+    assert_eq!(
+        read_logs(dir.path()),
+        [
+            ("x.log".into(), "line 8\nline 9\n".into()),
+            ("x.log.1".into(), "line 6\nline 7\n".into()),
+            ("x.log.2".into(), "line 4\nline 5\n".into()),
+        ]
+    );
+
+    // Appended to after reopening. Lines longer than the limit still go
+    // in a file of their own.
+    drop(log);
+    let log = LogFile::open(&path, 20, 2).unwrap();
+    log.write_line("a line longer than the limit").unwrap();
+    log.write_line("x").unwrap();
+    let files = read_logs(dir.path());
+    assert_eq!(files[0].1, "x\n");
+    assert_eq!(files[1].1, "a line longer than the limit\n");
+    assert_eq!(files[2].1, "line 8\nline 9\n");
+
+    // Without rotated files.
+    let log = LogFile::open(&path, 20, 0).unwrap();
+    for i in 0..3 {
+        log.write_line(&format!("line {}", i)).unwrap();
+    }
+    assert_eq!(read_logs(dir.path())[0].1, "line 2\n");
+}
+
+#[test]
+fn test_log_file_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x.log");
+    let log = LogFile::open(&path, 0, 2).unwrap();
+    log.write_line("a").unwrap();
+    // Like logrotate.
+    fs::rename(&path, dir.path().join("x.log-old")).unwrap();
+    log.write_line("b").unwrap();
+    log.reopen().unwrap();
+    log.write_line("c").unwrap();
+    log.sync().unwrap();
+    assert_eq!(
+        read_logs(dir.path()),
+        [
+            ("x.log".into(), "c\n".into()),
+            ("x.log-old".into(), "a\nb\n".into()),
+        ]
+    );
+}
@@ -0,0 +1,191 @@
+//! Log lines for people, or JSON objects for log collectors.
+//!
+//! `log_kv!` logs a message with fields. In the human format, fields follow
+//! the message as `key=value`. In the JSON format, they are keys of the
+//! object, passed to the formatter in a thread local, as the `log` crate
+//! has no stable way to carry them.
+
+use super::logfile::LogFile;
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?} (try human or json)", s)),
+        }
+    }
+}
+
+static FORMAT: OnceCell<LogFormat> = OnceCell::new();
+
+thread_local! {
+    /// Fields of the record being logged in the JSON format.
+    static FIELDS: RefCell<Vec<(&'static str, Value)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Install the logger, configured by `X79D8_LOG` like `RUST_LOG`. Log to
+/// `file` instead of stderr if set.
+pub fn init(format: LogFormat, file: Option<Arc<LogFile>>) {
+    let _ = FORMAT.set(format);
+    let mut builder = env_logger::Builder::from_env("X79D8_LOG");
+    match (format, file) {
+        (LogFormat::Human, None) => builder.format_timestamp_millis(),
+        (format, file) => builder.format(move |buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            let line = match format {
+                LogFormat::Human => human_line(&timestamp, record),
+                LogFormat::Json => {
+                    let fields = FIELDS.with(|f| f.borrow().clone());
+                    json_line(&timestamp, record, &fields)
+                }
+            };
+            // Written by env_logger to stderr. Leave it empty if the
+            // line went to the file.
+            match &file {
+                Some(file) => file.write_line(&line),
+                None => writeln!(buf, "{}", line),
+            }
+        }),
+    };
+    builder.init();
+}
+
+/// Log `message` at `level` with `fields`. Use `log_kv!` instead.
+pub fn log_with_fields(
+    level: log::Level,
+    target: &str,
+    message: &str,
+    fields: Vec<(&'static str, Value)>,
+) {
+    match FORMAT.get() {
+        Some(LogFormat::Json) => {
+            FIELDS.with(|f| *f.borrow_mut() = fields);
+            log::log!(target: target, level, "{}", message);
+            FIELDS.with(|f| f.borrow_mut().clear());
+        }
+        _ => log::log!(target: target, level, "{}{}", message, human_fields(&fields)),
+    }
+}
+
+/// Fields as " key=value", strings without quotes.
+fn human_fields(fields: &[(&'static str, Value)]) -> String {
+    let mut text = String::new();
+    for (key, value) in fields {
+        match value {
+            Value::String(s) => text += &format!(" {}={}", key, s),
+            v => text += &format!(" {}={}", key, v),
+        }
+    }
+    text
+}
+
+/// Like the default format of env_logger, without colors.
+fn human_line(timestamp: &str, record: &log::Record) -> String {
+    format!(
+        "[{} {:<5} {}] {}",
+        timestamp,
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+fn json_line(timestamp: &str, record: &log::Record, fields: &[(&'static str, Value)]) -> String {
+    let mut object = Map::new();
+    object.insert("timestamp".into(), timestamp.into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+    for (key, value) in fields {
+        object.entry(*key).or_insert_with(|| value.clone());
+    }
+    Value::Object(object).to_string()
+}
+
+/// Log a message with fields, like
+/// `log_kv!(log::Level::Info, "Flushed"; entries = 3, secs = 0.5)`. Values
+/// are anything serializable.
+#[macro_export]
+macro_rules! log_kv {
+    ($level:expr, $message:expr; $($key:ident = $value:expr),+ $(,)?) => {
+        if log::log_enabled!($level) {
+            $crate::util::logging::log_with_fields(
+                $level,
+                module_path!(),
+                $message,
+                vec![$((stringify!($key), serde_json::json!($value))),+],
+            );
+        }
+    };
+}
+
+#[test]
+fn test_log_format_parse() {
+    assert_eq!("json".parse(), Ok(LogFormat::Json));
+    assert_eq!("human".parse(), Ok(LogFormat::Human));
+    assert!("JSON"
+        .parse::<LogFormat>()
+        .unwrap_err()
+        .contains("human or json"));
+}
+
+#[test]
+fn test_log_lines() {
+    let fields = vec![
+        ("index", Value::from(3)),
+        ("path", Value::from("a b/c")),
+        ("secs", Value::from(1.5)),
+        // Does not replace the message.
+        ("message", Value::from("x")),
+    ];
+    assert_eq!(human_fields(&fields[..3]), " index=3 path=a b/c secs=1.5");
+
+    let line = json_line(
+        "2021-01-01T00:00:00.000Z",
+        &log::Record::builder()
+            .level(log::Level::Info)
+            .target("x79d8::ftpfs")
+            .args(format_args!("Flushed \"a\""))
+            .build(),
+        &fields,
+    );
+    let value: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(
+        value,
+        serde_json::json!({
+            "timestamp": "2021-01-01T00:00:00.000Z",
+            "level": "INFO",
+            "target": "x79d8::ftpfs",
+            "message": "Flushed \"a\"",
+            "index": 3,
+            "path": "a b/c",
+            "secs": 1.5,
+        })
+    );
+    assert!(!line.contains('\n'));
+
+    let record = log::Record::builder()
+        .level(log::Level::Warn)
+        .target("x79d8::cli")
+        .args(format_args!("Low disk space"))
+        .build();
+    assert_eq!(
+        human_line("2021-01-01T00:00:00.000Z", &record),
+        "[2021-01-01T00:00:00.000Z WARN  x79d8::cli] Low disk space"
+    );
+}
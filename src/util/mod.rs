@@ -2,6 +2,13 @@ use bincode::Options;
 use serde::{Deserialize, Serialize};
 use std::io;
 
+pub mod harden;
+pub mod logfile;
+pub mod logging;
+pub mod progress;
+pub mod rename;
+pub mod size;
+
 fn bincode_opts() -> impl bincode::Options {
     bincode::options()
         .with_big_endian()
@@ -9,30 +16,280 @@ fn bincode_opts() -> impl bincode::Options {
         .allow_trailing_bytes()
 }
 
+fn varint_opts() -> impl bincode::Options {
+    bincode::options()
+        .with_big_endian()
+        .with_varint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// Integer encoding of bincode. `Fixint` is used by older vaults. `Varint`
+/// writes small integers in fewer bytes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Fixint,
+    Varint,
+}
+
+impl Encoding {
+    /// Serialized size of a `u64` or a length.
+    pub fn int_size(self, value: u64) -> u64 {
+        match self {
+            Encoding::Fixint => 8,
+            Encoding::Varint => match value {
+                0..=250 => 1,
+                251..=0xffff => 3,
+                0x1_0000..=0xffff_ffff => 5,
+                _ => 9,
+            },
+        }
+    }
+
+    /// Largest serialized size of a `u64` or a length.
+    pub fn max_int_size(self) -> u64 {
+        self.int_size(u64::MAX)
+    }
+}
+
 /// Bincode deserialize using options preferred by the crate.
 pub fn bincode_deserialize<T: for<'a> Deserialize<'a>>(data: &[u8]) -> io::Result<T> {
-    bincode_opts()
-        .deserialize(data)
-        .map_err(|_| io::ErrorKind::InvalidData.into())
+    bincode_deserialize_with(Encoding::Fixint, data)
+}
+
+/// Bincode deserialize using the given integer encoding.
+pub fn bincode_deserialize_with<T: for<'a> Deserialize<'a>>(
+    encoding: Encoding,
+    data: &[u8],
+) -> io::Result<T> {
+    let result = match encoding {
+        Encoding::Fixint => bincode_opts().deserialize(data),
+        Encoding::Varint => varint_opts().deserialize(data),
+    };
+    result.map_err(|_| io::ErrorKind::InvalidData.into())
 }
 
-/// Bincode serialize size using options preferred by the crate.
-pub fn bincode_size<T: Serialize>(value: &T) -> u64 {
-    bincode_opts().serialized_size(value).unwrap()
+/// Bincode serialize size using the given integer encoding.
+pub fn bincode_size_with<T: Serialize>(encoding: Encoding, value: &T) -> u64 {
+    let result = match encoding {
+        Encoding::Fixint => bincode_opts().serialized_size(value),
+        Encoding::Varint => varint_opts().serialized_size(value),
+    };
+    result.unwrap()
 }
 
 /// Bincode serialize using options preferred by the crate.
 /// If `page_size` is not 0, add padding to `page_size`.
-pub fn bincode_serialize_pad<T: Serialize>(value: &T, mut page_size: u64) -> Vec<u8> {
-    let opts = bincode_opts();
-    if page_size == 0 {
-        page_size = bincode_size(value);
-    }
+pub fn bincode_serialize_pad<T: Serialize>(value: &T, page_size: u64) -> Vec<u8> {
+    bincode_serialize_pad_with(Encoding::Fixint, value, page_size)
+}
+
+/// Bincode serialize using the given integer encoding, padded like
+/// `bincode_serialize_pad`.
+pub fn bincode_serialize_pad_with<T: Serialize>(
+    encoding: Encoding,
+    value: &T,
+    page_size: u64,
+) -> Vec<u8> {
+    let size = bincode_size_with(encoding, value);
+    let page_size = match page_size {
+        0 => size,
+        n => n,
+    };
+    assert!(size <= page_size);
     let mut buf = Vec::with_capacity(page_size as _);
-    opts.serialize_into(&mut buf, value).unwrap();
-    debug_assert_eq!(buf.len() as u64, bincode_size(value));
-    assert!(buf.len() <= page_size as _);
+    match encoding {
+        Encoding::Fixint => bincode_opts().serialize_into(&mut buf, value),
+        Encoding::Varint => varint_opts().serialize_into(&mut buf, value),
+    }
+    .unwrap();
+    debug_assert_eq!(buf.len() as u64, size);
     // Padding
     buf.resize(page_size as _, 0);
     buf
 }
+
+/// Number of threads to use for parallel work by default.
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Size of the physical memory in bytes, or None if unknown.
+pub fn physical_memory() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if pages > 0 && page_size > 0 {
+            return Some(pages as u64 * page_size as u64);
+        }
+    }
+    None
+}
+
+/// Space of a filesystem in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskSpace {
+    /// Available to unprivileged users.
+    pub available: u64,
+    pub total: u64,
+}
+
+/// Space of the filesystem containing `path`.
+pub fn disk_space(path: &std::path::Path) -> io::Result<DiskSpace> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let block_size = stat.f_frsize as u64;
+        Ok(DiskSpace {
+            available: stat.f_bavail as u64 * block_size,
+            total: stat.f_blocks as u64 * block_size,
+        })
+    }
+    #[cfg(not(unix))]
+    Ok(DiskSpace {
+        available: fs2::available_space(path)?,
+        total: fs2::total_space(path)?,
+    })
+}
+
+/// Map items using up to `threads` threads. Preserve the order.
+pub fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let threads = threads.min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let chunk_size = items.len().div_ceil(threads);
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+}
+
+/// Overwrite bytes with zeros. Volatile writes are not optimized out.
+pub fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // Safety: `b` is a valid `&mut u8`.
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Overwrite a value with zeros, for states holding key material.
+///
+/// # Safety
+///
+/// `T` must not hold pointers, and all-zero bytes must be a valid `T`.
+pub unsafe fn zeroize_raw<T>(value: &mut T) {
+    let ptr = value as *mut T as *mut u8;
+    zeroize(std::slice::from_raw_parts_mut(
+        ptr,
+        std::mem::size_of::<T>(),
+    ));
+}
+
+/// Bytes wiped on drop, for keys and passwords. Not shown by `Debug`.
+pub struct Secret<T: AsMut<[u8]>>(T);
+
+impl<T: AsMut<[u8]>> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: AsMut<[u8]>> std::ops::Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: AsMut<[u8]>> std::ops::DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: AsMut<[u8]>> Drop for Secret<T> {
+    fn drop(&mut self) {
+        zeroize(self.0.as_mut());
+    }
+}
+
+impl<T: AsMut<[u8]>> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[test]
+fn test_secret_drop() {
+    use std::sync::{Arc, Mutex};
+
+    // Record the bytes after `Secret::drop`, before the inner value drops.
+    struct Probe([u8; 4], Arc<Mutex<Vec<u8>>>);
+    impl AsMut<[u8]> for Probe {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            *self.1.lock().unwrap() = self.0.to_vec();
+        }
+    }
+
+    let observed = Arc::new(Mutex::new(Vec::new()));
+    let secret = Secret::new(Probe([1, 2, 3, 4], observed.clone()));
+    assert_eq!(secret.0 .0, [1, 2, 3, 4]);
+    assert_eq!(format!("{:?}", secret), "Secret(..)");
+    drop(secret);
+    assert_eq!(*observed.lock().unwrap(), vec![0; 4]);
+}
+
+#[test]
+fn test_encoding_int_size() {
+    for encoding in [Encoding::Fixint, Encoding::Varint] {
+        for value in [
+            0u64,
+            250,
+            251,
+            0xffff,
+            0x1_0000,
+            0xffff_ffff,
+            1 << 32,
+            u64::MAX,
+        ] {
+            let size = bincode_size_with(encoding, &value);
+            assert_eq!(encoding.int_size(value), size, "{:?} {}", encoding, value);
+            let data = bincode_serialize_pad_with(encoding, &value, 16);
+            assert_eq!(data.len(), 16);
+            assert_eq!(
+                bincode_deserialize_with::<u64>(encoding, &data).unwrap(),
+                value
+            );
+        }
+    }
+    let map: std::collections::BTreeMap<u64, u64> = (0..100).map(|i| (i, i * 2)).collect();
+    assert_eq!(bincode_size_with(Encoding::Fixint, &map), 8 + 100 * 16);
+    assert_eq!(bincode_size_with(Encoding::Varint, &map), 1 + 100 * 2);
+}
@@ -0,0 +1,353 @@
+//! Progress of long-running commands, like `rekey` or `fsck`.
+//!
+//! On a terminal, a bar is redrawn in place on stderr. Otherwise, a line is
+//! written every few seconds, so logs of scheduled jobs show it too. With
+//! `--json-progress`, events are written as JSON objects, one per line, for
+//! programs running x79d8. `--quiet` hides progress.
+
+use super::size::MIB;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+/// How progress is shown, set by the global flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// A bar on terminals, lines otherwise.
+    Auto,
+    Quiet,
+    Json,
+}
+
+static MODE: OnceCell<ProgressMode> = OnceCell::new();
+
+/// Set how `Progress` is shown. Only the first call counts.
+pub fn set_mode(mode: ProgressMode) {
+    let _ = MODE.set(mode);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Style {
+    Hidden,
+    Bar,
+    Lines,
+    Json,
+}
+
+impl Style {
+    /// Time between reports.
+    fn interval(self) -> Duration {
+        match self {
+            Style::Hidden => Duration::MAX,
+            Style::Bar => Duration::from_millis(100),
+            Style::Lines => Duration::from_secs(10),
+            Style::Json => Duration::from_secs(1),
+        }
+    }
+}
+
+/// Items of a task done, out of a total if known, with bytes processed.
+pub struct Progress {
+    task: &'static str,
+    /// What the items are, like "blocks".
+    unit: &'static str,
+    total: Option<u64>,
+    done: u64,
+    bytes: u64,
+    style: Style,
+    interval: Duration,
+    start: Instant,
+    last_report: Option<Instant>,
+    finished: bool,
+    out: Box<dyn Write + Send>,
+}
+
+impl Progress {
+    /// Start reporting progress of `task` to stderr.
+    pub fn new(task: &'static str, unit: &'static str, total: Option<u64>) -> Self {
+        let style = match MODE.get().copied().unwrap_or(ProgressMode::Auto) {
+            ProgressMode::Quiet => Style::Hidden,
+            ProgressMode::Json => Style::Json,
+            ProgressMode::Auto if io::stderr().is_terminal() => Style::Bar,
+            ProgressMode::Auto => Style::Lines,
+        };
+        Self::with_output(task, unit, total, style, Box::new(io::stderr()))
+    }
+
+    fn with_output(
+        task: &'static str,
+        unit: &'static str,
+        total: Option<u64>,
+        style: Style,
+        out: Box<dyn Write + Send>,
+    ) -> Self {
+        let mut progress = Self {
+            task,
+            unit,
+            total,
+            done: 0,
+            bytes: 0,
+            style,
+            interval: style.interval(),
+            start: Instant::now(),
+            last_report: None,
+            finished: false,
+            out,
+        };
+        if style == Style::Json {
+            progress.write_event("start");
+        }
+        progress
+    }
+
+    /// Count `items` more done, taking `bytes`.
+    pub fn add(&mut self, items: u64, bytes: u64) {
+        self.done += items;
+        self.bytes += bytes;
+        self.maybe_report();
+    }
+
+    /// Set the items done and the total, for callbacks reporting both.
+    pub fn set(&mut self, done: u64, total: u64) {
+        self.done = done;
+        self.total = Some(total);
+        self.maybe_report();
+    }
+
+    /// Report the final count.
+    pub fn finish(mut self) {
+        self.finished = true;
+        match self.style {
+            Style::Hidden => {}
+            Style::Bar => {
+                let line = self.describe();
+                let _ = write!(self.out, "\r\x1b[K{}\n", line);
+            }
+            Style::Lines => {
+                let line = self.describe();
+                let _ = writeln!(self.out, "{}", line);
+            }
+            Style::Json => self.write_event("finish"),
+        }
+    }
+
+    fn maybe_report(&mut self) {
+        let now = Instant::now();
+        let due = match self.last_report {
+            None => now.duration_since(self.start) >= self.interval,
+            Some(last) => now.duration_since(last) >= self.interval,
+        };
+        if !due {
+            return;
+        }
+        self.last_report = Some(now);
+        match self.style {
+            Style::Hidden => {}
+            Style::Bar => {
+                let line = self.describe();
+                let _ = write!(self.out, "\r\x1b[K{}", line);
+                let _ = self.out.flush();
+            }
+            Style::Lines => {
+                let line = self.describe();
+                let _ = writeln!(self.out, "{}", line);
+            }
+            Style::Json => self.write_event("progress"),
+        }
+    }
+
+    fn rates(&self) -> (f64, f64, Option<f64>) {
+        let secs = self.start.elapsed().as_secs_f64().max(1e-3);
+        let items_per_sec = self.done as f64 / secs;
+        let eta = match self.total {
+            Some(total) if self.done > 0 => {
+                Some(total.saturating_sub(self.done) as f64 / items_per_sec)
+            }
+            _ => None,
+        };
+        (items_per_sec, self.bytes as f64 / secs, eta)
+    }
+
+    /// Like "rekey: 300/1000 entries (30%), 12.5 MB/s, ETA 1m 5s".
+    fn describe(&self) -> String {
+        let (items_per_sec, bytes_per_sec, eta) = self.rates();
+        let mut line = match self.total {
+            Some(total) => format!(
+                "{}: {}/{} {} ({}%)",
+                self.task,
+                self.done,
+                total,
+                self.unit,
+                (self.done * 100).checked_div(total).unwrap_or(100)
+            ),
+            None => format!("{}: {} {}", self.task, self.done, self.unit),
+        };
+        match self.bytes {
+            0 => line += &format!(", {:.0} {}/s", items_per_sec, self.unit),
+            _ => line += &format!(", {:.1} MB/s", bytes_per_sec / MIB as f64),
+        }
+        if let (Some(eta), false) = (eta, self.finished) {
+            line += &format!(", ETA {}", format_secs(eta));
+        }
+        line
+    }
+
+    fn event(&self, event: &str) -> Value {
+        let (items_per_sec, bytes_per_sec, eta) = self.rates();
+        json!({
+            "event": event,
+            "task": self.task,
+            "unit": self.unit,
+            "done": self.done,
+            "total": self.total,
+            "bytes": self.bytes,
+            "elapsed_secs": self.start.elapsed().as_secs_f64(),
+            "items_per_sec": items_per_sec,
+            "bytes_per_sec": bytes_per_sec,
+            "eta_secs": eta,
+        })
+    }
+
+    fn write_event(&mut self, event: &str) {
+        let line = self.event(event).to_string();
+        let _ = writeln!(self.out, "{}", line);
+    }
+}
+
+impl Drop for Progress {
+    /// Move off the bar, so errors start on their own line.
+    fn drop(&mut self) {
+        if !self.finished && self.style == Style::Bar && self.last_report.is_some() {
+            let _ = writeln!(self.out);
+        }
+    }
+}
+
+/// Like "1h 2m", "3m 4s" or "5s".
+fn format_secs(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, _) => format!("{}h {}m", h, m),
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct Output(std::sync::Arc<parking_lot::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_progress_json_events() {
+    let output = Output::default();
+    let mut progress = Progress::with_output(
+        "migrate",
+        "entries",
+        Some(3),
+        Style::Json,
+        Box::new(output.clone()),
+    );
+    progress.interval = Duration::ZERO;
+    progress.add(1, 100);
+    progress.add(2, 50);
+    progress.finish();
+
+    let text = String::from_utf8(output.0.lock().clone()).unwrap();
+    let events: Vec<Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds, ["start", "progress", "progress", "finish"]);
+    for event in &events {
+        assert_eq!(event["task"], "migrate");
+        assert_eq!(event["unit"], "entries");
+        assert_eq!(event["total"], 3);
+        for key in ["elapsed_secs", "items_per_sec", "bytes_per_sec"] {
+            assert!(event[key].is_number(), "{}: {}", key, event);
+        }
+    }
+    let done: Vec<u64> = events.iter().map(|e| e["done"].as_u64().unwrap()).collect();
+    assert_eq!(done, [0, 1, 3, 3]);
+    assert_eq!(events[3]["bytes"], 150);
+    assert!(events[0]["eta_secs"].is_null());
+    assert!(events[1]["eta_secs"].is_number());
+    assert_eq!(events[2]["eta_secs"], 0.0);
+
+    // Without a total, and with a total set by a callback.
+    let output = Output::default();
+    let mut progress = Progress::with_output(
+        "rekey",
+        "entries",
+        None,
+        Style::Json,
+        Box::new(output.clone()),
+    );
+    progress.interval = Duration::ZERO;
+    progress.set(5, 10);
+    drop(progress);
+    let text = String::from_utf8(output.0.lock().clone()).unwrap();
+    let events: Vec<Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert!(events[0]["total"].is_null());
+    assert_eq!(
+        (&events[1]["done"], &events[1]["total"]),
+        (&json!(5), &json!(10))
+    );
+}
+
+#[test]
+fn test_progress_lines() {
+    let output = Output::default();
+    let mut progress = Progress::with_output(
+        "fsck",
+        "blocks",
+        Some(4),
+        Style::Lines,
+        Box::new(output.clone()),
+    );
+    // Not reported before the interval.
+    progress.add(1, 0);
+    assert!(output.0.lock().is_empty());
+    progress.interval = Duration::ZERO;
+    progress.add(1, 0);
+    progress.finish();
+    let text = String::from_utf8(output.0.lock().clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(
+        lines[0].starts_with("fsck: 2/4 blocks (50%), "),
+        "{}",
+        lines[0]
+    );
+    assert!(lines[0].contains("blocks/s, ETA "), "{}", lines[0]);
+    assert!(
+        lines[1].starts_with("fsck: 2/4 blocks (50%), "),
+        "{}",
+        lines[1]
+    );
+    assert!(!lines[1].contains("ETA"), "{}", lines[1]);
+
+    assert_eq!(format_secs(4.6), "5s");
+    assert_eq!(format_secs(184.0), "3m 4s");
+    assert_eq!(format_secs(3725.0), "1h 2m");
+}
@@ -0,0 +1,195 @@
+//! Renaming files over others, atomically swapping them where supported.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exchange the files `a` and `b` atomically. Both must exist. Fails with
+/// `Unsupported` if the platform or the filesystem cannot.
+#[cfg(target_os = "linux")]
+pub fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (a, b) = (c_path(a)?, c_path(b)?);
+    // Safety: the paths are valid C strings. glibc before 2.28 lacks a
+    // wrapper of renameat2.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // Linux before 3.15, or a filesystem without support.
+            Some(libc::ENOSYS) | Some(libc::EINVAL) => Err(io::ErrorKind::Unsupported.into()),
+            _ => Err(e),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// `exchange` failed with `Unsupported`. Not tried again by this process.
+#[cfg(target_os = "linux")]
+static EXCHANGE_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Rename `from` over `to`, moving the replaced file, if any, to `old`.
+/// Fails with `NotFound` if `from` is missing. Running it again after an
+/// interruption completes it.
+///
+/// On Linux, `from` is linked as `old` and exchanged with `to`, so `to`
+/// exists throughout. Elsewhere, `to` is missing between two renames.
+pub fn rename_keeping(from: &Path, to: &Path, old: &Path) -> io::Result<()> {
+    let from_metadata = fs::metadata(from)?;
+    let to_metadata = match fs::metadata(to) {
+        Ok(metadata) => metadata,
+        // Nothing to keep. Or an interrupted run moved it already.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return fs::rename(from, to),
+        Err(e) => return Err(e),
+    };
+    if exchange_keeping(from, to, old, &from_metadata, &to_metadata)? {
+        return Ok(());
+    }
+    fs::rename(to, old)?;
+    fs::rename(from, to)
+}
+
+/// `rename_keeping` using `exchange`. Return false to fall back to
+/// renames.
+#[cfg(target_os = "linux")]
+fn exchange_keeping(
+    from: &Path,
+    to: &Path,
+    old: &Path,
+    from_metadata: &fs::Metadata,
+    to_metadata: &fs::Metadata,
+) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    if EXCHANGE_UNSUPPORTED.load(Ordering::Relaxed) {
+        return Ok(false);
+    }
+    let same = |m: &fs::Metadata| m.dev() == from_metadata.dev() && m.ino() == from_metadata.ino();
+    if same(to_metadata) {
+        // Exchanged by an interrupted run.
+        fs::remove_file(from)?;
+        return Ok(true);
+    }
+    let linked = match fs::metadata(old) {
+        Ok(metadata) => same(&metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+    if !linked {
+        // `old` is of an earlier generation.
+        match fs::remove_file(old) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Some filesystems do not support hard links.
+        if fs::hard_link(from, old).is_err() {
+            return Ok(false);
+        }
+    }
+    match exchange(old, to) {
+        Ok(()) => {
+            fs::remove_file(from)?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            log::info!("Atomic exchange of files is not supported");
+            EXCHANGE_UNSUPPORTED.store(true, Ordering::Relaxed);
+            fs::remove_file(old)?;
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange_keeping(
+    _from: &Path,
+    _to: &Path,
+    _old: &Path,
+    _from_metadata: &fs::Metadata,
+    _to_metadata: &fs::Metadata,
+) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_exchange() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, b"1").unwrap();
+    fs::write(&b, b"2").unwrap();
+    match exchange(&a, &b) {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+        result => result.unwrap(),
+    }
+    assert_eq!(fs::read(&a).unwrap(), b"2");
+    assert_eq!(fs::read(&b).unwrap(), b"1");
+    let missing = dir.path().join("c");
+    assert_eq!(
+        exchange(&a, &missing).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn test_rename_keeping() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name| dir.path().join(name);
+    let (from, to, old) = (path("from"), path("to"), path("old"));
+    let check = || {
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"new");
+        assert_eq!(fs::read(&old).unwrap(), b"old");
+    };
+
+    fs::write(&from, b"new").unwrap();
+    fs::write(&to, b"old").unwrap();
+    fs::write(&old, b"older").unwrap();
+    rename_keeping(&from, &to, &old).unwrap();
+    check();
+    assert_eq!(
+        rename_keeping(&from, &to, &old).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+
+    // Resume after linking, and after exchanging.
+    #[cfg(target_os = "linux")]
+    if exchange(&to, &old).is_ok() {
+        fs::hard_link(&old, &from).unwrap();
+        rename_keeping(&from, &to, &old).unwrap();
+        check();
+
+        fs::remove_file(&old).unwrap();
+        fs::hard_link(&to, &from).unwrap();
+        fs::write(&old, b"old").unwrap();
+        rename_keeping(&from, &to, &old).unwrap();
+        check();
+    }
+
+    // Resume after moving `to` away. Nothing to keep.
+    fs::write(&from, b"new").unwrap();
+    fs::remove_file(&to).unwrap();
+    rename_keeping(&from, &to, &old).unwrap();
+    check();
+}
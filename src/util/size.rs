@@ -0,0 +1,271 @@
+//! Sizes written like "256MiB", "1G" or "1.5k". Suffixes are powers of
+//! 1024, case-insensitive, and may end with "B" or "iB".
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+
+pub const KIB: u64 = 1 << 10;
+pub const MIB: u64 = 1 << 20;
+
+/// Parse a byte count like "512M". Plain numbers are bytes. Fractions are
+/// rounded down to whole bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    parse(s, 1)
+}
+
+/// Parse a size as a multiple of `unit` bytes, which plain numbers count.
+/// "1G" in MiB is 1024. Sizes not a multiple of `unit` are refused.
+pub fn parse_size_in(s: &str, unit: u64) -> Result<u64, String> {
+    let bytes = parse(s, unit)?;
+    match bytes % unit {
+        0 => Ok(bytes / unit),
+        _ => Err(format!(
+            "size {:?} is not a multiple of {}",
+            s,
+            unit_name(unit)
+        )),
+    }
+}
+
+/// `parse_size_in` for integers other than `u64`.
+pub fn parse_size_as<T: TryFrom<u64>>(s: &str, unit: u64) -> Result<T, String> {
+    let n = parse_size_in(s, unit)?;
+    T::try_from(n).map_err(|_| format!("size {:?} is too large", s))
+}
+
+fn unit_name(unit: u64) -> String {
+    match unit {
+        1 => "1 byte".to_string(),
+        KIB => "1 KiB".to_string(),
+        MIB => "1 MiB".to_string(),
+        n => format!("{} bytes", n),
+    }
+}
+
+/// Parse a size. Plain numbers count `unit` bytes.
+fn parse(s: &str, unit: u64) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let invalid = || format!("invalid size {:?}", s);
+    let end = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(end);
+    let multiplier: u64 = match suffix.trim_start().to_ascii_lowercase().as_str() {
+        "" => unit,
+        "b" => 1,
+        "k" | "kb" | "kib" => KIB,
+        "m" | "mb" | "mib" => MIB,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        "p" | "pb" | "pib" => 1 << 50,
+        _ => return Err(format!("unknown size suffix in {:?}", s)),
+    };
+    let (whole, fraction) = match number.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (number, ""),
+    };
+    if (whole.is_empty() && fraction.is_empty()) || fraction.contains('.') {
+        return Err(invalid());
+    }
+    if !fraction.is_empty() && multiplier == 1 {
+        return Err(format!("size {:?} is not a whole number of bytes", s));
+    }
+    let too_large = || format!("size {:?} is too large", s);
+    let whole: u128 = match whole {
+        "" => 0,
+        w => w.parse().map_err(|_| too_large())?,
+    };
+    let mut bytes = whole
+        .checked_mul(multiplier as u128)
+        .ok_or_else(too_large)?;
+    // Digits beyond 2^50 bytes are below a byte.
+    let fraction = &fraction[..fraction.len().min(18)];
+    if !fraction.is_empty() {
+        let scale = 10u128.pow(fraction.len() as u32);
+        let digits: u128 = fraction.parse().map_err(|_| invalid())?;
+        bytes += digits * multiplier as u128 / scale;
+    }
+    u64::try_from(bytes).map_err(|_| too_large())
+}
+
+/// Read a size in bytes from an integer, or a string like "256MiB". Write
+/// the integer. For `#[serde(with = "util::size::bytes")]`.
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, T: TryFrom<u64>, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+        super::deserialize_in(d, 1)
+    }
+}
+
+/// Like `bytes`, for sizes in KiB.
+pub mod kib {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, T: TryFrom<u64>, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+        super::deserialize_in(d, KIB)
+    }
+}
+
+/// Like `bytes`, for sizes in MiB.
+pub mod mib {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, T: TryFrom<u64>, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+        super::deserialize_in(d, MIB)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Count(u64),
+    Text(String),
+}
+
+fn deserialize_in<'de, T: TryFrom<u64>, D: Deserializer<'de>>(
+    d: D,
+    unit: u64,
+) -> Result<T, D::Error> {
+    use serde::de::Error;
+    match Size::deserialize(d)? {
+        Size::Count(n) => {
+            T::try_from(n).map_err(|_| D::Error::custom(format!("{} is too large", n)))
+        }
+        Size::Text(s) => parse_size_as(&s, unit).map_err(D::Error::custom),
+    }
+}
+
+#[test]
+fn test_parse_size_suffixes() {
+    for (suffixes, multiplier) in [
+        (&["", "b", "B"][..], 1),
+        (&["k", "K", "kb", "KB", "KiB", "kib"][..], KIB),
+        (&["m", "M", "MB", "MiB", "mIb"][..], MIB),
+        (&["g", "G", "GB", "GiB"][..], 1 << 30),
+        (&["t", "TB", "TiB"][..], 1 << 40),
+        (&["p", "PB", "PiB"][..], 1 << 50),
+    ] {
+        for suffix in suffixes {
+            for (n, expected) in [(0, 0), (1, multiplier), (3, 3 * multiplier)] {
+                let s = format!("{}{}", n, suffix);
+                assert_eq!(parse_size(&s), Ok(expected), "{}", s);
+                // Spaces around the number and before the suffix.
+                let s = format!(" {} {} ", n, suffix);
+                assert_eq!(parse_size(&s), Ok(expected), "{}", s);
+            }
+        }
+    }
+    assert_eq!(parse_size("256MiB"), Ok(256 << 20));
+    assert_eq!(parse_size("512k"), Ok(512 << 10));
+}
+
+#[test]
+fn test_parse_size_decimal() {
+    assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+    assert_eq!(parse_size("1.5k"), Ok(1536));
+    assert_eq!(parse_size(".5M"), Ok(512 << 10));
+    assert_eq!(parse_size("2.M"), Ok(2 << 20));
+    assert_eq!(parse_size("0.25KiB"), Ok(256));
+    // Rounded down to bytes.
+    assert_eq!(parse_size("0.1k"), Ok(102));
+    assert_eq!(parse_size("1.000000000000000000001P"), Ok(1 << 50));
+    assert!(parse_size("1.5")
+        .unwrap_err()
+        .contains("whole number of bytes"));
+    assert!(parse_size("1.5B").is_err());
+}
+
+#[test]
+fn test_parse_size_errors() {
+    for s in [
+        "", " ", ".", "M", "k1", "-1", "+1", "1..5M", "1.5.2G", "1e3", "0x10",
+    ] {
+        let err = parse_size(s).unwrap_err();
+        assert!(err.contains("size"), "{:?}: {}", s, err);
+    }
+    assert!(parse_size("1X")
+        .unwrap_err()
+        .starts_with("unknown size suffix"));
+    assert!(parse_size("1 MiBs")
+        .unwrap_err()
+        .starts_with("unknown size suffix"));
+    assert!(parse_size("16E")
+        .unwrap_err()
+        .starts_with("unknown size suffix"));
+    assert!(parse_size("99999999T").unwrap_err().contains("too large"));
+    assert!(parse_size("99999999999999999999999")
+        .unwrap_err()
+        .contains("too large"));
+    assert_eq!(parse_size("16383P"), Ok(16383 << 50));
+    assert!(parse_size("16384P").unwrap_err().contains("too large"));
+}
+
+#[test]
+fn test_parse_size_in() {
+    assert_eq!(parse_size_in("1024", KIB), Ok(1024));
+    assert_eq!(parse_size_in("1M", KIB), Ok(1024));
+    assert_eq!(parse_size_in("1G", MIB), Ok(1024));
+    assert_eq!(parse_size_in("1.5G", MIB), Ok(1536));
+    assert_eq!(parse_size_in("2048B", KIB), Ok(2));
+    let err = parse_size_in("1.5k", MIB).unwrap_err();
+    assert_eq!(err, "size \"1.5k\" is not a multiple of 1 MiB");
+    assert_eq!(parse_size_as::<u16>("63M", KIB), Ok(63 << 10));
+    assert!(parse_size_as::<u16>("64M", KIB)
+        .unwrap_err()
+        .contains("too large"));
+    assert!(parse_size_as::<u16>("65536", KIB)
+        .unwrap_err()
+        .contains("too large"));
+}
+
+#[test]
+fn test_size_serde() {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Sizes {
+        #[serde(with = "bytes")]
+        a: usize,
+        #[serde(with = "kib")]
+        b: u16,
+        #[serde(with = "mib", default)]
+        c: u64,
+    }
+    let parse = |json: &str| serde_json::from_str::<Sizes>(json).map_err(|e| e.to_string());
+    let sizes = parse(r#"{"a": "256MiB", "b": "1M", "c": "1.5G"}"#).unwrap();
+    assert_eq!(
+        sizes,
+        Sizes {
+            a: 256 << 20,
+            b: 1024,
+            c: 1536
+        }
+    );
+    // Plain integers still work, and integers are written back.
+    assert_eq!(
+        parse(r#"{"a": 268435456, "b": 1024, "c": 1536}"#).unwrap(),
+        sizes
+    );
+    let json = serde_json::to_string(&sizes).unwrap();
+    assert_eq!(json, r#"{"a":268435456,"b":1024,"c":1536}"#);
+    assert_eq!(parse(r#"{"a": 1, "b": 2}"#).unwrap().c, 0);
+
+    let err = parse(r#"{"a": "1X", "b": 1}"#).unwrap_err();
+    assert!(err.contains("unknown size suffix"), "{}", err);
+    let err = parse(r#"{"a": 1, "b": "1G"}"#).unwrap_err();
+    assert!(err.contains("too large"), "{}", err);
+    let err = parse(r#"{"a": 1, "b": 65536}"#).unwrap_err();
+    assert!(err.contains("too large"), "{}", err);
+    assert!(parse(r#"{"a": -1, "b": 1}"#).is_err());
+}